@@ -9,10 +9,28 @@
 //!    -V, --version    Prints version information
 //!
 //! SUBCOMMANDS:
-//!    cp      Multithreaded directory copy
-//!    help    Prints this message or the help of the given subcommand(s)
-//!    rm      Multithreaded directory remove
-//!    sync    Multithreaded directory synchronization [aliases: s]
+//!    bisync         Synchronize two directories with each other, in both directions
+//!    completions    Print the completion script of a shell, to complete subcommands and options of lms
+//!    cp             Multithreaded directory or file copy
+//!    daemon         Run jobs from the config file on cron-like schedules
+//!    diff           Compare two directories, or two manifests exported by the manifest subcommand
+//!    dupes          Find files with identical contents in a directory, and delete or hard link the extra copies
+//!    export-diff    Export what a copy of a directory is missing into an archive, to bring it up to date offline
+//!    export-oci     Export a directory as a reproducible OCI container image
+//!    hash           Print the BLAKE3 checksum of every file in a directory, for verify to check
+//!    help           Print the help of a subcommand, or of a topic such as archives or snapshots
+//!    history        Show the last runs of each profile, and how they changed from run to run
+//!    hydrate        Replace placeholders left by --materialize with their real contents
+//!    man            Print the man page of lms, with every subcommand and help topic, in roff
+//!    manifest       Export a manifest of every file in a directory, with its permissions and hash
+//!    mv             Move a directory, verifying copies across filesystems before removing sources
+//!    overlay        Merge several sources into a destination, where later sources take precedence
+//!    rm             Multithreaded directory remove
+//!    snapshot       Take an incremental snapshot, hard linking unchanged files to the previous one
+//!    stats          Count the files in a directory, with their total size, the largest ones, and a histogram of sizes
+//!    sync           Multithreaded directory synchronization [aliases: s]
+//!    tui            Review the changes a sync would make, approving, skipping or inspecting each one
+//!    verify         Check a directory for bit rot against the checksums printed by hash, or against a mirror of it
 //! ```
 
 mod lumins;
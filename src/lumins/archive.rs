@@ -0,0 +1,1667 @@
+//! Writes directory trees into tar archives, which may be compressed, and extracts them again
+
+use std::env;
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+use log::{error, info};
+
+use crate::lumins::file_ops::{self, FileOps, FileSets};
+use crate::lumins::parse::Options;
+use crate::lumins::{dirfd, image, platform, sandbox, squashfs, unpack, zip};
+use crate::progress::{self, PROGRESS_BAR, STATS};
+
+/// Size of a tar header and of the blocks that file contents are padded to
+const BLOCK_SIZE: usize = 512;
+
+/// Largest file size or timestamp that fits into a ustar header
+const MAX_USTAR_SIZE: u64 = 0o777_7777_7777;
+
+/// Largest owner ID that fits into a ustar header
+const MAX_USTAR_ID: u64 = 0o777_7777;
+
+/// Enum to represent how an archive is compressed
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub enum Compression {
+    None,
+    /// Compressed with zstd
+    Zstd,
+}
+
+/// Gets the compression of the archive at `path`, by its extension
+///
+/// # Returns
+/// * Some: The compression of the archive
+/// * None: If `path` is not an archive that a tree can be written into
+pub fn compression(path: &str) -> Option<Compression> {
+    if path.ends_with(".tar") {
+        Some(Compression::None)
+    } else if path.ends_with(".tar.zst") || path.ends_with(".tzst") {
+        Some(Compression::Zstd)
+    } else {
+        None
+    }
+}
+
+/// Checks whether `path` is an archive or an image that a tree can be written into, by its
+/// extension
+pub fn is_archive(path: &str) -> bool {
+    compression(path).is_some() || image::is_image(path) || squashfs::is_squashfs(path)
+}
+
+/// Checks whether `path` is an archive to extract a tree from, rather than a directory that is
+/// only named like one
+///
+/// Images are only ever written, so they are never archive sources, and zip archives are only
+/// ever read.
+pub fn is_archive_src(path: &str) -> bool {
+    (compression(path).is_some() || zip::is_zip(path)) && !Path::new(path).is_dir()
+}
+
+/// Enum to represent the kinds of entries that are written to an archive
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+enum EntryType {
+    File,
+    Dir,
+    Symlink,
+    PaxHeader,
+}
+
+impl EntryType {
+    /// Gets the ustar type flag of the entry type
+    fn flag(self) -> u8 {
+        match self {
+            EntryType::File => b'0',
+            EntryType::Dir => b'5',
+            EntryType::Symlink => b'2',
+            EntryType::PaxHeader => b'x',
+        }
+    }
+}
+
+/// A struct that represents the metadata that is archived with an entry
+#[derive(Eq, PartialEq, Debug, Clone, Copy, Default)]
+pub struct EntryMetadata {
+    /// Permission bits
+    pub mode: u32,
+    pub uid: u64,
+    pub gid: u64,
+    /// Modification time, in seconds since the Unix epoch
+    pub mtime: u64,
+}
+
+impl EntryMetadata {
+    /// Gets the archived metadata of a file from its filesystem metadata
+    #[cfg(target_family = "unix")]
+    pub fn from(metadata: &fs::Metadata) -> Self {
+        use std::os::unix::fs::MetadataExt;
+
+        EntryMetadata {
+            mode: metadata.mode(),
+            uid: u64::from(metadata.uid()),
+            gid: u64::from(metadata.gid()),
+            mtime: metadata.mtime().max(0) as u64,
+        }
+    }
+
+    /// Gets the archived metadata of a file from its filesystem metadata
+    #[cfg(not(target_family = "unix"))]
+    pub fn from(metadata: &fs::Metadata) -> Self {
+        use std::time::UNIX_EPOCH;
+
+        let mode = if metadata.is_dir() {
+            0o755
+        } else if metadata.permissions().readonly() {
+            0o444
+        } else {
+            0o644
+        };
+        let mtime = metadata
+            .modified()
+            .ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        EntryMetadata {
+            mode,
+            uid: 0,
+            gid: 0,
+            mtime,
+        }
+    }
+}
+
+/// A writer of tar archives in the POSIX pax format
+///
+/// Only the permission bits, owner IDs, and modification times of entries are archived, so
+/// owner names, access times, and extended attributes never make two archives differ.
+/// A deterministic writer also clears owners and clamps timestamps, so that the same tree
+/// always gives the same archive.
+pub struct TarWriter<W: Write> {
+    writer: W,
+    deterministic: Option<u64>,
+}
+
+impl<W: Write> TarWriter<W> {
+    /// Creates a tar writer that writes an archive into `writer`
+    ///
+    /// # Arguments
+    /// * `writer`: where to write the archive
+    /// * `deterministic`: if set, every owner is written as 0, and every timestamp is clamped
+    ///   to this many seconds since the Unix epoch
+    pub fn new(writer: W, deterministic: Option<u64>) -> Self {
+        TarWriter {
+            writer,
+            deterministic,
+        }
+    }
+
+    /// Appends a directory at `path`
+    pub fn append_dir(&mut self, path: &Path, metadata: EntryMetadata) -> Result<(), io::Error> {
+        let mut name = archive_path(path)?;
+        name.push(b'/');
+        self.append_header(EntryType::Dir, &name, b"", metadata, 0)
+    }
+
+    /// Appends a symlink at `path` that points to `target`
+    pub fn append_symlink(
+        &mut self,
+        path: &Path,
+        target: &Path,
+        metadata: EntryMetadata,
+    ) -> Result<(), io::Error> {
+        let name = archive_path(path)?;
+        let target = archive_path(target)?;
+        let metadata = EntryMetadata {
+            mode: 0o777,
+            ..metadata
+        };
+        self.append_header(EntryType::Symlink, &name, &target, metadata, 0)
+    }
+
+    /// Appends a file at `path`, with `size` bytes read from `contents`
+    ///
+    /// # Errors
+    /// This function will return an error if `contents` can not be read, or has less than
+    /// `size` bytes
+    pub fn append_file<R: Read>(
+        &mut self,
+        path: &Path,
+        metadata: EntryMetadata,
+        size: u64,
+        contents: &mut R,
+    ) -> Result<(), io::Error> {
+        let name = archive_path(path)?;
+        self.append_header(EntryType::File, &name, b"", metadata, size)?;
+
+        let copied = io::copy(&mut contents.take(size), &mut self.writer)?;
+        if copied != size {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!(
+                    "Archive Error -- {:?} was shorter than {} bytes",
+                    path, size
+                ),
+            ));
+        }
+        self.pad(size)
+    }
+
+    /// Writes the end-of-archive marker, and returns the underlying writer
+    pub fn finish(mut self) -> Result<W, io::Error> {
+        self.writer.write_all(&[0; BLOCK_SIZE * 2])?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+
+    /// Writes the header of an entry, preceded by a pax header if it does not fit into ustar
+    fn append_header(
+        &mut self,
+        entry_type: EntryType,
+        name: &[u8],
+        link_name: &[u8],
+        metadata: EntryMetadata,
+        size: u64,
+    ) -> Result<(), io::Error> {
+        let metadata = match self.deterministic {
+            Some(mtime) => EntryMetadata {
+                uid: 0,
+                gid: 0,
+                mtime: metadata.mtime.min(mtime),
+                ..metadata
+            },
+            None => metadata,
+        };
+
+        let mut records = Vec::new();
+        if name.len() > 100 {
+            records.extend(pax_record("path", name));
+        }
+        if link_name.len() > 100 {
+            records.extend(pax_record("linkpath", link_name));
+        }
+        if size > MAX_USTAR_SIZE {
+            records.extend(pax_record("size", size.to_string().as_bytes()));
+        }
+        if metadata.uid > MAX_USTAR_ID {
+            records.extend(pax_record("uid", metadata.uid.to_string().as_bytes()));
+        }
+        if metadata.gid > MAX_USTAR_ID {
+            records.extend(pax_record("gid", metadata.gid.to_string().as_bytes()));
+        }
+        if metadata.mtime > MAX_USTAR_SIZE {
+            records.extend(pax_record("mtime", metadata.mtime.to_string().as_bytes()));
+        }
+
+        if !records.is_empty() {
+            let pax_metadata = EntryMetadata {
+                mode: 0o644,
+                ..EntryMetadata::default()
+            };
+            let header = self.header(
+                EntryType::PaxHeader,
+                b"././@PaxHeader",
+                b"",
+                pax_metadata,
+                records.len() as u64,
+            );
+            self.writer.write_all(&header)?;
+            self.writer.write_all(&records)?;
+            self.pad(records.len() as u64)?;
+        }
+
+        let header = self.header(entry_type, name, link_name, metadata, size);
+        self.writer.write_all(&header)
+    }
+
+    /// Builds a ustar header, truncating fields that are too long for it
+    fn header(
+        &self,
+        entry_type: EntryType,
+        name: &[u8],
+        link_name: &[u8],
+        metadata: EntryMetadata,
+        size: u64,
+    ) -> [u8; BLOCK_SIZE] {
+        let mut header = [0; BLOCK_SIZE];
+
+        copy_field(&mut header[0..100], name);
+        write_octal(&mut header[100..108], u64::from(metadata.mode & 0o7777));
+        write_octal(&mut header[108..116], metadata.uid.min(MAX_USTAR_ID));
+        write_octal(&mut header[116..124], metadata.gid.min(MAX_USTAR_ID));
+        write_octal(&mut header[124..136], size.min(MAX_USTAR_SIZE));
+        write_octal(&mut header[136..148], metadata.mtime.min(MAX_USTAR_SIZE));
+        header[156] = entry_type.flag();
+        copy_field(&mut header[157..257], link_name);
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[263..265].copy_from_slice(b"00");
+        write_octal(&mut header[329..337], 0);
+        write_octal(&mut header[337..345], 0);
+
+        // The checksum is computed with the checksum field filled with spaces
+        header[148..156].copy_from_slice(b"        ");
+        let checksum: u64 = header.iter().map(|&b| u64::from(b)).sum();
+        write_octal(&mut header[148..155], checksum);
+
+        header
+    }
+
+    /// Pads an entry of `size` bytes to a whole number of blocks
+    fn pad(&mut self, size: u64) -> Result<(), io::Error> {
+        let remainder = (size % BLOCK_SIZE as u64) as usize;
+        if remainder != 0 {
+            self.writer.write_all(&[0; BLOCK_SIZE][remainder..])?;
+        }
+        Ok(())
+    }
+}
+
+/// Enum to represent the kinds of entries that are read from an archive
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub enum ArchivedKind {
+    File,
+    Dir,
+    /// A symlink with the given target
+    Symlink(PathBuf),
+    /// A hard link to the given earlier entry of the archive
+    HardLink(PathBuf),
+    /// An entry that can not be extracted, such as a device, with its ustar type flag
+    Other(u8),
+}
+
+/// A struct that represents the header of an entry that was read from an archive
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub struct ArchivedEntry {
+    /// Path of the entry, relative to the root of the archive
+    pub path: PathBuf,
+    pub kind: ArchivedKind,
+    pub metadata: EntryMetadata,
+    /// Size of the contents of the entry
+    pub size: u64,
+}
+
+/// A reader of tar archives in the ustar, pax, and GNU formats
+///
+/// Long paths are read from pax headers, or from GNU long name entries, and pax overrides of
+/// sizes, owners, and timestamps are applied. Global pax headers are ignored.
+pub struct TarReader<R: Read> {
+    reader: R,
+    /// Bytes of the contents of the current entry that have not been read yet
+    contents: u64,
+    /// Bytes of padding after the contents of the current entry
+    padding: u64,
+}
+
+/// A struct that represents the fields of a header that earlier extended headers override
+#[derive(Default)]
+struct Overrides {
+    path: Option<Vec<u8>>,
+    link_path: Option<Vec<u8>>,
+    size: Option<u64>,
+    uid: Option<u64>,
+    gid: Option<u64>,
+    mtime: Option<u64>,
+}
+
+impl<R: Read> TarReader<R> {
+    /// Creates a tar reader that reads an archive from `reader`
+    pub fn new(reader: R) -> Self {
+        TarReader {
+            reader,
+            contents: 0,
+            padding: 0,
+        }
+    }
+
+    /// Reads the header of the next entry, skipping whatever is left of the current entry
+    ///
+    /// # Returns
+    /// * Some: The header of the next entry
+    /// * None: At the end-of-archive marker, or at the end of the reader
+    ///
+    /// # Errors
+    /// This function will return an error if the archive is truncated, or has an invalid header
+    pub fn next_entry(&mut self) -> Result<Option<ArchivedEntry>, io::Error> {
+        let mut overrides = Overrides::default();
+        loop {
+            self.skip()?;
+
+            let mut header = [0; BLOCK_SIZE];
+            if !self.read_block(&mut header)? || header.iter().all(|&b| b == 0) {
+                return Ok(None);
+            }
+
+            let checksum = read_octal(&header[148..156])?;
+            let actual: u64 = header
+                .iter()
+                .enumerate()
+                .map(|(i, &b)| {
+                    if (148..156).contains(&i) {
+                        32
+                    } else {
+                        u64::from(b)
+                    }
+                })
+                .sum();
+            if checksum != actual {
+                return Err(invalid_archive("header checksum does not match"));
+            }
+
+            let size = match overrides.size {
+                Some(size) => size,
+                None => read_octal(&header[124..136])?,
+            };
+            self.contents = size;
+            self.padding = (BLOCK_SIZE as u64 - size % BLOCK_SIZE as u64) % BLOCK_SIZE as u64;
+
+            let kind = match header[156] {
+                b'x' => {
+                    let records = self.read_extended(size)?;
+                    read_pax_records(&records, &mut overrides)?;
+                    continue;
+                }
+                b'g' => continue,
+                b'L' => {
+                    overrides.path = Some(trim_nul(self.read_extended(size)?));
+                    continue;
+                }
+                b'K' => {
+                    overrides.link_path = Some(trim_nul(self.read_extended(size)?));
+                    continue;
+                }
+                b'0' | b'7' | 0 => ArchivedKind::File,
+                b'5' => ArchivedKind::Dir,
+                b'1' | b'2' => {
+                    let link_path = match overrides.link_path.take() {
+                        Some(link_path) => link_path,
+                        None => trim_nul(header[157..257].to_vec()),
+                    };
+                    let link_path = path_from_archive(link_path)?;
+                    if header[156] == b'1' {
+                        ArchivedKind::HardLink(link_path)
+                    } else {
+                        ArchivedKind::Symlink(link_path)
+                    }
+                }
+                flag => ArchivedKind::Other(flag),
+            };
+
+            let path = match overrides.path.take() {
+                Some(path) => path,
+                None => {
+                    let name = trim_nul(header[0..100].to_vec());
+                    let prefix = trim_nul(header[345..500].to_vec());
+                    if &header[257..262] == b"ustar" && !prefix.is_empty() {
+                        [prefix, name].join(&b'/')
+                    } else {
+                        name
+                    }
+                }
+            };
+
+            let metadata = EntryMetadata {
+                mode: read_octal(&header[100..108])? as u32,
+                uid: match overrides.uid {
+                    Some(uid) => uid,
+                    None => read_octal(&header[108..116])?,
+                },
+                gid: match overrides.gid {
+                    Some(gid) => gid,
+                    None => read_octal(&header[116..124])?,
+                },
+                mtime: match overrides.mtime {
+                    Some(mtime) => mtime,
+                    None => read_octal(&header[136..148])?,
+                },
+            };
+
+            return Ok(Some(ArchivedEntry {
+                path: path_from_archive(path)?,
+                kind,
+                metadata,
+                size,
+            }));
+        }
+    }
+
+    /// Copies the contents of the current entry into `writer`
+    ///
+    /// # Errors
+    /// This function will return an error if the archive is truncated, or `writer` fails
+    pub fn read_contents<W: Write>(&mut self, writer: &mut W) -> Result<u64, io::Error> {
+        let size = self.contents;
+        let copied = io::copy(&mut (&mut self.reader).take(size), writer)?;
+        self.contents -= copied;
+        if copied != size {
+            return Err(truncated_archive());
+        }
+        Ok(copied)
+    }
+
+    /// Skips the rest of the current entry and its padding
+    fn skip(&mut self) -> Result<(), io::Error> {
+        let size = self.contents + self.padding;
+        let skipped = io::copy(&mut (&mut self.reader).take(size), &mut io::sink())?;
+        if skipped != size {
+            return Err(truncated_archive());
+        }
+        self.contents = 0;
+        self.padding = 0;
+        Ok(())
+    }
+
+    /// Reads the contents of an extended header of `size` bytes
+    fn read_extended(&mut self, size: u64) -> Result<Vec<u8>, io::Error> {
+        // Extended headers only hold paths and numbers, so anything larger is not one
+        if size > 1 << 20 {
+            return Err(invalid_archive("extended header is too large"));
+        }
+        let mut contents = Vec::with_capacity(size as usize);
+        self.read_contents(&mut contents)?;
+        Ok(contents)
+    }
+
+    /// Reads a whole block into `block`
+    ///
+    /// # Returns
+    /// Whether a block was read, which is not the case at the end of the reader
+    fn read_block(&mut self, block: &mut [u8; BLOCK_SIZE]) -> Result<bool, io::Error> {
+        let mut len = 0;
+        while len < BLOCK_SIZE {
+            match self.reader.read(&mut block[len..]) {
+                Ok(0) if len == 0 => return Ok(false),
+                Ok(0) => return Err(truncated_archive()),
+                Ok(read) => len += read,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(true)
+    }
+}
+
+impl<R: Read + Seek> TarReader<R> {
+    /// Skips the contents of the current entry without reading them, for archives that are
+    /// listed before their contents are read
+    ///
+    /// # Returns
+    /// The offset of the contents of the entry in the reader
+    pub fn seek_past_contents(&mut self) -> Result<u64, io::Error> {
+        let offset = self.reader.stream_position()?;
+        self.reader
+            .seek(SeekFrom::Current((self.contents + self.padding) as i64))?;
+        self.contents = 0;
+        self.padding = 0;
+        Ok(offset)
+    }
+}
+
+/// Writes every file, directory, and symlink in `src` into `tar`, sorted by path
+///
+/// Paths in the archive are relative to `src`, and parents are always written before their
+/// children. Any file that can not be read fails the whole archive, instead of being left out.
+///
+/// # Arguments
+/// * `src`: directory to archive
+/// * `tar`: tar writer to append the entries to
+///
+/// # Errors
+/// This function will return an error if `src` is an invalid directory, or if any entry
+/// could not be read or written
+pub fn write_tree<W: Write>(src: &str, tar: &mut TarWriter<W>) -> Result<(), io::Error> {
+    write_file_sets(src, &file_ops::get_all_files(src)?, tar)
+}
+
+/// Writes every file, directory, and symlink of `file_sets`, which were listed in `src`, into
+/// `tar`, sorted by path, like `write_tree`
+///
+/// # Errors
+/// This function will return an error if any entry could not be read or written
+pub fn write_file_sets<W: Write>(
+    src: &str,
+    file_sets: &FileSets,
+    tar: &mut TarWriter<W>,
+) -> Result<(), io::Error> {
+    // Path's ordering compares components, so parents sort before their children
+    let mut entries: Vec<(&Path, Entry)> = Vec::new();
+    entries.extend(
+        file_sets
+            .dirs()
+            .iter()
+            .map(|dir| (dir.path().as_path(), Entry::Dir)),
+    );
+    entries.extend(
+        file_sets
+            .files()
+            .iter()
+            .map(|file| (file.path().as_path(), Entry::File)),
+    );
+    entries.extend(
+        file_sets
+            .symlinks()
+            .iter()
+            .map(|symlink| (symlink.path().as_path(), Entry::Symlink(symlink.target()))),
+    );
+    entries.sort_unstable_by(|a, b| a.0.cmp(b.0));
+
+    progress::progress_init(entries.len() as u64);
+
+    let src = Path::new(src);
+    for (path, entry) in entries {
+        let result = match entry {
+            Entry::Dir => fs::symlink_metadata(src.join(path))
+                .and_then(|metadata| tar.append_dir(path, EntryMetadata::from(&metadata))),
+            Entry::File => dirfd::open_file(src, path).and_then(|mut file| {
+                let metadata = file.metadata()?;
+                tar.append_file(
+                    path,
+                    EntryMetadata::from(&metadata),
+                    metadata.len(),
+                    &mut file,
+                )
+            }),
+            Entry::Symlink(target) => fs::symlink_metadata(src.join(path)).and_then(|metadata| {
+                tar.append_symlink(path, target, EntryMetadata::from(&metadata))
+            }),
+        };
+
+        result.map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Archive Error -- Archiving {:?}: {}", src.join(path), e),
+            )
+        })?;
+        PROGRESS_BAR.inc(1);
+    }
+
+    Ok(())
+}
+
+/// Writes every file, directory, and symlink in `src` into a new tar archive at `dest`
+///
+/// With `Options::deterministic`, timestamps are clamped to `SOURCE_DATE_EPOCH`, or to 0 if it is
+/// not set. Archives are compressed by their extension, see `compression`, where the tree is
+/// streamed into the compressor as it is read. If the archive can not be completely written,
+/// `dest` is removed.
+///
+/// Images are written by `image::write_image` and `squashfs::write_squashfs` instead, see
+/// `image::is_image` and `squashfs::is_squashfs`.
+///
+/// # Arguments
+/// * `src`: directory to archive
+/// * `dest`: path of the archive
+/// * `options`: Options of the run
+///
+/// # Errors
+/// This function will return an error if `src` is an invalid directory, if the archive
+/// could not be written, or if its compressor could not be run
+pub fn copy_to_archive(src: &str, dest: &str, options: &Options) -> Result<(), io::Error> {
+    if image::is_image(dest) {
+        return image::write_image(src, dest, options);
+    }
+    if squashfs::is_squashfs(dest) {
+        return squashfs::write_squashfs(src, dest, options);
+    }
+    write_archive(dest, options, |tar| write_tree(src, tar))
+}
+
+/// Writes a new tar archive at `dest`, with the entries that `write` appends to it
+///
+/// Archives are written as by `copy_to_archive`, and `dest` is removed if `write` fails.
+///
+/// # Errors
+/// This function will return an error if the archive could not be written, or if its
+/// compressor could not be run
+pub fn write_archive<F>(dest: &str, options: &Options, write: F) -> Result<(), io::Error>
+where
+    F: FnOnce(&mut TarWriter<&mut dyn Write>) -> Result<(), io::Error>,
+{
+    let deterministic = if options.deterministic {
+        Some(source_date_epoch())
+    } else {
+        None
+    };
+
+    let file = fs::File::create(dest).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("Archive Error -- Creating {:?}: {}", dest, e),
+        )
+    })?;
+    let result = match compression(dest) {
+        Some(Compression::Zstd) => write_compressed(file, deterministic, write),
+        _ => write_tar(&mut BufWriter::new(file), deterministic, write),
+    };
+
+    if result.is_err() {
+        fs::remove_file(dest).ok();
+    }
+    result
+}
+
+/// Writes the tar archive with the entries that `write` appends into `file`, compressed with zstd
+///
+/// # Errors
+/// This function will return an error if the entries could not be archived, or the compressed
+/// stream could not be written
+fn write_compressed<F>(
+    file: fs::File,
+    deterministic: Option<u64>,
+    write: F,
+) -> Result<(), io::Error>
+where
+    F: FnOnce(&mut TarWriter<&mut dyn Write>) -> Result<(), io::Error>,
+{
+    let mut encoder = zstd::Encoder::new(BufWriter::new(file), zstd::DEFAULT_COMPRESSION_LEVEL)?;
+    encoder.multithread(rayon::current_num_threads() as u32)?;
+    write_tar(&mut encoder, deterministic, write)?;
+    encoder.finish()?.flush()
+}
+
+/// Writes the tar archive with the entries that `write` appends into `writer`
+fn write_tar<F>(
+    writer: &mut dyn Write,
+    deterministic: Option<u64>,
+    write: F,
+) -> Result<(), io::Error>
+where
+    F: FnOnce(&mut TarWriter<&mut dyn Write>) -> Result<(), io::Error>,
+{
+    let mut tar = TarWriter::new(writer, deterministic);
+    write(&mut tar)?;
+    tar.finish()?.flush()
+}
+
+/// Extracts every file, directory, and symlink of the tar or zip archive `src` into `dest`
+///
+/// Archives are decompressed by their extension, see `compression`. Entries are extracted with
+/// their permission bits and modification times, and with `options.idmap`, their owners are
+/// mapped and restored too. Entries whose paths are absolute or climb out of `dest` with `..`
+/// are refused, and parents are never followed through symlinks, so an archive can only write
+/// inside `dest`. Entries that can not be extracted are logged and skipped, and files that are
+/// already in `dest` are only written if they differ, see `unpack::unpack`.
+///
+/// # Arguments
+/// * `src`: path of the archive
+/// * `dest`: directory to extract into
+/// * `options`: Options of the run
+///
+/// # Errors
+/// This function will return an error if `src` could not be read or is not a valid archive, if
+/// its decompressor could not be run, or if any entry could not be extracted
+pub fn copy_from_archive(src: &str, dest: &str, options: &Options) -> Result<(), io::Error> {
+    unpack::unpack(src, dest, options, false)
+}
+
+/// Extracts the uncompressed tar archive that `reader` streams into `dest`, such as from a pipe
+///
+/// Entries are extracted as by `copy_from_archive`, where `name` names the stream in errors.
+///
+/// # Errors
+/// This function will return an error if the archive is invalid, or if any entry could not be
+/// extracted
+pub fn extract_stream<R: Read>(
+    reader: R,
+    name: &str,
+    dest: &str,
+    options: &Options,
+) -> Result<(), io::Error> {
+    progress::progress_init(0);
+    let (entries, failures) = extract(
+        &mut TarReader::new(BufReader::new(reader)),
+        Path::new(dest),
+        options,
+    )?;
+
+    if failures > 0 {
+        return Err(io::Error::other(format!(
+            "Archive Error -- {} of {} entries in {} could not be extracted",
+            failures, entries, name
+        )));
+    }
+    Ok(())
+}
+
+/// Extracts every entry that `tar` reads into `dest`
+///
+/// Directories get their permissions and modification times once everything in them has been
+/// extracted, so that read-only directories can still be filled.
+///
+/// # Returns
+/// The number of entries in the archive, and the number of them that could not be extracted
+///
+/// # Errors
+/// This function will return an error if the archive is invalid
+fn extract<R: Read>(
+    tar: &mut TarReader<R>,
+    dest: &Path,
+    options: &Options,
+) -> Result<(usize, usize), io::Error> {
+    let mut entries = 0;
+    let mut failures = 0;
+    let mut dirs = Vec::new();
+
+    while let Some(entry) = tar.next_entry()? {
+        entries += 1;
+        PROGRESS_BAR.inc_length(1);
+
+        // The root of the archive is `dest` itself
+        if entry
+            .path
+            .components()
+            .all(|component| component == std::path::Component::CurDir)
+        {
+            PROGRESS_BAR.inc(1);
+            continue;
+        }
+
+        let result = extract_entry(tar, &entry, dest, options);
+        match result {
+            Ok(_) => {
+                info!("Extracting {:?}", dest.join(&entry.path));
+                if entry.kind == ArchivedKind::Dir {
+                    dirs.push(entry);
+                }
+            }
+            Err(e) => {
+                failures += 1;
+                error!(
+                    "Archive Error -- Extracting {:?}: {}",
+                    dest.join(&entry.path),
+                    e
+                );
+            }
+        }
+        PROGRESS_BAR.inc(1);
+    }
+
+    // Path's ordering compares components, so children sort after their parents
+    dirs.sort_unstable_by(|a, b| b.path.cmp(&a.path));
+    for dir in dirs {
+        if let Err(e) = set_dir_metadata(dest, &dir) {
+            failures += 1;
+            error!(
+                "Archive Error -- Setting metadata {:?}: {}",
+                dest.join(&dir.path),
+                e
+            );
+        }
+    }
+
+    Ok((entries, failures))
+}
+
+/// Extracts `entry` into `dest`, reading the contents of files from `tar`
+fn extract_entry<R: Read>(
+    tar: &mut TarReader<R>,
+    entry: &ArchivedEntry,
+    dest: &Path,
+    options: &Options,
+) -> Result<(), io::Error> {
+    check_entry(entry, options)?;
+
+    if entry.kind == ArchivedKind::Dir {
+        dirfd::create_dir_all(dest, &entry.path)?;
+        let (parent, name) = dirfd::open_parent(dest, &entry.path, false)?;
+        return set_owner(&parent, &name, entry, options);
+    }
+
+    let (parent, name) = dirfd::open_parent(dest, &entry.path, true)?;
+    // Whatever is in the way is replaced, unless it is a directory
+    match parent.kind(&name) {
+        Ok(dirfd::EntryKind::Dir) | Err(_) => {}
+        Ok(_) => parent.remove(&name, false)?,
+    }
+
+    match &entry.kind {
+        ArchivedKind::File | ArchivedKind::HardLink(_) => {
+            let mut file = parent.create_file(&name, entry.metadata.mode & 0o777)?;
+            let size = match &entry.kind {
+                ArchivedKind::HardLink(target) => {
+                    // Hard links are extracted as copies of their target, which is already in dest
+                    io::copy(&mut dirfd::open_file(dest, target)?, &mut file)?
+                }
+                _ => tar.read_contents(&mut file)?,
+            };
+            set_owner(&parent, &name, entry, options)?;
+            set_permissions(&file, entry.metadata.mode)?;
+            file.set_modified(UNIX_EPOCH + Duration::from_secs(entry.metadata.mtime))?;
+            STATS.add_copied(size);
+            Ok(())
+        }
+        ArchivedKind::Symlink(target) => {
+            #[cfg(target_family = "unix")]
+            parent.symlink(target, &name)?;
+            #[cfg(not(target_family = "unix"))]
+            {
+                use std::os::windows::fs;
+                fs::symlink_file(target, dest.join(&entry.path))?;
+            }
+            set_owner(&parent, &name, entry, options)
+        }
+        ArchivedKind::Other(flag) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("entries of type {:?} can not be extracted", *flag as char),
+        )),
+        ArchivedKind::Dir => Ok(()),
+    }
+}
+
+/// Checks that extracting `entry` would write nothing that leads out of the destination, unless
+/// `options` turn the sandbox off
+///
+/// # Errors
+/// This function will return an error if the path of `entry` is absolute or climbs out of the
+/// destination, or if it links to somewhere that does
+pub fn check_entry(entry: &ArchivedEntry, options: &Options) -> Result<(), io::Error> {
+    if !sandbox::is_sandboxed(options, true) {
+        return Ok(());
+    }
+    match &entry.kind {
+        ArchivedKind::Symlink(target) => sandbox::check_symlink(&entry.path, target),
+        ArchivedKind::HardLink(target) => {
+            sandbox::check_path(&entry.path)?;
+            sandbox::check_path(target)
+        }
+        _ => sandbox::check_path(&entry.path),
+    }
+}
+
+/// Sets the owner of the extracted `entry` at `name` in `parent`, mapped by the `idmap` of
+/// `options`
+///
+/// Nothing is done if `options` has no `idmap`
+pub fn set_owner(
+    parent: &dirfd::DirHandle,
+    name: &OsStr,
+    entry: &ArchivedEntry,
+    options: &Options,
+) -> Result<(), io::Error> {
+    let idmap = match &options.idmap {
+        Some(idmap) => idmap,
+        None => return Ok(()),
+    };
+
+    #[cfg(target_family = "unix")]
+    {
+        let uid = idmap.uid(entry.metadata.uid as u32);
+        let gid = idmap.gid(entry.metadata.gid as u32);
+        parent.chown(name, uid, gid)
+    }
+
+    #[cfg(not(target_family = "unix"))]
+    {
+        let _ = (idmap, parent, name, entry);
+        Ok(())
+    }
+}
+
+/// Sets the permissions of the extracted `file` to the archived `mode`
+pub fn set_permissions(file: &fs::File, mode: u32) -> Result<(), io::Error> {
+    #[cfg(target_family = "unix")]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(fs::Permissions::from_mode(mode & 0o7777))
+    }
+
+    #[cfg(not(target_family = "unix"))]
+    {
+        let mut permissions = file.metadata()?.permissions();
+        permissions.set_readonly(mode & 0o222 == 0);
+        file.set_permissions(permissions)
+    }
+}
+
+/// Sets the permissions and modification time of the extracted directory `dir`
+pub fn set_dir_metadata(dest: &Path, dir: &ArchivedEntry) -> Result<(), io::Error> {
+    let (parent, name) = dirfd::open_parent(dest, &dir.path, false)?;
+    // Opening the directory through its parent makes sure it is not a symlink
+    parent.open_dir(&name)?;
+    let path = dest.join(&dir.path);
+
+    #[cfg(target_family = "unix")]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(
+            &path,
+            fs::Permissions::from_mode(dir.metadata.mode & 0o7777),
+        )?;
+    }
+    platform::set_modified(&path, UNIX_EPOCH + Duration::from_secs(dir.metadata.mtime))
+}
+
+/// Gets the timestamp that reproducible archives are clamped to, from `SOURCE_DATE_EPOCH`
+pub fn source_date_epoch() -> u64 {
+    env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+/// Enum to represent what is written for each path of a tree
+enum Entry<'a> {
+    Dir,
+    File,
+    Symlink(&'a Path),
+}
+
+/// Builds a pax extended header record, `<length> <key>=<value>\n`
+///
+/// The length is the number of bytes in the whole record, including itself
+fn pax_record(key: &str, value: &[u8]) -> Vec<u8> {
+    // Length of " key=value\n"
+    let rest = key.len() + value.len() + 3;
+    let mut length = rest + 1;
+    while length != rest + length.to_string().len() {
+        length = rest + length.to_string().len();
+    }
+
+    let mut record = format!("{} {}=", length, key).into_bytes();
+    record.extend_from_slice(value);
+    record.push(b'\n');
+    record
+}
+
+/// Copies as much of `value` as fits into `field`
+fn copy_field(field: &mut [u8], value: &[u8]) {
+    let length = value.len().min(field.len());
+    field[..length].copy_from_slice(&value[..length]);
+}
+
+/// Writes `value` into `field` as zero-padded octal, terminated by a NUL
+fn write_octal(field: &mut [u8], value: u64) {
+    let digits = format!("{:0width$o}", value, width = field.len() - 1);
+    copy_field(field, digits.as_bytes());
+    field[field.len() - 1] = 0;
+}
+
+/// Converts `path` into the bytes used for it in an archive, with `/` as the separator
+///
+/// # Errors
+/// This function will return an error if `path` is not valid UTF-8 on a platform that does not
+/// store paths as bytes
+pub fn archive_path(path: &Path) -> Result<Vec<u8>, io::Error> {
+    #[cfg(target_family = "unix")]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        Ok(path.as_os_str().as_bytes().to_vec())
+    }
+    #[cfg(not(target_family = "unix"))]
+    {
+        let path = path.to_str().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Archive Error -- {:?} is not valid UTF-8", path),
+            )
+        })?;
+        Ok(path.replace('\\', "/").into_bytes())
+    }
+}
+
+/// Reads the pax extended header `records` into `overrides`
+///
+/// # Errors
+/// This function will return an error if a record is malformed
+fn read_pax_records(mut records: &[u8], overrides: &mut Overrides) -> Result<(), io::Error> {
+    let malformed = || invalid_archive("malformed pax record");
+    while !records.is_empty() {
+        let space = records
+            .iter()
+            .position(|&b| b == b' ')
+            .ok_or_else(malformed)?;
+        let length: usize = std::str::from_utf8(&records[..space])
+            .ok()
+            .and_then(|length| length.parse().ok())
+            .ok_or_else(malformed)?;
+        if length <= space || length > records.len() || records[length - 1] != b'\n' {
+            return Err(malformed());
+        }
+
+        let record = &records[space + 1..length - 1];
+        let equals = record
+            .iter()
+            .position(|&b| b == b'=')
+            .ok_or_else(malformed)?;
+        let (key, value) = (&record[..equals], &record[equals + 1..]);
+        let number = || {
+            std::str::from_utf8(value)
+                .ok()
+                .and_then(|value| value.split('.').next())
+                .and_then(|value| value.parse().ok())
+                .ok_or_else(malformed)
+        };
+        match key {
+            b"path" => overrides.path = Some(value.to_vec()),
+            b"linkpath" => overrides.link_path = Some(value.to_vec()),
+            b"size" => overrides.size = Some(number()?),
+            b"uid" => overrides.uid = Some(number()?),
+            b"gid" => overrides.gid = Some(number()?),
+            b"mtime" => overrides.mtime = Some(number()?),
+            _ => {}
+        }
+        records = &records[length..];
+    }
+    Ok(())
+}
+
+/// Reads a numeric header field, in octal, or in the base-256 encoding of GNU tar
+///
+/// # Errors
+/// This function will return an error if `field` is not a number
+fn read_octal(field: &[u8]) -> Result<u64, io::Error> {
+    if field[0] & 0x80 != 0 {
+        return Ok(field[1..]
+            .iter()
+            .fold(u64::from(field[0] & 0x7f), |value, &b| {
+                value << 8 | u64::from(b)
+            }));
+    }
+
+    let digits: Vec<u8> = field
+        .iter()
+        .copied()
+        .skip_while(|&b| b == b' ')
+        .take_while(|&b| b != 0 && b != b' ')
+        .collect();
+    if digits.is_empty() {
+        return Ok(0);
+    }
+    std::str::from_utf8(&digits)
+        .ok()
+        .and_then(|digits| u64::from_str_radix(digits, 8).ok())
+        .ok_or_else(|| invalid_archive("invalid number in header"))
+}
+
+/// Cuts `value` off at its first NUL
+fn trim_nul(mut value: Vec<u8>) -> Vec<u8> {
+    if let Some(nul) = value.iter().position(|&b| b == 0) {
+        value.truncate(nul);
+    }
+    value
+}
+
+/// Converts the bytes of a path in an archive into a path, see `archive_path`
+///
+/// # Errors
+/// This function will return an error if `path` is not valid UTF-8 on a platform that does not
+/// store paths as bytes
+pub fn path_from_archive(path: Vec<u8>) -> Result<PathBuf, io::Error> {
+    #[cfg(target_family = "unix")]
+    {
+        use std::os::unix::ffi::OsStringExt;
+        Ok(PathBuf::from(std::ffi::OsString::from_vec(path)))
+    }
+    #[cfg(not(target_family = "unix"))]
+    {
+        String::from_utf8(path)
+            .map(PathBuf::from)
+            .map_err(|_| invalid_archive("path is not valid UTF-8"))
+    }
+}
+
+/// Builds the error for an archive that is not valid
+pub fn invalid_archive(message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Archive Error -- {}", message),
+    )
+}
+
+/// Builds the error for an archive that ends in the middle of an entry
+pub fn truncated_archive() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "Archive Error -- archive is truncated",
+    )
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_tar_writer {
+    use super::*;
+
+    #[test]
+    fn header_fields() {
+        let metadata = EntryMetadata {
+            mode: 0o100644,
+            uid: 1000,
+            gid: 100,
+            mtime: 1_600_000_000,
+        };
+        let mut tar = TarWriter::new(Vec::new(), None);
+        tar.append_file(Path::new("a/b"), metadata, 3, &mut &b"abc"[..])
+            .unwrap();
+        let archive = tar.finish().unwrap();
+
+        // Header, one block of contents, and the end-of-archive marker
+        assert_eq!(archive.len(), BLOCK_SIZE * 4);
+        assert_eq!(&archive[0..4], b"a/b\0");
+        assert_eq!(&archive[100..108], b"0000644\0");
+        assert_eq!(&archive[108..116], b"0001750\0");
+        assert_eq!(&archive[116..124], b"0000144\0");
+        assert_eq!(&archive[136..148], b"13727410000\0");
+        assert_eq!(&archive[124..136], b"00000000003\0");
+        assert_eq!(archive[156], b'0');
+        assert_eq!(&archive[257..263], b"ustar\0");
+        assert_eq!(&archive[BLOCK_SIZE..BLOCK_SIZE + 4], b"abc\0");
+        assert!(archive[BLOCK_SIZE * 2..].iter().all(|&b| b == 0));
+
+        let checksum: u64 = archive[..BLOCK_SIZE]
+            .iter()
+            .enumerate()
+            .map(|(i, &b)| {
+                if (148..156).contains(&i) {
+                    32
+                } else {
+                    u64::from(b)
+                }
+            })
+            .sum();
+        assert_eq!(&archive[148..155], format!("{:06o}\0", checksum).as_bytes());
+    }
+
+    #[test]
+    fn short_file() {
+        let mut tar = TarWriter::new(Vec::new(), None);
+        assert!(tar
+            .append_file(
+                Path::new("a"),
+                EntryMetadata::default(),
+                4,
+                &mut &b"abc"[..]
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn long_path() {
+        let path = "a/".repeat(100);
+        let mut tar = TarWriter::new(Vec::new(), None);
+        tar.append_dir(Path::new(&path[..path.len() - 1]), EntryMetadata::default())
+            .unwrap();
+        let archive = tar.finish().unwrap();
+
+        assert_eq!(archive[156], b'x');
+        let record = pax_record("path", path.as_bytes());
+        assert_eq!(&record[..4], b"210 ");
+        assert_eq!(record.len(), 210);
+        assert_eq!(&archive[BLOCK_SIZE..BLOCK_SIZE + record.len()], &record[..]);
+        assert_eq!(archive[BLOCK_SIZE * 2 + 156], b'5');
+    }
+
+    #[test]
+    fn large_ids() {
+        let metadata = EntryMetadata {
+            uid: 100_000_000,
+            ..EntryMetadata::default()
+        };
+        let mut tar = TarWriter::new(Vec::new(), None);
+        tar.append_dir(Path::new("a"), metadata).unwrap();
+        let archive = tar.finish().unwrap();
+
+        assert_eq!(archive[156], b'x');
+        let record = pax_record("uid", b"100000000");
+        assert_eq!(&archive[BLOCK_SIZE..BLOCK_SIZE + record.len()], &record[..]);
+        assert_eq!(
+            &archive[BLOCK_SIZE * 2 + 108..BLOCK_SIZE * 2 + 116],
+            b"7777777\0"
+        );
+    }
+
+    #[test]
+    fn deterministic() {
+        let archive = |metadata, deterministic| {
+            let mut tar = TarWriter::new(Vec::new(), deterministic);
+            tar.append_dir(Path::new("a"), metadata).unwrap();
+            tar.finish().unwrap()
+        };
+        let old = EntryMetadata {
+            mode: 0o755,
+            uid: 1000,
+            gid: 1000,
+            mtime: 100,
+        };
+        let new = EntryMetadata {
+            uid: 0,
+            mtime: 300,
+            ..old
+        };
+
+        assert_ne!(archive(old, None), archive(new, None));
+        assert_ne!(archive(old, Some(200)), archive(new, Some(400)));
+        assert_eq!(archive(old, Some(0)), archive(new, Some(0)));
+
+        // Timestamps are clamped, so those before the limit are kept
+        let cleared = EntryMetadata {
+            uid: 0,
+            gid: 0,
+            ..old
+        };
+        assert_eq!(archive(old, Some(200)), archive(cleared, None));
+        assert_eq!(
+            archive(new, Some(200)),
+            archive(
+                EntryMetadata {
+                    mtime: 200,
+                    ..cleared
+                },
+                None
+            )
+        );
+    }
+
+    #[test]
+    fn pax_record_length() {
+        assert_eq!(pax_record("path", b"abc"), b"12 path=abc\n");
+        // The length field growing a digit pushes the record from 99 to 101 bytes
+        let record = pax_record("path", &[b'a'; 91]);
+        assert_eq!(record.len(), 101);
+        assert_eq!(&record[..4], b"101 ");
+    }
+}
+
+#[cfg(test)]
+mod test_write_tree {
+    use super::*;
+    use std::process::Command;
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn deterministic() {
+        use std::os::unix::fs::symlink;
+
+        const TEST_DIR: &str = "test_archive_write_tree_deterministic";
+        const TEST_DIR_OUT: &str = "test_archive_write_tree_deterministic_out";
+
+        fs::create_dir_all([TEST_DIR, "b/c"].join("/")).unwrap();
+        fs::create_dir_all(TEST_DIR_OUT).unwrap();
+        fs::write([TEST_DIR, "a"].join("/"), b"file a").unwrap();
+        fs::write([TEST_DIR, "b/c/d"].join("/"), b"file d").unwrap();
+        symlink("c/d", [TEST_DIR, "b/link"].join("/")).unwrap();
+
+        let mut tar = TarWriter::new(Vec::new(), Some(0));
+        write_tree(TEST_DIR, &mut tar).unwrap();
+        let archive = tar.finish().unwrap();
+
+        let mut tar = TarWriter::new(Vec::new(), Some(0));
+        write_tree(TEST_DIR, &mut tar).unwrap();
+        assert_eq!(tar.finish().unwrap(), archive);
+
+        let archive_path = [TEST_DIR_OUT, "tree.tar"].join("/");
+        fs::write(&archive_path, &archive).unwrap();
+
+        let list = Command::new("tar")
+            .args(["-tf", &archive_path])
+            .output()
+            .unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&list.stdout),
+            "a\nb/\nb/c/\nb/c/d\nb/link\n"
+        );
+
+        let extracted = [TEST_DIR_OUT, "tree"].join("/");
+        fs::create_dir_all(&extracted).unwrap();
+        let extract = Command::new("tar")
+            .args(["-xf", &archive_path, "-C", &extracted])
+            .output()
+            .unwrap();
+        assert!(extract.status.success());
+
+        let diff = Command::new("diff")
+            .args(["-r", TEST_DIR, &extracted])
+            .output()
+            .unwrap();
+        assert!(diff.status.success());
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_dir_all(TEST_DIR_OUT).unwrap();
+    }
+
+    #[test]
+    fn invalid_dir() {
+        let mut tar = TarWriter::new(Vec::new(), Some(0));
+        assert!(write_tree("/?", &mut tar).is_err());
+    }
+
+    #[test]
+    fn compressions() {
+        assert_eq!(compression("a.tar"), Some(Compression::None));
+        assert_eq!(compression("a.tar.zst"), Some(Compression::Zstd));
+        assert_eq!(compression("a.tzst"), Some(Compression::Zstd));
+        assert_eq!(compression("a.zst"), None);
+        assert_eq!(compression("a"), None);
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn zstd() {
+        const TEST_DIR: &str = "test_archive_write_tree_zstd";
+        const TEST_DIR_OUT: &str = "test_archive_write_tree_zstd_out";
+
+        fs::create_dir_all([TEST_DIR, "b"].join("/")).unwrap();
+        fs::create_dir_all(TEST_DIR_OUT).unwrap();
+        fs::write([TEST_DIR, "a"].join("/"), b"file a").unwrap();
+        fs::write([TEST_DIR, "b/c"].join("/"), vec![b'c'; 1 << 20]).unwrap();
+
+        let archive_path = [TEST_DIR_OUT, "tree.tar.zst"].join("/");
+        let options = Options {
+            deterministic: true,
+            ..Options::default()
+        };
+        assert!(copy_to_archive(TEST_DIR, &archive_path, &options).is_ok());
+        assert!(fs::metadata(&archive_path).unwrap().len() < 1 << 16);
+
+        let tar = zstd::decode_all(fs::File::open(&archive_path).unwrap()).unwrap();
+        let mut expected = TarWriter::new(Vec::new(), Some(0));
+        write_tree(TEST_DIR, &mut expected).unwrap();
+        assert_eq!(tar, expected.finish().unwrap());
+
+        let extracted = [TEST_DIR_OUT, "tree"].join("/");
+        assert!(copy_from_archive(&archive_path, &extracted, &options).is_ok());
+        let diff = Command::new("diff")
+            .args(["-r", TEST_DIR, &extracted])
+            .output()
+            .unwrap();
+        assert!(diff.status.success());
+
+        // Incomplete archives are removed
+        assert!(copy_to_archive("/?", &archive_path, &options).is_err());
+        assert!(fs::metadata(&archive_path).is_err());
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_dir_all(TEST_DIR_OUT).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod test_tar_reader {
+    use super::*;
+    use std::process::Command;
+
+    #[test]
+    fn read_entries() {
+        let metadata = EntryMetadata {
+            mode: 0o640,
+            uid: 100_000_000,
+            gid: 100,
+            mtime: 1_600_000_000,
+        };
+        let long_path = "a/".repeat(100) + "b";
+        let mut tar = TarWriter::new(Vec::new(), None);
+        tar.append_dir(Path::new("a"), metadata).unwrap();
+        tar.append_file(Path::new(&long_path), metadata, 3, &mut &b"abc"[..])
+            .unwrap();
+        tar.append_symlink(Path::new("c"), Path::new("a/b"), metadata)
+            .unwrap();
+        let archive = tar.finish().unwrap();
+
+        let mut tar = TarReader::new(&archive[..]);
+        let dir = tar.next_entry().unwrap().unwrap();
+        assert_eq!(dir.path, Path::new("a"));
+        assert_eq!(dir.kind, ArchivedKind::Dir);
+        assert_eq!(dir.metadata, metadata);
+
+        let file = tar.next_entry().unwrap().unwrap();
+        assert_eq!(file.path, Path::new(&long_path));
+        assert_eq!(file.kind, ArchivedKind::File);
+        assert_eq!(file.size, 3);
+        let mut contents = Vec::new();
+        tar.read_contents(&mut contents).unwrap();
+        assert_eq!(contents, b"abc");
+
+        // The contents of entries that are not read are skipped
+        let symlink = tar.next_entry().unwrap().unwrap();
+        assert_eq!(symlink.kind, ArchivedKind::Symlink(PathBuf::from("a/b")));
+        assert_eq!(tar.next_entry().unwrap(), None);
+    }
+
+    #[test]
+    fn invalid_archives() {
+        let mut tar = TarWriter::new(Vec::new(), None);
+        tar.append_file(
+            Path::new("a"),
+            EntryMetadata::default(),
+            3,
+            &mut &b"abc"[..],
+        )
+        .unwrap();
+        let archive = tar.finish().unwrap();
+
+        let mut corrupted = archive.clone();
+        corrupted[0] = b'b';
+        assert!(TarReader::new(&corrupted[..]).next_entry().is_err());
+
+        let mut tar = TarReader::new(&archive[..BLOCK_SIZE + 2]);
+        tar.next_entry().unwrap().unwrap();
+        assert!(tar.read_contents(&mut Vec::new()).is_err());
+
+        let mut tar = TarReader::new(&archive[..BLOCK_SIZE - 1]);
+        assert!(tar.next_entry().is_err());
+        assert_eq!(TarReader::new(&[][..]).next_entry().unwrap(), None);
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(read_octal(b"0000644\0").unwrap(), 0o644);
+        assert_eq!(read_octal(b"  644 \0\0").unwrap(), 0o644);
+        assert_eq!(read_octal(b"\0\0\0\0").unwrap(), 0);
+        assert_eq!(read_octal(&[0x80, 0, 1, 0]).unwrap(), 256);
+        assert!(read_octal(b"0000948\0").is_err());
+
+        let mut overrides = Overrides::default();
+        let mut records = pax_record("path", b"a b=c");
+        records.extend(pax_record("mtime", b"1600000000.5"));
+        records.extend(pax_record("comment", b"ignored"));
+        read_pax_records(&records, &mut overrides).unwrap();
+        assert_eq!(overrides.path, Some(b"a b=c".to_vec()));
+        assert_eq!(overrides.mtime, Some(1_600_000_000));
+        assert!(read_pax_records(b"99 path=a\n", &mut overrides).is_err());
+    }
+
+    #[test]
+    fn refuses_escaping_paths() {
+        const TEST_DIR: &str = "test_archive_refuses_escaping_paths";
+        const TEST_ARCHIVE: &str = "test_archive_refuses_escaping_paths.tar";
+
+        let mut tar = TarWriter::new(Vec::new(), None);
+        for path in &["../escaped", "/absolute", "a/../../escaped", "kept"] {
+            tar.append_file(
+                Path::new(path),
+                EntryMetadata {
+                    mode: 0o644,
+                    ..EntryMetadata::default()
+                },
+                1,
+                &mut &b"x"[..],
+            )
+            .unwrap();
+        }
+        fs::write(TEST_ARCHIVE, tar.finish().unwrap()).unwrap();
+        fs::create_dir_all(TEST_DIR).unwrap();
+
+        assert!(copy_from_archive(TEST_ARCHIVE, TEST_DIR, &Options::default()).is_err());
+        assert_eq!(fs::read([TEST_DIR, "kept"].join("/")).unwrap(), b"x");
+        assert!(fs::metadata("escaped").is_err());
+        assert!(fs::metadata("/absolute").is_err());
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_file(TEST_ARCHIVE).unwrap();
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn refuses_escaping_symlinks() {
+        const TEST_DIR: &str = "test_archive_refuses_escaping_symlinks";
+        const TEST_ARCHIVE: &str = "test_archive_refuses_escaping_symlinks.tar";
+        let path = |path: &str| [TEST_DIR, path].join("/");
+
+        let mut tar = TarWriter::new(Vec::new(), None);
+        for (link, target) in &[("up", "a/../.."), ("absolute", "/etc"), ("a/kept", "../up")] {
+            tar.append_symlink(Path::new(link), Path::new(target), EntryMetadata::default())
+                .unwrap();
+        }
+        fs::write(TEST_ARCHIVE, tar.finish().unwrap()).unwrap();
+
+        // Only symlinks that stay inside dest are extracted
+        assert!(copy_from_archive(TEST_ARCHIVE, TEST_DIR, &Options::default()).is_err());
+        assert_eq!(fs::read_link(path("a/kept")).unwrap(), Path::new("../up"));
+        assert!(fs::symlink_metadata(path("up")).is_err());
+        assert!(fs::symlink_metadata(path("absolute")).is_err());
+
+        // Unless the sandbox is turned off
+        let options = Options {
+            sandbox: sandbox::SandboxMode::Never,
+            ..Options::default()
+        };
+        assert!(copy_from_archive(TEST_ARCHIVE, TEST_DIR, &options).is_ok());
+        assert_eq!(fs::read_link(path("absolute")).unwrap(), Path::new("/etc"));
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_file(TEST_ARCHIVE).unwrap();
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn extract_tree() {
+        use std::os::unix::fs::{symlink, MetadataExt, PermissionsExt};
+
+        const TEST_DIR: &str = "test_archive_extract_tree";
+        const TEST_ARCHIVE: &str = "test_archive_extract_tree.tar";
+        const TEST_DIR_OUT: &str = "test_archive_extract_tree_out";
+
+        let long_dir = [TEST_DIR, &"d".repeat(90), &"e".repeat(90)].join("/");
+        fs::create_dir_all(&long_dir).unwrap();
+        fs::create_dir_all([TEST_DIR, "read_only"].join("/")).unwrap();
+        fs::write([&long_dir, "a"].join("/"), b"file a").unwrap();
+        fs::write([TEST_DIR, "read_only/b"].join("/"), b"file b").unwrap();
+        fs::set_permissions(
+            [TEST_DIR, "read_only/b"].join("/"),
+            fs::Permissions::from_mode(0o600),
+        )
+        .unwrap();
+        symlink("read_only/b", [TEST_DIR, "link"].join("/")).unwrap();
+        fs::set_permissions(
+            [TEST_DIR, "read_only"].join("/"),
+            fs::Permissions::from_mode(0o555),
+        )
+        .unwrap();
+
+        assert!(copy_to_archive(TEST_DIR, TEST_ARCHIVE, &Options::default()).is_ok());
+        assert!(copy_from_archive(TEST_ARCHIVE, TEST_DIR_OUT, &Options::default()).is_ok());
+
+        let diff = Command::new("diff")
+            .args(["-r", TEST_DIR, TEST_DIR_OUT])
+            .output()
+            .unwrap();
+        assert!(diff.status.success());
+        for path in &["read_only", "read_only/b", "link"] {
+            let src = fs::symlink_metadata([TEST_DIR, path].join("/")).unwrap();
+            let dest = fs::symlink_metadata([TEST_DIR_OUT, path].join("/")).unwrap();
+            assert_eq!(src.mode(), dest.mode(), "{}", path);
+            assert_eq!(src.mtime(), dest.mtime(), "{}", path);
+        }
+
+        for dir in &[TEST_DIR, TEST_DIR_OUT] {
+            fs::set_permissions(
+                [dir, "read_only"].join("/"),
+                fs::Permissions::from_mode(0o755),
+            )
+            .unwrap();
+            fs::remove_dir_all(dir).unwrap();
+        }
+        fs::remove_file(TEST_ARCHIVE).unwrap();
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn hard_links() {
+        const TEST_DIR: &str = "test_archive_hard_links";
+        const TEST_ARCHIVE: &str = "test_archive_hard_links.tar";
+        const TEST_DIR_OUT: &str = "test_archive_hard_links_out";
+
+        fs::create_dir_all(TEST_DIR).unwrap();
+        fs::write([TEST_DIR, "a"].join("/"), b"file a").unwrap();
+        fs::hard_link([TEST_DIR, "a"].join("/"), [TEST_DIR, "b"].join("/")).unwrap();
+
+        // GNU tar archives the second name of a file as a hard link to the first
+        let archived = Command::new("tar")
+            .args(["--format=gnu", "-cf", TEST_ARCHIVE, "-C", TEST_DIR, "."])
+            .output();
+        if archived.is_ok_and(|output| output.status.success()) {
+            assert!(copy_from_archive(TEST_ARCHIVE, TEST_DIR_OUT, &Options::default()).is_ok());
+            assert_eq!(fs::read([TEST_DIR_OUT, "a"].join("/")).unwrap(), b"file a");
+            assert_eq!(fs::read([TEST_DIR_OUT, "b"].join("/")).unwrap(), b"file a");
+            fs::remove_dir_all(TEST_DIR_OUT).unwrap();
+        }
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_file(TEST_ARCHIVE).ok();
+    }
+}
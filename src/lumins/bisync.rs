@@ -0,0 +1,778 @@
+//! Synchronizes two directories in both directions
+//!
+//! The state of every entry on both sides is stored after each bisync. On the next bisync, an
+//! entry that differs from its stored state changed on that side, which tells "new on one side"
+//! apart from "deleted on the other". Entries that changed on both sides are conflicts.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+
+use clap::ValueEnum;
+use hashbrown::{HashMap, HashSet};
+use log::{debug, error, info};
+use rayon::prelude::*;
+
+use crate::lumins::core;
+use crate::lumins::file_ops::{self, Dir, File, FileOps, FileSets, Symlink};
+use crate::lumins::journal::{escape_path, unescape_path};
+use crate::lumins::parse::Options;
+use crate::progress::{self, PROGRESS_BAR};
+
+/// Name of the state of the last bisync, which is kept in the root of both directories
+pub const STATE_NAME: &str = ".lumins-bisync";
+
+/// Enum to represent which side wins conflicts, with `--prefer`
+#[derive(Eq, PartialEq, Debug, Clone, Copy, ValueEnum)]
+pub enum Prefer {
+    /// The first directory wins
+    A,
+    /// The second directory wins
+    B,
+    /// The side with the newer modification time wins
+    Newer,
+}
+
+/// Enum to represent the type of an entry
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+enum Kind {
+    File,
+    Dir,
+    Symlink,
+}
+
+/// The state of an entry on one side, which is its type, and for files and symlinks, its size
+/// and modification time in nanoseconds since the Unix epoch
+///
+/// Directories have no size or modification time, since their modification time changes
+/// whenever their contents do.
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+struct EntryState {
+    kind: Kind,
+    size: u64,
+    mtime: u128,
+}
+
+/// The states of an entry on both sides after the last bisync
+type SyncedState = (EntryState, EntryState);
+
+/// Enum to represent an entry on one side
+#[derive(Clone, Copy)]
+enum Item<'a> {
+    File(&'a File),
+    Dir(&'a Dir),
+    Symlink(&'a Symlink),
+}
+
+impl Item<'_> {
+    fn kind(&self) -> Kind {
+        match self {
+            Item::File(_) => Kind::File,
+            Item::Dir(_) => Kind::Dir,
+            Item::Symlink(_) => Kind::Symlink,
+        }
+    }
+
+    fn copy(&self, src: &str, dest: &str, options: &Options) -> bool {
+        let (src, dest) = (Path::new(src), Path::new(dest));
+        match self {
+            Item::File(file) => file.copy(src, dest, options),
+            Item::Dir(dir) => dir.copy(src, dest, options),
+            Item::Symlink(symlink) => symlink.copy(src, dest, options),
+        }
+    }
+
+    fn remove(&self, location: &str) {
+        let location = Path::new(location);
+        match self {
+            Item::File(file) => file.remove(location),
+            Item::Dir(dir) => dir.remove(location),
+            Item::Symlink(symlink) => symlink.remove(location),
+        }
+    }
+}
+
+/// A struct that represents the entries of one side, and their states
+struct Side<'a> {
+    root: &'a str,
+    entries: HashMap<&'a Path, (Item<'a>, EntryState)>,
+}
+
+impl<'a> Side<'a> {
+    /// Gets the state of every entry in `file_sets`, which were found in `root`
+    fn scan(root: &'a str, file_sets: &'a FileSets) -> Self {
+        let items: Vec<(&Path, Item)> = file_sets
+            .files()
+            .iter()
+            .map(|file| (file.path().as_path(), Item::File(file)))
+            .chain(
+                file_sets
+                    .dirs()
+                    .iter()
+                    .map(|dir| (dir.path().as_path(), Item::Dir(dir))),
+            )
+            .chain(
+                file_sets
+                    .symlinks()
+                    .iter()
+                    .map(|symlink| (symlink.path().as_path(), Item::Symlink(symlink))),
+            )
+            .collect();
+
+        let entries = items
+            .into_par_iter()
+            .filter_map(|(path, item)| {
+                let state = entry_state(&Path::new(root).join(path))?;
+                // Skip entries that changed type since they were found
+                if state.kind == item.kind() {
+                    Some((path, (item, state)))
+                } else {
+                    None
+                }
+            })
+            .collect();
+
+        Side { root, entries }
+    }
+
+    fn item(&self, path: &Path) -> Option<Item<'a>> {
+        self.entries.get(path).map(|(item, _)| *item)
+    }
+
+    fn state(&self, path: &Path) -> Option<EntryState> {
+        self.entries.get(path).map(|(_, state)| *state)
+    }
+}
+
+/// Enum to represent how the sides are brought back in sync for an entry
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+enum Resolution {
+    /// Nothing to do, since the entry is the same on both sides
+    Keep,
+    /// Make `b` match `a`, by copying the entry from `a` or deleting it from `b`
+    AToB,
+    /// Make `a` match `b`, by copying the entry from `b` or deleting it from `a`
+    BToA,
+    /// The entry changed on both sides, and there is no preference to resolve it with
+    Conflict,
+}
+
+/// Synchronizes `a` and `b` with each other, propagating the changes made on each side since the
+/// last bisync to the other side
+///
+/// Without a stored state, such as on the first bisync, `a` and `b` are merged: entries on only one
+/// side are copied to the other, and nothing is deleted. Entries that changed on both sides are
+/// resolved by `options.prefer`, see `Prefer`, and are otherwise left untouched as conflicts. With
+/// `Options::heal_empty_files`, files that are empty on only one side are always replaced by the
+/// other side.
+///
+/// # Arguments
+/// * `a`: First directory
+/// * `b`: Second directory
+/// * `options`: Options of the run
+///
+/// # Errors
+/// This function will return an error in the following situations,
+/// but is not limited to just these cases:
+/// * `a` or `b` is an invalid directory
+/// * The state could not be written
+/// * Some entries were left unresolved as conflicts
+pub fn bisync(a: &str, b: &str, options: &Options) -> Result<(), io::Error> {
+    let options = &core::for_run(options);
+    let synced = load_state(a, b);
+
+    let mut a_file_sets = file_ops::get_all_files(a)?;
+    a_file_sets.remove_file(Path::new(STATE_NAME));
+    let mut b_file_sets = file_ops::get_all_files(b)?;
+    b_file_sets.remove_file(Path::new(STATE_NAME));
+    let (side_a, side_b) = rayon::join(
+        || Side::scan(a, &a_file_sets),
+        || Side::scan(b, &b_file_sets),
+    );
+
+    let resolutions = plan(&side_a, &side_b, &synced, options);
+    let conflicts: Vec<&Path> = resolutions
+        .iter()
+        .filter(|(_, resolution)| **resolution == Resolution::Conflict)
+        .map(|(path, _)| *path)
+        .collect();
+    for path in &conflicts {
+        error!(
+            "Conflict -- {:?} changed in both {} and {}",
+            path,
+            Path::new(a).join(path).display(),
+            Path::new(b).join(path).display()
+        );
+    }
+
+    let failed = apply(&resolutions, &side_a, &side_b, options);
+
+    // Record the new state of every entry that is now the same on both sides
+    let mut state = Vec::new();
+    for (&path, &resolution) in &resolutions {
+        let entry = match resolution {
+            Resolution::Keep => side_a.state(path).zip(side_b.state(path)),
+            Resolution::AToB | Resolution::BToA if !failed.contains(path) => {
+                match (
+                    entry_state(&Path::new(a).join(path)),
+                    entry_state(&Path::new(b).join(path)),
+                ) {
+                    (Some(a_state), Some(b_state)) if a_state.kind == b_state.kind => {
+                        Some((a_state, b_state))
+                    }
+                    // Deleted from both sides
+                    (None, None) => None,
+                    // The entry could not be deleted, so try again next time
+                    _ => synced.get(path).copied(),
+                }
+            }
+            // Nothing was changed, so the entry is still as it was after the last bisync
+            _ => synced.get(path).copied(),
+        };
+        if let Some(entry) = entry {
+            state.push((path, entry));
+        }
+    }
+    state.sort_unstable_by(|x, y| x.0.cmp(y.0));
+    write_state(a, b, &state)?;
+
+    if conflicts.is_empty() {
+        Ok(())
+    } else {
+        Err(io::Error::other(format!(
+            "Bisync Error -- {} conflict(s) were left unresolved; rerun with --prefer to \
+             resolve them",
+            conflicts.len()
+        )))
+    }
+}
+
+/// Decides how to resolve every entry that is on either side, or was in the last bisync
+fn plan<'a>(
+    side_a: &Side<'a>,
+    side_b: &Side<'a>,
+    synced: &'a HashMap<PathBuf, SyncedState>,
+    options: &Options,
+) -> HashMap<&'a Path, Resolution> {
+    let mut paths: HashSet<&Path> = side_a.entries.keys().copied().collect();
+    paths.extend(side_b.entries.keys().copied());
+    paths.extend(synced.keys().map(PathBuf::as_path));
+
+    let mut resolutions: HashMap<&Path, Resolution> = paths
+        .into_par_iter()
+        .map(|path| {
+            let a = side_a.state(path);
+            let b = side_b.state(path);
+            let synced = synced.get(path);
+            let a_changed = a != synced.map(|s| s.0);
+            let b_changed = b != synced.map(|s| s.1);
+
+            // Empty files are healed whether or not they changed
+            let healed = if options.heal_empty_files {
+                heal(a, b)
+            } else {
+                None
+            };
+            if healed.is_some() {
+                debug!("{:?} is healed, since it is empty on only one side", path);
+            }
+
+            let resolution = healed.unwrap_or_else(|| match (a_changed, b_changed) {
+                (false, false) => Resolution::Keep,
+                (true, false) => Resolution::AToB,
+                (false, true) => Resolution::BToA,
+                (true, true) if is_same(path, side_a, side_b, options) => Resolution::Keep,
+                (true, true) => prefer(a, b, options),
+            });
+            (path, resolution)
+        })
+        .collect();
+
+    // A directory can not be deleted from one side while entries are copied into it on the
+    // other side, so it is copied back instead
+    let mut restored = Vec::new();
+    for (&path, &resolution) in &resolutions {
+        let (src, deleting) = match resolution {
+            Resolution::AToB if side_a.item(path).is_some() => (side_a, Resolution::BToA),
+            Resolution::BToA if side_b.item(path).is_some() => (side_b, Resolution::AToB),
+            _ => continue,
+        };
+        for ancestor in path.ancestors().skip(1) {
+            if resolutions.get(ancestor) == Some(&deleting) && src.item(ancestor).is_some() {
+                let restore = if deleting == Resolution::BToA {
+                    Resolution::AToB
+                } else {
+                    Resolution::BToA
+                };
+                restored.push((ancestor, restore));
+            }
+        }
+    }
+    resolutions.extend(restored);
+
+    resolutions
+}
+
+/// Checks whether the entry at `path` is identical on both sides, even though it changed on both
+fn is_same(path: &Path, side_a: &Side, side_b: &Side, options: &Options) -> bool {
+    match (side_a.item(path), side_b.item(path)) {
+        (None, None) => true,
+        (Some(Item::Dir(_)), Some(Item::Dir(_))) => true,
+        (Some(Item::Symlink(a)), Some(Item::Symlink(b))) => a.target() == b.target(),
+        (Some(Item::File(a)), Some(Item::File(b))) if a.size() == b.size() => {
+            if options.secure {
+                let a_hash = file_ops::hash_file_secure(a, side_a.root);
+                a_hash.is_some() && a_hash == file_ops::hash_file_secure(b, side_b.root)
+            } else {
+                let a_hash = file_ops::hash_file(a, side_a.root);
+                a_hash.is_some() && a_hash == file_ops::hash_file(b, side_b.root)
+            }
+        }
+        _ => false,
+    }
+}
+
+/// Resolves a file that is empty on one side, but has content on the other, such as one left
+/// behind by a failed copy, which is always stale whatever its modification time
+///
+/// # Returns
+/// * Some: The resolution that copies the file with content over the empty file
+/// * None: If the entry is not a file on both sides, or is not empty on exactly one side
+fn heal(a: Option<EntryState>, b: Option<EntryState>) -> Option<Resolution> {
+    match (a, b) {
+        (Some(a), Some(b)) if a.kind == Kind::File && b.kind == Kind::File => {
+            match (a.size, b.size) {
+                (0, size) if size > 0 => Some(Resolution::BToA),
+                (size, 0) if size > 0 => Some(Resolution::AToB),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Resolves an entry that changed differently on both sides, with the preference in `options`
+///
+/// With `Prefer::Newer`, the side that was modified last wins, and a modification always wins
+/// over a deletion.
+fn prefer(a: Option<EntryState>, b: Option<EntryState>, options: &Options) -> Resolution {
+    match options.prefer {
+        Some(Prefer::A) => Resolution::AToB,
+        Some(Prefer::B) => Resolution::BToA,
+        Some(Prefer::Newer) => match (a, b) {
+            (Some(a), Some(b)) if b.mtime > a.mtime => Resolution::BToA,
+            (None, Some(_)) => Resolution::BToA,
+            _ => Resolution::AToB,
+        },
+        None => Resolution::Conflict,
+    }
+}
+
+/// An entry to copy from a source root over a destination root, as its path, its source and
+/// destination entries, either of which may be missing, and the source and destination roots
+type Action<'a, 'b> = (
+    &'a Path,
+    Option<Item<'b>>,
+    Option<Item<'b>>,
+    &'b str,
+    &'b str,
+);
+
+/// Brings both sides in sync by applying `resolutions`
+///
+/// Entries to replace or delete are removed first, then directories are created, and then files
+/// and symlinks are copied.
+///
+/// # Returns
+/// The paths of the entries that could not be copied
+fn apply<'a>(
+    resolutions: &HashMap<&'a Path, Resolution>,
+    side_a: &Side<'_>,
+    side_b: &Side<'_>,
+    options: &Options,
+) -> HashSet<&'a Path> {
+    let mut actions: Vec<Action> = resolutions
+        .iter()
+        .filter_map(|(&path, resolution)| match resolution {
+            Resolution::AToB => Some((
+                path,
+                side_a.item(path),
+                side_b.item(path),
+                side_a.root,
+                side_b.root,
+            )),
+            Resolution::BToA => Some((
+                path,
+                side_b.item(path),
+                side_a.item(path),
+                side_b.root,
+                side_a.root,
+            )),
+            _ => None,
+        })
+        .collect();
+    actions.sort_unstable_by(|x, y| x.0.cmp(y.0));
+    progress::progress_init(actions.len() as u64);
+
+    // Files are copied over files, but everything else is removed and created again
+    let is_replaced = |src: Option<Item>, dest: Item| match src {
+        Some(Item::File(_)) => dest.kind() != Kind::File,
+        Some(Item::Dir(_)) => dest.kind() != Kind::Dir,
+        _ => true,
+    };
+
+    actions
+        .par_iter()
+        .filter(|(_, src, dest, _, _)| {
+            dest.is_some_and(|dest| dest.kind() != Kind::Dir && is_replaced(*src, dest))
+        })
+        .for_each(|(_, _, dest, _, dest_root)| dest.unwrap().remove(dest_root));
+
+    // Remove directories after their contents, and create them before their contents
+    for (_, src, dest, _, dest_root) in actions.iter().rev() {
+        if let Some(dest) = dest.filter(|dest| dest.kind() == Kind::Dir) {
+            if is_replaced(*src, dest) {
+                dest.remove(dest_root);
+            }
+        }
+    }
+
+    let failed = Mutex::new(HashSet::new());
+    let copy = |(path, src, _, src_root, dest_root): &Action<'a, '_>| {
+        if let Some(src) = src {
+            if !src.copy(src_root, dest_root, options) {
+                failed
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .insert(*path);
+            }
+        }
+        PROGRESS_BAR.inc(1);
+    };
+    let (dirs, others): (Vec<_>, Vec<_>) = actions
+        .iter()
+        .partition(|(_, src, _, _, _)| src.is_some_and(|src| src.kind() == Kind::Dir));
+    dirs.into_iter().for_each(copy);
+    others.into_par_iter().for_each(copy);
+    // Files that were still being written are copied once the others are, but stay failed, so
+    // that the next bisync compares them again
+    file_ops::copy_deferred(options);
+
+    failed.into_inner().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Gets the state of the entry at `path`, without following symlinks
+fn entry_state(path: &Path) -> Option<EntryState> {
+    let metadata = fs::symlink_metadata(path).ok()?;
+    let file_type = metadata.file_type();
+    let kind = if file_type.is_dir() {
+        Kind::Dir
+    } else if file_type.is_symlink() {
+        Kind::Symlink
+    } else {
+        Kind::File
+    };
+    if kind == Kind::Dir {
+        return Some(EntryState {
+            kind,
+            size: 0,
+            mtime: 0,
+        });
+    }
+
+    let mtime = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    Some(EntryState {
+        kind,
+        size: metadata.len(),
+        mtime: mtime.as_nanos(),
+    })
+}
+
+/// Loads the state of the last bisync of `a` and `b`
+///
+/// The state is only trusted if both sides have the same one, so that a side that was bisynced
+/// with another directory, or restored from a backup, is merged instead of having its entries
+/// deleted.
+fn load_state(a: &str, b: &str) -> HashMap<PathBuf, SyncedState> {
+    let a_state = fs::read(Path::new(a).join(STATE_NAME));
+    let b_state = fs::read(Path::new(b).join(STATE_NAME));
+    match (a_state, b_state) {
+        (Ok(a_state), Ok(b_state)) if a_state == b_state => a_state
+            .split(|&byte| byte == b'\n')
+            .filter_map(parse_line)
+            .collect(),
+        (Err(_), Err(_)) => HashMap::new(),
+        _ => {
+            info!(
+                "No matching bisync state in {} and {}, merging them instead",
+                a, b
+            );
+            HashMap::new()
+        }
+    }
+}
+
+/// Writes `state` into both `a` and `b`
+///
+/// # Errors
+/// This function will return an error if the state could not be written to either side
+fn write_state(a: &str, b: &str, state: &[(&Path, SyncedState)]) -> Result<(), io::Error> {
+    let mut contents = Vec::new();
+    for (path, (a_state, b_state)) in state {
+        if let Some(path) = escape_path(path) {
+            contents.extend(format_state(a_state).bytes());
+            contents.push(b' ');
+            contents.extend(format_state(b_state).bytes());
+            contents.push(b' ');
+            contents.extend(path);
+            contents.push(b'\n');
+        }
+    }
+
+    for root in &[a, b] {
+        let path = Path::new(root).join(STATE_NAME);
+        fs::write(&path, &contents)
+            .map_err(|e| io::Error::new(e.kind(), format!("Bisync Error -- {:?}: {}", path, e)))?;
+    }
+    Ok(())
+}
+
+/// Formats an entry state as `<type> <size> <mtime>`
+fn format_state(state: &EntryState) -> String {
+    let kind = match state.kind {
+        Kind::File => 'f',
+        Kind::Dir => 'd',
+        Kind::Symlink => 'l',
+    };
+    format!("{} {} {}", kind, state.size, state.mtime)
+}
+
+/// Parses a state line, `<a state> <b state> <escaped path>`
+fn parse_line(line: &[u8]) -> Option<(PathBuf, SyncedState)> {
+    let mut fields = line.splitn(7, |&b| b == b' ');
+    let mut state = || -> Option<EntryState> {
+        let kind = match fields.next()? {
+            b"f" => Kind::File,
+            b"d" => Kind::Dir,
+            b"l" => Kind::Symlink,
+            _ => return None,
+        };
+        let size = std::str::from_utf8(fields.next()?).ok()?.parse().ok()?;
+        let mtime = std::str::from_utf8(fields.next()?).ok()?.parse().ok()?;
+        Some(EntryState { kind, size, mtime })
+    };
+    let a_state = state()?;
+    let b_state = state()?;
+    let path = unescape_path(fields.next()?)?;
+    Some((path, (a_state, b_state)))
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_bisync {
+    use super::*;
+
+    fn setup(test_dir: &str) -> (String, String) {
+        let a = format!("{}/a", test_dir);
+        let b = format!("{}/b", test_dir);
+        fs::create_dir_all(&a).unwrap();
+        fs::create_dir_all(&b).unwrap();
+        (a, b)
+    }
+
+    fn read(path: &str) -> Option<String> {
+        fs::read_to_string(path).ok()
+    }
+
+    #[test]
+    fn invalid_dirs() {
+        assert!(bisync("/?", "src", &Options::default()).is_err());
+    }
+
+    #[test]
+    fn first_run_merges() {
+        const TEST_DIR: &str = "test_bisync_first_run_merges";
+        let (a, b) = setup(TEST_DIR);
+        fs::write(format!("{}/x", a), "x").unwrap();
+        fs::create_dir_all(format!("{}/d", b)).unwrap();
+        fs::write(format!("{}/d/y", b), "y").unwrap();
+
+        assert!(bisync(&a, &b, &Options::default()).is_ok());
+
+        for side in &[&a, &b] {
+            assert_eq!(read(&format!("{}/x", side)).as_deref(), Some("x"));
+            assert_eq!(read(&format!("{}/d/y", side)).as_deref(), Some("y"));
+        }
+        assert_eq!(
+            fs::read(format!("{}/{}", a, STATE_NAME)).unwrap(),
+            fs::read(format!("{}/{}", b, STATE_NAME)).unwrap()
+        );
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+
+    #[test]
+    fn propagates_changes() {
+        const TEST_DIR: &str = "test_bisync_propagates_changes";
+        let (a, b) = setup(TEST_DIR);
+        fs::write(format!("{}/x", a), "x").unwrap();
+        fs::write(format!("{}/y", a), "y").unwrap();
+        assert!(bisync(&a, &b, &Options::default()).is_ok());
+
+        fs::write(format!("{}/x", a), "modified").unwrap();
+        fs::remove_file(format!("{}/y", b)).unwrap();
+        fs::write(format!("{}/z", b), "z").unwrap();
+        assert!(bisync(&a, &b, &Options::default()).is_ok());
+
+        for side in &[&a, &b] {
+            assert_eq!(read(&format!("{}/x", side)).as_deref(), Some("modified"));
+            assert_eq!(read(&format!("{}/y", side)), None);
+            assert_eq!(read(&format!("{}/z", side)).as_deref(), Some("z"));
+        }
+
+        // Nothing changed since, so nothing is copied or deleted
+        assert!(bisync(&a, &b, &Options::default()).is_ok());
+        assert_eq!(read(&format!("{}/z", a)).as_deref(), Some("z"));
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+
+    #[test]
+    fn conflicts() {
+        const TEST_DIR: &str = "test_bisync_conflicts";
+        let (a, b) = setup(TEST_DIR);
+        fs::write(format!("{}/x", a), "x").unwrap();
+        assert!(bisync(&a, &b, &Options::default()).is_ok());
+
+        // Unresolved conflicts are left untouched
+        fs::write(format!("{}/x", a), "from a").unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        fs::write(format!("{}/x", b), "newer from b").unwrap();
+        assert!(bisync(&a, &b, &Options::default()).is_err());
+        assert_eq!(read(&format!("{}/x", a)).as_deref(), Some("from a"));
+        assert_eq!(read(&format!("{}/x", b)).as_deref(), Some("newer from b"));
+
+        let options = Options {
+            prefer: Some(Prefer::Newer),
+            ..Options::default()
+        };
+        assert!(bisync(&a, &b, &options).is_ok());
+        assert_eq!(read(&format!("{}/x", a)).as_deref(), Some("newer from b"));
+
+        // Deleted on b, but modified on a
+        fs::write(format!("{}/x", a), "modified on a").unwrap();
+        fs::remove_file(format!("{}/x", b)).unwrap();
+        let options = Options {
+            prefer: Some(Prefer::B),
+            ..Options::default()
+        };
+        assert!(bisync(&a, &b, &options).is_ok());
+        assert_eq!(read(&format!("{}/x", a)), None);
+
+        fs::write(format!("{}/y", a), "a").unwrap();
+        fs::write(format!("{}/y", b), "b").unwrap();
+        let options = Options {
+            prefer: Some(Prefer::A),
+            ..Options::default()
+        };
+        assert!(bisync(&a, &b, &options).is_ok());
+        assert_eq!(read(&format!("{}/y", b)).as_deref(), Some("a"));
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+
+    #[test]
+    fn heals_empty_files() {
+        const TEST_DIR: &str = "test_bisync_heals_empty_files";
+        let (a, b) = setup(TEST_DIR);
+        fs::write(format!("{}/x", a), "x").unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        fs::write(format!("{}/x", b), "").unwrap();
+
+        // The empty file is stale, even though it is newer
+        let options = Options {
+            prefer: Some(Prefer::Newer),
+            heal_empty_files: true,
+            ..Options::default()
+        };
+        assert!(bisync(&a, &b, &options).is_ok());
+        for side in &[&a, &b] {
+            assert_eq!(read(&format!("{}/x", side)).as_deref(), Some("x"));
+        }
+
+        // Files that were emptied since the last bisync are healed too
+        fs::write(format!("{}/x", a), "").unwrap();
+        assert!(bisync(&a, &b, &options).is_ok());
+        assert_eq!(read(&format!("{}/x", a)).as_deref(), Some("x"));
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+
+    #[test]
+    fn revives_deleted_dirs() {
+        const TEST_DIR: &str = "test_bisync_revives_deleted_dirs";
+        let (a, b) = setup(TEST_DIR);
+        fs::create_dir_all(format!("{}/d", a)).unwrap();
+        fs::write(format!("{}/d/x", a), "x").unwrap();
+        assert!(bisync(&a, &b, &Options::default()).is_ok());
+
+        // A file added to a dir on b keeps the dir that was deleted on a
+        fs::remove_dir_all(format!("{}/d", a)).unwrap();
+        fs::write(format!("{}/d/y", b), "y").unwrap();
+        assert!(bisync(&a, &b, &Options::default()).is_ok());
+
+        for side in &[&a, &b] {
+            assert_eq!(read(&format!("{}/d/x", side)), None);
+            assert_eq!(read(&format!("{}/d/y", side)).as_deref(), Some("y"));
+        }
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+
+    #[test]
+    fn state() {
+        const TEST_DIR: &str = "test_bisync_state";
+        let (a, b) = setup(TEST_DIR);
+        let file = EntryState {
+            kind: Kind::File,
+            size: 3,
+            mtime: 1_600_000_000_123_456_789,
+        };
+        let dir = EntryState {
+            kind: Kind::Dir,
+            size: 0,
+            mtime: 0,
+        };
+        let symlink = EntryState {
+            kind: Kind::Symlink,
+            size: 1,
+            mtime: 1,
+        };
+        let state = [
+            (Path::new("d"), (dir, dir)),
+            (Path::new("d/with space\nand newline"), (file, file)),
+            (Path::new("l"), (symlink, symlink)),
+        ];
+        write_state(&a, &b, &state).unwrap();
+
+        let loaded = load_state(&a, &b);
+        assert_eq!(loaded.len(), state.len());
+        for (path, entry) in &state {
+            assert_eq!(loaded.get(*path), Some(entry));
+        }
+
+        // A state that differs between the sides is not trusted
+        fs::write(format!("{}/{}", b, STATE_NAME), "f 1 1 f 1 1 x\n").unwrap();
+        assert!(load_state(&a, &b).is_empty());
+        assert!(parse_line(b"f 1 1 d 0 0 x").is_some());
+        assert!(parse_line(b"x 1 1 d 0 0 x").is_none());
+        assert!(parse_line(b"f 1 1 d 0 x").is_none());
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+}
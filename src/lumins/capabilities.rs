@@ -0,0 +1,349 @@
+//! Probes what the filesystem of a destination supports before a run writes to it, and turns off
+//! what it does not support
+//!
+//! FAT and exFAT have no symlinks, hard links, permissions, or extended attributes, and FAT only
+//! keeps modification times to 2 seconds, so a run into them would otherwise fail on every entry
+//! that needs one of them, or keep finding times that differ. A run instead probes its
+//! destination once, leaves out its symlinks, and turns off what the destination can not keep,
+//! logging each thing that it turned off.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::warn;
+
+use crate::lumins::dirfd;
+use crate::lumins::fidelity::{self, Loss};
+use crate::lumins::file_ops::{FileOps, FileSets};
+use crate::lumins::parse::Options;
+use crate::lumins::platform;
+
+/// The resolutions that filesystems keep modification times to, from finest to coarsest, such as
+/// 100ns for NTFS, 10ms for exFAT, and 2s for FAT
+const RESOLUTIONS: &[Duration] = &[
+    Duration::from_nanos(1),
+    Duration::from_nanos(100),
+    Duration::from_micros(1),
+    Duration::from_millis(1),
+    Duration::from_millis(10),
+    Duration::from_secs(1),
+    Duration::from_secs(2),
+];
+
+/// A struct that represents what the filesystem of a destination supports
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub struct Capabilities {
+    pub symlinks: bool,
+    pub hard_links: bool,
+    pub permissions: bool,
+    pub xattrs: bool,
+    /// The resolution that modification times are kept to
+    pub mtime_resolution: Duration,
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Capabilities {
+            symlinks: true,
+            hard_links: true,
+            permissions: true,
+            xattrs: true,
+            mtime_resolution: RESOLUTIONS[0],
+        }
+    }
+}
+
+/// A temporary directory in a destination that probes are made in, which is removed again with
+/// everything in it once it is dropped, however the probe ends
+struct ProbeDir(PathBuf);
+
+impl ProbeDir {
+    /// Creates a temporary directory in `dest`
+    fn create(dest: &Path) -> Result<Self, io::Error> {
+        let dir = dest.join(dirfd::temp_name());
+        fs::create_dir(&dir)?;
+        Ok(ProbeDir(dir))
+    }
+}
+
+impl Drop for ProbeDir {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_dir_all(&self.0) {
+            warn!("Capability Warning -- Could not remove {:?}: {}", self.0, e);
+        }
+    }
+}
+
+impl Capabilities {
+    /// Probes what the filesystem of the directory `dest` supports, by trying each of them on
+    /// files in a temporary directory in `dest`, which is removed again
+    ///
+    /// # Errors
+    /// This function will return an error if the temporary directory or its file could not be
+    /// created
+    pub fn probe(dest: &Path) -> Result<Self, io::Error> {
+        let dir = ProbeDir::create(dest)?;
+        Capabilities::probe_in(&dir.0)
+    }
+
+    /// Probes what the filesystem of the empty directory `dir` supports
+    fn probe_in(dir: &Path) -> Result<Self, io::Error> {
+        let file = dir.join("file");
+        fs::write(&file, b"")?;
+
+        #[cfg(target_family = "unix")]
+        let symlinks = std::os::unix::fs::symlink("file", dir.join("symlink")).is_ok();
+        #[cfg(not(target_family = "unix"))]
+        let symlinks = std::os::windows::fs::symlink_file("file", dir.join("symlink")).is_ok();
+
+        let hard_links = fs::hard_link(&file, dir.join("hard_link")).is_ok();
+
+        // Filesystems without permissions refuse other modes, or give every file the same one
+        #[cfg(target_family = "unix")]
+        let permissions = {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&file, fs::Permissions::from_mode(0o604)).is_ok()
+                && fs::metadata(&file)?.permissions().mode() & 0o777 == 0o604
+        };
+        #[cfg(not(target_family = "unix"))]
+        let permissions = true;
+
+        #[cfg(target_family = "unix")]
+        let xattrs = !xattr::SUPPORTED_PLATFORM || xattr::set(&file, "user.lumins", b"1").is_ok();
+        #[cfg(not(target_family = "unix"))]
+        let xattrs = true;
+
+        // An odd number of seconds and nanoseconds, so that any rounding of it shows
+        let modified = UNIX_EPOCH + Duration::new(1_000_000_001, 123_456_789);
+        platform::set_modified(&file, modified)?;
+        let kept = fs::metadata(&file)?.modified()?;
+        let difference = kept
+            .duration_since(modified)
+            .or_else(|_| modified.duration_since(kept))
+            .unwrap_or_default();
+
+        Ok(Capabilities {
+            symlinks,
+            hard_links,
+            permissions,
+            xattrs,
+            mtime_resolution: resolution(difference),
+        })
+    }
+
+    /// Checks whether the modification time `a` is at or before `b`, once both are kept to the
+    /// resolution of modification times
+    pub fn modified_by(&self, a: SystemTime, b: SystemTime) -> bool {
+        a <= b + self.mtime_resolution - RESOLUTIONS[0]
+    }
+}
+
+/// Finds the coarsest resolution that a modification time could be rounded by `difference` to
+fn resolution(difference: Duration) -> Duration {
+    RESOLUTIONS
+        .iter()
+        .copied()
+        .find(|resolution| difference < *resolution)
+        .unwrap_or(RESOLUTIONS[RESOLUTIONS.len() - 1])
+}
+
+/// Probes the capabilities of `dest`, unless an earlier run already did, and turns off what
+/// `options` selects that `dest` does not support, see `downgrade`
+///
+/// Dry runs write nothing to `dest`, so they do not probe it either.
+///
+/// # Returns
+/// The options to run with, which are none if `options` already has the capabilities of `dest`,
+/// if it is a dry run, or if they could not be probed, in which case everything is assumed to be
+/// supported
+pub fn probe(dest: &str, options: &Options) -> Option<Options> {
+    if options.capabilities.is_some() || options.dry_run || !Path::new(dest).is_dir() {
+        return None;
+    }
+    match Capabilities::probe(Path::new(dest)) {
+        Ok(capabilities) => Some(downgrade(capabilities, dest, options)),
+        Err(e) => {
+            warn!(
+                "Capability Warning -- Could not probe what {} supports, so everything is \
+                 assumed to be: {}",
+                dest, e
+            );
+            None
+        }
+    }
+}
+
+/// Turns off what `options` selects that `capabilities` of `dest` do not support, logging each
+/// thing that is turned off
+///
+/// Symlinks are left out by `unlist_symlinks`, permissions are not copied, and modification times
+/// are compared to the resolution of `dest`, which all go by the capabilities in the options.
+pub fn downgrade(capabilities: Capabilities, dest: &str, options: &Options) -> Options {
+    let mut options = options.clone();
+    let report = |what: &str, change: &str| {
+        warn!(
+            "Capability Warning -- {} does not support {}, so {}",
+            dest, what, change
+        );
+    };
+    if !capabilities.symlinks {
+        report("symlinks", "symlinks are left out");
+    }
+    if !capabilities.hard_links && options.dedup {
+        report("hard links", "--dedup is turned off");
+        options.dedup = false;
+    }
+    if !capabilities.permissions {
+        report("permissions", "permissions are not copied");
+    }
+    if !capabilities.xattrs && (options.xattrs || options.acls) {
+        report("extended attributes", "--xattrs and --acls are turned off");
+        options.xattrs = false;
+        options.acls = false;
+    }
+    if capabilities.mtime_resolution >= Duration::from_secs(1) && options.update {
+        report(
+            "modification times finer than seconds",
+            &format!(
+                "--update compares them to within {:?}",
+                capabilities.mtime_resolution
+            ),
+        );
+    }
+
+    Options {
+        capabilities: Some(capabilities),
+        ..options
+    }
+}
+
+/// Removes the symlinks from `file_sets` of a source, recording each in the fidelity report, if
+/// the capabilities in `options` show that `dest` does not support symlinks
+pub fn unlist_symlinks(file_sets: &mut FileSets, dest: &str, options: &Options) {
+    if options
+        .capabilities
+        .is_none_or(|capabilities| capabilities.symlinks)
+    {
+        return;
+    }
+    for symlink in file_sets.take_symlinks() {
+        fidelity::record(
+            Loss::Symlink,
+            &Path::new(dest).join(symlink.path()),
+            &"the destination does not support symlinks",
+        );
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_capabilities {
+    use super::*;
+    use crate::lumins::file_ops::{File, Symlink};
+    use hashbrown::HashSet;
+
+    #[test]
+    fn finds_resolution() {
+        assert_eq!(resolution(Duration::from_nanos(0)), Duration::from_nanos(1));
+        assert_eq!(
+            resolution(Duration::from_nanos(89)),
+            Duration::from_nanos(100)
+        );
+        assert_eq!(
+            resolution(Duration::from_millis(3)),
+            Duration::from_millis(10)
+        );
+        assert_eq!(
+            resolution(Duration::from_millis(123)),
+            Duration::from_secs(1)
+        );
+        assert_eq!(
+            resolution(Duration::from_millis(1123)),
+            Duration::from_secs(2)
+        );
+
+        // Times are at or before each other within the resolution
+        let fat = Capabilities {
+            mtime_resolution: Duration::from_secs(2),
+            ..Capabilities::default()
+        };
+        let time = UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        assert!(fat.modified_by(time + Duration::from_millis(1999), time));
+        assert!(!fat.modified_by(time + Duration::from_secs(2), time));
+        assert!(Capabilities::default().modified_by(time, time));
+        assert!(!Capabilities::default().modified_by(time + Duration::from_nanos(1), time));
+    }
+
+    #[test]
+    fn probes_dest() {
+        const TEST_DIR: &str = "test_capabilities_probes_dest";
+        fs::create_dir_all(TEST_DIR).unwrap();
+
+        let capabilities = Capabilities::probe(Path::new(TEST_DIR)).unwrap();
+        #[cfg(target_family = "unix")]
+        {
+            assert!(capabilities.symlinks);
+            assert!(capabilities.hard_links);
+            assert!(capabilities.permissions);
+        }
+        assert!(capabilities.mtime_resolution < Duration::from_secs(1));
+        // Nothing is left behind, even by a probe that fails partway, or by dry runs
+        assert_eq!(fs::read_dir(TEST_DIR).unwrap().count(), 0);
+        let failed = || -> Result<(), io::Error> {
+            let dir = ProbeDir::create(Path::new(TEST_DIR))?;
+            fs::write(dir.0.join("file"), b"")?;
+            fs::read(dir.0.join("missing"))?;
+            Ok(())
+        };
+        assert!(failed().is_err());
+        assert!(probe(
+            TEST_DIR,
+            &Options {
+                dry_run: true,
+                ..Options::default()
+            }
+        )
+        .is_none());
+        assert_eq!(fs::read_dir(TEST_DIR).unwrap().count(), 0);
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+
+    #[test]
+    fn downgrades() {
+        let fat = Capabilities {
+            symlinks: false,
+            hard_links: false,
+            permissions: false,
+            xattrs: false,
+            mtime_resolution: Duration::from_secs(2),
+        };
+        let options = Options {
+            xattrs: true,
+            dedup: true,
+            update: true,
+            ..Options::default()
+        };
+        let downgraded = downgrade(fat, "dest", &options);
+        assert!(downgraded.update && !downgraded.xattrs && !downgraded.dedup);
+        assert_eq!(downgraded.capabilities, Some(fat));
+        // Destinations that were already probed are not probed again
+        assert!(probe(".", &downgraded).is_none());
+
+        let mut file_sets = FileSets::with(
+            [File::from("file", 1)].iter().cloned().collect(),
+            HashSet::new(),
+            [Symlink::from("link", "file")].iter().cloned().collect(),
+        );
+        unlist_symlinks(&mut file_sets, "dest", &options);
+        assert_eq!(file_sets.symlinks().len(), 1);
+        unlist_symlinks(&mut file_sets, "dest", &downgraded);
+        assert!(file_sets.symlinks().is_empty());
+        assert_eq!(file_sets.files().len(), 1);
+    }
+}
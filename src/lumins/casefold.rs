@@ -0,0 +1,231 @@
+//! Compares paths regardless of case, for `--ignore-case`, when synchronizing into destinations
+//! whose filesystems do not tell names apart by case, such as exFAT, NTFS, or APFS
+//!
+//! There, `Readme.md` and `README.md` are the same entry, so a run that compared them by case
+//! would delete the one and copy the other on every run. Entries of the destination that only
+//! differ in case from entries of the source are instead renamed to how the source spells them,
+//! and compared with them. Entries of the source that only differ in case from each other can not
+//! both exist in the destination, so only the first of them, by path, is synchronized.
+
+use std::path::{Path, PathBuf};
+
+use hashbrown::{HashMap, HashSet};
+use log::{error, info};
+
+use crate::lumins::dirfd;
+use crate::lumins::file_ops::{FileOps, FileSets};
+use crate::lumins::parse::Options;
+
+/// Folds the case of `path`, so that paths that only differ in case fold to the same string
+pub fn fold(path: &Path) -> String {
+    path.to_string_lossy().to_lowercase()
+}
+
+/// Lists the paths of every entry of `file_sets`, sorted
+fn paths(file_sets: &FileSets) -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = (file_sets.files().iter().map(|file| file.path()))
+        .chain(file_sets.dirs().iter().map(|dir| dir.path()))
+        .chain(file_sets.symlinks().iter().map(|symlink| symlink.path()))
+        .chain(file_sets.specials().iter().map(|special| special.path()))
+        .cloned()
+        .collect();
+    paths.sort();
+    paths
+}
+
+/// Removes the entries of `file_sets` of `src` that only differ in case from an entry before
+/// them, by path, along with everything inside of them
+///
+/// # Returns
+/// The paths of the removed entries that differ in case, which are logged as errors
+pub fn unlist_collisions(file_sets: &mut FileSets, src: &str) -> Vec<PathBuf> {
+    let mut spellings: HashMap<String, PathBuf> = HashMap::new();
+    let mut collisions = Vec::new();
+    for path in paths(file_sets) {
+        if collisions
+            .iter()
+            .any(|collision| path.starts_with(collision))
+        {
+            continue;
+        }
+        let first = spellings.entry(fold(&path)).or_insert_with(|| path.clone());
+        if *first != path {
+            error!(
+                "Error -- {:?} only differs in case from {:?}, so it is not synchronized",
+                Path::new(src).join(&path),
+                Path::new(src).join(&*first)
+            );
+            collisions.push(path);
+        }
+    }
+    file_sets.retain_paths(|path| {
+        !collisions
+            .iter()
+            .any(|collision| path.starts_with(collision))
+    });
+    collisions
+}
+
+/// Renames the entries of `dest_file_sets` of `dest` that only differ in case from entries of
+/// `src_file_sets` to how `src_file_sets` spells them, in `dest` and in `dest_file_sets`
+///
+/// Entries whose spelling is already in `dest_file_sets` are left as they are, to be deleted like
+/// any other entry that is not in the source. Entries that could not be renamed are logged, and
+/// left out of `dest_file_sets`, along with everything inside of them. With `Options::dry_run`,
+/// they are only respelled in `dest_file_sets`, which finds them under either spelling in a
+/// case-insensitive `dest`.
+pub fn match_case(
+    src_file_sets: &FileSets,
+    dest_file_sets: &mut FileSets,
+    dest: &str,
+    options: &Options,
+) {
+    let spellings: HashMap<String, PathBuf> = paths(src_file_sets)
+        .into_iter()
+        .map(|path| (fold(&path), path))
+        .collect();
+    let dest_paths = paths(dest_file_sets);
+    let listed: HashSet<&PathBuf> = dest_paths.iter().collect();
+
+    // How the deepest directory that `path` is in, or `path` itself, is spelled in the source
+    let respell = |path: &Path| -> Option<PathBuf> {
+        path.ancestors()
+            .filter(|ancestor| *ancestor != Path::new(""))
+            .find_map(|ancestor| {
+                let spelling = spellings.get(&fold(ancestor))?;
+                let respelled = spelling.join(path.strip_prefix(ancestor).ok()?);
+                Some(respelled).filter(|respelled| respelled != path)
+            })
+    };
+
+    // Sorted paths rename each directory before the entries inside of it
+    let mut respelled: HashMap<PathBuf, PathBuf> = HashMap::new();
+    let mut failed: Vec<PathBuf> = Vec::new();
+    for path in &dest_paths {
+        if failed.iter().any(|failure| path.starts_with(failure)) {
+            continue;
+        }
+        let new = match respell(path) {
+            Some(new) if !listed.contains(&new) => new,
+            _ => continue,
+        };
+        // Entries are only renamed within their directories, which were renamed first, so
+        // entries inside of a renamed directory only need to be renamed if their names differ
+        let parent = path.parent().unwrap_or_else(|| Path::new(""));
+        let parent = respelled
+            .get(parent)
+            .map_or(parent, |parent| parent.as_path());
+        if new.parent() != Some(parent) {
+            continue;
+        }
+        let old = parent.join(path.file_name().unwrap_or_default());
+        if old != new && !options.dry_run {
+            match dirfd::rename(Path::new(dest), &old, &new) {
+                Ok(_) => info!(
+                    "Renaming {:?} -> {:?}",
+                    Path::new(dest).join(&old),
+                    Path::new(dest).join(&new)
+                ),
+                Err(e) => {
+                    error!("Error -- Renaming {:?}: {}", Path::new(dest).join(&old), e);
+                    failed.push(path.clone());
+                    continue;
+                }
+            }
+        }
+        respelled.insert(path.clone(), new);
+    }
+
+    dest_file_sets.retain_paths(|path| !failed.iter().any(|failure| path.starts_with(failure)));
+    dest_file_sets.rename_paths(|path| respelled.get(path).cloned());
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_casefold {
+    use super::*;
+    use crate::lumins::file_ops::{self, Dir, File};
+    use std::fs;
+
+    #[test]
+    fn unlists_collisions() {
+        let mut file_sets = FileSets::with(
+            [
+                File::from("README.md", 1),
+                File::from("Readme.md", 1),
+                File::from("docs/a", 1),
+                File::from("Docs/b", 1),
+            ]
+            .iter()
+            .cloned()
+            .collect(),
+            [Dir::from("Docs"), Dir::from("docs")]
+                .iter()
+                .cloned()
+                .collect(),
+            HashSet::new(),
+        );
+        let collisions = unlist_collisions(&mut file_sets, "src");
+        assert_eq!(
+            collisions,
+            vec![PathBuf::from("Readme.md"), PathBuf::from("docs")]
+        );
+        assert_eq!(
+            paths(&file_sets),
+            vec![
+                PathBuf::from("Docs"),
+                PathBuf::from("Docs/b"),
+                PathBuf::from("README.md")
+            ]
+        );
+    }
+
+    #[test]
+    fn matches_case() {
+        const TEST_DIR: &str = "test_casefold_matches_case";
+        fs::create_dir_all(TEST_DIR).ok();
+        fs::create_dir_all([TEST_DIR, "Docs"].join("/")).unwrap();
+        fs::write([TEST_DIR, "Docs/A.txt"].join("/"), "a").unwrap();
+        fs::write([TEST_DIR, "Docs/old.txt"].join("/"), "old").unwrap();
+        fs::write([TEST_DIR, "readme.md"].join("/"), "readme").unwrap();
+        fs::write([TEST_DIR, "Both"].join("/"), "").unwrap();
+        fs::write([TEST_DIR, "both"].join("/"), "").unwrap();
+
+        let src_file_sets = FileSets::with(
+            [
+                File::from("docs/a.txt", 1),
+                File::from("README.md", 6),
+                File::from("both", 0),
+            ]
+            .iter()
+            .cloned()
+            .collect(),
+            [Dir::from("docs")].iter().cloned().collect(),
+            HashSet::new(),
+        );
+        let mut dest_file_sets = file_ops::get_all_files(TEST_DIR).unwrap();
+        match_case(
+            &src_file_sets,
+            &mut dest_file_sets,
+            TEST_DIR,
+            &Options::default(),
+        );
+
+        // What is spelled differently in the source is renamed, along with what is inside of it
+        let expected = vec![
+            PathBuf::from("Both"),
+            PathBuf::from("README.md"),
+            PathBuf::from("both"),
+            PathBuf::from("docs"),
+            PathBuf::from("docs/a.txt"),
+            PathBuf::from("docs/old.txt"),
+        ];
+        assert_eq!(paths(&dest_file_sets), expected);
+        assert_eq!(paths(&file_ops::get_all_files(TEST_DIR).unwrap()), expected);
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+}
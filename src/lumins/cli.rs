@@ -0,0 +1,842 @@
+//! Definitions of the subcommands of lms and of their arguments
+//!
+//! The command line, the flags of jobs in the config file, completion scripts, and the man page
+//! all come from these definitions, so that they can not disagree, and clap checks them when they
+//! are compiled. `parse::parse_args` turns them into the options of a run.
+
+use clap::{ArgAction, Args, Parser, Subcommand, ValueEnum};
+use clap_complete::Shell;
+
+use crate::lumins::{
+    bisync::Prefer, compress::Codec, dirfd::Reflink, file_ops::Order, image::Filesystem,
+    limits::ShortenNames, manifest::Format, sandbox::SandboxMode, sanitize::Sanitize,
+};
+
+/// Luminous Synchronize - A fast and reliable multithreaded alternative to rsync for synchronizing
+/// local files
+#[derive(Parser, Debug)]
+#[command(
+    name = "LuminS",
+    bin_name = "lms",
+    version,
+    arg_required_else_help = true,
+    disable_help_subcommand = true
+)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+/// Enum to represent the subcommands of lms, with their arguments
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Multithreaded directory or file copy
+    #[command(
+        arg_required_else_help = true,
+        after_help = "EXAMPLES:
+    lms cp ~/photos /mnt/backup
+    lms cp file.bin /mnt/backup/
+    lms cp ~/site site.tar.zst --deterministic
+    lms cp backup.zip ~/restored
+
+    See lms help for topics such as archives or snapshots"
+    )]
+    Cp(CpArgs),
+    /// Move a directory, verifying copies across filesystems before removing sources
+    #[command(
+        arg_required_else_help = true,
+        after_help = "EXAMPLES:
+    lms mv ~/downloads/videos /mnt/media
+
+    See lms help for topics such as archives or snapshots"
+    )]
+    Mv(MvArgs),
+    /// Multithreaded directory remove
+    #[command(
+        arg_required_else_help = true,
+        after_help = "EXAMPLES:
+    lms rm build target
+
+    See lms help for topics such as archives or snapshots"
+    )]
+    Rm(RmArgs),
+    /// Multithreaded directory synchronization
+    #[command(
+        visible_alias = "s",
+        arg_required_else_help = true,
+        after_help = "EXAMPLES:
+    lms sync ~/photos /mnt/backup/photos
+    lms sync -n --update ~/docs /mnt/docs
+    lms sync --watch ~/projects /mnt/projects
+    lms sync --profile nightly --refuse-anomalies /data /backup
+
+    See lms help for topics such as archives or snapshots"
+    )]
+    Sync(SyncArgs),
+    /// Run jobs from the config file on cron-like schedules
+    #[command(after_help = "EXAMPLES:
+    lms daemon --config /etc/lms.toml
+
+    See lms help for topics such as archives or snapshots")]
+    Daemon(DaemonArgs),
+    /// Export a directory as a reproducible OCI container image
+    #[command(
+        arg_required_else_help = true,
+        after_help = "EXAMPLES:
+    lms export-oci ./rootfs ./image --tag v1
+
+    See lms help for topics such as archives or snapshots"
+    )]
+    ExportOci(ExportOciArgs),
+    /// Export what a copy of a directory is missing into an archive, to bring it up to date
+    /// offline
+    #[command(
+        arg_required_else_help = true,
+        after_help = "EXAMPLES:
+    lms export-diff ~/data ~/mirror -o changes.tar.zst
+
+    See lms help for topics such as archives or snapshots"
+    )]
+    ExportDiff(ExportDiffArgs),
+    /// Export a manifest of every file in a directory, with its permissions and hash
+    #[command(arg_required_else_help = true)]
+    Manifest(ManifestArgs),
+    /// Compare two directories, or two manifests exported by the manifest subcommand
+    #[command(
+        arg_required_else_help = true,
+        after_help = "EXAMPLES:
+    lms diff ~/data /mnt/backup/data --format summary
+    lms diff --manifest laptop.json --manifest server.json --format json
+
+    See lms help for topics such as archives or snapshots"
+    )]
+    Diff(DiffArgs),
+    /// Print the BLAKE3 checksum of every file in a directory, for verify to check
+    #[command(arg_required_else_help = true)]
+    Hash(HashArgs),
+    /// Find files with identical contents in a directory, and delete or hard link the extra
+    /// copies
+    #[command(
+        arg_required_else_help = true,
+        after_help = "EXAMPLES:
+    lms dupes ~/photos
+    lms dupes ~/photos --delete --keep-first
+
+    See lms help for topics such as archives or snapshots"
+    )]
+    Dupes(DupesArgs),
+    /// Count the files in a directory, with their total size, the largest ones, and a histogram
+    /// of sizes
+    #[command(
+        arg_required_else_help = true,
+        after_help = "EXAMPLES:
+    lms stats ~/data --top 20
+
+    See lms help for topics such as archives or snapshots"
+    )]
+    Stats(StatsArgs),
+    /// Check a directory for bit rot against the checksums printed by hash, or against a mirror
+    /// of it
+    #[command(arg_required_else_help = true)]
+    Verify(VerifyArgs),
+    /// Take an incremental snapshot, hard linking unchanged files to the previous one
+    #[command(
+        arg_required_else_help = true,
+        after_help = "EXAMPLES:
+    lms snapshot ~/docs /mnt/backups
+
+    See lms help for topics such as archives or snapshots"
+    )]
+    Snapshot(SnapshotArgs),
+    /// Merge several sources into a destination, where later sources take precedence
+    #[command(
+        arg_required_else_help = true,
+        after_help = "EXAMPLES:
+    lms overlay base patches local /srv/merged
+
+    See lms help for topics such as archives or snapshots"
+    )]
+    Overlay(OverlayArgs),
+    /// Synchronize two directories with each other, in both directions
+    #[command(
+        arg_required_else_help = true,
+        after_help = "EXAMPLES:
+    lms bisync ~/notes /mnt/usb/notes
+
+    See lms help for topics such as archives or snapshots"
+    )]
+    Bisync(BisyncArgs),
+    /// Replace placeholders left by --materialize with their real contents
+    #[command(arg_required_else_help = true)]
+    Hydrate(HydrateArgs),
+    /// Show the last runs of each profile, and how they changed from run to run
+    History(HistoryArgs),
+    /// Review the changes a sync would make, approving, skipping or inspecting each one
+    #[command(arg_required_else_help = true)]
+    Tui(TuiArgs),
+    /// Print the completion script of a shell, to complete subcommands and options of lms
+    #[command(after_help = "EXAMPLES:
+    lms completions bash > /etc/bash_completion.d/lms
+    lms completions zsh > ~/.zfunc/_lms
+    lms completions fish > ~/.config/fish/completions/lms.fish")]
+    Completions(CompletionsArgs),
+    /// Print the man page of lms, with every subcommand and help topic, in roff
+    #[command(after_help = "EXAMPLES:
+    lms man > /usr/share/man/man1/lms.1
+    lms man | man -l -")]
+    Man,
+    /// Print the help of a subcommand, or of a topic such as archives or snapshots
+    Help(HelpArgs),
+    /// Stream SOURCE as a tar archive to stdout, as the reader of cp --privsep
+    #[command(hide = true)]
+    PrivsepReader(PrivsepReaderArgs),
+}
+
+/// Enum to represent how a trailing separator on the source is read, with `--trailing-slash`
+#[derive(Eq, PartialEq, Debug, Clone, Copy, ValueEnum)]
+pub enum TrailingSlash {
+    /// Ignore it
+    Strict,
+    /// Copy the contents of the source with it, and the source itself without it, like rsync
+    Rsync,
+}
+
+/// Options of how a run is logged
+#[derive(Args, Debug)]
+pub struct LogArgs {
+    /// Verbose outputs, where -vv also explains why files are copied, and -vvv traces everything
+    #[arg(short = 'v', long, action = ArgAction::Count)]
+    pub verbose: u8,
+    /// Print nothing but errors, not even the progress bar or a summary, such as for cron jobs that
+    /// mail any output
+    #[arg(short = 'q', long, conflicts_with = "verbose")]
+    pub quiet: bool,
+    /// Append timestamped logs of every operation to PATH, whether or not -v is given, rotating it
+    /// at 10 MiB and keeping 5 old logs as PATH.1 to PATH.5
+    #[arg(long, value_name = "PATH")]
+    pub log_file: Option<String>,
+}
+
+/// Options of how files are copied, which cp and sync share
+#[derive(Args, Debug)]
+pub struct TransferArgs {
+    /// Preserve extended attributes
+    #[arg(long)]
+    pub xattrs: bool,
+    /// Preserve ACLs (POSIX ACLs on Linux, NTFS ACLs on Windows)
+    #[arg(long)]
+    pub acls: bool,
+    /// Update destination files directly, writing only the blocks that changed
+    #[arg(long)]
+    pub inplace: bool,
+    /// Only append what files grew by to their copies, if their copies are still the start of them,
+    /// such as for logs
+    #[arg(long)]
+    pub append_verify: bool,
+    /// Copy files in ORDER, such as smallest-first to show quick progress, or largest-first to
+    /// stream large files one after another, instead of copying small files alongside large ones
+    #[arg(long, value_name = "ORDER", value_enum)]
+    pub order: Option<Order>,
+    /// Copy files through a buffer of SIZE bytes, such as 64K or 4M, instead of one sized to each
+    /// file
+    #[arg(long, value_name = "SIZE")]
+    pub buffer_size: Option<String>,
+    /// Copy large files without going through the page cache
+    #[arg(long)]
+    pub direct_io: bool,
+    /// Flush each copied file and its directory to disk before counting it as copied, and the
+    /// destination at the end, so that a power loss right after the run leaves no truncated files
+    #[arg(long)]
+    pub fsync: bool,
+    /// Skip files that are still being written, whose size changed since they were listed, or that
+    /// changed while they were copied, and list them in the fidelity report, instead of copying
+    /// them again at the end of the run
+    #[arg(long)]
+    pub skip_growing: bool,
+    /// Copy even if the destination does not have enough free space for what the run writes, which
+    /// is checked before anything is written
+    #[arg(long)]
+    pub no_check_space: bool,
+    /// Make copy-on-write clones of files where the filesystem supports them
+    #[arg(long, value_name = "WHEN", value_enum, default_value_t = Reflink::Auto)]
+    pub reflink: Reflink,
+    /// Hard link identical files in DESTINATION to one copy, to save space
+    #[arg(long)]
+    pub dedup: bool,
+    /// Preserve ownership, shifting user and group IDs by the mappings in FILE
+    #[arg(long, value_name = "FILE")]
+    pub idmap: Option<String>,
+    /// How a trailing separator on SOURCE is read, where strict ignores it, and rsync copies
+    /// SOURCE/ as the contents of SOURCE, and SOURCE as a subdirectory of DESTINATION named after
+    /// it, like rsync
+    #[arg(long, value_name = "MODE", value_enum, default_value_t = TrailingSlash::Strict)]
+    pub trailing_slash: TrailingSlash,
+    /// Copy into a subdirectory of DESTINATION named after SOURCE, like cp -r
+    #[arg(long)]
+    pub into: bool,
+    /// Replace files matched by the rules in FILE with empty files or cold store symlinks
+    #[arg(long, value_name = "FILE")]
+    pub materialize: Option<String>,
+    /// Write each directory matching GLOB, and flush it to disk, before the next one in path order
+    #[arg(long, value_name = "GLOB")]
+    pub fence: Vec<String>,
+    /// Rewrite the targets of absolute symlinks with the sed substitution EXPR, such as
+    /// s#^/old/root#/new/root#
+    #[arg(long, value_name = "EXPR")]
+    pub link_rewrite: Vec<String>,
+    /// Make absolute symlinks that point inside SOURCE relative, so that DESTINATION can be moved
+    /// anywhere
+    #[arg(long)]
+    pub relativize_links: bool,
+    /// Move the files that are replaced or deleted in DESTINATION into a directory of the run under
+    /// DESTINATION, which is purged only once the run completes without errors
+    #[arg(long)]
+    pub quarantine: bool,
+    /// Never overwrite files that already exist in DESTINATION
+    #[arg(long)]
+    pub ignore_existing: bool,
+    /// Only overwrite files in DESTINATION that are older than their sources
+    #[arg(short = 'u', long)]
+    pub update: bool,
+    /// Overwrite files in DESTINATION that are empty while their sources are not, such as those
+    /// left by failed copies, even with --update or --ignore-existing
+    #[arg(long)]
+    pub heal_empty_files: bool,
+    /// Include (+ PATTERN) or exclude (- PATTERN) the entries of SOURCE and DESTINATION that
+    /// PATTERN matches, where the first matching rule of those given decides, like rsync filter
+    /// rules
+    #[arg(long, value_name = "RULE", allow_hyphen_values = true)]
+    pub filter: Vec<String>,
+    /// Only copy the top N levels of SOURCE, where 1 is what is directly in it, leaving deeper
+    /// levels of DESTINATION alone
+    #[arg(long, value_name = "N")]
+    pub max_depth: Option<usize>,
+    /// Print the first N entries that --link-rewrite, --relativize-links or --materialize write
+    /// differently than they are in SOURCE, and ask whether to go on before anything is written
+    #[arg(long, value_name = "N")]
+    pub preview: Option<usize>,
+    /// Do not cross filesystem boundaries, listing mount points in SOURCE and DESTINATION but not
+    /// what is mounted on them
+    #[arg(short = 'x', long)]
+    pub one_file_system: bool,
+    /// Create the sockets, FIFOs and device nodes of SOURCE in DESTINATION with mknod, instead of
+    /// skipping and reporting them
+    #[arg(long)]
+    pub specials: bool,
+    /// Refuse to write entries that lead out of DESTINATION, such as symlinks to absolute paths or
+    /// above DESTINATION, where auto only does so for archive sources
+    #[arg(long, value_name = "WHEN", value_enum, default_value_t = SandboxMode::Auto)]
+    pub sandbox_dest: SandboxMode,
+    /// Write through symlinks to directories in DESTINATION where SOURCE has directories, instead
+    /// of replacing them
+    #[arg(short = 'K', long)]
+    pub keep_dirlinks: bool,
+    /// Copy symlinks to directories in SOURCE as the directories they point to
+    #[arg(short = 'k', long)]
+    pub copy_dirlinks: bool,
+    /// On Linux, restrict the process with Landlock and seccomp to reading SOURCE and writing
+    /// DESTINATION, and deny running other programs or opening sockets
+    #[arg(long)]
+    pub harden: bool,
+    /// Leave out entries whose names or paths are too long for the filesystem of DESTINATION, which
+    /// are otherwise reported before the run and fail
+    #[arg(long)]
+    pub skip_long_paths: bool,
+    /// What to do with entries whose names are too long for the filesystem of DESTINATION, where
+    /// error refuses to write anything, and truncate-hash shortens them with a hash of the whole
+    /// name, recording the names they had in a .lumins-shortened file next to them
+    #[arg(long, value_name = "POLICY", value_enum)]
+    pub shorten_names: Option<ShortenNames>,
+    /// Write the characters that FAT, exFAT, and NTFS do not allow in names as escapes with escape,
+    /// such as %3A for a colon, or restore the characters of such escapes with restore
+    #[arg(long, value_name = "MODE", value_enum)]
+    pub sanitize_names: Option<Sanitize>,
+    /// Go on past files that fail, and report every error again at the end
+    #[arg(long)]
+    pub keep_going: bool,
+    /// Stop once more than N errors happened, skipping the files that are left
+    #[arg(long, value_name = "N")]
+    pub max_errors: Option<u64>,
+    /// Write an empty file NAME into each directory of DESTINATION once everything in it is copied
+    /// and verified
+    #[arg(long, value_name = "NAME")]
+    pub done_marker: Option<String>,
+    /// Retry copies that fail with transient errors, such as those of briefly unavailable network
+    /// shares, up to N times
+    #[arg(long, value_name = "N")]
+    pub retries: Option<u32>,
+    /// Wait SECONDS, or 1 second if not given, before the first retry, and twice as long before
+    /// each one after it, up to 60 seconds
+    #[arg(long, value_name = "SECONDS")]
+    pub retry_delay: Option<f64>,
+    /// Run CMD with the shell before each file is copied, with {src}, {dest} and {path} replaced by
+    /// its quoted paths, and skip the file if CMD fails, such as to scan it for viruses
+    #[arg(long, value_name = "CMD")]
+    pub hook_pre: Option<String>,
+    /// Run CMD with the shell after each file is copied, with {src}, {dest} and {path} replaced by
+    /// its quoted paths, such as to send notifications
+    #[arg(long, value_name = "CMD")]
+    pub hook_post: Option<String>,
+    /// Write every owner, extended attribute, ACL or symlink that could not be preserved to FILE as
+    /// JSON, even if there were none
+    #[arg(long, value_name = "FILE")]
+    pub fidelity_report: Option<String>,
+    /// Record a summary of the run under NAME, to be shown by the history subcommand
+    #[arg(long, value_name = "NAME")]
+    pub profile: Option<String>,
+}
+
+/// Arguments of cp
+#[derive(Args, Debug)]
+pub struct CpArgs {
+    #[command(flatten)]
+    pub log: LogArgs,
+    /// Copy files sequentially instead of in parallel
+    #[arg(short = 'S', long)]
+    pub sequential: bool,
+    #[command(flatten)]
+    pub transfer: TransferArgs,
+    /// Only copy the paths inside SOURCE listed in FILE, one per line or separated by NULs, where -
+    /// reads them from stdin
+    #[arg(long, value_name = "FILE")]
+    pub files_from: Option<String>,
+    /// Write archive destinations reproducibly, without owners or timestamps
+    #[arg(long)]
+    pub deterministic: bool,
+    /// On Linux, read SOURCE in a process of its own that streams it to this one, with each process
+    /// restricted to its own tree as with --harden
+    #[arg(long)]
+    pub privsep: bool,
+    /// Filesystem of a .iso image DESTINATION, where iso9660 is ISO 9660 with Joliet names, which
+    /// is the default, and udf is UDF 1.02, which also holds symlinks, large files, and deep trees
+    #[arg(long, value_name = "FS", value_enum)]
+    pub fs: Option<Filesystem>,
+    /// How the blocks of a .sqfs or .squashfs image DESTINATION are compressed, where zstd is the
+    /// default, and gzip is read by older kernels too
+    #[arg(long, value_name = "CODEC", value_enum)]
+    pub comp: Option<Codec>,
+    /// Source directory, or a .tar, .tar.zst, or .zip archive to extract
+    pub source: String,
+    /// Destination directory, or a .tar or .tar.zst archive, or a .iso or .sqfs image
+    pub destination: String,
+}
+
+/// Arguments of mv
+#[derive(Args, Debug)]
+pub struct MvArgs {
+    #[command(flatten)]
+    pub log: LogArgs,
+    /// Copy files sequentially instead of in parallel
+    #[arg(short = 'S', long)]
+    pub sequential: bool,
+    /// Only compare the sizes of copies across filesystems before removing files, not their hashes
+    #[arg(long)]
+    pub no_verify: bool,
+    /// Source directory
+    pub source: String,
+    /// Destination directory
+    pub destination: String,
+}
+
+/// Arguments of rm
+#[derive(Args, Debug)]
+pub struct RmArgs {
+    #[command(flatten)]
+    pub log: LogArgs,
+    /// Delete files sequentially instead of in parallel
+    #[arg(short = 'S', long)]
+    pub sequential: bool,
+    /// Target directory
+    #[arg(value_name = "TARGET", required = true)]
+    pub target: Vec<String>,
+}
+
+/// Arguments of sync
+#[derive(Args, Debug)]
+pub struct SyncArgs {
+    /// Do not delete any destination files
+    #[arg(short = 'n', long)]
+    pub nodelete: bool,
+    /// Delete the directories of DESTINATION that are empty once it is synchronized, deepest first,
+    /// such as those left empty by deletions
+    #[arg(short = 'm', long)]
+    pub prune_empty_dirs: bool,
+    /// Refuse to synchronize if SOURCE has no files while DESTINATION has some, such as when SOURCE
+    /// is a mount point that was not mounted
+    #[arg(long)]
+    pub safe_delete: bool,
+    /// Synchronize anyway with --safe-delete, deleting everything in DESTINATION if SOURCE has no
+    /// files
+    #[arg(long, requires = "safe_delete")]
+    pub force_empty_source: bool,
+    /// Refuse to synchronize if it would delete more than N files of DESTINATION, or more than N%
+    /// of them with a % suffix
+    #[arg(long, value_name = "N")]
+    pub max_delete: Option<String>,
+    /// Treat paths that only differ in case as the same path, for case-insensitive destinations
+    /// such as exFAT, NTFS, or APFS
+    #[arg(long)]
+    pub ignore_case: bool,
+    /// Use a cryptographic hash function for hashing similar files
+    #[arg(short = 's', long)]
+    pub secure: bool,
+    /// Cache the hashes of files in DESTINATION in extended attributes, and trust them while the
+    /// files keep their size and modification time, instead of hashing the files again
+    #[arg(long, requires = "secure")]
+    pub xattr_hash_cache: bool,
+    #[command(flatten)]
+    pub log: LogArgs,
+    /// Copy files sequentially instead of in parallel
+    #[arg(short = 'S', long)]
+    pub sequential: bool,
+    /// Print the changes the sync would make, without making any of them
+    #[arg(long, conflicts_with = "watch")]
+    pub dry_run: bool,
+    /// Print only the paths that --dry-run would change, each followed by a NUL, for xargs -0
+    #[arg(long, requires = "dry_run")]
+    pub print0: bool,
+    /// Create a read-only snapshot of the destination before synchronizing (btrfs, zfs)
+    #[arg(long)]
+    pub dest_snapshot: bool,
+    #[command(flatten)]
+    pub transfer: TransferArgs,
+    /// Journal completed files in the destination, so that an interrupted run can be resumed
+    #[arg(long)]
+    pub resume: bool,
+    /// Refuse runs that would delete far more of the destination than usual
+    #[arg(long)]
+    pub refuse_anomalies: bool,
+    /// Proceed with runs that --refuse-anomalies would refuse
+    #[arg(long)]
+    pub force: bool,
+    /// Compare SOURCE and DESTINATION one directory at a time as SOURCE is listed, instead of
+    /// listing both completely first, so that memory stays low however large they are, but renamed
+    /// files are copied again
+    #[arg(long)]
+    pub low_memory: bool,
+    /// After synchronizing, keep watching SOURCE and synchronize what changes in it as it changes,
+    /// on Linux
+    #[arg(long)]
+    pub watch: bool,
+    /// Append every change that --watch sees to PATH as a line of JSON, before acting on it, or
+    /// print them if PATH is -
+    #[arg(long, value_name = "PATH", requires = "watch")]
+    pub audit: Option<String>,
+    /// Wait until --watch has seen no changes for DURATION, in seconds, or with a suffix of ms, s,
+    /// m, h, or d, before synchronizing them, so that bursts of changes are synchronized at once
+    /// [default 10ms]
+    #[arg(long, value_name = "DURATION", requires = "watch")]
+    pub debounce: Option<String>,
+    /// Synchronize the changes that --watch has seen once there are N of them, even if more keep
+    /// coming
+    #[arg(long, value_name = "N", requires = "watch")]
+    pub max_batch: Option<usize>,
+    /// Source directory, or a .tar, .tar.zst, or .zip archive to restore
+    pub source: String,
+    /// Destination directory
+    pub destination: String,
+}
+
+/// Arguments of daemon
+#[derive(Args, Debug)]
+pub struct DaemonArgs {
+    /// Verbose outputs, where -vv also explains why files are copied, and -vvv traces everything
+    #[arg(short = 'v', long, action = ArgAction::Count)]
+    pub verbose: u8,
+    /// Append timestamped logs of every operation to PATH, whether or not -v is given, rotating it
+    /// at 10 MiB and keeping 5 old logs as PATH.1 to PATH.5
+    #[arg(long, value_name = "PATH")]
+    pub log_file: Option<String>,
+    /// Config file to load jobs from [default: ~/.config/lms/config.toml]
+    #[arg(short = 'c', long, value_name = "FILE")]
+    pub config: Option<String>,
+}
+
+/// Arguments of export-oci
+#[derive(Args, Debug)]
+pub struct ExportOciArgs {
+    #[command(flatten)]
+    pub log: LogArgs,
+    /// Reference name of the image in the layout's index, e.g. latest
+    #[arg(short = 't', long, value_name = "NAME")]
+    pub tag: Option<String>,
+    /// Source directory
+    pub source: String,
+    /// OCI image layout directory
+    pub layout: String,
+}
+
+/// Arguments of export-diff
+#[derive(Args, Debug)]
+pub struct ExportDiffArgs {
+    #[command(flatten)]
+    pub log: LogArgs,
+    /// Use a cryptographic hash function for hashing similar files
+    #[arg(short = 's', long)]
+    pub secure: bool,
+    /// Write the archive reproducibly, without owners or timestamps
+    #[arg(long)]
+    pub deterministic: bool,
+    /// The .tar or .tar.zst archive to write
+    #[arg(short = 'o', long, value_name = "FILE")]
+    pub output: String,
+    /// Source directory
+    pub source: String,
+    /// Copy of the source to export the differences from, such as a mirror of an offline system
+    pub destination: String,
+}
+
+/// Arguments of manifest
+#[derive(Args, Debug)]
+pub struct ManifestArgs {
+    /// Write the manifest to FILE instead of standard output
+    #[arg(short = 'o', long, value_name = "FILE")]
+    pub output: Option<String>,
+    /// Source directory
+    pub source: String,
+}
+
+/// Arguments of diff
+#[derive(Args, Debug)]
+pub struct DiffArgs {
+    /// Use a cryptographic hash function for comparing files
+    #[arg(short = 's', long)]
+    pub secure: bool,
+    /// Print every difference on a line, as a JSON array, or only how many entries differ
+    #[arg(long, value_name = "FORMAT", value_enum, default_value_t = Format::Plain)]
+    pub format: Format,
+    /// Manifest to compare, given twice
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with = "first",
+        required_unless_present = "first"
+    )]
+    pub manifest: Vec<String>,
+    /// Directory to compare, whose entries are shown as removed if they are not in SECOND
+    #[arg(value_name = "FIRST", requires = "second")]
+    pub first: Option<String>,
+    /// Directory to compare FIRST with
+    #[arg(value_name = "SECOND")]
+    pub second: Option<String>,
+}
+
+/// Arguments of hash
+#[derive(Args, Debug)]
+pub struct HashArgs {
+    /// Source directory
+    pub source: String,
+}
+
+/// Arguments of dupes
+#[derive(Args, Debug)]
+pub struct DupesArgs {
+    /// Verbose outputs, where -v also prints each file that is deleted or linked
+    #[arg(short = 'v', long, action = ArgAction::Count)]
+    pub verbose: u8,
+    /// Print nothing but errors, not even the duplicates that were found
+    #[arg(short = 'q', long, conflicts_with = "verbose")]
+    pub quiet: bool,
+    /// Replace the copies of each set with hard links to one of them, where they have the same
+    /// permissions and owner
+    #[arg(long, conflicts_with = "delete")]
+    pub link: bool,
+    /// Delete the extra copies of each set
+    #[arg(long, requires = "keep_first")]
+    pub delete: bool,
+    /// Keep the first copy of each set, in path order, when deleting
+    #[arg(long, requires = "delete")]
+    pub keep_first: bool,
+    /// Directory to search for duplicates
+    pub source: String,
+}
+
+/// Arguments of stats
+#[derive(Args, Debug)]
+pub struct StatsArgs {
+    /// Number of the largest files to show
+    #[arg(long, value_name = "N", default_value_t = 10)]
+    pub top: usize,
+    /// Source directory
+    pub source: String,
+}
+
+/// Arguments of verify
+#[derive(Args, Debug)]
+pub struct VerifyArgs {
+    /// Verbose outputs, where -v also prints each pass of --continuous
+    #[arg(short = 'v', long, action = ArgAction::Count)]
+    pub verbose: u8,
+    /// Print nothing but errors, not even the progress bar or a summary, such as for cron jobs that
+    /// mail any output
+    #[arg(short = 'q', long, conflicts_with = "verbose")]
+    pub quiet: bool,
+    /// Append timestamped logs of every operation to PATH, whether or not -v is given, rotating it
+    /// at 10 MiB and keeping 5 old logs as PATH.1 to PATH.5
+    #[arg(long, value_name = "PATH")]
+    pub log_file: Option<String>,
+    /// Keep verifying at a low IO priority, until the first pass that finds a divergence, such as
+    /// to watch a warm standby mirror
+    #[arg(long)]
+    pub continuous: bool,
+    /// Wait DURATION between the passes of --continuous, in seconds, or with a suffix of ms, s, m,
+    /// h, or d [default 1h]
+    #[arg(long, value_name = "DURATION", requires = "continuous")]
+    pub interval: Option<String>,
+    /// Post a JSON alert to URL with curl when --continuous finds a divergence
+    #[arg(long, value_name = "URL", requires = "continuous")]
+    pub webhook: Option<String>,
+    /// Directory to check
+    pub source: String,
+    /// Checksums printed by the hash subcommand, or a mirror of SOURCE that must hold the same
+    /// files
+    pub manifest: String,
+}
+
+/// Arguments of snapshot
+#[derive(Args, Debug)]
+pub struct SnapshotArgs {
+    /// Compare the contents of files with the previous snapshot, instead of their modification
+    /// times
+    #[arg(short = 's', long)]
+    pub secure: bool,
+    #[command(flatten)]
+    pub log: LogArgs,
+    /// Preserve extended attributes
+    #[arg(long)]
+    pub xattrs: bool,
+    /// Preserve ACLs (POSIX ACLs on Linux, NTFS ACLs on Windows)
+    #[arg(long)]
+    pub acls: bool,
+    /// Source directory
+    pub source: String,
+    /// Directory of snapshots
+    pub destination: String,
+}
+
+/// Arguments of overlay
+#[derive(Args, Debug)]
+pub struct OverlayArgs {
+    /// Do not delete any destination files
+    #[arg(short = 'n', long)]
+    pub nodelete: bool,
+    /// Use a cryptographic hash function for hashing similar files
+    #[arg(short = 's', long)]
+    pub secure: bool,
+    #[command(flatten)]
+    pub log: LogArgs,
+    /// Copy files sequentially instead of in parallel
+    #[arg(short = 'S', long)]
+    pub sequential: bool,
+    /// Source directories, from lowest to highest precedence, followed by the destination directory
+    #[arg(value_name = "ROOTS", required = true, num_args = 2..)]
+    pub roots: Vec<String>,
+}
+
+/// Arguments of bisync
+#[derive(Args, Debug)]
+pub struct BisyncArgs {
+    /// Use a cryptographic hash function for hashing similar files
+    #[arg(short = 's', long)]
+    pub secure: bool,
+    #[command(flatten)]
+    pub log: LogArgs,
+    /// Resolve conflicts in favor of A, B, or the newer change
+    #[arg(long, value_name = "SIDE", value_enum)]
+    pub prefer: Option<Prefer>,
+    /// Treat files that are empty on one side but not on the other as stale, such as those left by
+    /// failed copies, and copy the other side over them, whatever their modification times
+    #[arg(long)]
+    pub heal_empty_files: bool,
+    /// First directory
+    pub a: String,
+    /// Second directory
+    pub b: String,
+}
+
+/// Arguments of hydrate
+#[derive(Args, Debug)]
+pub struct HydrateArgs {
+    #[command(flatten)]
+    pub log: LogArgs,
+    /// Copy the contents from SOURCE instead of the source the tree was materialized from
+    #[arg(long, value_name = "SOURCE")]
+    pub from: Option<String>,
+    /// Placeholders, or directories of placeholders, to hydrate
+    #[arg(value_name = "PATH", required = true)]
+    pub path: Vec<String>,
+}
+
+/// Arguments of history
+#[derive(Args, Debug)]
+pub struct HistoryArgs {
+    /// Number of runs to show per profile
+    #[arg(short = 'n', long, value_name = "N", default_value_t = 10)]
+    pub runs: usize,
+    /// History file to read runs from [default: ~/.local/share/lms/history.toml]
+    #[arg(short = 'f', long, value_name = "FILE")]
+    pub file: Option<String>,
+    /// Only show runs of this profile
+    #[arg(value_name = "PROFILE")]
+    pub profile: Option<String>,
+}
+
+/// Arguments of tui
+#[derive(Args, Debug)]
+pub struct TuiArgs {
+    /// Do not delete any destination files
+    #[arg(short = 'n', long)]
+    pub nodelete: bool,
+    /// Use a cryptographic hash function for hashing similar files
+    #[arg(short = 's', long)]
+    pub secure: bool,
+    /// Verbose outputs, where -vv also explains why files are copied, and -vvv traces everything
+    #[arg(short = 'v', long, action = ArgAction::Count)]
+    pub verbose: u8,
+    /// Append timestamped logs of every operation to PATH, whether or not -v is given, rotating it
+    /// at 10 MiB and keeping 5 old logs as PATH.1 to PATH.5
+    #[arg(long, value_name = "PATH")]
+    pub log_file: Option<String>,
+    /// Source directory
+    pub source: String,
+    /// Destination directory
+    pub destination: String,
+}
+
+/// Arguments of completions
+#[derive(Args, Debug)]
+pub struct CompletionsArgs {
+    /// Shell to print the completion script of
+    #[arg(value_enum)]
+    pub shell: Shell,
+}
+
+/// Arguments of help
+#[derive(Args, Debug)]
+pub struct HelpArgs {
+    /// Subcommand or topic, where no TOPIC lists the topics
+    #[arg(value_name = "TOPIC")]
+    pub topic: Option<String>,
+}
+
+/// Arguments of privsep-reader
+#[derive(Args, Debug)]
+pub struct PrivsepReaderArgs {
+    /// Source directory
+    pub source: String,
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_cli {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn checks_definition() {
+        Cli::command().debug_assert();
+    }
+}
@@ -0,0 +1,282 @@
+//! Compresses blocks of data on their own, for images that are compressed block by block
+//!
+//! Blocks are compressed into zstd frames by the zstd library, or into zlib streams here, with a
+//! greedy LZ77 match finder whose matches are written with the fixed Huffman codes of deflate.
+//! zlib streams do not compress as well as the reference compressor does, but are read by any
+//! decompressor.
+
+use std::io;
+
+use clap::ValueEnum;
+
+/// Enum to represent how blocks are compressed, with `--comp`
+#[derive(Eq, PartialEq, Debug, Clone, Copy, Default, ValueEnum)]
+pub enum Codec {
+    /// zlib streams, which SquashFS calls gzip
+    Gzip,
+    /// Zstandard frames
+    #[default]
+    Zstd,
+}
+
+/// Compresses `data` on its own with `codec`
+///
+/// # Errors
+/// This function will return an error if zstd could not compress `data`
+pub fn compress(codec: Codec, data: &[u8]) -> Result<Vec<u8>, io::Error> {
+    match codec {
+        Codec::Gzip => Ok(zlib(data)),
+        Codec::Zstd => zstd::bulk::compress(data, zstd::DEFAULT_COMPRESSION_LEVEL),
+    }
+}
+
+/// A match, which repeats `length` bytes from `distance` bytes back, after `literals` bytes that
+/// are copied as they are
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+struct Sequence {
+    literals: usize,
+    length: usize,
+    distance: usize,
+}
+
+/// Shortest match that is worth encoding
+const MIN_MATCH: usize = 4;
+
+/// Number of bits of the hashes that matches are looked up by
+const HASH_BITS: u32 = 15;
+
+/// Finds the matches in `data` greedily, taking the first match that is found at each position
+///
+/// # Returns
+/// The matches, after which the bytes that no match covered are left as literals
+fn find_matches(data: &[u8], max_distance: usize, max_length: usize) -> Vec<Sequence> {
+    let hash = |i: usize| {
+        let word = u32::from_le_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]);
+        (word.wrapping_mul(2_654_435_761) >> (32 - HASH_BITS)) as usize
+    };
+
+    let mut table = vec![usize::MAX; 1 << HASH_BITS];
+    let mut sequences = Vec::new();
+    let mut literal_start = 0;
+    let mut i = 0;
+    while i + MIN_MATCH <= data.len() {
+        let candidate = std::mem::replace(&mut table[hash(i)], i);
+        if candidate == usize::MAX
+            || i - candidate > max_distance
+            || data[candidate..candidate + MIN_MATCH] != data[i..i + MIN_MATCH]
+        {
+            i += 1;
+            continue;
+        }
+
+        let mut length = MIN_MATCH;
+        while i + length < data.len()
+            && length < max_length
+            && data[candidate + length] == data[i + length]
+        {
+            length += 1;
+        }
+        sequences.push(Sequence {
+            literals: i - literal_start,
+            length,
+            distance: i - candidate,
+        });
+        for j in i + 1..(i + length).min(data.len() - MIN_MATCH + 1) {
+            table[hash(j)] = j;
+        }
+        i += length;
+        literal_start = i;
+    }
+    sequences
+}
+
+/// A writer of bits, which are packed into bytes with the first bits in the lowest ones
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    bits: u64,
+    count: u32,
+}
+
+impl BitWriter {
+    /// Writes the lowest `count` bits of `value`, of which there may be at most 32
+    fn write(&mut self, value: u64, count: u32) {
+        self.bits |= (value & ((1 << count) - 1)) << self.count;
+        self.count += count;
+        while self.count >= 8 {
+            self.bytes.push(self.bits as u8);
+            self.bits >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    /// Writes the lowest `count` bits of `value`, with the highest of them first, as Huffman
+    /// codes are written in deflate
+    fn write_reversed(&mut self, value: u64, count: u32) {
+        let reversed = (0..count).fold(0, |reversed, i| (reversed << 1) | ((value >> i) & 1));
+        self.write(reversed, count);
+    }
+
+    /// Pads the bits to a whole byte with zeros, and gets the bytes
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.bytes.push(self.bits as u8);
+        }
+        self.bytes
+    }
+}
+
+/// Gets the code of `value` from the bases of the codes, which are sorted, with the number of
+/// extra bits that are written after it and their value
+fn code_of(value: usize, bases: &[usize], extra_bits: &[u32]) -> (usize, u32, u64) {
+    // Safe to unwrap since the first base is the smallest value
+    let code = bases.iter().rposition(|&base| base <= value).unwrap();
+    (code, extra_bits[code], (value - bases[code]) as u64)
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// zlib
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Farthest back that deflate matches reach
+const DEFLATE_MAX_DISTANCE: usize = 32768;
+
+/// Smallest match length of each length code, starting at 257
+pub const LENGTH_BASES: [usize; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+
+/// Number of extra bits of each length code
+pub const LENGTH_BITS: [u32; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+
+/// Smallest distance of each distance code
+pub const DISTANCE_BASES: [usize; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+
+/// Number of extra bits of each distance code
+pub const DISTANCE_BITS: [u32; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+/// Compresses `data` into a zlib stream, as a single deflate block with the fixed Huffman codes
+fn zlib(data: &[u8]) -> Vec<u8> {
+    let mut writer = BitWriter::default();
+    // The last block, with fixed codes
+    writer.write(1, 1);
+    writer.write(1, 2);
+
+    let literal = |writer: &mut BitWriter, symbol: usize| match symbol {
+        0..=143 => writer.write_reversed(0x30 + symbol as u64, 8),
+        144..=255 => writer.write_reversed(0x190 + (symbol - 144) as u64, 9),
+        256..=279 => writer.write_reversed((symbol - 256) as u64, 7),
+        _ => writer.write_reversed(0xc0 + (symbol - 280) as u64, 8),
+    };
+    let mut position = 0;
+    for sequence in find_matches(data, DEFLATE_MAX_DISTANCE, LENGTH_BASES[28]) {
+        for &byte in &data[position..position + sequence.literals] {
+            literal(&mut writer, usize::from(byte));
+        }
+        position += sequence.literals + sequence.length;
+
+        let (code, bits, value) = code_of(sequence.length, &LENGTH_BASES, &LENGTH_BITS);
+        literal(&mut writer, 257 + code);
+        writer.write(value, bits);
+        let (code, bits, value) = code_of(sequence.distance, &DISTANCE_BASES, &DISTANCE_BITS);
+        writer.write_reversed(code as u64, 5);
+        writer.write(value, bits);
+    }
+    for &byte in &data[position..] {
+        literal(&mut writer, usize::from(byte));
+    }
+    literal(&mut writer, 256);
+
+    let mut stream = vec![0x78, 0x01];
+    stream.extend(writer.finish());
+    stream.extend(adler32(data).to_be_bytes());
+    stream
+}
+
+/// Computes the Adler-32 checksum of `data`, which zlib streams end with
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    // Sums are reduced often enough that they can not overflow
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += u32::from(byte);
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    (b << 16) | a
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_compress {
+    use super::*;
+
+    /// Gets data that compresses, but not trivially
+    fn sample() -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut seed = 1u32;
+        for i in 0..300_000 {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            if i % 7 == 0 {
+                data.extend(format!("line {} of the sample ", seed % 1000).as_bytes());
+            } else {
+                data.push((seed >> 24) as u8 % 16 + b'a');
+            }
+        }
+        data
+    }
+
+    #[test]
+    fn finds_matches() {
+        let data = b"abcdefgh abcdefgh abcdefgh";
+        let sequences = find_matches(data, data.len(), 258);
+        assert_eq!(
+            sequences,
+            [Sequence {
+                literals: 9,
+                length: 17,
+                distance: 9,
+            }]
+        );
+    }
+
+    #[test]
+    fn adler32_of_wikipedia() {
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+    }
+
+    #[test]
+    fn zstd_decompresses() {
+        for data in [Vec::new(), b"a".to_vec(), vec![7; 1000], sample()] {
+            let frame = compress(Codec::Zstd, &data).unwrap();
+            assert_eq!(zstd::decode_all(&frame[..]).unwrap(), data);
+        }
+        assert!(compress(Codec::Zstd, &sample()).unwrap().len() < sample().len() / 2);
+    }
+
+    #[test]
+    fn zlib_stream() {
+        assert_eq!(
+            compress(Codec::Gzip, b"").unwrap(),
+            [0x78, 0x01, 0x03, 0x00, 0x00, 0x00, 0x00, 0x01]
+        );
+        let data = sample();
+        let stream = compress(Codec::Gzip, &data).unwrap();
+        assert!(stream.len() < data.len() / 2);
+        assert_eq!(&stream[stream.len() - 4..], adler32(&data).to_be_bytes());
+    }
+}
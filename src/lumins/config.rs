@@ -0,0 +1,366 @@
+//! Loads job definitions from the LuminS config file
+
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::{env, fs, io};
+
+use clap::error::{ContextKind, ContextValue, ErrorKind};
+use clap::Parser;
+use serde::Deserialize;
+
+use crate::lumins::cli::Cli;
+use crate::lumins::parse::{self, Endpoints, Options, SubCommandType};
+
+/// Enum to represent the subcommand a job runs
+#[derive(Deserialize, Eq, PartialEq, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum JobCommand {
+    Cp,
+    #[default]
+    Sync,
+}
+
+impl JobCommand {
+    /// Gets the name of the subcommand on the command line
+    pub fn name(self) -> &'static str {
+        match self {
+            JobCommand::Cp => "cp",
+            JobCommand::Sync => "sync",
+        }
+    }
+}
+
+/// A struct that represents a single job definition
+#[derive(Deserialize, Eq, PartialEq, Debug, Clone)]
+pub struct Job {
+    pub name: String,
+    pub schedule: String,
+    #[serde(default)]
+    pub command: JobCommand,
+    pub source: String,
+    pub destination: String,
+    #[serde(default)]
+    pub flags: Vec<String>,
+}
+
+/// Options that change how `lms` runs, rather than what a run does, which only the command line
+/// acts on, or that wait for answers on a terminal, so jobs can not be given them
+///
+/// Output and threads are set up once for the daemon, so jobs can not change them either.
+const COMMAND_LINE_OPTIONS: [&str; 12] = [
+    "dry-run",
+    "print0",
+    "watch",
+    "harden",
+    "privsep",
+    "log-file",
+    "fidelity-report",
+    "profile",
+    "preview",
+    "verbose",
+    "quiet",
+    "sequential",
+];
+
+impl Job {
+    /// Converts the job's flags into the options of its runs
+    ///
+    /// Flags are the long names of the options of the job's subcommand, and options that take
+    /// values are written with them, such as `max-delete=10` or `reflink=always`. They are parsed
+    /// like the arguments of `lms <command>` are, so that jobs take the same options, and refuse
+    /// the same values, as the command line.
+    ///
+    /// # Returns
+    /// * Ok: The options named by the job
+    /// * Error: If the job names a flag that its subcommand does not have, such as `delete` for
+    ///   sync, a flag that only the command line acts on, such as `dry-run`, which a job would
+    ///   otherwise ignore, or an invalid value
+    pub fn options(&self) -> Result<Options, io::Error> {
+        let error = |message: String| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Config Error -- job {}: {}", self.name, message),
+            )
+        };
+
+        let mut args = vec![String::from("lms"), String::from(self.command.name())];
+        for flag in &self.flags {
+            let name = flag.split('=').next().unwrap_or_default();
+            if COMMAND_LINE_OPTIONS.contains(&name) {
+                return Err(error(format!(
+                    "flag {} can only be given on the command line",
+                    name
+                )));
+            }
+            args.push(format!("--{}", flag));
+        }
+        // The paths follow --, so that options that take many values do not take them too
+        args.extend([
+            String::from("--"),
+            self.source.clone(),
+            self.destination.clone(),
+        ]);
+
+        let cli = Cli::try_parse_from(args).map_err(|e| {
+            match (e.kind(), e.get(ContextKind::InvalidArg)) {
+                (ErrorKind::UnknownArgument, Some(ContextValue::String(flag))) => error(format!(
+                    "flag {} does not apply to {}",
+                    flag.trim_start_matches('-'),
+                    self.command.name()
+                )),
+                // Only the first line says what is wrong, and the rest how to use the command
+                _ => error(
+                    e.to_string()
+                        .lines()
+                        .next()
+                        .unwrap_or_default()
+                        .trim_start_matches("error: ")
+                        .to_string(),
+                ),
+            }
+        })?;
+        parse::parse_options(&cli.command).map_err(|e| error(e.to_string()))
+    }
+
+    /// Gets the destination that the job's runs write the tree to, given the job's `options`
+    ///
+    /// With --into, or a source without a trailing separator with --trailing-slash rsync, the
+    /// destination is a subdirectory named after the source.
+    pub fn run_destination(&self, options: &Options) -> String {
+        let mut destination = PathBuf::from(&self.destination);
+        if options.into || parse::nests_source(&self.source, options.trailing_slash) == Some(true) {
+            if let Some(name) = Path::new(&self.source).file_name() {
+                destination.push(name);
+            }
+        }
+        destination.to_string_lossy().to_string()
+    }
+
+    /// Finds what the job's source and destination are, see `Endpoints`
+    pub fn endpoints(&self) -> Endpoints {
+        let sub_command_type = match self.command {
+            JobCommand::Cp => SubCommandType::Copy,
+            JobCommand::Sync => SubCommandType::Synchronize,
+        };
+        Endpoints::new(&sub_command_type, &self.source, &self.destination)
+    }
+
+    /// Checks that the job's source and destination are apart, given the job's `options`
+    ///
+    /// # Errors
+    /// This function will return an error if the source and destination are the same, or one is
+    /// inside the other, since a sync would then delete its own source
+    pub fn check_apart(&self, options: &Options) -> Result<(), io::Error> {
+        self.endpoints()
+            .check_dest(&self.source, &self.run_destination(options))
+            .map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "Config Error -- job {}: {} and {} are not apart",
+                        self.name, self.source, self.destination
+                    ),
+                )
+            })
+    }
+}
+
+/// A struct that represents the contents of the config file
+#[derive(Deserialize, Eq, PartialEq, Debug, Default)]
+pub struct Config {
+    #[serde(default, rename = "job")]
+    pub jobs: Vec<Job>,
+}
+
+impl FromStr for Config {
+    type Err = io::Error;
+
+    /// Parses a config from a TOML string, whose jobs must only have flags they act on, and
+    /// sources and destinations that are apart
+    ///
+    /// Sources are not checked to exist, since they may only be mounted by the time their jobs
+    /// run.
+    fn from_str(contents: &str) -> Result<Self, Self::Err> {
+        let config: Config = toml::from_str(contents).map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("Config Error -- {}", e))
+        })?;
+        for job in &config.jobs {
+            job.check_apart(&job.options()?)?;
+        }
+        Ok(config)
+    }
+}
+
+impl Config {
+    /// Finds a job by name
+    pub fn job(&self, name: &str) -> Option<&Job> {
+        self.jobs.iter().find(|job| job.name == name)
+    }
+}
+
+/// Gets the default location of the config file,
+/// `$XDG_CONFIG_HOME/lms/config.toml` or `~/.config/lms/config.toml`
+pub fn default_path() -> PathBuf {
+    let mut path = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => {
+            let mut home = PathBuf::from(env::var_os("HOME").unwrap_or_default());
+            home.push(".config");
+            home
+        }
+    };
+    path.push("lms");
+    path.push("config.toml");
+    path
+}
+
+/// Loads the config file at `path`, or at the default location if `path` is None
+///
+/// # Errors
+/// This function will return an error in the following situations,
+/// but is not limited to just these cases:
+/// * The config file could not be read
+/// * The config file is not valid TOML or is missing required job fields
+/// * A job has a flag that its command does not have, that only the command line acts on, or
+///   with an invalid value, see `Job::options`
+/// * A job's source and destination are the same, or one is inside the other
+pub fn load(path: Option<&str>) -> Result<Config, io::Error> {
+    let path = path.map(PathBuf::from).unwrap_or_else(default_path);
+
+    let contents = fs::read_to_string(&path)
+        .map_err(|e| io::Error::new(e.kind(), format!("Config Error -- {:?}: {}", path, e)))?;
+
+    contents.parse()
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_config {
+    use super::*;
+    use crate::lumins::dirfd::Reflink;
+    use crate::lumins::history::MaxDelete;
+
+    #[test]
+    fn invalid_path() {
+        assert!(load(Some("/?")).is_err());
+    }
+
+    #[test]
+    fn empty_config() {
+        assert_eq!(Config::from_str("").unwrap(), Config::default());
+    }
+
+    #[test]
+    fn jobs() {
+        let config = Config::from_str(
+            r#"
+            [[job]]
+            name = "photos"
+            schedule = "0 3 * * *"
+            source = "/home/photos"
+            destination = "/backup/photos"
+            flags = ["secure", "nodelete"]
+
+            [[job]]
+            name = "docs"
+            schedule = "@hourly"
+            command = "cp"
+            source = "/home/docs"
+            destination = "/backup/docs"
+
+            [[job]]
+            name = "music"
+            schedule = "@daily"
+            source = "/home/music"
+            destination = "/backup/music"
+            flags = ["update", "max-delete=10%", "reflink=always", "filter=- *.tmp"]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.jobs.len(), 3);
+        assert_eq!(config.jobs[0].command, JobCommand::Sync);
+        let options = config.jobs[0].options().unwrap();
+        assert!(options.secure && !options.delete);
+        assert!(!options.update);
+        assert_eq!(config.job("docs").unwrap().command, JobCommand::Cp);
+        let options = config.job("docs").unwrap().options().unwrap();
+        assert!(!options.secure && options.delete);
+
+        // Options that take values are written with them
+        let options = config.job("music").unwrap().options().unwrap();
+        assert!(options.update);
+        assert_eq!(options.max_delete, Some(MaxDelete::Percent(10.0)));
+        assert_eq!(options.reflink, Reflink::Always);
+        assert!(options.filter.is_some());
+        assert!(config.job("videos").is_none());
+    }
+
+    #[test]
+    fn invalid_jobs() {
+        assert!(Config::from_str("[[job]]\nname = \"a\"").is_err());
+
+        let job = |flag: &str| {
+            format!(
+                "[[job]]\nname = \"a\"\nschedule = \"* * * * *\"\nsource = \"a\"\n\
+                 destination = \"b\"\nflags = [\"secure\", \"{}\"]",
+                flag
+            )
+        };
+        let error = Config::from_str(&job("bogus")).unwrap_err().to_string();
+        assert_eq!(
+            error,
+            "Config Error -- job a: flag bogus does not apply to sync"
+        );
+
+        // Flags of other subcommands are refused, rather than doing nothing for the job
+        let error = Config::from_str(&job("delete")).unwrap_err().to_string();
+        assert_eq!(
+            error,
+            "Config Error -- job a: flag delete does not apply to sync"
+        );
+
+        // Values are checked like they are on the command line
+        for flag in &[
+            "reflink=sometimes",
+            "max-delete=many",
+            "max-depth=0",
+            "update=yes",
+        ] {
+            assert!(Config::from_str(&job(flag)).is_err(), "{}", flag);
+        }
+
+        // Jobs whose destination holds their source would delete it
+        let error = Config::from_str(
+            "[[job]]\nname = \"a\"\nschedule = \"* * * * *\"\nsource = \"b/c\"\n\
+             destination = \"b/\"",
+        )
+        .unwrap_err()
+        .to_string();
+        assert_eq!(error, "Config Error -- job a: b/c and b/ are not apart");
+
+        // Flags that a job would ignore are refused, rather than running the job without them
+        for flag in &[
+            "dry-run",
+            "print0",
+            "watch",
+            "harden",
+            "privsep",
+            "log-file",
+            "profile",
+            "verbose",
+            "quiet",
+            "sequential",
+        ] {
+            let error = Config::from_str(&job(flag)).unwrap_err().to_string();
+            assert!(
+                error.contains(&format!("flag {} can only", flag)),
+                "{}",
+                error
+            );
+        }
+    }
+}
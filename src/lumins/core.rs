@@ -1,135 +1,1465 @@
-//! Contains core copy, remove, synchronize functions
+//! Contains core copy, move, remove, synchronize functions
 
+use std::fs;
 use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 
+use hashbrown::{HashMap, HashSet};
+use log::{debug, error, info, warn};
 use rayon::prelude::*;
 
-use crate::lumins::{file_ops, file_ops::Dir, parse::Flag};
+use crate::lumins::archive;
+use crate::lumins::capabilities;
+use crate::lumins::casefold;
+#[cfg(target_family = "unix")]
+use crate::lumins::dedup;
+use crate::lumins::dirlinks;
+use crate::lumins::fence;
+use crate::lumins::file_ops::{self, Dir, File, FileOps, FileSets, Symlink};
+use crate::lumins::history;
+use crate::lumins::journal::{Journal, JOURNAL_NAME};
+use crate::lumins::limits::{self, ShortenNames};
+use crate::lumins::lookup::Lookup;
+use crate::lumins::marker;
+use crate::lumins::materialize::{self, Placeholder};
+use crate::lumins::parse::Options;
+use crate::lumins::preview;
+use crate::lumins::quarantine::{self, Quarantine};
+use crate::lumins::rewrite;
+use crate::lumins::snapshot;
+use crate::lumins::space;
+use crate::lumins::unpack;
+use crate::lumins::workers;
 use crate::progress::{self, PROGRESS_BAR};
 
 /// Synchronizes all files, directories, and symlinks in `dest` with `src`
 ///
+/// Both trees are listed once, and what to copy, compare, and delete is worked out from the
+/// listings, so entries of `dest` are never looked up one at a time, which matters for `dest`s
+/// on high-latency network filesystems. Whether what is written replaces anything is found from
+/// the names of the directories it is written into, which are read once for the run, see
+/// `lookup`.
+///
 /// # Arguments
-/// * `src`: Source directory
+/// * `src`: Source directory, or a tar or zip archive to restore, see `archive::is_archive_src`
 /// * `dest`: Destination directory
-/// * `flags`: set for Flag's
+/// * `options`: Options of the run
 ///
 /// # Errors
 /// This function will return an error in the following situations,
 /// but is not limited to just these cases:
 /// * `src` is an invalid directory
 /// * `dest` is an invalid directory
-pub fn synchronize(src: &str, dest: &str, flags: Flag) -> Result<(), io::Error> {
-    // Retrieve data from src directory about files, dirs, symlinks
-    let src_file_sets = file_ops::get_all_files(&src)?;
+/// * `src` is an archive that could not be restored
+/// * `Options::dest_snapshot` is set and the snapshot of `dest` could not be created, though a
+///   `dest` that can not be snapshotted is only warned about, see `snapshot::snapshot_dest`
+/// * `Options::resume` is set and the journal in `dest` could not be opened
+/// * `Options::refuse_anomalies` is set, and the synchronization would delete far more than usual,
+///   see `history::check_plan`
+/// * The synchronization would delete more of `dest` than `options.max_delete` allows
+/// * `Options::safe_delete` is set, and `src` has no files while `dest` has some, unless
+///   `Options::force_empty_source` is set
+/// * What the synchronization writes does not fit in `dest`, see `space::check`
+/// * `options` has materialization rules, and the materialization state of `dest` could not be
+///   written
+/// * `Options::quarantine_files` is set, and the quarantine in `dest` could not be created or
+///   purged
+pub fn synchronize(src: &str, dest: &str, options: &Options) -> Result<(), io::Error> {
+    // Take a snapshot of dest before anything is modified
+    if options.dest_snapshot {
+        snapshot::snapshot_dest(dest)?;
+    }
+
+    // What dest does not support is turned off before anything is written to it, once the
+    // snapshot no longer sees what probing it writes
+    let probed_options = capabilities::probe(dest, options);
+    let options = &for_run(probed_options.as_ref().unwrap_or(options));
+
+    // Archives are listed and compared with dest as they are extracted
+    if archive::is_archive_src(src) {
+        unpack::unpack(src, dest, options, options.delete)?;
+        if options.prune_empty_dirs {
+            let dest_file_sets = file_ops::get_files_within(dest, options)?;
+            prune_empty_dirs(dest_file_sets.dirs(), dest);
+        }
+        return Ok(());
+    }
+
+    // Very large trees are synchronized one directory at a time, unless an option needs to know
+    // the whole tree first
+    if options.low_memory {
+        if streams(options)
+            && !(options.resume
+                || options.refuse_anomalies
+                || options.prune_empty_dirs
+                || options.safe_delete
+                || options.ignore_case)
+            && options.max_delete.is_none()
+        {
+            return synchronize_by_dir(src, dest, options);
+        }
+        warn!(
+            "Memory Warning -- --low-memory does not apply with options that need to know the \
+             whole tree, so {} and {} are listed completely",
+            src, dest
+        );
+    }
+
+    // Record completed files, and skip those completed by an interrupted run
+    let resume = options.resume;
+    let journal_options;
+    let options = if resume {
+        journal_options = Options {
+            journal: Some(Arc::new(Journal::open(dest)?)),
+            ..options.clone()
+        };
+        &journal_options
+    } else {
+        options
+    };
+
+    let Listing {
+        src_file_sets,
+        dest_file_sets,
+        src_complete,
+        shortened,
+        placeholders,
+        marked_dirs,
+        copied_links,
+        kept_links,
+    } = list(src, dest, options)?;
+
     let src_files = src_file_sets.files();
     let src_dirs = src_file_sets.dirs();
     let src_symlinks = src_file_sets.symlinks();
-
-    // Retrieve data from dest directory about files, dirs, symlinks
-    let dest_file_sets = file_ops::get_all_files(&dest)?;
+    let src_specials = src_file_sets.specials();
     let dest_files = dest_file_sets.files();
     let dest_dirs = dest_file_sets.dirs();
     let dest_symlinks = dest_file_sets.symlinks();
+    let dest_specials = dest_file_sets.specials();
 
     // Initialize progress bar
     progress::progress_init(
         (src_files.len()
             + src_dirs.len()
             + src_symlinks.len()
+            + src_specials.len()
             + dest_files.len()
             + dest_dirs.len()
-            + dest_symlinks.len()) as u64,
+            + dest_symlinks.len()
+            + dest_specials.len()
+            + placeholders.len()) as u64,
     );
 
-    // Determine whether or not to delete
-    let delete = !flags.contains(Flag::NO_DELETE);
+    // Determine whether or not to delete. What could not be listed in src is not missing from it,
+    // so nothing is deleted if src could not be listed completely
+    let delete = options.delete && src_complete;
+    if options.delete && !src_complete {
+        error!(
+            "Error -- {} could not be listed completely, so nothing is deleted from {}",
+            src, dest
+        );
+    }
 
-    // Delete files and symlinks
+    // Files that are already in dest are kept as they are with --ignore-existing, or with --update
+    // unless their sources are newer
+    let kept = kept_files(src_files, dest_files, src, dest, options);
+
+    // Files that are still in src, whatever their size, are replaced by their copies rather than
+    // deleted, so that dest never lacks them
+    let src_paths: HashSet<&PathBuf> = src_files.iter().map(|file| file.path()).collect();
+
+    // Find files that were renamed in src, to rename instead of deleting and copying them again.
+    // Files that are in the way of directories of src are deleted before those are created, so
+    // they are never renamed
+    let src_dir_paths: HashSet<&PathBuf> = src_dirs.iter().map(|dir| dir.path()).collect();
+    let renames = if delete {
+        file_ops::find_renames(
+            Vec::from_par_iter(
+                dest_files
+                    .par_difference(src_files)
+                    .filter(|file| !src_paths.contains(file.path()))
+                    .filter(|file| !src_dir_paths.contains(file.path()))
+                    .filter(|file| !placeholders.contains(*file)),
+            ),
+            Vec::from_par_iter(
+                src_files
+                    .par_difference(dest_files)
+                    .filter(|file| !kept.contains(file.path())),
+            ),
+            src,
+            dest,
+            options,
+        )
+    } else {
+        Vec::new()
+    };
+    let renamed_old: HashSet<&File> = renames.iter().map(|(old, _)| *old).collect();
+    let renamed_new: HashSet<&File> = renames.iter().map(|(_, new)| *new).collect();
+
+    // Files are deleted unless they are still in src, which includes those that are kept or
+    // replaced by a rename, were renamed, or are placeholders
+    let files_to_delete = || {
+        dest_files
+            .par_difference(src_files)
+            .filter(|file| !src_paths.contains(file.path()))
+            .filter(|file| !placeholders.contains(*file))
+            .filter(|file| !renamed_old.contains(file))
+    };
+
+    // Refuse to make changes that are far out of the ordinary, or that delete more than allowed,
+    // before anything is modified. Symlinks that are replaced are still in src, so only those
+    // that are no longer at their paths in src count as deleted
+    let to_delete = || {
+        let src_paths: HashSet<&PathBuf> = (src_files.iter().map(|file| file.path()))
+            .chain(src_symlinks.iter().map(|symlink| symlink.path()))
+            .collect();
+        let symlinks = dest_symlinks
+            .par_difference(src_symlinks)
+            .filter(|symlink| !src_paths.contains(symlink.path()));
+        (files_to_delete().count() + symlinks.count()) as u64
+    };
     if delete {
-        let symlinks_to_delete = dest_symlinks.par_difference(&src_symlinks);
-        let files_to_delete = dest_files.par_difference(&src_files);
+        let src_empty = src_files.is_empty() && src_symlinks.is_empty() && placeholders.is_empty();
+        let dest_count = (dest_files.len() + dest_symlinks.len()) as u64;
+        check_deletes(src, dest, src_empty, dest_count, to_delete, options)?;
+    }
 
-        file_ops::delete_files(symlinks_to_delete, &dest);
-        file_ops::delete_files(files_to_delete, &dest);
+    // Refuse to start what does not fit in dest, where what is deleted or replaced makes room
+    // unless it is quarantined
+    let files_to_copy = || {
+        src_files
+            .par_difference(dest_files)
+            .filter(|file| !renamed_new.contains(file))
+            .filter(|file| !kept.contains(file.path()))
+    };
+    let files_to_copy_paths: Vec<&Path> =
+        files_to_copy().map(|file| file.path().as_path()).collect();
+    let options = &with_lookup(dest, files_to_copy_paths, options);
+    space::check(
+        space::size_of(files_to_copy()),
+        || match (options.quarantine_files, delete) {
+            (true, _) => 0,
+            (false, true) => {
+                space::size_of(files_to_delete())
+                    + space::overwritten(files_to_copy(), dest, options)
+            }
+            (false, false) => space::overwritten(files_to_copy(), dest, options),
+        },
+        dest,
+        options,
+    )?;
+
+    // Keep what is replaced or deleted until the run completes
+    let options = &with_quarantine(dest, options)?;
+
+    // Kept files are done, both in src and in dest
+    PROGRESS_BAR.inc(2 * kept.len() as u64);
+
+    // Directories are no longer done once anything in them may change
+    if let Some(name) = &options.done_marker {
+        marker::remove_markers(name, marked_dirs.par_iter().map(PathBuf::as_path), dest);
     }
 
-    let dirs_to_copy = src_dirs.par_difference(&dest_dirs);
-    let symlinks_to_copy = src_symlinks.par_difference(&dest_symlinks);
-    let files_to_copy = src_files.par_difference(&dest_files);
-    let files_to_compare = src_files.par_intersection(&dest_files);
+    // Delete symlinks
+    if delete {
+        let symlinks_to_delete = dest_symlinks.par_difference(src_symlinks);
+        match &options.quarantine {
+            Some(quarantine) => quarantine.keep_files(symlinks_to_delete),
+            None => file_ops::delete_files(symlinks_to_delete, dest),
+        }
+
+        // Special files skipped in src without --specials are left as they are in dest
+        let skipped: HashSet<&PathBuf> = if options.specials {
+            HashSet::new()
+        } else {
+            src_specials.iter().map(|special| special.path()).collect()
+        };
+        let specials_to_delete = dest_specials
+            .par_difference(src_specials)
+            .filter(|special| !skipped.contains(special.path()));
+        match &options.quarantine {
+            Some(quarantine) => quarantine.keep_files(specials_to_delete),
+            None => file_ops::delete_files(specials_to_delete, dest),
+        }
+    }
+
+    let placeholders_to_create: Vec<Placeholder> = placeholders
+        .difference(dest_files)
+        .map(Placeholder::from)
+        .collect();
+
+    // Write everything, one fenced directory at a time
+    let phases = fence::Phases::new(&options.fences, src_dirs);
+    for phase in 0..phases.count() {
+        let in_phase = |path: &Path| phases.phase(path) == phase;
+
+        // Deleting only makes room for what is written, so it is never fenced. Files are deleted
+        // before directories are created, since they may be in their way
+        if delete && phase == 0 {
+            match &options.quarantine {
+                Some(quarantine) => quarantine.keep_files(files_to_delete()),
+                None => file_ops::delete_files(files_to_delete(), dest),
+            }
+        }
+
+        let dirs_to_copy = src_dirs
+            .par_difference(dest_dirs)
+            .filter(|dir| in_phase(dir.path()));
+        file_ops::create_dirs(dirs_to_copy, src, dest, options);
+
+        let renames_in_phase: Vec<(&File, &File)> = renames
+            .iter()
+            .filter(|(_, new)| in_phase(new.path()))
+            .copied()
+            .collect();
+        file_ops::rename_files(&renames_in_phase, src, dest, options);
+
+        let symlinks_to_copy = src_symlinks
+            .par_difference(dest_symlinks)
+            .filter(|symlink| in_phase(symlink.path()));
+        let specials_to_copy = src_specials
+            .par_difference(dest_specials)
+            .filter(|special| in_phase(special.path()));
+        let files_to_copy = files_to_copy()
+            .filter(|file| in_phase(file.path()))
+            .inspect(|file| {
+                debug!(
+                    "{:?} is copied, since it is not in {}, or has a different size there",
+                    file.path(),
+                    dest
+                )
+            });
+        let files_to_compare = src_files
+            .par_intersection(dest_files)
+            .filter(|file| !kept.contains(file.path()))
+            .filter(|file| match &options.journal {
+                Some(journal) if journal.is_completed(src, file.path()) => {
+                    debug!(
+                        "{:?} is skipped, since it was completed before",
+                        file.path()
+                    );
+                    false
+                }
+                _ => true,
+            })
+            .filter(|file| in_phase(file.path()));
+        let placeholders_to_create = placeholders_to_create
+            .par_iter()
+            .filter(|placeholder| in_phase(placeholder.path()));
+
+        file_ops::copy_files(symlinks_to_copy, src, dest, options);
+        file_ops::copy_files(specials_to_copy, src, dest, options);
+        file_ops::copy_files(files_to_copy, src, dest, options);
+        file_ops::compare_and_copy_files(files_to_compare, src, dest, options);
+        file_ops::copy_files(placeholders_to_create, src, dest, options);
+        // Files that were still being written are copied again before the phase is done
+        file_ops::copy_deferred(options);
+        phases.flush(phase, &src_file_sets, dest);
+    }
 
-    file_ops::copy_files(dirs_to_copy, &src, &dest);
-    file_ops::copy_files(symlinks_to_copy, &src, &dest);
-    file_ops::copy_files(files_to_copy, &src, &dest);
-    file_ops::compare_and_copy_files(files_to_compare, &src, &dest, flags);
+    // Bring the metadata of directories that are already in dest up to date
+    if options.copies_metadata() {
+        let dirs_to_update = src_dirs.par_intersection(dest_dirs);
+        file_ops::copy_files_metadata(dirs_to_update, src, dest, options);
+    }
 
     // Delete dirs in the correct order
     if delete {
-        let dirs_to_delete = dest_dirs.par_difference(&src_dirs);
+        // What is in directories at the bounds of the listing is not known, so they are never
+        // deleted
+        let dirs_to_delete = dest_dirs
+            .par_difference(src_dirs)
+            .filter(|dir| !dest_file_sets.is_bounded(dir.path()));
         let dirs_to_delete: Vec<&file_ops::Dir> = file_ops::sort_files(dirs_to_delete);
-        file_ops::delete_files_sequential(dirs_to_delete, &dest);
+        file_ops::delete_files_sequential(dirs_to_delete, dest);
+    }
+
+    for link in copied_links.iter().chain(&kept_links) {
+        if let Some(linked_options) = dirlinks::linked_options(options, link) {
+            synchronize(&join(src, link), &join(dest, link), &linked_options)?;
+        }
+    }
+    limits::write_shortened(&shortened, src, dest, options, synchronize)?;
+
+    // Nothing is finished if the run stopped for having too many errors
+    progress::check_stopped()?;
+
+    // Directories that are empty once everything is synchronized are deleted, deepest first
+    if options.prune_empty_dirs {
+        prune_empty_dirs(src_dirs, dest);
+    }
+
+    // Share one copy of identical files
+    #[cfg(target_family = "unix")]
+    if options.dedup {
+        let (linked, freed) = dedup::dedup(dest, src_files);
+        info!("Linked {} identical files, freeing {} bytes", linked, freed);
+    }
+
+    // Every file was flushed as it was copied, which leaves the directories that hold them
+    if options.fsync {
+        file_ops::sync_dirs(src_dirs, dest)?;
+    }
+
+    if let Some(name) = &options.done_marker {
+        let marked = marker::write_markers(name, &src_file_sets, src, dest, options);
+        info!("Marked {} directories as done", marked);
+    }
+
+    // Everything is done, so there is nothing left to resume
+    if let Some(journal) = &options.journal {
+        journal.finish(dest)?;
+    }
+
+    match &options.quarantine {
+        Some(quarantine) => quarantine.finish(),
+        None => Ok(()),
+    }
+}
+
+/// What `synchronize` lists of `src` and `dest`, once the entries that options leave out, write
+/// elsewhere, or write by runs of their own, are left out of both listings
+pub struct Listing {
+    /// Entries of `src` that are synchronized
+    pub src_file_sets: FileSets,
+    /// Entries of `dest` that are compared with them
+    pub dest_file_sets: FileSets,
+    /// Whether `src` was listed completely, without which nothing is deleted
+    pub src_complete: bool,
+    /// Entries that are written under other names, see `limits::write_shortened`
+    pub shortened: Vec<limits::Shortened>,
+    /// Files that are written as empty placeholders, see `materialize`
+    pub placeholders: HashSet<File>,
+    /// Directories of `dest` that were marked as done, see `marker::unlist`
+    marked_dirs: Vec<PathBuf>,
+    /// Symlinks of `src` whose directories are copied by runs of their own
+    copied_links: Vec<PathBuf>,
+    /// Symlinks of `dest` whose directories are written through them by runs of their own
+    kept_links: Vec<PathBuf>,
+}
+
+/// Lists `src` and `dest` as `synchronize` does, with every option that changes what is compared
+///
+/// With `Options::dry_run`, nothing is written while listing them: entries that only differ in case
+/// are not renamed, the materialization state is not recorded, and transformations are not
+/// previewed.
+///
+/// # Errors
+/// This function will return an error if `src` or `dest` could not be listed, or if an option
+/// refuses what is listed, such as `ShortenNames::Error`
+pub fn list(src: &str, dest: &str, options: &Options) -> Result<Listing, io::Error> {
+    // Retrieve data from src directory about files, dirs, symlinks
+    let mut src_file_sets = file_ops::get_files_within(src, options)?;
+    if options.resume {
+        src_file_sets.remove_file(Path::new(JOURNAL_NAME));
+    }
+    // Markers in src would mark directories as done before they are
+    if let Some(name) = &options.done_marker {
+        marker::unlist(&mut src_file_sets, name);
+    }
+    // Directories linked to in src are copied as directories of their own
+    let copied_links = dirlinks::copy_dirlinks(&mut src_file_sets, src, options);
+    // Entries that are too long for dest are reported before anything is written
+    let shortened = limits::check(&mut src_file_sets, dest, options)?;
+    capabilities::unlist_symlinks(&mut src_file_sets, dest, options);
+    // Targets are kept as they are in src, to preview how they are rewritten
+    let original_symlinks = options.preview.map(|_| src_file_sets.symlinks().clone());
+    // Links into src are made relative before the links that are left are rewritten
+    if options.relativize_links {
+        rewrite::relativize_targets(src, &mut src_file_sets)?;
+    }
+    rewrite::rewrite_targets(&options.link_rewrites, &mut src_file_sets);
+    let src_complete = src_file_sets.is_complete();
+    let (mut src_file_sets, placeholders) = materialize(src_file_sets, src, dest, options)?;
+    // Show how entries are transformed, and only go on once that is confirmed, which dry runs
+    // do not ask for
+    if let (Some(limit), Some(symlinks), false) =
+        (options.preview, &original_symlinks, options.dry_run)
+    {
+        let mappings = preview::mappings(symlinks, &src_file_sets, &placeholders);
+        preview::confirm(limit, &mappings, src, dest)?;
+    }
+
+    // Retrieve data from dest directory about files, dirs, symlinks
+    let mut dest_file_sets = file_ops::get_files_within(dest, options)?;
+    if options.resume {
+        dest_file_sets.remove_file(Path::new(JOURNAL_NAME));
+    }
+    if options.materialize.is_some() {
+        dest_file_sets.remove_file(Path::new(materialize::STATE_NAME));
+    }
+    if options.quarantine_files {
+        quarantine::unlist(&mut dest_file_sets);
+    }
+    limits::unlist(&mut dest_file_sets, &shortened, options);
+    let marked_dirs = match &options.done_marker {
+        Some(name) => marker::unlist(&mut dest_file_sets, name),
+        None => Vec::new(),
+    };
+
+    // Directories reached through symlinks are left to runs of their own, with the symlinks as
+    // their roots
+    dirlinks::unlist(&mut dest_file_sets, &copied_links, true);
+    let kept_links = dirlinks::keep_dirlinks(&src_file_sets, &dest_file_sets, dest, options);
+    dirlinks::unlist(&mut src_file_sets, &kept_links, false);
+    dirlinks::unlist(&mut dest_file_sets, &kept_links, false);
+
+    // Entries that only differ in case are the same entry in case-insensitive destinations
+    if options.ignore_case {
+        casefold::unlist_collisions(&mut src_file_sets, src);
+        casefold::match_case(&src_file_sets, &mut dest_file_sets, dest, options);
+    }
+
+    Ok(Listing {
+        src_file_sets,
+        dest_file_sets,
+        src_complete,
+        shortened,
+        placeholders,
+        marked_dirs,
+        copied_links,
+        kept_links,
+    })
+}
+
+/// Synchronizes `dest` with `src` like `synchronize`, one directory at a time, with
+/// `Options::low_memory`
+///
+/// Each directory of `src` is compared with the same directory of `dest` as soon as it is listed,
+/// see `file_ops::walk`, so only the entries of the directories that are being compared are held
+/// in memory, however large the trees are. Files that were renamed are not found, so they are
+/// copied again rather than renamed.
+///
+/// # Errors
+/// This function will return an error if `src` or `dest` is an invalid directory, if a directory
+/// did not fit in `dest`, or if the run stopped for having too many errors
+fn synchronize_by_dir(src: &str, dest: &str, options: &Options) -> Result<(), io::Error> {
+    progress::progress_init(0);
+    // Directories of dest that are not listed, such as mount points with --one-file-system,
+    // along with those below them
+    let unlisted: Mutex<HashSet<PathBuf>> = Mutex::new(HashSet::new());
+    let shortened = Mutex::new(Vec::new());
+    // The directory that did not fit in dest, after which nothing else is written
+    let out_of_space: Mutex<Option<io::Error>> = Mutex::new(None);
+    // The first directory that could not be flushed with --fsync
+    let unflushed: Mutex<Option<io::Error>> = Mutex::new(None);
+
+    file_ops::walk(src, options, &|relative, src_file_sets| {
+        if out_of_space.lock().unwrap().is_some() {
+            src_file_sets.retain_paths(|_| false);
+            return;
+        }
+        // Entries that are too long for dest are reported before they are written
+        match limits::check(src_file_sets, dest, options) {
+            // Shortened entries are written once everything else is, like in `synchronize`
+            Ok(entries) => shortened.lock().unwrap().extend(entries),
+            Err(e) => error!("{}", e),
+        }
+        capabilities::unlist_symlinks(src_file_sets, dest, options);
+
+        let is_unlisted = unlisted.lock().unwrap().remove(relative);
+        let dest_file_sets = if is_unlisted {
+            None
+        } else {
+            match file_ops::list_within(dest, relative, options) {
+                Ok(mut dest_file_sets) => {
+                    limits::unlist(&mut dest_file_sets, &shortened.lock().unwrap(), options);
+                    Some(dest_file_sets)
+                }
+                Err(e) => {
+                    error!(
+                        "Error -- Listing {:?}, so nothing is deleted from it: {}",
+                        Path::new(dest).join(relative),
+                        e
+                    );
+                    None
+                }
+            }
+        };
+        // What is below a directory that is not listed is not listed either
+        if is_unlisted || dest_file_sets.is_none() {
+            let mut unlisted = unlisted.lock().unwrap();
+            unlisted.extend(src_file_sets.dirs().iter().map(|dir| dir.path().clone()));
+        } else if let Some(dest_file_sets) = &dest_file_sets {
+            let mut unlisted = unlisted.lock().unwrap();
+            unlisted.extend(
+                dest_file_sets
+                    .dirs()
+                    .iter()
+                    .map(|dir| dir.path())
+                    .filter(|path| dest_file_sets.is_bounded(path))
+                    .cloned(),
+            );
+        }
+
+        if let Err(e) = synchronize_dir(src_file_sets, dest_file_sets.as_ref(), src, dest, options)
+        {
+            src_file_sets.retain_paths(|_| false);
+            out_of_space.lock().unwrap().get_or_insert(e);
+        } else {
+            flush_dir(relative, dest, options, &unflushed);
+        }
+    })?;
+    if let Some(e) = out_of_space.into_inner().unwrap() {
+        return Err(e);
+    }
+    // Files that were still being written are copied again once everything else is
+    file_ops::copy_deferred(options);
+    let shortened = shortened.into_inner().unwrap();
+    limits::write_shortened(&shortened, src, dest, options, synchronize)?;
+
+    // Nothing is finished if the run stopped for having too many errors
+    progress::check_stopped()?;
+    match unflushed.into_inner().unwrap() {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// Flushes the directory `relative` of `dest` to disk with `Options::fsync`, once its entries are
+/// written by a run that writes each directory as soon as it is listed, keeping the first error
+/// in `unflushed`
+///
+/// Every file was flushed as it was copied, and every file that is copied again later flushes
+/// its directory itself, see `dirfd::copy_file`, which leaves the directories that were created
+/// in `relative`.
+fn flush_dir(relative: &Path, dest: &str, options: &Options, unflushed: &Mutex<Option<io::Error>>) {
+    if !options.fsync {
+        return;
+    }
+    if let Err(e) = file_ops::sync_dir(relative, dest) {
+        unflushed.lock().unwrap().get_or_insert(e);
+    }
+}
+
+/// Synchronizes the entries of one directory, `src_file_sets`, with `dest_file_sets`, the entries
+/// of the same directory in `dest`, for `synchronize_by_dir`
+///
+/// Nothing is deleted if `dest_file_sets` is None, since what is in the directory is not known.
+///
+/// # Errors
+/// This function will return an error if what is copied into the directory does not fit in
+/// `dest`, see `space::check`, in which case nothing in it is written
+fn synchronize_dir(
+    src_file_sets: &FileSets,
+    dest_file_sets: Option<&FileSets>,
+    src: &str,
+    dest: &str,
+    options: &Options,
+) -> Result<(), io::Error> {
+    let empty = FileSets::with(HashSet::new(), HashSet::new(), HashSet::new());
+    let delete = options.delete && src_file_sets.is_complete() && dest_file_sets.is_some();
+    let dest_file_sets = dest_file_sets.unwrap_or(&empty);
+
+    let src_files = src_file_sets.files();
+    let src_dirs = src_file_sets.dirs();
+    let src_symlinks = src_file_sets.symlinks();
+    let src_specials = src_file_sets.specials();
+    let dest_files = dest_file_sets.files();
+    let dest_dirs = dest_file_sets.dirs();
+    let dest_symlinks = dest_file_sets.symlinks();
+    let dest_specials = dest_file_sets.specials();
+    PROGRESS_BAR.inc_length(
+        (src_files.len()
+            + src_dirs.len()
+            + src_symlinks.len()
+            + src_specials.len()
+            + dest_files.len()
+            + dest_dirs.len()
+            + dest_symlinks.len()
+            + dest_specials.len()) as u64,
+    );
+
+    let kept = kept_files(src_files, dest_files, src, dest, options);
+    let files_to_copy = || {
+        src_files
+            .par_difference(dest_files)
+            .filter(|file| !kept.contains(file.path()))
+    };
+    // Files that are still in src are replaced by their copies rather than deleted
+    let src_paths: HashSet<&PathBuf> = src_files.iter().map(|file| file.path()).collect();
+    let files_to_delete = || {
+        dest_files
+            .par_difference(src_files)
+            .filter(|file| !src_paths.contains(file.path()))
+    };
+    // Nothing in the directory is written if it does not fit in what is left of dest
+    space::check(
+        space::size_of(files_to_copy()),
+        || {
+            if delete {
+                space::size_of(files_to_delete())
+                    + space::overwritten(files_to_copy(), dest, options)
+            } else {
+                space::overwritten(files_to_copy(), dest, options)
+            }
+        },
+        dest,
+        options,
+    )?;
+    PROGRESS_BAR.inc(2 * kept.len() as u64);
+
+    if delete {
+        file_ops::delete_files(dest_symlinks.par_difference(src_symlinks), dest);
+        // Special files skipped in src without --specials are left as they are in dest
+        let skipped: HashSet<&PathBuf> = if options.specials {
+            HashSet::new()
+        } else {
+            src_specials.iter().map(|special| special.path()).collect()
+        };
+        let specials_to_delete = dest_specials
+            .par_difference(src_specials)
+            .filter(|special| !skipped.contains(special.path()));
+        file_ops::delete_files(specials_to_delete, dest);
+        // Files are deleted before directories are created, since they may be in their way
+        file_ops::delete_files(files_to_delete(), dest);
+    }
+
+    file_ops::create_dirs(src_dirs.par_difference(dest_dirs), src, dest, options);
+
+    let files_to_compare = src_files
+        .par_intersection(dest_files)
+        .filter(|file| !kept.contains(file.path()));
+    file_ops::copy_files(
+        src_symlinks.par_difference(dest_symlinks),
+        src,
+        dest,
+        options,
+    );
+    file_ops::copy_files(
+        src_specials.par_difference(dest_specials),
+        src,
+        dest,
+        options,
+    );
+    file_ops::copy_files(files_to_copy(), src, dest, options);
+    file_ops::compare_and_copy_files(files_to_compare, src, dest, options);
+    if options.copies_metadata() {
+        let dirs_to_update = src_dirs.par_intersection(dest_dirs);
+        file_ops::copy_files_metadata(dirs_to_update, src, dest, options);
     }
 
+    // Directories that are no longer in src are deleted along with everything in them
+    if delete {
+        dest_dirs
+            .par_difference(src_dirs)
+            .filter(|dir| !dest_file_sets.is_bounded(dir.path()))
+            .for_each(|dir| remove_tree(dest, dir, options));
+    }
     Ok(())
 }
 
+/// Deletes the directory `dir` inside `dest` and everything in it, one directory at a time, for
+/// `synchronize_by_dir`
+///
+/// Directories at the bounds of `options` are left as they are, along with those that contain
+/// them.
+fn remove_tree(dest: &str, dir: &Dir, options: &Options) {
+    let root = join(dest, dir.path());
+    let options = match dirlinks::linked_options(options, dir.path()) {
+        Some(options) => options,
+        None => return,
+    };
+    let dirs = Mutex::new(Vec::new());
+    let bounded = Mutex::new(Vec::new());
+
+    let walked = file_ops::walk(&root, &options, &|_, file_sets| {
+        PROGRESS_BAR.inc_length(
+            (file_sets.files().len()
+                + file_sets.dirs().len()
+                + file_sets.symlinks().len()
+                + file_sets.specials().len()) as u64,
+        );
+        file_ops::delete_files(file_sets.files().par_iter(), &root);
+        file_ops::delete_files(file_sets.symlinks().par_iter(), &root);
+        file_ops::delete_files(file_sets.specials().par_iter(), &root);
+        for dir in file_sets.dirs() {
+            if file_sets.is_bounded(dir.path()) {
+                bounded.lock().unwrap().push(dir.path().clone());
+            }
+            dirs.lock().unwrap().push(dir.clone());
+        }
+    });
+    if let Err(e) = walked {
+        error!("Error -- Deleting dir {:?}: {}", root, e);
+        return;
+    }
+
+    let bounded = bounded.into_inner().unwrap();
+    let mut dirs = dirs.into_inner().unwrap();
+    dirs.retain(|dir| !bounded.iter().any(|path| path.starts_with(dir.path())));
+    let mut dirs = file_ops::sort_files(dirs.par_iter());
+    // The directory itself is deleted last
+    let root_dir = Dir::from("");
+    if bounded.is_empty() {
+        dirs.push(&root_dir);
+    } else {
+        PROGRESS_BAR.inc(1);
+    }
+    file_ops::delete_files_sequential(dirs, &root);
+}
+
 /// Copies all files, directories, and symlinks in `src` to `dest`
 ///
 /// # Arguments
-/// * `src`: Source directory
-/// * `dest`: Destination directory
-/// * `flags`: set for Flag's
+/// * `src`: Source directory, a tar or zip archive to extract, see `archive::is_archive_src`, or
+///   another file to copy to the file `dest`
+/// * `dest`: Destination directory, or a tar archive to write, see `archive::is_archive`
+/// * `options`: Options of the run
 ///
 /// # Errors
 /// This function will return an error in the following situations,
 /// but is not limited to just these cases:
 /// * `src` is an invalid directory
 /// * `dest` is an invalid directory
-pub fn copy(src: &str, dest: &str, _flags: Flag) -> Result<(), io::Error> {
-    // Retrieve data from src directory about files, dirs, symlinks
-    let src_file_sets = file_ops::get_all_files(&src)?;
+/// * `src` is a file, and `dest` is not a path to copy it to
+/// * `src` is an archive that could not be extracted
+/// * `dest` is an archive that could not be written
+/// * What the copy writes does not fit in `dest`, see `space::check`
+/// * `options` has materialization rules, and the materialization state of `dest` could not be
+///   written
+/// * `Options::quarantine_files` is set, and the quarantine in `dest` could not be created or
+///   purged
+pub fn copy(src: &str, dest: &str, options: &Options) -> Result<(), io::Error> {
+    // What dest does not support is turned off before anything is written to it
+    let probed_options = capabilities::probe(dest, options);
+    let options = &for_run(probed_options.as_ref().unwrap_or(options));
+
+    // Archive sources and destinations are read and written by archive readers and writers
+    if archive::is_archive_src(src) {
+        return archive::copy_from_archive(src, dest, options);
+    }
+    // Other files are copied on their own
+    if fs::metadata(src)?.is_file() {
+        return copy_file(src, dest, options);
+    }
+    if archive::is_archive(dest) {
+        return archive::copy_to_archive(src, dest, options);
+    }
+    // Unless an option needs the whole tree first, entries are copied as soon as they are listed
+    if streams(options) {
+        return copy_streaming(src, dest, options);
+    }
+
+    // Retrieve data from src directory about files, dirs, symlinks, or only about those listed
+    let mut src_file_sets = match &options.files_from {
+        Some(paths) => file_ops::get_files_listed(src, paths, options.filter.as_ref()),
+        None => file_ops::get_files_within(src, options)?,
+    };
+    // Markers in src would mark directories as done before they are
+    if let Some(name) = &options.done_marker {
+        marker::unlist(&mut src_file_sets, name);
+    }
+    // Directories linked to in src are copied as directories of their own
+    let copied_links = dirlinks::copy_dirlinks(&mut src_file_sets, src, options);
+    // Entries that are too long for dest are reported before anything is written
+    let shortened = limits::check(&mut src_file_sets, dest, options)?;
+    capabilities::unlist_symlinks(&mut src_file_sets, dest, options);
+    // Targets are kept as they are in src, to preview how they are rewritten
+    let original_symlinks = options.preview.map(|_| src_file_sets.symlinks().clone());
+    // Links into src are made relative before the links that are left are rewritten
+    if options.relativize_links {
+        rewrite::relativize_targets(src, &mut src_file_sets)?;
+    }
+    rewrite::rewrite_targets(&options.link_rewrites, &mut src_file_sets);
+    let (mut src_file_sets, placeholders) = materialize(src_file_sets, src, dest, options)?;
+    // Show how entries are transformed, and only go on once that is confirmed
+    if let (Some(limit), Some(symlinks)) = (options.preview, &original_symlinks) {
+        let mappings = preview::mappings(symlinks, &src_file_sets, &placeholders);
+        preview::confirm(limit, &mappings, src, dest)?;
+    }
+    // Directories reached through symlinks in dest are left to copies of their own, with the
+    // symlinks as their roots
+    let kept_links = dirlinks::find_dirlinks(&src_file_sets, dest, options);
+    dirlinks::unlist(&mut src_file_sets, &kept_links, false);
+    // Keep what is replaced until the run completes
+    let options = &with_quarantine(dest, options)?;
     let src_files = src_file_sets.files();
     let src_dirs = src_file_sets.dirs();
     let src_symlinks = src_file_sets.symlinks();
+    let src_specials = src_file_sets.specials();
+    let placeholders: Vec<Placeholder> = placeholders.iter().map(Placeholder::from).collect();
 
     // Initialize progress bar
-    progress::progress_init((src_files.len() + src_dirs.len() + src_symlinks.len()) as u64);
+    progress::progress_init(
+        (src_files.len()
+            + src_dirs.len()
+            + src_symlinks.len()
+            + src_specials.len()
+            + placeholders.len()) as u64,
+    );
+
+    let options = &with_lookup(
+        dest,
+        src_files.iter().map(|file| file.path().as_path()),
+        options,
+    );
+
+    // Files that are already in dest are kept as they are with --ignore-existing, or with --update
+    // unless their sources are newer
+    let kept: HashSet<&PathBuf> = if keeps_any(options) {
+        src_files
+            .par_iter()
+            .map(|file| file.path())
+            .filter(|path| file_ops::keeps_existing(path, src, dest, options))
+            .collect()
+    } else {
+        HashSet::new()
+    };
+    PROGRESS_BAR.inc(kept.len() as u64);
+
+    // Refuse to start what does not fit in dest, where what is overwritten makes room unless it
+    // is quarantined
+    let files_to_copy = || {
+        src_files
+            .par_iter()
+            .filter(|file| !kept.contains(file.path()))
+    };
+    space::check(
+        space::size_of(files_to_copy()),
+        || match &options.quarantine {
+            Some(_) => 0,
+            None => space::overwritten(files_to_copy(), dest, options),
+        },
+        dest,
+        options,
+    )?;
+
+    // Directories are no longer done once anything in them may change
+    if let Some(name) = &options.done_marker {
+        let dirs = src_dirs.par_iter().map(|dir| dir.path().as_path());
+        marker::remove_markers(name, dirs.chain(rayon::iter::once(Path::new(""))), dest);
+    }
+
+    // Copy everything, one fenced directory at a time
+    let phases = fence::Phases::new(&options.fences, src_dirs);
+    for phase in 0..phases.count() {
+        let in_phase = |path: &Path| phases.phase(path) == phase;
+        let dirs_to_copy = src_dirs.par_iter().filter(|dir| in_phase(dir.path()));
+        let files_to_copy = files_to_copy().filter(|file| in_phase(file.path()));
+        let symlinks_to_copy = src_symlinks
+            .par_iter()
+            .filter(|symlink| in_phase(symlink.path()));
+        let specials_to_copy = src_specials
+            .par_iter()
+            .filter(|special| in_phase(special.path()));
+        let placeholders_to_create = placeholders
+            .par_iter()
+            .filter(|placeholder| in_phase(placeholder.path()));
+
+        file_ops::create_dirs(dirs_to_copy, src, dest, options);
+        file_ops::copy_files(files_to_copy, src, dest, options);
+        file_ops::copy_files(symlinks_to_copy, src, dest, options);
+        file_ops::copy_files(specials_to_copy, src, dest, options);
+        file_ops::copy_files(placeholders_to_create, src, dest, options);
+        // Files that were still being written are copied again before the phase is done
+        file_ops::copy_deferred(options);
+        phases.flush(phase, &src_file_sets, dest);
+    }
 
-    // Copy everything
-    file_ops::copy_files(src_dirs.into_par_iter(), &src, &dest);
-    file_ops::copy_files(src_files.into_par_iter(), &src, &dest);
-    file_ops::copy_files(src_symlinks.into_par_iter(), &src, &dest);
+    for link in copied_links.iter().chain(&kept_links) {
+        if let Some(linked_options) = dirlinks::linked_options(options, link) {
+            copy(&join(src, link), &join(dest, link), &linked_options)?;
+        }
+    }
+    limits::write_shortened(&shortened, src, dest, options, copy)?;
+
+    // Nothing is finished if the run stopped for having too many errors
+    progress::check_stopped()?;
+
+    // Share one copy of identical files
+    #[cfg(target_family = "unix")]
+    if options.dedup {
+        let (linked, freed) = dedup::dedup(dest, src_files);
+        info!("Linked {} identical files, freeing {} bytes", linked, freed);
+    }
+
+    // Every file was flushed as it was copied, which leaves the directories that hold them
+    if options.fsync {
+        file_ops::sync_dirs(src_dirs, dest)?;
+    }
+
+    if let Some(name) = &options.done_marker {
+        let marked = marker::write_markers(name, &src_file_sets, src, dest, options);
+        info!("Marked {} directories as done", marked);
+    }
+
+    match &options.quarantine {
+        Some(quarantine) => quarantine.finish(),
+        None => Ok(()),
+    }
+}
+
+/// Copies the single file `src` to the file `dest`, which is overwritten if it exists
+///
+/// The file is copied like the files of a directory are, from the parent of `src` to the parent
+/// of `dest`, so that it can be copied under another name.
+///
+/// # Errors
+/// This function will return an error if `src` or `dest` has no name, or if `src` does not fit
+/// in `dest`, see `space::check`
+fn copy_file(src: &str, dest: &str, options: &Options) -> Result<(), io::Error> {
+    let split = |path: &str| {
+        let path = Path::new(path);
+        match (path.parent(), path.file_name()) {
+            (Some(parent), Some(name)) if parent == Path::new("") => {
+                Ok((PathBuf::from("."), PathBuf::from(name)))
+            }
+            (Some(parent), Some(name)) => Ok((parent.to_path_buf(), PathBuf::from(name))),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Error -- {} is not a path to a file", path.display()),
+            )),
+        }
+    };
+    let (src_dir, from) = split(src)?;
+    let (dest_dir, to) = split(dest)?;
+
+    progress::progress_init(1);
+    if !file_ops::keeps_existing_at(Path::new(src), Path::new(dest), options) {
+        let file = File::from(&from.to_string_lossy(), fs::metadata(src)?.len());
+        let overwritten = || {
+            fs::symlink_metadata(dest)
+                .ok()
+                .filter(|metadata| metadata.is_file())
+                .map_or(0, |metadata| metadata.len())
+        };
+        space::check(
+            file.size(),
+            overwritten,
+            &dest_dir.to_string_lossy(),
+            options,
+        )?;
+
+        file.copy_as(&src_dir, &dest_dir, &to, options);
+        file_ops::copy_deferred(options);
+    }
+    PROGRESS_BAR.inc(1);
+    progress::check_stopped()
+}
+
+/// Deletes the directories of `dirs` that are empty in `dest`, with `Options::prune_empty_dirs`,
+/// see `file_ops::prune_empty_dirs`
+fn prune_empty_dirs(dirs: &HashSet<Dir>, dest: &str) {
+    let pruned = file_ops::prune_empty_dirs(dirs.par_iter(), dest);
+    info!("Pruned {} empty directories", pruned);
+}
+
+/// Joins `base` and the relative `path` into the root of a run of its own
+fn join(base: &str, path: &Path) -> String {
+    Path::new(base).join(path).to_string_lossy().into_owned()
+}
+
+/// Checks whether a copy with `options` can write the entries of each directory as soon as it is
+/// listed, which it can unless an option needs to know the whole tree first
+fn streams(options: &Options) -> bool {
+    options.done_marker.is_none()
+        && options.files_from.is_none()
+        && options.fences.is_empty()
+        && options.link_rewrites.is_empty()
+        && options.materialize.is_none()
+        && options.preview.is_none()
+        && !(options.relativize_links
+            || options.copy_dirlinks
+            || options.keep_dirlinks
+            || options.dedup
+            || options.quarantine_files)
+        && options.shorten_names != Some(ShortenNames::Error)
+}
+
+/// Copies `src` to `dest` like `copy`, writing the entries of each directory as soon as it is
+/// listed, see `file_ops::walk`
+///
+/// # Errors
+/// This function will return an error if `src` is an invalid directory, if a directory did not
+/// fit in `dest`, or if the run stopped for having too many errors
+fn copy_streaming(src: &str, dest: &str, options: &Options) -> Result<(), io::Error> {
+    progress::progress_init(0);
+
+    let shortened = Mutex::new(Vec::new());
+    // The directory that did not fit in dest, after which nothing else is written
+    let out_of_space: Mutex<Option<io::Error>> = Mutex::new(None);
+    // The first directory that could not be flushed with --fsync
+    let unflushed: Mutex<Option<io::Error>> = Mutex::new(None);
+    file_ops::walk(src, options, &|relative, file_sets| {
+        if out_of_space.lock().unwrap().is_some() {
+            file_sets.retain_paths(|_| false);
+            return;
+        }
+        // Entries that are too long for dest are reported before they are written
+        match limits::check(file_sets, dest, options) {
+            // Shortened entries are written once everything else is, like in `copy`
+            Ok(entries) => shortened.lock().unwrap().extend(entries),
+            Err(e) => error!("{}", e),
+        }
+        capabilities::unlist_symlinks(file_sets, dest, options);
+        let files = file_sets.files();
+        let dirs = file_sets.dirs();
+        let symlinks = file_sets.symlinks();
+        let specials = file_sets.specials();
+        PROGRESS_BAR
+            .inc_length((files.len() + dirs.len() + symlinks.len() + specials.len()) as u64);
+
+        let kept: HashSet<&PathBuf> = if keeps_any(options) {
+            files
+                .par_iter()
+                .map(|file| file.path())
+                .filter(|path| file_ops::keeps_existing(path, src, dest, options))
+                .collect()
+        } else {
+            HashSet::new()
+        };
+        PROGRESS_BAR.inc(kept.len() as u64);
+
+        // Nothing in the directory is written if it does not fit in what is left of dest
+        let files_to_copy = || files.par_iter().filter(|file| !kept.contains(file.path()));
+        let needed = space::size_of(files_to_copy());
+        if let Err(e) = space::check(
+            needed,
+            || space::overwritten(files_to_copy(), dest, options),
+            dest,
+            options,
+        ) {
+            // Its subdirectories are skipped as they are visited
+            out_of_space.lock().unwrap().get_or_insert(e);
+            return;
+        }
+
+        // Subdirectories are created before they are listed, so what is in them has somewhere to go
+        file_ops::create_dirs(dirs.par_iter(), src, dest, options);
+        file_ops::copy_files(files_to_copy(), src, dest, options);
+        file_ops::copy_files(symlinks.par_iter(), src, dest, options);
+        file_ops::copy_files(specials.par_iter(), src, dest, options);
+        flush_dir(relative, dest, options, &unflushed);
+    })?;
+    if let Some(e) = out_of_space.into_inner().unwrap() {
+        return Err(e);
+    }
+    // Files that were still being written are copied again once everything else is
+    file_ops::copy_deferred(options);
+    let shortened = shortened.into_inner().unwrap();
+    limits::write_shortened(&shortened, src, dest, options, copy)?;
+
+    // Nothing is finished if the run stopped for having too many errors
+    progress::check_stopped()?;
+    match unflushed.into_inner().unwrap() {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// Checks whether `options` keeps any files that are already in the destination, see
+/// `file_ops::keeps_existing`
+fn keeps_any(options: &Options) -> bool {
+    options.ignore_existing || options.update
+}
 
+/// Finds the files of `src_files` that are kept as they are in `dest`, instead of being
+/// overwritten, see `file_ops::keeps_existing`
+///
+/// Only files that are in `dest_files`, whatever their size, are kept, unless they are empty while
+/// their sources are not and `Options::heal_empty_files` is set. With `Options::ignore_existing`
+/// these are known from the listing, so only `Options::update` has to look them up.
+///
+/// # Returns
+/// The paths of the kept files
+pub(crate) fn kept_files<'a>(
+    src_files: &'a HashSet<File>,
+    dest_files: &HashSet<File>,
+    src: &str,
+    dest: &str,
+    options: &Options,
+) -> HashSet<&'a PathBuf> {
+    if !keeps_any(options) {
+        return HashSet::new();
+    }
+    let dest_sizes: HashMap<&PathBuf, u64> = dest_files
+        .iter()
+        .map(|file| (file.path(), file.size()))
+        .collect();
+    let heal = options.heal_empty_files;
+    src_files
+        .par_iter()
+        .filter(|file| match dest_sizes.get(file.path()) {
+            Some(0) if heal && file.size() > 0 => false,
+            Some(_) => true,
+            None => false,
+        })
+        .map(|file| file.path())
+        .filter(|path| {
+            options.ignore_existing || file_ops::keeps_existing(path, src, dest, options)
+        })
+        .collect()
+}
+
+/// Gets `options` with a new lookup of what is missing from `dest`, where the directories that
+/// would hold `paths` are read up front, so that the entries a run writes are not looked up one at
+/// a time, see `lookup`
+fn with_lookup<'a, I>(dest: &str, paths: I, options: &Options) -> Options
+where
+    I: IntoIterator<Item = &'a Path>,
+{
+    let lookup = Lookup::new(dest);
+    lookup.prefetch(paths);
+    Options {
+        lookup: Some(Arc::new(lookup)),
+        ..options.clone()
+    }
+}
+
+/// Refuses to delete what the guards of a sync do not allow, before anything is modified
+///
+/// # Arguments
+/// * `src`: Source directory
+/// * `dest`: Destination directory
+/// * `src_empty`: Whether `src` has no files, symlinks or placeholders
+/// * `dest_count`: The number of files and symlinks in `dest`
+/// * `to_delete`: Counts the files and symlinks of `dest` that the sync deletes
+/// * `options`: Options of the run
+///
+/// # Errors
+/// This function will return an error in the following situations:
+/// * `Options::safe_delete` is set, and `src` is empty while `dest` is not, unless
+///   `Options::force_empty_source` is set
+/// * `Options::refuse_anomalies` is set, and the sync would delete far more than usual, see
+///   `history::check_plan`
+/// * The sync would delete more of `dest` than `options.max_delete` allows
+pub(crate) fn check_deletes<F>(
+    src: &str,
+    dest: &str,
+    src_empty: bool,
+    dest_count: u64,
+    to_delete: F,
+    options: &Options,
+) -> Result<(), io::Error>
+where
+    F: Fn() -> u64,
+{
+    // A src without files is more likely a mount point that was not mounted than a tree whose
+    // files were all deleted, so dest is not emptied along with it
+    if options.safe_delete && !options.force_empty_source && src_empty && dest_count > 0 {
+        return Err(io::Error::other(format!(
+            "Delete Error -- {} has no files, but {} has {}, so it may not be mounted; \
+             nothing was deleted (--force-empty-source deletes them anyway)",
+            src, dest, dest_count
+        )));
+    }
+    if options.refuse_anomalies && !options.force {
+        history::check_plan(options.norms.as_ref(), dest_count, to_delete())?;
+    }
+    if let Some(max_delete) = options.max_delete {
+        max_delete.check(dest_count, to_delete())?;
+    }
     Ok(())
 }
 
+/// Gets `options` for a run of its own
+///
+/// The run has a queue of deferred files of its own, so that runs within a run, such as of
+/// directories reached through symlinks, and runs of other callers never copy each other's
+/// deferred files, see `file_ops::copy_deferred`. It copies files with the copy workers of the run
+/// it is part of, or with workers of its own, see `workers::copy_workers`.
+pub(crate) fn for_run(options: &Options) -> Options {
+    Options {
+        workers: Some(workers::copy_workers(options)),
+        deferred: Arc::default(),
+        ..options.clone()
+    }
+}
+
+/// Gets `options` with a new quarantine in `dest` when `Options::quarantine_files` is set, so that
+/// the files a run replaces or deletes are kept until it completes
+///
+/// # Errors
+/// This function will return an error if the quarantine could not be created
+fn with_quarantine(dest: &str, options: &Options) -> Result<Options, io::Error> {
+    let quarantine = if options.quarantine_files {
+        Some(Arc::new(Quarantine::create(dest)?))
+    } else {
+        None
+    };
+    Ok(Options {
+        quarantine,
+        ..options.clone()
+    })
+}
+
+/// Moves directory `src` to `dest`
+///
+/// `src` is renamed if it is on the same filesystem as `dest`. Otherwise, it is copied, and every
+/// file is only removed from `src` once the hash of its copy matches, or without `Options::verify`,
+/// once its copy has the same size. Files whose copies do not match are kept in `src`.
+///
+/// # Arguments
+/// * `src`: Source directory
+/// * `dest`: Destination directory, which must be empty if it exists
+/// * `options`: Options of the run
+///
+/// # Errors
+/// This function will return an error in the following situations,
+/// but is not limited to just these cases:
+/// * `src` is an invalid directory
+/// * `dest` is inside `src`
+/// * Some files did not match their copies, and were kept in `src`
+pub fn move_dir(src: &str, dest: &str, options: &Options) -> Result<(), io::Error> {
+    let src_path = fs::canonicalize(src)?;
+    if fs::canonicalize(dest).is_ok_and(|dest_path| dest_path.starts_with(&src_path)) {
+        return Err(io::Error::other(format!(
+            "Move Error -- can not move {} into itself",
+            src
+        )));
+    }
+
+    match fs::rename(src, dest) {
+        Ok(_) => {
+            info!("Renaming {:?} to {:?}", src, dest);
+            return Ok(());
+        }
+        // Copy across filesystems instead
+        Err(e) => info!(
+            "Renaming {:?} to {:?} failed, copying instead: {}",
+            src, dest, e
+        ),
+    }
+
+    copy(src, dest, options)?;
+
+    // Retrieve src again, to only remove what was copied
+    let src_file_sets = file_ops::get_all_files(src)?;
+    let src_files = src_file_sets.files();
+    let src_dirs = src_file_sets.dirs();
+    let src_symlinks = src_file_sets.symlinks();
+    progress::progress_init((src_files.len() + src_dirs.len() + src_symlinks.len()) as u64);
+
+    let verify = options.verify;
+    let is_copied_file = |file: &&File| {
+        if verify {
+            let hash = file_ops::hash_file_secure(*file, src);
+            hash.is_some() && hash == file_ops::hash_file_secure(*file, dest)
+        } else {
+            fs::symlink_metadata(Path::new(dest).join(file.path()))
+                .is_ok_and(|metadata| metadata.is_file() && metadata.len() == file.size())
+        }
+    };
+    let is_copied_symlink = |symlink: &&Symlink| {
+        fs::read_link(Path::new(dest).join(symlink.path()))
+            .is_ok_and(|target| &target == symlink.target())
+    };
+
+    let kept: Vec<&PathBuf> = src_files
+        .par_iter()
+        .filter(|file| !is_copied_file(file))
+        .map(|file| file.path())
+        .chain(
+            src_symlinks
+                .par_iter()
+                .filter(|symlink| !is_copied_symlink(symlink))
+                .map(|symlink| symlink.path()),
+        )
+        .collect();
+    for path in &kept {
+        error!(
+            "Move Error -- {:?} does not match its copy, so it is kept",
+            Path::new(src).join(path)
+        );
+    }
+    let kept_paths: HashSet<&PathBuf> = kept.iter().copied().collect();
+
+    let files_to_remove = src_files
+        .par_iter()
+        .filter(|file| !kept_paths.contains(file.path()));
+    file_ops::delete_files(files_to_remove, src);
+    let symlinks_to_remove = src_symlinks
+        .par_iter()
+        .filter(|symlink| !kept_paths.contains(symlink.path()));
+    file_ops::delete_files(symlinks_to_remove, src);
+
+    // Directories are removed after their contents, and kept if they still have contents
+    let dirs_to_remove = src_dirs
+        .par_iter()
+        .filter(|dir| !kept.iter().any(|path| path.starts_with(dir.path())));
+    let mut dirs_to_remove: Vec<&Dir> = file_ops::sort_files(dirs_to_remove);
+    let root_dir = Dir::from("");
+    if kept.is_empty() {
+        dirs_to_remove.push(&root_dir);
+    }
+    file_ops::delete_files_sequential(dirs_to_remove, src);
+
+    if kept.is_empty() {
+        Ok(())
+    } else {
+        Err(io::Error::other(format!(
+            "Move Error -- {} file(s) did not match their copies, and were kept in {}",
+            kept.len(),
+            src
+        )))
+    }
+}
+
+/// Applies the materialization rules of `options` to `file_sets` from `src`, if there are any,
+/// and records `src` in `dest` as the source to hydrate placeholders from, unless
+/// `Options::dry_run` is set
+///
+/// # Returns
+/// The file sets to copy, and the empty placeholders to create, see `Rules::apply`
+///
+/// # Errors
+/// This function will return an error if the materialization state in `dest` could not be read
+/// or written
+fn materialize(
+    file_sets: FileSets,
+    src: &str,
+    dest: &str,
+    options: &Options,
+) -> Result<(FileSets, HashSet<File>), io::Error> {
+    let rules = match &options.materialize {
+        Some(rules) => rules,
+        None => return Ok((file_sets, HashSet::new())),
+    };
+
+    // Files that were hydrated stay materialized, for as long as they are in src
+    let mut state = materialize::State::load(Path::new(dest))?.unwrap_or_default();
+    state.source = fs::canonicalize(src)?;
+    let paths: HashSet<&PathBuf> = file_sets.files().iter().map(|file| file.path()).collect();
+    state.hydrated.retain(|path| paths.contains(path));
+    if !options.dry_run {
+        state.save(Path::new(dest))?;
+    }
+
+    Ok(rules.apply(&file_sets, &state.hydrated))
+}
+
 /// Deletes directory `target`
 ///
 /// # Arguments
 /// * `target`: Target directory
-/// * `flags`: set for Flag's
+/// * `options`: Options of the run
 ///
 /// # Errors
 /// This function will return an error in the following situations,
 /// but is not limited to just these cases:
 /// * `target` is an invalid directory
-pub fn remove(target: &str, _flags: Flag) -> Result<(), io::Error> {
+pub fn remove(target: &str, _options: &Options) -> Result<(), io::Error> {
     // Retrieve data from target directory about files, dirs, symlinks
-    let target_file_sets = file_ops::get_all_files(&target)?;
+    let target_file_sets = file_ops::get_all_files(target)?;
     let target_files = target_file_sets.files();
     let target_dirs = target_file_sets.dirs();
     let target_symlinks = target_file_sets.symlinks();
+    let target_specials = target_file_sets.specials();
 
     // Initialize progress bar
     progress::progress_init(
-        (target_files.len() + target_dirs.len() + target_symlinks.len()) as u64,
+        (target_files.len() + target_dirs.len() + target_symlinks.len() + target_specials.len())
+            as u64,
     );
     PROGRESS_BAR.enable_steady_tick(1);
 
     // Delete everything
-    file_ops::delete_files(target_files.into_par_iter(), &target);
-    file_ops::delete_files(target_symlinks.into_par_iter(), &target);
+    file_ops::delete_files(target_files.into_par_iter(), target);
+    file_ops::delete_files(target_symlinks.into_par_iter(), target);
+    file_ops::delete_files(target_specials.into_par_iter(), target);
 
     // Directories must always be deleted sequentially so that they are deleted in the correct order
     let mut target_dirs: Vec<&file_ops::Dir> = file_ops::sort_files(target_dirs.into_par_iter());
@@ -138,7 +1468,7 @@ pub fn remove(target: &str, _flags: Flag) -> Result<(), io::Error> {
     let root_dir = Dir::from("");
     target_dirs.push(&root_dir);
 
-    file_ops::delete_files_sequential(target_dirs.into_iter(), &target);
+    file_ops::delete_files_sequential(target_dirs, target);
 
     Ok(())
 }
@@ -150,6 +1480,10 @@ pub fn remove(target: &str, _flags: Flag) -> Result<(), io::Error> {
 #[cfg(test)]
 mod test_synchronize {
     use super::*;
+    use crate::lumins::filter::Filter;
+    use crate::lumins::glob::Glob;
+    use crate::lumins::history::MaxDelete;
+    use crate::lumins::sanitize::Sanitize;
     use std::fs;
     use std::process::Command;
 
@@ -161,12 +1495,12 @@ mod test_synchronize {
 
     #[test]
     fn invalid_src() {
-        assert_eq!(synchronize("/?", "src", Flag::empty()).is_err(), true);
+        assert!(synchronize("/?", "src", &Options::default()).is_err());
     }
 
     #[test]
     fn invalid_dest() {
-        assert_eq!(synchronize("src", "/?", Flag::empty()).is_err(), true);
+        assert!(synchronize("src", "/?", &Options::default()).is_err());
     }
 
     #[cfg(target_family = "unix")]
@@ -175,14 +1509,14 @@ mod test_synchronize {
         const TEST_DIR: &str = "test_synchronize_dir1";
         fs::create_dir_all(TEST_DIR).unwrap();
 
-        assert_eq!(synchronize("src", TEST_DIR, Flag::empty()).is_ok(), true);
+        assert!(synchronize("src", TEST_DIR, &Options::default()).is_ok());
 
         let diff = Command::new("diff")
-            .args(&["-r", "src", TEST_DIR])
+            .args(["-r", "src", TEST_DIR])
             .output()
             .unwrap();
 
-        assert_eq!(diff.status.success(), true);
+        assert!(diff.status.success());
 
         fs::remove_dir_all(TEST_DIR).unwrap();
     }
@@ -193,39 +1527,33 @@ mod test_synchronize {
         const TEST_DIR: &str = "test_synchronize_dir2";
         fs::create_dir_all(TEST_DIR).unwrap();
 
-        assert_eq!(
-            synchronize(BUILD_DIR, TEST_DIR, Flag::empty()).is_ok(),
-            true
-        );
+        assert!(synchronize(BUILD_DIR, TEST_DIR, &Options::default()).is_ok());
 
         let diff = Command::new("diff")
-            .args(&["-r", BUILD_DIR, TEST_DIR])
+            .args(["-r", BUILD_DIR, TEST_DIR])
             .output()
             .unwrap();
 
-        assert_eq!(diff.status.success(), true);
+        assert!(diff.status.success());
 
         fs::File::create([BUILD_DIR, "file.txt"].join("/")).unwrap();
         fs::remove_dir_all([BUILD_DIR, "build"].join("/")).unwrap();
 
         let diff = Command::new("diff")
-            .args(&["-r", BUILD_DIR, TEST_DIR])
+            .args(["-r", BUILD_DIR, TEST_DIR])
             .output()
             .unwrap();
 
-        assert_eq!(diff.status.success(), false);
+        assert!(!diff.status.success());
 
-        assert_eq!(
-            synchronize(BUILD_DIR, TEST_DIR, Flag::empty()).is_ok(),
-            true
-        );
+        assert!(synchronize(BUILD_DIR, TEST_DIR, &Options::default()).is_ok());
 
         let diff = Command::new("diff")
-            .args(&["-r", BUILD_DIR, TEST_DIR])
+            .args(["-r", BUILD_DIR, TEST_DIR])
             .output()
             .unwrap();
 
-        assert_eq!(diff.status.success(), true);
+        assert!(diff.status.success());
 
         fs::remove_dir_all(TEST_DIR).unwrap();
     }
@@ -244,23 +1572,20 @@ mod test_synchronize {
         symlink("../Cargo.toml", [TEST_DEST, "file"].join("/")).unwrap();
 
         let diff = Command::new("diff")
-            .args(&["-r", TEST_SRC, TEST_DEST])
+            .args(["-r", TEST_SRC, TEST_DEST])
             .output()
             .unwrap();
 
-        assert_eq!(diff.status.success(), false);
+        assert!(!diff.status.success());
 
-        assert_eq!(
-            synchronize(TEST_SRC, TEST_DEST, Flag::empty()).is_ok(),
-            true
-        );
+        assert!(synchronize(TEST_SRC, TEST_DEST, &Options::default()).is_ok());
 
         let diff = Command::new("diff")
-            .args(&["-r", TEST_SRC, TEST_DEST])
+            .args(["-r", TEST_SRC, TEST_DEST])
             .output()
             .unwrap();
 
-        assert_eq!(diff.status.success(), true);
+        assert!(diff.status.success());
 
         fs::remove_dir_all(TEST_DEST).unwrap();
         fs::remove_dir_all(TEST_SRC).unwrap();
@@ -282,87 +1607,1540 @@ mod test_synchronize {
         fs::File::create([TEST_DIR_EXPECTED, TEST_FILES[0]].join("/")).unwrap();
         fs::File::create([TEST_DIR_EXPECTED, TEST_FILES[1]].join("/")).unwrap();
 
-        assert_eq!(
-            synchronize(TEST_DIR, TEST_DIR_OUT, Flag::empty()).is_ok(),
-            true
-        );
+        assert!(synchronize(TEST_DIR, TEST_DIR_OUT, &Options::default()).is_ok());
 
         fs::File::create([TEST_DIR, TEST_FILES[1]].join("/")).unwrap();
 
-        let mut flags = Flag::empty();
-        flags.insert(Flag::VERBOSE);
-        flags.insert(Flag::NO_DELETE);
-        flags.insert(Flag::SECURE);
-        flags.insert(Flag::SEQUENTIAL);
+        let options = Options {
+            delete: false,
+            secure: true,
+            sequential: true,
+            verbosity: 1,
+            ..Options::default()
+        };
 
-        assert_eq!(synchronize(TEST_DIR, TEST_DIR_OUT, flags).is_ok(), true);
+        assert!(synchronize(TEST_DIR, TEST_DIR_OUT, &options).is_ok());
 
         let diff = Command::new("diff")
-            .args(&["-r", TEST_DIR_OUT, TEST_DIR_EXPECTED])
+            .args(["-r", TEST_DIR_OUT, TEST_DIR_EXPECTED])
             .output()
             .unwrap();
 
-        assert_eq!(diff.status.success(), true);
+        assert!(diff.status.success());
 
         fs::remove_dir_all(TEST_DIR).unwrap();
         fs::remove_dir_all(TEST_DIR_OUT).unwrap();
         fs::remove_dir_all(TEST_DIR_EXPECTED).unwrap();
     }
-}
-
-#[cfg(test)]
-mod test_copy {
-    use super::*;
-    use std::fs;
-    use std::process::Command;
 
     #[test]
-    fn invalid_src() {
-        assert_eq!(copy("/?", "src", Flag::empty()).is_err(), true);
-    }
+    fn dest_snapshot_unsupported() {
+        const TEST_DIR: &str = "test_synchronize_dest_snapshot_unsupported";
+        fs::create_dir_all(TEST_DIR).unwrap();
+
+        if snapshot::snapshot_fs(TEST_DIR).unwrap().is_none() {
+            assert!(synchronize(
+                "src",
+                TEST_DIR,
+                &Options {
+                    dest_snapshot: true,
+                    ..Options::default()
+                }
+            )
+            .is_ok());
+            assert!(fs::metadata([TEST_DIR, "main.rs"].join("/")).is_ok());
+        }
 
-    #[test]
-    fn invalid_dest() {
-        const TEST_DIR: &str = "test_copy_invalid_dest";
-        assert_eq!(copy("src", TEST_DIR, Flag::empty()).is_ok(), true);
         fs::remove_dir_all(TEST_DIR).unwrap();
     }
 
     #[cfg(target_family = "unix")]
     #[test]
-    fn dir1() {
-        const TEST_DIR: &str = "test_copy_dir1";
-        fs::create_dir_all(TEST_DIR).unwrap();
-
-        assert_eq!(copy("src", TEST_DIR, Flag::empty()).is_ok(), true);
-
-        let diff = Command::new("diff")
-            .args(&["-r", "src", TEST_DIR])
-            .output()
-            .unwrap();
+    fn xattrs() {
+        const TEST_DIR: &str = "test_synchronize_xattrs";
+        const TEST_DIR_OUT: &str = "test_synchronize_xattrs_out";
+        fs::create_dir_all([TEST_DIR, "dir"].join("/")).unwrap();
+        fs::write([TEST_DIR, "file"].join("/"), b"1234").unwrap();
 
-        assert_eq!(diff.status.success(), true);
+        assert!(synchronize(TEST_DIR, TEST_DIR_OUT, &Options::default()).is_err());
+        fs::create_dir_all(TEST_DIR_OUT).unwrap();
+        assert!(synchronize(TEST_DIR, TEST_DIR_OUT, &Options::default()).is_ok());
+
+        // Skip if the filesystem does not support user xattrs
+        if xattr::set([TEST_DIR, "file"].join("/"), "user.lumins", b"1").is_ok() {
+            xattr::set([TEST_DIR, "dir"].join("/"), "user.lumins", b"2").unwrap();
+
+            // Unchanged files and dirs still have their xattrs updated
+            assert!(synchronize(
+                TEST_DIR,
+                TEST_DIR_OUT,
+                &Options {
+                    xattrs: true,
+                    ..Options::default()
+                }
+            )
+            .is_ok());
+            assert_eq!(
+                xattr::get([TEST_DIR_OUT, "file"].join("/"), "user.lumins").unwrap(),
+                Some(b"1".to_vec())
+            );
+            assert_eq!(
+                xattr::get([TEST_DIR_OUT, "dir"].join("/"), "user.lumins").unwrap(),
+                Some(b"2".to_vec())
+            );
+        }
 
         fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_dir_all(TEST_DIR_OUT).unwrap();
     }
 
     #[cfg(target_family = "unix")]
     #[test]
-    fn flags() {
-        const TEST_DIR: &str = "test_copy_flags";
-        fs::create_dir_all(TEST_DIR).unwrap();
-
-        let mut flags = Flag::empty();
-        flags.insert(Flag::SEQUENTIAL);
+    fn xattr_hash_cache() {
+        use crate::lumins::hashcache::HASH_XATTR;
 
-        assert_eq!(copy("src", TEST_DIR, flags).is_ok(), true);
+        const TEST_DIR: &str = "test_synchronize_xattr_hash_cache";
+        const TEST_DIR_OUT: &str = "test_synchronize_xattr_hash_cache_out";
+        let dest_file = [TEST_DIR_OUT, "file"].join("/");
+        fs::create_dir_all(TEST_DIR).unwrap();
+        fs::create_dir_all(TEST_DIR_OUT).unwrap();
+        fs::write([TEST_DIR, "file"].join("/"), b"1234").unwrap();
+
+        let options = Options {
+            secure: true,
+            xattr_hash_cache: true,
+            ..Options::default()
+        };
+        assert!(synchronize(TEST_DIR, TEST_DIR_OUT, &options).is_ok());
+
+        // Skip if the filesystem does not support user xattrs
+        if let Ok(Some(cached)) = xattr::get(&dest_file, HASH_XATTR) {
+            // A cached hash is trusted over the contents of the file, so a wrong one is copied over
+            let cached = String::from_utf8(cached).unwrap();
+            let (state, _) = cached.rsplit_once(' ').unwrap();
+            let wrong = format!("{} 00", state);
+            xattr::set(&dest_file, HASH_XATTR, wrong.as_bytes()).unwrap();
+
+            assert!(synchronize(TEST_DIR, TEST_DIR_OUT, &options).is_ok());
+            let recached = xattr::get(&dest_file, HASH_XATTR).unwrap().unwrap();
+            assert_ne!(recached, wrong.as_bytes());
+        }
 
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_dir_all(TEST_DIR_OUT).unwrap();
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn renames() {
+        use std::os::unix::fs::MetadataExt;
+        const TEST_DIR: &str = "test_synchronize_renames";
+        const TEST_DIR_OUT: &str = "test_synchronize_renames_out";
+
+        fs::create_dir_all([TEST_DIR, "a"].join("/")).unwrap();
+        fs::write([TEST_DIR, "a", "file"].join("/"), b"1234").unwrap();
+        fs::write([TEST_DIR, "a", "same_size"].join("/"), b"5678").unwrap();
+        fs::write([TEST_DIR, "grown"].join("/"), b"12").unwrap();
+        fs::create_dir_all(TEST_DIR_OUT).unwrap();
+
+        assert!(synchronize(TEST_DIR, TEST_DIR_OUT, &Options::default()).is_ok());
+        let inode = |path: &str| fs::metadata([TEST_DIR_OUT, path].join("/")).unwrap().ino();
+        let file_inode = inode("a/file");
+
+        // Move a file into a new dir, and replace a file with the contents of a deleted one
+        fs::create_dir_all([TEST_DIR, "b"].join("/")).unwrap();
+        fs::rename(
+            [TEST_DIR, "a", "file"].join("/"),
+            [TEST_DIR, "b", "moved"].join("/"),
+        )
+        .unwrap();
+        fs::write([TEST_DIR, "grown"].join("/"), b"5678").unwrap();
+        fs::remove_file([TEST_DIR, "a", "same_size"].join("/")).unwrap();
+
+        assert!(synchronize(TEST_DIR, TEST_DIR_OUT, &Options::default()).is_ok());
+
+        let diff = Command::new("diff")
+            .args(["-r", TEST_DIR, TEST_DIR_OUT])
+            .output()
+            .unwrap();
+        assert!(diff.status.success());
+        assert_eq!(inode("b/moved"), file_inode);
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_dir_all(TEST_DIR_OUT).unwrap();
+    }
+
+    #[test]
+    fn file_replaced_by_dir() {
+        const TEST_DIR: &str = "test_synchronize_file_replaced_by_dir";
+        const TEST_DIR_OUT: &str = "test_synchronize_file_replaced_by_dir_out";
+        let path = |dir: &str, path: &str| [dir, path].join("/");
+
+        fs::create_dir_all(path(TEST_DIR, "x")).unwrap();
+        fs::write(path(TEST_DIR, "x/f"), b"hi").unwrap();
+        fs::create_dir_all(path(TEST_DIR, "moved")).unwrap();
+        fs::write(path(TEST_DIR, "moved/f"), b"same").unwrap();
+
+        for low_memory in [false, true] {
+            fs::create_dir_all(TEST_DIR_OUT).unwrap();
+            fs::write(path(TEST_DIR_OUT, "x"), b"old").unwrap();
+            // A file in the way of a directory is not renamed into it, even with its contents
+            fs::write(path(TEST_DIR_OUT, "moved"), b"same").unwrap();
+
+            let options = Options {
+                low_memory,
+                ..Options::default()
+            };
+            assert!(synchronize(TEST_DIR, TEST_DIR_OUT, &options).is_ok());
+            assert_eq!(fs::read(path(TEST_DIR_OUT, "x/f")).unwrap(), b"hi");
+            assert_eq!(fs::read(path(TEST_DIR_OUT, "moved/f")).unwrap(), b"same");
+
+            fs::remove_dir_all(TEST_DIR_OUT).unwrap();
+        }
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn resume() {
+        use crate::lumins::journal::Journal;
+
+        const TEST_DIR: &str = "test_synchronize_resume";
+        const TEST_DIR_OUT: &str = "test_synchronize_resume_out";
+
+        fs::create_dir_all(TEST_DIR).unwrap();
+        fs::create_dir_all(TEST_DIR_OUT).unwrap();
+        fs::write([TEST_DIR, "completed"].join("/"), b"1234").unwrap();
+        fs::write([TEST_DIR, "pending"].join("/"), b"1234").unwrap();
+        fs::write([TEST_DIR, "new"].join("/"), b"1234").unwrap();
+
+        // An interrupted run that completed one file, and left another one unfinished
+        fs::write([TEST_DIR_OUT, "completed"].join("/"), b"5678").unwrap();
+        fs::write([TEST_DIR_OUT, "pending"].join("/"), b"5678").unwrap();
+        Journal::open(TEST_DIR_OUT)
+            .unwrap()
+            .record(Path::new(TEST_DIR), Path::new("completed"));
+
+        let options = Options {
+            resume: true,
+            ..Options::default()
+        };
+        assert!(synchronize(TEST_DIR, TEST_DIR_OUT, &options).is_ok());
+
+        // Completed files are trusted instead of being compared again
+        assert_eq!(
+            fs::read([TEST_DIR_OUT, "completed"].join("/")).unwrap(),
+            b"5678"
+        );
+        assert_eq!(
+            fs::read([TEST_DIR_OUT, "pending"].join("/")).unwrap(),
+            b"1234"
+        );
+        assert_eq!(fs::read([TEST_DIR_OUT, "new"].join("/")).unwrap(), b"1234");
+        assert!(fs::metadata([TEST_DIR_OUT, JOURNAL_NAME].join("/")).is_err());
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_dir_all(TEST_DIR_OUT).unwrap();
+    }
+
+    #[test]
+    fn refuse_anomalies() {
+        const TEST_DIR: &str = "test_synchronize_refuse_anomalies";
+        const TEST_DIR_OUT: &str = "test_synchronize_refuse_anomalies_out";
+
+        fs::create_dir_all(TEST_DIR).unwrap();
+        fs::create_dir_all(TEST_DIR_OUT).unwrap();
+        fs::write([TEST_DIR, "kept"].join("/"), b"1234").unwrap();
+        for name in &["kept", "old1", "old2"] {
+            fs::write([TEST_DIR_OUT, name].join("/"), b"5678").unwrap();
+        }
+
+        // Deleting 2 of 3 files is refused, and nothing is changed
+        let options = Options {
+            refuse_anomalies: true,
+            ..Options::default()
+        };
+        assert!(synchronize(TEST_DIR, TEST_DIR_OUT, &options).is_err());
+        assert_eq!(fs::read([TEST_DIR_OUT, "kept"].join("/")).unwrap(), b"5678");
+        assert!(fs::metadata([TEST_DIR_OUT, "old1"].join("/")).is_ok());
+
+        // Unless an earlier run deleted as many
+        let options = Options {
+            norms: Some(history::Norms {
+                runs: 1,
+                max_deleted: 2,
+            }),
+            refuse_anomalies: true,
+            ..Options::default()
+        };
+        assert!(synchronize(TEST_DIR, TEST_DIR_OUT, &options).is_ok());
+        assert_eq!(fs::read([TEST_DIR_OUT, "kept"].join("/")).unwrap(), b"1234");
+        assert!(fs::metadata([TEST_DIR_OUT, "old1"].join("/")).is_err());
+
+        // Or the run is forced
+        fs::write([TEST_DIR_OUT, "old1"].join("/"), b"5678").unwrap();
+        fs::write([TEST_DIR_OUT, "old2"].join("/"), b"5678").unwrap();
+        let options = Options {
+            refuse_anomalies: true,
+            force: true,
+            ..Options::default()
+        };
+        assert!(synchronize(TEST_DIR, TEST_DIR_OUT, &options).is_ok());
+        assert!(fs::metadata([TEST_DIR_OUT, "old1"].join("/")).is_err());
+
+        // Files whose sizes changed are updated, not deleted, so updating all of them is not
+        // out of the ordinary
+        for name in &["a", "b", "c"] {
+            fs::write([TEST_DIR, name].join("/"), b"new").unwrap();
+            fs::write([TEST_DIR_OUT, name].join("/"), b"old file").unwrap();
+        }
+        fs::write([TEST_DIR, "kept"].join("/"), b"new").unwrap();
+        let options = Options {
+            refuse_anomalies: true,
+            ..Options::default()
+        };
+        assert!(synchronize(TEST_DIR, TEST_DIR_OUT, &options).is_ok());
+        assert_eq!(fs::read([TEST_DIR_OUT, "a"].join("/")).unwrap(), b"new");
+        assert_eq!(fs::read([TEST_DIR_OUT, "kept"].join("/")).unwrap(), b"new");
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_dir_all(TEST_DIR_OUT).unwrap();
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn materialize() {
+        const TEST_DIR: &str = "test_synchronize_materialize";
+        const TEST_DIR_OUT: &str = "test_synchronize_materialize_out";
+
+        fs::create_dir_all([TEST_DIR, "assets/big"].join("/")).unwrap();
+        fs::create_dir_all([TEST_DIR, "raw"].join("/")).unwrap();
+        fs::create_dir_all(TEST_DIR_OUT).unwrap();
+        fs::write([TEST_DIR, "code"].join("/"), b"code").unwrap();
+        fs::write([TEST_DIR, "assets/big/texture"].join("/"), b"texture").unwrap();
+        fs::write([TEST_DIR, "raw/photo"].join("/"), b"photo").unwrap();
+        fs::write([TEST_DIR_OUT, "code"].join("/"), b"old").unwrap();
+
+        let options = Options {
+            materialize: Some("empty assets\nlink raw /cold".parse().unwrap()),
+            ..Options::default()
+        };
+        for _ in 0..2 {
+            assert!(synchronize(TEST_DIR, TEST_DIR_OUT, &options).is_ok());
+
+            assert_eq!(fs::read([TEST_DIR_OUT, "code"].join("/")).unwrap(), b"code");
+            assert_eq!(
+                fs::read([TEST_DIR_OUT, "assets/big/texture"].join("/")).unwrap(),
+                b""
+            );
+            assert_eq!(
+                fs::read_link([TEST_DIR_OUT, "raw/photo"].join("/")).unwrap(),
+                PathBuf::from("/cold/raw/photo")
+            );
+        }
+
+        // Files are copied in full once the rules no longer exclude them
+        assert!(synchronize(TEST_DIR, TEST_DIR_OUT, &Options::default()).is_ok());
         let diff = Command::new("diff")
-            .args(&["-r", "src", TEST_DIR])
+            .args(["-r", TEST_DIR, TEST_DIR_OUT])
             .output()
             .unwrap();
+        assert!(diff.status.success());
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_dir_all(TEST_DIR_OUT).unwrap();
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn link_rewrites() {
+        use std::os::unix::fs::{symlink, MetadataExt};
+
+        const TEST_DIR: &str = "test_synchronize_link_rewrites";
+        const TEST_DIR_OUT: &str = "test_synchronize_link_rewrites_out";
+        let path = |dir: &str, path: &str| [dir, path].join("/");
+
+        fs::create_dir_all(TEST_DIR).unwrap();
+        fs::create_dir_all(TEST_DIR_OUT).unwrap();
+        symlink("/old/root/f", path(TEST_DIR, "absolute")).unwrap();
+        symlink("old/root/f", path(TEST_DIR, "relative")).unwrap();
+
+        let options = Options {
+            link_rewrites: vec!["s#^/old/root#/new/root#".parse().unwrap()],
+            ..Options::default()
+        };
+        assert!(synchronize(TEST_DIR, TEST_DIR_OUT, &options).is_ok());
+        let target = |link: &str| fs::read_link(path(TEST_DIR_OUT, link)).unwrap();
+        assert_eq!(target("absolute"), Path::new("/new/root/f"));
+        assert_eq!(target("relative"), Path::new("old/root/f"));
+
+        // Rewritten symlinks are up to date, so they are not created again
+        let inode = || {
+            fs::symlink_metadata(path(TEST_DIR_OUT, "absolute"))
+                .unwrap()
+                .ino()
+        };
+        let before = inode();
+        assert!(synchronize(TEST_DIR, TEST_DIR_OUT, &options).is_ok());
+        assert_eq!(inode(), before);
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_dir_all(TEST_DIR_OUT).unwrap();
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn relativize_links() {
+        use std::os::unix::fs::symlink;
+
+        const TEST_DIR: &str = "test_synchronize_relativize_links";
+        const TEST_DIR_OUT: &str = "test_synchronize_relativize_links_out";
+        let path = |dir: &str, path: &str| [dir, path].join("/");
+
+        fs::create_dir_all(path(TEST_DIR, "a")).unwrap();
+        fs::create_dir_all(path(TEST_DIR, "b")).unwrap();
+        fs::create_dir_all(TEST_DIR_OUT).unwrap();
+        fs::write(path(TEST_DIR, "b/f"), b"f").unwrap();
+        let root = fs::canonicalize(TEST_DIR).unwrap();
+        symlink(root.join("b/f"), path(TEST_DIR, "a/inside")).unwrap();
+        symlink("/elsewhere", path(TEST_DIR, "a/outside")).unwrap();
+
+        let options = Options {
+            relativize_links: true,
+            ..Options::default()
+        };
+        assert!(synchronize(TEST_DIR, TEST_DIR_OUT, &options).is_ok());
+        let target = |link: &str| fs::read_link(path(TEST_DIR_OUT, link)).unwrap();
+        assert_eq!(target("a/inside"), Path::new("../b/f"));
+        assert_eq!(target("a/outside"), Path::new("/elsewhere"));
+        assert_eq!(fs::read(path(TEST_DIR_OUT, "a/inside")).unwrap(), b"f");
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_dir_all(TEST_DIR_OUT).unwrap();
+    }
+
+    #[test]
+    fn quarantine() {
+        const TEST_DIR: &str = "test_synchronize_quarantine";
+        const TEST_DIR_OUT: &str = "test_synchronize_quarantine_out";
+        let path = |dir: &str, path: &str| [dir, path].join("/");
+
+        fs::create_dir_all(TEST_DIR).unwrap();
+        fs::create_dir_all(path(TEST_DIR_OUT, ".lumins-quarantine-0-0")).unwrap();
+        fs::write(path(TEST_DIR, "changed"), b"new").unwrap();
+        fs::write(path(TEST_DIR_OUT, "changed"), b"old").unwrap();
+        fs::write(path(TEST_DIR_OUT, "stale"), b"stale").unwrap();
+        fs::write(path(TEST_DIR_OUT, ".lumins-quarantine-0-0/kept"), b"kept").unwrap();
+
+        let options = Options {
+            quarantine_files: true,
+            ..Options::default()
+        };
+        assert!(synchronize(TEST_DIR, TEST_DIR_OUT, &options).is_ok());
+        assert_eq!(fs::read(path(TEST_DIR_OUT, "changed")).unwrap(), b"new");
+        assert!(fs::metadata(path(TEST_DIR_OUT, "stale")).is_err());
+
+        // Quarantines of earlier runs are left alone, and one of this run is only kept if other
+        // tests logged errors while it ran
+        for entry in fs::read_dir(TEST_DIR_OUT).unwrap() {
+            let entry = entry.unwrap().path();
+            if entry.ends_with(".lumins-quarantine-0-0") {
+                assert_eq!(fs::read(entry.join("kept")).unwrap(), b"kept");
+            } else if entry
+                .to_string_lossy()
+                .contains(quarantine::QUARANTINE_PREFIX)
+            {
+                assert_eq!(fs::read(entry.join("changed")).unwrap(), b"old");
+                assert_eq!(fs::read(entry.join("stale")).unwrap(), b"stale");
+            }
+        }
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_dir_all(TEST_DIR_OUT).unwrap();
+    }
+
+    #[test]
+    fn max_depth() {
+        const TEST_DIR: &str = "test_synchronize_max_depth";
+        const TEST_DIR_OUT: &str = "test_synchronize_max_depth_out";
+        let path = |dir: &str, path: &str| [dir, path].join("/");
+
+        fs::create_dir_all(path(TEST_DIR, "project/src")).unwrap();
+        fs::create_dir_all(path(TEST_DIR_OUT, "old/src")).unwrap();
+        fs::write(path(TEST_DIR, "project/README"), b"readme").unwrap();
+        fs::write(path(TEST_DIR, "project/src/main.rs"), b"main").unwrap();
+        fs::write(path(TEST_DIR_OUT, "old/src/lib.rs"), b"lib").unwrap();
+
+        let options = Options {
+            max_depth: Some(2),
+            ..Options::default()
+        };
+        assert!(synchronize(TEST_DIR, TEST_DIR_OUT, &options).is_ok());
+        assert_eq!(
+            fs::read(path(TEST_DIR_OUT, "project/README")).unwrap(),
+            b"readme"
+        );
+        assert!(fs::metadata(path(TEST_DIR_OUT, "project/src"))
+            .unwrap()
+            .is_dir());
+        assert!(fs::metadata(path(TEST_DIR_OUT, "project/src/main.rs")).is_err());
+
+        // Nothing below the deepest level is deleted
+        assert_eq!(
+            fs::read(path(TEST_DIR_OUT, "old/src/lib.rs")).unwrap(),
+            b"lib"
+        );
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_dir_all(TEST_DIR_OUT).unwrap();
+    }
+
+    #[test]
+    fn filter() {
+        const TEST_DIR: &str = "test_synchronize_filter";
+        const TEST_DIR_OUT: &str = "test_synchronize_filter_out";
+        let path = |dir: &str, path: &str| [dir, path].join("/");
+
+        fs::create_dir_all(path(TEST_DIR, "var/www")).unwrap();
+        fs::create_dir_all(path(TEST_DIR, "var/log")).unwrap();
+        fs::create_dir_all(path(TEST_DIR_OUT, "var/cache")).unwrap();
+        for file in &[
+            "var/www/index.html",
+            "var/www/page.tmp",
+            "var/log/syslog",
+            "etc",
+        ] {
+            fs::write(path(TEST_DIR, file), file.as_bytes()).unwrap();
+        }
+        fs::write(path(TEST_DIR_OUT, "var/cache/kept"), b"kept").unwrap();
+        fs::write(path(TEST_DIR_OUT, "old.tmp"), b"kept").unwrap();
+
+        let options = Options {
+            filter: Some(Filter::parse(vec!["+ /var/www/**", "- /var/**", "- *.tmp"]).unwrap()),
+            ..Options::default()
+        };
+        for low_memory in [false, true] {
+            let options = Options {
+                low_memory,
+                ..options.clone()
+            };
+            assert!(synchronize(TEST_DIR, TEST_DIR_OUT, &options).is_ok());
+            for file in &["var/www/index.html", "var/www/page.tmp", "etc"] {
+                assert_eq!(fs::read(path(TEST_DIR_OUT, file)).unwrap(), file.as_bytes());
+            }
+            assert!(fs::metadata(path(TEST_DIR_OUT, "var/log")).is_err());
+
+            // Excluded entries of dest are not deleted
+            assert!(fs::metadata(path(TEST_DIR_OUT, "var/cache/kept")).is_ok());
+            assert!(fs::metadata(path(TEST_DIR_OUT, "old.tmp")).is_ok());
+        }
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_dir_all(TEST_DIR_OUT).unwrap();
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn low_memory() {
+        const TEST_DIR: &str = "test_synchronize_low_memory";
+        const TEST_DIR_OUT: &str = "test_synchronize_low_memory_out";
+        let path = |dir: &str, path: &str| [dir, path].join("/");
+
+        fs::create_dir_all(path(TEST_DIR, "a/b/c")).unwrap();
+        fs::write(path(TEST_DIR, "file"), b"1").unwrap();
+        fs::write(path(TEST_DIR, "a/b/changed"), b"new").unwrap();
+        fs::write(path(TEST_DIR, "a/b/c/file"), b"123").unwrap();
+        fs::create_dir_all(path(TEST_DIR_OUT, "a/b")).unwrap();
+        fs::create_dir_all(path(TEST_DIR_OUT, "old/deeper")).unwrap();
+        fs::write(path(TEST_DIR_OUT, "a/b/changed"), b"old").unwrap();
+        fs::write(path(TEST_DIR_OUT, "a/extra"), b"extra").unwrap();
+        fs::write(path(TEST_DIR_OUT, "old/deeper/file"), b"old").unwrap();
+
+        let options = Options {
+            low_memory: true,
+            ..Options::default()
+        };
+        assert!(synchronize(TEST_DIR, TEST_DIR_OUT, &options).is_ok());
+        let diff = Command::new("diff")
+            .args(["-r", TEST_DIR, TEST_DIR_OUT])
+            .output()
+            .unwrap();
+        assert!(diff.status.success());
+
+        // Nothing below the deepest level is deleted
+        fs::create_dir_all(path(TEST_DIR_OUT, "a/old")).unwrap();
+        fs::write(path(TEST_DIR_OUT, "a/old/file"), b"old").unwrap();
+        fs::write(path(TEST_DIR_OUT, "a/b/extra"), b"extra").unwrap();
+        let options = Options {
+            low_memory: true,
+            max_depth: Some(1),
+            ..Options::default()
+        };
+        assert!(synchronize(TEST_DIR, TEST_DIR_OUT, &options).is_ok());
+        assert!(fs::metadata(path(TEST_DIR_OUT, "a/old/file")).is_ok());
+        assert!(fs::metadata(path(TEST_DIR_OUT, "a/b/extra")).is_ok());
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_dir_all(TEST_DIR_OUT).unwrap();
+    }
+
+    #[test]
+    fn ignore_existing_and_update() {
+        use crate::lumins::platform;
+        use std::time::{Duration, UNIX_EPOCH};
+
+        const TEST_DIR: &str = "test_synchronize_ignore_existing_and_update";
+        const TEST_DIR_OUT: &str = "test_synchronize_ignore_existing_and_update_out";
+        let path = |dir: &str, path: &str| [dir, path].join("/");
+        let set_mtime = |dir: &str, file: &str, secs: u64| {
+            platform::set_modified(
+                Path::new(&path(dir, file)),
+                UNIX_EPOCH + Duration::from_secs(secs),
+            )
+            .unwrap()
+        };
+
+        fs::create_dir_all(TEST_DIR).unwrap();
+        fs::create_dir_all(TEST_DIR_OUT).unwrap();
+        for (file, src, dest) in &[("newer", 2, 1), ("older", 1, 2)] {
+            fs::write(path(TEST_DIR, file), b"src").unwrap();
+            fs::write(path(TEST_DIR_OUT, file), b"dest!").unwrap();
+            set_mtime(TEST_DIR, file, *src);
+            set_mtime(TEST_DIR_OUT, file, *dest);
+        }
+        fs::write(path(TEST_DIR, "new"), b"new").unwrap();
+
+        // Only files that are older than their sources are overwritten
+        let options = Options {
+            update: true,
+            ..Options::default()
+        };
+        assert!(synchronize(TEST_DIR, TEST_DIR_OUT, &options).is_ok());
+        assert_eq!(fs::read(path(TEST_DIR_OUT, "newer")).unwrap(), b"src");
+        assert_eq!(fs::read(path(TEST_DIR_OUT, "older")).unwrap(), b"dest!");
+        assert_eq!(fs::read(path(TEST_DIR_OUT, "new")).unwrap(), b"new");
+
+        // Files that exist are never overwritten
+        fs::write(path(TEST_DIR_OUT, "newer"), b"dest!").unwrap();
+        set_mtime(TEST_DIR_OUT, "newer", 1);
+        let options = Options {
+            ignore_existing: true,
+            ..Options::default()
+        };
+        assert!(synchronize(TEST_DIR, TEST_DIR_OUT, &options).is_ok());
+        assert_eq!(fs::read(path(TEST_DIR_OUT, "newer")).unwrap(), b"dest!");
+        assert_eq!(fs::read(path(TEST_DIR_OUT, "older")).unwrap(), b"dest!");
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_dir_all(TEST_DIR_OUT).unwrap();
+    }
+
+    #[test]
+    fn heal_empty_files() {
+        use crate::lumins::platform;
+        use std::time::{Duration, UNIX_EPOCH};
+
+        const TEST_DIR: &str = "test_synchronize_heal_empty_files";
+        const TEST_DIR_OUT: &str = "test_synchronize_heal_empty_files_out";
+        let path = |dir: &str, path: &str| [dir, path].join("/");
+
+        fs::create_dir_all(TEST_DIR).unwrap();
+        fs::create_dir_all(TEST_DIR_OUT).unwrap();
+        for (file, contents) in &[("truncated", &b"src"[..]), ("empty", &b""[..])] {
+            fs::write(path(TEST_DIR, file), contents).unwrap();
+            fs::write(path(TEST_DIR_OUT, file), b"").unwrap();
+            platform::set_modified(
+                Path::new(&path(TEST_DIR, file)),
+                UNIX_EPOCH + Duration::from_secs(1),
+            )
+            .unwrap();
+        }
+
+        // Empty files that are newer than their sources are kept without --heal-empty-files
+        let options = Options {
+            update: true,
+            ..Options::default()
+        };
+        assert!(synchronize(TEST_DIR, TEST_DIR_OUT, &options).is_ok());
+        assert_eq!(fs::read(path(TEST_DIR_OUT, "truncated")).unwrap(), b"");
+
+        for update in [true, false] {
+            fs::write(path(TEST_DIR_OUT, "truncated"), b"").unwrap();
+            let options = Options {
+                update,
+                ignore_existing: !update,
+                heal_empty_files: true,
+                ..Options::default()
+            };
+            assert!(synchronize(TEST_DIR, TEST_DIR_OUT, &options).is_ok());
+            assert_eq!(fs::read(path(TEST_DIR_OUT, "truncated")).unwrap(), b"src");
+            assert_eq!(fs::read(path(TEST_DIR_OUT, "empty")).unwrap(), b"");
+        }
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_dir_all(TEST_DIR_OUT).unwrap();
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn specials() {
+        use std::os::unix::fs::FileTypeExt;
+
+        const TEST_DIR: &str = "test_synchronize_specials";
+        const TEST_DIR_OUT: &str = "test_synchronize_specials_out";
+        let path = |dir: &str, path: &str| [dir, path].join("/");
+        let is_fifo = |path: &str| {
+            fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_fifo())
+        };
+
+        fs::create_dir_all(TEST_DIR).unwrap();
+        fs::write(path(TEST_DIR, "file.txt"), b"file").unwrap();
+        assert!(Command::new("mkfifo")
+            .arg(path(TEST_DIR, "fifo"))
+            .status()
+            .unwrap()
+            .success());
+
+        // Special files are skipped, without making the listing incomplete
+        fs::create_dir_all(TEST_DIR_OUT).unwrap();
+        fs::write(path(TEST_DIR_OUT, "stale.txt"), b"stale").unwrap();
+        assert!(synchronize(TEST_DIR, TEST_DIR_OUT, &Options::default()).is_ok());
+        assert!(!Path::new(&path(TEST_DIR_OUT, "fifo")).exists());
+        assert!(!Path::new(&path(TEST_DIR_OUT, "stale.txt")).exists());
+
+        // And created with --specials
+        let options = Options {
+            specials: true,
+            ..Options::default()
+        };
+        assert!(synchronize(TEST_DIR, TEST_DIR_OUT, &options).is_ok());
+        assert!(is_fifo(&path(TEST_DIR_OUT, "fifo")));
+
+        // Special files that are no longer in src are deleted
+        fs::remove_file(path(TEST_DIR, "fifo")).unwrap();
+        assert!(synchronize(TEST_DIR, TEST_DIR_OUT, &options).is_ok());
+        assert!(!is_fifo(&path(TEST_DIR_OUT, "fifo")));
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        assert!(remove(TEST_DIR_OUT, &Options::default()).is_ok());
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn dirlinks() {
+        use std::os::unix::fs::symlink;
+
+        const TEST_DIR: &str = "test_synchronize_dirlinks";
+        const TEST_DIR_OUT: &str = "test_synchronize_dirlinks_out";
+        const TEST_VOLUME: &str = "test_synchronize_dirlinks_volume";
+        let path = |dir: &str, path: &str| [dir, path].join("/");
+
+        fs::create_dir_all(path(TEST_DIR, "dir")).unwrap();
+        fs::write(path(TEST_DIR, "dir/file.txt"), b"file").unwrap();
+        fs::create_dir_all(path(TEST_DIR, "target")).unwrap();
+        fs::write(path(TEST_DIR, "target/data.txt"), b"data").unwrap();
+        symlink("target", path(TEST_DIR, "data")).unwrap();
+        fs::create_dir_all(TEST_VOLUME).unwrap();
+        fs::write(path(TEST_VOLUME, "stale.txt"), b"stale").unwrap();
+        fs::create_dir_all(TEST_DIR_OUT).unwrap();
+        symlink(
+            "../test_synchronize_dirlinks_volume",
+            path(TEST_DIR_OUT, "dir"),
+        )
+        .unwrap();
+
+        // The symlink in dest is written through, and the one in src is copied as a directory
+        let options = Options {
+            keep_dirlinks: true,
+            copy_dirlinks: true,
+            ..Options::default()
+        };
+        assert!(synchronize(TEST_DIR, TEST_DIR_OUT, &options).is_ok());
+        assert!(fs::symlink_metadata(path(TEST_DIR_OUT, "dir"))
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        assert_eq!(fs::read(path(TEST_VOLUME, "file.txt")).unwrap(), b"file");
+        assert!(!Path::new(&path(TEST_VOLUME, "stale.txt")).exists());
+        assert!(fs::symlink_metadata(path(TEST_DIR_OUT, "data"))
+            .unwrap()
+            .is_dir());
+        assert_eq!(
+            fs::read(path(TEST_DIR_OUT, "data/data.txt")).unwrap(),
+            b"data"
+        );
+
+        // Without --keep-dirlinks, symlinks in dest are replaced
+        let options = Options {
+            copy_dirlinks: true,
+            ..Options::default()
+        };
+        assert!(synchronize(TEST_DIR, TEST_DIR_OUT, &options).is_ok());
+        assert!(fs::symlink_metadata(path(TEST_DIR_OUT, "dir"))
+            .unwrap()
+            .is_dir());
+        assert_eq!(
+            fs::read(path(TEST_DIR_OUT, "dir/file.txt")).unwrap(),
+            b"file"
+        );
+        assert!(Path::new(&path(TEST_VOLUME, "file.txt")).exists());
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_dir_all(TEST_DIR_OUT).unwrap();
+        fs::remove_dir_all(TEST_VOLUME).unwrap();
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn dedup() {
+        use std::os::unix::fs::MetadataExt;
+
+        const TEST_DIR: &str = "test_synchronize_dedup";
+        const TEST_DIR_OUT: &str = "test_synchronize_dedup_out";
+        let path = |dir: &str, path: &str| [dir, path].join("/");
+
+        fs::create_dir_all(TEST_DIR).unwrap();
+        fs::create_dir_all(TEST_DIR_OUT).unwrap();
+        fs::write(path(TEST_DIR, "a"), b"same").unwrap();
+        fs::write(path(TEST_DIR, "b"), b"same").unwrap();
+
+        let options = Options {
+            dedup: true,
+            ..Options::default()
+        };
+        assert!(synchronize(TEST_DIR, TEST_DIR_OUT, &options).is_ok());
+        let inode = |file: &str| fs::metadata(path(TEST_DIR_OUT, file)).unwrap().ino();
+        assert_eq!(inode("a"), inode("b"));
+
+        // Changing one of the files never changes the files linked to it
+        fs::write(path(TEST_DIR, "b"), b"changed").unwrap();
+        assert!(synchronize(TEST_DIR, TEST_DIR_OUT, &options).is_ok());
+        assert_eq!(fs::read(path(TEST_DIR_OUT, "a")).unwrap(), b"same");
+        assert_eq!(fs::read(path(TEST_DIR_OUT, "b")).unwrap(), b"changed");
+        assert_ne!(inode("a"), inode("b"));
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_dir_all(TEST_DIR_OUT).unwrap();
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn inplace() {
+        use std::os::unix::fs::MetadataExt;
+
+        const TEST_DIR: &str = "test_synchronize_inplace";
+        const TEST_DIR_OUT: &str = "test_synchronize_inplace_out";
+        let path = |dir: &str, path: &str| [dir, path].join("/");
+
+        fs::create_dir_all(TEST_DIR).unwrap();
+        fs::create_dir_all(TEST_DIR_OUT).unwrap();
+        fs::write(path(TEST_DIR, "file"), b"old contents").unwrap();
+        fs::write(path(TEST_DIR_OUT, "file"), b"old").unwrap();
+        fs::hard_link(path(TEST_DIR_OUT, "file"), path(TEST_DIR, "link")).unwrap();
+        let inode = fs::metadata(path(TEST_DIR_OUT, "file")).unwrap().ino();
+
+        // A file whose size changed is updated, not deleted and copied again, so its hard links
+        // are kept
+        let options = Options {
+            inplace: true,
+            ..Options::default()
+        };
+        assert!(synchronize(TEST_DIR, TEST_DIR_OUT, &options).is_ok());
+        assert_eq!(
+            fs::metadata(path(TEST_DIR_OUT, "file")).unwrap().ino(),
+            inode
+        );
+        assert_eq!(
+            fs::read(path(TEST_DIR_OUT, "file")).unwrap(),
+            b"old contents"
+        );
+        assert_eq!(fs::read(path(TEST_DIR, "link")).unwrap(), b"old contents");
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_dir_all(TEST_DIR_OUT).unwrap();
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn append_verify() {
+        use std::os::unix::fs::MetadataExt;
+
+        const TEST_DIR: &str = "test_synchronize_append_verify";
+        const TEST_DIR_OUT: &str = "test_synchronize_append_verify_out";
+        let path = |dir: &str, path: &str| [dir, path].join("/");
+
+        fs::create_dir_all(TEST_DIR).unwrap();
+        fs::create_dir_all(TEST_DIR_OUT).unwrap();
+        fs::write(path(TEST_DIR, "log"), b"line 1\n").unwrap();
+        let options = Options {
+            append_verify: true,
+            ..Options::default()
+        };
+        assert!(synchronize(TEST_DIR, TEST_DIR_OUT, &options).is_ok());
+        let inode = fs::metadata(path(TEST_DIR_OUT, "log")).unwrap().ino();
+
+        // A file that grew is appended to, rather than deleted and copied again
+        fs::write(path(TEST_DIR, "log"), b"line 1\nline 2\n").unwrap();
+        assert!(synchronize(TEST_DIR, TEST_DIR_OUT, &options).is_ok());
+        assert_eq!(
+            fs::metadata(path(TEST_DIR_OUT, "log")).unwrap().ino(),
+            inode
+        );
+        assert_eq!(
+            fs::read(path(TEST_DIR_OUT, "log")).unwrap(),
+            b"line 1\nline 2\n"
+        );
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_dir_all(TEST_DIR_OUT).unwrap();
+    }
 
-        assert_eq!(diff.status.success(), true);
+    #[test]
+    fn max_delete() {
+        const TEST_DIR: &str = "test_synchronize_max_delete";
+        const TEST_DIR_OUT: &str = "test_synchronize_max_delete_out";
+
+        fs::create_dir_all(TEST_DIR).unwrap();
+        fs::create_dir_all([TEST_DIR_OUT, "old"].join("/")).unwrap();
+        fs::write([TEST_DIR, "kept"].join("/"), b"kept").unwrap();
+        for file in &["kept", "a", "old/b", "old/c"] {
+            fs::write([TEST_DIR_OUT, file].join("/"), file.as_bytes()).unwrap();
+        }
+
+        // Deleting 3 of 4 files is refused, and nothing is changed
+        for max_delete in &[MaxDelete::Files(2), MaxDelete::Percent(50.0)] {
+            let options = Options {
+                max_delete: Some(*max_delete),
+                ..Options::default()
+            };
+            assert!(synchronize(TEST_DIR, TEST_DIR_OUT, &options).is_err());
+            assert!(fs::metadata([TEST_DIR_OUT, "old/c"].join("/")).is_ok());
+        }
+
+        let options = Options {
+            max_delete: Some(MaxDelete::Percent(75.0)),
+            ..Options::default()
+        };
+        assert!(synchronize(TEST_DIR, TEST_DIR_OUT, &options).is_ok());
+        assert!(fs::metadata([TEST_DIR_OUT, "old"].join("/")).is_err());
+
+        // Files that are only updated are not deleted, whatever their sizes were
+        for i in 0..10 {
+            fs::write([TEST_DIR, &i.to_string()].join("/"), b"new").unwrap();
+            fs::write([TEST_DIR_OUT, &i.to_string()].join("/"), b"old file").unwrap();
+        }
+        let options = Options {
+            max_delete: Some(MaxDelete::Files(0)),
+            ..Options::default()
+        };
+        assert!(synchronize(TEST_DIR, TEST_DIR_OUT, &options).is_ok());
+        assert_eq!(fs::read([TEST_DIR_OUT, "9"].join("/")).unwrap(), b"new");
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_dir_all(TEST_DIR_OUT).unwrap();
+    }
+
+    #[test]
+    fn ignore_case() {
+        const TEST_DIR: &str = "test_synchronize_ignore_case";
+        const TEST_DIR_OUT: &str = "test_synchronize_ignore_case_out";
+
+        fs::create_dir_all([TEST_DIR, "docs"].join("/")).unwrap();
+        fs::create_dir_all([TEST_DIR_OUT, "Docs"].join("/")).unwrap();
+        fs::write([TEST_DIR, "README.md"].join("/"), b"readme").unwrap();
+        fs::write([TEST_DIR, "Readme.md"].join("/"), b"other").unwrap();
+        fs::write([TEST_DIR, "docs/a"].join("/"), b"new").unwrap();
+        fs::write([TEST_DIR_OUT, "readme.md"].join("/"), b"readme").unwrap();
+        fs::write([TEST_DIR_OUT, "Docs/a"].join("/"), b"old").unwrap();
+
+        // Entries of dest are renamed to how src spells them, and only the first of the entries
+        // of src that differ in case is synchronized
+        let options = Options {
+            ignore_case: true,
+            ..Options::default()
+        };
+        assert!(synchronize(TEST_DIR, TEST_DIR_OUT, &options).is_ok());
+        let mut entries: Vec<String> = fs::read_dir(TEST_DIR_OUT)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        entries.sort();
+        assert_eq!(entries, vec!["README.md", "docs"]);
+        assert_eq!(
+            fs::read([TEST_DIR_OUT, "README.md"].join("/")).unwrap(),
+            b"readme"
+        );
+        assert_eq!(
+            fs::read([TEST_DIR_OUT, "docs/a"].join("/")).unwrap(),
+            b"new"
+        );
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_dir_all(TEST_DIR_OUT).unwrap();
+    }
+
+    #[test]
+    fn sanitize_names() {
+        const TEST_DIR: &str = "test_synchronize_sanitize_names";
+        const TEST_DIR_OUT: &str = "test_synchronize_sanitize_names_out";
+        const TEST_DIR_RESTORED: &str = "test_synchronize_sanitize_names_restored";
+
+        fs::create_dir_all([TEST_DIR, "dir?"].join("/")).unwrap();
+        fs::write([TEST_DIR, "a:b"].join("/"), b"a").unwrap();
+        fs::write([TEST_DIR, "dir?/c*d"].join("/"), b"c").unwrap();
+        fs::write([TEST_DIR, "50%3A"].join("/"), b"50").unwrap();
+        fs::create_dir_all(TEST_DIR_OUT).unwrap();
+        fs::create_dir_all(TEST_DIR_RESTORED).unwrap();
+
+        // Escaped entries are neither deleted nor escaped again by later runs
+        let options = Options {
+            sanitize_names: Some(Sanitize::Escape),
+            ..Options::default()
+        };
+        for _ in 0..2 {
+            assert!(synchronize(TEST_DIR, TEST_DIR_OUT, &options).is_ok());
+        }
+        let mut entries: Vec<PathBuf> = file_ops::get_all_files(TEST_DIR_OUT)
+            .unwrap()
+            .files()
+            .iter()
+            .map(|file| file.path().clone())
+            .collect();
+        entries.sort();
+        assert_eq!(
+            entries,
+            vec![
+                PathBuf::from("50%253A"),
+                PathBuf::from("a%3Ab"),
+                PathBuf::from("dir%3F/c%2Ad")
+            ]
+        );
+
+        let options = Options {
+            sanitize_names: Some(Sanitize::Restore),
+            ..Options::default()
+        };
+        assert!(synchronize(TEST_DIR_OUT, TEST_DIR_RESTORED, &options).is_ok());
+        assert!(!file_ops::file_differs(
+            &File::from("dir?/c*d", 1),
+            TEST_DIR,
+            TEST_DIR_RESTORED,
+            &Options::default()
+        ));
+        assert!(fs::metadata([TEST_DIR_RESTORED, "a:b"].join("/")).is_ok());
+        assert!(fs::metadata([TEST_DIR_RESTORED, "50%3A"].join("/")).is_ok());
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_dir_all(TEST_DIR_OUT).unwrap();
+        fs::remove_dir_all(TEST_DIR_RESTORED).unwrap();
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn downgrades_to_capabilities() {
+        use crate::lumins::capabilities::Capabilities;
+        use std::time::Duration;
+
+        const TEST_DIR: &str = "test_synchronize_downgrades_to_capabilities";
+        const TEST_DIR_OUT: &str = "test_synchronize_downgrades_to_capabilities_out";
+
+        fs::create_dir_all(TEST_DIR).unwrap();
+        fs::create_dir_all(TEST_DIR_OUT).unwrap();
+        fs::write([TEST_DIR, "file"].join("/"), b"file").unwrap();
+        std::os::unix::fs::symlink("file", [TEST_DIR, "link"].join("/")).unwrap();
+        fs::write([TEST_DIR_OUT, "file"].join("/"), b"old").unwrap();
+
+        // Files are still copied without their permissions, while the symlink is left out
+        let options = Options {
+            capabilities: Some(Capabilities {
+                symlinks: false,
+                permissions: false,
+                mtime_resolution: Duration::from_secs(2),
+                ..Capabilities::default()
+            }),
+            ..Options::default()
+        };
+        assert!(synchronize(TEST_DIR, TEST_DIR_OUT, &options).is_ok());
+        assert_eq!(fs::read([TEST_DIR_OUT, "file"].join("/")).unwrap(), b"file");
+        assert!(fs::symlink_metadata([TEST_DIR_OUT, "link"].join("/")).is_err());
+
+        // Destinations that were not probed yet are probed, and support everything here
+        assert!(synchronize(TEST_DIR, TEST_DIR_OUT, &Options::default()).is_ok());
+        assert!(fs::symlink_metadata([TEST_DIR_OUT, "link"].join("/")).is_ok());
+        assert_eq!(fs::read_dir(TEST_DIR_OUT).unwrap().count(), 2);
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_dir_all(TEST_DIR_OUT).unwrap();
+    }
+
+    #[test]
+    fn safe_delete() {
+        const TEST_DIR: &str = "test_synchronize_safe_delete";
+        const TEST_DIR_OUT: &str = "test_synchronize_safe_delete_out";
+
+        fs::create_dir_all([TEST_DIR, "empty"].join("/")).unwrap();
+        fs::create_dir_all(TEST_DIR_OUT).unwrap();
+        fs::write([TEST_DIR_OUT, "backup"].join("/"), b"backup").unwrap();
+
+        // Nothing is deleted from dest while src has no files
+        let options = Options {
+            safe_delete: true,
+            ..Options::default()
+        };
+        assert!(synchronize(TEST_DIR, TEST_DIR_OUT, &options).is_err());
+        assert!(fs::metadata([TEST_DIR_OUT, "backup"].join("/")).is_ok());
+        assert!(fs::metadata([TEST_DIR_OUT, "empty"].join("/")).is_err());
+
+        let options = Options {
+            safe_delete: true,
+            force_empty_source: true,
+            ..Options::default()
+        };
+        assert!(synchronize(TEST_DIR, TEST_DIR_OUT, &options).is_ok());
+        assert!(fs::metadata([TEST_DIR_OUT, "backup"].join("/")).is_err());
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_dir_all(TEST_DIR_OUT).unwrap();
+    }
+
+    #[test]
+    fn prune_empty_dirs() {
+        const TEST_DIR: &str = "test_synchronize_prune_empty_dirs";
+        const TEST_DIR_OUT: &str = "test_synchronize_prune_empty_dirs_out";
+        let path = |dir: &str, path: &str| [dir, path].join("/");
+
+        fs::create_dir_all(path(TEST_DIR, "empty/nested")).unwrap();
+        fs::create_dir_all(TEST_DIR_OUT).unwrap();
+        fs::create_dir_all(path(TEST_DIR, "full/nested")).unwrap();
+        fs::write(path(TEST_DIR, "full/nested/a"), b"a").unwrap();
+
+        let options = Options {
+            prune_empty_dirs: true,
+            ..Options::default()
+        };
+        assert!(synchronize(TEST_DIR, TEST_DIR_OUT, &options).is_ok());
+        assert!(fs::metadata(path(TEST_DIR_OUT, "empty")).is_err());
+        assert_eq!(fs::read(path(TEST_DIR_OUT, "full/nested/a")).unwrap(), b"a");
+
+        // Directories left empty by deletions are deleted along with them
+        fs::remove_file(path(TEST_DIR, "full/nested/a")).unwrap();
+        fs::write(path(TEST_DIR, "full/b"), b"b").unwrap();
+        assert!(synchronize(TEST_DIR, TEST_DIR_OUT, &options).is_ok());
+        assert!(fs::metadata(path(TEST_DIR_OUT, "full/nested")).is_err());
+        assert_eq!(fs::read(path(TEST_DIR_OUT, "full/b")).unwrap(), b"b");
+        assert!(fs::metadata(TEST_DIR_OUT).is_ok());
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_dir_all(TEST_DIR_OUT).unwrap();
+    }
+
+    #[test]
+    fn done_markers() {
+        const TEST_DIR: &str = "test_synchronize_done_markers";
+        const TEST_DIR_OUT: &str = "test_synchronize_done_markers_out";
+        let path = |dir: &str, path: &str| [dir, path].join("/");
+
+        fs::create_dir_all(path(TEST_DIR, "batch")).unwrap();
+        fs::create_dir_all(path(TEST_DIR_OUT, "stale")).unwrap();
+        fs::write(path(TEST_DIR, "batch/a"), b"a").unwrap();
+        fs::write(path(TEST_DIR, "batch/DONE"), b"from src").unwrap();
+        fs::write(path(TEST_DIR_OUT, "stale/DONE"), b"").unwrap();
+
+        let options = Options {
+            done_marker: Some("DONE".to_string()),
+            ..Options::default()
+        };
+        for _ in 0..2 {
+            assert!(synchronize(TEST_DIR, TEST_DIR_OUT, &options).is_ok());
+            assert_eq!(fs::read(path(TEST_DIR_OUT, "batch/a")).unwrap(), b"a");
+            assert_eq!(fs::read(path(TEST_DIR_OUT, "batch/DONE")).unwrap(), b"");
+            assert!(fs::metadata(path(TEST_DIR_OUT, "DONE")).is_ok());
+            assert!(fs::metadata(path(TEST_DIR_OUT, "stale")).is_err());
+        }
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_dir_all(TEST_DIR_OUT).unwrap();
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn fences() {
+        const TEST_DIR: &str = "test_synchronize_fences";
+        const TEST_DIR_OUT: &str = "test_synchronize_fences_out";
+
+        for dir in &["batches/1/sub", "batches/2", "other"] {
+            fs::create_dir_all([TEST_DIR, dir].join("/")).unwrap();
+        }
+        fs::create_dir_all([TEST_DIR_OUT, "batches/stale"].join("/")).unwrap();
+        fs::write([TEST_DIR, "batches/1/sub/a"].join("/"), b"file a").unwrap();
+        fs::write([TEST_DIR, "batches/2/b"].join("/"), b"file b").unwrap();
+        fs::write([TEST_DIR, "other/c"].join("/"), b"file c").unwrap();
+        fs::write([TEST_DIR_OUT, "batches/stale/d"].join("/"), b"file d").unwrap();
+
+        let options = Options {
+            fences: vec![Glob::new("batches/*").unwrap()],
+            ..Options::default()
+        };
+        let same = || {
+            Command::new("diff")
+                .args(["-r", TEST_DIR, TEST_DIR_OUT])
+                .output()
+                .unwrap()
+                .status
+                .success()
+        };
+        assert!(synchronize(TEST_DIR, TEST_DIR_OUT, &options).is_ok());
+        assert!(same());
+
+        // Renames into a later fence still happen in its phase
+        fs::rename(
+            [TEST_DIR, "batches/1/sub/a"].join("/"),
+            [TEST_DIR, "batches/2/a"].join("/"),
+        )
+        .unwrap();
+        assert!(synchronize(TEST_DIR, TEST_DIR_OUT, &options).is_ok());
+        assert!(same());
+
+        fs::remove_dir_all(TEST_DIR_OUT).unwrap();
+        assert!(copy(TEST_DIR, TEST_DIR_OUT, &options).is_ok());
+        assert!(same());
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_dir_all(TEST_DIR_OUT).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod test_copy {
+    use super::*;
+    use std::fs;
+    use std::process::Command;
+
+    #[test]
+    fn invalid_src() {
+        assert!(copy("/?", "src", &Options::default()).is_err());
+    }
+
+    #[test]
+    fn invalid_dest() {
+        const TEST_DIR: &str = "test_copy_invalid_dest";
+        assert!(copy("src", TEST_DIR, &Options::default()).is_ok());
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn dir1() {
+        const TEST_DIR: &str = "test_copy_dir1";
+        fs::create_dir_all(TEST_DIR).unwrap();
+
+        assert!(copy("src", TEST_DIR, &Options::default()).is_ok());
+
+        let diff = Command::new("diff")
+            .args(["-r", "src", TEST_DIR])
+            .output()
+            .unwrap();
+
+        assert!(diff.status.success());
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn flags() {
+        const TEST_DIR: &str = "test_copy_flags";
+        fs::create_dir_all(TEST_DIR).unwrap();
+
+        let options = Options {
+            sequential: true,
+            ..Options::default()
+        };
+
+        assert!(copy("src", TEST_DIR, &options).is_ok());
+
+        let diff = Command::new("diff")
+            .args(["-r", "src", TEST_DIR])
+            .output()
+            .unwrap();
+
+        assert!(diff.status.success());
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+
+    #[test]
+    fn single_file() {
+        const TEST_DIR: &str = "test_copy_single_file";
+        fs::create_dir_all(TEST_DIR).unwrap();
+
+        // A file is copied under the name it is given
+        let dest = [TEST_DIR, "renamed.rs"].join("/");
+        assert!(copy("src/main.rs", &dest, &Options::default()).is_ok());
+        assert_eq!(fs::read(&dest).unwrap(), fs::read("src/main.rs").unwrap());
+
+        fs::write(&dest, b"changed").unwrap();
+        let options = Options {
+            ignore_existing: true,
+            ..Options::default()
+        };
+        assert!(copy("src/main.rs", &dest, &options).is_ok());
+        assert_eq!(fs::read(&dest).unwrap(), b"changed");
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+
+    #[test]
+    fn files_from() {
+        const TEST_DIR: &str = "test_copy_files_from";
+
+        let options = Options {
+            files_from: Some(vec![
+                PathBuf::from("lumins/core.rs"),
+                PathBuf::from("main.rs"),
+            ]),
+            ..Options::default()
+        };
+        assert!(copy("src", TEST_DIR, &options).is_ok());
+
+        // Only what is listed is copied, along with the directories it is in
+        for copied in &["lumins/core.rs", "main.rs"] {
+            let dest_file = Path::new(TEST_DIR).join(copied);
+            assert_eq!(
+                fs::read(dest_file).unwrap(),
+                fs::read(Path::new("src").join(copied)).unwrap()
+            );
+        }
+        assert!(!Path::new(TEST_DIR).join("lumins/parse.rs").exists());
+        assert!(!Path::new(TEST_DIR).join("cli.yml").exists());
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn xattrs() {
+        const TEST_DIR: &str = "test_copy_xattrs";
+        const TEST_DIR_OUT: &str = "test_copy_xattrs_out";
+        fs::create_dir_all(TEST_DIR).unwrap();
+        fs::create_dir_all(TEST_DIR_OUT).unwrap();
+        fs::write([TEST_DIR, "file"].join("/"), b"1234").unwrap();
+
+        // Skip if the filesystem does not support user xattrs
+        if xattr::set([TEST_DIR, "file"].join("/"), "user.lumins", b"1").is_ok() {
+            xattr::set(TEST_DIR, "user.lumins", b"2").unwrap();
+
+            assert!(copy(TEST_DIR, TEST_DIR_OUT, &Options::default()).is_ok());
+            assert_eq!(
+                xattr::get([TEST_DIR_OUT, "file"].join("/"), "user.lumins").unwrap(),
+                None
+            );
+
+            assert!(copy(
+                TEST_DIR,
+                TEST_DIR_OUT,
+                &Options {
+                    xattrs: true,
+                    ..Options::default()
+                }
+            )
+            .is_ok());
+            assert_eq!(
+                xattr::get([TEST_DIR_OUT, "file"].join("/"), "user.lumins").unwrap(),
+                Some(b"1".to_vec())
+            );
+        }
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_dir_all(TEST_DIR_OUT).unwrap();
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn idmap() {
+        use std::os::unix::fs::{symlink, MetadataExt};
+        const TEST_DIR: &str = "test_copy_idmap";
+        const TEST_DIR_OUT: &str = "test_copy_idmap_out";
+        fs::create_dir_all([TEST_DIR, "dir"].join("/")).unwrap();
+        fs::write([TEST_DIR, "dir", "file"].join("/"), b"1234").unwrap();
+        symlink("file", [TEST_DIR, "dir", "link"].join("/")).unwrap();
+
+        let uid = fs::metadata(TEST_DIR).unwrap().uid();
+        let gid = fs::metadata(TEST_DIR).unwrap().gid();
+        // Only root may give files away, so map to the current owner otherwise
+        let (idmap, expected) = if uid == 0 && gid == 0 {
+            ("b +100000", (100000, 100000))
+        } else {
+            ("", (uid, gid))
+        };
+        let options = Options {
+            idmap: Some(idmap.parse().unwrap()),
+            ..Options::default()
+        };
+
+        assert!(copy(TEST_DIR, TEST_DIR_OUT, &options).is_ok());
+
+        for path in &["dir", "dir/file", "dir/link"] {
+            let metadata = fs::symlink_metadata([TEST_DIR_OUT, path].join("/")).unwrap();
+            assert_eq!((metadata.uid(), metadata.gid()), expected, "{}", path);
+        }
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_dir_all(TEST_DIR_OUT).unwrap();
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn fsync_streaming() {
+        const TEST_DIR: &str = "test_copy_fsync_streaming";
+        const TEST_DIR_OUT: &str = "test_copy_fsync_streaming_out";
+        fs::create_dir_all([TEST_DIR, "dir"].join("/")).unwrap();
+        fs::write([TEST_DIR, "dir", "file"].join("/"), b"synced").unwrap();
+
+        // Copies write each directory as soon as it is listed, and then flush it
+        let options = Options {
+            fsync: true,
+            ..Options::default()
+        };
+        assert!(streams(&options));
+        assert!(copy(TEST_DIR, TEST_DIR_OUT, &options).is_ok());
+        let low_memory = Options {
+            fsync: true,
+            low_memory: true,
+            ..Options::default()
+        };
+        assert!(synchronize(TEST_DIR, TEST_DIR_OUT, &low_memory).is_ok());
+        assert_eq!(
+            fs::read([TEST_DIR_OUT, "dir", "file"].join("/")).unwrap(),
+            b"synced"
+        );
+
+        // The first directory that could not be flushed is kept, and only with --fsync
+        let unflushed = Mutex::new(None);
+        flush_dir(Path::new("dir"), TEST_DIR_OUT, &options, &unflushed);
+        flush_dir(
+            Path::new("missing"),
+            TEST_DIR_OUT,
+            &Options::default(),
+            &unflushed,
+        );
+        assert!(unflushed.lock().unwrap().is_none());
+        flush_dir(Path::new("missing"), TEST_DIR_OUT, &options, &unflushed);
+        let e = unflushed.into_inner().unwrap().unwrap();
+        assert!(e.to_string().starts_with("Sync Error"));
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_dir_all(TEST_DIR_OUT).unwrap();
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn archive() {
+        const TEST_DIR: &str = "test_copy_archive";
+        const TEST_ARCHIVE: &str = "test_copy_archive.tar";
+        const TEST_DIR_OUT: &str = "test_copy_archive_out";
+        fs::create_dir_all([TEST_DIR, "a"].join("/")).unwrap();
+        fs::create_dir_all(TEST_DIR_OUT).unwrap();
+        fs::write([TEST_DIR, "a/file"].join("/"), b"1234").unwrap();
+
+        let options = Options {
+            deterministic: true,
+            ..Options::default()
+        };
+        assert!(copy(TEST_DIR, TEST_ARCHIVE, &options).is_ok());
+        let archive = fs::read(TEST_ARCHIVE).unwrap();
+
+        // Touching the source does not change a deterministic archive
+        Command::new("touch")
+            .args([[TEST_DIR, "a/file"].join("/")])
+            .output()
+            .unwrap();
+        assert!(copy(TEST_DIR, TEST_ARCHIVE, &options).is_ok());
+        assert_eq!(fs::read(TEST_ARCHIVE).unwrap(), archive);
+
+        let extract = Command::new("tar")
+            .args(["-xf", TEST_ARCHIVE, "-C", TEST_DIR_OUT])
+            .output()
+            .unwrap();
+        assert!(extract.status.success());
+
+        let diff = Command::new("diff")
+            .args(["-r", TEST_DIR, TEST_DIR_OUT])
+            .output()
+            .unwrap();
+        assert!(diff.status.success());
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_file(TEST_ARCHIVE).unwrap();
+        fs::remove_dir_all(TEST_DIR_OUT).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod test_move {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn invalid_src() {
+        assert!(move_dir("/?", "test_move_invalid_src", &Options::default()).is_err());
+    }
+
+    #[test]
+    fn into_itself() {
+        const TEST_DIR: &str = "test_move_into_itself";
+        fs::create_dir_all([TEST_DIR, "sub"].join("/")).unwrap();
+
+        assert!(move_dir(TEST_DIR, &[TEST_DIR, "sub"].join("/"), &Options::default()).is_err());
+        assert!(fs::metadata([TEST_DIR, "sub"].join("/")).is_ok());
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+
+    #[test]
+    fn rename() {
+        const TEST_DIR: &str = "test_move_rename";
+        let path = |path: &str| [TEST_DIR, path].join("/");
+        fs::create_dir_all(path("src/d")).unwrap();
+        fs::write(path("src/d/a"), b"a").unwrap();
+
+        assert!(move_dir(&path("src"), &path("dest"), &Options::default()).is_ok());
+        assert!(fs::metadata(path("src")).is_err());
+        assert_eq!(fs::read(path("dest/d/a")).unwrap(), b"a");
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn verified_copy() {
+        const TEST_DIR: &str = "test_move_verified_copy";
+        let path = |path: &str| [TEST_DIR, path].join("/");
+        fs::create_dir_all(path("src/d")).unwrap();
+        fs::create_dir_all(path("dest")).unwrap();
+        fs::write(path("src/d/a"), b"a").unwrap();
+        fs::write(path("src/b"), b"b").unwrap();
+        std::os::unix::fs::symlink("b", path("src/l")).unwrap();
+        // A non-empty dest can not be renamed over, so src is copied and verified instead
+        fs::write(path("dest/c"), b"c").unwrap();
+
+        for verify in [true, false] {
+            let options = Options {
+                verify,
+                ..Options::default()
+            };
+            assert!(move_dir(&path("src"), &path("dest"), &options).is_ok());
+            assert!(fs::metadata(path("src")).is_err());
+            assert_eq!(fs::read(path("dest/d/a")).unwrap(), b"a");
+            assert_eq!(fs::read(path("dest/b")).unwrap(), b"b");
+            assert_eq!(fs::read(path("dest/c")).unwrap(), b"c");
+            assert_eq!(fs::read_link(path("dest/l")).unwrap(), Path::new("b"));
+
+            fs::rename(path("dest"), path("src")).unwrap();
+            fs::create_dir_all(path("dest")).unwrap();
+            fs::rename(path("src/c"), path("dest/c")).unwrap();
+        }
 
         fs::remove_dir_all(TEST_DIR).unwrap();
     }
@@ -382,7 +3160,7 @@ mod test_remove {
 
     #[test]
     fn invalid_target() {
-        assert_eq!(remove("/?", Flag::empty()).is_err(), true);
+        assert!(remove("/?", &Options::default()).is_err());
     }
 
     #[cfg(target_family = "unix")]
@@ -392,13 +3170,13 @@ mod test_remove {
         fs::create_dir_all(TEST_DIR).unwrap();
 
         Command::new("cp")
-            .args(&["-r", BUILD_DIR, TEST_DIR])
+            .args(["-r", BUILD_DIR, TEST_DIR])
             .output()
             .unwrap();
 
-        assert_eq!(remove(TEST_DIR, Flag::empty()).is_ok(), true);
+        assert!(remove(TEST_DIR, &Options::default()).is_ok());
 
-        assert_eq!(fs::read_dir(TEST_DIR).is_err(), true);
+        assert!(fs::read_dir(TEST_DIR).is_err());
     }
 
     #[cfg(target_family = "unix")]
@@ -407,16 +3185,18 @@ mod test_remove {
         const TEST_DIR: &str = "test_remove_flags";
         fs::create_dir_all(TEST_DIR).unwrap();
 
-        let mut flags = Flag::empty();
-        flags.insert(Flag::SEQUENTIAL);
+        let options = Options {
+            sequential: true,
+            ..Options::default()
+        };
 
         Command::new("cp")
-            .args(&["-r", "src", TEST_DIR])
+            .args(["-r", "src", TEST_DIR])
             .output()
             .unwrap();
 
-        assert_eq!(remove(TEST_DIR, flags).is_ok(), true);
+        assert!(remove(TEST_DIR, &options).is_ok());
 
-        assert_eq!(fs::read_dir(TEST_DIR).is_err(), true);
+        assert!(fs::read_dir(TEST_DIR).is_err());
     }
 }
@@ -0,0 +1,614 @@
+//! Runs jobs from the config file on cron-like schedules
+
+use std::io;
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::lumins::config::{Config, Job, JobCommand};
+use crate::lumins::core;
+use crate::lumins::history::{self, Norms};
+use crate::lumins::parse;
+use crate::progress::{PROGRESS_BAR, STATS};
+
+/// A struct that represents a broken-down local time, down to the minute
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub struct Time {
+    /// 0-59
+    pub minute: u32,
+    /// 0-23
+    pub hour: u32,
+    /// 1-31
+    pub day: u32,
+    /// 1-12
+    pub month: u32,
+    /// 0-6, where 0 is Sunday
+    pub weekday: u32,
+}
+
+impl Time {
+    /// Converts seconds since the Unix epoch into local time
+    #[cfg(target_family = "unix")]
+    pub fn from_unix(secs: i64) -> Self {
+        let time = secs as libc::time_t;
+        // This is safe to zero, since `tm` is a plain C struct
+        let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+
+        // Safe since both pointers are valid for the duration of the call
+        unsafe { libc::localtime_r(&time, &mut tm) };
+
+        Time {
+            minute: tm.tm_min as u32,
+            hour: tm.tm_hour as u32,
+            day: tm.tm_mday as u32,
+            month: tm.tm_mon as u32 + 1,
+            weekday: tm.tm_wday as u32,
+        }
+    }
+
+    /// Converts seconds since the Unix epoch into UTC time
+    #[cfg(not(target_family = "unix"))]
+    pub fn from_unix(secs: i64) -> Self {
+        let days = secs.div_euclid(86400);
+        let secs_of_day = secs.rem_euclid(86400);
+
+        // Convert days since the epoch into a civil date
+        let z = days + 719_468;
+        let era = z.div_euclid(146_097);
+        let doe = z - era * 146_097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+
+        Time {
+            minute: (secs_of_day / 60 % 60) as u32,
+            hour: (secs_of_day / 3600) as u32,
+            day: day as u32,
+            month: month as u32,
+            // The epoch was a Thursday
+            weekday: (days + 4).rem_euclid(7) as u32,
+        }
+    }
+}
+
+/// A struct that represents a parsed cron schedule
+///
+/// Each field is a bitmask of the values that match, such that bit `i` is set if `i` matches
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub struct Schedule {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl FromStr for Schedule {
+    type Err = io::Error;
+
+    /// Parses a five field cron expression (`minute hour day month weekday`),
+    /// supporting `*`, lists, ranges, steps, and the `@hourly`, `@daily`, `@weekly`,
+    /// `@monthly`, and `@yearly` shortcuts
+    fn from_str(schedule: &str) -> Result<Self, Self::Err> {
+        let expanded = match schedule.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            other => other,
+        };
+
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Schedule Error -- invalid schedule {:?}", schedule),
+            )
+        };
+
+        let fields: Vec<&str> = expanded.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(invalid());
+        }
+
+        let mut weekdays = parse_field(fields[4], 0, 7).ok_or_else(invalid)?;
+        // Both 0 and 7 are Sunday
+        if weekdays & (1 << 7) != 0 {
+            weekdays |= 1;
+        }
+
+        // Fields are unrestricted if they match every value, however they are written, such as
+        // `*/1` or `1-31`
+        let days = parse_field(fields[2], 1, 31).ok_or_else(invalid)?;
+        let all = |min: u32, max: u32| (min..=max).fold(0, |mask, value| mask | 1 << value);
+        Ok(Schedule {
+            minutes: parse_field(fields[0], 0, 59).ok_or_else(invalid)?,
+            hours: parse_field(fields[1], 0, 23).ok_or_else(invalid)?,
+            days,
+            months: parse_field(fields[3], 1, 12).ok_or_else(invalid)?,
+            weekdays,
+            any_day: days == all(1, 31),
+            any_weekday: weekdays & all(0, 6) == all(0, 6),
+        })
+    }
+}
+
+impl Schedule {
+    /// Checks whether the schedule fires at `time`
+    ///
+    /// As in cron, if both the day of month and day of week are restricted,
+    /// the schedule fires when either matches
+    pub fn matches(&self, time: &Time) -> bool {
+        let day = self.days & (1 << time.day) != 0;
+        let weekday = self.weekdays & (1 << time.weekday) != 0;
+        let day_matches = match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        };
+
+        self.minutes & (1 << time.minute) != 0
+            && self.hours & (1 << time.hour) != 0
+            && self.months & (1 << time.month) != 0
+            && day_matches
+    }
+}
+
+/// Parses a single cron field into a bitmask of matching values
+///
+/// # Returns
+/// * Some: The bitmask of values in `min..=max` matched by `field`
+/// * None: If `field` is invalid or out of range
+fn parse_field(field: &str, min: u32, max: u32) -> Option<u64> {
+    let mut mask = 0;
+
+    for part in field.split(',') {
+        let (range, step) = match part.find('/') {
+            Some(i) => (&part[..i], part[i + 1..].parse::<u32>().ok()?),
+            None => (part, 1),
+        };
+        if step == 0 {
+            return None;
+        }
+
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some(i) = range.find('-') {
+            (
+                range[..i].parse::<u32>().ok()?,
+                range[i + 1..].parse::<u32>().ok()?,
+            )
+        } else {
+            let start = range.parse::<u32>().ok()?;
+            // `n/step` means every `step` starting from `n`
+            (start, if part.contains('/') { max } else { start })
+        };
+
+        if start < min || end > max || start > end {
+            return None;
+        }
+
+        for value in (start..=end).step_by(step as usize) {
+            mask |= 1 << value;
+        }
+    }
+
+    Some(mask)
+}
+
+/// Runs a single job once
+///
+/// The destination directory is created if it does not exist, or the directory that it is
+/// written into if it is an archive or image
+///
+/// # Errors
+/// This function will return an error in the following situations,
+/// but is not limited to just these cases:
+/// * The job has an invalid flag
+/// * The job's source is missing, or its source and destination are not apart
+/// * The job's destination could not be created
+pub fn run_job(job: &Job) -> Result<(), io::Error> {
+    let mut options = job.options()?;
+    if options.refuse_anomalies {
+        options.norms = Some(Norms::load(&history::default_path(), &job.name)?);
+    }
+
+    // The source may have been replaced since the config was loaded, such as by a mount, so its
+    // endpoints are checked again before every run
+    let endpoints = job.endpoints();
+    let destination = job.run_destination(&options);
+    endpoints
+        .check_src(&job.source)
+        .and_then(|_| endpoints.check_dest(&job.source, &destination))
+        .and_then(|_| endpoints.create_dest(&destination, &options))
+        .map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Config Error -- job {}: invalid source or destination",
+                    job.name
+                ),
+            )
+        })?;
+    parse::set_run_env(&options);
+
+    let result = match job.command {
+        JobCommand::Cp => core::copy(&job.source, &destination, &options),
+        JobCommand::Sync => core::synchronize(&job.source, &destination, &options),
+    };
+    PROGRESS_BAR.finish_and_clear();
+
+    // With keep-going, report every error of the job again once it is done
+    let messages = STATS.error_messages();
+    if !messages.is_empty() {
+        eprintln!("Job {} -- {} errors:", job.name, messages.len());
+        for message in messages {
+            eprintln!("  {}", message);
+        }
+    }
+
+    result
+}
+
+/// Gets the indices of the `schedules` that fire at any minute after the minute `after`, up to
+/// and including the minute `until`, where minutes are counted from the Unix epoch
+///
+/// Only the last `MAX_CATCH_UP` minutes are checked, so that a clock that jumps far ahead does
+/// not take long to check, nor run every job at once.
+fn due(schedules: &[Schedule], after: u64, until: u64) -> Vec<usize> {
+    let after = after.max(until.saturating_sub(MAX_CATCH_UP));
+    let times: Vec<Time> = (after + 1..=until)
+        .map(|minute| Time::from_unix(minute as i64 * 60))
+        .collect();
+    (0..schedules.len())
+        .filter(|&i| times.iter().any(|time| schedules[i].matches(time)))
+        .collect()
+}
+
+/// Minutes of schedules that `run` catches up on, once jobs ran past them, which is a day
+const MAX_CATCH_UP: u64 = 24 * 60;
+
+/// Runs the jobs in `config` forever, checking their schedules once every minute
+///
+/// Jobs that are due at the same minute are run one after another in the order they are
+/// defined. Jobs that came due while others ran are run once those are done, once each however
+/// many times they came due, so that a long run never makes others skip theirs. The result of
+/// every run is printed, along with how long it took, and a summary of it is recorded in the
+/// history under the name of its job.
+///
+/// # Errors
+/// This function only returns if a job has an invalid schedule or invalid flags
+pub fn run(config: &Config) -> Result<(), io::Error> {
+    let mut schedules = Vec::with_capacity(config.jobs.len());
+    for job in &config.jobs {
+        job.options()?;
+        schedules.push(job.schedule.parse::<Schedule>().map_err(|e| {
+            io::Error::new(e.kind(), format!("Config Error -- job {}: {}", job.name, e))
+        })?);
+    }
+
+    println!("Starting daemon with {} job(s)", config.jobs.len());
+
+    // Every minute since the last one that was checked is checked
+    let mut checked = unix_now() / 60 - 1;
+    loop {
+        let now = unix_now() / 60;
+        let due = due(&schedules, checked, now);
+        checked = checked.max(now);
+
+        for job in due.into_iter().map(|i| &config.jobs[i]) {
+            let start = Instant::now();
+            match history::record(&history::default_path(), &job.name, || run_job(job)) {
+                Ok(_) => println!(
+                    "Job {} -- finished in {:.1}s",
+                    job.name,
+                    start.elapsed().as_secs_f64()
+                ),
+                Err(e) => eprintln!(
+                    "Job {} -- failed after {:.1}s: {}",
+                    job.name,
+                    start.elapsed().as_secs_f64(),
+                    e
+                ),
+            }
+        }
+
+        // Sleep until the start of the next minute
+        let next_minute = (unix_now() / 60 + 1) * 60;
+        thread::sleep(Duration::from_secs(next_minute.saturating_sub(unix_now())));
+    }
+}
+
+/// Gets the current time in seconds since the Unix epoch
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_schedule {
+    use super::*;
+
+    const TIME: Time = Time {
+        minute: 30,
+        hour: 3,
+        day: 15,
+        month: 6,
+        weekday: 1,
+    };
+
+    #[test]
+    fn invalid_schedules() {
+        for schedule in &[
+            "",
+            "* * * *",
+            "* * * * * *",
+            "60 * * * *",
+            "* 24 * * *",
+            "* * 0 * *",
+            "* * * 13 *",
+            "* * * * 8",
+            "*/0 * * * *",
+            "5-1 * * * *",
+            "a * * * *",
+        ] {
+            assert!(schedule.parse::<Schedule>().is_err(), "{}", schedule);
+        }
+    }
+
+    #[test]
+    fn matching_schedules() {
+        for schedule in &[
+            "* * * * *",
+            "30 3 * * *",
+            "*/15 * * * *",
+            "0-59/10 */3 * * *",
+            "29,30,31 3 15 6 *",
+            "30 3 * * 1-5",
+            "30 3 1 * 1",
+            "30 3 15 * 0",
+        ] {
+            assert!(
+                schedule.parse::<Schedule>().unwrap().matches(&TIME),
+                "{}",
+                schedule
+            );
+        }
+    }
+
+    #[test]
+    fn non_matching_schedules() {
+        for schedule in &[
+            "@hourly",
+            "@daily",
+            "31 3 * * *",
+            "*/7 * * * *",
+            "30 3 * * 0,6",
+            "30 3 1 * 0",
+            "30 3 * 7 *",
+        ] {
+            assert!(
+                !schedule.parse::<Schedule>().unwrap().matches(&TIME),
+                "{}",
+                schedule
+            );
+        }
+    }
+
+    #[test]
+    fn unrestricted_days() {
+        // Days that match every value are unrestricted however they are written, so both days
+        // have to match
+        for schedule in &[
+            "30 3 */1 * 0",
+            "30 3 1-31 * 0",
+            "30 3 1 * 0-6",
+            "30 3 1 * */1",
+        ] {
+            assert!(
+                !schedule.parse::<Schedule>().unwrap().matches(&TIME),
+                "{}",
+                schedule
+            );
+        }
+        assert!("30 3 1-31 * 1-5"
+            .parse::<Schedule>()
+            .unwrap()
+            .matches(&TIME));
+        assert!("30 3 1-30 * 0".parse::<Schedule>().unwrap().matches(&TIME));
+    }
+
+    #[test]
+    fn due_schedules() {
+        let minute = 28_000_000;
+        let time = Time::from_unix(minute as i64 * 60);
+        let schedules: Vec<Schedule> = [
+            format!(
+                "{} {} {} {} *",
+                time.minute, time.hour, time.day, time.month
+            ),
+            "* * * * *".to_string(),
+            format!("{} * * * *", (time.minute + 30) % 60),
+        ]
+        .iter()
+        .map(|schedule| schedule.parse().unwrap())
+        .collect();
+
+        // Schedules that came due since the last check are due once, however often they did
+        assert_eq!(due(&schedules, minute - 10, minute + 10), vec![0, 1]);
+        assert_eq!(due(&schedules, minute, minute + 10), vec![1]);
+        assert_eq!(due(&schedules, minute - 1, minute), vec![0, 1]);
+        assert!(due(&schedules, minute, minute).is_empty());
+        assert_eq!(due(&schedules, minute - 60, minute), vec![0, 1, 2]);
+        // Far behind, only the last day is caught up on
+        assert_eq!(due(&schedules, 0, minute + 2 * MAX_CATCH_UP), vec![1, 2]);
+    }
+
+    #[test]
+    fn sunday() {
+        let sunday = Time { weekday: 0, ..TIME };
+        assert!("30 3 * * 7".parse::<Schedule>().unwrap().matches(&sunday));
+        assert!("@weekly".parse::<Schedule>().unwrap().matches(&Time {
+            minute: 0,
+            hour: 0,
+            ..sunday
+        }));
+    }
+}
+
+#[cfg(test)]
+mod test_run_job {
+    use super::*;
+    use std::fs;
+    use std::process::Command;
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn sync_job() {
+        const TEST_DIR: &str = "test_run_job_sync_job";
+
+        let job = Job {
+            name: "test".to_string(),
+            schedule: "* * * * *".to_string(),
+            command: JobCommand::Sync,
+            source: "src".to_string(),
+            destination: TEST_DIR.to_string(),
+            flags: vec!["secure".to_string()],
+        };
+
+        assert!(run_job(&job).is_ok());
+
+        let diff = Command::new("diff")
+            .args(["-r", "src", TEST_DIR])
+            .output()
+            .unwrap();
+
+        assert!(diff.status.success());
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn into_job() {
+        const TEST_DIR: &str = "test_run_job_into_job";
+
+        let job = Job {
+            name: "test".to_string(),
+            schedule: "* * * * *".to_string(),
+            command: JobCommand::Sync,
+            source: "src".to_string(),
+            destination: TEST_DIR.to_string(),
+            flags: vec!["into".to_string()],
+        };
+
+        assert!(run_job(&job).is_ok());
+
+        let diff = Command::new("diff")
+            .args(["-r", "src", &[TEST_DIR, "src"].join("/")])
+            .output()
+            .unwrap();
+
+        assert!(diff.status.success());
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn trailing_slash_job() {
+        const TEST_DIR: &str = "test_run_job_trailing_slash_job";
+
+        // Like rsync, src/ is the contents of src, and src is src itself
+        for source in &["src/", "src"] {
+            let job = Job {
+                name: "test".to_string(),
+                schedule: "* * * * *".to_string(),
+                command: JobCommand::Cp,
+                source: source.to_string(),
+                destination: TEST_DIR.to_string(),
+                flags: vec!["trailing-slash=rsync".to_string()],
+            };
+            assert!(run_job(&job).is_ok());
+        }
+
+        for copy in &[TEST_DIR.to_string(), [TEST_DIR, "src"].join("/")] {
+            let diff = Command::new("diff")
+                .args(["-r", "-x", "src", "src", copy])
+                .output()
+                .unwrap();
+            assert!(diff.status.success());
+        }
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn archive_job() {
+        const TEST_DIR: &str = "test_run_job_archive_job";
+
+        // Archives are written into their directory, rather than being created as one
+        let job = Job {
+            name: "test".to_string(),
+            schedule: "* * * * *".to_string(),
+            command: JobCommand::Cp,
+            source: "src".to_string(),
+            destination: [TEST_DIR, "src.tar"].join("/"),
+            flags: vec![],
+        };
+        assert!(run_job(&job).is_ok());
+        assert!(fs::metadata([TEST_DIR, "src.tar"].join("/"))
+            .unwrap()
+            .is_file());
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+
+    #[test]
+    fn job_into_its_source() {
+        const TEST_DIR: &str = "test_run_job_job_into_its_source";
+
+        fs::create_dir_all([TEST_DIR, "sub"].join("/")).unwrap();
+        fs::write([TEST_DIR, "sub/file"].join("/"), "").unwrap();
+
+        // A sync into the directory that holds its source would delete the source
+        let job = Job {
+            name: "test".to_string(),
+            schedule: "* * * * *".to_string(),
+            command: JobCommand::Sync,
+            source: [TEST_DIR, "sub"].join("/"),
+            destination: TEST_DIR.to_string(),
+            flags: vec![],
+        };
+        assert!(run_job(&job).is_err());
+        assert!(fs::metadata([TEST_DIR, "sub/file"].join("/")).is_ok());
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+
+    #[test]
+    fn invalid_job() {
+        let job = Job {
+            name: "test".to_string(),
+            schedule: "* * * * *".to_string(),
+            command: JobCommand::Cp,
+            source: "/?".to_string(),
+            destination: "test_run_job_invalid_job".to_string(),
+            flags: vec![],
+        };
+
+        // The source is checked before the destination is created
+        assert!(run_job(&job).is_err());
+        assert!(fs::metadata("test_run_job_invalid_job").is_err());
+    }
+}
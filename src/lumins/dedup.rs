@@ -0,0 +1,200 @@
+//! Hard links files with identical contents in a tree to each other, to save space
+//!
+//! Files are only linked if they also have the same permissions and owner, since linked files
+//! share them. Files that are already linked to each other are never hashed again.
+
+use std::path::Path;
+
+use hashbrown::HashMap;
+use log::{error, info};
+use rayon::prelude::*;
+
+use crate::lumins::dirfd;
+use crate::lumins::file_ops::{self, File, FileOps};
+
+/// A struct that represents what linked files share, besides their contents
+#[derive(Hash, Eq, PartialEq, Debug, Clone, Copy)]
+struct Key {
+    size: u64,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+}
+
+/// A struct that represents the identity of a file on disk
+#[derive(Hash, Eq, PartialEq, Debug, Clone, Copy)]
+struct Inode {
+    dev: u64,
+    ino: u64,
+}
+
+/// The files of `files` that are one inode, along with the number of links it has
+type Linked<'a> = (u64, Vec<&'a File>);
+
+/// Hard links the files of `files` in `dest` that have identical contents to each other
+///
+/// Of every set of identical files, the copy that already has the most of them linked to it is
+/// kept, and the others are replaced with links to it. Empty files are never linked, since they
+/// take no space. Files that can not be read or linked are logged and left as they are.
+///
+/// # Arguments
+/// * `dest`: base directory of `files`
+/// * `files`: files to deduplicate, relative to `dest`
+///
+/// # Returns
+/// The number of files that were replaced with links, and the number of bytes that were freed
+// `mode_t` and `dev_t` are narrower than these on some platforms
+#[allow(clippy::unnecessary_cast)]
+pub fn dedup<'a, T>(dest: &str, files: T) -> (usize, u64)
+where
+    T: IntoParallelIterator<Item = &'a File>,
+{
+    let base = Path::new(dest);
+
+    let stats: Vec<(Key, Inode, u64, &File)> = files
+        .into_par_iter()
+        .filter(|file| file.size() > 0)
+        .filter_map(|file| {
+            let stat = dirfd::open_parent(base, file.path(), false)
+                .and_then(|(parent, name)| parent.stat(&name));
+            match stat {
+                Ok(stat) if stat.st_mode & libc::S_IFMT == libc::S_IFREG => {
+                    let key = Key {
+                        size: stat.st_size as u64,
+                        mode: stat.st_mode as u32 & 0o7777,
+                        uid: stat.st_uid,
+                        gid: stat.st_gid,
+                    };
+                    let inode = Inode {
+                        dev: stat.st_dev as u64,
+                        ino: stat.st_ino as u64,
+                    };
+                    Some((key, inode, stat.st_nlink as u64, file))
+                }
+                Ok(_) => None,
+                Err(e) => {
+                    error!("Dedup Error -- Reading {:?}: {}", base.join(file.path()), e);
+                    None
+                }
+            }
+        })
+        .collect();
+
+    // Only files with the same key, that are not all one file already, could be linked
+    let mut groups: HashMap<Key, HashMap<Inode, Linked>> = HashMap::new();
+    for (key, inode, links, file) in stats {
+        groups
+            .entry(key)
+            .or_default()
+            .entry(inode)
+            .or_insert_with(|| (links, Vec::new()))
+            .1
+            .push(file);
+    }
+
+    groups
+        .into_par_iter()
+        .filter(|(_, inodes)| inodes.len() > 1)
+        .map(|(key, inodes)| {
+            // Every file of an inode has the same contents, so only one of them is hashed
+            let mut hashes: HashMap<Vec<u8>, Vec<Linked>> = HashMap::new();
+            let hashed: Vec<(Option<Vec<u8>>, Linked)> = inodes
+                .into_par_iter()
+                .map(|(_, copy)| (file_ops::hash_file_secure(copy.1[0], dest), copy))
+                .collect();
+            for (hash, copy) in hashed {
+                if let Some(hash) = hash {
+                    hashes.entry(hash).or_default().push(copy);
+                }
+            }
+
+            let mut linked = 0;
+            let mut freed = 0;
+            for mut copies in hashes
+                .into_iter()
+                .map(|(_, copies)| copies)
+                .filter(|copies| copies.len() > 1)
+            {
+                // Keep the copy with the most links, and of those, the first by path
+                for (_, files) in copies.iter_mut() {
+                    files.sort_unstable_by_key(|file| file.path());
+                }
+                copies.sort_unstable_by(|(_, a), (_, b)| {
+                    b.len().cmp(&a.len()).then(a[0].path().cmp(b[0].path()))
+                });
+                let target = copies[0].1[0].path();
+
+                for (links, files) in &copies[1..] {
+                    let mut unlinked = 0;
+                    for file in files {
+                        let from = base.join(file.path());
+                        match dirfd::link_over(base, target, file.path()) {
+                            Ok(_) => {
+                                info!("Linking {:?} to {:?}", from, base.join(target));
+                                linked += 1;
+                                unlinked += 1;
+                            }
+                            Err(e) => error!(
+                                "Dedup Error -- Linking {:?} to {:?}: {}",
+                                from,
+                                base.join(target),
+                                e
+                            ),
+                        }
+                    }
+                    // A copy only frees its space once none of its links are left
+                    if unlinked == *links {
+                        freed += key.size;
+                    }
+                }
+            }
+            (linked, freed)
+        })
+        .reduce(|| (0, 0), |a, b| (a.0 + b.0, a.1 + b.1))
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_dedup {
+    use super::*;
+    use crate::lumins::file_ops;
+    use std::fs;
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    #[test]
+    fn links_identical_files() {
+        const TEST_DIR: &str = "test_dedup_links_identical_files";
+        let path = |path: &str| [TEST_DIR, path].join("/");
+
+        fs::create_dir_all(path("sub")).unwrap();
+        for file in &["a", "b", "sub/c", "private"] {
+            fs::write(path(file), b"same").unwrap();
+        }
+        fs::hard_link(path("sub/c"), path("sub/linked")).unwrap();
+        fs::write(path("other"), b"diff").unwrap();
+        fs::write(path("empty"), b"").unwrap();
+        fs::write(path("empty2"), b"").unwrap();
+        fs::set_permissions(path("private"), fs::Permissions::from_mode(0o600)).unwrap();
+
+        let file_sets = file_ops::get_all_files(TEST_DIR).unwrap();
+        // sub/c already has two links, so the others are linked to it
+        assert_eq!(dedup(TEST_DIR, file_sets.files()), (2, 8));
+
+        let inode = |file: &str| fs::metadata(path(file)).unwrap().ino();
+        assert_eq!(inode("a"), inode("sub/c"));
+        assert_eq!(inode("b"), inode("sub/c"));
+        assert_eq!(inode("sub/linked"), inode("sub/c"));
+        assert_ne!(inode("private"), inode("sub/c"));
+        assert_ne!(inode("other"), inode("sub/c"));
+        assert_ne!(inode("empty"), inode("empty2"));
+        assert_eq!(fs::read(path("a")).unwrap(), b"same");
+
+        // Linked files are not hashed or linked again
+        assert_eq!(dedup(TEST_DIR, file_sets.files()), (0, 0));
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+}
@@ -0,0 +1,311 @@
+//! Packages the differences between two trees into an archive, to bring offline copies up to date
+//!
+//! `export_diff` compares a source with a copy of it, such as a mirror of what an offline system
+//! holds, and writes only what the copy is missing into a tar archive. What has to be deleted
+//! from the copy is listed in the archive too, as its first entry, so that extracting the archive
+//! into the offline copy with `lms cp` leaves it the same as the source.
+
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hashbrown::HashSet;
+use log::info;
+use rayon::prelude::*;
+
+use crate::lumins::archive::{self, ArchivedEntry, ArchivedKind, EntryMetadata, TarWriter};
+use crate::lumins::file_ops::{self, Dir, File, FileOps, FileSets, Symlink};
+use crate::lumins::parse::Options;
+
+/// Name of the list of paths to delete, at the root of a differential archive
+pub const DELETIONS_NAME: &str = ".lms-deletions";
+
+/// Writes everything in `src` that differs from `dest` into a new tar archive at `output`,
+/// along with a list of what to delete from `dest`
+///
+/// Files are compared like a sync compares them, by size and then by hash, with
+/// `Options::secure` by a cryptographic hash. Directories are archived if they are not in `dest`,
+/// or if anything in them is archived, so that the archive can be extracted on its own. The
+/// paths of `dest` that are not in `src` as the same kind of entry are listed, NUL-separated,
+/// in the file `DELETIONS_NAME`, which is extracted as deletions, see `unpack::unpack`.
+///
+/// # Arguments
+/// * `src`: directory whose contents are exported
+/// * `dest`: directory that the archive brings up to date with `src`
+/// * `output`: path of the archive, see `archive::compression`
+/// * `options`: Options of the run
+///
+/// # Errors
+/// This function will return an error if either directory could not be listed completely, or
+/// if the archive could not be written
+pub fn export_diff(
+    src: &str,
+    dest: &str,
+    output: &str,
+    options: &Options,
+) -> Result<(), io::Error> {
+    let mut src_file_sets = file_ops::get_files_within(src, options)?;
+    src_file_sets.remove_file(Path::new(DELETIONS_NAME));
+    let dest_file_sets = file_ops::get_files_within(dest, options)?;
+    // What could not be listed would be missing from the archive, or deleted by it
+    for (dir, file_sets) in &[(src, &src_file_sets), (dest, &dest_file_sets)] {
+        if !file_sets.is_complete() {
+            return Err(io::Error::other(format!(
+                "Diff Error -- {} could not be listed completely",
+                dir
+            )));
+        }
+    }
+
+    let changes = changes(&src_file_sets, &dest_file_sets, src, dest, options);
+    let deletions = deletions(&src_file_sets, &dest_file_sets);
+    info!(
+        "Exporting {} changed entries and {} deletions to {}",
+        changes.files().len() + changes.dirs().len() + changes.symlinks().len(),
+        deletions.len(),
+        output
+    );
+
+    archive::write_archive(output, options, |tar| {
+        write_deletions(&deletions, tar)?;
+        archive::write_file_sets(src, &changes, tar)
+    })
+}
+
+/// Gets the files, directories, and symlinks of `src` that are missing from `dest` or differ
+/// there, along with the directories that they are in
+fn changes(
+    src_file_sets: &FileSets,
+    dest_file_sets: &FileSets,
+    src: &str,
+    dest: &str,
+    options: &Options,
+) -> FileSets {
+    let (src_files, dest_files) = (src_file_sets.files(), dest_file_sets.files());
+    let files: HashSet<File> = src_files
+        .par_difference(dest_files)
+        .chain(
+            src_files
+                .par_intersection(dest_files)
+                .filter(|file| file_ops::file_differs(*file, src, dest, options)),
+        )
+        .cloned()
+        .collect();
+    let symlinks: HashSet<Symlink> = src_file_sets
+        .symlinks()
+        .par_difference(dest_file_sets.symlinks())
+        .cloned()
+        .collect();
+
+    // Parents are listed too, so that their metadata is restored along with them
+    let dirs: HashSet<Dir> = {
+        let parents: HashSet<&Path> = (files.iter().map(|file| file.path()))
+            .chain(symlinks.iter().map(|symlink| symlink.path()))
+            .flat_map(|path| path.ancestors().skip(1))
+            .collect();
+        src_file_sets
+            .dirs()
+            .par_iter()
+            .filter(|dir| {
+                !dest_file_sets.dirs().contains(*dir) || parents.contains(dir.path().as_path())
+            })
+            .cloned()
+            .collect()
+    };
+
+    FileSets::with(files, dirs, symlinks)
+}
+
+/// Gets the paths of `dest` that are not in `src` as the same kind of entry, sorted
+fn deletions<'a>(src_file_sets: &FileSets, dest_file_sets: &'a FileSets) -> Vec<&'a PathBuf> {
+    fn paths<S: FileOps>(entries: &HashSet<S>) -> HashSet<&PathBuf> {
+        entries.iter().map(|entry| entry.path()).collect()
+    }
+
+    let (src_files, src_symlinks) = (
+        paths(src_file_sets.files()),
+        paths(src_file_sets.symlinks()),
+    );
+    let src_specials = paths(src_file_sets.specials());
+    let mut deletions: Vec<&PathBuf> = (dest_file_sets.files().iter())
+        .map(|file| file.path())
+        .filter(|path| !src_files.contains(path))
+        .chain(
+            (dest_file_sets.symlinks().iter())
+                .map(|symlink| symlink.path())
+                .filter(|path| !src_symlinks.contains(path)),
+        )
+        .chain(
+            (dest_file_sets.specials().iter())
+                .map(|special| special.path())
+                .filter(|path| !src_specials.contains(path)),
+        )
+        .chain(
+            (dest_file_sets.dirs().iter())
+                .filter(|dir| !src_file_sets.dirs().contains(*dir))
+                .map(|dir| dir.path()),
+        )
+        .collect();
+    deletions.sort_unstable();
+    deletions
+}
+
+/// Appends the list of `deletions` to `tar`, as the file `DELETIONS_NAME`
+fn write_deletions(
+    deletions: &[&PathBuf],
+    tar: &mut TarWriter<&mut dyn Write>,
+) -> Result<(), io::Error> {
+    let mut list = Vec::new();
+    for path in deletions {
+        list.extend(archive::archive_path(path)?);
+        list.push(0);
+    }
+    let mtime = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let metadata = EntryMetadata {
+        mode: 0o644,
+        mtime,
+        ..EntryMetadata::default()
+    };
+    tar.append_file(
+        Path::new(DELETIONS_NAME),
+        metadata,
+        list.len() as u64,
+        &mut &list[..],
+    )
+}
+
+/// Checks whether `entry` is the list of deletions of a differential archive
+pub fn is_deletions(entry: &ArchivedEntry) -> bool {
+    entry.kind == ArchivedKind::File && entry.path == Path::new(DELETIONS_NAME)
+}
+
+/// Reads the paths of a list of deletions, see `export_diff`
+///
+/// # Errors
+/// This function will return an error if a path is not valid on this platform
+pub fn read_deletions(list: &[u8]) -> Result<Vec<PathBuf>, io::Error> {
+    list.split(|&byte| byte == 0)
+        .filter(|path| !path.is_empty())
+        .map(|path| archive::path_from_archive(path.to_vec()))
+        .collect()
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_export_diff {
+    use super::*;
+    use crate::lumins::{archive, core};
+    use std::fs;
+    use std::process::Command;
+
+    #[test]
+    fn brings_copy_up_to_date() {
+        const TEST_SRC: &str = "test_delta_brings_copy_up_to_date";
+        const TEST_MIRROR: &str = "test_delta_brings_copy_up_to_date_mirror";
+        const TEST_OFFLINE: &str = "test_delta_brings_copy_up_to_date_offline";
+        const TEST_ARCHIVE: &str = "test_delta_brings_copy_up_to_date.tar";
+
+        fs::create_dir_all([TEST_SRC, "kept"].join("/")).unwrap();
+        fs::create_dir_all([TEST_SRC, "gone/deeper"].join("/")).unwrap();
+        fs::write([TEST_SRC, "kept/same"].join("/"), b"same").unwrap();
+        fs::write([TEST_SRC, "kept/changed"].join("/"), b"old").unwrap();
+        fs::write([TEST_SRC, "gone/deeper/file"].join("/"), b"gone").unwrap();
+        fs::write([TEST_SRC, "becomes_dir"].join("/"), b"file").unwrap();
+        for copy in &[TEST_MIRROR, TEST_OFFLINE] {
+            core::copy(TEST_SRC, copy, &Options::default()).unwrap();
+        }
+
+        fs::write([TEST_SRC, "kept/changed"].join("/"), b"new").unwrap();
+        fs::write([TEST_SRC, "kept/added"].join("/"), b"added").unwrap();
+        fs::remove_dir_all([TEST_SRC, "gone"].join("/")).unwrap();
+        fs::remove_file([TEST_SRC, "becomes_dir"].join("/")).unwrap();
+        fs::create_dir([TEST_SRC, "becomes_dir"].join("/")).unwrap();
+        fs::write([TEST_SRC, "becomes_dir/file"].join("/"), b"inside").unwrap();
+
+        export_diff(TEST_SRC, TEST_MIRROR, TEST_ARCHIVE, &Options::default()).unwrap();
+
+        // Only what changed is archived, after the list of deletions
+        let mut tar = archive::TarReader::new(fs::File::open(TEST_ARCHIVE).unwrap());
+        let mut paths = Vec::new();
+        let mut list = Vec::new();
+        while let Some(entry) = tar.next_entry().unwrap() {
+            if is_deletions(&entry) {
+                tar.read_contents(&mut list).unwrap();
+            } else {
+                tar.read_contents(&mut io::sink()).unwrap();
+            }
+            paths.push(entry.path);
+        }
+        let expected: Vec<PathBuf> = [
+            DELETIONS_NAME,
+            "becomes_dir",
+            "becomes_dir/file",
+            "kept",
+            "kept/added",
+            "kept/changed",
+        ]
+        .iter()
+        .map(PathBuf::from)
+        .collect();
+        assert_eq!(paths, expected);
+        assert_eq!(
+            read_deletions(&list).unwrap(),
+            vec![
+                PathBuf::from("becomes_dir"),
+                PathBuf::from("gone"),
+                PathBuf::from("gone/deeper"),
+                PathBuf::from("gone/deeper/file")
+            ]
+        );
+
+        // Extracting the archive applies the deletions too
+        core::copy(TEST_ARCHIVE, TEST_OFFLINE, &Options::default()).unwrap();
+        let diff = Command::new("diff")
+            .args(["-r", TEST_SRC, TEST_OFFLINE])
+            .output()
+            .unwrap();
+        assert!(diff.status.success());
+        assert!(fs::metadata([TEST_OFFLINE, DELETIONS_NAME].join("/")).is_err());
+
+        for dir in &[TEST_SRC, TEST_MIRROR, TEST_OFFLINE] {
+            fs::remove_dir_all(dir).unwrap();
+        }
+        fs::remove_file(TEST_ARCHIVE).unwrap();
+    }
+
+    #[test]
+    fn refuses_escaping_deletions() {
+        const TEST_DIR: &str = "test_delta_refuses_escaping_deletions";
+        const TEST_ARCHIVE: &str = "test_delta_refuses_escaping_deletions.tar";
+
+        fs::create_dir_all([TEST_DIR, "dest"].join("/")).unwrap();
+        fs::write([TEST_DIR, "outside"].join("/"), b"outside").unwrap();
+
+        let list = b"../outside\0";
+        let mut tar = TarWriter::new(fs::File::create(TEST_ARCHIVE).unwrap(), None);
+        tar.append_file(
+            Path::new(DELETIONS_NAME),
+            EntryMetadata::default(),
+            list.len() as u64,
+            &mut &list[..],
+        )
+        .unwrap();
+        tar.finish().unwrap();
+
+        let dest = [TEST_DIR, "dest"].join("/");
+        assert!(core::copy(TEST_ARCHIVE, &dest, &Options::default()).is_err());
+        assert_eq!(
+            fs::read([TEST_DIR, "outside"].join("/")).unwrap(),
+            b"outside"
+        );
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_file(TEST_ARCHIVE).unwrap();
+    }
+}
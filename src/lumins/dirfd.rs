@@ -0,0 +1,1827 @@
+//! Operates on files relative to open directory handles
+//!
+//! On Unix-based platforms, every path below a base directory is resolved one component at a
+//! time with `openat` and `O_NOFOLLOW`, and files are then created, opened, inspected, and
+//! removed with `openat`, `fstatat`, `unlinkat`, and friends relative to their parent directory.
+//! This way, no component can be swapped for a symlink between being checked and being used,
+//! which matters when synchronizing directories that are writable by other users.
+//! The base directories themselves are trusted, and may be symlinks.
+//!
+//! On other platforms, the same operations are done on joined paths.
+
+use std::error::Error;
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+use clap::ValueEnum;
+
+use crate::lumins::platform;
+
+/// The type of a directory entry
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub enum EntryKind {
+    Dir,
+    /// A regular file of the given size
+    File(u64),
+    /// A symlink with the given target
+    Symlink(PathBuf),
+    /// A socket, FIFO, or device node, with its type and permission bits, and its device number
+    Special {
+        mode: u32,
+        rdev: u64,
+    },
+}
+
+/// When to make copy-on-write clones of files instead of copying their contents
+#[derive(Eq, PartialEq, Debug, Clone, Copy, ValueEnum)]
+pub enum Reflink {
+    /// Clone where the filesystem supports it, and copy everywhere else
+    Auto,
+    /// Always clone, and fail where the filesystem does not support it
+    Always,
+    /// Always copy the contents
+    Never,
+}
+
+/// How the contents of files are read and written where they go through a buffer
+#[derive(Eq, PartialEq, Debug, Clone, Copy, Default)]
+pub struct IoStrategy {
+    /// Whether to copy the contents of large files past the page cache
+    pub direct_io: bool,
+    /// Size of the buffer to copy through, instead of one sized to each file
+    pub buffer_size: Option<usize>,
+    /// Whether to flush each copied file, and the directory it is in, to disk before the copy
+    /// succeeds
+    pub fsync: bool,
+    /// Whether to fail copies of files that change while they are copied, see `Changed`
+    pub check_changes: bool,
+    /// Whether to only append the tails of files that grew since they were copied, see
+    /// `append_contents`
+    pub append: bool,
+    /// Whether to leave copies with the permissions they are created with, for destinations
+    /// without permissions, see `capabilities`
+    pub skip_permissions: bool,
+}
+
+/// An error for a file that changed while it was copied, so that its copy may be torn
+#[derive(Debug)]
+pub struct Changed;
+
+impl fmt::Display for Changed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "the file changed while it was copied")
+    }
+}
+
+impl Error for Changed {}
+
+/// Checks whether `error` is a `Changed` error
+pub fn is_changed(error: &io::Error) -> bool {
+    error.get_ref().is_some_and(|e| e.is::<Changed>())
+}
+
+/// Fails with `Changed` if `file` no longer has the size and modification time of `before`
+fn check_unchanged(file: &fs::File, before: Option<&fs::Metadata>) -> Result<(), io::Error> {
+    if let Some(before) = before {
+        let after = file.metadata()?;
+        if after.len() != before.len() || after.modified().ok() != before.modified().ok() {
+            return Err(io::Error::other(Changed));
+        }
+    }
+    Ok(())
+}
+
+/// Largest buffer that files are copied through on the stack
+const STACK_BUFFER_SIZE: usize = 1 << 14;
+/// Smallest and largest buffers that files are copied through, when they are sized to the file
+const MIN_BUFFER_SIZE: usize = 1 << 12;
+const MAX_BUFFER_SIZE: usize = 1 << 22;
+
+impl IoStrategy {
+    /// Gets the size of the buffer to copy a file of `len` bytes through
+    ///
+    /// Unless a size is given, small files are copied through a buffer as small as they are,
+    /// which fits on the stack, and large files through a buffer of a few MiB, so that they are
+    /// read and written with few calls. Buffers are never empty, which nothing could be copied
+    /// through, so a size of 0 is taken as 1.
+    pub fn buffer_size(&self, len: u64) -> usize {
+        match self.buffer_size {
+            Some(buffer_size) => buffer_size.max(1),
+            None => (len.min(MAX_BUFFER_SIZE as u64) as usize)
+                .next_power_of_two()
+                .max(MIN_BUFFER_SIZE),
+        }
+    }
+}
+
+/// The name of a directory entry, along with its type, or why its type could not be read
+pub type Entry = (OsString, Result<EntryKind, io::Error>);
+
+/// An open directory, which other files can be accessed relative to
+#[derive(Debug)]
+pub struct DirHandle {
+    #[cfg(target_family = "unix")]
+    fd: std::os::unix::io::RawFd,
+    #[cfg(not(target_family = "unix"))]
+    path: PathBuf,
+}
+
+#[cfg(target_family = "unix")]
+impl DirHandle {
+    /// Opens the directory at `path`, following symlinks
+    pub fn open(path: &Path) -> Result<Self, io::Error> {
+        let path = c_string(path.as_os_str())?;
+        // Safe since `path` is a valid null-terminated string
+        let fd = cvt(unsafe {
+            libc::open(
+                path.as_ptr(),
+                libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC,
+            )
+        })?;
+        Ok(DirHandle { fd })
+    }
+
+    /// Opens the subdirectory `name`, failing if it is a symlink
+    pub fn open_dir(&self, name: &OsStr) -> Result<Self, io::Error> {
+        let name = c_string(name)?;
+        // Safe since `name` is a valid null-terminated string
+        let fd = cvt(unsafe {
+            libc::openat(
+                self.fd,
+                name.as_ptr(),
+                libc::O_RDONLY | libc::O_DIRECTORY | libc::O_NOFOLLOW | libc::O_CLOEXEC,
+            )
+        })?;
+        Ok(DirHandle { fd })
+    }
+
+    /// Creates the subdirectory `name`
+    pub fn create_dir(&self, name: &OsStr) -> Result<(), io::Error> {
+        let name = c_string(name)?;
+        // Safe since `name` is a valid null-terminated string
+        cvt(unsafe { libc::mkdirat(self.fd, name.as_ptr(), 0o777) }).map(|_| ())
+    }
+
+    /// Flushes the entries of this directory to disk
+    pub fn sync(&self) -> Result<(), io::Error> {
+        // Safe since `fd` is owned by this handle
+        cvt(unsafe { libc::fsync(self.fd) }).map(|_| ())
+    }
+
+    /// Opens the file `name` for reading, failing if it is a symlink
+    pub fn open_file(&self, name: &OsStr) -> Result<fs::File, io::Error> {
+        use std::os::unix::io::FromRawFd;
+
+        let name = c_string(name)?;
+        // Safe since `name` is a valid null-terminated string
+        let fd = cvt(unsafe {
+            libc::openat(
+                self.fd,
+                name.as_ptr(),
+                libc::O_RDONLY | libc::O_NOFOLLOW | libc::O_CLOEXEC,
+            )
+        })?;
+        // Safe since `fd` was just opened, and is owned by nothing else
+        Ok(unsafe { fs::File::from_raw_fd(fd) })
+    }
+
+    /// Creates or truncates the file `name` for writing, failing if it is a symlink
+    ///
+    /// Newly created files get permissions `mode`, minus the umask
+    pub fn create_file(&self, name: &OsStr, mode: u32) -> Result<fs::File, io::Error> {
+        use std::os::unix::io::FromRawFd;
+
+        let name = c_string(name)?;
+        // Safe since `name` is a valid null-terminated string
+        let fd = cvt(unsafe {
+            libc::openat(
+                self.fd,
+                name.as_ptr(),
+                libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC | libc::O_NOFOLLOW | libc::O_CLOEXEC,
+                mode as libc::c_uint,
+            )
+        })?;
+        // Safe since `fd` was just opened, and is owned by nothing else
+        Ok(unsafe { fs::File::from_raw_fd(fd) })
+    }
+
+    /// Opens the existing file `name` for reading and writing, without truncating it, failing if
+    /// it is a symlink
+    pub fn update_file(&self, name: &OsStr) -> Result<fs::File, io::Error> {
+        use std::os::unix::io::FromRawFd;
+
+        let name = c_string(name)?;
+        // Safe since `name` is a valid null-terminated string
+        let fd = cvt(unsafe {
+            libc::openat(
+                self.fd,
+                name.as_ptr(),
+                libc::O_RDWR | libc::O_NOFOLLOW | libc::O_CLOEXEC,
+            )
+        })?;
+        // Safe since `fd` was just opened, and is owned by nothing else
+        Ok(unsafe { fs::File::from_raw_fd(fd) })
+    }
+
+    /// Replaces the file `name` with a copy-on-write clone of `src`, using `FICLONE`
+    ///
+    /// # Errors
+    /// This function will return an error if `name` could not be created, or if the filesystem
+    /// does not support cloning, or `src` is on a different filesystem
+    #[cfg(target_os = "linux")]
+    pub fn clone_file(&self, src: &fs::File, name: &OsStr, mode: u32) -> Result<(), io::Error> {
+        use std::os::unix::io::AsRawFd;
+
+        const FICLONE: libc::c_ulong = 0x4004_9409;
+
+        let dest = self.create_file(name, mode)?;
+        // Safe since both descriptors are open for the duration of the call
+        cvt(unsafe { libc::ioctl(dest.as_raw_fd(), FICLONE as _, src.as_raw_fd()) }).map(|_| ())
+    }
+
+    /// Replaces the file `name` with a copy-on-write clone of `src`, using `fclonefileat`
+    ///
+    /// # Errors
+    /// This function will return an error if `name` could not be replaced, or if the filesystem
+    /// does not support cloning, or `src` is on a different filesystem
+    #[cfg(target_os = "macos")]
+    pub fn clone_file(&self, src: &fs::File, name: &OsStr, _mode: u32) -> Result<(), io::Error> {
+        use std::os::unix::io::AsRawFd;
+
+        extern "C" {
+            fn fclonefileat(
+                src_fd: libc::c_int,
+                dst_dirfd: libc::c_int,
+                dst: *const libc::c_char,
+                flags: u32,
+            ) -> libc::c_int;
+        }
+
+        // Clones can only be made to paths that do not exist yet
+        match self.remove(name, false) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+            result => result?,
+        }
+
+        let c_name = c_string(name)?;
+        // Safe since `src` is open for the duration of the call and `c_name` is a valid
+        // null-terminated string
+        cvt(unsafe { fclonefileat(src.as_raw_fd(), self.fd, c_name.as_ptr(), 0) }).map(|_| ())
+    }
+
+    /// Clones are not supported on this platform, so this always returns an error
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    pub fn clone_file(&self, _src: &fs::File, _name: &OsStr, _mode: u32) -> Result<(), io::Error> {
+        Err(io::Error::other(
+            "reflinks are not supported on this platform",
+        ))
+    }
+
+    /// Renames the entry `name` to `new_name` inside `new_dir`, replacing it if it exists
+    pub fn rename(
+        &self,
+        name: &OsStr,
+        new_dir: &DirHandle,
+        new_name: &OsStr,
+    ) -> Result<(), io::Error> {
+        let name = c_string(name)?;
+        let new_name = c_string(new_name)?;
+        // Safe since both are valid null-terminated strings
+        cvt(unsafe { libc::renameat(self.fd, name.as_ptr(), new_dir.fd, new_name.as_ptr()) })
+            .map(|_| ())
+    }
+
+    /// Creates a hard link `new_name` inside `new_dir` to the entry `name`, without following
+    /// symlinks
+    pub fn hard_link(
+        &self,
+        name: &OsStr,
+        new_dir: &DirHandle,
+        new_name: &OsStr,
+    ) -> Result<(), io::Error> {
+        let name = c_string(name)?;
+        let new_name = c_string(new_name)?;
+        // Safe since both are valid null-terminated strings
+        cvt(unsafe { libc::linkat(self.fd, name.as_ptr(), new_dir.fd, new_name.as_ptr(), 0) })
+            .map(|_| ())
+    }
+
+    /// Creates a symlink `name` that points to `target`
+    pub fn symlink(&self, target: &Path, name: &OsStr) -> Result<(), io::Error> {
+        let target = c_string(target.as_os_str())?;
+        let name = c_string(name)?;
+        // Safe since both are valid null-terminated strings
+        cvt(unsafe { libc::symlinkat(target.as_ptr(), self.fd, name.as_ptr()) }).map(|_| ())
+    }
+
+    /// Creates a socket, FIFO, or device node `name` of the type and permissions in `mode`, with
+    /// the device number `rdev`
+    #[cfg(not(target_os = "macos"))]
+    pub fn mknod(&self, name: &OsStr, mode: u32, rdev: u64) -> Result<(), io::Error> {
+        let name = c_string(name)?;
+        // Safe since `name` is a valid null-terminated string
+        cvt(unsafe {
+            libc::mknodat(
+                self.fd,
+                name.as_ptr(),
+                mode as libc::mode_t,
+                rdev as libc::dev_t,
+            )
+        })
+        .map(|_| ())
+    }
+
+    /// Removes the file or symlink `name`, or the empty directory `name` if `dir` is set
+    pub fn remove(&self, name: &OsStr, dir: bool) -> Result<(), io::Error> {
+        let name = c_string(name)?;
+        let flags = if dir { libc::AT_REMOVEDIR } else { 0 };
+        // Safe since `name` is a valid null-terminated string
+        cvt(unsafe { libc::unlinkat(self.fd, name.as_ptr(), flags) }).map(|_| ())
+    }
+
+    /// Gets the type of the entry `name`, without following symlinks
+    #[allow(clippy::unnecessary_cast)]
+    pub fn kind(&self, name: &OsStr) -> Result<EntryKind, io::Error> {
+        let stat = self.stat(name)?;
+
+        match stat.st_mode & libc::S_IFMT {
+            libc::S_IFDIR => Ok(EntryKind::Dir),
+            libc::S_IFREG => Ok(EntryKind::File(stat.st_size as u64)),
+            libc::S_IFLNK => self.read_link(name).map(EntryKind::Symlink),
+            libc::S_IFIFO | libc::S_IFSOCK | libc::S_IFCHR | libc::S_IFBLK => {
+                Ok(EntryKind::Special {
+                    mode: stat.st_mode as u32,
+                    rdev: stat.st_rdev as u64,
+                })
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "not a file, directory, or symlink",
+            )),
+        }
+    }
+
+    /// Gets the status of the entry `name`, without following symlinks
+    pub fn stat(&self, name: &OsStr) -> Result<libc::stat, io::Error> {
+        let name = c_string(name)?;
+        // This is safe to zero, since `stat` is a plain C struct
+        let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+        // Safe since `name` is a valid null-terminated string and `stat` is valid for writes
+        cvt(unsafe {
+            libc::fstatat(self.fd, name.as_ptr(), &mut stat, libc::AT_SYMLINK_NOFOLLOW)
+        })?;
+        Ok(stat)
+    }
+
+    /// Gets the device of the filesystem that the entry `name` is on, without following symlinks
+    #[allow(clippy::unnecessary_cast)]
+    pub fn device(&self, name: &OsStr) -> Result<u64, io::Error> {
+        self.stat(name).map(|stat| stat.st_dev as u64)
+    }
+
+    /// Changes the owner of the entry `name`, without following symlinks
+    pub fn chown(&self, name: &OsStr, uid: u32, gid: u32) -> Result<(), io::Error> {
+        let name = c_string(name)?;
+        // Safe since `name` is a valid null-terminated string
+        cvt(unsafe { libc::fchownat(self.fd, name.as_ptr(), uid, gid, libc::AT_SYMLINK_NOFOLLOW) })
+            .map(|_| ())
+    }
+
+    /// Reads the target of the symlink `name`
+    fn read_link(&self, name: &OsStr) -> Result<PathBuf, io::Error> {
+        use std::os::unix::ffi::OsStringExt;
+
+        let name = c_string(name)?;
+        let mut buffer = vec![0u8; 256];
+
+        loop {
+            // Safe since `name` is a valid null-terminated string, and `buffer` is valid for
+            // writes of its length
+            let len = unsafe {
+                libc::readlinkat(
+                    self.fd,
+                    name.as_ptr(),
+                    buffer.as_mut_ptr() as *mut libc::c_char,
+                    buffer.len(),
+                )
+            };
+            if len < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            // The target may have been truncated if it filled the whole buffer
+            let len = len as usize;
+            if len < buffer.len() {
+                buffer.truncate(len);
+                return Ok(PathBuf::from(OsString::from_vec(buffer)));
+            }
+            buffer.resize(buffer.len() * 2, 0);
+        }
+    }
+
+    /// Lists the names of all entries in this directory, along with their types
+    pub fn entries(&self) -> Result<Vec<Entry>, io::Error> {
+        use std::os::unix::ffi::OsStrExt;
+
+        // `fdopendir` takes ownership of the descriptor, so give it a copy
+        // Safe since `self.fd` is an open descriptor
+        let fd = cvt(unsafe { libc::fcntl(self.fd, libc::F_DUPFD_CLOEXEC, 0) })?;
+        // Safe since `fd` is an open descriptor owned by nothing else
+        let dir = unsafe { libc::fdopendir(fd) };
+        if dir.is_null() {
+            let error = io::Error::last_os_error();
+            // Safe since `fd` was not taken over by `fdopendir`
+            unsafe { libc::close(fd) };
+            return Err(error);
+        }
+
+        let mut names = Vec::new();
+        loop {
+            // Safe since `dir` is an open directory stream
+            let entry = unsafe { libc::readdir(dir) };
+            if entry.is_null() {
+                break;
+            }
+            // Safe since `d_name` is a null-terminated string that lives until the next readdir
+            let name = unsafe { std::ffi::CStr::from_ptr((*entry).d_name.as_ptr()) };
+            let name = OsStr::from_bytes(name.to_bytes());
+            if name != "." && name != ".." {
+                names.push(name.to_os_string());
+            }
+        }
+        // Safe since `dir` is an open directory stream that is not used again
+        unsafe { libc::closedir(dir) };
+
+        Ok(names
+            .into_iter()
+            .map(|name| {
+                let kind = self.kind(&name);
+                (name, kind)
+            })
+            .collect())
+    }
+}
+
+#[cfg(target_family = "unix")]
+impl Drop for DirHandle {
+    fn drop(&mut self) {
+        // Safe since `fd` is owned by this handle
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+#[cfg(not(target_family = "unix"))]
+impl DirHandle {
+    /// Opens the directory at `path`, following symlinks
+    pub fn open(path: &Path) -> Result<Self, io::Error> {
+        let path = platform::long_path(path);
+        fs::read_dir(&path)?;
+        Ok(DirHandle { path })
+    }
+
+    /// Opens the subdirectory `name`, failing if it is a symlink
+    pub fn open_dir(&self, name: &OsStr) -> Result<Self, io::Error> {
+        let path = self.path.join(name);
+        if !fs::symlink_metadata(&path)?.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "not a directory",
+            ));
+        }
+        Ok(DirHandle { path })
+    }
+
+    /// Creates the subdirectory `name`
+    pub fn create_dir(&self, name: &OsStr) -> Result<(), io::Error> {
+        fs::create_dir(self.path.join(name))
+    }
+
+    /// Does nothing, since directories can not be flushed here, see `platform::sync_dir`
+    pub fn sync(&self) -> Result<(), io::Error> {
+        Ok(())
+    }
+
+    /// Opens the file `name` for reading
+    pub fn open_file(&self, name: &OsStr) -> Result<fs::File, io::Error> {
+        fs::File::open(self.path.join(name))
+    }
+
+    /// Creates or truncates the file `name` for writing
+    pub fn create_file(&self, name: &OsStr, _mode: u32) -> Result<fs::File, io::Error> {
+        fs::File::create(self.path.join(name))
+    }
+
+    /// Opens the existing file `name` for reading and writing, without truncating it
+    pub fn update_file(&self, name: &OsStr) -> Result<fs::File, io::Error> {
+        fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(self.path.join(name))
+    }
+
+    /// Clones are not supported on this platform, so this always returns an error
+    pub fn clone_file(&self, _src: &fs::File, _name: &OsStr, _mode: u32) -> Result<(), io::Error> {
+        Err(io::Error::other(
+            "reflinks are not supported on this platform",
+        ))
+    }
+
+    /// Renames the entry `name` to `new_name` inside `new_dir`, replacing it if it exists
+    pub fn rename(
+        &self,
+        name: &OsStr,
+        new_dir: &DirHandle,
+        new_name: &OsStr,
+    ) -> Result<(), io::Error> {
+        fs::rename(self.path.join(name), new_dir.path.join(new_name))
+    }
+
+    /// Removes the file or symlink `name`, or the empty directory `name` if `dir` is set
+    pub fn remove(&self, name: &OsStr, dir: bool) -> Result<(), io::Error> {
+        if dir {
+            fs::remove_dir(self.path.join(name))
+        } else {
+            fs::remove_file(self.path.join(name))
+        }
+    }
+
+    /// Gets the type of the entry `name`, without following symlinks
+    pub fn kind(&self, name: &OsStr) -> Result<EntryKind, io::Error> {
+        let path = self.path.join(name);
+        let metadata = fs::symlink_metadata(&path)?;
+
+        if metadata.is_dir() {
+            Ok(EntryKind::Dir)
+        } else if metadata.is_file() {
+            Ok(EntryKind::File(metadata.len()))
+        } else {
+            fs::read_link(&path).map(EntryKind::Symlink)
+        }
+    }
+
+    /// Gets the device of the filesystem that the entry `name` is on, which is not known here
+    pub fn device(&self, _name: &OsStr) -> Result<u64, io::Error> {
+        Err(io::Error::other(
+            "devices are not supported on this platform",
+        ))
+    }
+
+    /// Lists the names of all entries in this directory, along with their types
+    pub fn entries(&self) -> Result<Vec<Entry>, io::Error> {
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(&self.path)? {
+            let name = entry?.file_name();
+            let kind = self.kind(&name);
+            entries.push((name, kind));
+        }
+        Ok(entries)
+    }
+}
+
+/// Opens the parent directory of `relative` inside `base`, without following symlinks in any
+/// component of `relative`
+///
+/// # Arguments
+/// * `base`: base directory, which may be a symlink
+/// * `relative`: path relative to `base`, made up of only normal components
+/// * `create`: whether to create missing parent directories, including `base`
+///
+/// # Returns
+/// * Ok: The parent directory, and the last component of `relative`
+/// * Error: If `relative` is empty or not a plain relative path, or a parent directory could
+///   not be opened
+pub fn open_parent(
+    base: &Path,
+    relative: &Path,
+    create: bool,
+) -> Result<(DirHandle, OsString), io::Error> {
+    let mut names = Vec::new();
+    for component in relative.components() {
+        match component {
+            Component::Normal(name) => names.push(name),
+            Component::CurDir => {}
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{:?} is not a plain relative path", relative),
+                ))
+            }
+        }
+    }
+
+    let name = match names.pop() {
+        Some(name) => name.to_os_string(),
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "empty relative path",
+            ))
+        }
+    };
+
+    let mut dir = match DirHandle::open(base) {
+        // The base directory is trusted, so it can be created by path
+        Err(ref e) if create && e.kind() == io::ErrorKind::NotFound => {
+            fs::create_dir_all(base)?;
+            DirHandle::open(base)?
+        }
+        result => result?,
+    };
+    for component in names {
+        dir = match dir.open_dir(component) {
+            Err(ref e) if create && e.kind() == io::ErrorKind::NotFound => {
+                match dir.create_dir(component) {
+                    Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+                    result => result?,
+                }
+                dir.open_dir(component)?
+            }
+            result => result?,
+        };
+    }
+
+    Ok((dir, name))
+}
+
+/// Creates the directory `relative` inside `base`, along with any missing parents
+///
+/// Succeeds if the directory already exists
+pub fn create_dir_all(base: &Path, relative: &Path) -> Result<(), io::Error> {
+    let (parent, name) = open_parent(base, relative, true)?;
+    match parent.create_dir(&name) {
+        Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
+            // Make sure what already exists is a directory, and not a symlink to one
+            parent.open_dir(&name).map(|_| ())
+        }
+        result => result,
+    }
+}
+
+/// Removes the file or symlink `relative` inside `base`
+pub fn remove_file(base: &Path, relative: &Path) -> Result<(), io::Error> {
+    let (parent, name) = open_parent(base, relative, false)?;
+    parent.remove(&name, false)
+}
+
+/// Removes the empty directory `relative` inside `base`
+///
+/// If `relative` is empty, `base` itself is removed
+pub fn remove_dir(base: &Path, relative: &Path) -> Result<(), io::Error> {
+    if relative.as_os_str().is_empty() {
+        return fs::remove_dir(base);
+    }
+    let (parent, name) = open_parent(base, relative, false)?;
+    parent.remove(&name, true)
+}
+
+/// Renames `from` inside `base` to `to` inside `base`, replacing `to` if it exists
+///
+/// The parent directory of `to` must already exist
+pub fn rename(base: &Path, from: &Path, to: &Path) -> Result<(), io::Error> {
+    let (from_parent, from_name) = open_parent(base, from, false)?;
+    let (to_parent, to_name) = open_parent(base, to, false)?;
+    from_parent.rename(&from_name, &to_parent, &to_name)
+}
+
+/// Opens the file `relative` inside `base` for reading
+pub fn open_file(base: &Path, relative: &Path) -> Result<fs::File, io::Error> {
+    let (parent, name) = open_parent(base, relative, false)?;
+    parent.open_file(&name)
+}
+
+/// Copies the contents and permissions of the file `relative` inside `src` to the file
+/// `relative` inside `dest`, replacing it if it exists
+///
+/// Unless `inplace` is set, the copy is written to a temporary file next to the destination
+/// file, which is then renamed over it, so that the destination file is never seen half-written.
+/// The temporary file is removed if the copy fails.
+///
+/// If `inplace` is set and the destination file exists, only the blocks that differ from the
+/// source are written to it, unless `reflink` is `Reflink::Always`.
+///
+/// With `io.fsync`, the file and then the directory that it is in are flushed to disk, so that
+/// the copy is complete on disk once it succeeds. With `io.check_changes`, a copy of a file whose
+/// size or modification time changed while it was copied fails with `Changed`, and is only left
+/// in `dest` if it was written in place.
+///
+/// # Arguments
+/// * `src`: base directory of the file to copy from
+/// * `dest`: base directory of the file to copy to
+/// * `relative`: path of the file, relative to both `src` and `dest`
+/// * `reflink`: whether to make a copy-on-write clone instead of copying the contents
+/// * `inplace`: whether to overwrite the destination file directly
+/// * `io`: how to copy the contents where they go through a buffer
+///
+/// # Returns
+/// * Ok: The number of bytes copied, or written when only changed blocks are written
+/// * Error: If either file could not be opened, the copy failed, `reflink` is
+///   `Reflink::Always` and the file could not be cloned, or the file changed while it was copied
+pub fn copy_file(
+    src: &Path,
+    dest: &Path,
+    relative: &Path,
+    reflink: Reflink,
+    inplace: bool,
+    io: IoStrategy,
+) -> Result<u64, io::Error> {
+    copy_file_as(src, relative, dest, relative, reflink, inplace, io)
+}
+
+/// Copies the file `from` inside `src` to the file `to` inside `dest`, like `copy_file`, for
+/// files that are written under another name
+pub fn copy_file_as(
+    src: &Path,
+    from: &Path,
+    dest: &Path,
+    to: &Path,
+    reflink: Reflink,
+    inplace: bool,
+    io: IoStrategy,
+) -> Result<u64, io::Error> {
+    let mut src_file = open_file(src, from)?;
+    let (dest_parent, name) = open_parent(dest, to, false)?;
+    let before = if io.check_changes {
+        Some(src_file.metadata()?)
+    } else {
+        None
+    };
+
+    // Files that only grew since they were copied, such as logs, only have what they grew by
+    // appended to their copies
+    if io.append && reflink != Reflink::Always {
+        if let Ok(mut dest_file) = dest_parent.update_file(&name) {
+            if let Some(written) = append_contents(&mut src_file, &mut dest_file)? {
+                check_unchanged(&src_file, before.as_ref())?;
+                set_permissions(&dest_file, src_file.metadata()?.permissions(), io)?;
+                if io.fsync {
+                    dest_file.sync_all()?;
+                }
+                return Ok(written);
+            }
+            std::io::Seek::rewind(&mut src_file)?;
+        }
+    }
+
+    if inplace {
+        if reflink != Reflink::Always {
+            match dest_parent.update_file(&name) {
+                Ok(mut dest_file) => {
+                    let written = update_contents(&mut src_file, &mut dest_file)?;
+                    check_unchanged(&src_file, before.as_ref())?;
+                    set_permissions(&dest_file, src_file.metadata()?.permissions(), io)?;
+                    if io.fsync {
+                        dest_file.sync_all()?;
+                    }
+                    return Ok(written);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        let copied = copy_into(&mut src_file, &dest_parent, &name, reflink, io)?;
+        check_unchanged(&src_file, before.as_ref())?;
+        if io.fsync {
+            dest_parent.sync()?;
+        }
+        return Ok(copied);
+    }
+
+    let temp_name = temp_name();
+    // A copy of a file that changed while it was copied is never renamed into place
+    match copy_into(&mut src_file, &dest_parent, &temp_name, reflink, io).and_then(|copied| {
+        check_unchanged(&src_file, before.as_ref())?;
+        dest_parent
+            .rename(&temp_name, &dest_parent, &name)
+            .map(|_| copied)
+    }) {
+        // The file was flushed before it was renamed, so that its name never shows a partial file
+        Ok(copied) if io.fsync => dest_parent.sync().map(|_| copied),
+        Ok(copied) => Ok(copied),
+        Err(e) => {
+            dest_parent.remove(&temp_name, false).ok();
+            Err(e)
+        }
+    }
+}
+
+/// Sets the permissions of the copy `dest_file` to `permissions`, unless `io.skip_permissions` is
+/// set
+fn set_permissions(
+    dest_file: &fs::File,
+    permissions: fs::Permissions,
+    io: IoStrategy,
+) -> Result<(), io::Error> {
+    if io.skip_permissions {
+        return Ok(());
+    }
+    dest_file.set_permissions(permissions)
+}
+
+/// Gets a name for a temporary file that is unique among all running copies
+pub fn temp_name() -> OsString {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static NEXT_TEMP: AtomicUsize = AtomicUsize::new(0);
+
+    OsString::from(format!(
+        ".lms-{}-{}.tmp",
+        std::process::id(),
+        NEXT_TEMP.fetch_add(1, Ordering::Relaxed)
+    ))
+}
+
+/// Copies the contents and permissions of `src_file` to the file `name` inside `dest_parent`,
+/// replacing it if it exists
+///
+/// Files that are not cloned have their space reserved before they are copied, see
+/// `platform::preallocate`, so that a copy that does not fit fails before it is written. With
+/// `io.direct_io`, files of at least `DIRECT_IO_MIN_SIZE` bytes that are not cloned are copied
+/// past the page cache. With `io.fsync`, the file is flushed to disk once it is copied.
+///
+/// # Returns
+/// * Ok: The number of bytes copied
+/// * Error: If `name` could not be created, the copy failed, or `reflink` is
+///   `Reflink::Always` and the file could not be cloned
+fn copy_into(
+    src_file: &mut fs::File,
+    dest_parent: &DirHandle,
+    name: &OsStr,
+    reflink: Reflink,
+    io: IoStrategy,
+) -> Result<u64, io::Error> {
+    let metadata = src_file.metadata()?;
+    let permissions = metadata.permissions();
+
+    #[cfg(target_family = "unix")]
+    let mode = std::os::unix::fs::PermissionsExt::mode(&permissions);
+    #[cfg(not(target_family = "unix"))]
+    let mode = 0;
+
+    if reflink != Reflink::Never {
+        match dest_parent.clone_file(src_file, name, mode) {
+            Ok(_) => {
+                let dest_file = dest_parent.open_file(name)?;
+                set_permissions(&dest_file, permissions, io)?;
+                if io.fsync {
+                    dest_file.sync_all()?;
+                }
+                return Ok(metadata.len());
+            }
+            Err(e) if reflink == Reflink::Always => return Err(e),
+            // Fall back to copying the contents
+            Err(_) => {}
+        }
+    }
+
+    let mut dest_file = dest_parent.create_file(name, mode)?;
+    platform::preallocate(&dest_file, metadata.len())?;
+    let buffer_size = io.buffer_size(metadata.len());
+    let copied = if io.direct_io && metadata.len() >= DIRECT_IO_MIN_SIZE {
+        copy_direct(src_file, &mut dest_file, buffer_size)?
+    } else if reflink == Reflink::Never {
+        copy_contents(src_file, &mut dest_file, buffer_size)?
+    } else {
+        // This may still share data between the files, where the filesystem supports it
+        copy_offloaded(src_file, &mut dest_file, buffer_size)?
+    };
+    set_permissions(&dest_file, permissions, io)?;
+    if io.fsync {
+        dest_file.sync_all()?;
+    }
+
+    Ok(copied)
+}
+
+/// Copies all data from `src` to `dest` through a buffer of `buffer_size` bytes, so that no data
+/// is shared between them even on filesystems that could share it
+///
+/// Buffers of up to `STACK_BUFFER_SIZE` bytes are kept on the stack.
+///
+/// # Returns
+/// * Ok: The number of bytes copied
+/// * Error: If reading or writing failed
+fn copy_contents(
+    src: &mut fs::File,
+    dest: &mut fs::File,
+    buffer_size: usize,
+) -> Result<u64, io::Error> {
+    if buffer_size <= STACK_BUFFER_SIZE {
+        copy_through(src, dest, &mut [0; STACK_BUFFER_SIZE][..buffer_size])
+    } else {
+        copy_through(src, dest, &mut vec![0; buffer_size])
+    }
+}
+
+/// Copies all data from `src` to `dest` through `buffer`
+fn copy_through(
+    src: &mut fs::File,
+    dest: &mut fs::File,
+    buffer: &mut [u8],
+) -> Result<u64, io::Error> {
+    use std::io::{Read, Write};
+
+    let mut copied = 0;
+
+    loop {
+        let len = match src.read(buffer) {
+            Ok(0) => return Ok(copied),
+            Ok(len) => len,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        dest.write_all(&buffer[..len])?;
+        copied += len as u64;
+    }
+}
+
+/// The size from which files are copied past the page cache with `--direct-io`, since smaller
+/// files are copied faster through it, and do not take enough of it to matter
+const DIRECT_IO_MIN_SIZE: u64 = 1 << 26;
+
+/// Copies all data from `src` to `dest` through an aligned buffer of at least `buffer_size`
+/// bytes, with direct IO, so that neither file takes up the page cache
+///
+/// Files are copied through the page cache instead where either of them does not support direct
+/// IO, and so is the tail of a file that does not fill a whole block.
+///
+/// # Returns
+/// * Ok: The number of bytes copied
+/// * Error: If reading or writing failed
+fn copy_direct(
+    src: &mut fs::File,
+    dest: &mut fs::File,
+    buffer_size: usize,
+) -> Result<u64, io::Error> {
+    use std::io::{Read, Write};
+
+    const ALIGNMENT: usize = 1 << 12;
+
+    if set_direct_io(src, true).is_err() {
+        return copy_contents(src, dest, buffer_size);
+    }
+    if set_direct_io(dest, true).is_err() {
+        set_direct_io(src, false)?;
+        return copy_contents(src, dest, buffer_size);
+    }
+
+    // Direct IO reads and writes whole blocks
+    let aligned_size = buffer_size.div_ceil(ALIGNMENT) * ALIGNMENT;
+    let mut buffer = vec![0; aligned_size + ALIGNMENT];
+    let offset = buffer.as_ptr().align_offset(ALIGNMENT);
+    let buffer = &mut buffer[offset..offset + aligned_size];
+    let mut copied = 0;
+
+    loop {
+        let len = match src.read(buffer) {
+            Ok(0) => return Ok(copied),
+            Ok(len) => len,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        if len % ALIGNMENT != 0 {
+            set_direct_io(src, false)?;
+            set_direct_io(dest, false)?;
+            dest.write_all(&buffer[..len])?;
+            return Ok(copied + len as u64 + copy_contents(src, dest, buffer_size)?);
+        }
+        dest.write_all(&buffer[..len])?;
+        copied += len as u64;
+    }
+}
+
+/// Turns direct IO for `file` on or off, with `O_DIRECT`
+#[cfg(target_os = "linux")]
+fn set_direct_io(file: &fs::File, direct_io: bool) -> Result<(), io::Error> {
+    use std::os::unix::io::AsRawFd;
+
+    // Safe since the descriptor is open for the duration of the calls
+    let flags = cvt(unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETFL) })?;
+    let flags = if direct_io {
+        flags | libc::O_DIRECT
+    } else {
+        flags & !libc::O_DIRECT
+    };
+    cvt(unsafe { libc::fcntl(file.as_raw_fd(), libc::F_SETFL, flags) }).map(|_| ())
+}
+
+/// Turns direct IO for `file` on or off, with `F_NOCACHE`
+#[cfg(target_os = "macos")]
+fn set_direct_io(file: &fs::File, direct_io: bool) -> Result<(), io::Error> {
+    use std::os::unix::io::AsRawFd;
+
+    // Safe since the descriptor is open for the duration of the call
+    cvt(unsafe { libc::fcntl(file.as_raw_fd(), libc::F_NOCACHE, direct_io as libc::c_int) })
+        .map(|_| ())
+}
+
+/// Turns direct IO for `file` on or off, which is not supported on this platform
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn set_direct_io(_file: &fs::File, _direct_io: bool) -> Result<(), io::Error> {
+    Err(io::Error::other("direct IO is not supported"))
+}
+
+/// Copies all data from `src` to `dest` inside the kernel, with `copy_file_range`, so that the data
+/// never passes through a buffer of its own
+///
+/// Whatever `copy_file_range` can not copy, such as across filesystems on older kernels, or from
+/// files like those in `/proc` that claim to be empty, is copied through a buffer instead, from
+/// where the kernel stopped, through a buffer of `buffer_size` bytes.
+///
+/// # Returns
+/// * Ok: The number of bytes copied
+/// * Error: If reading or writing failed
+#[cfg(target_os = "linux")]
+fn copy_offloaded(
+    src: &mut fs::File,
+    dest: &mut fs::File,
+    buffer_size: usize,
+) -> Result<u64, io::Error> {
+    use std::os::unix::io::AsRawFd;
+
+    const CHUNK_SIZE: usize = 1 << 30;
+
+    let mut copied = 0;
+    loop {
+        // Safe since both descriptors are open for the duration of the call, and null offsets
+        // make the kernel use and advance the offsets of the files
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_copy_file_range,
+                src.as_raw_fd(),
+                std::ptr::null_mut::<libc::loff_t>(),
+                dest.as_raw_fd(),
+                std::ptr::null_mut::<libc::loff_t>(),
+                CHUNK_SIZE,
+                0,
+            )
+        };
+        match result {
+            // Files that claim to be empty may not be, so they are read to be sure
+            0 if copied == 0 => return copy_contents(src, dest, buffer_size),
+            0 => return Ok(copied),
+            -1 => {
+                let e = io::Error::last_os_error();
+                match e.raw_os_error() {
+                    Some(libc::EINTR) => continue,
+                    Some(
+                        libc::ENOSYS
+                        | libc::EXDEV
+                        | libc::EOPNOTSUPP
+                        | libc::EINVAL
+                        | libc::EPERM
+                        | libc::EBADF,
+                    ) => return Ok(copied + copy_contents(src, dest, buffer_size)?),
+                    _ => return Err(e),
+                }
+            }
+            len => copied += len as u64,
+        }
+    }
+}
+
+/// Copies all data from `src` to `dest` inside the kernel, with `fcopyfile`, so that the data
+/// never passes through a buffer of its own
+///
+/// Files that `fcopyfile` can not copy are copied through a buffer of `buffer_size` bytes
+/// instead.
+///
+/// # Returns
+/// * Ok: The number of bytes copied
+/// * Error: If reading or writing failed
+#[cfg(target_os = "macos")]
+fn copy_offloaded(
+    src: &mut fs::File,
+    dest: &mut fs::File,
+    buffer_size: usize,
+) -> Result<u64, io::Error> {
+    use std::os::unix::io::AsRawFd;
+
+    const COPYFILE_DATA: u32 = 1 << 3;
+
+    extern "C" {
+        fn fcopyfile(
+            from: libc::c_int,
+            to: libc::c_int,
+            state: *mut libc::c_void,
+            flags: u32,
+        ) -> libc::c_int;
+    }
+
+    // Safe since both descriptors are open for the duration of the call, and no state is passed
+    let result = cvt(unsafe {
+        fcopyfile(
+            src.as_raw_fd(),
+            dest.as_raw_fd(),
+            std::ptr::null_mut(),
+            COPYFILE_DATA,
+        )
+    });
+    match result {
+        Ok(_) => src.metadata().map(|metadata| metadata.len()),
+        Err(ref e) if e.raw_os_error() == Some(libc::ENOTSUP) => {
+            copy_contents(src, dest, buffer_size)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Copies all data from `src` to `dest`, where the standard library may copy it inside the
+/// kernel
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn copy_offloaded(
+    src: &mut fs::File,
+    dest: &mut fs::File,
+    _buffer_size: usize,
+) -> Result<u64, io::Error> {
+    io::copy(src, dest)
+}
+
+/// Updates `dest` to the contents of `src` in place, writing only the blocks that differ
+///
+/// Unchanged blocks are only read, so updating a huge file with a few changes writes little more
+/// than the changes themselves, and needs no room for a second copy of the file.
+///
+/// # Returns
+/// * Ok: The number of bytes written
+/// * Error: If reading or writing failed
+fn update_contents(src: &mut fs::File, dest: &mut fs::File) -> Result<u64, io::Error> {
+    use std::io::{Seek, SeekFrom, Write};
+
+    const BLOCK_SIZE: usize = 1 << 16;
+
+    let mut src_block = vec![0; BLOCK_SIZE];
+    let mut dest_block = vec![0; BLOCK_SIZE];
+    let mut offset = 0;
+    let mut written = 0;
+
+    loop {
+        let len = read_block(src, &mut src_block)?;
+        if len == 0 {
+            break;
+        }
+        let dest_len = read_block(dest, &mut dest_block[..len])?;
+        if src_block[..len] != dest_block[..dest_len] {
+            dest.seek(SeekFrom::Start(offset))?;
+            dest.write_all(&src_block[..len])?;
+            written += len as u64;
+        }
+        offset += len as u64;
+    }
+    dest.set_len(offset)?;
+
+    Ok(written)
+}
+
+/// Appends to `dest` what `src` has past the end of `dest`, if `dest` is a prefix of `src`
+///
+/// The prefix is compared block by block before anything is written, so a file that was
+/// rotated or rewritten since it was copied is never appended to, but copied as a whole.
+///
+/// # Returns
+/// * Ok: The number of bytes appended, or None if `dest` is not a prefix of `src`
+/// * Error: If reading or writing failed
+fn append_contents(src: &mut fs::File, dest: &mut fs::File) -> Result<Option<u64>, io::Error> {
+    const BLOCK_SIZE: usize = 1 << 16;
+
+    if dest.metadata()?.len() > src.metadata()?.len() {
+        return Ok(None);
+    }
+    let mut src_block = vec![0; BLOCK_SIZE];
+    let mut dest_block = vec![0; BLOCK_SIZE];
+    loop {
+        let len = read_block(dest, &mut dest_block)?;
+        if len == 0 {
+            break;
+        }
+        if read_block(src, &mut src_block[..len])? != len || src_block[..len] != dest_block[..len] {
+            return Ok(None);
+        }
+    }
+    // Both files are now read up to the end of `dest`
+    io::copy(src, dest).map(Some)
+}
+
+/// Reads from `file` until `buffer` is full or the end of the file is reached
+///
+/// # Returns
+/// * Ok: The number of bytes read, which is only less than the length of `buffer` at the end
+/// * Error: If reading failed
+fn read_block(file: &mut fs::File, buffer: &mut [u8]) -> Result<usize, io::Error> {
+    use std::io::Read;
+
+    let mut len = 0;
+    while len < buffer.len() {
+        match file.read(&mut buffer[len..]) {
+            Ok(0) => break,
+            Ok(read) => len += read,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(len)
+}
+
+/// Sets the owner of `relative` inside `dest` to the owner of `relative` inside `src`,
+/// as mapped by `map`, without following symlinks
+///
+/// Changing the owner of a file clears its setuid and setgid bits, so those are restored
+/// afterwards.
+///
+/// # Arguments
+/// * `src`: base directory of the file to copy the owner of
+/// * `dest`: base directory of the file to change the owner of
+/// * `relative`: path of the file, relative to both `src` and `dest`
+/// * `map`: maps the user and group IDs of the source file to those of the destination file
+#[cfg(target_family = "unix")]
+pub fn copy_owner<F>(src: &Path, dest: &Path, relative: &Path, map: F) -> Result<(), io::Error>
+where
+    F: Fn(u32, u32) -> (u32, u32),
+{
+    copy_owner_as(src, relative, dest, relative, map)
+}
+
+/// Copies the owner of the file `from` inside `src` to the file `to` inside `dest`, like
+/// `copy_owner`, for files that are written under another name
+#[cfg(target_family = "unix")]
+// `mode_t` is narrower than `u32` on some platforms
+#[allow(clippy::unnecessary_cast)]
+pub fn copy_owner_as<F>(
+    src: &Path,
+    from: &Path,
+    dest: &Path,
+    to: &Path,
+    map: F,
+) -> Result<(), io::Error>
+where
+    F: Fn(u32, u32) -> (u32, u32),
+{
+    use std::os::unix::fs::PermissionsExt;
+
+    let (src_parent, name) = open_parent(src, from, false)?;
+    let stat = src_parent.stat(&name)?;
+    let (uid, gid) = map(stat.st_uid, stat.st_gid);
+
+    let (dest_parent, name) = open_parent(dest, to, false)?;
+    dest_parent.chown(&name, uid, gid)?;
+
+    let special_bits = (libc::S_ISUID | libc::S_ISGID) as u32;
+    if stat.st_mode & libc::S_IFMT == libc::S_IFREG && stat.st_mode as u32 & special_bits != 0 {
+        dest_parent
+            .open_file(&name)?
+            .set_permissions(fs::Permissions::from_mode(stat.st_mode as u32 & 0o7777))?;
+    }
+
+    Ok(())
+}
+
+/// Replaces the file `relative` inside `base` with a hard link to the file `target` inside `base`
+///
+/// The link is made under a temporary name next to `relative`, which is then renamed over it, so
+/// that `relative` is never missing. `target` and `relative` must not already be the same file.
+#[cfg(target_family = "unix")]
+pub fn link_over(base: &Path, target: &Path, relative: &Path) -> Result<(), io::Error> {
+    let (target_parent, target_name) = open_parent(base, target, false)?;
+    let (parent, name) = open_parent(base, relative, false)?;
+
+    let temp_name = temp_name();
+    target_parent.hard_link(&target_name, &parent, &temp_name)?;
+    parent.rename(&temp_name, &parent, &name).inspect_err(|_| {
+        parent.remove(&temp_name, false).ok();
+    })
+}
+
+/// Creates a symlink `relative` inside `base` that points to `target`
+#[cfg(target_family = "unix")]
+pub fn symlink(target: &Path, base: &Path, relative: &Path) -> Result<(), io::Error> {
+    let (parent, name) = open_parent(base, relative, false)?;
+    parent.symlink(target, &name)
+}
+
+/// Creates a socket, FIFO, or device node `relative` inside `base`, see `DirHandle::mknod`
+#[cfg(all(target_family = "unix", not(target_os = "macos")))]
+pub fn mknod(base: &Path, relative: &Path, mode: u32, rdev: u64) -> Result<(), io::Error> {
+    let (parent, name) = open_parent(base, relative, false)?;
+    parent.mknod(&name, mode, rdev)
+}
+
+/// Creates a socket, FIFO, or device node `relative` inside `base`
+///
+/// There is no `mknodat` on macOS, so the joined path is used there
+#[cfg(target_os = "macos")]
+pub fn mknod(base: &Path, relative: &Path, mode: u32, rdev: u64) -> Result<(), io::Error> {
+    let path = c_string(base.join(relative).as_os_str())?;
+    // Safe since `path` is a valid null-terminated string
+    cvt(unsafe { libc::mknod(path.as_ptr(), mode as libc::mode_t, rdev as libc::dev_t) })
+        .map(|_| ())
+}
+
+/// Converts an OS string into a null-terminated C string
+#[cfg(target_family = "unix")]
+fn c_string(string: &OsStr) -> Result<std::ffi::CString, io::Error> {
+    use std::os::unix::ffi::OsStrExt;
+
+    std::ffi::CString::new(string.as_bytes()).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{:?} contains a null byte", string),
+        )
+    })
+}
+
+/// Converts the return value of a libc call into a Result, using errno on failure
+#[cfg(target_family = "unix")]
+fn cvt(result: libc::c_int) -> Result<libc::c_int, io::Error> {
+    if result == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(result)
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_open_parent {
+    use super::*;
+
+    #[test]
+    fn invalid_relative_paths() {
+        for relative in &["", ".", "..", "a/../b", "/a"] {
+            assert!(open_parent(Path::new("."), Path::new(relative), false).is_err());
+        }
+    }
+
+    #[test]
+    fn missing_parents() {
+        const TEST_DIR: &str = "test_open_parent_missing_parents";
+        fs::create_dir_all(TEST_DIR).unwrap();
+
+        assert!(open_parent(Path::new(TEST_DIR), Path::new("a/b/c"), false).is_err());
+        let (_, name) = open_parent(Path::new(TEST_DIR), Path::new("a/b/c"), true).unwrap();
+        assert_eq!(name, "c");
+        assert!(Path::new(TEST_DIR).join("a/b").is_dir());
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn symlinked_component() {
+        use std::os::unix::fs::symlink;
+        const TEST_DIR: &str = "test_open_parent_symlinked_component";
+
+        fs::create_dir_all([TEST_DIR, "real"].join("/")).unwrap();
+        fs::write([TEST_DIR, "real", "file"].join("/"), b"1").unwrap();
+        symlink("real", [TEST_DIR, "link"].join("/")).unwrap();
+
+        // The base directory may be a symlink, but nothing below it may be
+        assert!(open_file(&Path::new(TEST_DIR).join("link"), Path::new("file")).is_ok());
+        assert!(open_file(Path::new(TEST_DIR), Path::new("link/file")).is_err());
+        assert!(remove_file(Path::new(TEST_DIR), Path::new("link/file")).is_err());
+        assert!(create_dir_all(Path::new(TEST_DIR), Path::new("link")).is_err());
+        assert!(create_dir_all(Path::new(TEST_DIR), Path::new("link/dir")).is_err());
+        assert!(Path::new(TEST_DIR).join("real/file").exists());
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod test_dir_handle {
+    use super::*;
+
+    #[test]
+    fn invalid_dir() {
+        assert!(DirHandle::open(Path::new("/?")).is_err());
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn entries() {
+        use std::os::unix::fs::symlink;
+        const TEST_DIR: &str = "test_dir_handle_entries";
+
+        fs::create_dir_all([TEST_DIR, "dir"].join("/")).unwrap();
+        fs::write([TEST_DIR, "file"].join("/"), b"1234").unwrap();
+        symlink("file", [TEST_DIR, "link"].join("/")).unwrap();
+
+        let dir = DirHandle::open(Path::new(TEST_DIR)).unwrap();
+        let mut entries: Vec<_> = dir
+            .entries()
+            .unwrap()
+            .into_iter()
+            .map(|(name, kind)| (name, kind.unwrap()))
+            .collect();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+
+        assert_eq!(
+            entries,
+            vec![
+                (OsString::from("dir"), EntryKind::Dir),
+                (OsString::from("file"), EntryKind::File(4)),
+                (
+                    OsString::from("link"),
+                    EntryKind::Symlink(PathBuf::from("file"))
+                ),
+            ]
+        );
+        assert!(dir.open_dir(OsStr::new("link")).is_err());
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn copy_over_symlink() {
+        use std::os::unix::fs::symlink;
+        const TEST_DIR: &str = "test_dir_handle_copy_over_symlink";
+
+        fs::create_dir_all([TEST_DIR, "src"].join("/")).unwrap();
+        fs::create_dir_all([TEST_DIR, "dest"].join("/")).unwrap();
+        fs::write([TEST_DIR, "src", "file"].join("/"), b"new").unwrap();
+        fs::write([TEST_DIR, "target"].join("/"), b"old").unwrap();
+        symlink("../target", [TEST_DIR, "dest", "file"].join("/")).unwrap();
+
+        let base = Path::new(TEST_DIR);
+        let copy = |reflink, inplace| {
+            copy_file(
+                &base.join("src"),
+                &base.join("dest"),
+                Path::new("file"),
+                reflink,
+                inplace,
+                IoStrategy::default(),
+            )
+        };
+        for &reflink in &[Reflink::Auto, Reflink::Never] {
+            assert!(copy(reflink, true).is_err());
+        }
+        assert_eq!(fs::read(base.join("target")).unwrap(), b"old");
+
+        // Renaming over the symlink replaces the symlink itself
+        assert!(copy(Reflink::Auto, false).is_ok());
+        assert_eq!(fs::read(base.join("target")).unwrap(), b"old");
+        assert!(fs::symlink_metadata(base.join("dest/file"))
+            .unwrap()
+            .file_type()
+            .is_file());
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+
+    #[test]
+    fn copy_reflink() {
+        const TEST_DIR: &str = "test_dir_handle_copy_reflink";
+
+        fs::create_dir_all([TEST_DIR, "src"].join("/")).unwrap();
+        fs::create_dir_all([TEST_DIR, "dest"].join("/")).unwrap();
+        fs::write([TEST_DIR, "src", "file"].join("/"), b"12345").unwrap();
+
+        let base = Path::new(TEST_DIR);
+        let copy = |reflink, inplace| {
+            copy_file(
+                &base.join("src"),
+                &base.join("dest"),
+                Path::new("file"),
+                reflink,
+                inplace,
+                IoStrategy::default(),
+            )
+        };
+
+        for &reflink in &[Reflink::Auto, Reflink::Never] {
+            for &inplace in &[false, true] {
+                fs::write([TEST_DIR, "dest", "file"].join("/"), b"old").unwrap();
+                assert_eq!(copy(reflink, inplace).unwrap(), 5);
+                assert_eq!(fs::read(base.join("dest/file")).unwrap(), b"12345");
+            }
+        }
+
+        // Only succeeds on filesystems that support reflinks
+        fs::write([TEST_DIR, "dest", "file"].join("/"), b"old").unwrap();
+        if copy(Reflink::Always, false).is_ok() {
+            assert_eq!(fs::read(base.join("dest/file")).unwrap(), b"12345");
+        } else {
+            assert_eq!(fs::read(base.join("dest/file")).unwrap(), b"old");
+        }
+
+        // No temporary files are left behind, whether the copies failed or not
+        let names: Vec<_> = fs::read_dir(base.join("dest"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(names, vec![OsString::from("file")]);
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+
+    #[test]
+    fn copy_offloaded_contents() {
+        const TEST_DIR: &str = "test_dir_handle_copy_offloaded_contents";
+
+        fs::create_dir_all(TEST_DIR).unwrap();
+        let contents: Vec<u8> = (0..3 << 20).map(|i: u32| (i * 7 % 251) as u8).collect();
+        fs::write([TEST_DIR, "src"].join("/"), &contents).unwrap();
+
+        let copy = |src: &str, dest: &str| {
+            let mut src_file = fs::File::open(src).unwrap();
+            let mut dest_file = fs::File::create(dest).unwrap();
+            copy_offloaded(&mut src_file, &mut dest_file, 1 << 16).unwrap()
+        };
+
+        let dest = [TEST_DIR, "dest"].join("/");
+        assert_eq!(
+            copy(&[TEST_DIR, "src"].join("/"), &dest),
+            contents.len() as u64
+        );
+        assert_eq!(fs::read(&dest).unwrap(), contents);
+
+        // Files that claim to be empty are still copied
+        if cfg!(target_os = "linux") {
+            let len = copy("/proc/self/cmdline", &dest);
+            assert!(len > 0);
+            assert_eq!(fs::metadata(&dest).unwrap().len(), len);
+        }
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+
+    #[test]
+    fn copy_direct_contents() {
+        const TEST_DIR: &str = "test_dir_handle_copy_direct_contents";
+
+        fs::create_dir_all(TEST_DIR).unwrap();
+        let base = Path::new(TEST_DIR);
+
+        // Both whole blocks and the tail of the file are copied, whatever the size of the buffer
+        for &len in &[0, 1 << 12, 3 << 20, (3 << 20) + 100] {
+            let contents: Vec<u8> = (0..len).map(|i: u32| (i * 7 % 251) as u8).collect();
+            fs::write(base.join("src"), &contents).unwrap();
+
+            for &buffer_size in &[5000, 1 << 20] {
+                let mut src_file = fs::File::open(base.join("src")).unwrap();
+                let mut dest_file = fs::File::create(base.join("dest")).unwrap();
+                assert_eq!(
+                    copy_direct(&mut src_file, &mut dest_file, buffer_size).unwrap(),
+                    len as u64
+                );
+                assert_eq!(fs::read(base.join("dest")).unwrap(), contents);
+            }
+        }
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+
+    #[test]
+    fn copy_buffered_contents() {
+        const TEST_DIR: &str = "test_dir_handle_copy_buffered_contents";
+
+        // Buffers grow with files, up to a few MiB, unless their size is given
+        let adaptive = IoStrategy::default();
+        assert_eq!(adaptive.buffer_size(0), MIN_BUFFER_SIZE);
+        assert_eq!(adaptive.buffer_size(10_000), 1 << 14);
+        assert_eq!(adaptive.buffer_size(1 << 40), MAX_BUFFER_SIZE);
+        let fixed = IoStrategy {
+            buffer_size: Some(1000),
+            ..IoStrategy::default()
+        };
+        assert_eq!(fixed.buffer_size(1 << 40), 1000);
+        let empty = IoStrategy {
+            buffer_size: Some(0),
+            ..IoStrategy::default()
+        };
+        assert_eq!(empty.buffer_size(10), 1);
+
+        fs::create_dir_all(TEST_DIR).unwrap();
+        let base = Path::new(TEST_DIR);
+        let contents: Vec<u8> = (0..100_000).map(|i: u32| (i * 7 % 251) as u8).collect();
+        fs::write(base.join("src"), &contents).unwrap();
+
+        // Both buffers on the stack and on the heap copy everything
+        for &buffer_size in &[1, 1000, STACK_BUFFER_SIZE, 1 << 20] {
+            let mut src_file = fs::File::open(base.join("src")).unwrap();
+            let mut dest_file = fs::File::create(base.join("dest")).unwrap();
+            assert_eq!(
+                copy_contents(&mut src_file, &mut dest_file, buffer_size).unwrap(),
+                contents.len() as u64
+            );
+            assert_eq!(fs::read(base.join("dest")).unwrap(), contents);
+        }
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+
+    #[test]
+    fn copy_synced_files() {
+        const TEST_DIR: &str = "test_dir_handle_copy_synced_files";
+
+        fs::create_dir_all([TEST_DIR, "src"].join("/")).unwrap();
+        fs::create_dir_all([TEST_DIR, "dest"].join("/")).unwrap();
+        fs::write([TEST_DIR, "src", "file"].join("/"), b"synced").unwrap();
+
+        let base = Path::new(TEST_DIR);
+        let io = IoStrategy {
+            fsync: true,
+            ..IoStrategy::default()
+        };
+        // New files, and files that are updated in place, are flushed however they are copied
+        for &reflink in &[Reflink::Auto, Reflink::Never] {
+            fs::remove_file(base.join("dest/file")).ok();
+            for &inplace in &[true, false, true] {
+                let copied = copy_file(
+                    &base.join("src"),
+                    &base.join("dest"),
+                    Path::new("file"),
+                    reflink,
+                    inplace,
+                    io,
+                )
+                .unwrap();
+                assert!(copied <= 6);
+                assert_eq!(fs::read(base.join("dest/file")).unwrap(), b"synced");
+            }
+        }
+        let names: Vec<_> = fs::read_dir(base.join("dest"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(names, vec![OsString::from("file")]);
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn copy_renamed_into_place() {
+        const TEST_DIR: &str = "test_dir_handle_copy_renamed_into_place";
+
+        fs::create_dir_all([TEST_DIR, "src", "dir"].join("/")).unwrap();
+        fs::create_dir_all([TEST_DIR, "dest", "dir", "full"].join("/")).unwrap();
+        fs::write([TEST_DIR, "src", "file"].join("/"), b"new contents").unwrap();
+        fs::write([TEST_DIR, "src", "dir", "full"].join("/"), b"new").unwrap();
+        fs::write([TEST_DIR, "dest", "file"].join("/"), b"old").unwrap();
+        fs::write([TEST_DIR, "dest", "dir", "full", "a"].join("/"), b"a").unwrap();
+
+        let base = Path::new(TEST_DIR);
+        let copy = |relative: &str| {
+            copy_file(
+                &base.join("src"),
+                &base.join("dest"),
+                Path::new(relative),
+                Reflink::Never,
+                false,
+                IoStrategy::default(),
+            )
+        };
+        let names = |dir: &str| -> Vec<OsString> {
+            let mut names: Vec<_> = fs::read_dir(base.join(dir))
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name())
+                .collect();
+            names.sort();
+            names
+        };
+
+        // The old file is replaced as a whole, so what still has it open keeps reading it
+        let mut old = fs::File::open(base.join("dest/file")).unwrap();
+        assert_eq!(copy("file").unwrap(), 12);
+        assert_eq!(fs::read(base.join("dest/file")).unwrap(), b"new contents");
+        let mut contents = Vec::new();
+        std::io::Read::read_to_end(&mut old, &mut contents).unwrap();
+        assert_eq!(contents, b"old");
+        assert_eq!(
+            names("dest"),
+            vec![OsString::from("dir"), OsString::from("file")]
+        );
+
+        // A copy that can not be renamed into place leaves neither it nor what it replaces
+        assert!(copy("dir/full").is_err());
+        assert_eq!(names("dest/dir"), vec![OsString::from("full")]);
+        assert_eq!(names("dest/dir/full"), vec![OsString::from("a")]);
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn copy_inplace_delta() {
+        use std::os::unix::fs::MetadataExt;
+        const TEST_DIR: &str = "test_dir_handle_copy_inplace_delta";
+        const BLOCK: usize = 1 << 16;
+
+        fs::create_dir_all([TEST_DIR, "src"].join("/")).unwrap();
+        fs::create_dir_all([TEST_DIR, "dest"].join("/")).unwrap();
+        let mut contents = vec![1; 3 * BLOCK + 100];
+        fs::write([TEST_DIR, "dest", "file"].join("/"), &contents).unwrap();
+        fs::hard_link(
+            [TEST_DIR, "dest", "file"].join("/"),
+            [TEST_DIR, "link"].join("/"),
+        )
+        .unwrap();
+
+        let base = Path::new(TEST_DIR);
+        let copy = || {
+            copy_file(
+                &base.join("src"),
+                &base.join("dest"),
+                Path::new("file"),
+                Reflink::Auto,
+                true,
+                IoStrategy::default(),
+            )
+        };
+        let inode = fs::metadata(base.join("dest/file")).unwrap().ino();
+
+        // Only the changed block is written
+        contents[BLOCK + 5] = 2;
+        fs::write(base.join("src/file"), &contents).unwrap();
+        assert_eq!(copy().unwrap(), BLOCK as u64);
+        assert_eq!(fs::read(base.join("dest/file")).unwrap(), contents);
+
+        // Growing and shrinking only write the changed tail
+        contents.extend(&[3; 10]);
+        fs::write(base.join("src/file"), &contents).unwrap();
+        assert_eq!(copy().unwrap(), 110);
+        assert_eq!(fs::read(base.join("dest/file")).unwrap(), contents);
+        contents.truncate(BLOCK);
+        fs::write(base.join("src/file"), &contents).unwrap();
+        assert_eq!(copy().unwrap(), 0);
+        assert_eq!(fs::read(base.join("dest/file")).unwrap(), contents);
+
+        // The file is updated, not replaced
+        assert_eq!(fs::metadata(base.join("dest/file")).unwrap().ino(), inode);
+        assert_eq!(fs::read(base.join("link")).unwrap(), contents);
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn copy_appended() {
+        use std::os::unix::fs::MetadataExt;
+        const TEST_DIR: &str = "test_dir_handle_copy_appended";
+
+        fs::create_dir_all([TEST_DIR, "src"].join("/")).unwrap();
+        fs::create_dir_all([TEST_DIR, "dest"].join("/")).unwrap();
+        fs::write([TEST_DIR, "dest", "log"].join("/"), b"line 1\n").unwrap();
+
+        let base = Path::new(TEST_DIR);
+        let copy = || {
+            let io = IoStrategy {
+                append: true,
+                ..IoStrategy::default()
+            };
+            let src = base.join("src");
+            copy_file(
+                &src,
+                &base.join("dest"),
+                Path::new("log"),
+                Reflink::Never,
+                false,
+                io,
+            )
+        };
+        let inode = fs::metadata(base.join("dest/log")).unwrap().ino();
+
+        // Only what the file grew by is written, to the same file
+        fs::write(base.join("src/log"), b"line 1\nline 2\n").unwrap();
+        assert_eq!(copy().unwrap(), 7);
+        assert_eq!(
+            fs::read(base.join("dest/log")).unwrap(),
+            b"line 1\nline 2\n"
+        );
+        assert_eq!(fs::metadata(base.join("dest/log")).unwrap().ino(), inode);
+
+        // A file that was rotated is copied as a whole
+        fs::write(base.join("src/log"), b"line 3\nline 4\nline 5\n").unwrap();
+        assert_eq!(copy().unwrap(), 21);
+        assert_eq!(
+            fs::read(base.join("dest/log")).unwrap(),
+            b"line 3\nline 4\nline 5\n"
+        );
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+
+    #[test]
+    fn rename_file() {
+        const TEST_DIR: &str = "test_dir_handle_rename_file";
+
+        fs::create_dir_all([TEST_DIR, "a"].join("/")).unwrap();
+        fs::create_dir_all([TEST_DIR, "b"].join("/")).unwrap();
+        fs::write([TEST_DIR, "a", "file"].join("/"), b"1234").unwrap();
+
+        let base = Path::new(TEST_DIR);
+        assert!(rename(base, Path::new("a/file"), Path::new("c/file")).is_err());
+        assert!(rename(base, Path::new("a/file"), Path::new("b/renamed")).is_ok());
+        assert!(!base.join("a/file").exists());
+        assert_eq!(fs::read(base.join("b/renamed")).unwrap(), b"1234");
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+}
@@ -0,0 +1,238 @@
+//! Writes through symlinks to directories, with `--keep-dirlinks` and `--copy-dirlinks`
+//!
+//! Entries are only ever written below the roots of a run, without following symlinks, see
+//! `dirfd`. So a directory that is reached through a symlink, in the destination with
+//! `--keep-dirlinks` or in the source with `--copy-dirlinks`, is left out of the run that found
+//! it, and is then synchronized or copied by a run of its own, which has the symlink as its
+//! trusted root.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use log::{error, info};
+
+use crate::lumins::file_ops::{FileOps, FileSets};
+use crate::lumins::parse::Options;
+
+/// Replaces the symlinks to directories in `file_sets` of `src` with directories, with
+/// `Options::copy_dirlinks`
+///
+/// Symlinks to directories that contain them, or that contain one of the symlinks `src` was
+/// reached through, are kept as symlinks, since copying them would never end.
+///
+/// # Returns
+/// The paths of the replaced symlinks, whose directories are copied on their own
+pub fn copy_dirlinks(file_sets: &mut FileSets, src: &str, options: &Options) -> Vec<PathBuf> {
+    if !options.copy_dirlinks {
+        return Vec::new();
+    }
+
+    let mut links: Vec<PathBuf> = file_sets
+        .symlinks()
+        .iter()
+        .map(|symlink| symlink.path())
+        .filter(|path| {
+            let target = match fs::canonicalize(Path::new(src).join(path)) {
+                Ok(target) if target.is_dir() => target,
+                _ => return false,
+            };
+            // The chain of symlinks that led here is in the path of `src` itself
+            let loops = Path::new(src).ancestors().any(|ancestor| {
+                fs::canonicalize(ancestor).is_ok_and(|ancestor| ancestor.starts_with(&target))
+            });
+            if loops {
+                error!(
+                    "Error -- {:?} links to a directory that contains it, so it is copied as a \
+                     symlink",
+                    Path::new(src).join(path)
+                );
+            }
+            !loops
+        })
+        .cloned()
+        .collect();
+    links.sort();
+
+    for link in &links {
+        info!("Copying {:?} as a directory", Path::new(src).join(link));
+        file_sets.replace_with_dir(link);
+    }
+    links
+}
+
+/// Finds the symlinks to directories in `dest_file_sets` of `dest` that are directories in
+/// `src_file_sets`, with `Options::keep_dirlinks`
+///
+/// # Returns
+/// The paths of the symlinks, whose directories are written through them on their own
+pub fn keep_dirlinks(
+    src_file_sets: &FileSets,
+    dest_file_sets: &FileSets,
+    dest: &str,
+    options: &Options,
+) -> Vec<PathBuf> {
+    if !options.keep_dirlinks {
+        return Vec::new();
+    }
+
+    let src_dirs: Vec<&PathBuf> = src_file_sets.dirs().iter().map(|dir| dir.path()).collect();
+    let mut links: Vec<PathBuf> = dest_file_sets
+        .symlinks()
+        .iter()
+        .map(|symlink| symlink.path())
+        .filter(|path| src_dirs.contains(path))
+        .filter(|path| Path::new(dest).join(path).is_dir())
+        .cloned()
+        .collect();
+    links.sort();
+    links
+}
+
+/// Finds the directories of `src_file_sets` that are symlinks to directories in `dest`, with
+/// `Options::keep_dirlinks`, for runs that do not list `dest`
+///
+/// Directories inside such a symlink are not looked up, since they are written through it by a
+/// run of its own.
+///
+/// # Returns
+/// The paths of the symlinks, whose directories are written through them on their own
+pub fn find_dirlinks(src_file_sets: &FileSets, dest: &str, options: &Options) -> Vec<PathBuf> {
+    if !options.keep_dirlinks {
+        return Vec::new();
+    }
+
+    let mut dirs: Vec<&PathBuf> = src_file_sets.dirs().iter().map(|dir| dir.path()).collect();
+    // Path's ordering compares components, so parents sort before their children
+    dirs.sort();
+
+    let mut links: Vec<PathBuf> = Vec::new();
+    for dir in dirs {
+        if links.iter().any(|link| dir.starts_with(link)) {
+            continue;
+        }
+        let path = Path::new(dest).join(dir);
+        let is_link =
+            fs::symlink_metadata(&path).is_ok_and(|metadata| metadata.file_type().is_symlink());
+        if is_link && path.is_dir() {
+            links.push(dir.clone());
+        }
+    }
+    links
+}
+
+/// Removes what is inside each of `links` from `file_sets`, as well as the links themselves
+/// unless `keep_links` is set, since they are written by runs of their own
+pub fn unlist(file_sets: &mut FileSets, links: &[PathBuf], keep_links: bool) {
+    if links.is_empty() {
+        return;
+    }
+    file_sets.retain_paths(|path| {
+        !links
+            .iter()
+            .any(|link| path.starts_with(link) && !(keep_links && path == link))
+    });
+}
+
+/// Gets the options of the run of its own for the directory linked at `link`
+///
+/// Snapshots are only taken of the destination as a whole, and the depth and the listed paths
+/// that are left are those below `link`.
+///
+/// # Returns
+/// * Some: The options for the run
+/// * None: If nothing below `link` is within `options.max_depth`
+pub fn linked_options(options: &Options, link: &Path) -> Option<Options> {
+    let depth = link.components().count();
+    let max_depth = match options.max_depth {
+        Some(max_depth) if max_depth <= depth => return None,
+        max_depth => max_depth.map(|max_depth| max_depth - depth),
+    };
+    Some(Options {
+        dest_snapshot: false,
+        journal: None,
+        quarantine: None,
+        max_depth,
+        files_from: options.files_from.as_ref().map(|paths| {
+            paths
+                .iter()
+                .filter_map(|path| path.strip_prefix(link).ok())
+                .filter(|path| *path != Path::new(""))
+                .map(Path::to_path_buf)
+                .collect()
+        }),
+        ..options.clone()
+    })
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_dirlinks {
+    use super::*;
+    use crate::lumins::file_ops;
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn finds_dirlinks() {
+        use std::os::unix::fs::symlink;
+
+        const TEST_DIR: &str = "test_dirlinks_finds_dirlinks";
+        const TEST_DIR_OUT: &str = "test_dirlinks_finds_dirlinks_out";
+        let path = |dir: &str, path: &str| [dir, path].join("/");
+
+        fs::create_dir_all(path(TEST_DIR, "dir/sub")).unwrap();
+        fs::create_dir_all(path(TEST_DIR, "target")).unwrap();
+        symlink("target", path(TEST_DIR, "linked")).unwrap();
+        symlink("..", path(TEST_DIR, "dir/parent")).unwrap();
+        fs::create_dir_all(path(TEST_DIR_OUT, "volume")).unwrap();
+        symlink("volume", path(TEST_DIR_OUT, "dir")).unwrap();
+
+        let options = Options {
+            copy_dirlinks: true,
+            keep_dirlinks: true,
+            ..Options::default()
+        };
+        let mut src_file_sets = file_ops::get_all_files(TEST_DIR).unwrap();
+        assert_eq!(
+            copy_dirlinks(&mut src_file_sets, TEST_DIR, &options),
+            vec![PathBuf::from("linked")]
+        );
+        assert!(src_file_sets
+            .dirs()
+            .iter()
+            .any(|dir| dir.path() == Path::new("linked")));
+        assert_eq!(src_file_sets.symlinks().len(), 1);
+
+        let dest_file_sets = file_ops::get_all_files(TEST_DIR_OUT).unwrap();
+        let links = keep_dirlinks(&src_file_sets, &dest_file_sets, TEST_DIR_OUT, &options);
+        assert_eq!(links, vec![PathBuf::from("dir")]);
+        assert_eq!(find_dirlinks(&src_file_sets, TEST_DIR_OUT, &options), links);
+
+        unlist(&mut src_file_sets, &links, false);
+        assert!(src_file_sets
+            .dirs()
+            .iter()
+            .all(|dir| !dir.path().starts_with("dir")));
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_dir_all(TEST_DIR_OUT).unwrap();
+    }
+
+    #[test]
+    fn linked_options_depth() {
+        let options = Options {
+            dest_snapshot: true,
+            xattrs: true,
+            secure: true,
+            max_depth: Some(2),
+            ..Options::default()
+        };
+        let linked = linked_options(&options, Path::new("a")).unwrap();
+        assert!(linked.xattrs && !linked.dest_snapshot);
+        assert!(linked.secure);
+        assert_eq!(linked.max_depth, Some(1));
+        assert!(linked_options(&options, Path::new("a/b")).is_none());
+    }
+}
@@ -0,0 +1,246 @@
+//! Finds files with identical contents in a tree, and deletes or hard links the extra copies
+//!
+//! Files are compared by size first, and only files that share their size with another are
+//! hashed, with BLAKE2b, since copies are deleted on the strength of their hashes. Hard links to
+//! one file are one copy, and empty files are never duplicates, since they take no space.
+
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+use hashbrown::HashMap;
+use indicatif::HumanBytes;
+use rayon::prelude::*;
+
+use crate::lumins::file_ops::{self, File, FileOps};
+use crate::lumins::parse::Options;
+use crate::progress::STATS;
+
+/// A struct that represents a set of files with identical contents
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub struct Duplicates {
+    /// Size of each of the files
+    pub size: u64,
+    /// The files, relative to the tree, sorted by path
+    pub files: Vec<File>,
+}
+
+impl fmt::Display for Duplicates {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} x {}", HumanBytes(self.size), self.files.len())?;
+        for file in &self.files {
+            write!(f, "\n  {}", file.path().display())?;
+        }
+        Ok(())
+    }
+}
+
+/// Finds the sets of files in `dir` with identical contents, hashing files of the same size in
+/// parallel
+///
+/// Files that could not be read are logged and left out.
+///
+/// # Returns
+/// The sets of duplicates, from the largest files
+///
+/// # Errors
+/// This function will return an error if `dir` could not be listed
+pub fn find(dir: &str) -> Result<Vec<Duplicates>, io::Error> {
+    let file_sets = file_ops::get_all_files(dir)?;
+
+    let mut sizes: HashMap<u64, Vec<&File>> = HashMap::new();
+    for file in file_sets.files().iter().filter(|file| file.size() > 0) {
+        sizes.entry(file.size()).or_default().push(file);
+    }
+    let candidates: Vec<&File> = sizes
+        .into_par_iter()
+        .filter(|(_, files)| files.len() > 1)
+        .flat_map(|(_, files)| distinct(Path::new(dir), files))
+        .collect();
+
+    let hashed: Vec<((u64, Vec<u8>), &File)> = candidates
+        .into_par_iter()
+        .filter_map(|file| {
+            file_ops::hash_file_secure(file, dir).map(|hash| ((file.size(), hash), file))
+        })
+        .collect();
+    let mut sets: HashMap<(u64, Vec<u8>), Vec<File>> = HashMap::new();
+    for (key, file) in hashed {
+        sets.entry(key).or_default().push(file.clone());
+    }
+
+    let mut duplicates: Vec<Duplicates> = sets
+        .into_iter()
+        .filter(|(_, files)| files.len() > 1)
+        .map(|((size, _), mut files)| {
+            files.sort_unstable_by(|a, b| a.path().cmp(b.path()));
+            Duplicates { size, files }
+        })
+        .collect();
+    duplicates.sort_unstable_by(|a, b| {
+        b.size
+            .cmp(&a.size)
+            .then(a.files[0].path().cmp(b.files[0].path()))
+    });
+    Ok(duplicates)
+}
+
+/// Leaves out the files of `files` inside `base` that are hard links to an earlier one by path
+#[cfg(target_family = "unix")]
+fn distinct<'a>(base: &Path, mut files: Vec<&'a File>) -> Vec<&'a File> {
+    use hashbrown::HashSet;
+    use std::os::unix::fs::MetadataExt;
+
+    files.sort_unstable_by_key(|file| file.path());
+    let mut inodes = HashSet::new();
+    files.retain(
+        |file| match std::fs::symlink_metadata(base.join(file.path())) {
+            Ok(metadata) => inodes.insert((metadata.dev(), metadata.ino())),
+            // Files that can not be hashed are logged when they are
+            Err(_) => true,
+        },
+    );
+    files
+}
+
+/// Leaves out the files of `files` that are hard links to an earlier one, which are not told
+/// apart on this platform
+#[cfg(not(target_family = "unix"))]
+fn distinct<'a>(_base: &Path, files: Vec<&'a File>) -> Vec<&'a File> {
+    files
+}
+
+/// Finds the duplicates in `dir` and prints them, then deletes every copy but the first of each set
+/// with `Options::delete_dupes`, or hard links the copies to each other with `Options::link_dupes`
+///
+/// Copies are linked like `cp --dedup` links them, where only copies with the same permissions
+/// and owner are linked, since links share them.
+///
+/// # Errors
+/// This function will return an error if `dir` could not be listed, or if the duplicates could
+/// not be linked on this platform
+pub fn run(dir: &str, options: &Options) -> Result<(), io::Error> {
+    let duplicates = find(dir)?;
+    let quiet = options.quiet;
+    if !quiet {
+        for set in &duplicates {
+            println!("{}\n", set);
+        }
+    }
+    let extra: u64 = duplicates
+        .iter()
+        .map(|set| set.size * (set.files.len() as u64 - 1))
+        .sum();
+
+    let done = if options.delete_dupes {
+        duplicates
+            .par_iter()
+            .flat_map(|set| &set.files[1..])
+            .for_each(|file| file.remove(Path::new(dir)));
+        format!(", deleted {} files", STATS.files_deleted())
+    } else if options.link_dupes {
+        link(dir, &duplicates)?
+    } else {
+        String::new()
+    };
+
+    if !quiet {
+        println!(
+            "{} sets of duplicates, {} in extra copies{}",
+            duplicates.len(),
+            HumanBytes(extra),
+            done
+        );
+    }
+    Ok(())
+}
+
+/// Hard links the copies of each set of `duplicates` in `dir` to each other
+#[cfg(target_family = "unix")]
+fn link(dir: &str, duplicates: &[Duplicates]) -> Result<String, io::Error> {
+    use crate::lumins::dedup;
+
+    let copies = duplicates.par_iter().flat_map(|set| &set.files);
+    let (linked, freed) = dedup::dedup(dir, copies);
+    Ok(format!(
+        ", linked {} files, freeing {}",
+        linked,
+        HumanBytes(freed)
+    ))
+}
+
+/// Hard links the copies of each set of `duplicates`, which is not supported on this platform
+#[cfg(not(target_family = "unix"))]
+fn link(_dir: &str, _duplicates: &[Duplicates]) -> Result<String, io::Error> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Dupes Error -- --link is not supported on this platform",
+    ))
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_dupes {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn finds_duplicates() {
+        const TEST_DIR: &str = "test_dupes_finds_duplicates";
+        let path = |path: &str| [TEST_DIR, path].join("/");
+
+        fs::create_dir_all(path("sub")).unwrap();
+        for file in &["b", "a", "sub/c"] {
+            fs::write(path(file), b"same").unwrap();
+        }
+        fs::write(path("large"), b"larger").unwrap();
+        fs::write(path("sub/large"), b"larger").unwrap();
+        // Same size, other contents
+        fs::write(path("other"), b"diff").unwrap();
+        fs::write(path("empty"), b"").unwrap();
+        fs::write(path("sub/empty"), b"").unwrap();
+        #[cfg(target_family = "unix")]
+        fs::hard_link(path("a"), path("sub/linked")).unwrap();
+
+        let duplicates = find(TEST_DIR).unwrap();
+        let sets: Vec<(u64, Vec<&Path>)> = duplicates
+            .iter()
+            .map(|set| {
+                (
+                    set.size,
+                    set.files.iter().map(|f| f.path().as_path()).collect(),
+                )
+            })
+            .collect();
+        let paths = |paths: &[&'static str]| paths.iter().map(|p| Path::new(*p)).collect();
+        assert_eq!(
+            sets,
+            [
+                (6, paths(&["large", "sub/large"])),
+                (4, paths(&["a", "b", "sub/c"]))
+            ]
+        );
+        assert_eq!(duplicates[1].to_string(), "4B x 3\n  a\n  b\n  sub/c");
+
+        // Only the first copy of each set is kept
+        let options = Options {
+            delete_dupes: true,
+            keep_first: true,
+            quiet: true,
+            ..Options::default()
+        };
+        run(TEST_DIR, &options).unwrap();
+        for file in &["a", "large", "other", "empty", "sub/empty"] {
+            assert!(Path::new(&path(file)).exists());
+        }
+        for file in &["b", "sub/c", "sub/large"] {
+            assert!(!Path::new(&path(file)).exists());
+        }
+        assert!(find(TEST_DIR).unwrap().is_empty());
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+}
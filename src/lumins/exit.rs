@@ -0,0 +1,52 @@
+//! Exit codes of lms, so that scripts can tell apart why a run failed
+
+/// Enum to represent the exit status of lms
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub enum ExitCode {
+    /// Everything was done
+    Success = 0,
+    /// The run finished, but some files, directories, or symlinks could not be handled
+    Partial = 1,
+    /// The arguments, or the files they name such as an ID map, are invalid
+    Usage = 2,
+    /// A source, or a target of rm, does not exist or is not a directory
+    SourceMissing = 3,
+    /// The destination could not be created
+    DestNotWritable = 4,
+    /// The run stopped before it finished
+    Failed = 5,
+}
+
+impl ExitCode {
+    /// Gets the exit status of a run that returned `result`, after logging `errors` errors
+    pub fn of<T, E>(result: &Result<T, E>, errors: u64) -> Self {
+        match result {
+            Err(_) => ExitCode::Failed,
+            Ok(_) if errors > 0 => ExitCode::Partial,
+            Ok(_) => ExitCode::Success,
+        }
+    }
+
+    /// Gets the status to exit the process with
+    pub fn code(self) -> i32 {
+        self as i32
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_exit {
+    use super::*;
+
+    #[test]
+    fn of() {
+        assert_eq!(ExitCode::of(&Ok::<(), ()>(()), 0), ExitCode::Success);
+        assert_eq!(ExitCode::of(&Ok::<(), ()>(()), 2), ExitCode::Partial);
+        assert_eq!(ExitCode::of(&Err::<(), ()>(()), 0), ExitCode::Failed);
+        assert_eq!(ExitCode::of(&Err::<(), ()>(()), 2), ExitCode::Failed);
+        assert_eq!(ExitCode::Usage.code(), 2);
+    }
+}
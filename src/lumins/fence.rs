@@ -0,0 +1,142 @@
+//! Orders the writes of a copy by fenced directories, for consumers that watch directories and
+//! assume the order in which they arrive
+//!
+//! Everything outside fences is written first. Then each fenced directory is written, and flushed
+//! to disk, before anything in the next fenced directory in path order is touched.
+
+use std::path::Path;
+
+use hashbrown::{HashMap, HashSet};
+use log::error;
+use rayon::prelude::*;
+
+use crate::lumins::dirfd;
+use crate::lumins::file_ops::{Dir, FileOps, FileSets};
+use crate::lumins::glob::Glob;
+use crate::lumins::platform;
+
+/// A struct that represents the phases that the writes into a tree are split into
+///
+/// Phase 0 has everything outside fences, and phase `i` has everything in the `i`th fenced
+/// directory in path order, that is not in a deeper fenced directory.
+#[derive(Debug, Default)]
+pub struct Phases<'a> {
+    fences: Vec<&'a Path>,
+    phases: HashMap<&'a Path, usize>,
+}
+
+impl<'a> Phases<'a> {
+    /// Splits a tree with the directories `dirs` into phases, fencing the directories that any of
+    /// `globs` matches
+    pub fn new(globs: &[Glob], dirs: &'a HashSet<Dir>) -> Self {
+        if globs.is_empty() {
+            return Phases::default();
+        }
+
+        let mut fences: Vec<&Path> = dirs
+            .par_iter()
+            .map(|dir| dir.path().as_path())
+            .filter(|path| globs.iter().any(|glob| glob.matches(path)))
+            .collect();
+        // Path's ordering compares components, so parents sort before their children
+        fences.par_sort_unstable();
+        let phases = fences
+            .iter()
+            .enumerate()
+            .map(|(i, fence)| (*fence, i + 1))
+            .collect();
+
+        Phases { fences, phases }
+    }
+
+    /// Gets the number of phases, which is 1 if there are no fences
+    pub fn count(&self) -> usize {
+        self.fences.len() + 1
+    }
+
+    /// Gets the phase that `path` is written in
+    pub fn phase(&self, path: &Path) -> usize {
+        if self.phases.is_empty() {
+            return 0;
+        }
+        path.ancestors()
+            .find_map(|ancestor| self.phases.get(ancestor).copied())
+            .unwrap_or(0)
+    }
+
+    /// Flushes everything of `file_sets` that was written into `dest` in `phase` to disk, along
+    /// with the parent of its fenced directory
+    ///
+    /// Nothing is done for phase 0, which is not fenced. Entries that could not be flushed are
+    /// logged, since the copy itself succeeded.
+    pub fn flush(&self, phase: usize, file_sets: &FileSets, dest: &str) {
+        let fence = match phase.checked_sub(1).and_then(|i| self.fences.get(i)) {
+            Some(fence) => fence,
+            None => return,
+        };
+        let dest = Path::new(dest);
+
+        file_sets
+            .files()
+            .par_iter()
+            .filter(|file| self.phase(file.path()) == phase)
+            .for_each(|file| {
+                if let Err(e) = dirfd::open_file(dest, file.path()).and_then(|f| f.sync_all()) {
+                    error!(
+                        "Fence Error -- Flushing {:?}: {}",
+                        dest.join(file.path()),
+                        e
+                    );
+                }
+            });
+
+        // Directories are flushed after their files, so that the entries of the files are too
+        let parent = fence.parent().unwrap_or_else(|| Path::new(""));
+        file_sets
+            .dirs()
+            .par_iter()
+            .map(|dir| dir.path().as_path())
+            .filter(|path| self.phase(path) == phase)
+            .chain(rayon::iter::once(parent))
+            .for_each(|path| {
+                if let Err(e) = platform::sync_dir(&dest.join(path)) {
+                    error!("Fence Error -- Flushing {:?}: {}", dest.join(path), e);
+                }
+            });
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_fence {
+    use super::*;
+
+    #[test]
+    fn phases() {
+        let dirs: HashSet<Dir> = ["in", "in/b", "in/a", "in/a/deep", "out"]
+            .iter()
+            .map(|dir| Dir::from(dir))
+            .collect();
+        let globs = [Glob::new("in/*").unwrap(), Glob::new("**/deep").unwrap()];
+        let phases = Phases::new(&globs, &dirs);
+
+        assert_eq!(phases.count(), 4);
+        assert_eq!(phases.phase(Path::new("in")), 0);
+        assert_eq!(phases.phase(Path::new("in/file")), 0);
+        assert_eq!(phases.phase(Path::new("out/file")), 0);
+        assert_eq!(phases.phase(Path::new("in/a")), 1);
+        assert_eq!(phases.phase(Path::new("in/a/file")), 1);
+        assert_eq!(phases.phase(Path::new("in/a/deep/file")), 2);
+        assert_eq!(phases.phase(Path::new("in/b/c/file")), 3);
+
+        // Files are never fences, even if a glob matches them
+        assert_eq!(phases.phase(Path::new("in/c")), 0);
+
+        let phases = Phases::new(&[], &dirs);
+        assert_eq!(phases.count(), 1);
+        assert_eq!(phases.phase(Path::new("in/a")), 0);
+    }
+}
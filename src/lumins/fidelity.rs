@@ -0,0 +1,184 @@
+//! Reports the metadata that a run could not preserve, so that it is known how faithful a copy is
+//!
+//! Every owner, extended attribute, ACL, symlink, or special file that could not be copied is
+//! recorded in `STATS`, along with why, and is reported at the end of the run, and as JSON with
+//! `--fidelity-report`.
+
+use std::fmt::Display;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::lumins::json;
+use crate::progress::STATS;
+
+/// Enum to represent the metadata that was lost
+#[derive(Eq, PartialEq, Ord, PartialOrd, Debug, Clone, Copy)]
+pub enum Loss {
+    /// The owner could not be set, such as when chown is denied
+    Owner,
+    /// The extended attributes could not be copied, such as when one is too large for the
+    /// destination
+    Xattrs,
+    /// The ACLs could not be copied
+    Acls,
+    /// The symlink could not be created, such as on filesystems without symlinks
+    Symlink,
+    /// The socket, FIFO, or device node was skipped without `--specials`, or could not be
+    /// created
+    Special,
+    /// The file was still being written, so it was skipped with `--skip-growing`, or copied as it
+    /// was at the end of the run
+    Growing,
+}
+
+impl Loss {
+    /// Gets the name of the metadata in reports
+    pub fn name(self) -> &'static str {
+        match self {
+            Loss::Owner => "owner",
+            Loss::Xattrs => "xattrs",
+            Loss::Acls => "acls",
+            Loss::Symlink => "symlink",
+            Loss::Special => "special",
+            Loss::Growing => "growing",
+        }
+    }
+}
+
+/// Metadata of one entry that could not be preserved
+#[derive(Eq, PartialEq, Ord, PartialOrd, Debug, Clone)]
+pub struct Degradation {
+    pub path: PathBuf,
+    pub loss: Loss,
+    pub reason: String,
+}
+
+/// Records that the `loss` metadata of `path` could not be preserved, because of `reason`
+pub fn record(loss: Loss, path: &Path, reason: &dyn Display) {
+    STATS.add_degradation(Degradation {
+        path: path.to_path_buf(),
+        loss,
+        reason: reason.to_string(),
+    });
+}
+
+/// Formats `degradations` as the fidelity report printed at the end of a run
+///
+/// # Returns
+/// * Some: The report, with one degradation per line
+/// * None: If there are no degradations, so that the copy is faithful
+pub fn summary(degradations: &[Degradation]) -> Option<String> {
+    if degradations.is_empty() {
+        return None;
+    }
+    let mut summary = match degradations.len() {
+        1 => String::from("Fidelity report: 1 piece of metadata could not be preserved:"),
+        n => format!(
+            "Fidelity report: {} pieces of metadata could not be preserved:",
+            n
+        ),
+    };
+    for degradation in sorted(degradations) {
+        summary.push_str(&format!(
+            "\n  {:<8} {:?}: {}",
+            degradation.loss.name(),
+            degradation.path,
+            degradation.reason
+        ));
+    }
+    Some(summary)
+}
+
+/// Serializes `degradations` as a JSON fidelity report, with one degradation per line
+pub fn to_json(degradations: &[Degradation]) -> String {
+    let mut json = format!(
+        "{{\n  \"faithful\": {},\n  \"degraded\": [",
+        degradations.is_empty()
+    );
+    for (i, degradation) in sorted(degradations).iter().enumerate() {
+        json.push_str(if i == 0 { "\n    " } else { ",\n    " });
+        json.push_str(&format!(
+            "{{\"path\": {}, \"metadata\": \"{}\", \"reason\": {}}}",
+            json::string(&degradation.path.to_string_lossy()),
+            degradation.loss.name(),
+            json::string(&degradation.reason)
+        ));
+    }
+    json.push_str(if degradations.is_empty() {
+        "]\n}\n"
+    } else {
+        "\n  ]\n}\n"
+    });
+    json
+}
+
+/// Writes `degradations` as a JSON fidelity report to `path`, see `to_json`
+///
+/// # Errors
+/// This function will return an error if `path` could not be written
+pub fn write_report(path: &Path, degradations: &[Degradation]) -> Result<(), io::Error> {
+    fs::write(path, to_json(degradations)).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("Fidelity Error -- Writing {:?}: {}", path, e),
+        )
+    })
+}
+
+/// Sorts `degradations` by path, then by the metadata lost
+fn sorted(degradations: &[Degradation]) -> Vec<&Degradation> {
+    let mut sorted: Vec<&Degradation> = degradations.iter().collect();
+    sorted.sort();
+    sorted
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_fidelity {
+    use super::*;
+
+    #[test]
+    fn reports() {
+        let degradation = |path: &str, loss, reason: &str| Degradation {
+            path: PathBuf::from(path),
+            loss,
+            reason: String::from(reason),
+        };
+        let degradations = [
+            degradation("b", Loss::Xattrs, "Argument list too long"),
+            degradation("a", Loss::Owner, "Operation \"not\" permitted"),
+        ];
+
+        assert_eq!(summary(&[]), None);
+        assert!(summary(&degradations[..1])
+            .unwrap()
+            .starts_with("Fidelity report: 1 piece of metadata could not be preserved:\n"));
+        assert_eq!(
+            summary(&degradations).unwrap(),
+            "Fidelity report: 2 pieces of metadata could not be preserved:\n  \
+             owner    \"a\": Operation \"not\" permitted\n  \
+             xattrs   \"b\": Argument list too long"
+        );
+
+        assert_eq!(
+            to_json(&[]),
+            "{\n  \"faithful\": true,\n  \"degraded\": []\n}\n"
+        );
+        let report = json::parse(&to_json(&degradations)).unwrap();
+        assert_eq!(
+            report,
+            json::parse(
+                "{\"faithful\": false, \"degraded\": [\
+                 {\"path\": \"a\", \"metadata\": \"owner\", \
+                 \"reason\": \"Operation \\\"not\\\" permitted\"}, \
+                 {\"path\": \"b\", \"metadata\": \"xattrs\", \
+                 \"reason\": \"Argument list too long\"}]}"
+            )
+            .unwrap()
+        );
+    }
+}
@@ -1,28 +1,57 @@
 //! Contains utilities for copying, deleting, sorting, hashing files.
 
-use std::fs::OpenOptions;
-use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::cmp::Reverse;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::io::Read;
 use std::marker::Sync;
 use std::path::{Path, PathBuf};
-use std::{fs, io};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 
 use blake2::{Blake2b, Digest};
-use hashbrown::HashSet;
-use log::{error, info};
+use clap::ValueEnum;
+use crossbeam_channel::{unbounded, Sender};
+use hashbrown::{HashMap, HashSet};
+use log::{debug, error, info, trace, warn};
 use rayon::prelude::*;
 use seahash;
 
-use crate::lumins::parse::Flag;
-use crate::progress::PROGRESS_BAR;
+use crate::lumins::dirfd::{self, DirHandle, EntryKind, IoStrategy};
+use crate::lumins::fidelity::{self, Loss};
+use crate::lumins::filter::Filter;
+use crate::lumins::parse::Options;
+use crate::lumins::{hashcache, metadata, platform, sandbox, workers};
+use crate::progress::{PROGRESS_BAR, STATS};
+
+/// Enum to represent the order in which files are copied, with `--order`
+#[derive(Eq, PartialEq, Debug, Clone, Copy, ValueEnum)]
+pub enum Order {
+    /// The smallest files first
+    SmallestFirst,
+    /// The largest files first
+    LargestFirst,
+    /// In order of their paths
+    Alphabetical,
+    /// The order in which the files were listed
+    #[value(name = "none")]
+    Unsorted,
+}
 
 /// Interface for all file structs to perform common operations
 ///
 /// Ensures that all files (file, dir, symlink) have
 /// a way of obtaining their path, copying, and deleting
+///
+/// `remove` and `copy` take base directories, and operate on `path()` relative to them,
+/// so that no component of `path()` is followed if it is a symlink. See `dirfd`.
+/// `copy` logs its own errors, and returns whether the file itself was copied.
 pub trait FileOps {
     fn path(&self) -> &PathBuf;
-    fn remove(&self, path: &PathBuf);
-    fn copy(&self, src: &PathBuf, dest: &PathBuf);
+    fn size(&self) -> u64;
+    fn remove(&self, location: &Path);
+    fn copy(&self, src: &Path, dest: &Path, options: &Options) -> bool;
 }
 
 /// A struct that represents a single file
@@ -36,20 +65,33 @@ impl FileOps for File {
     fn path(&self) -> &PathBuf {
         &self.path
     }
-    fn remove(&self, path: &PathBuf) {
-        match fs::remove_file(&path) {
-            Ok(_) => info!("Deleting file {:?}", path),
+    fn size(&self) -> u64 {
+        self.size
+    }
+    fn remove(&self, location: &Path) {
+        let path = full_path(location, &self.path);
+        if let Err(e) = platform::clear_readonly(&path) {
+            error!("Error -- Clearing read-only attribute {:?}: {}", path, e);
+        }
+        match dirfd::remove_file(location, &self.path) {
+            Ok(_) => {
+                info!("Deleting file {:?}", path);
+                STATS.add_deleted();
+            }
             Err(e) => error!("Error -- Deleting file {:?}: {}", path, e),
         }
     }
-    fn copy(&self, src: &PathBuf, dest: &PathBuf) {
-        match fs::copy(&src, &dest) {
-            Ok(_) => info!("Copying file {:?} -> {:?}", src, dest),
-            Err(e) => error!("Error -- Copying file {:?}: {}", src, e),
-        }
+    fn copy(&self, src: &Path, dest: &Path, options: &Options) -> bool {
+        self.copy_as(src, dest, &self.path, options)
     }
 }
 
+/// Files of a run that were still being written when they were copied, with the source and
+/// destination they are copied between and the path they are copied to, to copy once everything
+/// else of the run is, see `copy_deferred`
+#[derive(Debug, Default)]
+pub struct Deferred(Mutex<Vec<(PathBuf, PathBuf, File, PathBuf)>>);
+
 impl File {
     pub fn from(path: &str, size: u64) -> Self {
         File {
@@ -58,46 +100,158 @@ impl File {
         }
     }
 
-    #[allow(unused)]
-    #[allow(clippy::unused_io_amount)]
-    fn diff_copy(src: &PathBuf, dest: &PathBuf) -> Result<(), io::Error> {
-        if !Path::new(&dest).exists() {
-            fs::copy(&src, &dest)?;
+    /// Copies the file like `FileOps::copy`, to `to` inside `dest` rather than to its own path,
+    /// such as for a single file that is copied under another name
+    pub fn copy_as(&self, src: &Path, dest: &Path, to: &Path, options: &Options) -> bool {
+        if let Some(hook) = &options.hook_pre {
+            if let Err(e) = hook.run(&full_path(src, &self.path), &full_path(dest, to), to) {
+                error!(
+                    "Hook Error -- {:?} is skipped, since --hook-pre failed: {}",
+                    full_path(src, &self.path),
+                    e
+                );
+                return false;
+            }
         }
-
-        const CHUNK_SIZE: usize = 10000;
-
-        let src_file = fs::File::open(&src)?;
-        let mut src_reader = BufReader::with_capacity(CHUNK_SIZE, &src_file);
-        let dest_file = OpenOptions::new()
-            .write(true)
-            .read(true)
-            .create(true)
-            .open(&dest)?;
-        dest_file.set_len(src_file.metadata()?.len())?;
-        let mut dest_reader = BufReader::with_capacity(CHUNK_SIZE, &dest_file);
-        let mut dest_writer = BufWriter::with_capacity(CHUNK_SIZE, &dest_file);
-
-        loop {
-            let mut src_buffer = [0; CHUNK_SIZE];
-            let mut dest_buffer = [0; CHUNK_SIZE];
-
-            if src_reader.read(&mut src_buffer)? == 0 {
-                break;
+        match self.copy_unless_changed(src, dest, to, options, true) {
+            Err(_) if options.skip_growing => {
+                warn!(
+                    "{:?} is skipped, since it is still being written",
+                    full_path(src, &self.path)
+                );
+                fidelity::record(
+                    Loss::Growing,
+                    &self.path,
+                    &"it was still being written, so it was skipped",
+                );
+                false
             }
-            dest_reader.read(&mut dest_buffer)?;
-
-            if seahash::hash(&src_buffer) != seahash::hash(&dest_buffer) {
-                dest_writer.write(&src_buffer)?;
-            } else {
-                dest_writer.seek(SeekFrom::Current(CHUNK_SIZE as i64));
+            // Files that are still being written are copied again once everything else is
+            Err(_) => {
+                debug!(
+                    "{:?} is deferred, since it is still being written",
+                    full_path(src, &self.path)
+                );
+                let deferred = (
+                    src.to_path_buf(),
+                    dest.to_path_buf(),
+                    self.clone(),
+                    to.to_path_buf(),
+                );
+                options.deferred.0.lock().unwrap().push(deferred);
+                false
             }
+            Ok(copied) => copied,
         }
+    }
 
-        Ok(())
+    /// Copies the file like `File::copy_as`, unless `check_changes` is set and the file is still
+    /// being written
+    ///
+    /// A file is still being written if its size is no longer the size it was listed with, or
+    /// if its size or modification time changed while it was copied, in which case it is left
+    /// as it was in `dest`, see `dirfd::copy_file`, unless it is copied in place.
+    ///
+    /// # Returns
+    /// * Ok: Whether the file was copied
+    /// * Error: If the file is still being written
+    fn copy_unless_changed(
+        &self,
+        src: &Path,
+        dest: &Path,
+        to: &Path,
+        options: &Options,
+        check_changes: bool,
+    ) -> Result<bool, dirfd::Changed> {
+        let src_file = full_path(src, &self.path);
+        let dest_file = full_path(dest, to);
+        if check_changes && fs::symlink_metadata(&src_file).is_ok_and(|m| m.len() != self.size) {
+            return Err(dirfd::Changed);
+        }
+        // Files that are missing from dest replace nothing
+        let missing = match &options.lookup {
+            Some(lookup) => lookup.is_missing(dest, to),
+            None => false,
+        };
+        if !missing {
+            if let Err(e) = platform::clear_readonly(&dest_file) {
+                error!(
+                    "Error -- Clearing read-only attribute {:?}: {}",
+                    dest_file, e
+                );
+            }
+            // A replaced file is kept until the run completes
+            if let Some(quarantine) = &options.quarantine {
+                if !quarantine.keep(to) {
+                    return Ok(false);
+                }
+            }
+        }
+        let inplace = options.inplace;
+        let io = IoStrategy {
+            check_changes,
+            ..io(options)
+        };
+        let copied = options.retry.run(&src_file, || {
+            dirfd::copy_file_as(src, &self.path, dest, to, options.reflink, inplace, io)
+                .and_then(|copied| platform::copy_attributes(&src_file, &dest_file).map(|_| copied))
+        });
+        // Whatever was written of the file is no longer missing from dest
+        if let Some(lookup) = &options.lookup {
+            lookup.insert(dest, to);
+        }
+        match copied {
+            Ok(copied) => {
+                info!("Copying file {:?} -> {:?}", src_file, dest_file);
+                STATS.add_copied(copied);
+            }
+            Err(ref e) if dirfd::is_changed(e) => return Err(dirfd::Changed),
+            Err(e) => {
+                error!("Error -- Copying file {:?}: {}", src_file, e);
+                return Ok(false);
+            }
+        }
+        copy_metadata_as(src, &self.path, dest, to, options);
+        // The hash is cached last, since it is only valid for the final modification time
+        if options.xattr_hash_cache {
+            cache_hash(dest, to);
+        }
+        if let Some(hook) = &options.hook_post {
+            if let Err(e) = hook.run(&src_file, &dest_file, to) {
+                error!("Hook Error -- --hook-post of {:?} failed: {}", dest_file, e);
+            }
+        }
+        Ok(true)
     }
 }
 
+/// Copies the files that the run of `options` deferred, since they were still being written when
+/// they were copied, now that everything else is copied, see `File::copy`
+///
+/// Files that are still being written are copied as they are, and listed in the fidelity
+/// report.
+pub fn copy_deferred(options: &Options) {
+    let deferred = std::mem::take(&mut *options.deferred.0.lock().unwrap());
+    deferred.par_iter().for_each(|(src, dest, file, to)| {
+        let copied = match file.copy_unless_changed(src, dest, to, options, true) {
+            Ok(copied) => copied,
+            Err(_) => {
+                fidelity::record(
+                    Loss::Growing,
+                    file.path(),
+                    &"it was still being written at the end of the run, so it was copied as it was",
+                );
+                // Safe to unwrap, since changes are not checked
+                file.copy_unless_changed(src, dest, to, options, false)
+                    .unwrap()
+            }
+        };
+        if copied {
+            record_completed(file, src, options);
+        }
+    });
+}
+
 /// A struct that represents a single directory
 #[derive(Hash, Eq, PartialEq, Debug, Clone)]
 pub struct Dir {
@@ -108,17 +262,35 @@ impl FileOps for Dir {
     fn path(&self) -> &PathBuf {
         &self.path
     }
-    fn remove(&self, path: &PathBuf) {
-        match fs::remove_dir(&path) {
+    fn size(&self) -> u64 {
+        0
+    }
+    fn remove(&self, location: &Path) {
+        let path = full_path(location, &self.path);
+        if let Err(e) = platform::clear_readonly(&path) {
+            error!("Error -- Clearing read-only attribute {:?}: {}", path, e);
+        }
+        match dirfd::remove_dir(location, &self.path) {
             Ok(_) => info!("Deleting dir {:?}", path),
             Err(e) => error!("Error -- Deleting dir {:?}: {}", path, e),
         }
     }
-    fn copy(&self, _src: &PathBuf, dest: &PathBuf) {
-        match fs::create_dir_all(&dest) {
-            Ok(_) => info!("Creating dir {:?}", dest),
-            Err(e) => error!("Error -- Creating dir {:?}: {}", dest, e),
+    fn copy(&self, src: &Path, dest: &Path, options: &Options) -> bool {
+        let src_dir = full_path(src, &self.path);
+        let dest_dir = full_path(dest, &self.path);
+        let created = options.retry.run(&src_dir, || {
+            dirfd::create_dir_all(dest, &self.path)
+                .and_then(|_| platform::copy_attributes(&src_dir, &dest_dir))
+        });
+        match created {
+            Ok(_) => info!("Creating dir {:?}", dest_dir),
+            Err(e) => {
+                error!("Error -- Creating dir {:?}: {}", dest_dir, e);
+                return false;
+            }
         }
+        copy_metadata(src, dest, &self.path, options);
+        true
     }
 }
 
@@ -141,36 +313,74 @@ impl FileOps for Symlink {
     fn path(&self) -> &PathBuf {
         &self.path
     }
-    fn remove(&self, path: &PathBuf) {
-        match fs::remove_file(&path) {
+    fn size(&self) -> u64 {
+        0
+    }
+    fn remove(&self, location: &Path) {
+        let path = full_path(location, &self.path);
+        if let Err(e) = platform::clear_readonly(&path) {
+            error!("Error -- Clearing read-only attribute {:?}: {}", path, e);
+        }
+        match dirfd::remove_file(location, &self.path) {
             Ok(_) => info!("Deleting symlink {:?}", path),
             Err(e) => error!("Error -- Deleting symlink {:?}: {}", path, e),
         }
     }
     #[cfg(target_family = "unix")]
-    fn copy(&self, _src: &PathBuf, dest: &PathBuf) {
-        use std::os::unix::fs;
-
-        match fs::symlink(&self.target, &dest) {
-            Ok(_) => info!("Creating symlink {:?} -> {:?}", dest, self.target),
-            Err(e) => error!("Error -- Creating symlink {:?}: {}", dest, e),
+    fn copy(&self, src: &Path, dest: &Path, options: &Options) -> bool {
+        let dest_link = full_path(dest, &self.path);
+        if let Err(e) = self.check_sandbox(options) {
+            error!("Error -- Creating symlink {:?}: {}", dest_link, e);
+            return false;
         }
+        match dirfd::symlink(&self.target, dest, &self.path) {
+            Ok(_) => info!("Creating symlink {:?} -> {:?}", dest_link, self.target),
+            Err(e) => {
+                error!("Error -- Creating symlink {:?}: {}", dest_link, e);
+                fidelity::record(Loss::Symlink, &dest_link, &e);
+                return false;
+            }
+        }
+        copy_owner(src, dest, &self.path, options);
+        true
     }
     #[cfg(target_family = "windows")]
-    fn copy(&self, _src: &PathBuf, dest: &PathBuf) {
+    fn copy(&self, src: &Path, dest: &Path, options: &Options) -> bool {
         use std::os::windows::fs;
-        if self.target.is_file() {
-            match fs::symlink_file(&self.target, &dest) {
-                Ok(_) => info!("Creating symlink file {:?} -> {:?}", dest, self.target),
-                Err(e) => error!("Error -- Creating symlink file{:?}: {}", dest, e),
-            }
+
+        let src = &full_path(src, &self.path);
+        let dest = &full_path(dest, &self.path);
+        if let Err(e) = self.check_sandbox(options) {
+            error!("Error -- Creating symlink {:?}: {}", dest, e);
+            return false;
         }
-        if self.target.is_dir() {
-            match fs::symlink_dir(&self.target, &dest) {
+
+        // Relative targets are relative to the directory containing the symlink
+        let target = match src.parent() {
+            Some(parent) => parent.join(&self.target),
+            None => self.target.clone(),
+        };
+
+        if target.is_dir() {
+            match fs::symlink_dir(&self.target, dest) {
                 Ok(_) => info!("Creating symlink dir {:?} -> {:?}", dest, self.target),
-                Err(e) => error!("Error -- Creating symlink dir {:?}: {}", dest, e),
+                Err(e) => {
+                    error!("Error -- Creating symlink dir {:?}: {}", dest, e);
+                    fidelity::record(Loss::Symlink, dest, &e);
+                    return false;
+                }
+            }
+        } else {
+            match fs::symlink_file(&self.target, dest) {
+                Ok(_) => info!("Creating symlink file {:?} -> {:?}", dest, self.target),
+                Err(e) => {
+                    error!("Error -- Creating symlink file {:?}: {}", dest, e);
+                    fidelity::record(Loss::Symlink, dest, &e);
+                    return false;
+                }
             }
         }
+        true
     }
 }
 
@@ -181,6 +391,92 @@ impl Symlink {
             target: PathBuf::from(target),
         }
     }
+
+    /// Gets the path that the symlink points to
+    pub fn target(&self) -> &PathBuf {
+        &self.target
+    }
+
+    /// Checks that the symlink leads nowhere outside of the destination, if `options` sandbox
+    /// it, see `sandbox::check_symlink`
+    fn check_sandbox(&self, options: &Options) -> Result<(), io::Error> {
+        if sandbox::is_sandboxed(options, false) {
+            sandbox::check_symlink(&self.path, &self.target)?;
+        }
+        Ok(())
+    }
+
+    /// Gets the same symlink, pointing to `target` instead
+    pub fn with_target(&self, target: PathBuf) -> Self {
+        Symlink {
+            path: self.path.clone(),
+            target,
+        }
+    }
+}
+
+/// A struct that represents a single socket, FIFO, or device node
+#[derive(Hash, Eq, PartialEq, Debug, Clone)]
+pub struct Special {
+    path: PathBuf,
+    /// Type and permission bits, like `st_mode`
+    mode: u32,
+    /// Device number of device nodes, like `st_rdev`
+    rdev: u64,
+}
+
+impl FileOps for Special {
+    fn path(&self) -> &PathBuf {
+        &self.path
+    }
+    fn size(&self) -> u64 {
+        0
+    }
+    fn remove(&self, location: &Path) {
+        let path = full_path(location, &self.path);
+        match dirfd::remove_file(location, &self.path) {
+            Ok(_) => info!("Deleting special file {:?}", path),
+            Err(e) => error!("Error -- Deleting special file {:?}: {}", path, e),
+        }
+    }
+    #[cfg(target_family = "unix")]
+    fn copy(&self, src: &Path, dest: &Path, options: &Options) -> bool {
+        let dest_special = full_path(dest, &self.path);
+        if !options.specials {
+            info!("Skipping special file {:?}", full_path(src, &self.path));
+            fidelity::record(
+                Loss::Special,
+                &dest_special,
+                &"special files are only created with --specials",
+            );
+            return false;
+        }
+        match dirfd::mknod(dest, &self.path, self.mode, self.rdev) {
+            Ok(_) => info!("Creating special file {:?}", dest_special),
+            Err(e) => {
+                error!("Error -- Creating special file {:?}: {}", dest_special, e);
+                fidelity::record(Loss::Special, &dest_special, &e);
+                return false;
+            }
+        }
+        copy_owner(src, dest, &self.path, options);
+        true
+    }
+    #[cfg(target_family = "windows")]
+    fn copy(&self, _src: &Path, _dest: &Path, _options: &Options) -> bool {
+        // Special files are never listed on Windows
+        false
+    }
+}
+
+impl Special {
+    pub fn from(path: &str, mode: u32, rdev: u64) -> Self {
+        Special {
+            path: PathBuf::from(path),
+            mode,
+            rdev,
+        }
+    }
 }
 
 /// A struct that represents sets of different types of files
@@ -189,6 +485,11 @@ pub struct FileSets {
     files: HashSet<File>,
     dirs: HashSet<Dir>,
     symlinks: HashSet<Symlink>,
+    specials: HashSet<Special>,
+    /// Whether every entry of the tree could be listed
+    complete: bool,
+    /// Directories whose entries were not listed, see `get_files_within`
+    bounded: HashSet<PathBuf>,
 }
 
 impl FileSets {
@@ -206,8 +507,15 @@ impl FileSets {
             files,
             dirs,
             symlinks,
+            specials: HashSet::new(),
+            complete: true,
+            bounded: HashSet::new(),
         }
     }
+    /// Gets the same FileSets, with `specials` as its set of special files
+    pub fn with_specials(self, specials: HashSet<Special>) -> Self {
+        FileSets { specials, ..self }
+    }
     /// Gets the set of files
     ///
     /// # Returns
@@ -229,87 +537,638 @@ impl FileSets {
     pub fn symlinks(&self) -> &HashSet<Symlink> {
         &self.symlinks
     }
+    /// Gets the set of sockets, FIFOs, and device nodes
+    ///
+    /// # Returns
+    /// The FileSets set of special files
+    pub fn specials(&self) -> &HashSet<Special> {
+        &self.specials
+    }
+    /// Checks whether every entry of the tree could be listed, so that entries that are not in
+    /// the sets are known not to exist
+    pub fn is_complete(&self) -> bool {
+        self.complete
+    }
+    /// Checks whether `path` is a directory whose entries were not listed, since it is at the
+    /// deepest level, or on another filesystem, see `get_files_within`
+    pub fn is_bounded(&self, path: &Path) -> bool {
+        self.bounded.contains(path)
+    }
+    /// Keeps only the files for which `f` returns true
+    pub fn retain_files<F>(&mut self, f: F)
+    where
+        F: FnMut(&File) -> bool,
+    {
+        self.files.retain(f);
+    }
+    /// Keeps only the files, dirs, symlinks, and special files whose paths `f` returns true for
+    pub fn retain_paths<F>(&mut self, f: F)
+    where
+        F: Fn(&Path) -> bool,
+    {
+        self.files.retain(|file| f(&file.path));
+        self.dirs.retain(|dir| f(&dir.path));
+        self.symlinks.retain(|symlink| f(&symlink.path));
+        self.specials.retain(|special| f(&special.path));
+    }
+    /// Replaces every symlink with what `f` returns for it
+    pub fn map_symlinks<F>(&mut self, f: F)
+    where
+        F: Fn(Symlink) -> Symlink + Sync + Send,
+    {
+        self.symlinks = std::mem::take(&mut self.symlinks)
+            .into_par_iter()
+            .map(f)
+            .collect();
+    }
+    /// Removes all symlinks from the set of symlinks, and returns them
+    pub fn take_symlinks(&mut self) -> HashSet<Symlink> {
+        std::mem::take(&mut self.symlinks)
+    }
+    /// Moves every entry that `f` returns a new path for to that path
+    pub fn rename_paths<F>(&mut self, f: F)
+    where
+        F: Fn(&Path) -> Option<PathBuf>,
+    {
+        let rename = |path: &mut PathBuf| {
+            if let Some(renamed) = f(path) {
+                *path = renamed;
+            }
+        };
+        self.files = (std::mem::take(&mut self.files).into_iter())
+            .map(|mut file| {
+                rename(&mut file.path);
+                file
+            })
+            .collect();
+        self.dirs = (std::mem::take(&mut self.dirs).into_iter())
+            .map(|mut dir| {
+                rename(&mut dir.path);
+                dir
+            })
+            .collect();
+        self.symlinks = (std::mem::take(&mut self.symlinks).into_iter())
+            .map(|mut symlink| {
+                rename(&mut symlink.path);
+                symlink
+            })
+            .collect();
+        self.specials = (std::mem::take(&mut self.specials).into_iter())
+            .map(|mut special| {
+                rename(&mut special.path);
+                special
+            })
+            .collect();
+        self.bounded = (std::mem::take(&mut self.bounded).into_iter())
+            .map(|mut path| {
+                rename(&mut path);
+                path
+            })
+            .collect();
+    }
+    /// Removes the file at `path` from the set of files, if there is one
+    pub fn remove_file(&mut self, path: &Path) {
+        self.files.retain(|file| file.path != path);
+    }
+    /// Adds every entry of `other` to these sets
+    ///
+    /// The smaller sets are added to the larger ones, so that merging the listings of
+    /// subdirectories does not copy large sets over and over.
+    fn extend(&mut self, mut other: FileSets) {
+        if other.files.len() + other.dirs.len() > self.files.len() + self.dirs.len() {
+            std::mem::swap(self, &mut other);
+        }
+        self.files.extend(other.files);
+        self.dirs.extend(other.dirs);
+        self.symlinks.extend(other.symlinks);
+        self.specials.extend(other.specials);
+        self.complete &= other.complete;
+        self.bounded.extend(other.bounded);
+    }
+    /// Replaces whatever is at `path` with a directory
+    pub fn replace_with_dir(&mut self, path: &Path) {
+        self.retain_paths(|other| other != path);
+        self.dirs.insert(Dir {
+            path: path.to_path_buf(),
+        });
+    }
 }
 
 /// Compares all files in `files_to_compare` in `src` with all files in `files_to_compare` in `dest`
 /// and copies them over if they are different, in parallel
 ///
+/// Comparing and copying are pipelined, so that files which have already been found to differ
+/// are copied by the copy workers of the run while the remaining files are still being hashed,
+/// see `workers::copy_workers`. Files to copy are handed to the copy workers through a bounded
+/// queue, so hashing never runs too far ahead of copying. Files are compared, and so copied,
+/// with small files interleaved among large ones, see `for_each_prioritized`, or with
+/// `options.order`, in that order, see `for_each_in_order`.
+///
 /// # Arguments
 /// * `files_to_compare`: files to compare
 /// * `src`: base directory of the files to copy from, such that for all `file` in
-/// `files_to_compare`, `src + file.path()` is the absolute path of the source file
+///   `files_to_compare`, `src + file.path()` is the absolute path of the source file
 /// * `dest`: base directory of the files to copy to, such that for all `file` in
-/// `files_to_compare`, `dest + file.path()` is the absolute path of the destination file
-/// * `flags`: set for Flag's
-pub fn compare_and_copy_files<'a, T, S>(files_to_compare: T, src: &str, dest: &str, flags: Flag)
-where
+///   `files_to_compare`, `dest + file.path()` is the absolute path of the destination file
+/// * `options`: Options of the run
+pub fn compare_and_copy_files<'a, T, S>(
+    files_to_compare: T,
+    src: &str,
+    dest: &str,
+    options: &Options,
+) where
     T: ParallelIterator<Item = &'a S>,
-    S: FileOps + Sync + 'a,
+    S: FileOps + Clone + Send + Sync + 'static,
 {
-    files_to_compare.for_each(|file| {
-        compare_and_copy_file(file, src, dest, flags);
-        PROGRESS_BAR.inc(2);
-    });
+    // Every queued copy holds a clone of `done`, so `finished` disconnects once all are done
+    let (done, finished) = unbounded::<()>();
+    let shared_options = Arc::new(options.clone());
+    let copy_workers = workers::copy_workers(options);
+
+    let compare = |done: &Sender<()>, file: &S| {
+        if STATS.stopped() {
+            return;
+        }
+        if !file_differs(file, src, dest, options) {
+            copy_file_metadata(file, src, dest, options);
+            record_completed(file, Path::new(src), options);
+            PROGRESS_BAR.inc(2);
+            return;
+        }
+
+        PROGRESS_BAR.inc(1);
+
+        let file = file.clone();
+        let src = PathBuf::from(src);
+        let dest = PathBuf::from(dest);
+        let options = shared_options.clone();
+        let done = done.clone();
+        copy_workers.execute(move || {
+            if !STATS.stopped() && file.copy(&src, &dest, &options) {
+                record_completed(&file, &src, &options);
+            }
+            PROGRESS_BAR.inc(1);
+            drop(done);
+        });
+    };
+    let files = Vec::from_par_iter(files_to_compare);
+    match options.order {
+        Some(order) => for_each_in_order(files, order, |file| compare(&done, file)),
+        None => for_each_prioritized(files, |file| compare(&done, file)),
+    }
+    drop(done);
+
+    // Wait for the remaining copies to finish
+    let _ = finished.recv();
 }
 
-/// Compares the given file and copies the src file over if it differs from the dest file
+/// Compares the given file in `src` with the given file in `dest`
 ///
 /// # Arguments
 /// * `file_to_compare`: file to compare
 /// * `src`: base directory of the file to copy from, such that `src + file.path()`
-/// is the absolute path of the source file
+///   is the absolute path of the source file
 /// * `dest`: base directory of the files to copy to, such that `dest + file.path()`
-/// is the absolute path of the destination file
-/// * `flags`: set for Flag's
-fn compare_and_copy_file<S>(file_to_compare: &S, src: &str, dest: &str, flags: Flag)
+///   is the absolute path of the destination file
+/// * `options`: Options of the run
+///
+/// # Returns
+/// Whether the src file differs from the dest file, or could not be hashed
+pub fn file_differs<S>(file_to_compare: &S, src: &str, dest: &str, options: &Options) -> bool
+where
+    S: FileOps,
+{
+    file_differs_as(file_to_compare, file_to_compare, src, dest, options)
+}
+
+/// Compares the file `src_file` in `src` with the file `dest_file` in `dest`, like
+/// `file_differs`, for files that are written under another name
+fn file_differs_as<S>(src_file: &S, dest_file: &S, src: &str, dest: &str, options: &Options) -> bool
 where
     S: FileOps,
 {
-    if flags.contains(Flag::SECURE) {
-        let src_file_hash_secure = hash_file_secure(file_to_compare, &src);
+    let dest_path = full_path(Path::new(dest), dest_file.path());
+    let differs = if options.secure {
+        let src_file_hash_secure = hash_file_secure(src_file, src);
 
         if src_file_hash_secure.is_none() {
-            copy_file(file_to_compare, &src, &dest);
-            return;
+            debug!(
+                "{:?} is copied, since its source could not be hashed",
+                dest_path
+            );
+            return true;
         }
 
-        let dest_file_hash_secure = hash_file_secure(file_to_compare, &dest);
+        src_file_hash_secure != hash_dest_secure(dest_file, dest, options)
+    } else {
+        let src_file_hash = hash_file(src_file, src);
 
-        if src_file_hash_secure != dest_file_hash_secure {
-            copy_file(file_to_compare, &src, &dest);
+        if src_file_hash.is_none() {
+            debug!(
+                "{:?} is copied, since its source could not be hashed",
+                dest_path
+            );
+            return true;
         }
+
+        src_file_hash != hash_file(dest_file, dest)
+    };
+
+    if differs {
+        debug!(
+            "{:?} is copied, since its hash differs from its source",
+            dest_path
+        );
     } else {
-        let src_file_hash = hash_file(file_to_compare, &src);
+        debug!(
+            "{:?} is skipped, since its hash matches its source",
+            dest_path
+        );
+    }
+    differs
+}
 
-        if src_file_hash.is_none() {
-            copy_file(file_to_compare, &src, &dest);
+/// Compares `file` inside `src` with the file `to` inside `dest` that it is written under, like
+/// `file_differs`
+///
+/// # Returns
+/// Whether the files differ, which they do if there is no file of the same size at `to`
+pub fn file_differs_at(file: &File, src: &str, dest: &str, to: &Path, options: &Options) -> bool {
+    let copy = File {
+        path: to.to_path_buf(),
+        size: file.size,
+    };
+    let same_size = fs::symlink_metadata(full_path(Path::new(dest), to))
+        .is_ok_and(|metadata| metadata.is_file() && metadata.len() == file.size);
+    !same_size || file_differs_as(file, &copy, src, dest, options)
+}
+
+/// Compares `file` inside `src` with the file `to` inside `dest` that it is written under, and
+/// copies it if they differ, like `compare_and_copy_files`, such as for files that are written
+/// under shortened or sanitized names
+///
+/// Files that are kept as they are, see `keeps_existing_at`, are left alone, and copies that
+/// have the same size and hash as their sources only have their metadata copied.
+pub fn compare_and_copy_file_as(file: &File, src: &str, dest: &str, to: &Path, options: &Options) {
+    let (src_root, dest_root) = (Path::new(src), Path::new(dest));
+    let dest_file = full_path(dest_root, to);
+    if keeps_existing_at(&full_path(src_root, &file.path), &dest_file, options) {
+        return;
+    }
+    if !file_differs_at(file, src, dest, to, options) {
+        copy_metadata_as(src_root, &file.path, dest_root, to, options);
+        record_completed(file, src_root, options);
+    } else if file.copy_as(src_root, dest_root, to, options) {
+        record_completed(file, src_root, options);
+    }
+}
+
+/// Checks whether the file at `relative` inside `dest` is kept as it is, instead of being
+/// overwritten by `relative` inside `src`
+///
+/// With `Options::ignore_existing`, every file that exists in `dest` is kept. With
+/// `Options::update`, files in `dest` are kept unless their sources were modified after them. With
+/// `Options::heal_empty_files`, empty files in `dest` are never kept when their sources are not
+/// empty.
+pub fn keeps_existing(relative: &Path, src: &str, dest: &str, options: &Options) -> bool {
+    // Files that are missing from dest have nothing to keep
+    if let Some(lookup) = &options.lookup {
+        if lookup.is_missing(Path::new(dest), relative) {
+            return false;
+        }
+    }
+    let src_file = full_path(Path::new(src), relative);
+    keeps_existing_at(&src_file, &full_path(Path::new(dest), relative), options)
+}
+
+/// Checks whether the file `dest_file` is kept as it is, instead of being overwritten by
+/// `src_file`, like `keeps_existing`
+pub fn keeps_existing_at(src_file: &Path, dest_file: &Path, options: &Options) -> bool {
+    if options.heal_empty_files && is_healed(src_file, dest_file) {
+        debug!(
+            "{:?} is healed, since it is empty while its source is not",
+            dest_file
+        );
+        return false;
+    }
+    if options.ignore_existing && fs::symlink_metadata(dest_file).is_ok() {
+        debug!("{:?} is skipped, since it already exists", dest_file);
+        return true;
+    }
+    if options.update {
+        let mtime = |path: &Path| fs::symlink_metadata(path).and_then(|m| m.modified()).ok();
+        let src_mtime = mtime(src_file);
+        if let (Some(src_mtime), Some(dest_mtime)) = (src_mtime, mtime(dest_file)) {
+            // Destinations that keep coarser times round the times of copies
+            let capabilities = options.capabilities.unwrap_or_default();
+            if capabilities.modified_by(src_mtime, dest_mtime) {
+                debug!(
+                    "{:?} is skipped, since it is not older than its source",
+                    dest_file
+                );
+                return true;
+            }
+        }
+    }
+    false
+}
+
+/// Checks whether `dest_file` is an empty file while `src_file` is not, such as one left behind by
+/// a copy that failed part way, so that it is overwritten whatever its modification time, see
+/// `Options::heal_empty_files`
+pub fn is_healed(src_file: &Path, dest_file: &Path) -> bool {
+    let size = |path: &Path| {
+        fs::symlink_metadata(path)
+            .ok()
+            .filter(|metadata| metadata.is_file())
+            .map(|metadata| metadata.len())
+    };
+    size(dest_file) == Some(0) && size(src_file).is_some_and(|size| size > 0)
+}
+
+/// Finds files that were renamed in `src`, by matching the sizes and hashes of files that are
+/// only in `dest` with those of files that are only in `src`
+///
+/// Empty files are never matched, since they are as cheap to copy as to rename.
+///
+/// # Arguments
+/// * `dest_only`: files in `dest` that are not in `src`
+/// * `src_only`: files in `src` that are not in `dest`
+/// * `src`: base directory of the files in `src_only`
+/// * `dest`: base directory of the files in `dest_only`
+/// * `options`: Options of the run
+///
+/// # Returns
+/// Pairs of `(old, new)`, such that `dest + old.path()` has the same contents as
+/// `src + new.path()`. Every file is in at most one pair.
+pub fn find_renames<'a>(
+    dest_only: Vec<&'a File>,
+    src_only: Vec<&'a File>,
+    src: &str,
+    dest: &str,
+    options: &Options,
+) -> Vec<(&'a File, &'a File)> {
+    // Only hash files with a size that is on both sides
+    let dest_sizes: HashSet<u64> = dest_only.iter().map(|file| file.size).collect();
+    let src_only: Vec<&File> = src_only
+        .into_iter()
+        .filter(|file| file.size > 0 && dest_sizes.contains(&file.size))
+        .collect();
+    let src_sizes: HashSet<u64> = src_only.iter().map(|file| file.size).collect();
+    let dest_only: Vec<&File> = dest_only
+        .into_iter()
+        .filter(|file| src_sizes.contains(&file.size))
+        .collect();
+
+    let hash_all = |files: Vec<&'a File>, location: &str| -> Vec<((u64, Vec<u8>), &'a File)> {
+        files
+            .into_par_iter()
+            .filter_map(|file| rename_key(file, location, options).map(|key| (key, file)))
+            .collect()
+    };
+
+    let mut old_files: HashMap<(u64, Vec<u8>), Vec<&File>> = HashMap::new();
+    for (key, file) in hash_all(dest_only, dest) {
+        old_files.entry(key).or_default().push(file);
+    }
+
+    hash_all(src_only, src)
+        .into_iter()
+        .filter_map(|(key, new)| {
+            old_files
+                .get_mut(&key)
+                .and_then(|old| old.pop())
+                .map(|old| (old, new))
+        })
+        .inspect(|(old, new)| {
+            debug!(
+                "{:?} is renamed to {:?}, since they have the same size and hash",
+                old.path(),
+                new.path()
+            )
+        })
+        .collect()
+}
+
+/// Gets the size and hash of a file, which identify it when looking for renames
+///
+/// # Returns
+/// * Some: The size and hash of the file, using the hash function selected by `options`
+/// * None: If the file could not be hashed
+fn rename_key(file: &File, location: &str, options: &Options) -> Option<(u64, Vec<u8>)> {
+    let hash = if options.secure {
+        hash_file_secure(file, location)?
+    } else {
+        hash_file(file, location)?.to_le_bytes().to_vec()
+    };
+
+    Some((file.size, hash))
+}
+
+/// Renames files in `dest` to match renames in `src`, in parallel
+///
+/// The permissions and the metadata selected by `options` are then copied from `src`.
+/// If a file can not be renamed, it is copied and the old file is deleted instead.
+///
+/// # Arguments
+/// * `renames`: pairs of `(old, new)`, as returned by `find_renames`
+/// * `src`: base directory of the new files
+/// * `dest`: base directory of the old files, which the new files are renamed in
+/// * `options`: Options of the run
+pub fn rename_files(renames: &[(&File, &File)], src: &str, dest: &str, options: &Options) {
+    renames.par_iter().for_each(|(old, new)| {
+        if STATS.stopped() {
             return;
         }
+        let from = full_path(Path::new(dest), old.path());
+        let to = full_path(Path::new(dest), new.path());
+
+        // The file that a rename replaces is kept until the run completes
+        if let Some(quarantine) = &options.quarantine {
+            if !quarantine.keep(new.path()) {
+                PROGRESS_BAR.inc(2);
+                return;
+            }
+        }
 
-        let dest_file_hash = hash_file(file_to_compare, &dest);
+        let renamed = dirfd::rename(Path::new(dest), old.path(), new.path()).and_then(|_| {
+            if io(options).skip_permissions {
+                return Ok(());
+            }
+            let permissions = dirfd::open_file(Path::new(src), new.path())?
+                .metadata()?
+                .permissions();
+            dirfd::open_file(Path::new(dest), new.path())?.set_permissions(permissions)
+        });
 
-        if src_file_hash != dest_file_hash {
-            copy_file(file_to_compare, &src, &dest);
+        match renamed {
+            Ok(_) => {
+                info!("Renaming file {:?} -> {:?}", from, to);
+                copy_file_metadata(*new, src, dest, options);
+            }
+            Err(e) => {
+                error!("Error -- Renaming file {:?}: {}", from, e);
+                copy_file(*new, src, dest, options);
+                match &options.quarantine {
+                    Some(quarantine) => {
+                        quarantine.keep(old.path());
+                    }
+                    None => old.remove(Path::new(dest)),
+                }
+            }
         }
+        PROGRESS_BAR.inc(2);
+    });
+}
+
+/// Creates all given directories from `src` in `dest`, one depth at a time, in parallel
+///
+/// Every directory is created after its parent, so no worker creates a parent that another
+/// worker is also creating, and deep trees are still created in parallel at each depth.
+///
+/// # Arguments
+/// * `dirs_to_create`: directories to create
+/// * `src`: base directory of the directories to copy from, such that for all `dir` in
+///   `dirs_to_create`, `src + dir.path()` is the absolute path of the source directory
+/// * `dest`: base directory of the directories to create, such that for all `dir` in
+///   `dirs_to_create`, `dest + dir.path()` is the absolute path of the destination directory
+/// * `options`: Options of the run
+pub fn create_dirs<'a, T>(dirs_to_create: T, src: &str, dest: &str, options: &Options)
+where
+    T: ParallelIterator<Item = &'a Dir>,
+{
+    let mut dirs = Vec::from_par_iter(dirs_to_create);
+    dirs.par_sort_unstable_by_key(|dir| dir.path.components().count());
+
+    for depth in dirs.chunk_by(|a, b| a.path.components().count() == b.path.components().count()) {
+        depth.par_iter().for_each(|dir| {
+            if STATS.stopped() {
+                return;
+            }
+            copy_file(*dir, src, dest, options);
+            PROGRESS_BAR.inc(1);
+        });
     }
 }
 
 /// Copies all given files from `src` to `dest` in parallel
 ///
+/// Small files are interleaved with large ones, so that bursts of large files do not hold up
+/// the quick wins, see `for_each_prioritized`, unless `options.order` is given, see
+/// `for_each_in_order`.
+///
 /// # Arguments
 /// * `files_to_copy`: files to copy
 /// * `src`: base directory of the files to copy from, such that for all `file` in
-/// `files_to_copy`, `src + file.path()` is the absolute path of the source file
+///   `files_to_copy`, `src + file.path()` is the absolute path of the source file
 /// * `dest`: base directory of the files to copy to, such that for all `file` in
-/// `files_to_copy`, `dest + file.path()` is the absolute path of the destination file
-pub fn copy_files<'a, T, S>(files_to_copy: T, src: &str, dest: &str)
+///   `files_to_copy`, `dest + file.path()` is the absolute path of the destination file
+/// * `options`: Options of the run
+pub fn copy_files<'a, T, S>(files_to_copy: T, src: &str, dest: &str, options: &Options)
 where
     T: ParallelIterator<Item = &'a S>,
     S: FileOps + Sync + 'a,
 {
-    files_to_copy.for_each(|file| {
-        copy_file(file, &src, &dest);
+    let copy = |file: &S| {
+        if STATS.stopped() {
+            return;
+        }
+        copy_file(file, src, dest, options);
         PROGRESS_BAR.inc(1);
+    };
+    let files = Vec::from_par_iter(files_to_copy);
+    match options.order {
+        Some(order) => for_each_in_order(files, order, copy),
+        None => for_each_prioritized(files, copy),
+    }
+}
+
+/// Calls `f` on all given files in parallel, starting on them in `order`
+///
+/// Every worker of the global rayon pool takes the next file that is left, so files are started
+/// in order, and finish roughly in order. Files of the same size are taken in the order they are
+/// given.
+///
+/// # Arguments
+/// * `files`: files to call `f` on
+/// * `order`: order to call `f` on the files in
+/// * `f`: function to call on each file exactly once
+pub fn for_each_in_order<'a, S, F>(mut files: Vec<&'a S>, order: Order, f: F)
+where
+    S: FileOps + Sync + 'a,
+    F: Fn(&'a S) + Sync,
+{
+    match order {
+        Order::SmallestFirst => files.par_sort_by_key(|file| file.size()),
+        Order::LargestFirst => files.par_sort_by_key(|file| Reverse(file.size())),
+        Order::Alphabetical => files.par_sort_by(|a, b| a.path().cmp(b.path())),
+        Order::Unsorted => {}
+    }
+
+    let next = AtomicUsize::new(0);
+    rayon::scope(|scope| {
+        for _ in 0..rayon::current_num_threads() {
+            let (files, next, f) = (&files, &next, &f);
+            scope.spawn(move |_| {
+                while let Some(&file) = files.get(next.fetch_add(1, Ordering::Relaxed)) {
+                    f(file);
+                }
+            });
+        }
+    });
+}
+
+/// Calls `f` on all given files in parallel, always keeping a fraction of the workers on
+/// small files
+///
+/// The workers are tasks on the global rayon pool, so no threads are started or stopped
+/// between phases. Files are split into small and large files by `SMALL_FILE_SIZE`.
+/// A quarter of the workers (at least one) take small files first, while the rest take large
+/// files first. Workers only move on to the other kind of file once there are none left of the
+/// kind they prefer.
+///
+/// # Arguments
+/// * `files`: files to call `f` on
+/// * `f`: function to call on each file exactly once
+pub fn for_each_prioritized<'a, S, F>(files: Vec<&'a S>, f: F)
+where
+    S: FileOps + Sync + 'a,
+    F: Fn(&'a S) + Sync,
+{
+    const SMALL_FILE_SIZE: u64 = 1 << 20;
+
+    let (small, large): (Vec<&S>, Vec<&S>) = files
+        .into_iter()
+        .partition(|file| file.size() < SMALL_FILE_SIZE);
+
+    let workers = rayon::current_num_threads();
+    let small_workers = (workers / 4).max(1);
+    let next_small = AtomicUsize::new(0);
+    let next_large = AtomicUsize::new(0);
+
+    // Takes the next file from `queue`, if there are any left
+    let take = |queue: &[&'a S], next: &AtomicUsize| -> Option<&'a S> {
+        queue.get(next.fetch_add(1, Ordering::Relaxed)).copied()
+    };
+
+    rayon::scope(|scope| {
+        for worker in 0..workers {
+            let (preferred, other) = if worker < small_workers {
+                ((&small, &next_small), (&large, &next_large))
+            } else {
+                ((&large, &next_large), (&small, &next_small))
+            };
+            let take = &take;
+            let f = &f;
+
+            scope.spawn(move |_| {
+                while let Some(file) =
+                    take(preferred.0, preferred.1).or_else(|| take(other.0, other.1))
+                {
+                    f(file);
+                }
+            });
+        }
     });
 }
 
@@ -318,19 +1177,162 @@ where
 /// # Arguments
 /// * `files_to_copy`: file to copy
 /// * `src`: base directory of the files to copy from, such that `src + file_to_copy.path()`
-/// is the absolute path of the source file
+///   is the absolute path of the source file
 /// * `dest`: base directory of the files to copy to, such that `dest + file.path()`
-/// is the absolute path of the destination file
-fn copy_file<S>(file_to_copy: &S, src: &str, dest: &str)
+///   is the absolute path of the destination file
+/// * `options`: Options of the run
+fn copy_file<S>(file_to_copy: &S, src: &str, dest: &str, options: &Options)
 where
     S: FileOps,
 {
-    let src_file = [&PathBuf::from(&src), file_to_copy.path()].iter().collect();
-    let dest_file = [&PathBuf::from(&dest), file_to_copy.path()]
-        .iter()
-        .collect();
+    if file_to_copy.copy(Path::new(src), Path::new(dest), options) {
+        record_completed(file_to_copy, Path::new(src), options);
+    }
+}
+
+/// Records a completed file in the journal of `options`, if there is one
+fn record_completed<S>(file: &S, src: &Path, options: &Options)
+where
+    S: FileOps,
+{
+    if let Some(journal) = &options.journal {
+        journal.record(src, file.path());
+    }
+}
+
+/// Copies the metadata selected by `options` of all given files from `src` to `dest` in parallel,
+/// without copying their contents
+///
+/// # Arguments
+/// * `files`: files whose metadata to copy
+/// * `src`: base directory of the files to copy from, such that for all `file` in
+///   `files`, `src + file.path()` is the absolute path of the source file
+/// * `dest`: base directory of the files to copy to, such that for all `file` in
+///   `files`, `dest + file.path()` is the absolute path of the destination file
+/// * `options`: Options of the run
+pub fn copy_files_metadata<'a, T, S>(files: T, src: &str, dest: &str, options: &Options)
+where
+    T: ParallelIterator<Item = &'a S>,
+    S: FileOps + Sync + 'a,
+{
+    files
+        .filter(|_| !STATS.stopped())
+        .for_each(|file| copy_file_metadata(file, src, dest, options));
+}
+
+/// Copies the metadata selected by `options` of a single file from `src` to `dest`
+///
+/// # Arguments
+/// * `file`: file whose metadata to copy
+/// * `src`: base directory of the file to copy from, such that `src + file.path()`
+///   is the absolute path of the source file
+/// * `dest`: base directory of the file to copy to, such that `dest + file.path()`
+///   is the absolute path of the destination file
+/// * `options`: Options of the run
+fn copy_file_metadata<S>(file: &S, src: &str, dest: &str, options: &Options)
+where
+    S: FileOps,
+{
+    copy_metadata(Path::new(src), Path::new(dest), file.path(), options);
+}
+
+/// Determines how the contents of files are copied where they go through a buffer, from
+/// `options`
+pub fn io(options: &Options) -> IoStrategy {
+    IoStrategy {
+        direct_io: options.direct_io,
+        buffer_size: options.buffer_size,
+        fsync: options.fsync,
+        check_changes: true,
+        append: options.append_verify,
+        skip_permissions: options
+            .capabilities
+            .is_some_and(|capabilities| !capabilities.permissions),
+    }
+}
+
+/// Flushes the directories `dirs` of `dest`, and then `dest` itself, to disk, so that the
+/// entries written into them survive a crash, see `Options::fsync`
+///
+/// # Errors
+/// This function will return an error if any directory could not be flushed
+pub fn sync_dirs(dirs: &HashSet<Dir>, dest: &str) -> Result<(), io::Error> {
+    dirs.par_iter()
+        .try_for_each(|dir| sync_dir(dir.path(), dest))?;
+    sync_dir(Path::new(""), dest)
+}
+
+/// Flushes the directory `dir` of `dest` to disk, like `sync_dirs`, such as once the entries of
+/// one directory are written by runs that write each directory as soon as it is listed
+///
+/// # Errors
+/// This function will return an error if the directory could not be flushed
+pub fn sync_dir(dir: &Path, dest: &str) -> Result<(), io::Error> {
+    let path = Path::new(dest).join(dir);
+    platform::sync_dir(&path).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("Sync Error -- Flushing {:?}: {}", path, e),
+        )
+    })
+}
+
+/// Copies the metadata selected by `options` of `relative` inside `src` to `relative` inside
+/// `dest`
+fn copy_metadata(src: &Path, dest: &Path, relative: &Path, options: &Options) {
+    copy_metadata_as(src, relative, dest, relative, options);
+}
+
+/// Copies the metadata selected by `options` of `from` inside `src` to `to` inside `dest`
+///
+/// The owner is copied first, since changing it may clear some extended attributes
+fn copy_metadata_as(src: &Path, from: &Path, dest: &Path, to: &Path, options: &Options) {
+    copy_owner_as(src, from, dest, to, options);
+
+    let src_file = full_path(src, from);
+    let dest_file = full_path(dest, to);
+
+    if options.xattrs {
+        if let Err(e) = metadata::copy_xattrs(&src_file, &dest_file) {
+            error!("Error -- Copying xattrs {:?}: {}", src_file, e);
+            fidelity::record(Loss::Xattrs, &dest_file, &e);
+        }
+    }
+    if options.acls {
+        if let Err(e) = metadata::copy_acls(&src_file, &dest_file) {
+            error!("Error -- Copying ACLs {:?}: {}", src_file, e);
+            fidelity::record(Loss::Acls, &dest_file, &e);
+        }
+    }
+}
+
+/// Copies the owner of `relative` inside `src` to `relative` inside `dest`, mapped by the
+/// `idmap` of `options`
+///
+/// Nothing is done if `options` has no `idmap`
+#[cfg(target_family = "unix")]
+fn copy_owner(src: &Path, dest: &Path, relative: &Path, options: &Options) {
+    copy_owner_as(src, relative, dest, relative, options);
+}
+
+/// Copies the owner of `from` inside `src` to `to` inside `dest`, like `copy_owner`
+fn copy_owner_as(src: &Path, from: &Path, dest: &Path, to: &Path, options: &Options) {
+    let idmap = match &options.idmap {
+        Some(idmap) => idmap,
+        None => return,
+    };
+
+    #[cfg(target_family = "unix")]
+    {
+        let map = |uid, gid| (idmap.uid(uid), idmap.gid(gid));
+        if let Err(e) = dirfd::copy_owner_as(src, from, dest, to, map) {
+            error!("Error -- Copying owner {:?}: {}", full_path(src, from), e);
+            fidelity::record(Loss::Owner, &full_path(dest, to), &e);
+        }
+    }
 
-    file_to_copy.copy(&src_file, &dest_file);
+    #[cfg(not(target_family = "unix"))]
+    let _ = (idmap, src, from, dest, to);
 }
 
 /// Deletes all given files in parallel
@@ -340,15 +1342,17 @@ where
 /// # Arguments
 /// `files_to_delete`: files to delete
 /// * `location`: base directory of the files to delete, such that for all `file` in
-/// `files_to_delete`, `location + file.path()` is the absolute path of the file
+///   `files_to_delete`, `location + file.path()` is the absolute path of the file
 pub fn delete_files<'a, T, S>(files_to_delete: T, location: &str)
 where
     T: ParallelIterator<Item = &'a S>,
     S: FileOps + Sync + 'a,
 {
     files_to_delete.for_each(|file| {
-        let path = [&PathBuf::from(&location), file.path()].iter().collect();
-        file.remove(&path);
+        if STATS.stopped() {
+            return;
+        }
+        file.remove(Path::new(location));
         PROGRESS_BAR.inc(1);
     });
 }
@@ -360,19 +1364,47 @@ where
 /// # Arguments
 /// * `files_to_delete`: files to delete, or sorted empty directories
 /// * `location`: base directory of the files to delete, such that for all `file` in
-/// `files_to_delete`, `location + file.path()` is the absolute path of the file
+///   `files_to_delete`, `location + file.path()` is the absolute path of the file
 pub fn delete_files_sequential<'a, T, S>(files_to_delete: T, location: &str)
 where
     T: IntoIterator<Item = &'a S>,
     S: FileOps + 'a,
 {
     for file in files_to_delete {
-        let path = [&PathBuf::from(&location), file.path()].iter().collect();
-        file.remove(&path);
+        if STATS.stopped() {
+            return;
+        }
+        file.remove(Path::new(location));
         PROGRESS_BAR.inc(1);
     }
 }
 
+/// Deletes the directories of `dirs` inside `location` that are empty, deepest first, so that
+/// directories that only held empty directories are deleted along with them
+///
+/// Directories that hold anything are left as they are.
+///
+/// # Returns
+/// The number of directories that were deleted
+pub fn prune_empty_dirs<'a, T>(dirs: T, location: &str) -> usize
+where
+    T: ParallelIterator<Item = &'a Dir>,
+{
+    let mut pruned = 0;
+    for dir in sort_files(dirs.filter(|dir| !dir.path().as_os_str().is_empty())) {
+        let path = full_path(Path::new(location), dir.path());
+        match dirfd::remove_dir(Path::new(location), dir.path()) {
+            Ok(_) => {
+                info!("Pruning empty dir {:?}", path);
+                pruned += 1;
+            }
+            Err(e) if e.kind() == io::ErrorKind::DirectoryNotEmpty => {}
+            Err(e) => error!("Error -- Pruning dir {:?}: {}", path, e),
+        }
+    }
+    pruned
+}
+
 /// Sorts (unstable) file paths in descending order by number of components, in parallel
 ///
 /// # Arguments
@@ -404,7 +1436,7 @@ where
 /// # Arguments
 /// * `file_to_hash`: file object to hash
 /// * `location`: base directory of the file to hash, such that
-/// `location + file_to_hash.path()` is the absolute path of the file
+///   `location + file_to_hash.path()` is the absolute path of the file
 ///
 /// # Returns
 /// * Some: The hash of the given file
@@ -413,12 +1445,12 @@ pub fn hash_file<S>(file_to_hash: &S, location: &str) -> Option<u64>
 where
     S: FileOps,
 {
-    let file: PathBuf = [&PathBuf::from(&location), file_to_hash.path()]
-        .iter()
-        .collect();
+    let mut contents = Vec::new();
 
-    match fs::read(file) {
-        Ok(contents) => Some(seahash::hash(&contents)),
+    match dirfd::open_file(Path::new(location), file_to_hash.path())
+        .and_then(|mut file| file.read_to_end(&mut contents))
+    {
+        Ok(_) => Some(seahash::hash(&contents)),
         Err(_) => None,
     }
 }
@@ -428,7 +1460,7 @@ where
 /// # Arguments
 /// * `file_to_hash`: file object to hash
 /// * `location`: base directory of the file to hash, such that
-/// `location + file_to_hash.path()` is the absolute path of the file
+///   `location + file_to_hash.path()` is the absolute path of the file
 ///
 /// # Returns
 /// * Some: The hash of the given file
@@ -437,32 +1469,71 @@ pub fn hash_file_secure<S>(file_to_hash: &S, location: &str) -> Option<Vec<u8>>
 where
     S: FileOps,
 {
-    let file: PathBuf = [&PathBuf::from(&location), file_to_hash.path()]
-        .iter()
-        .collect();
+    hash_secure(Path::new(location), file_to_hash.path())
+}
 
-    match &mut fs::File::open(&file) {
+/// Generates a BLAKE2b hash of `relative` inside `location`, see `hash_file_secure`
+pub fn hash_secure(location: &Path, relative: &Path) -> Option<Vec<u8>> {
+    match &mut dirfd::open_file(location, relative) {
         Ok(file) => {
             let mut hasher = Blake2b::new();
 
             match io::copy(file, &mut hasher) {
                 Ok(_) => Some(hasher.finalize().to_vec()),
                 Err(e) => {
-                    error!("Error -- Hashing: {:?}: {}", file_to_hash.path(), e);
+                    error!("Error -- Hashing: {:?}: {}", relative, e);
                     None
                 }
             }
         }
         Err(e) => {
-            error!("Error -- Opening File: {:?}: {}", file_to_hash.path(), e);
+            error!("Error -- Opening File: {:?}: {}", relative, e);
             None
         }
     }
 }
 
+/// Generates a BLAKE2b hash of the given destination file, like `hash_file_secure`, but answers
+/// from the hash cached in its extended attributes when `Options::xattr_hash_cache` is set
+///
+/// Files without a valid cached hash are hashed, and their hashes are cached. See `hashcache`.
+fn hash_dest_secure<S>(file_to_hash: &S, location: &str, options: &Options) -> Option<Vec<u8>>
+where
+    S: FileOps,
+{
+    if !options.xattr_hash_cache {
+        return hash_file_secure(file_to_hash, location);
+    }
+    let path = full_path(Path::new(location), file_to_hash.path());
+    match hashcache::cached_hash(&path) {
+        Some(hash) => {
+            trace!("Using the cached hash of {:?}", path);
+            Some(hash)
+        }
+        None => cache_hash(Path::new(location), file_to_hash.path()),
+    }
+}
+
+/// Hashes `relative` inside `location`, and caches its hash in its extended attributes
+///
+/// # Returns
+/// * Some: The hash of the file
+/// * None: If the file could not be hashed
+fn cache_hash(location: &Path, relative: &Path) -> Option<Vec<u8>> {
+    let hash = hash_secure(location, relative)?;
+    let path = full_path(location, relative);
+    if let Err(e) = hashcache::cache_hash(&path, &hash) {
+        error!("Error -- Caching hash {:?}: {}", path, e);
+    }
+    Some(hash)
+}
+
 /// Recursively traverses a directory and all its subdirectories and returns
 /// a FileSets that contains all files and all directories
 ///
+/// Subdirectories are opened relative to their parent's handle, and symlinks to directories
+/// are never followed. See `dirfd`.
+///
 /// # Arguments
 /// * `src`: directory to traverse
 ///
@@ -470,91 +1541,345 @@ where
 /// * Ok: A `FileSets` containing a set of files a set of directories
 /// * Error: If `src` is an invalid directory
 pub fn get_all_files(src: &str) -> Result<FileSets, io::Error> {
-    get_all_files_helper(&PathBuf::from(&src), &src)
+    let dir = DirHandle::open(Path::new(src))?;
+    get_all_files_helper(&dir, Path::new(""), None, None, None)
 }
 
-/// Recursive helper for `get_all_files`
+/// Traverses a directory like `get_all_files`, but within the bounds that `options` set
+///
+/// With `options.max_depth`, only that many levels below `src` are listed, where entries
+/// directly in `src` are at depth 1. With `Options::one_file_system`, directories on other
+/// filesystems than `src`, such as mount points, are not descended into. Directories at either
+/// bound are listed, but what is in them is not, see `FileSets::is_bounded`. Entries that
+/// `options.filter` excludes are not listed at all.
 ///
 /// # Arguments
 /// * `src`: directory to traverse
-/// * `base`: directory to traverse, used for recursive calls
+/// * `options`: Options of the run
 ///
 /// # Returns
 /// * Ok: A `FileSets` containing a set of files a set of directories
 /// * Error: If `src` is an invalid directory
-fn get_all_files_helper(src: &PathBuf, base: &str) -> Result<FileSets, io::Error> {
-    let dir = src.read_dir()?;
+pub fn get_files_within(src: &str, options: &Options) -> Result<FileSets, io::Error> {
+    let dir = DirHandle::open(Path::new(src))?;
+    let device = if options.one_file_system {
+        Some(platform::device(Path::new(src))?)
+    } else {
+        None
+    };
+    get_all_files_helper(
+        &dir,
+        Path::new(""),
+        options.max_depth,
+        device,
+        options.filter.as_ref(),
+    )
+}
 
-    let mut files = HashSet::new();
-    let mut dirs = HashSet::new();
-    let mut symlinks = HashSet::new();
+/// Lists the entries of `src` at `paths`, and the directories that they are in, instead of
+/// traversing `src`, see `filelist`
+///
+/// Directories at `paths` are listed, but not descended into, and entries that `filter`
+/// excludes, or that are in directories that it excludes, are left out. Entries that can not be
+/// read are logged, and leave the listing incomplete.
+pub fn get_files_listed(src: &str, paths: &[PathBuf], filter: Option<&Filter>) -> FileSets {
+    let kinds: Vec<_> = paths
+        .par_iter()
+        .map(|path| {
+            dirfd::open_parent(Path::new(src), path, false)
+                .and_then(|(parent, name)| parent.kind(&name))
+        })
+        .collect();
 
-    for file in dir {
-        if file.is_err() {
-            error!("{}", file.err().unwrap());
+    let mut file_sets = FileSets::with(HashSet::new(), HashSet::new(), HashSet::new());
+    for (path, kind) in paths.iter().zip(kinds) {
+        let kind = match kind {
+            Ok(kind) => kind,
+            Err(e) => {
+                error!("Error -- Reading metadata of {:?} {}", path, e);
+                file_sets.complete = false;
+                continue;
+            }
+        };
+        if filter.is_some_and(|filter| filter.excludes_within(path, kind == EntryKind::Dir)) {
             continue;
         }
+        for dir in path.ancestors().skip(1) {
+            if dir != Path::new("") {
+                let path = dir.to_path_buf();
+                file_sets.dirs.insert(Dir { path });
+            }
+        }
+        let path = path.clone();
+        match kind {
+            EntryKind::Dir => {
+                file_sets.dirs.insert(Dir { path });
+            }
+            EntryKind::File(size) => {
+                file_sets.files.insert(File { path, size });
+            }
+            EntryKind::Symlink(target) => {
+                file_sets.symlinks.insert(Symlink { path, target });
+            }
+            EntryKind::Special { mode, rdev } => {
+                file_sets.specials.insert(Special { path, mode, rdev });
+            }
+        }
+    }
+    file_sets
+}
 
-        let file = file.unwrap();
-        let metadata = file.metadata();
+/// Joins `base` and `path` into the path used to access a file
+///
+/// # Arguments
+/// * `base`: base directory of the file
+/// * `path`: path of the file, relative to `base`
+///
+/// # Returns
+/// `base + path`, in a form that is not limited by the platform's maximum path length
+fn full_path(base: &Path, path: &Path) -> PathBuf {
+    platform::long_path(&base.join(path))
+}
 
-        if metadata.is_err() {
-            error!(
-                "Error -- Reading metadata of {:?} {}",
-                file.path(),
-                metadata.err().unwrap()
-            );
-            continue;
-        }
+/// Traverses `src` like `get_files_within`, but hands each directory to `visit` as soon as it is
+/// listed, instead of listing the whole tree first
+///
+/// Directories are listed by the workers of the global rayon pool, which steal the directories
+/// that are left to list from each other, while `visit` writes what was listed. So copying starts
+/// with the first directory, and only the entries of directories that are being visited are held
+/// in memory.
+///
+/// # Arguments
+/// * `src`: directory to traverse
+/// * `options`: Options of the run
+/// * `visit`: function called with the path of each directory, relative to `src`, and the entries
+///   directly in it. It is called for a directory before it is called for any of its
+///   subdirectories, and subdirectories that it removes from the entries are not descended into
+///
+/// # Errors
+/// This function will return an error if `src` is an invalid directory. Directories below it that
+/// can not be read are logged and skipped
+pub fn walk<F>(src: &str, options: &Options, visit: &F) -> Result<(), io::Error>
+where
+    F: Fn(&Path, &mut FileSets) + Sync,
+{
+    let dir = DirHandle::open(Path::new(src))?;
+    let device = if options.one_file_system {
+        Some(platform::device(Path::new(src))?)
+    } else {
+        None
+    };
+    rayon::scope(|scope| {
+        walk_helper(
+            scope,
+            Arc::new(dir),
+            PathBuf::new(),
+            options.max_depth,
+            device,
+            options.filter.as_ref(),
+            visit,
+        )
+    });
+    Ok(())
+}
 
-        let metadata = metadata.unwrap();
+/// Recursive helper for `walk`, see `get_all_files_helper`
+///
+/// Subdirectories are only opened once a worker takes them, so the directories that are left to
+/// list hold no descriptors of their own, only their parents do.
+fn walk_helper<'a, F>(
+    scope: &rayon::Scope<'a>,
+    dir: Arc<DirHandle>,
+    relative: PathBuf,
+    max_depth: Option<usize>,
+    device: Option<u64>,
+    filter: Option<&'a Filter>,
+    visit: &'a F,
+) where
+    F: Fn(&Path, &mut FileSets) + Sync,
+{
+    let (mut file_sets, sub_dirs) = match list_dir(&dir, &relative, max_depth, device, filter) {
+        Ok(listed) => listed,
+        Err(e) => {
+            error!("Error - Retrieving files: {}", e);
+            return;
+        }
+    };
+    visit(&relative, &mut file_sets);
 
-        let path = file.path();
-        // This is safe to unwrap, since `get_all_files` always calls this helper
-        // with `base` equal to `src`
-        let relative_path = path.strip_prefix(base).unwrap();
+    for name in sub_dirs {
+        let path = relative.join(&name);
+        if !file_sets.dirs.contains(&Dir { path: path.clone() }) {
+            continue;
+        }
+        let dir = Arc::clone(&dir);
+        let max_depth = max_depth.map(|depth| depth - 1);
+        scope.spawn(move |scope| match dir.open_dir(&name) {
+            Ok(sub_dir) => {
+                // The parent is no longer needed once the subdirectory is open
+                drop(dir);
+                walk_helper(
+                    scope,
+                    Arc::new(sub_dir),
+                    path,
+                    max_depth,
+                    device,
+                    filter,
+                    visit,
+                )
+            }
+            Err(e) => error!("Error - Retrieving files: {:?}: {}", path, e),
+        });
+    }
+}
 
-        if metadata.is_dir() {
-            dirs.insert(Dir {
-                path: relative_path.to_path_buf(),
-            });
+/// Lists the entries directly in the directory `relative` inside `root`, like `walk` lists them
+/// for `root`, within the bounds that `options` set for `root`
+///
+/// Directories on the way to `relative` are opened without following symlinks, see `dirfd`.
+///
+/// # Errors
+/// This function will return an error if `relative` inside `root` is not a directory, or could
+/// not be read
+pub fn list_within(root: &str, relative: &Path, options: &Options) -> Result<FileSets, io::Error> {
+    let mut dir = DirHandle::open(Path::new(root))?;
+    let device = if options.one_file_system {
+        Some(platform::device(Path::new(root))?)
+    } else {
+        None
+    };
+    for component in relative.components() {
+        dir = dir.open_dir(component.as_os_str())?;
+    }
+    let depth = relative.components().count();
+    let max_depth = options
+        .max_depth
+        .map(|max_depth| max_depth.saturating_sub(depth));
+    list_dir(&dir, relative, max_depth, device, options.filter.as_ref())
+        .map(|(file_sets, _)| file_sets)
+}
 
+/// Recursive helper for `get_all_files`
+///
+/// Subdirectories are listed in parallel, see `list_dir`.
+///
+/// # Arguments
+/// * `dir`: open directory to traverse
+/// * `relative`: path of `dir`, relative to the directory passed to `get_all_files`
+/// * `max_depth`: number of levels left to list, including `dir`'s entries, or None for all
+/// * `device`: device of the filesystem to stay on, or None to cross into any
+/// * `filter`: rules for which entries to leave out, or None to list all
+///
+/// # Returns
+/// * Ok: A `FileSets` containing a set of files a set of directories
+/// * Error: If `dir` could not be read
+fn get_all_files_helper(
+    dir: &DirHandle,
+    relative: &Path,
+    max_depth: Option<usize>,
+    device: Option<u64>,
+    filter: Option<&Filter>,
+) -> Result<FileSets, io::Error> {
+    let (mut file_sets, sub_dirs) = list_dir(dir, relative, max_depth, device, filter)?;
+
+    let max_depth = max_depth.map(|depth| depth - 1);
+    let listed = sub_dirs
+        .into_par_iter()
+        .map(|name| {
             // Recursively call `get_all_files_helper` on the subdirectory
-            match get_all_files_helper(&file.path(), base) {
-                Ok(file_sets) => {
-                    // Add subdirectory subdirectories and files to sets
-                    files.extend(file_sets.files);
-                    dirs.extend(file_sets.dirs);
-                    symlinks.extend(file_sets.symlinks);
-                }
-                Err(e) => {
+            dir.open_dir(&name)
+                .and_then(|sub_dir| {
+                    let relative = relative.join(&name);
+                    get_all_files_helper(&sub_dir, &relative, max_depth, device, filter)
+                })
+                .unwrap_or_else(|e| {
                     error!("Error - Retrieving files: {}", e);
-                    continue;
-                }
+                    FileSets {
+                        complete: false,
+                        ..FileSets::with(HashSet::new(), HashSet::new(), HashSet::new())
+                    }
+                })
+        })
+        .reduce_with(|mut a, b| {
+            a.extend(b);
+            a
+        });
+    if let Some(listed) = listed {
+        file_sets.extend(listed);
+    }
+    Ok(file_sets)
+}
+
+/// Lists the entries directly in `dir`
+///
+/// # Arguments
+/// * `dir`: open directory to list
+/// * `relative`: path of `dir`, relative to the directory that is traversed
+/// * `max_depth`: number of levels left to list, including `dir`'s entries, or None for all
+/// * `device`: device of the filesystem to stay on, or None to cross into any
+/// * `filter`: rules for which entries to leave out, or None to list all
+///
+/// # Returns
+/// * Ok: A `FileSets` of the entries, and the names of the subdirectories to descend into
+/// * Error: If `dir` could not be read
+fn list_dir(
+    dir: &DirHandle,
+    relative: &Path,
+    max_depth: Option<usize>,
+    device: Option<u64>,
+    filter: Option<&Filter>,
+) -> Result<(FileSets, Vec<OsString>), io::Error> {
+    let mut file_sets = FileSets::with(HashSet::new(), HashSet::new(), HashSet::new());
+    let mut sub_dirs = Vec::new();
+
+    for (name, kind) in dir.entries()? {
+        let path = relative.join(&name);
+        trace!("Listing {:?}", path);
+
+        let kind = match kind {
+            Ok(kind) => kind,
+            Err(e) => {
+                error!("Error -- Reading metadata of {:?} {}", path, e);
+                file_sets.complete = false;
+                continue;
             }
-        } else if metadata.is_file() {
-            files.insert(File {
-                path: relative_path.to_path_buf(),
-                size: metadata.len(),
-            });
-        } else {
-            // If not a file nor dir, must be a symlink
-            match fs::read_link(&path) {
-                Ok(target) => {
-                    symlinks.insert(Symlink {
-                        path: relative_path.to_path_buf(),
-                        target,
-                    });
-                }
-                Err(e) => {
-                    error!("Error - Reading symlink: {}", e);
-                    continue;
-                }
+        };
+
+        if filter.is_some_and(|filter| filter.excludes(&path, kind == EntryKind::Dir)) {
+            debug!("{:?} is left out, since a filter rule excludes it", path);
+            continue;
+        }
+
+        match kind {
+            // What is in directories at the deepest level, or on other filesystems, is not listed
+            EntryKind::Dir
+                if max_depth == Some(1)
+                    || device.is_some_and(|device| dir.device(&name).ok() != Some(device)) =>
+            {
+                debug!(
+                    "{:?} is not descended into, since it is out of bounds",
+                    path
+                );
+                file_sets.bounded.insert(path.clone());
+                file_sets.dirs.insert(Dir { path });
+            }
+            EntryKind::Dir => {
+                file_sets.dirs.insert(Dir { path });
+                sub_dirs.push(name);
+            }
+            EntryKind::File(size) => {
+                file_sets.files.insert(File { path, size });
+            }
+            EntryKind::Symlink(target) => {
+                file_sets.symlinks.insert(Symlink { path, target });
+            }
+            EntryKind::Special { mode, rdev } => {
+                file_sets.specials.insert(Special { path, mode, rdev });
             }
         }
     }
 
-    Ok(FileSets::with(files, dirs, symlinks))
+    Ok((file_sets, sub_dirs))
 }
 
 ///////////////////////////////////////////////////////////////////////////////////////////////////
@@ -601,17 +1926,18 @@ mod test_file_ops {
 #[cfg(test)]
 mod test_get_all_files {
     use super::*;
+    use std::fs;
     use std::process::Command;
 
     #[test]
     fn invalid_dir() {
-        assert_eq!(get_all_files("/?").is_err(), true);
+        assert!(get_all_files("/?").is_err());
     }
 
     #[cfg(target_family = "unix")]
     #[test]
     fn dir_insufficient_permissions() {
-        assert_eq!(get_all_files("/root").is_err(), true);
+        assert!(get_all_files("/root").is_err());
     }
 
     #[test]
@@ -635,7 +1961,7 @@ mod test_get_all_files {
 
         fs::create_dir_all([TEST_DIR, TEST_SUB_DIR].join("/")).unwrap();
 
-        let file_sets = get_all_files(&TEST_DIR).unwrap();
+        let file_sets = get_all_files(TEST_DIR).unwrap();
         let mut dir_set = HashSet::new();
         dir_set.insert(Dir {
             path: PathBuf::from(&TEST_SUB_DIR),
@@ -644,7 +1970,7 @@ mod test_get_all_files {
         assert_eq!(file_sets.files(), &HashSet::new());
         assert_eq!(file_sets.dirs(), &dir_set);
 
-        fs::remove_dir_all(&TEST_DIR).unwrap();
+        fs::remove_dir_all(TEST_DIR).unwrap();
     }
 
     #[test]
@@ -695,6 +2021,9 @@ mod test_get_all_files {
                 files: HashSet::new(),
                 dirs: HashSet::new(),
                 symlinks: symlink_set,
+                specials: HashSet::new(),
+                complete: true,
+                bounded: HashSet::new(),
             }
         );
 
@@ -727,9 +2056,9 @@ mod test_get_all_files {
             });
         }
 
-        for i in 0..SUB_DIRS.len() {
+        for sub_dir in &SUB_DIRS {
             dir_set.insert(Dir {
-                path: PathBuf::from(SUB_DIRS[i]),
+                path: PathBuf::from(sub_dir),
             });
         }
 
@@ -739,6 +2068,118 @@ mod test_get_all_files {
         fs::remove_dir_all(TEST_DIR).unwrap();
     }
 
+    #[test]
+    fn max_depth() {
+        const TEST_DIR: &str = "test_get_all_files_max_depth";
+
+        fs::create_dir_all([TEST_DIR, "dir1/dir2"].join("/")).unwrap();
+        fs::write([TEST_DIR, "file.txt"].join("/"), b"1").unwrap();
+        fs::write([TEST_DIR, "dir1/file.txt"].join("/"), b"1").unwrap();
+
+        let depth = |max_depth| Options {
+            max_depth: Some(max_depth),
+            ..Options::default()
+        };
+        let file_sets = get_files_within(TEST_DIR, &depth(1)).unwrap();
+        assert_eq!(
+            file_sets.files(),
+            &[File::from("file.txt", 1)].iter().cloned().collect()
+        );
+        assert_eq!(
+            file_sets.dirs(),
+            &[Dir::from("dir1")].iter().cloned().collect()
+        );
+
+        assert!(file_sets.is_bounded(Path::new("dir1")));
+
+        let file_sets = get_files_within(TEST_DIR, &depth(2)).unwrap();
+        assert_eq!(file_sets.files().len(), 2);
+        assert_eq!(file_sets.dirs().len(), 2);
+        assert!(!file_sets.is_bounded(Path::new("dir1")));
+        assert!(file_sets.is_bounded(Path::new("dir1/dir2")));
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+
+    #[test]
+    fn walk_lists_everything() {
+        use std::sync::Mutex;
+        const TEST_DIR: &str = "test_get_all_files_walk_lists_everything";
+
+        fs::create_dir_all([TEST_DIR, "dir1/dir2/dir3"].join("/")).unwrap();
+        fs::create_dir_all([TEST_DIR, "skipped/dir"].join("/")).unwrap();
+        fs::write([TEST_DIR, "file.txt"].join("/"), b"1").unwrap();
+        fs::write([TEST_DIR, "dir1/dir2/file.txt"].join("/"), b"12").unwrap();
+        fs::write([TEST_DIR, "skipped/dir/file.txt"].join("/"), b"123").unwrap();
+
+        let options = Options {
+            max_depth: Some(3),
+            ..Options::default()
+        };
+        let visited = Mutex::new(Vec::new());
+        let walked = Mutex::new(FileSets::with(
+            HashSet::new(),
+            HashSet::new(),
+            HashSet::new(),
+        ));
+        walk(TEST_DIR, &options, &|relative, file_sets| {
+            visited.lock().unwrap().push(relative.to_path_buf());
+            // Directories that are left out are not listed
+            file_sets.retain_paths(|path| !path.starts_with("skipped/dir"));
+            walked.lock().unwrap().extend(FileSets {
+                files: file_sets.files.clone(),
+                dirs: file_sets.dirs.clone(),
+                symlinks: file_sets.symlinks.clone(),
+                specials: file_sets.specials.clone(),
+                complete: file_sets.complete,
+                bounded: file_sets.bounded.clone(),
+            });
+        })
+        .unwrap();
+
+        let mut file_sets = get_files_within(TEST_DIR, &options).unwrap();
+        file_sets.retain_paths(|path| !path.starts_with("skipped/dir"));
+        let walked = walked.into_inner().unwrap();
+        assert_eq!(walked.files(), file_sets.files());
+        assert_eq!(walked.dirs(), file_sets.dirs());
+        assert!(walked.is_bounded(Path::new("dir1/dir2/dir3")));
+
+        let mut visited = visited.into_inner().unwrap();
+        visited.sort();
+        assert_eq!(
+            visited,
+            ["", "dir1", "dir1/dir2", "skipped"]
+                .iter()
+                .map(PathBuf::from)
+                .collect::<Vec<PathBuf>>()
+        );
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn one_file_system() {
+        const TEST_DIR: &str = "test_get_all_files_one_file_system";
+
+        fs::create_dir_all([TEST_DIR, "dir1/dir2"].join("/")).unwrap();
+        fs::write([TEST_DIR, "dir1/dir2/file.txt"].join("/"), b"1").unwrap();
+
+        // Everything is on the same filesystem, so everything is listed
+        let file_sets = get_files_within(
+            TEST_DIR,
+            &Options {
+                one_file_system: true,
+                ..Options::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(file_sets, get_all_files(TEST_DIR).unwrap());
+        assert!(!file_sets.is_bounded(Path::new("dir1/dir2")));
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+
     #[cfg(target_family = "unix")]
     #[test]
     fn multi_level_insufficient_permissions() {
@@ -753,11 +2194,11 @@ mod test_get_all_files {
         fs::File::create(&file_path).unwrap();
 
         Command::new("chmod")
-            .args(&["000", &file_path])
+            .args(["000", &file_path])
             .output()
             .unwrap();
         Command::new("chmod")
-            .args(&["000", &dir_path])
+            .args(["000", &dir_path])
             .output()
             .unwrap();
 
@@ -778,7 +2219,7 @@ mod test_get_all_files {
 
         Command::new("chmod")
             .arg("777")
-            .args(&["777", &dir_path])
+            .args(["777", &dir_path])
             .output()
             .unwrap();
         fs::remove_dir_all(TEST_DIR).unwrap();
@@ -851,9 +2292,47 @@ mod test_sort_files {
     }
 }
 
+#[cfg(test)]
+mod test_for_each_in_order {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[test]
+    fn starts_in_order() {
+        let files = [
+            File::from("b", 2),
+            File::from("c", 1),
+            File::from("a", 3),
+            File::from("d", 1),
+        ];
+        // With one worker, files are also finished in the order they are started
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(1)
+            .build()
+            .unwrap();
+        let order = |order| {
+            let seen = Mutex::new(Vec::new());
+            pool.install(|| {
+                for_each_in_order(files.iter().collect(), order, |file: &File| {
+                    seen.lock()
+                        .unwrap()
+                        .push(file.path().to_string_lossy().into_owned());
+                })
+            });
+            seen.into_inner().unwrap().concat()
+        };
+
+        assert_eq!(order(Order::SmallestFirst), "cdba");
+        assert_eq!(order(Order::LargestFirst), "abcd");
+        assert_eq!(order(Order::Alphabetical), "abcd");
+        assert_eq!(order(Order::Unsorted), "bcad");
+    }
+}
+
 #[cfg(test)]
 mod test_hash_file {
     use super::*;
+    use std::fs;
 
     #[test]
     fn invalid_file() {
@@ -1005,6 +2484,7 @@ mod test_hash_file {
 #[cfg(test)]
 mod test_delete_files {
     use super::*;
+    use std::fs;
 
     #[test]
     fn delete_no_files() {
@@ -1017,17 +2497,17 @@ mod test_delete_files {
         let files_to_delete_sequential: Vec<&File> = Vec::new();
         let mut file_set = HashSet::new();
 
-        for i in 0..TEST_FILES.len() {
-            fs::File::create([TEST_DIR, TEST_FILES[i]].join("/")).unwrap();
+        for test_file in &TEST_FILES {
+            fs::File::create([TEST_DIR, test_file].join("/")).unwrap();
             let file = File {
-                path: PathBuf::from(TEST_FILES[i]),
+                path: PathBuf::from(test_file),
                 size: 0,
             };
             file_set.insert(file);
         }
 
         delete_files(files_to_delete.par_iter(), TEST_DIR);
-        delete_files_sequential(files_to_delete_sequential.into_iter(), TEST_DIR);
+        delete_files_sequential(files_to_delete_sequential, TEST_DIR);
 
         assert_eq!(
             get_all_files(TEST_DIR).unwrap(),
@@ -1035,6 +2515,9 @@ mod test_delete_files {
                 files: file_set,
                 dirs: HashSet::new(),
                 symlinks: HashSet::new(),
+                specials: HashSet::new(),
+                complete: true,
+                bounded: HashSet::new(),
             }
         );
 
@@ -1090,9 +2573,9 @@ mod test_delete_files {
         links_to_delete_sequential.push(&link);
 
         delete_files(files_to_delete.par_iter(), TEST_DIR);
-        delete_files_sequential(files_to_delete_sequential.into_iter(), TEST_DIR_SEQ);
+        delete_files_sequential(files_to_delete_sequential, TEST_DIR_SEQ);
         delete_files(links_to_delete.par_iter(), TEST_DIR);
-        delete_files_sequential(links_to_delete_sequential.into_iter(), TEST_DIR_SEQ);
+        delete_files_sequential(links_to_delete_sequential, TEST_DIR_SEQ);
 
         assert_eq!(
             get_all_files(TEST_DIR).unwrap(),
@@ -1100,6 +2583,9 @@ mod test_delete_files {
                 files: file_set.clone(),
                 dirs: HashSet::new(),
                 symlinks: link_set.clone(),
+                specials: HashSet::new(),
+                complete: true,
+                bounded: HashSet::new(),
             }
         );
         assert_eq!(
@@ -1108,6 +2594,9 @@ mod test_delete_files {
                 files: file_set,
                 dirs: HashSet::new(),
                 symlinks: link_set,
+                specials: HashSet::new(),
+                complete: true,
+                bounded: HashSet::new(),
             }
         );
 
@@ -1156,9 +2645,9 @@ mod test_delete_files {
         links_to_delete_sequential.push(&link);
 
         delete_files(files_to_delete.par_iter(), TEST_DIR);
-        delete_files_sequential(files_to_delete_sequential.into_iter(), TEST_DIR_SEQ);
+        delete_files_sequential(files_to_delete_sequential, TEST_DIR_SEQ);
         delete_files(links_to_delete.par_iter(), TEST_DIR);
-        delete_files_sequential(links_to_delete_sequential.into_iter(), TEST_DIR_SEQ);
+        delete_files_sequential(links_to_delete_sequential, TEST_DIR_SEQ);
 
         assert_eq!(
             get_all_files(TEST_DIR).unwrap(),
@@ -1166,6 +2655,9 @@ mod test_delete_files {
                 files: HashSet::new(),
                 dirs: HashSet::new(),
                 symlinks: HashSet::new(),
+                specials: HashSet::new(),
+                complete: true,
+                bounded: HashSet::new(),
             }
         );
         assert_eq!(
@@ -1174,6 +2666,9 @@ mod test_delete_files {
                 files: HashSet::new(),
                 dirs: HashSet::new(),
                 symlinks: HashSet::new(),
+                specials: HashSet::new(),
+                complete: true,
+                bounded: HashSet::new(),
             }
         );
 
@@ -1209,7 +2704,7 @@ mod test_delete_files {
         dirs_to_delete_sequential.push(&dir2);
 
         delete_files(dirs_to_delete.par_iter(), TEST_DIR);
-        delete_files_sequential(dirs_to_delete_sequential.into_iter(), TEST_DIR_SEQ);
+        delete_files_sequential(dirs_to_delete_sequential, TEST_DIR_SEQ);
 
         file_set.insert(Dir {
             path: PathBuf::from(TEST_SUB_DIRS[0]),
@@ -1224,6 +2719,9 @@ mod test_delete_files {
                 files: HashSet::new(),
                 dirs: file_set.clone(),
                 symlinks: HashSet::new(),
+                specials: HashSet::new(),
+                complete: true,
+                bounded: HashSet::new(),
             }
         );
         assert_eq!(
@@ -1232,6 +2730,9 @@ mod test_delete_files {
                 files: HashSet::new(),
                 dirs: file_set,
                 symlinks: HashSet::new(),
+                specials: HashSet::new(),
+                complete: true,
+                bounded: HashSet::new(),
             }
         );
 
@@ -1243,6 +2744,7 @@ mod test_delete_files {
 #[cfg(test)]
 mod test_copy_files {
     use super::*;
+    use std::fs;
     use std::process::Command;
 
     #[test]
@@ -1253,7 +2755,12 @@ mod test_copy_files {
         fs::create_dir_all(TEST_DIR).unwrap();
         fs::create_dir_all(TEST_DIR_OUT).unwrap();
 
-        copy_files(HashSet::<File>::new().par_iter(), TEST_DIR, TEST_DIR_OUT);
+        copy_files(
+            HashSet::<File>::new().par_iter(),
+            TEST_DIR,
+            TEST_DIR_OUT,
+            &Options::default(),
+        );
 
         assert_eq!(
             get_all_files(TEST_DIR_OUT).unwrap(),
@@ -1261,6 +2768,9 @@ mod test_copy_files {
                 files: HashSet::new(),
                 dirs: HashSet::new(),
                 symlinks: HashSet::new(),
+                specials: HashSet::new(),
+                complete: true,
+                bounded: HashSet::new(),
             }
         );
 
@@ -1279,11 +2789,13 @@ mod test_copy_files {
             get_all_files(TEST_DIR).unwrap().dirs().par_iter(),
             TEST_DIR,
             TEST_DIR_OUT,
+            &Options::default(),
         );
         copy_files(
             get_all_files(TEST_DIR).unwrap().files().par_iter(),
             TEST_DIR,
             TEST_DIR_OUT,
+            &Options::default(),
         );
 
         assert_eq!(
@@ -1294,6 +2806,30 @@ mod test_copy_files {
         fs::remove_dir_all(TEST_DIR_OUT).unwrap();
     }
 
+    #[test]
+    fn create_dirs_by_depth() {
+        const TEST_DIR: &str = "test_copy_files_create_dirs_by_depth";
+        const TEST_DIR_OUT: &str = "test_copy_files_create_dirs_by_depth_out";
+
+        for i in 0..8 {
+            fs::create_dir_all(format!("{}/{}/a/b/c/d", TEST_DIR, i)).unwrap();
+        }
+        fs::create_dir_all(TEST_DIR_OUT).unwrap();
+
+        let file_sets = get_all_files(TEST_DIR).unwrap();
+        create_dirs(
+            file_sets.dirs().par_iter(),
+            TEST_DIR,
+            TEST_DIR_OUT,
+            &Options::default(),
+        );
+
+        assert_eq!(get_all_files(TEST_DIR_OUT).unwrap(), file_sets);
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_dir_all(TEST_DIR_OUT).unwrap();
+    }
+
     #[test]
     #[cfg(target_family = "unix")]
     fn insufficient_output_permissions() {
@@ -1330,11 +2866,13 @@ mod test_copy_files {
             get_all_files(TEST_DIR).unwrap().dirs().par_iter(),
             TEST_DIR,
             TEST_DIR_OUT,
+            &Options::default(),
         );
         copy_files(
             get_all_files(TEST_DIR).unwrap().files().par_iter(),
             TEST_DIR,
             TEST_DIR_OUT,
+            &Options::default(),
         );
 
         let mut files = HashSet::new();
@@ -1361,6 +2899,9 @@ mod test_copy_files {
                 files: files.clone(),
                 dirs: dirs.clone(),
                 symlinks: HashSet::new(),
+                specials: HashSet::new(),
+                complete: true,
+                bounded: HashSet::new(),
             }
         );
 
@@ -1381,11 +2922,11 @@ mod test_copy_files {
         fs::create_dir_all(TEST_DIR_OUT).unwrap();
 
         Command::new("cp")
-            .args(&["-r", "src/lumins", TEST_DIR])
+            .args(["-r", "src/lumins", TEST_DIR])
             .output()
             .unwrap();
         Command::new("cp")
-            .args(&["src/main.rs", TEST_DIR])
+            .args(["src/main.rs", TEST_DIR])
             .output()
             .unwrap();
         Command::new("chmod")
@@ -1403,11 +2944,13 @@ mod test_copy_files {
             get_all_files(TEST_DIR).unwrap().dirs().par_iter(),
             TEST_DIR,
             TEST_DIR_OUT,
+            &Options::default(),
         );
         copy_files(
             get_all_files(TEST_DIR).unwrap().files().par_iter(),
             TEST_DIR,
             TEST_DIR_OUT,
+            &Options::default(),
         );
 
         let files = HashSet::new();
@@ -1422,6 +2965,9 @@ mod test_copy_files {
                 files: files.clone(),
                 dirs: dirs.clone(),
                 symlinks: HashSet::new(),
+                specials: HashSet::new(),
+                complete: true,
+                bounded: HashSet::new(),
             }
         );
 
@@ -1430,12 +2976,9 @@ mod test_copy_files {
             .arg([TEST_DIR, "lumins"].join("/"))
             .output()
             .unwrap();
+        Command::new("rm").args(["-rf", TEST_DIR]).output().unwrap();
         Command::new("rm")
-            .args(&["-rf", TEST_DIR])
-            .output()
-            .unwrap();
-        Command::new("rm")
-            .args(&["-rf", TEST_DIR_OUT])
+            .args(["-rf", TEST_DIR_OUT])
             .output()
             .unwrap();
     }
@@ -1455,6 +2998,7 @@ mod test_copy_files {
             get_all_files(TEST_DIR).unwrap().symlinks().par_iter(),
             TEST_DIR,
             TEST_DIR_OUT,
+            &Options::default(),
         );
 
         let mut links_set = HashSet::new();
@@ -1469,6 +3013,9 @@ mod test_copy_files {
                 files: HashSet::new(),
                 dirs: HashSet::new(),
                 symlinks: links_set.clone(),
+                specials: HashSet::new(),
+                complete: true,
+                bounded: HashSet::new(),
             }
         );
 
@@ -1479,8 +3026,8 @@ mod test_copy_files {
     #[test]
     #[cfg(target_family = "windows")]
     fn copy_symlink() {
-        use std::os::windows::fs as wfs;
         use std::env;
+        use std::os::windows::fs as wfs;
         const TEST_DIR: &str = "test_copy_files_copy_symlink";
         const TEST_DIR_OUT: &str = "test_copy_files_copy_symlink_out_seq";
         let CURRENT_PATH: PathBuf = env::current_dir().unwrap();
@@ -1494,6 +3041,7 @@ mod test_copy_files {
             get_all_files(TEST_DIR).unwrap().symlinks().par_iter(),
             TEST_DIR,
             TEST_DIR_OUT,
+            &Options::default(),
         );
 
         let mut links_set = HashSet::new();
@@ -1513,17 +3061,56 @@ mod test_copy_files {
                 files: HashSet::new(),
                 dirs: HashSet::new(),
                 symlinks: links_set.clone(),
+                specials: HashSet::new(),
+                complete: true,
+                bounded: HashSet::new(),
             }
         );
 
-       fs::remove_dir_all(TEST_DIR).unwrap();
-       fs::remove_dir_all(TEST_DIR_OUT).unwrap();
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_dir_all(TEST_DIR_OUT).unwrap();
+    }
+
+    #[test]
+    fn growing_files() {
+        const TEST_DIR: &str = "test_copy_files_growing_files";
+        const TEST_DIR_OUT: &str = "test_copy_files_growing_files_out";
+
+        fs::create_dir_all(TEST_DIR).unwrap();
+        fs::create_dir_all(TEST_DIR_OUT).unwrap();
+        fs::write([TEST_DIR, "log"].join("/"), b"first").unwrap();
+        let files = get_all_files(TEST_DIR).unwrap().files;
+        // The file grows after it was listed
+        fs::write([TEST_DIR, "log"].join("/"), b"first second").unwrap();
+
+        let options = Options {
+            skip_growing: true,
+            ..Options::default()
+        };
+        copy_files(files.par_iter(), TEST_DIR, TEST_DIR_OUT, &options);
+        assert!(fs::metadata([TEST_DIR_OUT, "log"].join("/")).is_err());
+
+        // Without --skip-growing, it is copied once everything else is
+        let options = Options::default();
+        copy_files(files.par_iter(), TEST_DIR, TEST_DIR_OUT, &options);
+        // Other runs leave what this one deferred alone
+        copy_deferred(&Options::default());
+        assert!(fs::metadata([TEST_DIR_OUT, "log"].join("/")).is_err());
+        copy_deferred(&options);
+        assert_eq!(
+            fs::read([TEST_DIR_OUT, "log"].join("/")).unwrap(),
+            b"first second"
+        );
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_dir_all(TEST_DIR_OUT).unwrap();
     }
 }
 
 #[cfg(test)]
 mod test_compare_and_copy_files {
     use super::*;
+    use std::fs;
 
     #[test]
     fn single_same() {
@@ -1546,17 +3133,24 @@ mod test_compare_and_copy_files {
         let mut files_to_compare = HashSet::new();
         files_to_compare.insert(file_to_compare.clone());
 
-        let mut flags = Flag::empty();
-        flags |= Flag::SECURE;
+        let options = Options {
+            secure: true,
+            ..Options::default()
+        };
 
         compare_and_copy_files(
             files_to_compare.clone().par_iter(),
             TEST_DIR,
             TEST_DIR_OUT,
-            Flag::empty(),
+            &Options::default(),
         );
 
-        compare_and_copy_files(files_to_compare.par_iter(), TEST_DIR, TEST_DIR_OUT, flags);
+        compare_and_copy_files(
+            files_to_compare.par_iter(),
+            TEST_DIR,
+            TEST_DIR_OUT,
+            &options,
+        );
 
         let actual = fs::read([TEST_DIR_OUT, "main.rs"].join("/")).unwrap();
         let expected = fs::read([TEST_DIR, "main.rs"].join("/")).unwrap();
@@ -1584,7 +3178,7 @@ mod test_compare_and_copy_files {
             files_to_compare.par_iter(),
             TEST_DIR,
             TEST_DIR_OUT,
-            Flag::empty(),
+            &Options::default(),
         );
 
         let actual = fs::read([TEST_DIR_OUT, "main.rs"].join("/")).unwrap();
@@ -1594,4 +3188,82 @@ mod test_compare_and_copy_files {
 
         fs::remove_dir_all(TEST_DIR_OUT).unwrap();
     }
+
+    #[test]
+    fn many_mixed() {
+        const TEST_DIR: &str = "test_compare_and_copy_files_many_mixed";
+        const TEST_DIR_OUT: &str = "test_compare_and_copy_files_many_mixed_out";
+        const NUM_FILES: usize = 200;
+
+        fs::create_dir_all(TEST_DIR).unwrap();
+        fs::create_dir_all(TEST_DIR_OUT).unwrap();
+
+        let mut files_to_compare = HashSet::new();
+        for i in 0..NUM_FILES {
+            let name = format!("file{}", i);
+            let contents = vec![i as u8; i * 100];
+            fs::write([TEST_DIR, &name].join("/"), &contents).unwrap();
+
+            // Only every third file is already up to date
+            if i % 3 == 0 {
+                fs::write([TEST_DIR_OUT, &name].join("/"), &contents).unwrap();
+            } else {
+                fs::write([TEST_DIR_OUT, &name].join("/"), b"old").unwrap();
+            }
+
+            files_to_compare.insert(File::from(&name, contents.len() as u64));
+        }
+
+        for &(secure, sequential) in &[(false, false), (true, false), (false, true)] {
+            let options = Options {
+                secure,
+                sequential,
+                ..Options::default()
+            };
+            compare_and_copy_files(
+                files_to_compare.par_iter(),
+                TEST_DIR,
+                TEST_DIR_OUT,
+                &options,
+            );
+
+            for i in 0..NUM_FILES {
+                let name = format!("file{}", i);
+                assert_eq!(
+                    fs::read([TEST_DIR_OUT, &name].join("/")).unwrap(),
+                    vec![i as u8; i * 100]
+                );
+            }
+        }
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_dir_all(TEST_DIR_OUT).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod test_for_each_prioritized {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[test]
+    fn no_files() {
+        for_each_prioritized(Vec::<&File>::new(), |_| panic!());
+    }
+
+    #[test]
+    fn each_file_once() {
+        let files: Vec<File> = (0..1000)
+            .map(|i| File::from(&i.to_string(), (i % 7) << 18))
+            .collect();
+        let visited = Mutex::new(Vec::new());
+
+        for_each_prioritized(files.iter().collect(), |file| {
+            visited.lock().unwrap().push(file.clone());
+        });
+
+        let mut visited = visited.into_inner().unwrap();
+        visited.sort_by_key(|file| file.path().to_string_lossy().parse::<u32>().unwrap());
+        assert_eq!(visited, files);
+    }
 }
@@ -0,0 +1,90 @@
+//! Reads the lists of paths that `--files-from` copies, instead of everything in the source
+//!
+//! Lists hold paths relative to the source, one per line, or separated by NULs, such as the
+//! output of `find -print0`, so that paths may contain newlines. Lists are separated by NULs if
+//! they contain any.
+
+#[cfg(target_family = "unix")]
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{self, Read};
+#[cfg(target_family = "unix")]
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, PathBuf};
+
+/// Reads the list of paths in the file `path`, or in stdin if `path` is `-`
+///
+/// # Errors
+/// This function will return an error if the list could not be read, or if it has a path that
+/// is not inside the source, see `parse`
+pub fn load(path: &str) -> Result<Vec<PathBuf>, io::Error> {
+    let with_path =
+        |e: io::Error| io::Error::new(e.kind(), format!("List Error -- {}: {}", path, e));
+    let list = if path == "-" {
+        let mut list = Vec::new();
+        io::stdin().lock().read_to_end(&mut list).map(|_| list)
+    } else {
+        fs::read(path)
+    };
+    parse(&list.map_err(with_path)?).map_err(with_path)
+}
+
+/// Parses a list of paths, see the module documentation
+///
+/// Empty lines are skipped, and leading `./` are removed, as `find .` prints them.
+///
+/// # Errors
+/// This function will return an error if a path is absolute or has `..` in it, since it would
+/// not be inside the source
+pub fn parse(list: &[u8]) -> Result<Vec<PathBuf>, io::Error> {
+    let separator = if list.contains(&0) { 0 } else { b'\n' };
+    list.split(|&b| b == separator)
+        .map(|path| match separator {
+            b'\n' => path.strip_suffix(b"\r").unwrap_or(path),
+            _ => path,
+        })
+        .filter(|path| !path.is_empty())
+        .map(|path| {
+            #[cfg(target_family = "unix")]
+            let path = PathBuf::from(OsStr::from_bytes(path));
+            #[cfg(not(target_family = "unix"))]
+            let path = PathBuf::from(String::from_utf8_lossy(path).into_owned());
+            if path
+                .components()
+                .any(|c| !matches!(c, Component::Normal(_) | Component::CurDir))
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{:?} is not a path inside SOURCE", path),
+                ));
+            }
+            Ok(path
+                .components()
+                .filter(|c| *c != Component::CurDir)
+                .collect::<PathBuf>())
+        })
+        .filter(|path| !path.as_ref().is_ok_and(|path| path.as_os_str().is_empty()))
+        .collect()
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_filelist {
+    use super::*;
+
+    #[test]
+    fn parses_lists() {
+        let paths = |paths: &[&str]| paths.iter().map(PathBuf::from).collect::<Vec<_>>();
+        assert_eq!(parse(b"./a\r\nb/c\n\n.\n").unwrap(), paths(&["a", "b/c"]));
+        // A list with NULs has paths with newlines
+        assert_eq!(
+            parse(b"with\nnewline\0./d\0").unwrap(),
+            paths(&["with\nnewline", "d"])
+        );
+        assert!(parse(b"a\n../b\n").is_err());
+        assert!(parse(b"/etc/passwd\n").is_err());
+    }
+}
@@ -0,0 +1,170 @@
+//! Ordered include and exclude rules, for `--filter`, which choose the entries of a tree that a
+//! run sees
+//!
+//! Rules are checked in the order they are given, like the filter rules of rsync, and the first
+//! rule that matches an entry decides whether it is included or excluded. Entries that no rule
+//! matches are included. Excluded directories are not descended into, unless an include rule
+//! before the rule that excludes them could match something inside them, so that
+//! `+ /var/www/**` followed by `- /var/**` only keeps `/var/www` of `/var`.
+//!
+//! Runs apply the rules to both their source and their destination, so excluded entries of the
+//! destination are neither compared nor deleted.
+
+use std::io;
+use std::path::Path;
+
+use crate::lumins::glob::Glob;
+
+/// Enum to represent what a rule does with the entries it matches
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+enum Action {
+    Include,
+    Exclude,
+}
+
+/// A rule that includes or excludes the entries matched by a pattern
+#[derive(Eq, PartialEq, Debug, Clone)]
+struct Rule {
+    action: Action,
+    pattern: Glob,
+    /// Whether the rule only matches directories, as patterns ending with `/` do
+    dirs_only: bool,
+}
+
+/// A struct that represents an ordered list of filter rules
+#[derive(Eq, PartialEq, Debug, Clone, Default)]
+pub struct Filter {
+    rules: Vec<Rule>,
+}
+
+impl Filter {
+    /// Parses `rules`, in order
+    ///
+    /// Each rule is `+ PATTERN` or `include PATTERN` to include what `PATTERN` matches, or
+    /// `- PATTERN` or `exclude PATTERN` to exclude it. Patterns starting with `/` match paths from
+    /// the root of the tree, and other patterns match the end of paths at any depth, so `*.tmp`
+    /// matches `a/b.tmp`. Patterns ending with `/` only match directories. See `Glob` for `*`,
+    /// `?`, and `**`.
+    ///
+    /// # Errors
+    /// This function will return an error if a rule is neither an include nor an exclude rule, or
+    /// if its pattern is invalid
+    pub fn parse<'a, I>(rules: I) -> Result<Self, io::Error>
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        let invalid = |rule: &str, reason: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Filter Error -- {:?} {}", rule, reason),
+            )
+        };
+        let mut filter = Filter::default();
+        for rule in rules {
+            let (action, pattern) = match rule.trim().split_once(char::is_whitespace) {
+                Some(("+", pattern)) | Some(("include", pattern)) => (Action::Include, pattern),
+                Some(("-", pattern)) | Some(("exclude", pattern)) => (Action::Exclude, pattern),
+                _ => {
+                    return Err(invalid(
+                        rule,
+                        "is not a rule, expected + PATTERN or - PATTERN",
+                    ))
+                }
+            };
+            let pattern = pattern.trim_start();
+            let anchored = pattern.starts_with('/');
+            let dirs_only = pattern.ends_with('/');
+            let pattern = if anchored {
+                Glob::new(pattern)
+            } else {
+                Glob::new(&format!("**/{}", pattern))
+            };
+            match pattern {
+                Some(pattern) => filter.rules.push(Rule {
+                    action,
+                    pattern,
+                    dirs_only,
+                }),
+                None => return Err(invalid(rule, "has an invalid pattern")),
+            }
+        }
+        Ok(filter)
+    }
+
+    /// Checks whether the rules exclude the entry at `path`, relative to the root of the tree,
+    /// where `is_dir` tells whether it is a directory
+    ///
+    /// The directories that `path` is in are not checked, since entries in excluded directories
+    /// are never listed, see `excludes_within`.
+    pub fn excludes(&self, path: &Path, is_dir: bool) -> bool {
+        for rule in &self.rules {
+            if rule.pattern.matches(path) && (is_dir || !rule.dirs_only) {
+                return rule.action == Action::Exclude;
+            }
+            // Directories that what a rule includes is inside of are descended into
+            if is_dir && rule.action == Action::Include && rule.pattern.matches_inside(path) {
+                return false;
+            }
+        }
+        false
+    }
+
+    /// Checks whether the rules exclude the entry at `path`, or any directory that it is in, such
+    /// as for the entries of archives, which are not listed directory by directory
+    pub fn excludes_within(&self, path: &Path, is_dir: bool) -> bool {
+        path.ancestors()
+            .skip(1)
+            .filter(|dir| *dir != Path::new(""))
+            .any(|dir| self.excludes(dir, true))
+            || self.excludes(path, is_dir)
+    }
+
+    /// Checks whether there are no rules, so that nothing is excluded
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_filter {
+    use super::*;
+
+    #[test]
+    fn parses_rules() {
+        assert!(Filter::parse(vec!["+ /a", "exclude *.tmp", "-   b/"]).is_ok());
+        assert!(Filter::parse(vec!["/a"]).is_err());
+        assert!(Filter::parse(vec!["! a"]).is_err());
+        assert!(Filter::parse(vec!["- /"]).is_err());
+        assert!(Filter::parse(vec!["- a/../b"]).is_err());
+    }
+
+    #[test]
+    fn first_match_wins() {
+        let filter =
+            Filter::parse(vec!["+ /var/www/**", "- /var/**", "- *.tmp", "- cache/"]).unwrap();
+        let excludes = |path: &str, is_dir| filter.excludes(Path::new(path), is_dir);
+
+        // Directories on the way to what is included are descended into
+        assert!(!excludes("var", true));
+        assert!(!excludes("var/www", true));
+        assert!(!excludes("var/www/index.html", false));
+        assert!(!excludes("var/www/cache.tmp", false));
+        assert!(excludes("var/log", true));
+        assert!(excludes("var/motd", false));
+
+        // Unanchored patterns match at any depth, and patterns ending with / only directories
+        assert!(excludes("a.tmp", false));
+        assert!(excludes("home/b.tmp", false));
+        assert!(excludes("home/cache", true));
+        assert!(!excludes("home/cache", false));
+        assert!(!excludes("home", true));
+
+        assert!(filter.excludes_within(Path::new("var/log/syslog"), false));
+        assert!(filter.excludes_within(Path::new("home/cache/a"), false));
+        assert!(!filter.excludes_within(Path::new("var/www/a"), false));
+    }
+}
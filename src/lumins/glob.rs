@@ -0,0 +1,144 @@
+//! Matches relative paths against glob patterns
+
+use std::path::Path;
+
+/// A glob pattern over relative paths
+///
+/// Components are separated by `/`, where `*` and `?` match inside a component, and `**`
+/// matches any number of components.
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub struct Glob {
+    components: Vec<String>,
+}
+
+impl Glob {
+    /// Parses the glob `pattern`, relative to the root of a tree
+    ///
+    /// Leading and repeated separators, and `.` components, are ignored.
+    ///
+    /// # Returns
+    /// * Some: The glob
+    /// * None: If `pattern` has no components, or has a `..` component
+    pub fn new(pattern: &str) -> Option<Self> {
+        let components: Vec<String> = pattern
+            .split('/')
+            .filter(|component| !component.is_empty() && *component != ".")
+            .map(String::from)
+            .collect();
+        if components.is_empty() || components.iter().any(|component| component == "..") {
+            return None;
+        }
+        Some(Glob { components })
+    }
+
+    /// Checks whether the glob matches all of `path`
+    pub fn matches(&self, path: &Path) -> bool {
+        matches(&self.components, &path_components(path))
+    }
+
+    /// Checks whether the glob matches `path`, or any directory containing it
+    pub fn matches_within(&self, path: &Path) -> bool {
+        let components = path_components(path);
+        (1..=components.len()).any(|len| matches(&self.components, &components[..len]))
+    }
+
+    /// Checks whether the glob could match a path inside the directory `dir`, whatever is in it
+    pub fn matches_inside(&self, dir: &Path) -> bool {
+        matches_prefix(&self.components, &path_components(dir))
+    }
+}
+
+/// Splits `path` into its components
+fn path_components(path: &Path) -> Vec<String> {
+    path.iter()
+        .map(|component| component.to_string_lossy().into_owned())
+        .collect()
+}
+
+/// Checks whether the glob `pattern` matches all of `components`
+fn matches(pattern: &[String], components: &[String]) -> bool {
+    match pattern.split_first() {
+        None => components.is_empty(),
+        Some((first, rest)) if first == "**" => {
+            (0..=components.len()).any(|skip| matches(rest, &components[skip..]))
+        }
+        Some((first, rest)) => match components.split_first() {
+            Some((component, components)) => {
+                matches_component(first.as_bytes(), component.as_bytes())
+                    && matches(rest, components)
+            }
+            None => false,
+        },
+    }
+}
+
+/// Checks whether the glob `pattern` matches a path that starts with all of `components`, and has
+/// more components after them
+fn matches_prefix(pattern: &[String], components: &[String]) -> bool {
+    match pattern.split_first() {
+        None => false,
+        // Any remaining pattern matches some path of at least one more component
+        Some(_) if components.is_empty() => true,
+        Some((first, _)) if first == "**" => true,
+        Some((first, rest)) => {
+            matches_component(first.as_bytes(), components[0].as_bytes())
+                && matches_prefix(rest, &components[1..])
+        }
+    }
+}
+
+/// Checks whether the glob `pattern` matches the single path component `name`
+fn matches_component(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((b'*', rest)) => (0..=name.len()).any(|skip| matches_component(rest, &name[skip..])),
+        Some((first, rest)) => match name.split_first() {
+            Some((c, name)) => (*first == b'?' || first == c) && matches_component(rest, name),
+            None => false,
+        },
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_glob {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            Glob::new("/./assets//**"),
+            Some(Glob {
+                components: vec!["assets".to_string(), "**".to_string()]
+            })
+        );
+        for pattern in &["", "/", ".", "a/../b"] {
+            assert_eq!(Glob::new(pattern), None, "{:?}", pattern);
+        }
+    }
+
+    #[test]
+    fn matches() {
+        let glob = |pattern| Glob::new(pattern).unwrap();
+        assert!(glob("a?c/**/d").matches(Path::new("abc/d")));
+        assert!(glob("a?c/**/d").matches(Path::new("abc/x/y/d")));
+        assert!(!glob("a?c/**/d").matches(Path::new("abbc/d")));
+        assert!(glob("*.raw").matches(Path::new("photo.raw")));
+        assert!(!glob("*.raw").matches(Path::new("a/photo.raw")));
+        assert!(!glob("a").matches(Path::new("a/b")));
+
+        assert!(glob("a").matches_within(Path::new("a/b")));
+        assert!(glob("a/*").matches_within(Path::new("a/b/c")));
+        assert!(!glob("a/*").matches_within(Path::new("a")));
+        assert!(!glob("a").matches_within(Path::new("ab/c")));
+
+        assert!(glob("var/www/**").matches_inside(Path::new("var")));
+        assert!(glob("var/www/**").matches_inside(Path::new("var/www")));
+        assert!(glob("**/*.tmp").matches_inside(Path::new("a/b")));
+        assert!(!glob("var/www/**").matches_inside(Path::new("var/log")));
+        assert!(!glob("var/www").matches_inside(Path::new("var/www")));
+    }
+}
@@ -0,0 +1,380 @@
+//! Restricts the process to the source and destination of a run, with `--harden`
+//!
+//! Landlock leaves the process able to read only below the source, and to write only below the
+//! destination and the few other paths a run writes to, such as its log file. A seccomp filter
+//! then denies the system calls a run never needs, such as running other programs, opening
+//! sockets, or tracing other processes. Both only apply to the thread that installs them, and to
+//! the threads it starts later, so the process is restricted before any worker is started.
+
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::ptr;
+
+use log::{info, warn};
+
+use crate::lumins::history;
+use crate::lumins::parse::Options;
+
+const SYS_LANDLOCK_CREATE_RULESET: libc::c_long = 444;
+const SYS_LANDLOCK_ADD_RULE: libc::c_long = 445;
+const SYS_LANDLOCK_RESTRICT_SELF: libc::c_long = 446;
+const LANDLOCK_CREATE_RULESET_VERSION: u32 = 1;
+const LANDLOCK_RULE_PATH_BENEATH: libc::c_int = 1;
+
+const ACCESS_FS_READ_FILE: u64 = 1 << 2;
+const ACCESS_FS_READ_DIR: u64 = 1 << 3;
+/// Every access right of the first version of Landlock, from executing files to making symlinks
+const ACCESS_FS_V1: u64 = (1 << 13) - 1;
+/// Linking and renaming files between directories, from the second version
+const ACCESS_FS_REFER: u64 = 1 << 13;
+/// Truncating files, from the third version
+const ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+
+const SECCOMP_RET_KILL_PROCESS: u32 = 0x8000_0000;
+const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+const BPF_LD_W_ABS: u16 = 0x20;
+const BPF_JMP_JEQ_K: u16 = 0x15;
+const BPF_JMP_JGE_K: u16 = 0x35;
+const BPF_RET_K: u16 = 0x06;
+/// Offsets of the system call number and architecture in `struct seccomp_data`
+const SECCOMP_DATA_NR: u32 = 0;
+const SECCOMP_DATA_ARCH: u32 = 4;
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc000_003e);
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc000_00b7);
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+const AUDIT_ARCH: Option<u32> = None;
+
+/// System calls of the x32 ABI have this bit set, and would otherwise get past the denied numbers
+#[cfg(target_arch = "x86_64")]
+const X32_SYSCALL_BIT: Option<u32> = Some(0x4000_0000);
+#[cfg(not(target_arch = "x86_64"))]
+const X32_SYSCALL_BIT: Option<u32> = None;
+
+/// System calls that a run never makes, and that are denied with EPERM
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+const DENIED: &[libc::c_long] = &[
+    libc::SYS_execve,
+    libc::SYS_execveat,
+    libc::SYS_ptrace,
+    libc::SYS_process_vm_readv,
+    libc::SYS_process_vm_writev,
+    libc::SYS_socket,
+    libc::SYS_mount,
+    libc::SYS_umount2,
+    libc::SYS_pivot_root,
+    libc::SYS_chroot,
+    libc::SYS_unshare,
+    libc::SYS_setns,
+    libc::SYS_init_module,
+    libc::SYS_finit_module,
+    libc::SYS_delete_module,
+    libc::SYS_kexec_load,
+    libc::SYS_reboot,
+    libc::SYS_bpf,
+    libc::SYS_perf_event_open,
+];
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+const DENIED: &[libc::c_long] = &[];
+
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: libc::c_int,
+}
+
+/// An instruction of a classic BPF program, as `struct sock_filter`
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+#[repr(C)]
+pub struct SockFilter {
+    code: u16,
+    jt: u8,
+    jf: u8,
+    k: u32,
+}
+
+#[repr(C)]
+struct SockFprog {
+    len: libc::c_ushort,
+    filter: *const SockFilter,
+}
+
+/// Gets the directories that a run writes to besides its destination, which are the directories
+/// of its log file and fidelity report, and of the history if the run is `recorded` in it
+pub fn writable_dirs(options: &Options, recorded: bool) -> Vec<PathBuf> {
+    let parent = |path: &Path| match path.parent() {
+        Some(parent) if parent != Path::new("") => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let mut dirs = Vec::new();
+    if let Some(log_file) = &options.log_file {
+        dirs.push(parent(log_file.path()));
+    }
+    if let Some(report) = &options.fidelity_report {
+        dirs.push(parent(report));
+    }
+    if recorded {
+        dirs.push(parent(&history::default_path()));
+    }
+    dirs
+}
+
+/// Gets the directory that a run writes its destination `dest` below, which is the directory an
+/// archive or image is written into, since it is created as a file
+pub fn writable_dest(dest: &str, archive_dest: bool) -> PathBuf {
+    let path = Path::new(dest);
+    match path.parent() {
+        Some(parent) if archive_dest && parent != Path::new("") => parent.to_path_buf(),
+        _ if archive_dest => PathBuf::from("."),
+        _ => path.to_path_buf(),
+    }
+}
+
+/// Restricts the process to reading below `readable`, and reading and writing below `writable`
+///
+/// Directories in `writable` that do not exist yet are created first, since they could not be
+/// created once the process is restricted.
+///
+/// # Errors
+/// This function will return an error if Landlock is not available, so that a run is never less
+/// restricted than it was asked to be, or if the restrictions could not be applied
+pub fn harden<P, Q>(readable: &[P], writable: &[Q]) -> Result<(), io::Error>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let harden_error = |e: io::Error| io::Error::new(e.kind(), format!("Harden Error -- {}", e));
+
+    let abi = unsafe {
+        libc::syscall(
+            SYS_LANDLOCK_CREATE_RULESET,
+            ptr::null::<RulesetAttr>(),
+            0,
+            LANDLOCK_CREATE_RULESET_VERSION,
+        )
+    };
+    if abi < 1 {
+        return Err(harden_error(io::Error::other(format!(
+            "Landlock is not available in this kernel: {}",
+            io::Error::last_os_error()
+        ))));
+    }
+    let handled = handled_access(abi);
+
+    let attr = RulesetAttr {
+        handled_access_fs: handled,
+    };
+    let ruleset = unsafe {
+        libc::syscall(
+            SYS_LANDLOCK_CREATE_RULESET,
+            &attr as *const RulesetAttr,
+            std::mem::size_of::<RulesetAttr>(),
+            0,
+        )
+    };
+    if ruleset < 0 {
+        return Err(harden_error(io::Error::last_os_error()));
+    }
+    let ruleset = ruleset as libc::c_int;
+
+    let rules = || -> Result<(), io::Error> {
+        for path in readable {
+            // Rules for files, such as archive sources, can only allow reading them
+            let path = path.as_ref();
+            let access = if path.is_dir() {
+                ACCESS_FS_READ_FILE | ACCESS_FS_READ_DIR
+            } else {
+                ACCESS_FS_READ_FILE
+            };
+            add_rule(ruleset, path, access)?;
+        }
+        for dir in writable {
+            let dir = dir.as_ref();
+            fs::create_dir_all(dir)
+                .map_err(|e| io::Error::new(e.kind(), format!("{:?}: {}", dir, e)))?;
+            add_rule(ruleset, dir, handled)?;
+        }
+        restrict(ruleset)
+    };
+    let result = rules();
+    unsafe { libc::close(ruleset) };
+    result.map_err(harden_error)?;
+
+    match AUDIT_ARCH {
+        Some(arch) => {
+            install_filter(&filter(arch, X32_SYSCALL_BIT, DENIED)).map_err(harden_error)?
+        }
+        None => warn!("Harden -- system calls are not filtered on this architecture"),
+    }
+    info!(
+        "Restricted to reading {:?}, and writing {:?}",
+        readable.iter().map(AsRef::as_ref).collect::<Vec<&Path>>(),
+        writable.iter().map(AsRef::as_ref).collect::<Vec<&Path>>()
+    );
+    Ok(())
+}
+
+/// Gets the access rights Landlock handles in version `abi`, which are denied unless a rule allows
+/// them
+fn handled_access(abi: libc::c_long) -> u64 {
+    let mut handled = ACCESS_FS_V1;
+    if abi >= 2 {
+        handled |= ACCESS_FS_REFER;
+    }
+    if abi >= 3 {
+        handled |= ACCESS_FS_TRUNCATE;
+    }
+    handled
+}
+
+/// Allows `access` below `path` in `ruleset`
+fn add_rule(ruleset: libc::c_int, path: &Path, access: u64) -> Result<(), io::Error> {
+    let path_error = |e: io::Error| io::Error::new(e.kind(), format!("{:?}: {}", path, e));
+
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| path_error(io::Error::new(io::ErrorKind::InvalidInput, e)))?;
+    let fd = unsafe { libc::open(c_path.as_ptr(), libc::O_PATH | libc::O_CLOEXEC) };
+    if fd < 0 {
+        return Err(path_error(io::Error::last_os_error()));
+    }
+    let attr = PathBeneathAttr {
+        allowed_access: access,
+        parent_fd: fd,
+    };
+    let result = unsafe {
+        libc::syscall(
+            SYS_LANDLOCK_ADD_RULE,
+            ruleset,
+            LANDLOCK_RULE_PATH_BENEATH,
+            &attr as *const PathBeneathAttr,
+            0,
+        )
+    };
+    let error = io::Error::last_os_error();
+    unsafe { libc::close(fd) };
+    if result < 0 {
+        return Err(path_error(error));
+    }
+    Ok(())
+}
+
+/// Restricts the process with `ruleset`, which can then not gain privileges by running programs
+fn restrict(ruleset: libc::c_int) -> Result<(), io::Error> {
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if unsafe { libc::syscall(SYS_LANDLOCK_RESTRICT_SELF, ruleset, 0) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Builds a seccomp filter that denies the system calls in `denied` with EPERM
+///
+/// # Arguments
+/// * `arch`: the audit architecture of the process, whose system call numbers `denied` are, and
+///   calls of any other architecture kill the process
+/// * `x32_bit`: the bit that marks system calls of the x32 ABI, which are all denied
+/// * `denied`: the numbers of the denied system calls
+pub fn filter(arch: u32, x32_bit: Option<u32>, denied: &[libc::c_long]) -> Vec<SockFilter> {
+    let statement = |code, k| SockFilter {
+        code,
+        jt: 0,
+        jf: 0,
+        k,
+    };
+    let deny = statement(BPF_RET_K, SECCOMP_RET_ERRNO | libc::EPERM as u32);
+
+    let mut filter = vec![
+        statement(BPF_LD_W_ABS, SECCOMP_DATA_ARCH),
+        SockFilter {
+            jt: 1,
+            ..statement(BPF_JMP_JEQ_K, arch)
+        },
+        statement(BPF_RET_K, SECCOMP_RET_KILL_PROCESS),
+        statement(BPF_LD_W_ABS, SECCOMP_DATA_NR),
+    ];
+    // Each check falls through to its denial if it matches, and skips over it if not
+    if let Some(x32_bit) = x32_bit {
+        filter.push(SockFilter {
+            jf: 1,
+            ..statement(BPF_JMP_JGE_K, x32_bit)
+        });
+        filter.push(deny);
+    }
+    for &nr in denied {
+        filter.push(SockFilter {
+            jf: 1,
+            ..statement(BPF_JMP_JEQ_K, nr as u32)
+        });
+        filter.push(deny);
+    }
+    filter.push(statement(BPF_RET_K, SECCOMP_RET_ALLOW));
+    filter
+}
+
+/// Installs the seccomp `filter` on the process
+fn install_filter(filter: &[SockFilter]) -> Result<(), io::Error> {
+    let program = SockFprog {
+        len: filter.len() as libc::c_ushort,
+        filter: filter.as_ptr(),
+    };
+    let result = unsafe {
+        libc::prctl(
+            libc::PR_SET_SECCOMP,
+            libc::SECCOMP_MODE_FILTER,
+            &program as *const SockFprog,
+        )
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_harden {
+    use super::*;
+
+    #[test]
+    fn builds_filter() {
+        let filter = filter(0xc000_003e, Some(0x4000_0000), &[59, 322]);
+        assert_eq!(filter.len(), 4 + 2 + 2 * 2 + 1);
+        // Other architectures are killed
+        assert_eq!(filter[1].k, 0xc000_003e);
+        assert_eq!((filter[1].jt, filter[1].jf), (1, 0));
+        assert_eq!(filter[2].k, SECCOMP_RET_KILL_PROCESS);
+        // x32 calls, then each denied call, fall through to EPERM
+        assert_eq!((filter[4].code, filter[4].k), (BPF_JMP_JGE_K, 0x4000_0000));
+        assert_eq!((filter[6].k, filter[6].jf), (59, 1));
+        assert_eq!(filter[9].k, SECCOMP_RET_ERRNO | libc::EPERM as u32);
+        assert_eq!(filter.last().unwrap().k, SECCOMP_RET_ALLOW);
+
+        assert_eq!(super::filter(0xc000_00b7, None, &[]).len(), 5);
+    }
+
+    #[test]
+    fn handled_access_by_abi() {
+        assert_eq!(handled_access(1), ACCESS_FS_V1);
+        assert_eq!(handled_access(2), ACCESS_FS_V1 | ACCESS_FS_REFER);
+        assert_eq!(
+            handled_access(7),
+            ACCESS_FS_V1 | ACCESS_FS_REFER | ACCESS_FS_TRUNCATE
+        );
+    }
+}
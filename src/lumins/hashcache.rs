@@ -0,0 +1,143 @@
+//! Caches the secure hashes of destination files in their extended attributes, so that files
+//! that have not changed since they were written are not hashed again
+//!
+//! A cached hash is stored with the size and modification time of the file it was taken of, and
+//! is only trusted while the file still has both. On filesystems without support for extended
+//! attributes, nothing is cached, and files are hashed as if there were no cache.
+
+use std::io;
+use std::path::Path;
+
+/// Name of the extended attribute that holds the cached hash
+pub const HASH_XATTR: &str = "user.lumins.blake2b";
+
+/// Gets the cached hash of `path`, if it has one that was taken at its current size and
+/// modification time
+#[cfg(target_family = "unix")]
+pub fn cached_hash(path: &Path) -> Option<Vec<u8>> {
+    let value = xattr::get(path, HASH_XATTR).ok()??;
+    let value = String::from_utf8(value).ok()?;
+    let mut fields = value.split(' ');
+    let size: u64 = fields.next()?.parse().ok()?;
+    let mtime: u128 = fields.next()?.parse().ok()?;
+    let hash = decode_hex(fields.next()?)?;
+    if fields.next().is_some() || state_of(path)? != (size, mtime) {
+        return None;
+    }
+    Some(hash)
+}
+
+/// Gets the cached hash of `path`
+///
+/// Extended attributes are only supported on Unix-based platforms,
+/// so nothing is ever cached on other platforms.
+#[cfg(not(target_family = "unix"))]
+pub fn cached_hash(_path: &Path) -> Option<Vec<u8>> {
+    None
+}
+
+/// Caches `hash` as the hash of `path`, at its current size and modification time
+///
+/// # Errors
+/// This function will return an error in the following situations,
+/// but is not limited to just these cases:
+/// * `path` does not exist
+/// * The extended attribute could not be set, other than because the filesystem of `path` does
+///   not support extended attributes
+#[cfg(target_family = "unix")]
+pub fn cache_hash(path: &Path, hash: &[u8]) -> Result<(), io::Error> {
+    let (size, mtime) = state_of(path)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "could not read size and mtime"))?;
+    let value = format!("{} {} {}", size, mtime, encode_hex(hash));
+    match xattr::set(path, HASH_XATTR, value.as_bytes()) {
+        Err(ref e) if e.raw_os_error() == Some(libc::ENOTSUP) => Ok(()),
+        result => result,
+    }
+}
+
+/// Caches `hash` as the hash of `path`
+///
+/// Extended attributes are only supported on Unix-based platforms,
+/// so this does nothing on other platforms.
+#[cfg(not(target_family = "unix"))]
+pub fn cache_hash(_path: &Path, _hash: &[u8]) -> Result<(), io::Error> {
+    Ok(())
+}
+
+/// Gets the size and modification time in nanoseconds since the Unix epoch of `path`, if it is a
+/// regular file
+#[cfg(target_family = "unix")]
+fn state_of(path: &Path) -> Option<(u64, u128)> {
+    let metadata = std::fs::symlink_metadata(path).ok()?;
+    if !metadata.is_file() {
+        return None;
+    }
+    let mtime = metadata
+        .modified()
+        .ok()?
+        .duration_since(std::time::UNIX_EPOCH)
+        .ok()?;
+    Some((metadata.len(), mtime.as_nanos()))
+}
+
+/// Encodes `bytes` as lowercase hex
+#[cfg(target_family = "unix")]
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Decodes the hex string `hex`
+///
+/// # Returns
+/// * Some: The decoded bytes
+/// * None: If `hex` is not an even number of hex digits
+#[cfg(target_family = "unix")]
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(all(test, target_family = "unix"))]
+mod test_hashcache {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn hex() {
+        assert_eq!(encode_hex(&[0, 0xab, 0x10]), "00ab10");
+        assert_eq!(decode_hex("00ab10"), Some(vec![0, 0xab, 0x10]));
+        assert_eq!(decode_hex("0ab"), None);
+        assert_eq!(decode_hex("zz"), None);
+    }
+
+    #[test]
+    fn cached_hashes() {
+        const TEST_DIR: &str = "test_hashcache_cached_hashes";
+        let file = Path::new(TEST_DIR).join("file");
+
+        fs::create_dir_all(TEST_DIR).unwrap();
+        fs::write(&file, b"a").unwrap();
+        assert_eq!(cached_hash(&file), None);
+
+        // Skip if the filesystem does not support user xattrs
+        if xattr::set(&file, "user.lumins", b"1").is_ok() {
+            cache_hash(&file, &[1, 2, 3]).unwrap();
+            assert_eq!(cached_hash(&file), Some(vec![1, 2, 3]));
+
+            // A file that changed no longer has a cached hash
+            fs::write(&file, b"ab").unwrap();
+            assert_eq!(cached_hash(&file), None);
+        }
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+}
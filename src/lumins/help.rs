@@ -0,0 +1,324 @@
+//! Help topics for `lms help TOPIC`, about what spans the options of several subcommands, such as
+//! archives or snapshots
+//!
+//! `lms help SUBCOMMAND` prints the help of the subcommand, with its examples, and `lms help`
+//! prints the help of lms and lists the topics.
+
+use std::io;
+
+use clap::error::ErrorKind;
+use clap::Command;
+
+/// A struct that represents a help topic
+#[derive(Eq, PartialEq, Debug)]
+pub struct Topic {
+    /// Name of the topic, as given to `lms help`
+    pub name: &'static str,
+    /// What the topic is about, in the list of topics
+    pub summary: &'static str,
+    /// The options that the topic is about, with what they do
+    pub options: &'static [(&'static str, &'static str)],
+    /// What the topic explains, wrapped to 80 columns
+    pub text: &'static str,
+}
+
+/// The topics of `lms help TOPIC`
+pub const TOPICS: &[Topic] = &[
+    Topic {
+        name: "archives",
+        summary: "Restoring archives, and writing archives and filesystem images",
+        options: &[
+            (
+                "--deterministic",
+                "Write archives and images that are identical across runs",
+            ),
+            ("--fs iso9660|udf", "Filesystem of .iso images"),
+            ("--comp zstd|gzip", "Compression of .sqfs images"),
+            (
+                "--sandbox-dest",
+                "Refuse symlinks that lead out of DESTINATION when extracting",
+            ),
+        ],
+        text: "\
+A SOURCE ending in .tar, .tar.zst, .tzst, or .zip is extracted into DESTINATION
+by cp, and restored into it by sync, which also deletes what is not in the
+archive. A DESTINATION of cp ending in .tar, .tar.zst, or .tzst is written as an
+archive of SOURCE, and one ending in .iso or .sqfs as a filesystem image. The
+tree is checked against the format of an image before it is written, so that
+nothing is written unless the whole tree fits.",
+    },
+    Topic {
+        name: "exit-codes",
+        summary: "What the exit status of a run means",
+        options: &[
+            ("0", "Everything was done"),
+            (
+                "1",
+                "The run finished, but some entries could not be handled",
+            ),
+            ("2", "The arguments, or the files they name, are invalid"),
+            ("3", "A source, or a target of rm, does not exist"),
+            ("4", "The destination could not be created"),
+            ("5", "The run stopped before it finished"),
+        ],
+        text: "\
+Entries that fail are logged, and the run goes on with the rest of the tree,
+unless --max-errors N errors were logged, after which it stops with status 5.",
+    },
+    Topic {
+        name: "filters",
+        summary: "Choosing which entries of SOURCE are copied, and which are left alone",
+        options: &[
+            (
+                "--files-from FILE",
+                "Only copy the paths listed in FILE, or in stdin for -",
+            ),
+            (
+                "--filter RULE",
+                "Include (+ PATTERN) or exclude (- PATTERN) entries",
+            ),
+            ("--max-depth N", "Only copy the top N levels of SOURCE"),
+            (
+                "-x, --one-file-system",
+                "Do not descend into other filesystems",
+            ),
+            (
+                "--ignore-existing",
+                "Never overwrite files that exist in DESTINATION",
+            ),
+            (
+                "-u, --update",
+                "Only overwrite files that are older than their sources",
+            ),
+            (
+                "--ignore-case",
+                "Match paths that only differ in case, as in exFAT or NTFS",
+            ),
+            (
+                "--skip-long-paths",
+                "Leave out names that are too long for DESTINATION",
+            ),
+            (
+                "--sanitize-names MODE",
+                "Escape characters that FAT and NTFS forbid, or restore them",
+            ),
+            (
+                "--skip-growing",
+                "Leave out files that are still being written",
+            ),
+            (
+                "--materialize FILE",
+                "Replace matched files with empty files or symlinks",
+            ),
+        ],
+        text: "\
+Filter rules are checked in the order they are given, and the first one that
+matches an entry decides, like the filter rules of rsync, so
+--filter '+ /var/www/**' --filter '- /var/**' keeps only /var/www of /var.
+What the rules exclude in DESTINATION is never deleted. With -vv, a run
+explains why each file is copied, or left alone.",
+    },
+    Topic {
+        name: "links",
+        summary: "How symlinks, and symlinks to directories, are copied",
+        options: &[
+            (
+                "-K, --keep-dirlinks",
+                "Write through symlinks to directories in DESTINATION",
+            ),
+            (
+                "-k, --copy-dirlinks",
+                "Copy symlinks to directories as directories",
+            ),
+            (
+                "--relativize-links",
+                "Make absolute symlinks into SOURCE relative",
+            ),
+            (
+                "--link-rewrite EXPR",
+                "Rewrite the targets of absolute symlinks with sed",
+            ),
+            (
+                "--preview N",
+                "Show how the first N entries are rewritten first",
+            ),
+        ],
+        text: "\
+Symlinks are copied as symlinks, with their targets as they are, and symlinks
+in SOURCE are never followed, unless --copy-dirlinks is given.",
+    },
+    Topic {
+        name: "metadata",
+        summary: "Which metadata is copied along with the contents of files",
+        options: &[
+            ("--xattrs", "Copy extended attributes"),
+            ("--acls", "Copy ACLs"),
+            (
+                "--idmap FILE",
+                "Copy owners, mapped by the user and group IDs in FILE",
+            ),
+            ("--specials", "Create sockets, FIFOs, and device nodes"),
+            (
+                "--fidelity-report PATH",
+                "Write what could not be copied faithfully",
+            ),
+        ],
+        text: "\
+Permissions and modification times are always copied. What could not be copied,
+such as attributes that the destination does not support, is logged, and listed
+in the fidelity report. The destination is probed before a run, and what it does
+not support, such as symlinks or permissions on FAT, is turned off and logged,
+and times are compared to within the resolution that it keeps them to.",
+    },
+    Topic {
+        name: "safety",
+        summary: "Guarding runs against mistakes, crashes, and full disks",
+        options: &[
+            (
+                "--dry-run",
+                "Print the changes a sync would make, without making them",
+            ),
+            (
+                "--safe-delete",
+                "Refuse syncs from a SOURCE without files, such as an unmounted one",
+            ),
+            (
+                "--max-delete N[%]",
+                "Refuse syncs that delete more than N files, or N% of them",
+            ),
+            (
+                "--refuse-anomalies",
+                "Refuse runs of a --profile that delete far more than usual",
+            ),
+            (
+                "--quarantine",
+                "Keep what is replaced or deleted until the run completes",
+            ),
+            ("--resume", "Skip what an interrupted run already copied"),
+            (
+                "--fsync",
+                "Flush every file to disk before it is counted as copied",
+            ),
+            (
+                "--no-check-space",
+                "Do not check that the run fits in DESTINATION",
+            ),
+            (
+                "--harden",
+                "Restrict the process to reading SOURCE and writing DESTINATION",
+            ),
+        ],
+        text: "\
+Files are copied to temporary files that are renamed into place, so DESTINATION
+never has half-written files, and runs that do not fit in DESTINATION are
+refused before anything is written, as are runs where SOURCE and DESTINATION
+are the same directory, or where either is inside the other.",
+    },
+    Topic {
+        name: "snapshots",
+        summary: "Snapshots of the destination, and incremental snapshot directories",
+        options: &[
+            (
+                "--dest-snapshot",
+                "Snapshot DESTINATION before a sync (btrfs, zfs)",
+            ),
+            (
+                "lms snapshot",
+                "Take a snapshot that hard links unchanged files",
+            ),
+        ],
+        text: "\
+Every snapshot of lms snapshot is a complete copy of SOURCE in a directory of
+DESTINATION named after when it was taken, where files that did not change
+since the latest snapshot are hard links into it. Restoring is a plain copy,
+such as lms cp backups/2020-06-14-030000 ~/restored.",
+    },
+];
+
+/// Finds the topic named `name`
+pub fn topic(name: &str) -> Option<&'static Topic> {
+    TOPICS.iter().find(|topic| topic.name == name)
+}
+
+/// Formats `topic` for the terminal, with its options aligned under each other
+pub fn render(topic: &Topic) -> String {
+    let width = topic
+        .options
+        .iter()
+        .map(|(option, _)| option.len())
+        .max()
+        .unwrap_or(0);
+    let mut help = format!("{}\n\n{}\n", topic.summary, topic.text);
+    if !topic.options.is_empty() {
+        help.push_str("\nOPTIONS:\n");
+        for (option, about) in topic.options {
+            help.push_str(&format!(
+                "    {:width$}    {}\n",
+                option,
+                about,
+                width = width
+            ));
+        }
+    }
+    help
+}
+
+/// Prints the help of `name`, which is a subcommand of `cli` or a topic, or the help of `cli`
+/// and the list of topics if there is no `name`
+///
+/// # Errors
+/// This function will return an error if `name` is neither a subcommand nor a topic, or if the
+/// help could not be printed
+pub fn print_help(mut cli: Command, name: Option<&str>) -> Result<(), io::Error> {
+    let name = match name {
+        Some(name) => name,
+        None => {
+            cli.print_help()?;
+            println!("\nTOPICS:");
+            for topic in TOPICS {
+                println!("    {:14} {}", topic.name, topic.summary);
+            }
+            return Ok(());
+        }
+    };
+    if let Some(topic) = topic(name) {
+        print!("{}", render(topic));
+        return Ok(());
+    }
+    // Subcommands print their help as the error of being asked for it
+    match cli.try_get_matches_from_mut(["lms", name, "--help"]) {
+        Err(e) if e.kind() == ErrorKind::DisplayHelp => e.print(),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "Help Error -- {} is neither a subcommand nor a topic, see lms help",
+                name
+            ),
+        )),
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_help {
+    use super::*;
+
+    #[test]
+    fn renders_topics() {
+        assert!(topic("filters").is_some());
+        assert!(topic("sync").is_none());
+
+        let help = render(topic("exit-codes").unwrap());
+        assert!(help.starts_with("What the exit status of a run means\n\n"));
+        assert!(help.contains("\n    0    Everything was done\n"));
+
+        // Topics stay readable in a terminal of 80 columns
+        for topic in TOPICS {
+            assert!(topic.text.lines().all(|line| line.len() <= 80));
+            assert!(topic.name.len() <= 14);
+        }
+    }
+}
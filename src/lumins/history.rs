@@ -0,0 +1,544 @@
+//! Keeps summaries of past runs, and shows how runs of the same profile changed over time
+
+use std::env;
+use std::fmt::Write as _;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use indicatif::HumanBytes;
+use log::error;
+use serde::{Deserialize, Serialize};
+
+use crate::progress::STATS;
+
+/// A struct that represents the summary of a single run
+#[derive(Serialize, Deserialize, Eq, PartialEq, Debug, Clone, Default)]
+pub struct RunSummary {
+    pub profile: String,
+    /// Start of the run, in seconds since the Unix epoch
+    pub started: u64,
+    /// Duration of the run, in milliseconds
+    pub duration_ms: u64,
+    pub files_copied: u64,
+    pub bytes_copied: u64,
+    pub files_deleted: u64,
+    /// Errors logged during the run, plus one if the run failed
+    pub errors: u64,
+}
+
+/// A struct that represents the contents of the history file
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct History {
+    #[serde(default, rename = "run")]
+    runs: Vec<RunSummary>,
+}
+
+/// Fraction of the files in a destination that a run may delete before it is an anomaly, unless an
+/// earlier run of its profile deleted as many
+const MAX_DELETE_RATIO: f64 = 0.5;
+
+/// How many times more files than any earlier run of its profile a run may delete before it is
+/// an anomaly
+const MAX_DELETE_FACTOR: u64 = 10;
+
+/// Number of earlier runs a profile needs before its deletions are compared with them
+const MIN_NORM_RUNS: usize = 3;
+
+/// Number of files a run may always delete, however few earlier runs of its profile deleted
+const MIN_ANOMALY_FILES: u64 = 100;
+
+/// A struct that represents what earlier runs of a profile have done, to compare the changes
+/// planned by a new run against
+#[derive(Eq, PartialEq, Debug, Clone, Copy, Default)]
+pub struct Norms {
+    /// Number of earlier runs
+    pub runs: usize,
+    /// Most files deleted by any earlier run
+    pub max_deleted: u64,
+}
+
+impl Norms {
+    /// Gets the norms of `profile` from `runs`
+    pub fn from_runs(runs: &[RunSummary], profile: &str) -> Self {
+        runs.iter()
+            .filter(|run| run.profile == profile)
+            .fold(Norms::default(), |norms, run| Norms {
+                runs: norms.runs + 1,
+                max_deleted: norms.max_deleted.max(run.files_deleted),
+            })
+    }
+
+    /// Loads the norms of `profile` from the history file at `path`, which has no runs if it does
+    /// not exist
+    ///
+    /// # Errors
+    /// This function will return an error if the history file exists, but could not be read or
+    /// parsed
+    pub fn load(path: &Path, profile: &str) -> Result<Self, io::Error> {
+        match load(path) {
+            Ok(runs) => Ok(Norms::from_runs(&runs, profile)),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(Norms::default()),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Checks that a run deleting `to_delete` of the `dest_files` files in its destination is not an
+/// anomaly, compared with earlier runs of its profile
+///
+/// A run is an anomaly if it deletes more than half of its destination, or, once its profile has
+/// a few earlier runs, more than ten times as many files as any of them did. Without `norms`, only
+/// the first check is made.
+///
+/// # Errors
+/// This function will return an error describing the anomaly if the run is one
+pub fn check_plan(norms: Option<&Norms>, dest_files: u64, to_delete: u64) -> Result<(), io::Error> {
+    let norms = norms.copied().unwrap_or_default();
+    let anomaly = |reason: String| {
+        Err(io::Error::other(format!(
+            "Anomaly Error -- run would delete {} of {} files in the destination, {}; \
+             rerun with --force to proceed",
+            to_delete, dest_files, reason
+        )))
+    };
+
+    if to_delete as f64 > dest_files as f64 * MAX_DELETE_RATIO && to_delete > norms.max_deleted {
+        return anomaly(format!(
+            "which is more than {:.0}%",
+            MAX_DELETE_RATIO * 100.0
+        ));
+    }
+    if norms.runs >= MIN_NORM_RUNS
+        && to_delete > MIN_ANOMALY_FILES
+        && to_delete > norms.max_deleted.saturating_mul(MAX_DELETE_FACTOR)
+    {
+        return anomaly(format!(
+            "while none of the last {} runs deleted more than {}",
+            norms.runs, norms.max_deleted
+        ));
+    }
+    Ok(())
+}
+
+/// Enum to represent how many files of the destination a run may delete, with `--max-delete`
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum MaxDelete {
+    /// A number of files
+    Files(u64),
+    /// A percentage of the files in the destination
+    Percent(f64),
+}
+
+impl MaxDelete {
+    /// Parses a number of files, such as `100`, or a percentage of them, such as `10%`
+    pub fn parse(max_delete: &str) -> Option<Self> {
+        match max_delete.strip_suffix('%') {
+            Some(percent) => percent
+                .parse()
+                .ok()
+                .filter(|percent: &f64| (0.0..=100.0).contains(percent))
+                .map(MaxDelete::Percent),
+            None => max_delete.parse().ok().map(MaxDelete::Files),
+        }
+    }
+
+    /// Checks that a run that deletes `to_delete` of the `dest_files` files in its destination
+    /// deletes no more than `self` allows
+    ///
+    /// # Errors
+    /// This function will return an error if the run deletes more
+    pub fn check(self, dest_files: u64, to_delete: u64) -> Result<(), io::Error> {
+        let (allowed, max_delete) = match self {
+            MaxDelete::Files(files) => (files, files.to_string()),
+            MaxDelete::Percent(percent) => (
+                (dest_files as f64 * percent / 100.0) as u64,
+                format!("{}%", percent),
+            ),
+        };
+        if to_delete > allowed {
+            return Err(io::Error::other(format!(
+                "Delete Error -- run would delete {} of {} files in the destination, which is \
+                 more than --max-delete {} allows; nothing was deleted",
+                to_delete, dest_files, max_delete
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Gets the default location of the history file,
+/// `$XDG_DATA_HOME/lms/history.toml` or `~/.local/share/lms/history.toml`
+pub fn default_path() -> PathBuf {
+    let mut path = match env::var_os("XDG_DATA_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => {
+            let mut home = PathBuf::from(env::var_os("HOME").unwrap_or_default());
+            home.push(".local");
+            home.push("share");
+            home
+        }
+    };
+    path.push("lms");
+    path.push("history.toml");
+    path
+}
+
+/// Runs `run`, and appends a summary of it to the history file at `path` under `profile`
+///
+/// The summary is taken from `STATS`, which is reset before `run` starts. Failing to write the
+/// summary is logged, but does not fail the run.
+///
+/// # Returns
+/// The result of `run`
+pub fn record<F>(path: &Path, profile: &str, run: F) -> Result<(), io::Error>
+where
+    F: FnOnce() -> Result<(), io::Error>,
+{
+    STATS.reset();
+    let started = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let start = Instant::now();
+
+    let result = run();
+
+    let summary = RunSummary {
+        profile: profile.to_string(),
+        started,
+        duration_ms: start.elapsed().as_millis() as u64,
+        files_copied: STATS.files_copied(),
+        bytes_copied: STATS.bytes_copied(),
+        files_deleted: STATS.files_deleted(),
+        errors: STATS.errors() + result.is_err() as u64,
+    };
+    if let Err(e) = append(path, &summary) {
+        error!("{}", e);
+    }
+
+    result
+}
+
+/// Appends `summary` to the history file at `path`, creating it if it does not exist
+///
+/// # Errors
+/// This function will return an error if the history file could not be written
+pub fn append(path: &Path, summary: &RunSummary) -> Result<(), io::Error> {
+    let history_error =
+        |e: io::Error| io::Error::new(e.kind(), format!("History Error -- {:?}: {}", path, e));
+
+    let history = History {
+        runs: vec![summary.clone()],
+    };
+    let contents = toml::to_string(&history)
+        .map_err(|e| history_error(io::Error::new(io::ErrorKind::InvalidData, e)))?;
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(history_error)?;
+    }
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(history_error)?;
+
+    // Write the whole run at once, so that concurrent runs do not interleave
+    file.write_all(format!("\n{}", contents).as_bytes())
+        .map_err(history_error)
+}
+
+/// Loads every run from the history file at `path`, oldest first
+///
+/// # Errors
+/// This function will return an error if the history file could not be read or parsed
+pub fn load(path: &Path) -> Result<Vec<RunSummary>, io::Error> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| io::Error::new(e.kind(), format!("History Error -- {:?}: {}", path, e)))?;
+    let history: History = toml::from_str(&contents).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("History Error -- {:?}: {}", path, e),
+        )
+    })?;
+    Ok(history.runs)
+}
+
+/// Formats the last `count` runs of each profile in `runs`, or only those of `profile` if given
+///
+/// Every run is compared with the run of the same profile before it, so that sudden changes,
+/// such as a backup that copies ten times more data than usual, stand out.
+///
+/// # Returns
+/// * Some: The formatted runs, grouped by profile in the order they first ran
+/// * None: If there are no runs of `profile`, or no runs at all
+pub fn report(runs: &[RunSummary], profile: Option<&str>, count: usize) -> Option<String> {
+    let mut profiles: Vec<&str> = Vec::new();
+    for run in runs {
+        if !profiles.contains(&run.profile.as_str()) && profile.is_none_or(|p| p == run.profile) {
+            profiles.push(&run.profile);
+        }
+    }
+    if profiles.is_empty() {
+        return None;
+    }
+
+    let mut report = String::new();
+    for name in profiles {
+        let runs: Vec<&RunSummary> = runs.iter().filter(|run| run.profile == name).collect();
+        let _ = writeln!(report, "{}", name);
+
+        let first = runs.len().saturating_sub(count);
+        for (i, run) in runs.iter().enumerate().skip(first) {
+            let previous = if i > 0 { Some(runs[i - 1]) } else { None };
+            let _ = writeln!(report, "  {}", format_run(run, previous));
+        }
+    }
+    Some(report)
+}
+
+/// Prints the last `count` runs of each profile in the history file at `path`, or only those of
+/// `profile` if given
+///
+/// # Errors
+/// This function will return an error if the history file could not be read, or has no runs of
+/// `profile`
+pub fn show(path: &Path, profile: Option<&str>, count: usize) -> Result<(), io::Error> {
+    let runs = load(path)?;
+    match report(&runs, profile, count) {
+        Some(report) => {
+            print!("{}", report);
+            Ok(())
+        }
+        None => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            match profile {
+                Some(profile) => {
+                    format!("History Error -- no runs recorded for profile {}", profile)
+                }
+                None => "History Error -- no runs recorded".to_string(),
+            },
+        )),
+    }
+}
+
+/// Formats a single run, along with how it changed from `previous`
+fn format_run(run: &RunSummary, previous: Option<&RunSummary>) -> String {
+    let delta = |current: u64, previous: Option<u64>| match previous {
+        Some(previous) if previous != current => {
+            format!(" ({:+})", current as i128 - previous as i128)
+        }
+        _ => String::new(),
+    };
+
+    let duration = format!("{:.1}s", run.duration_ms as f64 / 1000.0);
+    let duration_delta = match previous {
+        Some(previous) if previous.duration_ms != run.duration_ms => format!(
+            " ({:+.1}s)",
+            (run.duration_ms as f64 - previous.duration_ms as f64) / 1000.0
+        ),
+        _ => String::new(),
+    };
+
+    let bytes_delta = match previous {
+        Some(previous) if previous.bytes_copied != run.bytes_copied => {
+            let sign = if run.bytes_copied > previous.bytes_copied {
+                '+'
+            } else {
+                '-'
+            };
+            let change = HumanBytes(
+                run.bytes_copied.max(previous.bytes_copied)
+                    - run.bytes_copied.min(previous.bytes_copied),
+            );
+            if previous.bytes_copied > 0 {
+                let ratio = run.bytes_copied as f64 / previous.bytes_copied as f64;
+                format!(" ({}{}, {:.1}x)", sign, change, ratio)
+            } else {
+                format!(" ({}{})", sign, change)
+            }
+        }
+        _ => String::new(),
+    };
+
+    format!(
+        "{}  {}{}  copied {} files{}, {}{}  deleted {}{}  errors {}{}",
+        format_time(run.started),
+        duration,
+        duration_delta,
+        run.files_copied,
+        delta(run.files_copied, previous.map(|p| p.files_copied)),
+        HumanBytes(run.bytes_copied),
+        bytes_delta,
+        run.files_deleted,
+        delta(run.files_deleted, previous.map(|p| p.files_deleted)),
+        run.errors,
+        delta(run.errors, previous.map(|p| p.errors)),
+    )
+}
+
+/// Formats seconds since the Unix epoch as a UTC date and time, e.g. `2020-06-15 03:30`
+pub fn format_time(secs: u64) -> String {
+    let (year, month, day, hour, minute, _) = utc_time(secs);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}",
+        year, month, day, hour, minute
+    )
+}
+
+/// Converts seconds since the Unix epoch into a UTC date and time
+///
+/// # Returns
+/// The year, month, day, hour, minute, and second
+pub fn utc_time(secs: u64) -> (i64, i64, i64, u64, u64, u64) {
+    let days = (secs / 86400) as i64;
+    let secs_of_day = secs % 86400;
+
+    // Convert days since the epoch into a civil date
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+    )
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_history {
+    use super::*;
+
+    fn run(profile: &str, started: u64, bytes_copied: u64) -> RunSummary {
+        RunSummary {
+            profile: profile.to_string(),
+            started,
+            duration_ms: 1500,
+            files_copied: bytes_copied / 100,
+            bytes_copied,
+            ..RunSummary::default()
+        }
+    }
+
+    #[test]
+    fn times() {
+        assert_eq!(format_time(0), "1970-01-01 00:00");
+        assert_eq!(
+            format_time(951_782_400 + 3 * 3600 + 30 * 60),
+            "2000-02-29 03:30"
+        );
+        assert_eq!(format_time(1_592_191_800), "2020-06-15 03:30");
+    }
+
+    #[test]
+    fn append_and_load() {
+        const TEST_FILE: &str = "test_history_append_and_load/history.toml";
+
+        let runs = vec![run("photos", 0, 1000), run("music", 60, 0)];
+        for run in &runs {
+            append(Path::new(TEST_FILE), run).unwrap();
+        }
+        assert_eq!(load(Path::new(TEST_FILE)).unwrap(), runs);
+
+        fs::remove_dir_all("test_history_append_and_load").unwrap();
+    }
+
+    #[test]
+    fn invalid_file() {
+        assert!(load(Path::new("/?")).is_err());
+    }
+
+    #[test]
+    fn deltas() {
+        let runs = vec![
+            run("photos", 0, 1000),
+            run("music", 0, 500),
+            run("photos", 86400, 10000),
+            run("photos", 2 * 86400, 10000),
+        ];
+
+        assert_eq!(
+            report(&runs, Some("photos"), 2).unwrap(),
+            concat!(
+                "photos\n",
+                "  1970-01-02 00:00  1.5s  copied 100 files (+90), 9.77KB (+8.79KB, 10.0x)",
+                "  deleted 0  errors 0\n",
+                "  1970-01-03 00:00  1.5s  copied 100 files, 9.77KB  deleted 0  errors 0\n",
+            )
+        );
+
+        let report = report(&runs, None, 10).unwrap();
+        assert!(report.starts_with("photos\n  1970-01-01 00:00  1.5s  copied 10 files, 1000B"));
+        assert!(report.contains("music\n  1970-01-01 00:00  1.5s  copied 5 files, 500B"));
+    }
+
+    #[test]
+    fn anomalies() {
+        let deleted = |files_deleted| RunSummary {
+            profile: "photos".to_string(),
+            files_deleted,
+            ..RunSummary::default()
+        };
+        let runs = vec![deleted(5), deleted(120), deleted(2), run("music", 0, 0)];
+        let norms = Norms::from_runs(&runs, "photos");
+        assert_eq!(
+            norms,
+            Norms {
+                runs: 3,
+                max_deleted: 120
+            }
+        );
+
+        // More than half of the destination
+        assert!(check_plan(None, 10, 5).is_ok());
+        assert!(check_plan(None, 10, 6).is_err());
+        assert!(check_plan(Some(&Norms::default()), 10, 6).is_err());
+        assert!(check_plan(Some(&norms), 200, 110).is_ok());
+
+        // Far more than earlier runs
+        assert!(check_plan(Some(&norms), 100_000, 1200).is_ok());
+        assert!(check_plan(Some(&norms), 100_000, 1201).is_err());
+        let few_runs = Norms::from_runs(&runs[..2], "photos");
+        assert!(check_plan(Some(&few_runs), 100_000, 5000).is_ok());
+        let quiet = Norms::from_runs(&[deleted(0), deleted(0), deleted(0)], "photos");
+        assert!(check_plan(Some(&quiet), 100_000, 100).is_ok());
+        assert!(check_plan(Some(&quiet), 100_000, 101).is_err());
+    }
+
+    #[test]
+    fn max_delete() {
+        assert_eq!(MaxDelete::parse("100"), Some(MaxDelete::Files(100)));
+        assert_eq!(MaxDelete::parse("12.5%"), Some(MaxDelete::Percent(12.5)));
+        assert_eq!(MaxDelete::parse("101%"), None);
+        assert_eq!(MaxDelete::parse("-1"), None);
+        assert_eq!(MaxDelete::parse("many"), None);
+
+        assert!(MaxDelete::Files(100).check(1000, 100).is_ok());
+        assert!(MaxDelete::Files(100).check(1000, 101).is_err());
+        assert!(MaxDelete::Files(0).check(1000, 1).is_err());
+        assert!(MaxDelete::Percent(10.0).check(1000, 100).is_ok());
+        assert!(MaxDelete::Percent(10.0).check(1000, 101).is_err());
+        assert!(MaxDelete::Percent(10.0).check(0, 0).is_ok());
+    }
+
+    #[test]
+    fn no_runs() {
+        assert!(report(&[], None, 10).is_none());
+        assert!(report(&[run("photos", 0, 0)], Some("music"), 10).is_none());
+    }
+}
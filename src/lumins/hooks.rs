@@ -0,0 +1,171 @@
+//! Runs user commands for each file that a run copies, such as to scan files before they are
+//! copied, or to send notifications once they are
+//!
+//! A command given with `--hook-pre` runs before each file is copied, and the file is skipped if
+//! the command fails, while a command given with `--hook-post` runs once each file is copied.
+//! Commands are run by the shell, with `{src}`, `{dest}`, and `{path}` replaced by the quoted
+//! source and destination paths of the file, and its path relative to both, which are also in
+//! the environment variables `LMS_SRC`, `LMS_DEST`, and `LMS_PATH`.
+
+use std::io;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use log::debug;
+
+/// A struct that represents a command to run for each copied file
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub struct Hook {
+    command: String,
+}
+
+impl Hook {
+    /// Creates a hook that runs `command`, see the module documentation for its placeholders
+    pub fn new(command: &str) -> Self {
+        Hook {
+            command: command.to_string(),
+        }
+    }
+
+    /// Gets the command with its placeholders replaced for the file `src` that is copied to
+    /// `dest`, at `relative` inside the source and destination
+    ///
+    /// The command is scanned once, so that placeholders in the paths themselves, such as in a
+    /// file named `{dest}$(rm -rf ~)`, are never replaced.
+    fn command_for(&self, src: &Path, dest: &Path, relative: &Path) -> String {
+        let placeholders = [
+            ("{src}", quote(src)),
+            ("{dest}", quote(dest)),
+            ("{path}", quote(relative)),
+        ];
+        let mut command = String::with_capacity(self.command.len());
+        let mut rest = self.command.as_str();
+        while let Some(start) = rest.find('{') {
+            command.push_str(&rest[..start]);
+            rest = &rest[start..];
+            match placeholders
+                .iter()
+                .find(|(placeholder, _)| rest.starts_with(placeholder))
+            {
+                Some((placeholder, value)) => {
+                    command.push_str(value);
+                    rest = &rest[placeholder.len()..];
+                }
+                None => {
+                    command.push('{');
+                    rest = &rest[1..];
+                }
+            }
+        }
+        command.push_str(rest);
+        command
+    }
+
+    /// Runs the hook for the file `src` that is copied to `dest`, at `relative` inside the source
+    /// and destination, and waits for it to exit
+    ///
+    /// # Errors
+    /// This function will return an error if the command could not be run, or if it failed, with
+    /// what it printed to stderr
+    pub fn run(&self, src: &Path, dest: &Path, relative: &Path) -> Result<(), io::Error> {
+        let command = self.command_for(src, dest, relative);
+        debug!("Running hook {}", command);
+
+        #[cfg(not(target_family = "windows"))]
+        let mut shell = Command::new("sh");
+        #[cfg(not(target_family = "windows"))]
+        shell.arg("-c");
+        #[cfg(target_family = "windows")]
+        let mut shell = Command::new("cmd");
+        #[cfg(target_family = "windows")]
+        shell.arg("/C");
+
+        let output = shell
+            .arg(&command)
+            .env("LMS_SRC", src)
+            .env("LMS_DEST", dest)
+            .env("LMS_PATH", relative)
+            .stdin(Stdio::null())
+            .output()?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(io::Error::other(format!(
+                "{} exited with {}{}",
+                command,
+                output.status,
+                match stderr.trim() {
+                    "" => String::new(),
+                    stderr => format!(": {}", stderr),
+                }
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Quotes `path` for the shell that hooks are run by
+fn quote(path: &Path) -> String {
+    let path = path.to_string_lossy();
+    #[cfg(not(target_family = "windows"))]
+    return format!("'{}'", path.replace('\'', r"'\''"));
+    // Paths can not contain quotes on Windows
+    #[cfg(target_family = "windows")]
+    return format!("\"{}\"", path);
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_hooks {
+    use super::*;
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn replaces_placeholders() {
+        let hook = Hook::new("scan {src} && echo {path} > {dest}.log");
+        assert_eq!(
+            hook.command_for(Path::new("a/it's"), Path::new("b/it's"), Path::new("it's")),
+            r"scan 'a/it'\''s' && echo 'it'\''s' > 'b/it'\''s'.log"
+        );
+        assert_eq!(
+            Hook::new("echo {x} {{path}}").command_for(
+                Path::new("a"),
+                Path::new("b"),
+                Path::new("c")
+            ),
+            "echo {x} {'c'}"
+        );
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn ignores_placeholders_in_paths() {
+        // The {dest} in the name is not replaced, which would end the quotes around {src}
+        let name = "{dest}$(touch test_hooks_pwned)";
+        let (src, dest) = (Path::new("a").join(name), Path::new("b").join(name));
+        let hook = Hook::new("true {src} {path}");
+        assert_eq!(
+            hook.command_for(&src, &dest, Path::new(name)),
+            format!("true 'a/{}' '{}'", name, name)
+        );
+        hook.run(&src, &dest, Path::new(name)).unwrap();
+        assert!(!Path::new("test_hooks_pwned").exists());
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn fails_with_command() {
+        let (src, dest, path) = (Path::new("a/c d"), Path::new("b/c d"), Path::new("c d"));
+        assert!(
+            Hook::new("test {path} = 'c d' && test \"$LMS_SRC\" = 'a/c d'")
+                .run(src, dest, path)
+                .is_ok()
+        );
+        let e = Hook::new("echo infected >&2; exit 3")
+            .run(src, dest, path)
+            .unwrap_err();
+        assert!(e.to_string().ends_with(": infected"));
+    }
+}
@@ -0,0 +1,201 @@
+//! Maps user and group IDs of copied files, e.g. between user namespaces of containers
+
+use std::fs;
+use std::io;
+use std::str::FromStr;
+
+/// A contiguous range of IDs that is mapped onto another range of the same length
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+struct IdRange {
+    from: u64,
+    to: u64,
+    count: u64,
+}
+
+impl IdRange {
+    /// Maps `id` if it is inside this range
+    fn map(&self, id: u32) -> Option<u32> {
+        let id = u64::from(id);
+        if id >= self.from && id - self.from < self.count {
+            Some((self.to + (id - self.from)) as u32)
+        } else {
+            None
+        }
+    }
+}
+
+/// A struct that represents user and group ID mappings
+///
+/// IDs that are not inside any range are kept as they are.
+#[derive(Eq, PartialEq, Debug, Clone, Default)]
+pub struct IdMap {
+    uids: Vec<IdRange>,
+    gids: Vec<IdRange>,
+}
+
+impl FromStr for IdMap {
+    type Err = io::Error;
+
+    /// Parses ID mappings, one per line
+    ///
+    /// Each line is either `<kind> <from> <to> <count>`, which maps IDs `from..from + count` onto
+    /// `to..to + count`, or `<kind> +<shift>` or `<kind> -<shift>`, which shifts every ID
+    /// that stays in range. `<kind>` is `u` for user IDs, `g` for group IDs, or `b` for both.
+    /// Empty lines and lines starting with `#` are ignored.
+    ///
+    /// # Examples
+    /// `b 0 100000 65536` maps IDs 0-65535 onto 100000-165535, as do `b +100000` for IDs
+    /// up to 4294867295
+    fn from_str(idmap: &str) -> Result<Self, Self::Err> {
+        const ID_COUNT: u64 = 1 << 32;
+
+        let mut map = IdMap::default();
+
+        for (i, line) in idmap.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let invalid = || {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("IdMap Error -- line {}: invalid mapping {:?}", i + 1, line),
+                )
+            };
+
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let range = match fields[1..] {
+                [shift] if shift.starts_with('+') || shift.starts_with('-') => {
+                    let amount = shift[1..].parse::<u32>().map_err(|_| invalid())?;
+                    let amount = u64::from(amount);
+                    let (from, to) = if shift.starts_with('+') {
+                        (0, amount)
+                    } else {
+                        (amount, 0)
+                    };
+                    IdRange {
+                        from,
+                        to,
+                        count: ID_COUNT - amount,
+                    }
+                }
+                [from, to, count] => {
+                    let parse = |field: &str| field.parse::<u32>().map(u64::from);
+                    let range = IdRange {
+                        from: parse(from).map_err(|_| invalid())?,
+                        to: parse(to).map_err(|_| invalid())?,
+                        count: parse(count).map_err(|_| invalid())?,
+                    };
+                    if range.from + range.count > ID_COUNT || range.to + range.count > ID_COUNT {
+                        return Err(invalid());
+                    }
+                    range
+                }
+                _ => return Err(invalid()),
+            };
+
+            match fields[0] {
+                "u" => map.uids.push(range),
+                "g" => map.gids.push(range),
+                "b" => {
+                    map.uids.push(range);
+                    map.gids.push(range);
+                }
+                _ => return Err(invalid()),
+            }
+        }
+
+        Ok(map)
+    }
+}
+
+impl IdMap {
+    /// Loads ID mappings from `path`
+    ///
+    /// # Errors
+    /// This function will return an error if `path` could not be read, or has invalid mappings
+    pub fn load(path: &str) -> Result<Self, io::Error> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("IdMap Error -- {}: {}", path, e)))?;
+        contents.parse()
+    }
+
+    /// Maps a user ID, using the first range that contains it
+    pub fn uid(&self, uid: u32) -> u32 {
+        map_id(&self.uids, uid)
+    }
+
+    /// Maps a group ID, using the first range that contains it
+    pub fn gid(&self, gid: u32) -> u32 {
+        map_id(&self.gids, gid)
+    }
+}
+
+/// Maps `id` with the first range in `ranges` that contains it, or keeps it if there is none
+fn map_id(ranges: &[IdRange], id: u32) -> u32 {
+    ranges.iter().find_map(|range| range.map(id)).unwrap_or(id)
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_idmap {
+    use super::*;
+
+    #[test]
+    fn invalid_maps() {
+        for idmap in &[
+            "u",
+            "x 0 1 1",
+            "u 0 1",
+            "u 0 1 1 1",
+            "u a 1 1",
+            "u +a",
+            "u *5",
+            "u 0 4294967295 2",
+        ] {
+            assert!(idmap.parse::<IdMap>().is_err(), "{}", idmap);
+        }
+    }
+
+    #[test]
+    fn ranges() {
+        let idmap: IdMap = "# container\nu 0 100000 65536\n\ng 1000 2000 1\n"
+            .parse()
+            .unwrap();
+
+        assert_eq!(idmap.uid(0), 100000);
+        assert_eq!(idmap.uid(65535), 165535);
+        assert_eq!(idmap.uid(65536), 65536);
+        assert_eq!(idmap.gid(0), 0);
+        assert_eq!(idmap.gid(1000), 2000);
+        assert_eq!(idmap.gid(1001), 1001);
+    }
+
+    #[test]
+    fn shifts() {
+        let idmap: IdMap = "b +100000".parse().unwrap();
+        assert_eq!(idmap.uid(0), 100000);
+        assert_eq!(idmap.gid(1000), 101000);
+        assert_eq!(idmap.uid(u32::MAX), u32::MAX);
+
+        let idmap: IdMap = "u -100000".parse().unwrap();
+        assert_eq!(idmap.uid(100000), 0);
+        assert_eq!(idmap.uid(99999), 99999);
+        assert_eq!(idmap.gid(100000), 100000);
+    }
+
+    #[test]
+    fn first_range_wins() {
+        let idmap: IdMap = "u 0 10 5\nu 0 20 5".parse().unwrap();
+        assert_eq!(idmap.uid(1), 11);
+    }
+
+    #[test]
+    fn invalid_file() {
+        assert!(IdMap::load("/?").is_err());
+    }
+}
@@ -0,0 +1,1793 @@
+//! Writes directory trees into ISO 9660 and UDF filesystem images, for burning or mounting
+//!
+//! Both formats restrict what a tree can hold, such as how long names may be, which characters
+//! they may have, how deep directories may be nested, and how large files may be. The tree is
+//! planned before the image is created, and every entry that the chosen format can not hold is
+//! reported then, so that nothing is written unless the whole tree fits.
+//!
+//! ISO 9660 images have a primary hierarchy with 8.3 names for the oldest readers, and a Joliet
+//! hierarchy with the names as they are, which both share the contents of files. UDF images are
+//! written as UDF 1.02, which DVD players and every current operating system read, and which also
+//! holds symlinks, owners, and permissions.
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use clap::ValueEnum;
+use log::warn;
+
+use crate::lumins::archive::{self, EntryMetadata};
+use crate::lumins::dirfd;
+use crate::lumins::file_ops::{self, FileOps};
+use crate::lumins::history;
+use crate::lumins::parse::Options;
+use crate::progress::{self, PROGRESS_BAR};
+
+/// Size of the sectors of both formats, and of the logical blocks of UDF
+const SECTOR_SIZE: usize = 2048;
+
+/// Deepest level of directories in ISO 9660, where the root is at level 1
+const ISO_MAX_LEVELS: usize = 8;
+
+/// Most directories that the path tables of ISO 9660 can number
+const ISO_MAX_DIRS: usize = u16::MAX as usize;
+
+/// Longest name in a Joliet hierarchy, in UCS-2 characters
+const JOLIET_MAX_NAME: usize = 64;
+
+/// Largest extent of a UDF file, the largest multiple of the block size below 1 GiB
+const UDF_MAX_EXTENT: u64 = (1 << 30) - SECTOR_SIZE as u64;
+
+/// Sector that the first UDF anchor is at, with the partition right after it
+const UDF_ANCHOR: u32 = 256;
+
+/// Entity identifier of the implementation that wrote an image
+const IMPLEMENTATION: &str = "*LuminS";
+
+/// Enum to represent the filesystem of an image, with `--fs`
+#[derive(Eq, PartialEq, Debug, Clone, Copy, Default, ValueEnum)]
+pub enum Filesystem {
+    /// ISO 9660 with Joliet names
+    #[default]
+    Iso9660,
+    /// UDF 1.02
+    Udf,
+}
+
+impl Filesystem {
+    /// Gets the name of the filesystem, as it is written in messages
+    fn name(self) -> &'static str {
+        match self {
+            Filesystem::Iso9660 => "ISO 9660",
+            Filesystem::Udf => "UDF",
+        }
+    }
+}
+
+/// Checks whether `path` is an image that a tree can be written into, by its extension
+pub fn is_image(path: &str) -> bool {
+    path.ends_with(".iso")
+}
+
+/// Enum to represent what an entry of an image is
+#[derive(Eq, PartialEq, Debug, Clone)]
+enum NodeKind {
+    Dir,
+    File(u64),
+    Symlink(PathBuf),
+}
+
+/// An entry of the tree to write into an image
+#[derive(Eq, PartialEq, Debug, Clone)]
+struct Node {
+    /// Path of the entry, relative to the root of the tree
+    path: PathBuf,
+    name: String,
+    kind: NodeKind,
+    metadata: EntryMetadata,
+    /// Index of the directory that contains the entry, where the root contains itself
+    parent: usize,
+    /// Indices of the entries that a directory contains, sorted by name
+    children: Vec<usize>,
+}
+
+/// Writes every file, directory, and symlink in `src` into a new image at `dest`, with the
+/// filesystem that `options` select, see `Filesystem`
+///
+/// With `Options::deterministic`, entries have no owners and their timestamps are clamped to
+/// `SOURCE_DATE_EPOCH`, or to 0 if it is not set, like in archives. If the image can not be
+/// completely written, `dest` is removed.
+///
+/// # Errors
+/// This function will return an error if `src` is an invalid directory, if any entry can not be
+/// held by the filesystem, or if the image could not be written
+pub fn write_image(src: &str, dest: &str, options: &Options) -> Result<(), io::Error> {
+    let filesystem = options.filesystem;
+    let deterministic = if options.deterministic {
+        Some(archive::source_date_epoch())
+    } else {
+        None
+    };
+    let nodes = plan(src, filesystem, deterministic)?;
+    let label = label(dest);
+    let now = deterministic.unwrap_or_else(|| {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |now| now.as_secs())
+    });
+
+    let file = fs::File::create(dest).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("Image Error -- Creating {:?}: {}", dest, e),
+        )
+    })?;
+    progress::progress_init(nodes.len() as u64);
+
+    let mut image = ImageWriter::new(BufWriter::new(file));
+    let src = Path::new(src);
+    let result = match filesystem {
+        Filesystem::Iso9660 => write_iso(&nodes, src, &label, now, &mut image),
+        Filesystem::Udf => write_udf(&nodes, src, &label, now, &mut image),
+    }
+    .and_then(|_| image.writer.flush());
+
+    if result.is_err() {
+        fs::remove_file(dest).ok();
+    }
+    result
+}
+
+/// Lists `src` into the entries of an image, and checks them against the restrictions of
+/// `filesystem`
+///
+/// Special files are left out with a warning, since no image can hold them, like archives.
+///
+/// # Returns
+/// The entries, with the root first and every directory before what it contains
+///
+/// # Errors
+/// This function will return an error if `src` is an invalid directory, if the metadata of any
+/// entry can not be read, or if any entry can not be held by `filesystem`
+fn plan(
+    src: &str,
+    filesystem: Filesystem,
+    deterministic: Option<u64>,
+) -> Result<Vec<Node>, io::Error> {
+    let file_sets = file_ops::get_all_files(src)?;
+    let src_path = Path::new(src);
+    for special in file_sets.specials() {
+        warn!(
+            "Image Warning -- Leaving out {:?}, since images can not hold special files",
+            src_path.join(special.path())
+        );
+    }
+
+    let mut entries: Vec<(&PathBuf, NodeKind)> = Vec::new();
+    entries.extend(
+        file_sets
+            .dirs()
+            .iter()
+            .map(|dir| (dir.path(), NodeKind::Dir)),
+    );
+    entries.extend(
+        file_sets
+            .files()
+            .iter()
+            .map(|file| (file.path(), NodeKind::File(file.size()))),
+    );
+    entries.extend(
+        file_sets
+            .symlinks()
+            .iter()
+            .map(|symlink| (symlink.path(), NodeKind::Symlink(symlink.target().clone()))),
+    );
+    // Path's ordering compares components, so parents sort before their children
+    entries.sort_unstable_by(|a, b| a.0.cmp(b.0));
+
+    let metadata = |path: &Path| {
+        let metadata = fs::symlink_metadata(src_path.join(path)).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Image Error -- {:?}: {}", src_path.join(path), e),
+            )
+        })?;
+        let mut metadata = EntryMetadata::from(&metadata);
+        if let Some(epoch) = deterministic {
+            metadata.uid = 0;
+            metadata.gid = 0;
+            metadata.mtime = metadata.mtime.min(epoch);
+        }
+        Ok::<EntryMetadata, io::Error>(metadata)
+    };
+
+    let mut nodes = vec![Node {
+        path: PathBuf::new(),
+        name: String::new(),
+        kind: NodeKind::Dir,
+        metadata: metadata(Path::new(""))?,
+        parent: 0,
+        children: Vec::new(),
+    }];
+    let mut problems: Vec<(PathBuf, String)> = Vec::new();
+    let mut indices: HashMap<&Path, usize> = HashMap::new();
+    indices.insert(Path::new(""), 0);
+    for (path, kind) in entries {
+        let name = match path.file_name().and_then(|name| name.to_str()) {
+            Some(name) => name.to_string(),
+            None => {
+                problems.push((path.clone(), "its name is not valid Unicode".to_string()));
+                continue;
+            }
+        };
+        // Entries whose directories were left out are left out with them
+        let parent = match indices.get(path.parent().unwrap_or_else(|| Path::new(""))) {
+            Some(&parent) => parent,
+            None => continue,
+        };
+        if let Some(problem) = check(path, &name, &kind, filesystem) {
+            problems.push((path.clone(), problem));
+            continue;
+        }
+
+        let index = nodes.len();
+        if kind == NodeKind::Dir {
+            indices.insert(path, index);
+        }
+        nodes[parent].children.push(index);
+        nodes.push(Node {
+            path: path.clone(),
+            name,
+            kind,
+            metadata: metadata(path)?,
+            parent,
+            children: Vec::new(),
+        });
+    }
+
+    let dirs = nodes
+        .iter()
+        .filter(|node| node.kind == NodeKind::Dir)
+        .count();
+    if filesystem == Filesystem::Iso9660 && dirs > ISO_MAX_DIRS {
+        return Err(io::Error::other(format!(
+            "Image Error -- {:?} has {} directories, while ISO 9660 only allows {}, but UDF allows \
+             more",
+            src, dirs, ISO_MAX_DIRS
+        )));
+    }
+
+    if !problems.is_empty() {
+        for (path, problem) in &problems {
+            warn!(
+                "Image Error -- {:?} can not be written into {} images, since {}",
+                src_path.join(path),
+                filesystem.name(),
+                problem
+            );
+        }
+        return Err(io::Error::other(format!(
+            "Image Error -- {} entries of {:?} can not be written into {} images, so nothing is \
+             written",
+            problems.len(),
+            src,
+            filesystem.name()
+        )));
+    }
+    Ok(nodes)
+}
+
+/// Checks whether the entry `path` named `name` can be held by `filesystem`
+///
+/// # Returns
+/// * Some: Why the entry can not be held
+/// * None: If the entry can be held
+fn check(path: &Path, name: &str, kind: &NodeKind, filesystem: Filesystem) -> Option<String> {
+    match filesystem {
+        Filesystem::Iso9660 => {
+            if let Some(c) = name
+                .chars()
+                .find(|&c| c < ' ' || "*/:;?\\".contains(c) || c as u32 > 0xffff)
+            {
+                return Some(format!("Joliet names can not have {:?}", c));
+            }
+            let length = name.encode_utf16().count();
+            if length > JOLIET_MAX_NAME {
+                return Some(format!(
+                    "its name is {} characters long, while Joliet only allows {}",
+                    length, JOLIET_MAX_NAME
+                ));
+            }
+            match kind {
+                NodeKind::Dir if path.components().count() >= ISO_MAX_LEVELS => Some(format!(
+                    "it is nested {} directories deep, while ISO 9660 only allows {}, but UDF \
+                     allows more",
+                    path.components().count() + 1,
+                    ISO_MAX_LEVELS
+                )),
+                NodeKind::File(size) if *size > u64::from(u32::MAX) => Some(format!(
+                    "it is {} bytes large, while ISO 9660 only allows 4 GiB, but UDF allows more",
+                    size
+                )),
+                NodeKind::Symlink(_) => Some("ISO 9660 has no symlinks, but UDF does".to_string()),
+                _ => None,
+            }
+        }
+        Filesystem::Udf => {
+            if cs0(name).len() > 255 {
+                return Some(format!(
+                    "its name is {} characters long, while UDF only allows {}",
+                    name.chars().count(),
+                    if name.chars().all(|c| (c as u32) < 0x100) {
+                        254
+                    } else {
+                        127
+                    }
+                ));
+            }
+            match kind {
+                NodeKind::File(size) if extents(*size).len() > udf_max_extents() => Some(format!(
+                    "it is {} bytes large, while this writer only allows {} bytes",
+                    size,
+                    udf_max_extents() as u64 * UDF_MAX_EXTENT
+                )),
+                NodeKind::Symlink(target) => match path_components(target) {
+                    Some(_) => None,
+                    None => Some(format!(
+                        "its target {:?} is not valid Unicode, or has names that are too long",
+                        target
+                    )),
+                },
+                _ => None,
+            }
+        }
+    }
+}
+
+/// Gets the label of the image at `dest`, which is the name of the image without its extension
+fn label(dest: &str) -> String {
+    Path::new(dest)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// A writer of the sectors of an image, which only ever moves forward
+struct ImageWriter<W: Write> {
+    writer: W,
+    /// Number of bytes written so far
+    position: u64,
+}
+
+impl<W: Write> ImageWriter<W> {
+    fn new(writer: W) -> Self {
+        ImageWriter {
+            writer,
+            position: 0,
+        }
+    }
+
+    /// Writes `bytes` at the start of `sector`, filling everything before it with zeros
+    fn write_at(&mut self, sector: u64, bytes: &[u8]) -> Result<(), io::Error> {
+        self.pad_to(sector * SECTOR_SIZE as u64)?;
+        self.write(bytes)
+    }
+
+    /// Writes `bytes` where the last write ended
+    fn write(&mut self, bytes: &[u8]) -> Result<(), io::Error> {
+        self.writer.write_all(bytes)?;
+        self.position += bytes.len() as u64;
+        Ok(())
+    }
+
+    /// Fills the image with zeros up to `position`
+    fn pad_to(&mut self, position: u64) -> Result<(), io::Error> {
+        if position < self.position {
+            return Err(io::Error::other(format!(
+                "Image Error -- the layout overlaps at byte {}",
+                position
+            )));
+        }
+        io::copy(
+            &mut io::repeat(0).take(position - self.position),
+            &mut self.writer,
+        )?;
+        self.position = position;
+        Ok(())
+    }
+
+    /// Writes the contents of the file `path` inside `src` at the start of `sector`, which must
+    /// still be `size` bytes large
+    fn write_file(
+        &mut self,
+        sector: u64,
+        src: &Path,
+        path: &Path,
+        size: u64,
+    ) -> Result<(), io::Error> {
+        self.pad_to(sector * SECTOR_SIZE as u64)?;
+        let file = dirfd::open_file(src, path)?;
+        let copied = io::copy(&mut file.take(size), &mut self.writer)?;
+        self.position += copied;
+        if copied < size {
+            return Err(io::Error::other(format!(
+                "it shrank from {} to {} bytes while it was written",
+                size, copied
+            )));
+        }
+        Ok(())
+    }
+
+    /// Fills the rest of the current sector with zeros
+    fn finish_sector(&mut self) -> Result<(), io::Error> {
+        self.pad_to(sectors(self.position) * SECTOR_SIZE as u64)
+    }
+}
+
+/// Gets the number of sectors that `size` bytes take up
+fn sectors(size: u64) -> u64 {
+    size.div_ceil(SECTOR_SIZE as u64)
+}
+
+/// Writes the error of writing the entry `path` inside `src` into an image
+fn entry_error(src: &Path, path: &Path, e: io::Error) -> io::Error {
+    io::Error::new(
+        e.kind(),
+        format!("Image Error -- Writing {:?}: {}", src.join(path), e),
+    )
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// ISO 9660
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// A directory hierarchy of an ISO 9660 image, either the primary one or the Joliet one
+struct IsoTree {
+    /// Identifier of each entry
+    identifiers: Vec<Vec<u8>>,
+    /// Entries of each directory, sorted by identifier
+    children: Vec<Vec<usize>>,
+    /// Directories in the order of the path table, with the root first
+    dirs: Vec<usize>,
+    /// Number of each directory in the path table, starting at 1
+    numbers: Vec<u16>,
+    /// Directory that contains each entry, where the root contains itself
+    parents: Vec<usize>,
+    /// Size of the extent of each directory
+    sizes: Vec<u32>,
+}
+
+impl IsoTree {
+    /// Builds a hierarchy of `nodes` that has the identifiers `identifiers`
+    fn new(nodes: &[Node], identifiers: Vec<Vec<u8>>) -> Self {
+        let children: Vec<Vec<usize>> = nodes
+            .iter()
+            .map(|node| {
+                let mut children = node.children.clone();
+                children.sort_by(|&a, &b| identifiers[a].cmp(&identifiers[b]));
+                children
+            })
+            .collect();
+
+        // Directories are numbered by level, then by the number of their parents, then by their
+        // identifiers, which is the order they are found in breadth first
+        let mut dirs = vec![0];
+        let mut i = 0;
+        while i < dirs.len() {
+            let dir = dirs[i];
+            dirs.extend(
+                children[dir]
+                    .iter()
+                    .filter(|&&child| nodes[child].kind == NodeKind::Dir),
+            );
+            i += 1;
+        }
+        let mut numbers = vec![0; nodes.len()];
+        for (i, &dir) in dirs.iter().enumerate() {
+            numbers[dir] = (i + 1) as u16;
+        }
+
+        let mut tree = IsoTree {
+            identifiers,
+            children,
+            dirs,
+            numbers,
+            parents: nodes.iter().map(|node| node.parent).collect(),
+            sizes: vec![0; nodes.len()],
+        };
+        for &dir in &tree.dirs {
+            let records = tree.records(nodes, dir, &|_| (0, 0));
+            tree.sizes[dir] = pack_records(&records).len() as u32;
+        }
+        tree
+    }
+
+    /// Gets the directory records of the directory `dir`, with `locate` giving the extent and size
+    /// of each entry
+    fn records(
+        &self,
+        nodes: &[Node],
+        dir: usize,
+        locate: &dyn Fn(usize) -> (u32, u32),
+    ) -> Vec<Vec<u8>> {
+        let parent = nodes[dir].parent;
+        let (extent, size) = locate(dir);
+        let (parent_extent, parent_size) = locate(parent);
+        let mut records = vec![
+            dir_record(&[0], extent, size, nodes[dir].metadata.mtime, true),
+            dir_record(
+                &[1],
+                parent_extent,
+                parent_size,
+                nodes[parent].metadata.mtime,
+                true,
+            ),
+        ];
+        for &child in &self.children[dir] {
+            let (extent, size) = locate(child);
+            records.push(dir_record(
+                &self.identifiers[child],
+                extent,
+                size,
+                nodes[child].metadata.mtime,
+                nodes[child].kind == NodeKind::Dir,
+            ));
+        }
+        records
+    }
+
+    /// Gets the path table of the hierarchy, with the extent of each directory from `extents`
+    fn path_table(&self, extents: &[u32], big_endian: bool) -> Vec<u8> {
+        let mut table = Vec::new();
+        for &dir in &self.dirs {
+            let identifier: &[u8] = if dir == 0 {
+                &[0]
+            } else {
+                &self.identifiers[dir]
+            };
+            table.push(identifier.len() as u8);
+            table.push(0);
+            let parent_number = self.numbers[self.parents[dir]];
+            if big_endian {
+                table.extend(extents[dir].to_be_bytes());
+                table.extend(parent_number.to_be_bytes());
+            } else {
+                table.extend(extents[dir].to_le_bytes());
+                table.extend(parent_number.to_le_bytes());
+            }
+            table.extend(identifier);
+            if identifier.len() % 2 == 1 {
+                table.push(0);
+            }
+        }
+        table
+    }
+}
+
+/// Writes `nodes` of `src` as an ISO 9660 image with Joliet names, labelled `label`
+///
+/// The image has a primary volume descriptor with the primary hierarchy, a supplementary volume
+/// descriptor with the Joliet hierarchy, their path tables, the extents of their directories,
+/// and then the contents of every file, in that order.
+fn write_iso<W: Write>(
+    nodes: &[Node],
+    src: &Path,
+    label: &str,
+    now: u64,
+    image: &mut ImageWriter<W>,
+) -> Result<(), io::Error> {
+    let primary = IsoTree::new(nodes, primary_identifiers(nodes));
+    let joliet = IsoTree::new(nodes, joliet_identifiers(nodes));
+
+    // Both path tables of a hierarchy have the same size, whichever their byte order
+    let dummy = vec![0; nodes.len()];
+    let primary_table_size = primary.path_table(&dummy, false).len() as u64;
+    let joliet_table_size = joliet.path_table(&dummy, false).len() as u64;
+    let mut next = 19;
+    let mut allocate = |size: u64| {
+        let sector = next;
+        next += sectors(size);
+        sector
+    };
+    let primary_tables = [allocate(primary_table_size), allocate(primary_table_size)];
+    let joliet_tables = [allocate(joliet_table_size), allocate(joliet_table_size)];
+
+    let mut primary_extents = vec![0; nodes.len()];
+    for &dir in &primary.dirs {
+        primary_extents[dir] = allocate(u64::from(primary.sizes[dir])) as u32;
+    }
+    let mut joliet_extents = vec![0; nodes.len()];
+    for &dir in &joliet.dirs {
+        joliet_extents[dir] = allocate(u64::from(joliet.sizes[dir])) as u32;
+    }
+    // Empty files have no extent of their own
+    for (i, node) in nodes.iter().enumerate() {
+        if let NodeKind::File(size) = node.kind {
+            if size > 0 {
+                let extent = allocate(size) as u32;
+                primary_extents[i] = extent;
+                joliet_extents[i] = extent;
+            }
+        }
+    }
+    let total = next;
+    if total > u64::from(u32::MAX) {
+        return Err(io::Error::other(
+            "Image Error -- the tree is too large for an ISO 9660 image",
+        ));
+    }
+
+    let size_of = |tree: &IsoTree, i: usize| match nodes[i].kind {
+        NodeKind::File(size) => size as u32,
+        _ => tree.sizes[i],
+    };
+    let root = |tree: &IsoTree, extents: &[u32]| {
+        dir_record(
+            &[0],
+            extents[0],
+            tree.sizes[0],
+            nodes[0].metadata.mtime,
+            true,
+        )
+    };
+    let descriptor = |joliet_descriptor: bool| {
+        let (tree, extents, tables, table_size) = if joliet_descriptor {
+            (&joliet, &joliet_extents, joliet_tables, joliet_table_size)
+        } else {
+            (
+                &primary,
+                &primary_extents,
+                primary_tables,
+                primary_table_size,
+            )
+        };
+        volume_descriptor(&VolumeDescriptor {
+            joliet: joliet_descriptor,
+            label,
+            sectors: total as u32,
+            path_table_size: table_size as u32,
+            path_tables: [tables[0] as u32, tables[1] as u32],
+            root: root(tree, extents),
+            created: now,
+        })
+    };
+
+    image.write_at(16, &descriptor(false))?;
+    image.write_at(17, &descriptor(true))?;
+    let mut terminator = vec![0; SECTOR_SIZE];
+    terminator[0] = 255;
+    terminator[1..6].copy_from_slice(b"CD001");
+    terminator[6] = 1;
+    image.write_at(18, &terminator)?;
+
+    image.write_at(
+        primary_tables[0],
+        &primary.path_table(&primary_extents, false),
+    )?;
+    image.write_at(
+        primary_tables[1],
+        &primary.path_table(&primary_extents, true),
+    )?;
+    image.write_at(joliet_tables[0], &joliet.path_table(&joliet_extents, false))?;
+    image.write_at(joliet_tables[1], &joliet.path_table(&joliet_extents, true))?;
+
+    // Directories are counted once, as their Joliet extents are written
+    for (tree, extents, count) in [
+        (&primary, &primary_extents, false),
+        (&joliet, &joliet_extents, true),
+    ] {
+        for &dir in &tree.dirs {
+            let locate = |i: usize| (extents[i], size_of(tree, i));
+            let records = tree.records(nodes, dir, &locate);
+            image.write_at(u64::from(extents[dir]), &pack_records(&records))?;
+            if count {
+                PROGRESS_BAR.inc(1);
+            }
+        }
+    }
+
+    for (i, node) in nodes.iter().enumerate() {
+        if let NodeKind::File(size) = node.kind {
+            if size > 0 {
+                image
+                    .write_file(u64::from(primary_extents[i]), src, &node.path, size)
+                    .map_err(|e| entry_error(src, &node.path, e))?;
+            }
+            PROGRESS_BAR.inc(1);
+        }
+    }
+    image.pad_to(total * SECTOR_SIZE as u64)
+}
+
+/// The fields of a primary or Joliet volume descriptor
+struct VolumeDescriptor<'a> {
+    /// Whether it is the supplementary volume descriptor of the Joliet hierarchy
+    joliet: bool,
+    label: &'a str,
+    /// Size of the image
+    sectors: u32,
+    path_table_size: u32,
+    /// Sectors of the little and the big endian path tables
+    path_tables: [u32; 2],
+    /// Directory record of the root
+    root: Vec<u8>,
+    /// Seconds since the Unix epoch that the image was created at
+    created: u64,
+}
+
+/// Gets the sector of a primary or Joliet volume descriptor
+fn volume_descriptor(descriptor: &VolumeDescriptor) -> Vec<u8> {
+    let mut sector = vec![0; SECTOR_SIZE];
+    sector[0] = if descriptor.joliet { 2 } else { 1 };
+    sector[1..6].copy_from_slice(b"CD001");
+    sector[6] = 1;
+
+    let text = |field: &mut [u8], value: &str| {
+        if descriptor.joliet {
+            ucs2_field(field, value)
+        } else {
+            let value: String = value.chars().map(d_character).collect();
+            ascii_field(field, &value)
+        }
+    };
+    text(&mut sector[8..40], "");
+    text(&mut sector[40..72], descriptor.label);
+    both_u32(&mut sector[80..88], descriptor.sectors);
+    if descriptor.joliet {
+        // UCS-2 level 3
+        sector[88..91].copy_from_slice(b"%/E");
+    }
+    both_u16(&mut sector[120..124], 1);
+    both_u16(&mut sector[124..128], 1);
+    both_u16(&mut sector[128..132], SECTOR_SIZE as u16);
+    both_u32(&mut sector[132..140], descriptor.path_table_size);
+    sector[140..144].copy_from_slice(&descriptor.path_tables[0].to_le_bytes());
+    sector[148..152].copy_from_slice(&descriptor.path_tables[1].to_be_bytes());
+    sector[156..190].copy_from_slice(&descriptor.root);
+    text(&mut sector[190..318], descriptor.label);
+    text(&mut sector[318..446], "");
+    text(&mut sector[446..574], "");
+    text(&mut sector[574..702], "LUMINS");
+    for field in [702..739, 739..776, 776..813] {
+        text(&mut sector[field], "");
+    }
+    sector[813..830].copy_from_slice(&volume_date(Some(descriptor.created)));
+    sector[830..847].copy_from_slice(&volume_date(Some(descriptor.created)));
+    sector[847..864].copy_from_slice(&volume_date(None));
+    sector[864..881].copy_from_slice(&volume_date(None));
+    sector[881] = 1;
+    sector
+}
+
+/// Gets a directory record of an entry with the file identifier `identifier`
+fn dir_record(identifier: &[u8], extent: u32, size: u32, mtime: u64, is_dir: bool) -> Vec<u8> {
+    // Records have an even length, so identifiers of an even length are padded
+    let mut record = vec![0; 33 + identifier.len() + (identifier.len() + 1) % 2];
+    record[0] = record.len() as u8;
+    both_u32(&mut record[2..10], extent);
+    both_u32(&mut record[10..18], size);
+    let (year, month, day, hour, minute, second) = history::utc_time(mtime);
+    record[18] = (year - 1900).clamp(0, 255) as u8;
+    record[19] = month as u8;
+    record[20] = day as u8;
+    record[21] = hour as u8;
+    record[22] = minute as u8;
+    record[23] = second as u8;
+    record[25] = if is_dir { 0x02 } else { 0 };
+    both_u16(&mut record[28..32], 1);
+    record[32] = identifier.len() as u8;
+    record[33..33 + identifier.len()].copy_from_slice(identifier);
+    record
+}
+
+/// Packs directory records into the sectors of a directory extent, which no record may span
+fn pack_records(records: &[Vec<u8>]) -> Vec<u8> {
+    let mut extent = Vec::new();
+    for record in records {
+        if extent.len() % SECTOR_SIZE + record.len() > SECTOR_SIZE {
+            extent.resize(sectors(extent.len() as u64) as usize * SECTOR_SIZE, 0);
+        }
+        extent.extend(record);
+    }
+    extent.resize(sectors(extent.len() as u64) as usize * SECTOR_SIZE, 0);
+    extent
+}
+
+/// Gets the identifiers of the primary hierarchy, which are unique 8.3 names of d-characters
+///
+/// Names that are the same once they are shortened are told apart by a number at their end, in
+/// the order of the names they had.
+fn primary_identifiers(nodes: &[Node]) -> Vec<Vec<u8>> {
+    let mut identifiers = vec![Vec::new(); nodes.len()];
+    for node in nodes.iter().filter(|node| node.kind == NodeKind::Dir) {
+        let mut used: HashSet<Vec<u8>> = HashSet::new();
+        let mut numbers: HashMap<Vec<u8>, usize> = HashMap::new();
+        for &child in &node.children {
+            let is_dir = nodes[child].kind == NodeKind::Dir;
+            let (stem, extension) = match nodes[child].name.rfind('.') {
+                Some(i) if !is_dir => (&nodes[child].name[..i], &nodes[child].name[i + 1..]),
+                _ => (nodes[child].name.as_str(), ""),
+            };
+            let stem: String = stem.chars().map(d_character).take(8).collect();
+            let stem = if stem.is_empty() {
+                "_".to_string()
+            } else {
+                stem
+            };
+            let extension: String = extension.chars().map(d_character).take(3).collect();
+
+            let identifier = |stem: &str| {
+                if is_dir {
+                    stem.as_bytes().to_vec()
+                } else {
+                    format!("{}.{};1", stem, extension).into_bytes()
+                }
+            };
+            let shortened = identifier(&stem);
+            let mut unique = shortened.clone();
+            let number = numbers.entry(shortened).or_insert(0);
+            while used.contains(&unique) {
+                *number += 1;
+                let suffix = format!("~{}", number);
+                let kept: String = stem
+                    .chars()
+                    .take(8_usize.saturating_sub(suffix.len()))
+                    .collect();
+                unique = identifier(&(kept + &suffix));
+            }
+            used.insert(unique.clone());
+            identifiers[child] = unique;
+        }
+    }
+    identifiers
+}
+
+/// Gets the identifiers of the Joliet hierarchy, which are the names in UCS-2
+fn joliet_identifiers(nodes: &[Node]) -> Vec<Vec<u8>> {
+    nodes
+        .iter()
+        .map(|node| {
+            let name = match node.kind {
+                NodeKind::Dir => node.name.clone(),
+                _ => format!("{};1", node.name),
+            };
+            name.encode_utf16().flat_map(u16::to_be_bytes).collect()
+        })
+        .collect()
+}
+
+/// Maps `c` to a d-character, which is an upper case letter, a digit, or an underscore
+fn d_character(c: char) -> char {
+    match c.to_ascii_uppercase() {
+        c @ ('A'..='Z' | '0'..='9') => c,
+        _ => '_',
+    }
+}
+
+/// Gets a date and time of a volume descriptor, or one that is not specified
+fn volume_date(secs: Option<u64>) -> [u8; 17] {
+    let mut date = [b'0'; 17];
+    date[16] = 0;
+    if let Some(secs) = secs {
+        let (year, month, day, hour, minute, second) = history::utc_time(secs);
+        let digits = format!(
+            "{:04}{:02}{:02}{:02}{:02}{:02}00",
+            year, month, day, hour, minute, second
+        );
+        date[..16].copy_from_slice(digits.as_bytes());
+    }
+    date
+}
+
+/// Writes `value` into `field`, padded with spaces
+fn ascii_field(field: &mut [u8], value: &str) {
+    for (i, byte) in field.iter_mut().enumerate() {
+        *byte = value.as_bytes().get(i).copied().unwrap_or(b' ');
+    }
+}
+
+/// Writes `value` into `field` in UCS-2, padded with spaces
+fn ucs2_field(field: &mut [u8], value: &str) {
+    let mut units = value
+        .encode_utf16()
+        .chain(std::iter::repeat(u16::from(b' ')));
+    for pair in field.chunks_mut(2) {
+        let unit = units.next().unwrap_or_default().to_be_bytes();
+        pair.copy_from_slice(&unit[..pair.len()]);
+    }
+}
+
+/// Writes `value` into `field` in both byte orders
+fn both_u32(field: &mut [u8], value: u32) {
+    field[..4].copy_from_slice(&value.to_le_bytes());
+    field[4..8].copy_from_slice(&value.to_be_bytes());
+}
+
+/// Writes `value` into `field` in both byte orders
+fn both_u16(field: &mut [u8], value: u16) {
+    field[..2].copy_from_slice(&value.to_le_bytes());
+    field[2..4].copy_from_slice(&value.to_be_bytes());
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// UDF
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Where the entries of a UDF image are, in logical blocks of its partition
+struct UdfLayout {
+    /// Blocks of the file entry of each entry
+    entries: Vec<u32>,
+    /// First block of the contents of each entry
+    contents: Vec<u32>,
+    /// Size of the contents of each entry
+    sizes: Vec<u64>,
+    /// Size of the partition
+    blocks: u32,
+}
+
+impl UdfLayout {
+    /// Lays out `nodes`, with the file set descriptor and its terminator in the first two blocks,
+    /// then the file entries of all entries, then the contents of directories and symlinks, and
+    /// then the contents of files
+    fn new(nodes: &[Node]) -> Self {
+        let entries: Vec<u32> = (0..nodes.len()).map(|i| 2 + i as u32).collect();
+        let sizes: Vec<u64> = nodes
+            .iter()
+            .map(|node| match &node.kind {
+                NodeKind::Dir => fids(nodes, node, &entries).len() as u64,
+                NodeKind::File(size) => *size,
+                // Targets are checked when the tree is planned
+                NodeKind::Symlink(target) => {
+                    path_components(target).map_or(0, |components| components.len() as u64)
+                }
+            })
+            .collect();
+
+        let mut next = u64::from(2 + nodes.len() as u32);
+        let mut contents = vec![0; nodes.len()];
+        let files_last = |node: &Node| matches!(node.kind, NodeKind::File(_));
+        for pass in [false, true] {
+            for (i, node) in nodes.iter().enumerate() {
+                if files_last(node) == pass && sizes[i] > 0 {
+                    contents[i] = next as u32;
+                    next += sectors(sizes[i]);
+                }
+            }
+        }
+        UdfLayout {
+            entries,
+            contents,
+            sizes,
+            blocks: next as u32,
+        }
+    }
+}
+
+/// Writes `nodes` of `src` as a UDF 1.02 image, labelled `label`
+fn write_udf<W: Write>(
+    nodes: &[Node],
+    src: &Path,
+    label: &str,
+    now: u64,
+    image: &mut ImageWriter<W>,
+) -> Result<(), io::Error> {
+    let layout = UdfLayout::new(nodes);
+    let total = u64::from(UDF_ANCHOR) + 1 + u64::from(layout.blocks) + 1;
+    if total > u64::from(u32::MAX) {
+        return Err(io::Error::other(
+            "Image Error -- the tree is too large for a UDF image",
+        ));
+    }
+    let partition = u64::from(UDF_ANCHOR) + 1;
+
+    // Volume recognition sequence
+    for (i, identifier) in [b"BEA01", b"NSR02", b"TEA01"].iter().enumerate() {
+        let mut descriptor = vec![0; SECTOR_SIZE];
+        descriptor[1..6].copy_from_slice(*identifier);
+        descriptor[6] = 1;
+        image.write_at(16 + i as u64, &descriptor)?;
+    }
+
+    // Main and reserve volume descriptor sequences, which are the same but for where they are
+    let volume = UdfVolume {
+        label,
+        now,
+        partition_blocks: layout.blocks,
+    };
+    for start in [32, 48] {
+        for (i, descriptor) in volume.descriptors(start).into_iter().enumerate() {
+            image.write_at(u64::from(start) + i as u64, &descriptor)?;
+        }
+    }
+    image.write_at(64, &volume.integrity(nodes))?;
+    image.write_at(65, &terminating_descriptor(65))?;
+    image.write_at(u64::from(UDF_ANCHOR), &anchor(UDF_ANCHOR))?;
+
+    // Partition
+    image.write_at(partition, &volume.file_set())?;
+    image.write_at(partition + 1, &terminating_descriptor(1))?;
+    for i in 0..nodes.len() {
+        image.write_at(
+            partition + u64::from(layout.entries[i]),
+            &file_entry(nodes, i, &layout),
+        )?;
+    }
+    for (i, node) in nodes.iter().enumerate() {
+        let sector = partition + u64::from(layout.contents[i]);
+        match &node.kind {
+            NodeKind::Dir => {
+                let mut fids = fids(nodes, node, &layout.entries);
+                // Each descriptor is tagged with the block that it starts in
+                let mut offset = 0;
+                for fid in fid_lengths(nodes, node) {
+                    let location = layout.contents[i] + (offset / SECTOR_SIZE) as u32;
+                    tag(&mut fids[offset..offset + fid], 257, location);
+                    offset += fid;
+                }
+                image.write_at(sector, &fids)?;
+                PROGRESS_BAR.inc(1);
+            }
+            NodeKind::Symlink(target) => {
+                if let Some(components) = path_components(target) {
+                    image.write_at(sector, &components)?;
+                }
+                PROGRESS_BAR.inc(1);
+            }
+            NodeKind::File(_) => {}
+        }
+    }
+    for (i, node) in nodes.iter().enumerate() {
+        if let NodeKind::File(size) = node.kind {
+            if size > 0 {
+                image
+                    .write_file(
+                        partition + u64::from(layout.contents[i]),
+                        src,
+                        &node.path,
+                        size,
+                    )
+                    .map_err(|e| entry_error(src, &node.path, e))?;
+            }
+            PROGRESS_BAR.inc(1);
+        }
+    }
+    image.finish_sector()?;
+    image.write_at(total - 1, &anchor((total - 1) as u32))?;
+    image.finish_sector()
+}
+
+/// The fields of the descriptors of a UDF volume
+struct UdfVolume<'a> {
+    label: &'a str,
+    now: u64,
+    partition_blocks: u32,
+}
+
+impl UdfVolume<'_> {
+    /// Gets the descriptors of a volume descriptor sequence that starts at the sector `start`
+    fn descriptors(&self, start: u32) -> Vec<Vec<u8>> {
+        // Primary volume descriptor
+        let mut primary = vec![0; 512];
+        primary[16..20].copy_from_slice(&0u32.to_le_bytes());
+        dstring(&mut primary[24..56], self.label);
+        primary[56..58].copy_from_slice(&1u16.to_le_bytes());
+        primary[58..60].copy_from_slice(&1u16.to_le_bytes());
+        primary[60..62].copy_from_slice(&2u16.to_le_bytes());
+        primary[62..64].copy_from_slice(&3u16.to_le_bytes());
+        primary[64..68].copy_from_slice(&1u32.to_le_bytes());
+        primary[68..72].copy_from_slice(&1u32.to_le_bytes());
+        // The volume set identifier starts with a unique number in hex
+        dstring(
+            &mut primary[72..200],
+            &format!("{:08X}{}", self.now as u32, self.label),
+        );
+        charspec(&mut primary[200..264]);
+        charspec(&mut primary[264..328]);
+        regid(&mut primary[344..376], IMPLEMENTATION, &[]);
+        timestamp(&mut primary[376..388], self.now);
+        regid(&mut primary[388..420], IMPLEMENTATION, &[]);
+        tag(&mut primary, 1, start);
+
+        // Implementation use volume descriptor
+        let mut implementation = vec![0; 512];
+        implementation[16..20].copy_from_slice(&1u32.to_le_bytes());
+        regid(&mut implementation[20..52], "*UDF LV Info", &[0x02, 0x01]);
+        charspec(&mut implementation[52..116]);
+        dstring(&mut implementation[116..244], self.label);
+        regid(&mut implementation[352..384], IMPLEMENTATION, &[]);
+        tag(&mut implementation, 4, start + 1);
+
+        // Partition descriptor
+        let mut partition = vec![0; 512];
+        partition[16..20].copy_from_slice(&2u32.to_le_bytes());
+        partition[20..22].copy_from_slice(&1u16.to_le_bytes());
+        regid(&mut partition[24..56], "+NSR02", &[]);
+        // Read only
+        partition[184..188].copy_from_slice(&1u32.to_le_bytes());
+        partition[188..192].copy_from_slice(&(UDF_ANCHOR + 1).to_le_bytes());
+        partition[192..196].copy_from_slice(&self.partition_blocks.to_le_bytes());
+        regid(&mut partition[196..228], IMPLEMENTATION, &[]);
+        tag(&mut partition, 5, start + 2);
+
+        // Logical volume descriptor, with a single type 1 partition map
+        let mut logical = vec![0; 446];
+        logical[16..20].copy_from_slice(&3u32.to_le_bytes());
+        charspec(&mut logical[20..84]);
+        dstring(&mut logical[84..212], self.label);
+        logical[212..216].copy_from_slice(&(SECTOR_SIZE as u32).to_le_bytes());
+        regid(&mut logical[216..248], "*OSTA UDF Compliant", &[0x02, 0x01]);
+        long_ad(&mut logical[248..264], SECTOR_SIZE as u32, 0, 0);
+        logical[264..268].copy_from_slice(&6u32.to_le_bytes());
+        logical[268..272].copy_from_slice(&1u32.to_le_bytes());
+        regid(&mut logical[272..304], IMPLEMENTATION, &[]);
+        logical[432..436].copy_from_slice(&(2 * SECTOR_SIZE as u32).to_le_bytes());
+        logical[436..440].copy_from_slice(&64u32.to_le_bytes());
+        logical[440] = 1;
+        logical[441] = 6;
+        logical[442..444].copy_from_slice(&1u16.to_le_bytes());
+        tag(&mut logical, 6, start + 3);
+
+        // Unallocated space descriptor, since the partition has no free space
+        let mut unallocated = vec![0; 24];
+        unallocated[16..20].copy_from_slice(&4u32.to_le_bytes());
+        tag(&mut unallocated, 7, start + 4);
+
+        vec![
+            primary,
+            implementation,
+            partition,
+            logical,
+            unallocated,
+            terminating_descriptor(start + 5),
+        ]
+    }
+
+    /// Gets the logical volume integrity descriptor, which marks the volume as closed
+    fn integrity(&self, nodes: &[Node]) -> Vec<u8> {
+        let dirs = nodes
+            .iter()
+            .filter(|node| node.kind == NodeKind::Dir)
+            .count();
+        let mut integrity = vec![0; 134];
+        timestamp(&mut integrity[16..28], self.now);
+        integrity[28..32].copy_from_slice(&1u32.to_le_bytes());
+        // Unique IDs of entries start at 16, see `unique_id`
+        integrity[40..48].copy_from_slice(&unique_id(nodes.len()).to_le_bytes());
+        integrity[72..76].copy_from_slice(&1u32.to_le_bytes());
+        integrity[76..80].copy_from_slice(&46u32.to_le_bytes());
+        integrity[84..88].copy_from_slice(&self.partition_blocks.to_le_bytes());
+        regid(&mut integrity[88..120], IMPLEMENTATION, &[]);
+        integrity[120..124].copy_from_slice(&((nodes.len() - dirs) as u32).to_le_bytes());
+        integrity[124..128].copy_from_slice(&(dirs as u32).to_le_bytes());
+        for field in [128..130, 130..132, 132..134] {
+            integrity[field].copy_from_slice(&0x0102u16.to_le_bytes());
+        }
+        tag(&mut integrity, 9, 64);
+        integrity
+    }
+
+    /// Gets the file set descriptor, which is the first block of the partition
+    fn file_set(&self) -> Vec<u8> {
+        let mut file_set = vec![0; 512];
+        timestamp(&mut file_set[16..28], self.now);
+        file_set[28..30].copy_from_slice(&3u16.to_le_bytes());
+        file_set[30..32].copy_from_slice(&3u16.to_le_bytes());
+        file_set[32..36].copy_from_slice(&1u32.to_le_bytes());
+        file_set[36..40].copy_from_slice(&1u32.to_le_bytes());
+        charspec(&mut file_set[48..112]);
+        dstring(&mut file_set[112..240], self.label);
+        charspec(&mut file_set[240..304]);
+        dstring(&mut file_set[304..336], self.label);
+        // The root is the first entry
+        long_ad(&mut file_set[400..416], SECTOR_SIZE as u32, 2, 0);
+        regid(
+            &mut file_set[416..448],
+            "*OSTA UDF Compliant",
+            &[0x02, 0x01],
+        );
+        tag(&mut file_set, 256, 0);
+        file_set
+    }
+}
+
+/// Gets the file entry of the entry `i` of `nodes`
+fn file_entry(nodes: &[Node], i: usize, layout: &UdfLayout) -> Vec<u8> {
+    let node = &nodes[i];
+    let extents = extents(layout.sizes[i]);
+    let mut entry = vec![0; 176 + 8 * extents.len()];
+
+    // ICB tag, with short allocation descriptors
+    entry[16 + 4..16 + 6].copy_from_slice(&4u16.to_le_bytes());
+    entry[16 + 8..16 + 10].copy_from_slice(&1u16.to_le_bytes());
+    entry[16 + 11] = match node.kind {
+        NodeKind::Dir => 4,
+        NodeKind::File(_) => 5,
+        NodeKind::Symlink(_) => 12,
+    };
+    let mode = node.metadata.mode;
+    let mut flags = 0u16;
+    for (bit, flag) in [(0o4000, 0x40), (0o2000, 0x80), (0o1000, 0x100)] {
+        if mode & bit != 0 {
+            flags |= flag;
+        }
+    }
+    entry[16 + 18..16 + 20].copy_from_slice(&flags.to_le_bytes());
+
+    let uid = if node.metadata.uid > u64::from(u32::MAX) {
+        u32::MAX
+    } else {
+        node.metadata.uid as u32
+    };
+    let gid = if node.metadata.gid > u64::from(u32::MAX) {
+        u32::MAX
+    } else {
+        node.metadata.gid as u32
+    };
+    entry[36..40].copy_from_slice(&uid.to_le_bytes());
+    entry[40..44].copy_from_slice(&gid.to_le_bytes());
+    // Each class has its own five bits, of which the lowest three are execute, write, and read
+    let permissions = (mode & 0o7) | ((mode & 0o70) << 2) | ((mode & 0o700) << 4);
+    entry[44..48].copy_from_slice(&permissions.to_le_bytes());
+    let links = match node.kind {
+        NodeKind::Dir => {
+            1 + node
+                .children
+                .iter()
+                .filter(|&&child| nodes[child].kind == NodeKind::Dir)
+                .count()
+        }
+        _ => 1,
+    };
+    entry[48..50].copy_from_slice(&(links.min(usize::from(u16::MAX)) as u16).to_le_bytes());
+    entry[56..64].copy_from_slice(&layout.sizes[i].to_le_bytes());
+    entry[64..72].copy_from_slice(&sectors(layout.sizes[i]).to_le_bytes());
+    for field in [72..84, 84..96, 96..108] {
+        timestamp(&mut entry[field], node.metadata.mtime);
+    }
+    entry[108..112].copy_from_slice(&1u32.to_le_bytes());
+    regid(&mut entry[128..160], IMPLEMENTATION, &[]);
+    entry[160..168].copy_from_slice(&unique_id(i).to_le_bytes());
+    entry[172..176].copy_from_slice(&(8 * extents.len() as u32).to_le_bytes());
+
+    let mut block = layout.contents[i];
+    for (j, length) in extents.iter().enumerate() {
+        entry[176 + 8 * j..180 + 8 * j].copy_from_slice(&(*length as u32).to_le_bytes());
+        entry[180 + 8 * j..184 + 8 * j].copy_from_slice(&block.to_le_bytes());
+        block += sectors(*length) as u32;
+    }
+    tag(&mut entry, 261, layout.entries[i]);
+    entry
+}
+
+/// Gets the file identifier descriptors of the directory `dir`, untagged, see `write_udf`
+///
+/// The first one is of the parent of `dir`, followed by one for each entry in it.
+fn fids(nodes: &[Node], dir: &Node, entries: &[u32]) -> Vec<u8> {
+    let mut fids = Vec::new();
+    let mut push = |name: &[u8], characteristics: u8, node: usize| {
+        let length = fid_length(name.len());
+        let start = fids.len();
+        fids.resize(start + length, 0);
+        let fid = &mut fids[start..];
+        fid[16..18].copy_from_slice(&1u16.to_le_bytes());
+        fid[18] = characteristics;
+        fid[19] = name.len() as u8;
+        long_ad(
+            &mut fid[20..36],
+            SECTOR_SIZE as u32,
+            entries[node],
+            unique_id(node) as u32,
+        );
+        fid[38..38 + name.len()].copy_from_slice(name);
+    };
+
+    // The parent of the root is the root itself
+    push(&[], 0x0a, dir.parent);
+    for &child in &dir.children {
+        let characteristics = if nodes[child].kind == NodeKind::Dir {
+            0x02
+        } else {
+            0
+        };
+        push(&cs0(&nodes[child].name), characteristics, child);
+    }
+    fids
+}
+
+/// Gets the length of each file identifier descriptor of the directory `dir`, see `fids`
+fn fid_lengths(nodes: &[Node], dir: &Node) -> Vec<usize> {
+    std::iter::once(fid_length(0))
+        .chain(
+            dir.children
+                .iter()
+                .map(|&child| fid_length(cs0(&nodes[child].name).len())),
+        )
+        .collect()
+}
+
+/// Gets the length of a file identifier descriptor with a name `length` long, which is padded to
+/// a multiple of 4
+fn fid_length(length: usize) -> usize {
+    (38 + length).div_ceil(4) * 4
+}
+
+/// Gets the unique ID of the entry `i`, where the root has 0, and 1 to 15 are reserved
+fn unique_id(i: usize) -> u64 {
+    if i == 0 {
+        0
+    } else {
+        15 + i as u64
+    }
+}
+
+/// Splits contents of `size` bytes into the lengths of the extents that hold them
+fn extents(size: u64) -> Vec<u64> {
+    let mut extents = Vec::new();
+    let mut left = size;
+    while left > 0 {
+        let length = left.min(UDF_MAX_EXTENT);
+        extents.push(length);
+        left -= length;
+    }
+    extents
+}
+
+/// Gets the most extents that a file entry, which is a single block, can describe
+fn udf_max_extents() -> usize {
+    (SECTOR_SIZE - 176) / 8
+}
+
+/// Gets the path component records of the symlink target `target`
+///
+/// # Returns
+/// * Some: The records of the target
+/// * None: If a name in `target` is not valid Unicode, or too long
+fn path_components(target: &Path) -> Option<Vec<u8>> {
+    let mut records = Vec::new();
+    for component in target.components() {
+        let (kind, name) = match component {
+            Component::RootDir | Component::Prefix(_) => (2, Vec::new()),
+            Component::ParentDir => (3, Vec::new()),
+            Component::CurDir => (4, Vec::new()),
+            Component::Normal(name) => (5, cs0(name.to_str()?)),
+        };
+        if name.len() > 255 {
+            return None;
+        }
+        records.extend([kind, name.len() as u8, 0, 0]);
+        records.extend(name);
+    }
+    Some(records)
+}
+
+/// Encodes `value` in OSTA compressed Unicode, with 8 bits per character if every character fits
+/// into them, and 16 bits per UTF-16 unit if not
+fn cs0(value: &str) -> Vec<u8> {
+    if value.chars().all(|c| (c as u32) < 0x100) {
+        std::iter::once(8)
+            .chain(value.chars().map(|c| c as u8))
+            .collect()
+    } else {
+        std::iter::once(16)
+            .chain(value.encode_utf16().flat_map(u16::to_be_bytes))
+            .collect()
+    }
+}
+
+/// Writes `value` into the dstring `field`, whose last byte is the length of what is written,
+/// shortening `value` to fit
+fn dstring(field: &mut [u8], value: &str) {
+    if value.is_empty() {
+        return;
+    }
+    let mut encoded = cs0(value);
+    let unit = if encoded[0] == 8 { 1 } else { 2 };
+    let fits = 1 + (field.len() - 2) / unit * unit;
+    encoded.truncate(fits);
+    field[..encoded.len()].copy_from_slice(&encoded);
+    let last = field.len() - 1;
+    field[last] = encoded.len() as u8;
+}
+
+/// Writes the OSTA compressed Unicode character set specification into `field`
+fn charspec(field: &mut [u8]) {
+    field[0] = 0;
+    field[1..24].copy_from_slice(b"OSTA Compressed Unicode");
+}
+
+/// Writes the entity identifier `identifier`, with the identifier suffix `suffix`, into `field`
+fn regid(field: &mut [u8], identifier: &str, suffix: &[u8]) {
+    field[1..1 + identifier.len()].copy_from_slice(identifier.as_bytes());
+    field[24..24 + suffix.len()].copy_from_slice(suffix);
+}
+
+/// Writes a long allocation descriptor of an extent of the partition into `field`
+fn long_ad(field: &mut [u8], length: u32, block: u32, unique_id: u32) {
+    field[0..4].copy_from_slice(&length.to_le_bytes());
+    field[4..8].copy_from_slice(&block.to_le_bytes());
+    // The implementation use of the descriptor holds the unique ID of what it points to
+    field[12..16].copy_from_slice(&unique_id.to_le_bytes());
+}
+
+/// Writes `secs` since the Unix epoch into the timestamp `field`, in UTC
+fn timestamp(field: &mut [u8], secs: u64) {
+    let (year, month, day, hour, minute, second) = history::utc_time(secs);
+    field[0..2].copy_from_slice(&0x1000u16.to_le_bytes());
+    field[2..4].copy_from_slice(&(year as u16).to_le_bytes());
+    field[4] = month as u8;
+    field[5] = day as u8;
+    field[6] = hour as u8;
+    field[7] = minute as u8;
+    field[8] = second as u8;
+}
+
+/// Gets an anchor volume descriptor pointer at the sector `location`
+fn anchor(location: u32) -> Vec<u8> {
+    let mut anchor = vec![0; 512];
+    for (offset, start) in [(16, 32u32), (24, 48)] {
+        anchor[offset..offset + 4].copy_from_slice(&(16 * SECTOR_SIZE as u32).to_le_bytes());
+        anchor[offset + 4..offset + 8].copy_from_slice(&start.to_le_bytes());
+    }
+    tag(&mut anchor, 2, location);
+    anchor
+}
+
+/// Gets a terminating descriptor at the block `location`
+fn terminating_descriptor(location: u32) -> Vec<u8> {
+    let mut terminator = vec![0; 512];
+    tag(&mut terminator, 8, location);
+    terminator
+}
+
+/// Writes the tag of the descriptor `descriptor`, with the tag identifier `identifier` and the
+/// location `location`, into its first 16 bytes
+fn tag(descriptor: &mut [u8], identifier: u16, location: u32) {
+    descriptor[0..2].copy_from_slice(&identifier.to_le_bytes());
+    descriptor[2..4].copy_from_slice(&2u16.to_le_bytes());
+    descriptor[6..8].copy_from_slice(&1u16.to_le_bytes());
+    let crc = crc(&descriptor[16..]);
+    descriptor[8..10].copy_from_slice(&crc.to_le_bytes());
+    let length = (descriptor.len() - 16) as u16;
+    descriptor[10..12].copy_from_slice(&length.to_le_bytes());
+    descriptor[12..16].copy_from_slice(&location.to_le_bytes());
+    descriptor[4] = 0;
+    descriptor[4] = descriptor[..16]
+        .iter()
+        .fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+}
+
+/// Computes the CRC-ITU-T of `data`, as descriptors are checked with
+fn crc(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for &byte in data {
+        crc ^= u16::from(byte) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_plan {
+    use super::*;
+
+    fn node(path: &str, kind: NodeKind) -> (PathBuf, String, NodeKind) {
+        let path = PathBuf::from(path);
+        let name = path.file_name().unwrap().to_str().unwrap().to_string();
+        (path, name, kind)
+    }
+
+    #[test]
+    fn iso9660_restrictions() {
+        let check_iso = |(path, name, kind): (PathBuf, String, NodeKind)| {
+            check(&path, &name, &kind, Filesystem::Iso9660)
+        };
+        assert_eq!(check_iso(node("a/b.txt", NodeKind::File(1))), None);
+        assert!(check_iso(node("a/b?.txt", NodeKind::File(1))).is_some());
+        assert!(check_iso(node("a/\u{1f600}", NodeKind::File(1))).is_some());
+        assert!(check_iso(node(&"a".repeat(65), NodeKind::File(1))).is_some());
+        assert_eq!(check_iso(node(&"a".repeat(64), NodeKind::File(1))), None);
+        assert!(check_iso(node("a", NodeKind::File(1 << 32))).is_some());
+        assert!(check_iso(node("a", NodeKind::Symlink(PathBuf::from("b")))).is_some());
+        assert_eq!(check_iso(node("1/2/3/4/5/6/7", NodeKind::Dir)), None);
+        assert!(check_iso(node("1/2/3/4/5/6/7/8", NodeKind::Dir)).is_some());
+        // Only directories are nested
+        assert_eq!(check_iso(node("1/2/3/4/5/6/7/8", NodeKind::File(1))), None);
+    }
+
+    #[test]
+    fn udf_restrictions() {
+        let check_udf = |(path, name, kind): (PathBuf, String, NodeKind)| {
+            check(&path, &name, &kind, Filesystem::Udf)
+        };
+        assert_eq!(check_udf(node("a/b?.txt", NodeKind::File(1 << 32))), None);
+        assert_eq!(check_udf(node("1/2/3/4/5/6/7/8/9", NodeKind::Dir)), None);
+        assert_eq!(check_udf(node(&"a".repeat(254), NodeKind::File(1))), None);
+        assert!(check_udf(node(&"a".repeat(255), NodeKind::File(1))).is_some());
+        assert_eq!(
+            check_udf(node(&"\u{263a}".repeat(127), NodeKind::File(1))),
+            None
+        );
+        assert!(check_udf(node(&"\u{263a}".repeat(128), NodeKind::File(1))).is_some());
+        assert_eq!(
+            check_udf(node("a", NodeKind::Symlink(PathBuf::from("../b/./c")))),
+            None
+        );
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn refuses_whole_tree() {
+        use std::os::unix::fs::symlink;
+
+        const TEST_DIR: &str = "test_image_plan_refuses_whole_tree";
+
+        fs::create_dir_all([TEST_DIR, "a"].join("/")).unwrap();
+        fs::write([TEST_DIR, "a/b"].join("/"), b"b").unwrap();
+        symlink("b", [TEST_DIR, "a/link"].join("/")).unwrap();
+
+        let error = plan(TEST_DIR, Filesystem::Iso9660, None).unwrap_err();
+        assert!(error.to_string().contains("1 entries"));
+
+        let nodes = plan(TEST_DIR, Filesystem::Udf, None).unwrap();
+        let paths: Vec<&Path> = nodes.iter().map(|node| node.path.as_path()).collect();
+        assert_eq!(
+            paths,
+            [
+                Path::new(""),
+                Path::new("a"),
+                Path::new("a/b"),
+                Path::new("a/link")
+            ]
+        );
+        assert_eq!(nodes[0].children, [1]);
+        assert_eq!(nodes[1].children, [2, 3]);
+        assert_eq!(nodes[3].parent, 1);
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod test_iso9660 {
+    use super::*;
+
+    fn nodes(names: &[(&str, NodeKind)]) -> Vec<Node> {
+        let metadata = EntryMetadata {
+            mode: 0o755,
+            uid: 0,
+            gid: 0,
+            mtime: 0,
+        };
+        let mut nodes = vec![Node {
+            path: PathBuf::new(),
+            name: String::new(),
+            kind: NodeKind::Dir,
+            metadata,
+            parent: 0,
+            children: (1..=names.len()).collect(),
+        }];
+        for (name, kind) in names {
+            nodes.push(Node {
+                path: PathBuf::from(name),
+                name: name.to_string(),
+                kind: kind.clone(),
+                metadata,
+                parent: 0,
+                children: Vec::new(),
+            });
+        }
+        nodes
+    }
+
+    #[test]
+    fn primary_names() {
+        let nodes = nodes(&[
+            ("readme.markdown", NodeKind::File(1)),
+            ("README.md", NodeKind::File(1)),
+            ("a long name.tar.gz", NodeKind::File(1)),
+            ("Makefile", NodeKind::File(1)),
+            ("dir.d", NodeKind::Dir),
+            (".hidden", NodeKind::File(1)),
+        ]);
+        let identifiers: Vec<String> = primary_identifiers(&nodes)
+            .into_iter()
+            .map(|identifier| String::from_utf8(identifier).unwrap())
+            .collect();
+        assert_eq!(
+            identifiers,
+            [
+                "",
+                "README.MAR;1",
+                "README.MD;1",
+                "A_LONG_N.GZ;1",
+                "MAKEFILE.;1",
+                "DIR_D",
+                "_.HID;1",
+            ]
+        );
+    }
+
+    #[test]
+    fn primary_collisions() {
+        let nodes = nodes(&[
+            ("long name 1.txt", NodeKind::File(1)),
+            ("long name 2.txt", NodeKind::File(1)),
+            ("long name 3.txt", NodeKind::File(1)),
+        ]);
+        let identifiers = primary_identifiers(&nodes);
+        assert_eq!(identifiers[1], b"LONG_NAM.TXT;1");
+        assert_eq!(identifiers[2], b"LONG_N~1.TXT;1");
+        assert_eq!(identifiers[3], b"LONG_N~2.TXT;1");
+    }
+
+    #[test]
+    fn records_stay_in_sectors() {
+        let records = vec![vec![1; 1000], vec![2; 1000], vec![3; 100]];
+        let extent = pack_records(&records);
+        assert_eq!(extent.len(), 2 * SECTOR_SIZE);
+        assert_eq!(extent[2000], 0);
+        assert_eq!(extent[SECTOR_SIZE], 3);
+    }
+
+    #[test]
+    fn dir_record_layout() {
+        let record = dir_record(b"A.TXT;1", 20, 6, 0, false);
+        assert_eq!(record.len(), 40);
+        assert_eq!(record[0], 40);
+        assert_eq!(&record[2..10], &[20, 0, 0, 0, 0, 0, 0, 20]);
+        assert_eq!(&record[10..18], &[6, 0, 0, 0, 0, 0, 0, 6]);
+        assert_eq!(&record[18..24], &[70, 1, 1, 0, 0, 0]);
+        assert_eq!(record[32], 7);
+        assert_eq!(&record[33..], b"A.TXT;1");
+    }
+}
+
+#[cfg(test)]
+mod test_udf {
+    use super::*;
+
+    #[test]
+    fn crc_itu() {
+        assert_eq!(crc(b"123456789"), 0x31c3);
+    }
+
+    #[test]
+    fn compressed_unicode() {
+        assert_eq!(cs0("ab\u{e9}"), [8, b'a', b'b', 0xe9]);
+        assert_eq!(cs0("a\u{263a}"), [16, 0, b'a', 0x26, 0x3a]);
+    }
+
+    #[test]
+    fn symlink_targets() {
+        assert_eq!(
+            path_components(Path::new("/a/..")).unwrap(),
+            [2, 0, 0, 0, 5, 2, 0, 0, 8, b'a', 3, 0, 0, 0]
+        );
+        assert_eq!(
+            path_components(Path::new("./b")).unwrap(),
+            [4, 0, 0, 0, 5, 2, 0, 0, 8, b'b']
+        );
+    }
+
+    #[test]
+    fn tags_check() {
+        let mut descriptor = vec![0; 512];
+        descriptor[100] = 7;
+        tag(&mut descriptor, 8, 65);
+        let checksum = descriptor[..16]
+            .iter()
+            .enumerate()
+            .filter(|&(i, _)| i != 4)
+            .fold(0u8, |sum, (_, &byte)| sum.wrapping_add(byte));
+        assert_eq!(descriptor[4], checksum);
+        assert_eq!(
+            u16::from_le_bytes([descriptor[8], descriptor[9]]),
+            crc(&descriptor[16..])
+        );
+        assert_eq!(u16::from_le_bytes([descriptor[10], descriptor[11]]), 496);
+        assert_eq!(&descriptor[12..16], &65u32.to_le_bytes());
+    }
+
+    #[test]
+    fn large_files_have_extents() {
+        assert_eq!(extents(0), Vec::<u64>::new());
+        assert_eq!(extents(UDF_MAX_EXTENT + 1), [UDF_MAX_EXTENT, 1]);
+    }
+}
+
+#[cfg(test)]
+mod test_write_image {
+    use super::*;
+
+    fn tree(dir: &str) {
+        fs::create_dir_all([dir, "b/c"].join("/")).unwrap();
+        fs::write([dir, "a.txt"].join("/"), b"file a").unwrap();
+        fs::write([dir, "b/c/d"].join("/"), b"file d").unwrap();
+        fs::write([dir, "empty"].join("/"), b"").unwrap();
+    }
+
+    #[test]
+    fn iso9660() {
+        const TEST_DIR: &str = "test_image_write_image_iso9660";
+        const TEST_DIR_OUT: &str = "test_image_write_image_iso9660_out";
+
+        tree(TEST_DIR);
+        fs::create_dir_all(TEST_DIR_OUT).unwrap();
+        let options = Options {
+            deterministic: true,
+            ..Options::default()
+        };
+        let dest = [TEST_DIR_OUT, "backup.iso"].join("/");
+        write_image(TEST_DIR, &dest, &options).unwrap();
+        let image = fs::read(&dest).unwrap();
+
+        let primary = &image[16 * SECTOR_SIZE..17 * SECTOR_SIZE];
+        assert_eq!(&primary[..7], b"\x01CD001\x01");
+        assert_eq!(&primary[40..46], b"BACKUP");
+        let sectors = u32::from_le_bytes([primary[80], primary[81], primary[82], primary[83]]);
+        assert_eq!(sectors as usize * SECTOR_SIZE, image.len());
+        let joliet = &image[17 * SECTOR_SIZE..18 * SECTOR_SIZE];
+        assert_eq!(&joliet[..7], b"\x02CD001\x01");
+        assert_eq!(&joliet[88..91], b"%/E");
+        assert_eq!(image[18 * SECTOR_SIZE], 255);
+        assert!(image.windows(6).any(|window| window == b"file d"));
+
+        // Deterministic images are the same every time
+        write_image(TEST_DIR, &dest, &options).unwrap();
+        assert_eq!(fs::read(&dest).unwrap(), image);
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_dir_all(TEST_DIR_OUT).unwrap();
+    }
+
+    #[test]
+    fn udf() {
+        const TEST_DIR: &str = "test_image_write_image_udf";
+        const TEST_DIR_OUT: &str = "test_image_write_image_udf_out";
+
+        tree(TEST_DIR);
+        fs::create_dir_all(TEST_DIR_OUT).unwrap();
+        let options = Options {
+            filesystem: Filesystem::Udf,
+            deterministic: true,
+            ..Options::default()
+        };
+        let dest = [TEST_DIR_OUT, "backup.iso"].join("/");
+        write_image(TEST_DIR, &dest, &options).unwrap();
+        let image = fs::read(&dest).unwrap();
+
+        assert_eq!(&image[16 * SECTOR_SIZE + 1..16 * SECTOR_SIZE + 6], b"BEA01");
+        assert_eq!(&image[17 * SECTOR_SIZE + 1..17 * SECTOR_SIZE + 6], b"NSR02");
+        let sectors = image.len() / SECTOR_SIZE;
+        for anchor in [UDF_ANCHOR as usize, sectors - 1] {
+            let descriptor = &image[anchor * SECTOR_SIZE..anchor * SECTOR_SIZE + 512];
+            assert_eq!(&descriptor[..2], &2u16.to_le_bytes());
+            assert_eq!(&descriptor[12..16], &(anchor as u32).to_le_bytes());
+            assert_eq!(&descriptor[20..24], &32u32.to_le_bytes());
+            assert_eq!(
+                u16::from_le_bytes([descriptor[8], descriptor[9]]),
+                crc(&descriptor[16..])
+            );
+        }
+        // The file set descriptor is the first block of the partition
+        let file_set = (UDF_ANCHOR as usize + 1) * SECTOR_SIZE;
+        assert_eq!(&image[file_set..file_set + 2], &256u16.to_le_bytes());
+        assert!(image.windows(6).any(|window| window == b"file d"));
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_dir_all(TEST_DIR_OUT).unwrap();
+    }
+}
@@ -0,0 +1,367 @@
+//! Watches a directory tree for changes with inotify, see `watch`
+
+use std::ffi::{CString, OsStr};
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+use hashbrown::{HashMap, HashSet};
+use log::warn;
+
+use crate::lumins::watch::{Change, Event};
+
+/// Events that are watched for in every directory
+const WATCHED: u32 = libc::IN_CREATE
+    | libc::IN_MODIFY
+    | libc::IN_ATTRIB
+    | libc::IN_CLOSE_WRITE
+    | libc::IN_DELETE
+    | libc::IN_MOVED_FROM
+    | libc::IN_MOVED_TO
+    | libc::IN_DELETE_SELF
+    | libc::IN_ONLYDIR
+    | libc::IN_DONT_FOLLOW;
+
+/// How long a batch of events is read for, at most, so that changes that do not stop, such as to
+/// files that are kept open and written, such as logs, are still synchronized
+const MAX_WAIT: Duration = Duration::from_secs(60);
+
+/// A struct that represents an inotify instance that watches every directory of a tree
+#[derive(Debug)]
+pub struct Watcher {
+    fd: RawFd,
+    root: PathBuf,
+    /// The directory of each watch, relative to `root`
+    dirs: HashMap<libc::c_int, PathBuf>,
+    /// Files that were written to but not yet closed, relative to `root`
+    writing: HashSet<PathBuf>,
+}
+
+impl Watcher {
+    /// Starts watching `root` and every directory in it
+    ///
+    /// # Errors
+    /// This function will return an error if inotify could not be started, or `root` could not be
+    /// watched
+    pub fn new(root: &Path) -> Result<Self, io::Error> {
+        // Safe since inotify_init1 takes no pointers
+        let fd = match unsafe { libc::inotify_init1(libc::IN_CLOEXEC) } {
+            -1 => return Err(io::Error::last_os_error()),
+            fd => fd,
+        };
+        let mut watcher = Watcher {
+            fd,
+            root: root.to_path_buf(),
+            dirs: HashMap::new(),
+            writing: HashSet::new(),
+        };
+        watcher.add(Path::new(""))?;
+        watcher.add_tree(Path::new(""));
+        Ok(watcher)
+    }
+
+    /// Watches the directory `relative`
+    fn add(&mut self, relative: &Path) -> Result<(), io::Error> {
+        let path = CString::new(self.root.join(relative).as_os_str().as_bytes())?;
+        // Safe since `path` is a valid null-terminated string
+        match unsafe { libc::inotify_add_watch(self.fd, path.as_ptr(), WATCHED) } {
+            -1 => Err(io::Error::last_os_error()),
+            wd => {
+                self.dirs.insert(wd, relative.to_path_buf());
+                Ok(())
+            }
+        }
+    }
+
+    /// Watches every directory below the watched directory `relative`, logging those that could
+    /// not be watched
+    fn add_tree(&mut self, relative: &Path) {
+        let entries = match fs::read_dir(self.root.join(relative)) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("Could not watch {:?}: {}", self.root.join(relative), e);
+                return;
+            }
+        };
+        for entry in entries.flatten() {
+            if entry.file_type().map(|kind| kind.is_dir()).unwrap_or(false) {
+                let dir = relative.join(entry.file_name());
+                match self.add(&dir) {
+                    Ok(_) => self.add_tree(&dir),
+                    Err(e) => warn!("Could not watch {:?}: {}", self.root.join(&dir), e),
+                }
+            }
+        }
+    }
+
+    /// Stops watching the directory `relative` and every directory in it, such as when they are
+    /// moved out of the tree
+    fn remove_tree(&mut self, relative: &Path) {
+        let fd = self.fd;
+        self.dirs.retain(|&wd, dir| {
+            if dir.starts_with(relative) {
+                // Safe since inotify_rm_watch takes no pointers
+                unsafe { libc::inotify_rm_watch(fd, wd) };
+                return false;
+            }
+            true
+        });
+    }
+
+    /// Moves the watched directory `from` and every directory in it to `to`, along with the files
+    /// in them that are being written
+    fn rename_tree(&mut self, from: &Path, to: &Path) {
+        for dir in self.dirs.values_mut() {
+            if let Ok(rest) = dir.strip_prefix(from) {
+                *dir = to.join(rest);
+            }
+        }
+        self.writing = std::mem::take(&mut self.writing)
+            .into_iter()
+            .map(|file| match file.strip_prefix(from) {
+                Ok(rest) => to.join(rest),
+                Err(_) => file,
+            })
+            .collect();
+    }
+
+    /// Checks whether any file that `events` changed is still being written
+    fn writes_in_progress(&self, events: &[Event]) -> bool {
+        !self.writing.is_empty()
+            && events
+                .iter()
+                .any(|event| self.writing.contains(&event.path))
+    }
+
+    /// Waits for the tree to change
+    ///
+    /// Events are read until none arrive for `debounce`, so that a burst of them is read as one
+    /// batch, and both halves of a rename are read together, or until there are `max_batch` of
+    /// them. A batch in which files are still being written, that is, were written to but not yet
+    /// closed, waits for them to be closed too, so that they are not synchronized half-written.
+    /// Batches are read for up to `MAX_WAIT`, however many events keep arriving. Directories that
+    /// are created or moved into the tree are watched too, and renames within the tree are paired
+    /// into one event, where a rename out of the tree is a deletion, and into it a creation.
+    ///
+    /// # Returns
+    /// Every change, in the order it happened. If events were lost, because the kernel queue
+    /// overflowed, a `Change::Overflow` of the whole tree is among them.
+    ///
+    /// # Errors
+    /// This function will return an error if inotify could not be read, or the root of the tree
+    /// was deleted
+    pub fn next_events(
+        &mut self,
+        debounce: Duration,
+        max_batch: Option<usize>,
+    ) -> Result<Vec<Event>, io::Error> {
+        let mut events = Vec::new();
+        // Renames out of a directory, by their cookie, until the rename into one is read
+        let mut moved_from: HashMap<u32, usize> = HashMap::new();
+        let mut buffer = vec![0u8; 1 << 16];
+        let mut started: Option<Instant> = None;
+        loop {
+            let timeout = match started.map(|started| MAX_WAIT.checked_sub(started.elapsed())) {
+                None => None,
+                Some(None) => break,
+                Some(Some(left)) if self.writes_in_progress(&events) => Some(left),
+                Some(Some(left)) => Some(debounce.min(left)),
+            };
+            if !self.wait(timeout)? {
+                break;
+            }
+            // Safe since `buffer` is valid for its length
+            let len = match unsafe {
+                libc::read(
+                    self.fd,
+                    buffer.as_mut_ptr() as *mut libc::c_void,
+                    buffer.len(),
+                )
+            } {
+                -1 => return Err(io::Error::last_os_error()),
+                len => len as usize,
+            };
+            let time = SystemTime::now();
+
+            let mut offset = 0;
+            while offset + std::mem::size_of::<libc::inotify_event>() <= len {
+                // Safe since the kernel writes whole events, which may not be aligned
+                let event: libc::inotify_event =
+                    unsafe { std::ptr::read_unaligned(buffer[offset..].as_ptr() as *const _) };
+                let name_start = offset + std::mem::size_of::<libc::inotify_event>();
+                offset = name_start + event.len as usize;
+                let name = &buffer[name_start..offset];
+                let name = OsStr::from_bytes(
+                    &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())],
+                );
+
+                if event.mask & libc::IN_Q_OVERFLOW != 0 {
+                    events.push(Event::new(Change::Overflow, PathBuf::new(), true, time));
+                    continue;
+                }
+                let dir = match self.dirs.get(&event.wd) {
+                    Some(dir) => dir.clone(),
+                    None => continue,
+                };
+                if event.mask & libc::IN_IGNORED != 0 {
+                    self.dirs.remove(&event.wd);
+                    continue;
+                }
+                if event.mask & libc::IN_DELETE_SELF != 0 {
+                    if dir.as_os_str().is_empty() {
+                        return Err(io::Error::other(format!(
+                            "Watch Error -- {:?} was removed",
+                            self.root
+                        )));
+                    }
+                    continue;
+                }
+
+                let path = dir.join(name);
+                let is_dir = event.mask & libc::IN_ISDIR != 0;
+                if event.mask & libc::IN_MODIFY != 0 && !is_dir {
+                    self.writing.insert(path.clone());
+                } else if event.mask & libc::IN_CLOSE_WRITE != 0 {
+                    self.writing.remove(&path);
+                } else if event.mask & (libc::IN_DELETE | libc::IN_MOVED_FROM) != 0 {
+                    self.writing.retain(|file| !file.starts_with(&path));
+                }
+                let change = match event.mask {
+                    mask if mask & libc::IN_CREATE != 0 => Change::Create,
+                    mask if mask & libc::IN_DELETE != 0 => Change::Delete,
+                    mask if mask & libc::IN_MOVED_FROM != 0 => {
+                        moved_from.insert(event.cookie, events.len());
+                        Change::Delete
+                    }
+                    mask if mask & libc::IN_MOVED_TO != 0 => {
+                        match moved_from.remove(&event.cookie) {
+                            Some(i) => {
+                                let from = std::mem::take(&mut events[i].path);
+                                if is_dir {
+                                    self.rename_tree(&from, &path);
+                                }
+                                events[i].change = Change::Rename(from);
+                                events[i].path = path;
+                                continue;
+                            }
+                            None => Change::Create,
+                        }
+                    }
+                    _ => Change::Modify,
+                };
+                if is_dir && change == Change::Create {
+                    match self.add(&path) {
+                        Ok(_) => self.add_tree(&path),
+                        Err(e) => warn!("Could not watch {:?}: {}", self.root.join(&path), e),
+                    }
+                }
+
+                // Writes are reported once, however many times they are made
+                if change == Change::Modify
+                    && events.last().is_some_and(|last: &Event| {
+                        last.change == Change::Modify && last.path == path
+                    })
+                {
+                    continue;
+                }
+                events.push(Event::new(change, path, is_dir, time));
+            }
+            if !events.is_empty() {
+                started.get_or_insert_with(Instant::now);
+            }
+            if max_batch.is_some_and(|max_batch| events.len() >= max_batch) {
+                break;
+            }
+        }
+
+        // Directories that were moved out of the tree are no longer watched
+        for &i in moved_from.values() {
+            if events[i].dir {
+                let path = events[i].path.clone();
+                self.remove_tree(&path);
+            }
+        }
+        Ok(events)
+    }
+
+    /// Waits up to `timeout`, or forever if it is None, for events to read
+    ///
+    /// # Returns
+    /// Whether there are events to read
+    fn wait(&self, timeout: Option<Duration>) -> Result<bool, io::Error> {
+        let timeout = timeout.map_or(-1, |timeout| {
+            timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int
+        });
+        let mut poll = libc::pollfd {
+            fd: self.fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        loop {
+            // Safe since `poll` is valid for the duration of the call
+            match unsafe { libc::poll(&mut poll, 1, timeout) } {
+                -1 if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted => {}
+                -1 => return Err(io::Error::last_os_error()),
+                ready => return Ok(ready > 0),
+            }
+        }
+    }
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        // Safe since `fd` is owned by this watcher
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_inotify {
+    use super::*;
+    use std::io::Write;
+    use std::thread;
+
+    #[test]
+    fn batches_changes() {
+        const TEST_DIR: &str = "test_inotify_batches_changes";
+
+        fs::create_dir_all(TEST_DIR).unwrap();
+        let mut watcher = Watcher::new(Path::new(TEST_DIR)).unwrap();
+
+        // Files that are still being written hold the batch back until they are closed
+        let mut file = fs::File::create([TEST_DIR, "file"].join("/")).unwrap();
+        file.write_all(b"first").unwrap();
+        let writer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            file.write_all(b"second").unwrap();
+        });
+        let start = Instant::now();
+        let events = watcher
+            .next_events(Duration::from_millis(20), None)
+            .unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        writer.join().unwrap();
+        assert_eq!(events[0].change, Change::Create);
+        assert!(events.iter().all(|event| event.path == Path::new("file")));
+
+        // Batches end once they are large enough, however long the debounce is
+        for i in 0..4 {
+            fs::write([TEST_DIR, &i.to_string()].join("/"), b"file").unwrap();
+        }
+        let start = Instant::now();
+        let events = watcher
+            .next_events(Duration::from_secs(10), Some(2))
+            .unwrap();
+        assert!(start.elapsed() < Duration::from_secs(10));
+        assert!(events.len() >= 2);
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+}
@@ -0,0 +1,262 @@
+//! Keeps a journal of completed files, so that interrupted synchronizations can be resumed
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+
+use hashbrown::HashMap;
+use log::error;
+
+/// Name of the journal, in the root of the destination
+pub const JOURNAL_NAME: &str = ".lumins-state";
+
+/// The state of a source file when it was completed, which is the size and the modification time
+/// in nanoseconds since the Unix epoch
+type FileState = (u64, u128);
+
+/// A journal of files that have been completely synchronized into a destination
+///
+/// Every completed file is appended to the journal as soon as it is done, with the size and
+/// modification time of its source. A file is only skipped on a rerun if its source still has
+/// that size and modification time.
+#[derive(Debug)]
+pub struct Journal {
+    completed: HashMap<PathBuf, FileState>,
+    file: Mutex<fs::File>,
+}
+
+impl Journal {
+    /// Opens the journal in `dest`, loading the files completed by earlier runs
+    ///
+    /// Lines that can not be parsed, such as one that was cut off when the last run was killed,
+    /// are ignored.
+    ///
+    /// # Errors
+    /// This function will return an error if the journal can not be read or created
+    pub fn open(dest: &str) -> Result<Self, io::Error> {
+        let path = Path::new(dest).join(JOURNAL_NAME);
+        let journal_error =
+            |e: io::Error| io::Error::new(e.kind(), format!("Journal Error -- {:?}: {}", path, e));
+
+        let contents = match fs::read(&path) {
+            Ok(contents) => contents,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(journal_error(e)),
+        };
+        let completed = contents
+            .split(|&b| b == b'\n')
+            .filter_map(parse_line)
+            .collect();
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(journal_error)?;
+
+        // End an incomplete last line, so that it does not run into the next one
+        if !contents.is_empty() && !contents.ends_with(b"\n") {
+            file.write_all(b"\n").map_err(journal_error)?;
+        }
+
+        Ok(Journal {
+            completed,
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Checks whether `relative` was completed by an earlier run, and its source in `src` has not
+    /// changed since
+    pub fn is_completed(&self, src: &str, relative: &Path) -> bool {
+        match (
+            self.completed.get(relative),
+            file_state(&Path::new(src).join(relative)),
+        ) {
+            (Some(completed), Some(state)) => *completed == state,
+            _ => false,
+        }
+    }
+
+    /// Appends `relative` to the journal, with the state of its source in `src`
+    ///
+    /// Only regular files are journaled. Each file is written with a single write, so that a
+    /// killed run leaves at most its last line incomplete.
+    pub fn record(&self, src: &Path, relative: &Path) {
+        let (size, mtime) = match file_state(&src.join(relative)) {
+            Some(state) => state,
+            None => return,
+        };
+        let path = match escape_path(relative) {
+            Some(path) => path,
+            None => return,
+        };
+
+        let mut line = format!("{} {} ", size, mtime).into_bytes();
+        line.extend(path);
+        line.push(b'\n');
+
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = file.write_all(&line) {
+            error!("Journal Error -- Recording {:?}: {}", relative, e);
+        }
+    }
+
+    /// Removes the journal from `dest`, once a synchronization has completed
+    ///
+    /// # Errors
+    /// This function will return an error if the journal exists and could not be removed
+    pub fn finish(&self, dest: &str) -> Result<(), io::Error> {
+        match fs::remove_file(Path::new(dest).join(JOURNAL_NAME)) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+}
+
+/// Gets the size and modification time of `path`, if it is a regular file
+fn file_state(path: &Path) -> Option<FileState> {
+    let metadata = fs::symlink_metadata(path).ok()?;
+    if !metadata.is_file() {
+        return None;
+    }
+    let mtime = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    Some((metadata.len(), mtime.as_nanos()))
+}
+
+/// Parses a journal line, `<size> <mtime> <escaped path>`
+fn parse_line(line: &[u8]) -> Option<(PathBuf, FileState)> {
+    let mut fields = line.splitn(3, |&b| b == b' ');
+    let size = std::str::from_utf8(fields.next()?).ok()?.parse().ok()?;
+    let mtime = std::str::from_utf8(fields.next()?).ok()?.parse().ok()?;
+    let path = unescape_path(fields.next()?)?;
+    Some((path, (size, mtime)))
+}
+
+/// Escapes `path` into printable ASCII, so that it fits on one journal line
+///
+/// Bytes other than printable ASCII, and backslashes, are written as `\xHH`
+pub fn escape_path(path: &Path) -> Option<Vec<u8>> {
+    #[cfg(target_family = "unix")]
+    let bytes = {
+        use std::os::unix::ffi::OsStrExt;
+        path.as_os_str().as_bytes()
+    };
+    #[cfg(not(target_family = "unix"))]
+    let bytes = path.to_str()?.as_bytes();
+
+    let mut escaped = Vec::with_capacity(bytes.len());
+    for &b in bytes {
+        if b.is_ascii_graphic() && b != b'\\' || b == b' ' {
+            escaped.push(b);
+        } else {
+            escaped.extend(format!("\\x{:02x}", b).bytes());
+        }
+    }
+    Some(escaped)
+}
+
+/// Reverses `escape_path`
+pub fn unescape_path(escaped: &[u8]) -> Option<PathBuf> {
+    let mut bytes = Vec::with_capacity(escaped.len());
+    let mut i = 0;
+    while i < escaped.len() {
+        if escaped[i] == b'\\' {
+            let hex = std::str::from_utf8(escaped.get(i + 2..i + 4)?).ok()?;
+            if escaped[i + 1] != b'x' {
+                return None;
+            }
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 4;
+        } else {
+            bytes.push(escaped[i]);
+            i += 1;
+        }
+    }
+
+    #[cfg(target_family = "unix")]
+    {
+        use std::ffi::OsString;
+        use std::os::unix::ffi::OsStringExt;
+        Some(PathBuf::from(OsString::from_vec(bytes)))
+    }
+    #[cfg(not(target_family = "unix"))]
+    {
+        String::from_utf8(bytes).ok().map(PathBuf::from)
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_journal {
+    use super::*;
+
+    #[test]
+    fn escaping() {
+        for path in &["a b/c", "a\nb", "a\\x41", "ü"] {
+            let escaped = escape_path(Path::new(path)).unwrap();
+            assert!(escaped.iter().all(|&b| b.is_ascii_graphic() || b == b' '));
+            assert_eq!(unescape_path(&escaped).unwrap(), PathBuf::from(path));
+        }
+        assert!(unescape_path(b"a\\x4").is_none());
+        assert!(unescape_path(b"a\\y41").is_none());
+    }
+
+    #[test]
+    fn invalid_lines() {
+        assert!(parse_line(b"").is_none());
+        assert!(parse_line(b"12 34").is_none());
+        assert!(parse_line(b"a 34 path").is_none());
+        assert_eq!(
+            parse_line(b"12 34 a b"),
+            Some((PathBuf::from("a b"), (12, 34)))
+        );
+    }
+
+    #[test]
+    fn resume() {
+        const TEST_DIR: &str = "test_journal_resume";
+        const TEST_DIR_OUT: &str = "test_journal_resume_out";
+
+        fs::create_dir_all(TEST_DIR).unwrap();
+        fs::create_dir_all(TEST_DIR_OUT).unwrap();
+        fs::write([TEST_DIR, "done"].join("/"), b"1234").unwrap();
+        fs::write([TEST_DIR, "changed"].join("/"), b"1234").unwrap();
+        fs::write([TEST_DIR, "after crash"].join("/"), b"1234").unwrap();
+
+        let journal = Journal::open(TEST_DIR_OUT).unwrap();
+        journal.record(Path::new(TEST_DIR), Path::new("done"));
+        journal.record(Path::new(TEST_DIR), Path::new("changed"));
+        journal.record(Path::new(TEST_DIR), Path::new("missing"));
+        drop(journal);
+
+        // Simulate a run that was killed while writing a line
+        OpenOptions::new()
+            .append(true)
+            .open([TEST_DIR_OUT, JOURNAL_NAME].join("/"))
+            .unwrap()
+            .write_all(b"4 1")
+            .unwrap();
+        fs::write([TEST_DIR, "changed"].join("/"), b"12345").unwrap();
+
+        let journal = Journal::open(TEST_DIR_OUT).unwrap();
+        journal.record(Path::new(TEST_DIR), Path::new("after crash"));
+        drop(journal);
+
+        let journal = Journal::open(TEST_DIR_OUT).unwrap();
+        assert!(journal.is_completed(TEST_DIR, Path::new("done")));
+        assert!(!journal.is_completed(TEST_DIR, Path::new("changed")));
+        assert!(journal.is_completed(TEST_DIR, Path::new("after crash")));
+        assert!(!journal.is_completed(TEST_DIR, Path::new("missing")));
+
+        journal.finish(TEST_DIR_OUT).unwrap();
+        assert!(fs::read_dir(TEST_DIR_OUT).unwrap().next().is_none());
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_dir_all(TEST_DIR_OUT).unwrap();
+    }
+}
@@ -0,0 +1,366 @@
+//! A minimal JSON reader and writer, for the few JSON documents that LuminS reads and writes
+
+use std::fmt;
+
+/// Enum to represent a parsed JSON value
+///
+/// Numbers are kept as their text, so that integers are never rounded through a float.
+/// Objects keep their members in order.
+#[derive(PartialEq, Debug, Clone)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(String),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    /// Gets the member `key` of an object
+    ///
+    /// # Returns
+    /// * Some: The value of the first member named `key`
+    /// * None: If this is not an object, or has no member named `key`
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Object(members) => members
+                .iter()
+                .find(|(name, _)| name == key)
+                .map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    /// Gets the value of a string
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(string) => Some(string),
+            _ => None,
+        }
+    }
+
+    /// Gets the value of a number that is a non-negative integer
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            Value::Number(number) => number.parse().ok(),
+            _ => None,
+        }
+    }
+
+    /// Gets the elements of an array
+    pub fn as_array(&self) -> Option<&[Value]> {
+        match self {
+            Value::Array(elements) => Some(elements),
+            _ => None,
+        }
+    }
+}
+
+/// A struct that represents an error in a JSON document, at a byte offset into it
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub struct ParseError {
+    pub offset: usize,
+    pub message: &'static str,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at byte {}", self.message, self.offset)
+    }
+}
+
+/// Parses the JSON document `json`
+///
+/// # Errors
+/// This function will return an error if `json` is not a single valid JSON value
+pub fn parse(json: &str) -> Result<Value, ParseError> {
+    let mut parser = Parser {
+        bytes: json.as_bytes(),
+        offset: 0,
+    };
+    let value = parser.value()?;
+    parser.whitespace();
+    if parser.offset < parser.bytes.len() {
+        return Err(parser.error("trailing characters"));
+    }
+    Ok(value)
+}
+
+/// Serializes `value` as a JSON string
+pub fn string(value: &str) -> String {
+    let mut json = String::with_capacity(value.len() + 2);
+    json.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+/// A recursive descent parser over the bytes of a JSON document
+struct Parser<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, message: &'static str) -> ParseError {
+        ParseError {
+            offset: self.offset,
+            message,
+        }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.bytes.get(self.offset).copied()
+    }
+
+    fn whitespace(&mut self) {
+        while let Some(b' ') | Some(b'\t') | Some(b'\n') | Some(b'\r') = self.peek() {
+            self.offset += 1;
+        }
+    }
+
+    /// Consumes `literal` if the document continues with it
+    fn literal(&mut self, literal: &str) -> bool {
+        if self.bytes[self.offset..].starts_with(literal.as_bytes()) {
+            self.offset += literal.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, ParseError> {
+        self.whitespace();
+        match self.peek() {
+            Some(b'{') => self.object(),
+            Some(b'[') => self.array(),
+            Some(b'"') => self.string().map(Value::String),
+            Some(b'-') | Some(b'0'..=b'9') => self.number(),
+            _ if self.literal("null") => Ok(Value::Null),
+            _ if self.literal("true") => Ok(Value::Bool(true)),
+            _ if self.literal("false") => Ok(Value::Bool(false)),
+            Some(_) => Err(self.error("expected a value")),
+            None => Err(self.error("unexpected end of document")),
+        }
+    }
+
+    fn object(&mut self) -> Result<Value, ParseError> {
+        self.offset += 1;
+        let mut members = Vec::new();
+        self.whitespace();
+        if self.literal("}") {
+            return Ok(Value::Object(members));
+        }
+        loop {
+            self.whitespace();
+            if self.peek() != Some(b'"') {
+                return Err(self.error("expected a member name"));
+            }
+            let name = self.string()?;
+            self.whitespace();
+            if !self.literal(":") {
+                return Err(self.error("expected ':'"));
+            }
+            members.push((name, self.value()?));
+            self.whitespace();
+            if self.literal("}") {
+                return Ok(Value::Object(members));
+            }
+            if !self.literal(",") {
+                return Err(self.error("expected ',' or '}'"));
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Value, ParseError> {
+        self.offset += 1;
+        let mut elements = Vec::new();
+        self.whitespace();
+        if self.literal("]") {
+            return Ok(Value::Array(elements));
+        }
+        loop {
+            elements.push(self.value()?);
+            self.whitespace();
+            if self.literal("]") {
+                return Ok(Value::Array(elements));
+            }
+            if !self.literal(",") {
+                return Err(self.error("expected ',' or ']'"));
+            }
+        }
+    }
+
+    fn number(&mut self) -> Result<Value, ParseError> {
+        let start = self.offset;
+        while let Some(b'-') | Some(b'+') | Some(b'.') | Some(b'e') | Some(b'E')
+        | Some(b'0'..=b'9') = self.peek()
+        {
+            self.offset += 1;
+        }
+        // Safe to unwrap since the number only has ASCII characters
+        let number = std::str::from_utf8(&self.bytes[start..self.offset]).unwrap();
+        if number.parse::<f64>().is_err() {
+            self.offset = start;
+            return Err(self.error("invalid number"));
+        }
+        Ok(Value::Number(number.to_string()))
+    }
+
+    fn string(&mut self) -> Result<String, ParseError> {
+        self.offset += 1;
+        let mut string = Vec::new();
+        loop {
+            match self.peek() {
+                Some(b'"') => {
+                    self.offset += 1;
+                    // Safe to unwrap since the document is a str, and escapes are valid chars
+                    return Ok(String::from_utf8(string).unwrap());
+                }
+                Some(b'\\') => {
+                    self.offset += 1;
+                    let escaped = match self.peek() {
+                        Some(b'u') => {
+                            self.offset += 1;
+                            self.unicode_escape()?
+                        }
+                        Some(b) => {
+                            let escaped = match b {
+                                b'"' => '"',
+                                b'\\' => '\\',
+                                b'/' => '/',
+                                b'b' => '\u{8}',
+                                b'f' => '\u{c}',
+                                b'n' => '\n',
+                                b'r' => '\r',
+                                b't' => '\t',
+                                _ => return Err(self.error("invalid escape")),
+                            };
+                            self.offset += 1;
+                            escaped
+                        }
+                        None => return Err(self.error("unterminated string")),
+                    };
+                    let mut buffer = [0; 4];
+                    string.extend(escaped.encode_utf8(&mut buffer).as_bytes());
+                }
+                Some(b) if b < 0x20 => return Err(self.error("control character in string")),
+                Some(b) => {
+                    string.push(b);
+                    self.offset += 1;
+                }
+                None => return Err(self.error("unterminated string")),
+            }
+        }
+    }
+
+    /// Parses the 4 hex digits after `\u`, and the low surrogate after them, if any
+    fn unicode_escape(&mut self) -> Result<char, ParseError> {
+        let high = self.hex4()?;
+        let code = if (0xd800..0xdc00).contains(&high) {
+            if !self.literal("\\u") {
+                return Err(self.error("unpaired surrogate"));
+            }
+            let low = self.hex4()?;
+            if !(0xdc00..0xe000).contains(&low) {
+                return Err(self.error("unpaired surrogate"));
+            }
+            0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00)
+        } else {
+            high
+        };
+        std::char::from_u32(code).ok_or_else(|| self.error("invalid unicode escape"))
+    }
+
+    fn hex4(&mut self) -> Result<u32, ParseError> {
+        let hex = self
+            .bytes
+            .get(self.offset..self.offset + 4)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+            .ok_or_else(|| self.error("invalid unicode escape"))?;
+        self.offset += 4;
+        Ok(hex)
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_json {
+    use super::*;
+
+    #[test]
+    fn strings() {
+        assert_eq!(string("a\"b\\c\n"), "\"a\\\"b\\\\c\\u000a\"");
+        for value in &["", "a\"b\\c\n\u{1}", "ü 🦀"] {
+            assert_eq!(
+                parse(&string(value)).unwrap(),
+                Value::String(value.to_string())
+            );
+        }
+        assert_eq!(
+            parse(r#""\/\b\u00fc\ud83e\udd80""#).unwrap(),
+            Value::String("/\u{8}ü🦀".to_string())
+        );
+    }
+
+    #[test]
+    fn values() {
+        let value = parse(r#" {"a": [1, -2.5e3, true, false, null], "b": {}, "c": "d"} "#).unwrap();
+        assert_eq!(
+            value.get("a").unwrap().as_array().unwrap(),
+            &[
+                Value::Number("1".to_string()),
+                Value::Number("-2.5e3".to_string()),
+                Value::Bool(true),
+                Value::Bool(false),
+                Value::Null
+            ]
+        );
+        assert_eq!(value.get("b"), Some(&Value::Object(Vec::new())));
+        assert_eq!(value.get("c").and_then(Value::as_str), Some("d"));
+        assert_eq!(value.get("e"), None);
+        assert_eq!(
+            parse("18446744073709551615").unwrap().as_u64(),
+            Some(u64::MAX)
+        );
+    }
+
+    #[test]
+    fn invalid_documents() {
+        for json in &[
+            "",
+            "{",
+            "[1,]",
+            "{\"a\" 1}",
+            "{1: 2}",
+            "\"a",
+            "\"\\x\"",
+            "\"\\ud83e\"",
+            "\"\n\"",
+            "1 2",
+            "--1",
+            "nul",
+        ] {
+            assert!(parse(json).is_err(), "{:?}", json);
+        }
+        assert_eq!(
+            parse("[1, x]"),
+            Err(ParseError {
+                offset: 4,
+                message: "expected a value"
+            })
+        );
+    }
+}
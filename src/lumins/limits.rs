@@ -0,0 +1,740 @@
+//! Finds the entries whose names or paths are too long for the destination, before anything is
+//! written
+//!
+//! Filesystems limit how long the name of an entry, and the path to it, may be, such as 255 bytes
+//! for names and 4095 bytes for paths on Linux, or 143 bytes for names in eCryptfs. Names that
+//! are too long would otherwise only fail once the run reaches them, while paths that are too long
+//! are written relative to their parents, but can then not be opened by most programs. Both are
+//! reported when the run is planned, and left out with `--skip-long-paths`.
+//!
+//! With `--shorten-names truncate-hash`, names that are too long are shortened instead, keeping
+//! their start and extension with a hash of the whole name, and the names they had are recorded
+//! next to them, so that they can be restored. With `--shorten-names error`, nothing is written
+//! if any name is too long.
+
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use clap::ValueEnum;
+use hashbrown::{HashMap, HashSet};
+use log::{error, info, warn};
+
+use crate::lumins::dirfd;
+use crate::lumins::dirlinks;
+use crate::lumins::file_ops::{self, Dir, File, FileOps, FileSets, Symlink};
+use crate::lumins::journal::{escape_path, unescape_path};
+use crate::lumins::parse::Options;
+use crate::lumins::platform;
+use crate::lumins::sanitize;
+
+/// Name of the sidecar that records the names of the entries that were shortened in a directory
+pub const SHORTENED_NAME: &str = ".lumins-shortened";
+
+/// A struct that represents how long names and paths may be in a destination
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub struct Limits {
+    /// The longest name of an entry
+    pub name_max: usize,
+    /// The longest absolute path of an entry
+    pub path_max: usize,
+}
+
+impl Limits {
+    /// Gets the limits of the filesystem of the directory `dest`, see `platform::path_limits`
+    pub fn of(dest: &Path) -> Self {
+        let (name_max, path_max) = platform::path_limits(dest);
+        Limits { name_max, path_max }
+    }
+}
+
+/// Enum to represent why an entry is too long for the destination
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub enum TooLong {
+    /// Its name has this length
+    Name(usize),
+    /// Its path in the destination has this length
+    Path(usize),
+}
+
+/// Finds the entries of `file_sets` that are too long for `limits`, when written into `dest`
+///
+/// Only the outermost entry is found of a directory whose name or path is too long, since
+/// everything in it is too.
+///
+/// # Arguments
+/// * `file_sets`: entries to write, relative to `dest`
+/// * `dest`: absolute path of the destination
+/// * `limits`: limits of the destination
+///
+/// # Returns
+/// The path of every such entry, with why it is too long, sorted by path
+pub fn too_long(file_sets: &FileSets, dest: &Path, limits: &Limits) -> Vec<(PathBuf, TooLong)> {
+    let dest_len = len(dest.as_os_str());
+    let mut paths: Vec<&PathBuf> = file_sets
+        .files()
+        .iter()
+        .map(FileOps::path)
+        .chain(file_sets.dirs().iter().map(FileOps::path))
+        .chain(file_sets.symlinks().iter().map(FileOps::path))
+        .chain(file_sets.specials().iter().map(FileOps::path))
+        .collect();
+    // Path's ordering compares components, so parents sort before their children
+    paths.sort();
+
+    let mut too_long: Vec<(PathBuf, TooLong)> = Vec::new();
+    let mut found: HashSet<&Path> = HashSet::new();
+    for path in paths {
+        if is_within(path, &found) {
+            continue;
+        }
+        let name_len = path.file_name().map_or(0, len);
+        // Every component is joined to the destination with a separator
+        let path_len = dest_len
+            + path
+                .components()
+                .map(|component| len(component.as_os_str()) + 1)
+                .sum::<usize>();
+        if name_len > limits.name_max {
+            too_long.push((path.clone(), TooLong::Name(name_len)));
+        } else if path_len > limits.path_max {
+            too_long.push((path.clone(), TooLong::Path(path_len)));
+        } else {
+            continue;
+        }
+        found.insert(path);
+    }
+    too_long
+}
+
+/// Checks whether `path` is one of `paths`, or is inside of one of them
+///
+/// Only the directories that `path` is in are looked up, so checking every entry of a tree takes
+/// no longer for many `paths` than for few.
+pub fn is_within<P>(path: &Path, paths: &HashSet<P>) -> bool
+where
+    P: std::borrow::Borrow<Path> + std::hash::Hash + Eq,
+{
+    !paths.is_empty() && path.ancestors().any(|ancestor| paths.contains(ancestor))
+}
+
+/// Enum to represent what is done with entries whose names are too long for the destination, with
+/// `--shorten-names`
+#[derive(Eq, PartialEq, Debug, Clone, Copy, ValueEnum)]
+pub enum ShortenNames {
+    /// Nothing is written
+    Error,
+    /// Names are shortened with a hash of the whole name, as `shorten_name` does
+    TruncateHash,
+}
+
+/// An entry whose name is too long for the destination, and is written under a shorter name, with
+/// `ShortenNames::TruncateHash`, or whose name is sanitized, see `sanitize`
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub struct Shortened {
+    /// Path of the entry, relative to the source
+    pub path: PathBuf,
+    /// Name of the entry in the destination, see `shorten_name`
+    pub name: OsString,
+    /// The entry of the source
+    pub kind: Kind,
+    /// Whether the name it had is recorded next to it, which names that are only sanitized are
+    /// not, since they are restored from the name itself
+    recorded: bool,
+}
+
+impl Shortened {
+    /// Gets the path of the entry in the destination
+    pub fn dest_path(&self) -> PathBuf {
+        self.path.with_file_name(&self.name)
+    }
+}
+
+/// Enum to represent what kind of entry is shortened
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub enum Kind {
+    File(File),
+    Dir(Dir),
+    Symlink(Symlink),
+    Special,
+}
+
+/// Reports the entries of `file_sets` that are too long for `dest`, and leaves them out of
+/// `file_sets` with `Options::skip_long_paths`
+///
+/// With `ShortenNames::TruncateHash`, entries whose names are too long are left out of
+/// `file_sets` as well, to be written under shortened names by `write_shortened`, and so are
+/// entries whose names are escaped or restored by `sanitize::renames`.
+///
+/// # Returns
+/// The entries whose names are shortened
+///
+/// # Errors
+/// This function will return an error if `options.shorten_names` is `ShortenNames::Error`, and
+/// any name is too long
+pub fn check(
+    file_sets: &mut FileSets,
+    dest: &str,
+    options: &Options,
+) -> Result<Vec<Shortened>, io::Error> {
+    // The limits of an existing destination are those of its filesystem
+    let dest = fs::canonicalize(dest).unwrap_or_else(|_| PathBuf::from(dest));
+    let limits = Limits::of(&dest);
+    let mut renames = sanitize::renames(file_sets, options);
+    if limits.name_max == usize::MAX && limits.path_max == usize::MAX && renames.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let too_long = too_long(file_sets, &dest, &limits);
+    let kinds = kinds(
+        file_sets,
+        &(too_long.iter().map(|(path, _)| path.as_path()))
+            .chain(renames.iter().map(|(path, _)| path.as_path()))
+            .collect(),
+    );
+    let kind = |path: &Path| kinds.get(path).cloned().unwrap_or(Kind::Special);
+    let skip = options.skip_long_paths;
+    let shorten = options.shorten_names == Some(ShortenNames::TruncateHash);
+    let mut shortened = Vec::new();
+    let mut left = Vec::new();
+    for (path, reason) in too_long {
+        let action = if skip { "Skipping" } else { "Writing" };
+        match reason {
+            TooLong::Name(length) if shorten => {
+                // Sanitized names are shortened in turn
+                let name = match renames.iter().find(|(renamed, _)| *renamed == path) {
+                    Some((_, name)) => name.as_os_str(),
+                    None => path.file_name().unwrap_or_default(),
+                };
+                let name = shorten_name(name, limits.name_max);
+                info!(
+                    "Shortening {:?} to {:?}, since its name is {} long, while {:?} only allows {}",
+                    dest.join(&path),
+                    name,
+                    length,
+                    dest,
+                    limits.name_max
+                );
+                shortened.push(Shortened {
+                    kind: kind(&path),
+                    path,
+                    name,
+                    recorded: true,
+                });
+                continue;
+            }
+            TooLong::Name(length) => warn!(
+                "Path Warning -- {} {:?}, whose name is {} long, while {:?} only allows {}, so it \
+                 can not be written",
+                action,
+                dest.join(&path),
+                length,
+                dest,
+                limits.name_max
+            ),
+            // Entries are written relative to their parents, so only other programs fail on them
+            TooLong::Path(length) => warn!(
+                "Path Warning -- {} {:?}, whose path is {} long, while {:?} only allows {}, so \
+                 most programs can not open it",
+                action,
+                dest.join(&path),
+                length,
+                dest,
+                limits.path_max
+            ),
+        }
+        left.push((path, reason));
+    }
+
+    // Entries that are written under other names, or left out, are not sanitized again
+    let mut paths: HashSet<PathBuf> = shortened.iter().map(|entry| entry.path.clone()).collect();
+    if skip {
+        paths.extend(left.iter().map(|(path, _)| path.clone()));
+    }
+    renames.retain(|(path, _)| !is_within(path, &paths));
+    for (path, name) in renames {
+        info!(
+            "Writing {:?} as {:?}, since its name has characters that are escaped",
+            dest.join(&path),
+            name
+        );
+        paths.insert(path.clone());
+        shortened.push(Shortened {
+            kind: kind(&path),
+            path,
+            name,
+            recorded: false,
+        });
+    }
+
+    let names = left
+        .iter()
+        .filter(|(_, reason)| matches!(reason, TooLong::Name(_)))
+        .count();
+    if options.shorten_names == Some(ShortenNames::Error) && names > 0 {
+        return Err(io::Error::other(format!(
+            "Path Error -- {} names are too long for {:?}, so nothing is written, unless \
+             --shorten-names truncate-hash shortens them",
+            names, dest
+        )));
+    }
+    if !skip && !left.is_empty() {
+        warn!(
+            "Path Warning -- {} entries are too long for {:?}, which --skip-long-paths leaves out",
+            left.len(),
+            dest
+        );
+    }
+
+    if !paths.is_empty() {
+        file_sets.retain_paths(|path| !is_within(path, &paths));
+    }
+    Ok(shortened)
+}
+
+/// Gets what kind of entry each of `paths` is in `file_sets`, in one pass over `file_sets`
+fn kinds(file_sets: &FileSets, paths: &HashSet<&Path>) -> HashMap<PathBuf, Kind> {
+    let mut kinds = HashMap::new();
+    let wanted = |path: &PathBuf| paths.contains(path.as_path());
+    for file in file_sets.files().iter().filter(|file| wanted(file.path())) {
+        kinds.insert(file.path().clone(), Kind::File(file.clone()));
+    }
+    for dir in file_sets.dirs().iter().filter(|dir| wanted(dir.path())) {
+        kinds.insert(dir.path().clone(), Kind::Dir(dir.clone()));
+    }
+    for symlink in file_sets
+        .symlinks()
+        .iter()
+        .filter(|symlink| wanted(symlink.path()))
+    {
+        kinds.insert(symlink.path().clone(), Kind::Symlink(symlink.clone()));
+    }
+    for special in file_sets
+        .specials()
+        .iter()
+        .filter(|special| wanted(special.path()))
+    {
+        kinds.insert(special.path().clone(), Kind::Special);
+    }
+    kinds
+}
+
+/// Shortens `name` to at most `name_max` long, deterministically
+///
+/// The start of the name and its extension are kept, with a hash of the whole name in between, so
+/// that names which only differ after the start are still different, and the same name is
+/// always shortened the same.
+pub fn shorten_name(name: &OsStr, name_max: usize) -> OsString {
+    // Extensions that are longer than this are not kept
+    const EXTENSION_MAX: usize = 16;
+
+    let lossy = name.to_string_lossy();
+    // Names that are not valid Unicode are hashed as they are, so they are still told apart
+    #[cfg(target_family = "unix")]
+    let bytes = std::os::unix::ffi::OsStrExt::as_bytes(name);
+    #[cfg(not(target_family = "unix"))]
+    let bytes = lossy.as_bytes();
+    let hash = format!("~{:016x}", seahash::hash(bytes));
+    let (stem, extension) = match lossy.rfind('.') {
+        Some(i) if i > 0 && lossy.len() - i <= EXTENSION_MAX => lossy.split_at(i),
+        _ => (lossy.as_ref(), ""),
+    };
+    let extension = if len(OsStr::new(extension)) + len(OsStr::new(&hash)) <= name_max {
+        extension
+    } else {
+        ""
+    };
+
+    let mut left = name_max.saturating_sub(len(OsStr::new(&hash)) + len(OsStr::new(extension)));
+    let mut shortened: String = stem
+        .chars()
+        .take_while(|c| {
+            let length = len(OsStr::new(c.encode_utf8(&mut [0; 4])));
+            let fits = length <= left;
+            left = left.saturating_sub(length);
+            fits
+        })
+        .collect();
+    shortened.push_str(&hash);
+    shortened.push_str(extension);
+    // Limits too short for the hash itself only leave a part of it
+    while len(OsStr::new(&shortened)) > name_max && !shortened.is_empty() {
+        shortened.pop();
+    }
+    OsString::from(shortened)
+}
+
+/// Writes the `shortened` entries of `src` into `dest` under their shortened names, and records
+/// the names they had in a sidecar in each of their directories, see `SHORTENED_NAME`
+///
+/// Files are compared with what is already written under their names, and only copied if they
+/// differ, like any other file, see `file_ops::compare_and_copy_file_as`, and symlinks are only
+/// written again if their targets changed. Directories are written by a run of their own, `run`,
+/// that has the shortened directory as its destination, so what is in them is shortened as well
+/// if it needs to be.
+///
+/// # Errors
+/// This function will return an error if `run` fails
+pub fn write_shortened<F>(
+    shortened: &[Shortened],
+    src: &str,
+    dest: &str,
+    options: &Options,
+    run: F,
+) -> Result<(), io::Error>
+where
+    F: Fn(&str, &str, &Options) -> Result<(), io::Error>,
+{
+    let (src_root, dest_root) = (Path::new(src), Path::new(dest));
+    for entry in shortened {
+        let to = entry.dest_path();
+        let src_path = src_root.join(&entry.path);
+        let dest_path = dest_root.join(&to);
+        let written = match &entry.kind {
+            // What could not be copied is logged as it is for any other file
+            Kind::File(file) => {
+                file_ops::compare_and_copy_file_as(file, src, dest, &to, options);
+                Ok(())
+            }
+            Kind::Dir(_) => match dirfd::create_dir_all(dest_root, &to) {
+                Ok(_) => {
+                    if let Some(linked_options) = dirlinks::linked_options(options, &entry.path) {
+                        run(
+                            &src_path.to_string_lossy(),
+                            &dest_path.to_string_lossy(),
+                            &linked_options,
+                        )?;
+                    }
+                    Ok(())
+                }
+                Err(e) => Err(e),
+            },
+            Kind::Symlink(symlink)
+                if fs::read_link(&dest_path).is_ok_and(|old| old == *symlink.target()) =>
+            {
+                Ok(())
+            }
+            Kind::Symlink(symlink) => write_symlink(symlink.target(), dest_root, &to)
+                .map(|_| info!("Creating symlink {:?} -> {:?}", dest_path, symlink.target())),
+            Kind::Special => Err(io::Error::other(
+                "special files are not written under shortened names",
+            )),
+        };
+        if let Err(e) = written {
+            error!("Error -- Writing {:?} as {:?}: {}", src_path, dest_path, e);
+        }
+    }
+    // Files that were still being written are copied again once the others are
+    file_ops::copy_deferred(options);
+
+    // Each directory records the names of the entries that were shortened in it
+    let mut by_dir: Vec<&Shortened> = shortened.iter().filter(|entry| entry.recorded).collect();
+    by_dir.sort_by(|a, b| a.path.cmp(&b.path));
+    for entries in by_dir.chunk_by(|a, b| a.path.parent() == b.path.parent()) {
+        let dir = dest_root.join(entries[0].path.parent().unwrap_or_else(|| Path::new("")));
+        if let Err(e) = record_names(&dir, entries) {
+            error!("Error -- Recording shortened names in {:?}: {}", dir, e);
+        }
+    }
+    Ok(())
+}
+
+/// Creates a symlink `relative` inside `base` that points to `target`, replacing whatever is there
+fn write_symlink(target: &Path, base: &Path, relative: &Path) -> Result<(), io::Error> {
+    let path = base.join(relative);
+    if fs::symlink_metadata(&path).is_ok() {
+        dirfd::remove_file(base, relative)?;
+    }
+
+    #[cfg(target_family = "unix")]
+    {
+        dirfd::symlink(target, base, relative)
+    }
+
+    #[cfg(not(target_family = "unix"))]
+    {
+        let resolved = path.parent().unwrap_or(base).join(target);
+        if resolved.is_dir() {
+            std::os::windows::fs::symlink_dir(target, &path)
+        } else {
+            std::os::windows::fs::symlink_file(target, &path)
+        }
+    }
+}
+
+/// Adds the names of `entries` to the sidecar in `dir`, keeping those of entries that are still
+/// in `dir`
+///
+/// Each line of the sidecar is a shortened name and the name it had, separated by a tab and
+/// escaped like the paths of a journal, see `journal::escape_path`.
+fn record_names(dir: &Path, entries: &[&Shortened]) -> Result<(), io::Error> {
+    let sidecar = dir.join(SHORTENED_NAME);
+    let mut names = load_names(dir)?;
+    names.retain(|(name, _)| fs::symlink_metadata(dir.join(name)).is_ok());
+    for entry in entries {
+        let original = PathBuf::from(entry.path.file_name().unwrap_or_default());
+        names.retain(|(name, _)| name.as_os_str() != entry.name);
+        names.push((PathBuf::from(&entry.name), original));
+    }
+    names.sort();
+
+    let mut contents = Vec::new();
+    for (name, original) in &names {
+        if let (Some(name), Some(original)) = (escape_path(name), escape_path(original)) {
+            contents.extend(name);
+            contents.push(b'\t');
+            contents.extend(original);
+            contents.push(b'\n');
+        }
+    }
+    fs::write(&sidecar, contents)
+}
+
+/// Loads the shortened names recorded in the sidecar in `dir`, each with the name it had
+///
+/// # Returns
+/// The shortened names, which are none if `dir` has no sidecar
+///
+/// # Errors
+/// This function will return an error if the sidecar exists and could not be read
+pub fn load_names(dir: &Path) -> Result<Vec<(PathBuf, PathBuf)>, io::Error> {
+    let contents = match fs::read(dir.join(SHORTENED_NAME)) {
+        Ok(contents) => contents,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    Ok(contents
+        .split(|&b| b == b'\n')
+        .filter_map(|line| {
+            let mut fields = line.splitn(2, |&b| b == b'\t');
+            Some((
+                unescape_path(fields.next()?)?,
+                unescape_path(fields.next()?)?,
+            ))
+        })
+        .collect())
+}
+
+/// Removes the sidecars, and the entries that are written under shortened or sanitized names,
+/// from the listing `file_sets` of the destination, with `ShortenNames::TruncateHash` or
+/// `--sanitize-names`, since they are only written by `write_shortened`
+pub fn unlist(file_sets: &mut FileSets, shortened: &[Shortened], options: &Options) {
+    if options.shorten_names != Some(ShortenNames::TruncateHash) && options.sanitize_names.is_none()
+    {
+        return;
+    }
+    let paths: HashSet<PathBuf> = shortened.iter().map(Shortened::dest_path).collect();
+    file_sets.retain_paths(|path| {
+        path.file_name() != Some(OsStr::new(SHORTENED_NAME)) && !is_within(path, &paths)
+    });
+}
+
+/// Gets the length of `name` as the filesystem counts it, in bytes, or in UTF-16 units on Windows
+fn len(name: &OsStr) -> usize {
+    #[cfg(target_family = "windows")]
+    {
+        use std::os::windows::ffi::OsStrExt;
+
+        name.encode_wide().count()
+    }
+
+    #[cfg(not(target_family = "windows"))]
+    {
+        name.len()
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_limits {
+    use super::*;
+
+    #[test]
+    fn finds_too_long() {
+        let file_sets = FileSets::with(
+            [
+                File::from("short", 1),
+                File::from("a/nameistoolong", 1),
+                File::from("deep/deeper/file", 1),
+                File::from("deep/deeper/evendeeper/file", 1),
+            ]
+            .iter()
+            .cloned()
+            .collect(),
+            [
+                Dir::from("a"),
+                Dir::from("deep"),
+                Dir::from("deep/deeper"),
+                Dir::from("deep/deeper/evendeeper"),
+            ]
+            .iter()
+            .cloned()
+            .collect(),
+            [Symlink::from("linkistoolong", "short")]
+                .iter()
+                .cloned()
+                .collect::<HashSet<Symlink>>(),
+        );
+        let limits = Limits {
+            name_max: 12,
+            path_max: 27,
+        };
+
+        assert_eq!(
+            too_long(&file_sets, Path::new("/dest"), &limits),
+            vec![
+                (PathBuf::from("a/nameistoolong"), TooLong::Name(13)),
+                // Nothing in it is reported again
+                (PathBuf::from("deep/deeper/evendeeper"), TooLong::Path(28)),
+                (PathBuf::from("linkistoolong"), TooLong::Name(13)),
+            ]
+        );
+    }
+
+    #[test]
+    fn skips_too_long() {
+        const TEST_DIR: &str = "test_limits_skips_too_long";
+
+        fs::create_dir_all(TEST_DIR).unwrap();
+        let (name_max, _) = platform::path_limits(Path::new(TEST_DIR));
+        let long = "a".repeat(name_max.min(1000) + 1);
+        let mut file_sets = FileSets::with(
+            [File::from("short", 1), File::from(&long, 1)]
+                .iter()
+                .cloned()
+                .collect(),
+            HashSet::new(),
+            HashSet::new(),
+        );
+
+        assert!(check(&mut file_sets, TEST_DIR, &Options::default())
+            .unwrap()
+            .is_empty());
+        assert_eq!(file_sets.files().len(), 2);
+        if name_max < 1000 {
+            let options = Options {
+                shorten_names: Some(ShortenNames::Error),
+                ..Options::default()
+            };
+            assert!(check(&mut file_sets, TEST_DIR, &options).is_err());
+            assert_eq!(file_sets.files().len(), 2);
+
+            let options = Options {
+                skip_long_paths: true,
+                ..Options::default()
+            };
+            assert!(check(&mut file_sets, TEST_DIR, &options)
+                .unwrap()
+                .is_empty());
+            assert_eq!(file_sets.files().len(), 1);
+        }
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+
+    #[test]
+    fn shortens_names() {
+        let name = OsStr::new("a very long name of a photo, taken on holiday.jpeg");
+        let shortened = shorten_name(name, 32);
+        assert_eq!(len(&shortened), 32);
+        assert_eq!(shortened, shorten_name(name, 32));
+        let shortened = shortened.to_string_lossy().into_owned();
+        assert!(shortened.starts_with("a very lon~"));
+        assert!(shortened.ends_with(".jpeg"));
+
+        // Names that only differ after the start are shortened differently
+        let other = OsStr::new("a very long name of a photo, taken on holiday 2.jpeg");
+        assert_ne!(shorten_name(other, 32), shorten_name(name, 32));
+        // Multi-byte characters are never split
+        assert_eq!(len(&shorten_name(OsStr::new(&"é".repeat(40)), 32)), 31);
+        assert_eq!(len(&shorten_name(name, 8)), 8);
+    }
+
+    #[test]
+    fn writes_shortened() {
+        use crate::lumins::core;
+        const TEST_DIR: &str = "test_limits_writes_shortened";
+        const TEST_DIR_OUT: &str = "test_limits_writes_shortened_out";
+
+        fs::create_dir_all([TEST_DIR, "dir/long dir"].join("/")).unwrap();
+        fs::write([TEST_DIR, "dir/long file"].join("/"), b"1234").unwrap();
+        fs::write([TEST_DIR, "dir/long dir/file"].join("/"), b"12").unwrap();
+        fs::create_dir_all([TEST_DIR_OUT, "dir"].join("/")).unwrap();
+
+        let shortened = [
+            Shortened {
+                path: PathBuf::from("dir/long file"),
+                name: OsString::from("file~1"),
+                kind: Kind::File(File::from("dir/long file", 4)),
+                recorded: true,
+            },
+            Shortened {
+                path: PathBuf::from("dir/long dir"),
+                name: OsString::from("dir~2"),
+                kind: Kind::Dir(Dir::from("dir/long dir")),
+                recorded: true,
+            },
+        ];
+        let options = Options::default();
+        assert!(write_shortened(&shortened, TEST_DIR, TEST_DIR_OUT, &options, core::copy).is_ok());
+
+        assert_eq!(
+            fs::read([TEST_DIR_OUT, "dir/file~1"].join("/")).unwrap(),
+            b"1234"
+        );
+        assert_eq!(
+            fs::read([TEST_DIR_OUT, "dir/dir~2/file"].join("/")).unwrap(),
+            b"12"
+        );
+        let dir = Path::new(TEST_DIR_OUT).join("dir");
+        assert_eq!(
+            load_names(&dir).unwrap(),
+            vec![
+                (PathBuf::from("dir~2"), PathBuf::from("long dir")),
+                (PathBuf::from("file~1"), PathBuf::from("long file")),
+            ]
+        );
+
+        // Files that are already written are not copied again, and files that changed are, while
+        // what is in directories is written as `run` writes it
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            let inode = |path: &str| fs::metadata([TEST_DIR_OUT, path].join("/")).unwrap().ino();
+            let file = inode("dir/file~1");
+            assert!(
+                write_shortened(&shortened, TEST_DIR, TEST_DIR_OUT, &options, core::copy).is_ok()
+            );
+            assert_eq!(inode("dir/file~1"), file);
+
+            fs::write([TEST_DIR, "dir/long file"].join("/"), b"4321").unwrap();
+            assert!(
+                write_shortened(&shortened, TEST_DIR, TEST_DIR_OUT, &options, core::copy).is_ok()
+            );
+            assert_eq!(
+                fs::read([TEST_DIR_OUT, "dir/file~1"].join("/")).unwrap(),
+                b"4321"
+            );
+        }
+
+        // Sidecars and shortened entries are left out of listings of the destination
+        let mut file_sets = file_ops::get_all_files(TEST_DIR_OUT).unwrap();
+        unlist(
+            &mut file_sets,
+            &shortened,
+            &Options {
+                shorten_names: Some(ShortenNames::TruncateHash),
+                ..Options::default()
+            },
+        );
+        assert!(file_sets.files().is_empty());
+        assert_eq!(file_sets.dirs().len(), 1);
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_dir_all(TEST_DIR_OUT).unwrap();
+    }
+}
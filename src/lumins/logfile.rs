@@ -0,0 +1,162 @@
+//! Writes timestamped logs to a file, rotating it once it grows too large, so that scheduled runs
+//! leave an audit trail
+
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::Level;
+
+use crate::lumins::history;
+use crate::progress::PROGRESS_BAR;
+
+/// Size after which a log file is rotated
+pub const MAX_LOG_SIZE: u64 = 10 << 20;
+
+/// Number of rotated log files that are kept, as `PATH.1` (the newest) to `PATH.5`
+const ROTATED_LOGS: usize = 5;
+
+/// A struct that represents a log file, that is rotated once it is larger than its maximum
+#[derive(Debug)]
+pub struct LogFile {
+    path: PathBuf,
+    max_size: u64,
+    /// The open log file, and its size
+    file: Mutex<(fs::File, u64)>,
+}
+
+impl LogFile {
+    /// Opens the log file at `path` to append to it, creating it if it does not exist
+    ///
+    /// # Errors
+    /// This function will return an error if `path` could not be opened
+    pub fn open(path: &Path, max_size: u64) -> Result<Self, io::Error> {
+        let (file, size) = open_log(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("Log Error -- {:?}: {}", path, e)))?;
+        Ok(LogFile {
+            path: path.to_path_buf(),
+            max_size,
+            file: Mutex::new((file, size)),
+        })
+    }
+
+    /// Gets the path of the log file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Appends `message` to the log file, with the current UTC time and `level`
+    ///
+    /// The log file is rotated first if `message` would make it larger than its maximum. Errors
+    /// are printed, but can not be logged.
+    pub fn write(&self, level: Level, message: &str) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_secs());
+        let (year, month, day, hour, minute, second) = history::utc_time(now);
+        let line = format!(
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z {:<5} {}\n",
+            year, month, day, hour, minute, second, level, message
+        );
+
+        let mut file = self.file.lock().unwrap();
+        if file.1 > 0 && file.1 + line.len() as u64 > self.max_size {
+            match self.rotate() {
+                Ok(rotated) => *file = rotated,
+                Err(e) => {
+                    PROGRESS_BAR.println(format!("Log Error -- Rotating {:?}: {}", self.path, e))
+                }
+            }
+        }
+        match file.0.write_all(line.as_bytes()) {
+            Ok(_) => file.1 += line.len() as u64,
+            Err(e) => PROGRESS_BAR.println(format!("Log Error -- {:?}: {}", self.path, e)),
+        }
+    }
+
+    /// Moves the log file to `PATH.1`, and each older log file one further, dropping the oldest
+    ///
+    /// # Returns
+    /// A new, empty log file at `PATH`, and its size
+    fn rotate(&self) -> Result<(fs::File, u64), io::Error> {
+        let rotated = |i: usize| {
+            let mut path = self.path.clone().into_os_string();
+            path.push(format!(".{}", i));
+            PathBuf::from(path)
+        };
+        for i in (1..ROTATED_LOGS).rev() {
+            match fs::rename(rotated(i), rotated(i + 1)) {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                result => result?,
+            }
+        }
+        fs::rename(&self.path, rotated(1))?;
+        open_log(&self.path)
+    }
+}
+
+/// Opens the log file at `path` to append to it
+///
+/// # Returns
+/// The log file, and its size
+fn open_log(path: &Path) -> Result<(fs::File, u64), io::Error> {
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    let size = file.metadata()?.len();
+    Ok((file, size))
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_logfile {
+    use super::*;
+
+    #[test]
+    fn invalid_path() {
+        assert!(LogFile::open(Path::new("/?/lms.log"), MAX_LOG_SIZE).is_err());
+    }
+
+    #[test]
+    fn rotation() {
+        const TEST_DIR: &str = "test_logfile_rotation";
+        let path = |name: &str| Path::new(TEST_DIR).join(name);
+
+        fs::create_dir_all(TEST_DIR).unwrap();
+        let log = LogFile::open(&path("lms.log"), 100).unwrap();
+        log.write(Level::Info, "Copying file \"a\"");
+        let contents = fs::read_to_string(path("lms.log")).unwrap();
+        assert!(
+            contents.ends_with("Z INFO  Copying file \"a\"\n"),
+            "{}",
+            contents
+        );
+        assert_eq!(contents.len(), 44);
+
+        // Only two lines fit into each log file, so it is rotated more often than logs are kept
+        for i in 0..(ROTATED_LOGS + 1) * 2 {
+            log.write(Level::Error, &format!("Error -- {}", i));
+        }
+        for i in 1..=ROTATED_LOGS {
+            assert!(path(&format!("lms.log.{}", i)).exists());
+        }
+        assert!(!path(&format!("lms.log.{}", ROTATED_LOGS + 1)).exists());
+        let contents = fs::read_to_string(path("lms.log")).unwrap();
+        assert!(contents.ends_with("Error -- 11\n"), "{}", contents);
+        assert!(fs::metadata(path("lms.log.1")).unwrap().len() <= 100);
+
+        // Reopening appends to the log file
+        let size = fs::metadata(path("lms.log")).unwrap().len();
+        let log = LogFile::open(&path("lms.log"), 1000).unwrap();
+        log.write(Level::Info, "again");
+        assert!(fs::metadata(path("lms.log")).unwrap().len() > size);
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+}
@@ -0,0 +1,190 @@
+//! Answers whether entries are missing from a destination, for the duration of a run, from the
+//! names of its directories rather than by looking up every entry
+//!
+//! On high-latency storage, such as network shares, a run that copies many small files would
+//! otherwise make a round trip for every one of them, before copying it, only to find that it is
+//! not there. Each directory of the destination is instead read at most once per run, the first
+//! time an entry in it is looked up, and directories that are missing are known to be missing
+//! along with everything in them, without being read. Names are compared regardless of case, see
+//! `casefold::fold`, so that entries are never taken to be missing from destinations that do not
+//! tell names apart by case.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use hashbrown::{HashMap, HashSet};
+use rayon::prelude::*;
+
+use crate::lumins::casefold;
+
+/// What a directory of the destination was found to hold
+#[derive(Debug, Clone)]
+enum Names {
+    /// The folded names of its entries
+    Read(Arc<HashSet<String>>),
+    /// It does not exist, nor does anything in it
+    Missing,
+    /// It could not be read, so anything may be in it
+    Unknown,
+}
+
+/// The names of the directories of a destination that a run has read, see the module
+/// documentation
+#[derive(Debug)]
+pub struct Lookup {
+    dest: PathBuf,
+    dirs: Mutex<HashMap<PathBuf, Names>>,
+}
+
+impl Lookup {
+    /// Starts looking up entries of `dest`, without reading anything yet
+    pub fn new(dest: &str) -> Self {
+        Lookup {
+            dest: PathBuf::from(dest),
+            dirs: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Checks whether `relative` is known to be missing from `dest`
+    ///
+    /// Entries are only known to be missing from the destination the lookup was started for, so
+    /// that runs of their own inside of it, such as for linked directories, look up their own
+    /// entries.
+    pub fn is_missing(&self, dest: &Path, relative: &Path) -> bool {
+        if dest != self.dest {
+            return false;
+        }
+        let (parent, name) = match (relative.parent(), relative.file_name()) {
+            (Some(parent), Some(name)) => (parent, name),
+            _ => return false,
+        };
+        match self.names(parent) {
+            Names::Read(names) => !names.contains(&casefold::fold(Path::new(name))),
+            Names::Missing => true,
+            Names::Unknown => false,
+        }
+    }
+
+    /// Reads the directories that hold `paths` at once, in parallel, so that looking up each of
+    /// them later on does not wait for its directory to be read
+    pub fn prefetch<'a, I>(&self, paths: I)
+    where
+        I: IntoIterator<Item = &'a Path>,
+    {
+        let parents: HashSet<&Path> = paths.into_iter().filter_map(Path::parent).collect();
+        // Parents are read before what is in them, so that nothing in missing directories is read
+        let mut parents: Vec<&Path> = parents.into_iter().collect();
+        parents.sort_by_key(|parent| parent.components().count());
+        let mut start = 0;
+        while start < parents.len() {
+            let depth = parents[start].components().count();
+            let end = start
+                + parents[start..]
+                    .iter()
+                    .take_while(|parent| parent.components().count() == depth)
+                    .count();
+            parents[start..end].par_iter().for_each(|parent| {
+                self.names(parent);
+            });
+            start = end;
+        }
+    }
+
+    /// Records that the run wrote `relative` into the destination, so that it is no longer
+    /// taken to be missing
+    pub fn insert(&self, dest: &Path, relative: &Path) {
+        if dest != self.dest {
+            return;
+        }
+        if let (Some(parent), Some(name)) = (relative.parent(), relative.file_name()) {
+            let mut dirs = self.dirs.lock().unwrap();
+            let names = match dirs.get(parent) {
+                Some(Names::Read(names)) => {
+                    let mut names = HashSet::clone(names);
+                    names.insert(casefold::fold(Path::new(name)));
+                    Names::Read(Arc::new(names))
+                }
+                // What else was written into the directory is not known
+                _ => Names::Unknown,
+            };
+            dirs.insert(parent.to_path_buf(), names);
+        }
+    }
+
+    /// Gets the names in `dir` of the destination, reading it the first time it is asked for
+    fn names(&self, dir: &Path) -> Names {
+        if let Some(names) = self.dirs.lock().unwrap().get(dir) {
+            return names.clone();
+        }
+        // A directory is missing if its parent is read and does not have it
+        let missing = match (dir.parent(), dir.file_name()) {
+            (Some(parent), Some(name)) => match self.names(parent) {
+                Names::Read(names) => !names.contains(&casefold::fold(Path::new(name))),
+                Names::Missing => true,
+                Names::Unknown => false,
+            },
+            _ => false,
+        };
+        let names = if missing {
+            Names::Missing
+        } else {
+            match read_names(&self.dest.join(dir)) {
+                Ok(names) => Names::Read(Arc::new(names)),
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => Names::Missing,
+                Err(_) => Names::Unknown,
+            }
+        };
+        self.dirs
+            .lock()
+            .unwrap()
+            .entry(dir.to_path_buf())
+            .or_insert(names)
+            .clone()
+    }
+}
+
+/// Reads the folded names of the entries of `dir`
+fn read_names(dir: &Path) -> Result<HashSet<String>, io::Error> {
+    fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| casefold::fold(Path::new(&entry.file_name()))))
+        .collect()
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_lookup {
+    use super::*;
+
+    #[test]
+    fn finds_missing_entries() {
+        const TEST_DEST: &str = "test_lookup_finds_missing_entries";
+
+        fs::create_dir_all([TEST_DEST, "dir"].join("/")).unwrap();
+        fs::File::create([TEST_DEST, "dir/File.txt"].join("/")).unwrap();
+        let dest = Path::new(TEST_DEST);
+
+        let lookup = Lookup::new(TEST_DEST);
+        lookup.prefetch(vec![Path::new("dir/file.txt"), Path::new("gone/file.txt")]);
+        assert!(lookup.is_missing(dest, Path::new("dir/other.txt")));
+        assert!(lookup.is_missing(dest, Path::new("gone/deeper/file.txt")));
+        // Names that only differ in case may be the same entry
+        assert!(!lookup.is_missing(dest, Path::new("dir/file.txt")));
+        assert!(!lookup.is_missing(dest, Path::new("dir")));
+        // Other destinations are never answered for
+        assert!(!lookup.is_missing(&dest.join("dir"), Path::new("other.txt")));
+
+        // Entries are found once, and written ones are no longer missing
+        fs::File::create([TEST_DEST, "dir/other.txt"].join("/")).unwrap();
+        assert!(lookup.is_missing(dest, Path::new("dir/other.txt")));
+        lookup.insert(dest, Path::new("dir/other.txt"));
+        assert!(!lookup.is_missing(dest, Path::new("dir/other.txt")));
+        assert!(lookup.is_missing(dest, Path::new("dir/new.txt")));
+
+        fs::remove_dir_all(TEST_DEST).unwrap();
+    }
+}
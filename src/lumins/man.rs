@@ -0,0 +1,212 @@
+//! Renders the man page of lms, for `lms man`, from the same definition of the arguments that
+//! they are parsed with, and from the help topics
+//!
+//! Every subcommand that is not hidden is a section of the page, with its options, arguments, and
+//! examples, so that the page matches the binary it was printed by.
+
+use clap::builder::StyledStr;
+use clap::{Arg, ArgAction, Command};
+
+use crate::lumins::help::Topic;
+
+/// Renders the man page of `cli`, the definition of the arguments of lms, in section 1 with
+/// `topics` after the subcommands
+pub fn render(cli: &Command, topics: &[Topic]) -> String {
+    let mut page = format!(
+        ".TH LMS 1 \"\" \"lms {}\" \"User Commands\"\n\
+         .SH NAME\n\
+         lms \\- {}\n\
+         .SH SYNOPSIS\n\
+         \\fBlms\\fR \\fISUBCOMMAND\\fR [\\fIOPTIONS\\fR] [\\fIARGS\\fR]\n\
+         .SH DESCRIPTION\n\
+         Each subcommand below has options of its own. The topics after them, which are also\n\
+         printed by \\fBlms help\\fR \\fITOPIC\\fR, are about what spans the options of several\n\
+         subcommands.\n\
+         .SH SUBCOMMANDS\n",
+        cli.get_version().unwrap_or(""),
+        escape(&text(cli.get_about()))
+    );
+
+    for sub_command in cli
+        .get_subcommands()
+        .filter(|sub_command| !sub_command.is_hide_set())
+    {
+        let args: Vec<&Arg> = sub_command
+            .get_arguments()
+            .filter(|arg| !arg.is_hide_set())
+            .collect();
+        let mut synopsis = format!("lms {}", sub_command.get_name());
+        if args.iter().any(|arg| !arg.is_positional()) {
+            synopsis.push_str(" [OPTIONS]");
+        }
+        for arg in args.iter().filter(|arg| arg.is_positional()) {
+            let dots = if takes_many(arg) { "..." } else { "" };
+            if arg.is_required_set() {
+                synopsis.push_str(&format!(" {}{}", value_name(arg), dots));
+            } else {
+                synopsis.push_str(&format!(" [{}]{}", value_name(arg), dots));
+            }
+        }
+        page.push_str(&format!(".SS \"{}\"\n", escape(&synopsis)));
+        page.push_str(&escape(&text(sub_command.get_about())));
+        for alias in sub_command.get_visible_aliases() {
+            page.push_str(&format!(" (alias: {})", escape(alias)));
+        }
+        page.push('\n');
+
+        for arg in &args {
+            page.push_str(&format!(".TP\n{}\n{}\n", term(arg), describe(arg)));
+        }
+        if let Some(after_help) = sub_command.get_after_help() {
+            page.push_str(&format!(
+                ".PP\n.nf\n{}\n.fi\n",
+                escape(&after_help.to_string())
+            ));
+        }
+    }
+
+    page.push_str(".SH TOPICS\n");
+    for topic in topics {
+        page.push_str(&format!(
+            ".SS {}\n{}\n.PP\n{}\n",
+            escape(topic.name),
+            escape(topic.summary),
+            escape(topic.text)
+        ));
+        for (option, about) in topic.options {
+            page.push_str(&format!(
+                ".TP\n\\fB{}\\fR\n{}\n",
+                escape(option),
+                escape(about)
+            ));
+        }
+    }
+    page
+}
+
+/// Gets the plain text of a help text of clap, such as the about of a subcommand
+fn text(help: Option<&StyledStr>) -> String {
+    help.map(StyledStr::to_string).unwrap_or_default()
+}
+
+/// Checks whether `arg` is given many times, or takes many values
+fn takes_many(arg: &Arg) -> bool {
+    matches!(arg.get_action(), ArgAction::Append)
+        || arg
+            .get_num_args()
+            .is_some_and(|num_args| num_args.max_values() > 1)
+}
+
+/// Gets the name of the value of `arg`, such as `SOURCE` or `N`
+fn value_name(arg: &Arg) -> String {
+    match arg.get_value_names() {
+        Some(names) => names
+            .iter()
+            .map(|name| name.as_str())
+            .collect::<Vec<_>>()
+            .join(" "),
+        None => arg.get_id().as_str().to_uppercase(),
+    }
+}
+
+/// Formats how `arg` is given, such as `-s, --secure` or `--order <ORDER>`
+fn term(arg: &Arg) -> String {
+    if arg.is_positional() {
+        return format!("\\fI{}\\fR", escape(&value_name(arg)));
+    }
+    let mut term = Vec::new();
+    if let Some(short) = arg.get_short() {
+        term.push(format!("\\fB\\-{}\\fR", escape(&short.to_string())));
+    }
+    if let Some(long) = arg.get_long() {
+        term.push(format!("\\fB\\-\\-{}\\fR", escape(long)));
+    }
+    let mut term = term.join(", ");
+    if arg.get_action().takes_values() {
+        term.push_str(&format!(" \\fI{}\\fR", escape(&value_name(arg))));
+    }
+    term
+}
+
+/// Formats what `arg` does, with its possible and default values
+fn describe(arg: &Arg) -> String {
+    let mut description = escape(&text(arg.get_help()));
+    let values: Vec<String> = arg
+        .get_possible_values()
+        .iter()
+        .filter(|value| !value.is_hide_set())
+        .map(|value| value.get_name().to_string())
+        .collect();
+    if !values.is_empty() && arg.get_action().takes_values() {
+        description.push_str(&format!(
+            " [possible values: {}]",
+            escape(&values.join(", "))
+        ));
+    }
+    let defaults: Vec<String> = arg
+        .get_default_values()
+        .iter()
+        .map(|value| value.to_string_lossy().into_owned())
+        .collect();
+    if !defaults.is_empty() && arg.get_action().takes_values() {
+        description.push_str(&format!(" [default: {}]", escape(&defaults.join(", "))));
+    }
+    description
+}
+
+/// Escapes `text` for roff, so that backslashes, dashes, and lines starting with `.` or `'` are
+/// printed as they are
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\e")
+        .replace('-', "\\-")
+        .lines()
+        .map(|line| {
+            if line.starts_with('.') || line.starts_with('\'') {
+                format!("\\&{}", line)
+            } else {
+                line.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_man {
+    use super::*;
+    use crate::lumins::cli::Cli;
+    use crate::lumins::help::TOPICS;
+    use clap::CommandFactory;
+
+    #[test]
+    fn escapes() {
+        assert_eq!(escape("--max-delete"), "\\-\\-max\\-delete");
+        assert_eq!(escape("a\\b"), "a\\eb");
+        assert_eq!(escape("one\n.two\n'three"), "one\n\\&.two\n\\&'three");
+    }
+
+    #[test]
+    fn renders_page() {
+        let cli = Cli::command();
+        let page = render(&cli, TOPICS);
+        assert!(page.starts_with(".TH LMS 1 \"\" \"lms "));
+
+        // Every subcommand that is not hidden has its own section
+        for sub_command in cli.get_subcommands() {
+            let section = format!(".SS \"lms {}", escape(sub_command.get_name()));
+            assert_eq!(page.contains(&section), !sub_command.is_hide_set());
+        }
+        assert!(page.contains(
+            ".SS \"lms sync [OPTIONS] SOURCE DESTINATION\"\nMultithreaded directory \
+             synchronization (alias: s)\n"
+        ));
+        assert!(page.contains(".TP\n\\fB\\-s\\fR, \\fB\\-\\-secure\\fR\n"));
+        assert!(page.contains(".TP\n\\fB\\-\\-max\\-delete\\fR \\fIN\\fR\n"));
+        assert!(page.contains(".TP\n\\fISOURCE\\fR\n"));
+        assert!(page.contains(".SS exit\\-codes\n"));
+    }
+}
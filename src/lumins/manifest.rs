@@ -0,0 +1,1120 @@
+//! Exports manifests of directory trees, and compares them offline
+//!
+//! A manifest lists every file, directory, and symlink in a tree, with the permissions of each,
+//! and the size and BLAKE2b hash of each file. Comparing the manifests of two systems shows how
+//! their trees drifted apart, without either system having to reach the other. Trees that are
+//! both at hand are compared directly, the way that sync compares them.
+//!
+//! A checksum manifest only lists the BLAKE3 hash of each file, in the format of `b3sum`, so that
+//! a tree can be audited for bit rot against it later.
+
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+use blake2::{Blake2b, Digest};
+use clap::ValueEnum;
+use hashbrown::{HashMap, HashSet};
+use log::error;
+use rayon::prelude::*;
+
+use crate::lumins::archive::EntryMetadata;
+use crate::lumins::dirfd;
+use crate::lumins::file_ops::{self, File, FileOps, FileSets};
+use crate::lumins::json::{self, Value};
+use crate::lumins::parse::Options;
+use crate::progress::{self, PROGRESS_BAR};
+
+/// Version of the manifest format, which is written into every manifest
+pub const MANIFEST_VERSION: u64 = 1;
+
+/// Enum to represent the type of an entry in a manifest, along with what identifies its contents
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub enum EntryKind {
+    Dir,
+    File {
+        size: u64,
+        /// BLAKE2b hash of the contents, in hex
+        hash: String,
+    },
+    Symlink {
+        target: String,
+    },
+}
+
+impl EntryKind {
+    /// Gets the name of the type, as written in manifests
+    pub fn name(&self) -> &'static str {
+        match self {
+            EntryKind::Dir => "dir",
+            EntryKind::File { .. } => "file",
+            EntryKind::Symlink { .. } => "symlink",
+        }
+    }
+}
+
+/// A struct that represents a single file, directory, or symlink in a manifest
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub struct Entry {
+    /// Path relative to the root of the tree, with `/` separating components on every platform
+    pub path: String,
+    pub kind: EntryKind,
+    /// Permission bits, which are not recorded for symlinks
+    pub mode: u32,
+}
+
+/// A struct that represents the manifest of a tree, with its entries sorted by path
+#[derive(Eq, PartialEq, Debug, Clone, Default)]
+pub struct Manifest {
+    pub entries: Vec<Entry>,
+}
+
+impl Manifest {
+    /// Builds the manifest of `src`, hashing its files in parallel
+    ///
+    /// # Errors
+    /// This function will return an error if `src` is an invalid directory, or if any entry could
+    /// not be read, since a manifest with missing entries would show them as deleted
+    pub fn build(src: &str) -> Result<Self, io::Error> {
+        let file_sets = file_ops::get_all_files(src)?;
+        progress::progress_init(
+            (file_sets.files().len() + file_sets.dirs().len() + file_sets.symlinks().len()) as u64,
+        );
+
+        let src = Path::new(src);
+        let manifest_error = |path: &Path, e: io::Error| {
+            io::Error::new(
+                e.kind(),
+                format!("Manifest Error -- Reading {:?}: {}", src.join(path), e),
+            )
+        };
+        let entry = |path: &Path, kind: EntryKind| -> Result<Entry, io::Error> {
+            let metadata =
+                fs::symlink_metadata(src.join(path)).map_err(|e| manifest_error(path, e))?;
+            PROGRESS_BAR.inc(1);
+            Ok(Entry {
+                path: manifest_path(path),
+                mode: match kind {
+                    EntryKind::Symlink { .. } => 0,
+                    _ => EntryMetadata::from(&metadata).mode & 0o7777,
+                },
+                kind,
+            })
+        };
+
+        let mut entries = file_sets
+            .dirs()
+            .par_iter()
+            .map(|dir| entry(dir.path(), EntryKind::Dir))
+            .chain(file_sets.files().par_iter().map(|file| {
+                let (size, hash) =
+                    hash_file(src, file.path()).map_err(|e| manifest_error(file.path(), e))?;
+                entry(file.path(), EntryKind::File { size, hash })
+            }))
+            .chain(file_sets.symlinks().par_iter().map(|symlink| {
+                let target = symlink.target().to_string_lossy().to_string();
+                entry(symlink.path(), EntryKind::Symlink { target })
+            }))
+            .collect::<Result<Vec<Entry>, io::Error>>()?;
+        entries.par_sort_unstable_by(|a, b| a.path.cmp(&b.path));
+
+        Ok(Manifest { entries })
+    }
+
+    /// Serializes the manifest as JSON, with one entry per line
+    pub fn to_json(&self) -> String {
+        let mut json = format!("{{\n  \"version\": {},\n  \"entries\": [", MANIFEST_VERSION);
+        for (i, entry) in self.entries.iter().enumerate() {
+            json.push_str(if i == 0 { "\n    " } else { ",\n    " });
+            json.push_str(&format!(
+                "{{\"path\": {}, \"type\": \"{}\"",
+                json::string(&entry.path),
+                entry.kind.name()
+            ));
+            match &entry.kind {
+                EntryKind::Dir => {}
+                EntryKind::File { size, hash } => {
+                    json.push_str(&format!(", \"size\": {}, \"blake2b\": \"{}\"", size, hash))
+                }
+                EntryKind::Symlink { target } => {
+                    json.push_str(&format!(", \"target\": {}", json::string(target)))
+                }
+            }
+            if !matches!(entry.kind, EntryKind::Symlink { .. }) {
+                json.push_str(&format!(", \"mode\": \"{:04o}\"", entry.mode));
+            }
+            json.push('}');
+        }
+        json.push_str("\n  ]\n}\n");
+        json
+    }
+
+    /// Parses a manifest from the JSON written by `to_json`
+    ///
+    /// # Errors
+    /// This function will return an error describing the problem if `json` is not a manifest,
+    /// or is of another version
+    pub fn from_json(json: &str) -> Result<Self, String> {
+        let value = json::parse(json).map_err(|e| e.to_string())?;
+        match value.get("version").and_then(Value::as_u64) {
+            Some(MANIFEST_VERSION) => {}
+            Some(version) => return Err(format!("unsupported manifest version {}", version)),
+            None => return Err("missing manifest version".to_string()),
+        }
+
+        let mut entries = value
+            .get("entries")
+            .and_then(Value::as_array)
+            .ok_or("missing manifest entries")?
+            .iter()
+            .enumerate()
+            .map(|(i, entry)| parse_entry(entry).ok_or(format!("invalid entry {}", i)))
+            .collect::<Result<Vec<Entry>, String>>()?;
+        entries.sort_unstable_by(|a, b| a.path.cmp(&b.path));
+
+        Ok(Manifest { entries })
+    }
+}
+
+/// Enum to represent a change to a single entry between two manifests
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub enum Change {
+    /// Only in the first manifest
+    Removed,
+    /// Only in the second manifest
+    Added,
+    /// The type changed, e.g. from a file to a symlink
+    Type(&'static str, &'static str),
+    /// The contents of a file changed
+    Contents,
+    /// The target of a symlink changed
+    Target(String, String),
+    /// The permissions changed
+    Mode(u32, u32),
+}
+
+/// A struct that represents a change to the entry at `path` between two manifests
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub struct Difference<'a> {
+    pub path: &'a str,
+    pub change: Change,
+}
+
+impl fmt::Display for Difference<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.change {
+            Change::Removed => write!(f, "- {}", self.path),
+            Change::Added => write!(f, "+ {}", self.path),
+            Change::Type(from, to) => write!(f, "T {} ({} -> {})", self.path, from, to),
+            Change::Contents => write!(f, "M {}", self.path),
+            Change::Target(from, to) => write!(f, "L {} ({} -> {})", self.path, from, to),
+            Change::Mode(from, to) => write!(f, "P {} ({:04o} -> {:04o})", self.path, from, to),
+        }
+    }
+}
+
+impl Change {
+    /// Gets the name of the change, as written in JSON output
+    fn name(&self) -> &'static str {
+        match self {
+            Change::Removed => "removed",
+            Change::Added => "added",
+            Change::Type(..) => "type",
+            Change::Contents => "contents",
+            Change::Target(..) => "target",
+            Change::Mode(..) => "mode",
+        }
+    }
+}
+
+/// Enum to represent how differences are printed, with `--format`
+#[derive(Eq, PartialEq, Debug, Clone, Copy, ValueEnum)]
+pub enum Format {
+    /// One line per difference
+    Plain,
+    /// A JSON array with one object per difference
+    Json,
+    /// How many entries are only in either tree, and how many differ
+    Summary,
+}
+
+/// Compares the manifests `first` and `second` in parallel
+///
+/// # Returns
+/// Every difference between the manifests, sorted by path
+pub fn diff<'a>(first: &'a Manifest, second: &'a Manifest) -> Vec<Difference<'a>> {
+    let index = |manifest: &'a Manifest| -> HashMap<&'a str, &'a Entry> {
+        manifest
+            .entries
+            .iter()
+            .map(|entry| (entry.path.as_str(), entry))
+            .collect()
+    };
+    let (first_index, second_index) = rayon::join(|| index(first), || index(second));
+
+    let mut differences: Vec<Difference> = first
+        .entries
+        .par_iter()
+        .flat_map(|entry| match second_index.get(entry.path.as_str()) {
+            Some(other) => compare(entry, other),
+            None => vec![Difference {
+                path: &entry.path,
+                change: Change::Removed,
+            }],
+        })
+        .chain(
+            second
+                .entries
+                .par_iter()
+                .filter(|entry| !first_index.contains_key(entry.path.as_str()))
+                .map(|entry| Difference {
+                    path: &entry.path,
+                    change: Change::Added,
+                }),
+        )
+        .collect();
+    differences.par_sort_by(|a, b| a.path.cmp(b.path));
+
+    differences
+}
+
+/// Loads the manifests `first` and `second`, and prints every difference between them
+///
+/// # Errors
+/// This function will return an error if either manifest could not be loaded
+pub fn diff_files(first: &str, second: &str, format: Format) -> Result<(), io::Error> {
+    let (first, second) = rayon::join(|| load(first), || load(second));
+    let (first, second) = (first?, second?);
+
+    let stdout = io::stdout();
+    let mut stdout = io::BufWriter::new(stdout.lock());
+    write_differences(&diff(&first, &second), format, &mut stdout)?;
+    stdout.flush()
+}
+
+/// Enum to represent the type of an entry of a tree that `diff_dirs` compares
+enum Listed<'a> {
+    Dir,
+    File(&'a File),
+    Symlink(&'a Path),
+    Special,
+}
+
+impl Listed<'_> {
+    /// Gets the name of the type, as in manifests
+    fn name(&self) -> &'static str {
+        match self {
+            Listed::Dir => "dir",
+            Listed::File(_) => "file",
+            Listed::Symlink(_) => "symlink",
+            Listed::Special => "special",
+        }
+    }
+}
+
+/// Indexes the entries of `file_sets` by path, for `diff_dirs`
+fn index(file_sets: &FileSets) -> HashMap<&Path, Listed<'_>> {
+    let dirs = file_sets.dirs().iter().map(|dir| (dir.path(), Listed::Dir));
+    let files = file_sets
+        .files()
+        .iter()
+        .map(|f| (f.path(), Listed::File(f)));
+    let symlinks = file_sets
+        .symlinks()
+        .iter()
+        .map(|symlink| (symlink.path(), Listed::Symlink(symlink.target())));
+    let specials = file_sets
+        .specials()
+        .iter()
+        .map(|s| (s.path(), Listed::Special));
+    dirs.chain(files)
+        .chain(symlinks)
+        .chain(specials)
+        .map(|(path, listed)| (path.as_path(), listed))
+        .collect()
+}
+
+/// Compares the trees `first` and `second` the way that a sync of `first` into `second` would,
+/// without modifying either
+///
+/// Files differ if their sizes do, or else if their hashes do, with the hash function that
+/// `Options::secure` chooses, see `file_ops::file_differs`. Permissions are not compared, as sync
+/// copies them along with the contents.
+///
+/// # Returns
+/// Every difference between the trees, as the path that differs with how it does, sorted by path
+///
+/// # Errors
+/// This function will return an error if either tree could not be listed
+pub fn diff_dirs(
+    first: &str,
+    second: &str,
+    options: &Options,
+) -> Result<Vec<(String, Change)>, io::Error> {
+    let (first_sets, second_sets) = rayon::join(
+        || file_ops::get_all_files(first),
+        || file_ops::get_all_files(second),
+    );
+    let (first_sets, second_sets) = (first_sets?, second_sets?);
+    let (first_index, second_index) = rayon::join(|| index(&first_sets), || index(&second_sets));
+
+    let mut changes: Vec<(String, Change)> = first_index
+        .par_iter()
+        .filter_map(|(path, listed)| {
+            let change = match (listed, second_index.get(path)) {
+                (_, None) => Change::Removed,
+                (Listed::Dir, Some(Listed::Dir)) | (Listed::Special, Some(Listed::Special)) => {
+                    return None
+                }
+                (Listed::File(file), Some(Listed::File(other))) => {
+                    if file.size() == other.size()
+                        && !file_ops::file_differs(*file, first, second, options)
+                    {
+                        return None;
+                    }
+                    Change::Contents
+                }
+                (Listed::Symlink(target), Some(Listed::Symlink(other))) => {
+                    if target == other {
+                        return None;
+                    }
+                    Change::Target(
+                        target.to_string_lossy().to_string(),
+                        other.to_string_lossy().to_string(),
+                    )
+                }
+                (listed, Some(other)) => Change::Type(listed.name(), other.name()),
+            };
+            Some((manifest_path(path), change))
+        })
+        .chain(
+            second_index
+                .par_iter()
+                .filter(|(path, _)| !first_index.contains_key(*path))
+                .map(|(path, _)| (manifest_path(path), Change::Added)),
+        )
+        .collect();
+    changes.par_sort_by(|a, b| a.0.cmp(&b.0));
+
+    Ok(changes)
+}
+
+/// Compares the trees `first` and `second` with `diff_dirs`, and prints every difference
+/// between them
+///
+/// # Errors
+/// This function will return an error if either tree could not be listed
+pub fn diff_trees(
+    first: &str,
+    second: &str,
+    format: Format,
+    options: &Options,
+) -> Result<(), io::Error> {
+    let changes = diff_dirs(first, second, options)?;
+    let differences: Vec<Difference> = changes
+        .iter()
+        .map(|(path, change)| Difference {
+            path,
+            change: change.clone(),
+        })
+        .collect();
+
+    let stdout = io::stdout();
+    let mut stdout = io::BufWriter::new(stdout.lock());
+    write_differences(&differences, format, &mut stdout)?;
+    stdout.flush()
+}
+
+/// Writes `differences` to `output` in `format`
+fn write_differences<W: Write>(
+    differences: &[Difference],
+    format: Format,
+    output: &mut W,
+) -> Result<(), io::Error> {
+    match format {
+        Format::Plain => {
+            for difference in differences {
+                writeln!(output, "{}", difference)?;
+            }
+        }
+        Format::Json => {
+            write!(output, "[")?;
+            for (i, difference) in differences.iter().enumerate() {
+                write!(
+                    output,
+                    "{}{{\"path\": {}, \"change\": \"{}\"",
+                    if i == 0 { "\n  " } else { ",\n  " },
+                    json::string(difference.path),
+                    difference.change.name()
+                )?;
+                match &difference.change {
+                    Change::Type(from, to) => {
+                        write!(output, ", \"from\": \"{}\", \"to\": \"{}\"", from, to)?
+                    }
+                    Change::Target(from, to) => write!(
+                        output,
+                        ", \"from\": {}, \"to\": {}",
+                        json::string(from),
+                        json::string(to)
+                    )?,
+                    Change::Mode(from, to) => write!(
+                        output,
+                        ", \"from\": \"{:04o}\", \"to\": \"{:04o}\"",
+                        from, to
+                    )?,
+                    _ => {}
+                }
+                write!(output, "}}")?;
+            }
+            writeln!(
+                output,
+                "{}]",
+                if differences.is_empty() { "" } else { "\n" }
+            )?;
+        }
+        Format::Summary => {
+            let count = |change: &Change| {
+                differences
+                    .iter()
+                    .filter(|difference| difference.change == *change)
+                    .count()
+            };
+            // Entries with several differences, such as contents and permissions, count once
+            let differing: HashSet<&str> = differences
+                .iter()
+                .filter(|difference| !matches!(difference.change, Change::Removed | Change::Added))
+                .map(|difference| difference.path)
+                .collect();
+            writeln!(output, "{} only in the first", count(&Change::Removed))?;
+            writeln!(output, "{} only in the second", count(&Change::Added))?;
+            writeln!(output, "{} differing", differing.len())?;
+        }
+    }
+    Ok(())
+}
+
+/// Builds the manifest of `src` and writes it to `output`, or to stdout if there is none
+///
+/// # Errors
+/// This function will return an error if the manifest could not be built, or written
+pub fn export(src: &str, output: Option<&str>) -> Result<(), io::Error> {
+    let json = Manifest::build(src)?.to_json();
+    PROGRESS_BAR.finish_and_clear();
+
+    match output {
+        Some(output) => fs::write(output, json).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Manifest Error -- Writing {}: {}", output, e),
+            )
+        }),
+        None => io::stdout().write_all(json.as_bytes()),
+    }
+}
+
+/// Loads the manifest at `path`
+///
+/// # Errors
+/// This function will return an error if `path` could not be read, or is not a manifest
+pub fn load(path: &str) -> Result<Manifest, io::Error> {
+    let json = fs::read_to_string(path)
+        .map_err(|e| io::Error::new(e.kind(), format!("Manifest Error -- {}: {}", path, e)))?;
+    Manifest::from_json(&json).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Manifest Error -- {}: {}", path, e),
+        )
+    })
+}
+
+/// Writes the checksum manifest of every file in `src` to stdout, hashing them in parallel
+///
+/// Each line has the BLAKE3 hash of a file, two spaces, and its path relative to `src`, sorted by
+/// path, which is the format of `b3sum`, so `b3sum -c` in `src` can check it too.
+///
+/// # Errors
+/// This function will return an error if `src` is an invalid directory, or if any file could not
+/// be read, since a manifest with missing files would show them as missing
+pub fn hash_tree(src: &str) -> Result<(), io::Error> {
+    let checksums = checksums(src)?;
+
+    let stdout = io::stdout();
+    let mut stdout = io::BufWriter::new(stdout.lock());
+    for (path, hash) in checksums {
+        writeln!(stdout, "{}", format_checksum(&path, &hash))?;
+    }
+    stdout.flush()
+}
+
+/// Hashes every file in `src` in parallel
+///
+/// # Returns
+/// The path and hash of every file, sorted by path
+///
+/// # Errors
+/// This function will return an error if `src` is an invalid directory, or if any file could not
+/// be read
+fn checksums(src: &str) -> Result<Vec<(String, String)>, io::Error> {
+    let file_sets = file_ops::get_all_files(src)?;
+    progress::progress_init(file_sets.files().len() as u64);
+
+    let base = Path::new(src);
+    let mut checksums = file_sets
+        .files()
+        .par_iter()
+        .map(|file| {
+            let hash = checksum_file(base, file.path()).map_err(|e| {
+                io::Error::new(
+                    e.kind(),
+                    format!("Hash Error -- Reading {:?}: {}", base.join(file.path()), e),
+                )
+            })?;
+            PROGRESS_BAR.inc(1);
+            Ok((manifest_path(file.path()), hash))
+        })
+        .collect::<Result<Vec<(String, String)>, io::Error>>()?;
+    checksums.par_sort_unstable();
+    PROGRESS_BAR.finish_and_clear();
+    Ok(checksums)
+}
+
+/// Checks every file of the checksum manifest at `manifest` against the files in `src`,
+/// hashing them in parallel, and prints each file that differs
+///
+/// Files whose contents changed are printed as `M`, files that are missing as `-`, and files that
+/// are not in the manifest as `+`, like the differences of `diff`. Only changed and missing files
+/// fail the verification, since new files have nothing to have rotted from.
+///
+/// # Errors
+/// This function will return an error if `src` is an invalid directory, if `manifest` could not
+/// be loaded, or if any file in it did not match
+pub fn verify_tree(src: &str, manifest: &str) -> Result<(), io::Error> {
+    verify_checksums(src, &load_checksums(manifest)?, manifest, false)
+}
+
+/// Checks every file of `mirror` against the same file in `src`, and prints each file that differs
+///
+/// Differences are printed as by `verify_tree`, where files that are only in `mirror` are printed
+/// as `+`, and fail the verification too, since a mirror should hold nothing else.
+///
+/// # Errors
+/// This function will return an error if either directory is invalid, if any file of `src` could
+/// not be read, or if any file differs
+pub fn verify_mirror(src: &str, mirror: &str) -> Result<(), io::Error> {
+    verify_checksums(mirror, &checksums(src)?, src, true)
+}
+
+/// Checks the files in `src` against `checksums`, which were taken from `name`, see `verify_tree`
+///
+/// With `strict`, files that are not in `checksums` fail the verification too.
+fn verify_checksums(
+    src: &str,
+    checksums: &[(String, String)],
+    name: &str,
+    strict: bool,
+) -> Result<(), io::Error> {
+    let file_sets = file_ops::get_all_files(src)?;
+    progress::progress_init(checksums.len() as u64);
+
+    let base = Path::new(src);
+    let mut differences: Vec<Difference> = checksums
+        .par_iter()
+        .filter_map(|(path, hash)| {
+            let change = match checksum_file(base, Path::new(path)) {
+                Ok(actual) if &actual == hash => None,
+                Ok(_) => Some(Change::Contents),
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => Some(Change::Removed),
+                Err(e) => {
+                    error!("Verify Error -- Reading {:?}: {}", base.join(path), e);
+                    Some(Change::Contents)
+                }
+            };
+            PROGRESS_BAR.inc(1);
+            change.map(|change| Difference { path, change })
+        })
+        .collect();
+
+    let listed: HashSet<&str> = checksums.iter().map(|(path, _)| path.as_str()).collect();
+    let untracked: Vec<String> = file_sets
+        .files()
+        .par_iter()
+        .map(|file| manifest_path(file.path()))
+        .filter(|path| !listed.contains(path.as_str()))
+        .collect();
+    differences.extend(untracked.iter().map(|path| Difference {
+        path,
+        change: Change::Added,
+    }));
+    differences.par_sort_by(|a, b| a.path.cmp(b.path));
+    PROGRESS_BAR.finish_and_clear();
+
+    let stdout = io::stdout();
+    let mut stdout = io::BufWriter::new(stdout.lock());
+    for difference in &differences {
+        writeln!(stdout, "{}", difference)?;
+    }
+    stdout.flush()?;
+
+    let failures = differences
+        .iter()
+        .filter(|difference| strict || difference.change != Change::Added)
+        .count();
+    if failures > 0 {
+        return Err(io::Error::other(format!(
+            "Verify Error -- {} of {} files did not match {}",
+            failures,
+            checksums.len(),
+            name
+        )));
+    }
+    Ok(())
+}
+
+/// Loads the checksum manifest at `path`
+///
+/// # Returns
+/// The path and hash of every file in the manifest, in the order of the manifest
+///
+/// # Errors
+/// This function will return an error if `path` could not be read, or has a malformed line
+fn load_checksums(path: &str) -> Result<Vec<(String, String)>, io::Error> {
+    let checksums = fs::read_to_string(path)
+        .map_err(|e| io::Error::new(e.kind(), format!("Verify Error -- {}: {}", path, e)))?;
+    checksums
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.is_empty())
+        .map(|(i, line)| {
+            parse_checksum(line).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Verify Error -- {}: invalid line {}", path, i + 1),
+                )
+            })
+        })
+        .collect()
+}
+
+/// Formats the line of a checksum manifest for the file at `path`
+///
+/// Like `b3sum`, paths with backslashes or newlines have them escaped, and their line starts
+/// with a backslash.
+fn format_checksum(path: &str, hash: &str) -> String {
+    if path.contains('\\') || path.contains('\n') {
+        let path = path.replace('\\', "\\\\").replace('\n', "\\n");
+        format!("\\{}  {}", hash, path)
+    } else {
+        format!("{}  {}", hash, path)
+    }
+}
+
+/// Parses a line of a checksum manifest written by `format_checksum`
+///
+/// # Returns
+/// * Some: The path and hash of the file
+/// * None: If `line` is not a checksum of a file
+fn parse_checksum(line: &str) -> Option<(String, String)> {
+    let (escaped, line) = match line.strip_prefix('\\') {
+        Some(line) => (true, line),
+        None => (false, line),
+    };
+    let (hash, path) = line.split_once("  ")?;
+    if hash.len() != 64 || !hash.bytes().all(|b| b.is_ascii_hexdigit()) || path.is_empty() {
+        return None;
+    }
+
+    let path = if escaped {
+        let mut unescaped = String::with_capacity(path.len());
+        let mut chars = path.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => match chars.next()? {
+                    '\\' => unescaped.push('\\'),
+                    'n' => unescaped.push('\n'),
+                    _ => return None,
+                },
+                c => unescaped.push(c),
+            }
+        }
+        unescaped
+    } else {
+        path.to_string()
+    };
+    Some((path, hash.to_ascii_lowercase()))
+}
+
+/// Compares two entries at the same path
+fn compare<'a>(first: &'a Entry, second: &Entry) -> Vec<Difference<'a>> {
+    let difference = |change| Difference {
+        path: &first.path,
+        change,
+    };
+
+    let mut differences = Vec::new();
+    match (&first.kind, &second.kind) {
+        (EntryKind::Dir, EntryKind::Dir) => {}
+        (
+            EntryKind::File { size, hash },
+            EntryKind::File {
+                size: size2,
+                hash: hash2,
+            },
+        ) => {
+            if size != size2 || hash != hash2 {
+                differences.push(difference(Change::Contents));
+            }
+        }
+        (EntryKind::Symlink { target }, EntryKind::Symlink { target: target2 }) => {
+            if target != target2 {
+                differences.push(difference(Change::Target(target.clone(), target2.clone())));
+            }
+        }
+        (kind, kind2) => {
+            return vec![difference(Change::Type(kind.name(), kind2.name()))];
+        }
+    }
+    if first.mode != second.mode {
+        differences.push(difference(Change::Mode(first.mode, second.mode)));
+    }
+    differences
+}
+
+/// Parses a single entry of a manifest
+fn parse_entry(entry: &Value) -> Option<Entry> {
+    let field = |name| entry.get(name).and_then(Value::as_str);
+
+    let kind = match field("type")? {
+        "dir" => EntryKind::Dir,
+        "file" => EntryKind::File {
+            size: entry.get("size")?.as_u64()?,
+            hash: field("blake2b")?.to_string(),
+        },
+        "symlink" => EntryKind::Symlink {
+            target: field("target")?.to_string(),
+        },
+        _ => return None,
+    };
+    let mode = match kind {
+        EntryKind::Symlink { .. } => 0,
+        _ => u32::from_str_radix(field("mode")?, 8).ok()?,
+    };
+
+    Some(Entry {
+        path: field("path")?.to_string(),
+        kind,
+        mode,
+    })
+}
+
+/// Gets the size and BLAKE2b hash of the file `relative` inside `base`
+fn hash_file(base: &Path, relative: &Path) -> Result<(u64, String), io::Error> {
+    let mut file = dirfd::open_file(base, relative)?;
+    let mut hasher = Blake2b::new();
+    let size = io::copy(&mut file, &mut hasher)?;
+    let hash = hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    Ok((size, hash))
+}
+
+/// Gets the BLAKE3 hash of the file `relative` inside `base`, for checksum manifests
+fn checksum_file(base: &Path, relative: &Path) -> Result<String, io::Error> {
+    let mut file = dirfd::open_file(base, relative)?;
+    let mut hasher = blake3::Hasher::new();
+    io::copy(&mut file, &mut hasher)?;
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+/// Formats a relative path with `/` separating its components
+fn manifest_path(path: &Path) -> String {
+    path.components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_manifest {
+    use super::*;
+
+    fn file(path: &str, hash: &str, mode: u32) -> Entry {
+        Entry {
+            path: path.to_string(),
+            kind: EntryKind::File {
+                size: hash.len() as u64,
+                hash: hash.to_string(),
+            },
+            mode,
+        }
+    }
+
+    fn symlink(path: &str, target: &str) -> Entry {
+        Entry {
+            path: path.to_string(),
+            kind: EntryKind::Symlink {
+                target: target.to_string(),
+            },
+            mode: 0,
+        }
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let manifest = Manifest {
+            entries: vec![
+                Entry {
+                    path: "a".to_string(),
+                    kind: EntryKind::Dir,
+                    mode: 0o755,
+                },
+                file("a/\"b\"", "00ff", 0o4644),
+                symlink("c", "a/\"b\""),
+            ],
+        };
+        let json = manifest.to_json();
+        assert!(json.contains("{\"path\": \"a\", \"type\": \"dir\", \"mode\": \"0755\"}"));
+        assert_eq!(Manifest::from_json(&json).unwrap(), manifest);
+        assert_eq!(
+            Manifest::from_json("{\"version\": 1, \"entries\": []}").unwrap(),
+            Manifest::default()
+        );
+    }
+
+    #[test]
+    fn invalid_manifests() {
+        assert!(Manifest::from_json("[]").is_err());
+        assert!(Manifest::from_json("{\"version\": 2, \"entries\": []}").is_err());
+        assert!(Manifest::from_json("{\"version\": 1}").is_err());
+        assert!(Manifest::from_json(
+            "{\"version\": 1, \"entries\": [{\"path\": \"a\", \"type\": \"fifo\"}]}"
+        )
+        .is_err());
+        assert!(Manifest::from_json(
+            "{\"version\": 1, \"entries\": [{\"path\": \"a\", \"type\": \"dir\", \"mode\": \"9\"}]}"
+        )
+        .is_err());
+        assert!(load("/?").is_err());
+    }
+
+    #[test]
+    fn differences() {
+        let first = Manifest {
+            entries: vec![
+                file("changed", "00", 0o644),
+                file("chmod", "00", 0o644),
+                symlink("link", "a"),
+                file("removed", "00", 0o644),
+                file("same", "00", 0o644),
+                file("type", "00", 0o644),
+            ],
+        };
+        let second = Manifest {
+            entries: vec![
+                file("added", "00", 0o644),
+                file("changed", "01", 0o600),
+                file("chmod", "00", 0o600),
+                symlink("link", "b"),
+                file("same", "00", 0o644),
+                symlink("type", "a"),
+            ],
+        };
+
+        let differences: Vec<String> = diff(&first, &second)
+            .iter()
+            .map(|difference| difference.to_string())
+            .collect();
+        assert_eq!(
+            differences,
+            [
+                "+ added",
+                "M changed",
+                "P changed (0644 -> 0600)",
+                "P chmod (0644 -> 0600)",
+                "L link (a -> b)",
+                "- removed",
+                "T type (file -> symlink)",
+            ]
+        );
+        assert!(diff(&first, &first).is_empty());
+
+        let output = |format| {
+            let mut output = Vec::new();
+            write_differences(&diff(&first, &second), format, &mut output).unwrap();
+            String::from_utf8(output).unwrap()
+        };
+        assert!(output(Format::Plain).starts_with("+ added\nM changed\n"));
+        let json = json::parse(&output(Format::Json)).unwrap();
+        let json = json.as_array().unwrap();
+        assert_eq!(json.len(), 7);
+        assert_eq!(
+            json[4].get("change").and_then(Value::as_str),
+            Some("target")
+        );
+        assert_eq!(json[4].get("to").and_then(Value::as_str), Some("b"));
+        // Entries that differ in several ways count once
+        assert_eq!(
+            output(Format::Summary),
+            "1 only in the first\n1 only in the second\n4 differing\n"
+        );
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn differences_between_trees() {
+        const TEST_FIRST: &str = "test_manifest_differences_between_trees_first";
+        const TEST_SECOND: &str = "test_manifest_differences_between_trees_second";
+
+        for dir in &[TEST_FIRST, TEST_SECOND] {
+            fs::create_dir_all([dir, "sub"].join("/")).unwrap();
+            fs::write([dir, "same"].join("/"), b"same").unwrap();
+        }
+        fs::write([TEST_FIRST, "changed"].join("/"), b"abc").unwrap();
+        fs::write([TEST_SECOND, "changed"].join("/"), b"abd").unwrap();
+        fs::write([TEST_FIRST, "sub/removed"].join("/"), b"").unwrap();
+        fs::create_dir([TEST_SECOND, "added"].join("/")).unwrap();
+        std::os::unix::fs::symlink("same", [TEST_FIRST, "link"].join("/")).unwrap();
+        std::os::unix::fs::symlink("sub", [TEST_SECOND, "link"].join("/")).unwrap();
+        fs::write([TEST_FIRST, "type"].join("/"), b"").unwrap();
+        fs::create_dir([TEST_SECOND, "type"].join("/")).unwrap();
+
+        for &secure in &[false, true] {
+            let options = Options {
+                secure,
+                ..Options::default()
+            };
+            let changes = diff_dirs(TEST_FIRST, TEST_SECOND, &options).unwrap();
+            assert_eq!(
+                changes,
+                [
+                    ("added".to_string(), Change::Added),
+                    ("changed".to_string(), Change::Contents),
+                    (
+                        "link".to_string(),
+                        Change::Target("same".to_string(), "sub".to_string())
+                    ),
+                    ("sub/removed".to_string(), Change::Removed),
+                    ("type".to_string(), Change::Type("file", "dir")),
+                ]
+            );
+        }
+        assert!(diff_dirs(TEST_FIRST, TEST_FIRST, &Options::default())
+            .unwrap()
+            .is_empty());
+
+        fs::remove_dir_all(TEST_FIRST).unwrap();
+        fs::remove_dir_all(TEST_SECOND).unwrap();
+    }
+
+    #[test]
+    fn checksums() {
+        let hash = "0f".repeat(32);
+        for path in &["a", "a b/c  d", "a\\b", "a\nb\\n"] {
+            let line = format_checksum(path, &hash);
+            assert_eq!(
+                parse_checksum(&line),
+                Some((path.to_string(), hash.clone())),
+                "{}",
+                line
+            );
+        }
+        assert_eq!(format_checksum("a\nb", &hash), format!("\\{}  a\\nb", hash));
+
+        assert_eq!(parse_checksum(&format!("{} a", hash)), None);
+        assert_eq!(parse_checksum(&format!("{}  ", hash)), None);
+        assert_eq!(parse_checksum("0f  a"), None);
+        // BLAKE2b checksums, as printed by b2sum, are longer
+        assert_eq!(parse_checksum(&format!("{}  a", "0f".repeat(64))), None);
+        assert_eq!(parse_checksum(&format!("\\{}  a\\b", hash)), None);
+        assert!(load_checksums("/?").is_err());
+    }
+
+    #[test]
+    fn verify() {
+        const TEST_DIR: &str = "test_manifest_verify";
+        const TEST_MANIFEST: &str = "test_manifest_verify.b3";
+
+        fs::create_dir_all([TEST_DIR, "sub"].join("/")).unwrap();
+        fs::write([TEST_DIR, "a"].join("/"), b"a").unwrap();
+        fs::write([TEST_DIR, "sub/b"].join("/"), b"b").unwrap();
+        fs::write([TEST_DIR, "empty"].join("/"), b"").unwrap();
+        // The hash of nothing, as printed by b3sum
+        assert_eq!(
+            checksum_file(Path::new(TEST_DIR), Path::new("empty")).unwrap(),
+            "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262"
+        );
+
+        let line = |path: &str| {
+            format_checksum(
+                path,
+                &checksum_file(Path::new(TEST_DIR), Path::new(path)).unwrap(),
+            )
+        };
+        fs::write(
+            TEST_MANIFEST,
+            [line("a"), line("empty"), line("sub/b")].join("\n"),
+        )
+        .unwrap();
+        assert!(verify_tree(TEST_DIR, TEST_MANIFEST).is_ok());
+
+        // New files are not failures, while changed and missing files are
+        fs::write([TEST_DIR, "c"].join("/"), b"c").unwrap();
+        assert!(verify_tree(TEST_DIR, TEST_MANIFEST).is_ok());
+        fs::write([TEST_DIR, "a"].join("/"), b"rotted").unwrap();
+        assert!(verify_tree(TEST_DIR, TEST_MANIFEST).is_err());
+        fs::write([TEST_DIR, "a"].join("/"), b"a").unwrap();
+        fs::remove_file([TEST_DIR, "sub/b"].join("/")).unwrap();
+        assert!(verify_tree(TEST_DIR, TEST_MANIFEST).is_err());
+
+        assert!(verify_tree("/?", TEST_MANIFEST).is_err());
+        assert!(verify_tree(TEST_DIR, "/?").is_err());
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_file(TEST_MANIFEST).unwrap();
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn build() {
+        use std::os::unix::fs::PermissionsExt;
+
+        const TEST_DIR: &str = "test_manifest_build";
+
+        fs::create_dir_all([TEST_DIR, "sub"].join("/")).unwrap();
+        fs::write([TEST_DIR, "sub/file"].join("/"), b"").unwrap();
+        fs::set_permissions(
+            [TEST_DIR, "sub/file"].join("/"),
+            fs::Permissions::from_mode(0o640),
+        )
+        .unwrap();
+        std::os::unix::fs::symlink("sub/file", [TEST_DIR, "link"].join("/")).unwrap();
+
+        let manifest = Manifest::build(TEST_DIR).unwrap();
+        let paths: Vec<&str> = manifest.entries.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(paths, ["link", "sub", "sub/file"]);
+        assert_eq!(manifest.entries[0], symlink("link", "sub/file"));
+        assert_eq!(
+            manifest.entries[2],
+            Entry {
+                path: "sub/file".to_string(),
+                kind: EntryKind::File {
+                    size: 0,
+                    hash: "786a02f742015903c6c6fd852552d272912f4740e15847618a86e217f71f5419\
+                           d25e1031afee585313896444934eb04b903a685b1448b755d56f701afe9be2ce"
+                        .to_string()
+                },
+                mode: 0o640,
+            }
+        );
+
+        assert!(Manifest::build("/?").is_err());
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+}
@@ -0,0 +1,257 @@
+//! Writes marker files into destination directories once everything in them is complete, for
+//! downstream systems that only pick up directories that are marked as done
+//!
+//! Markers are removed before a directory is written to, and written deepest first once every
+//! file in the directory, and in the directories in it, matches its source and is flushed to
+//! disk. A directory is never marked before the directories in it.
+
+use std::path::{Path, PathBuf};
+
+use hashbrown::HashSet;
+use log::{error, info};
+use rayon::prelude::*;
+
+use crate::lumins::dirfd;
+use crate::lumins::file_ops::{self, FileOps, FileSets};
+use crate::lumins::parse::Options;
+use crate::lumins::platform;
+
+/// Removes the markers named `name` from the files of `file_sets`, so that they are neither
+/// copied nor deleted like other files
+///
+/// # Returns
+/// The directories that had markers
+pub fn unlist(file_sets: &mut FileSets, name: &str) -> Vec<PathBuf> {
+    let dirs = file_sets
+        .files()
+        .iter()
+        .filter(|file| is_marker(file.path(), name))
+        .map(|file| parent(file.path()).to_path_buf())
+        .collect();
+    file_sets.retain_files(|file| !is_marker(file.path(), name));
+    dirs
+}
+
+/// Removes the markers named `name` from the directories `dirs` of `dest`, before anything in
+/// them is written
+///
+/// Markers that do not exist are ignored.
+pub fn remove_markers<'a, T>(name: &str, dirs: T, dest: &str)
+where
+    T: IntoParallelIterator<Item = &'a Path>,
+{
+    let dest = Path::new(dest);
+    dirs.into_par_iter().for_each(|dir| {
+        let marker = dir.join(name);
+        match dirfd::remove_file(dest, &marker) {
+            Ok(_) => info!("Removing marker {:?}", dest.join(&marker)),
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => error!("Marker Error -- Removing {:?}: {}", dest.join(&marker), e),
+        }
+    });
+}
+
+/// Writes a marker named `name` into `dest` and every directory of `file_sets` in it, once all
+/// files they contain match their sources in `src`
+///
+/// Files are compared like `file_ops::compare_and_copy_files` compares them. Directories with
+/// files that do not match, or that contain directories whose markers could not be written, are
+/// logged and left unmarked.
+///
+/// # Arguments
+/// * `name`: File name of the markers
+/// * `file_sets`: Everything that was written into `dest`, relative to both `src` and `dest`
+/// * `src`: Source directory
+/// * `dest`: Destination directory
+/// * `options`: Options of the run
+///
+/// # Returns
+/// The number of directories that were marked
+pub fn write_markers(
+    name: &str,
+    file_sets: &FileSets,
+    src: &str,
+    dest: &str,
+    options: &Options,
+) -> usize {
+    let dest_path = Path::new(dest);
+
+    // Verify every file, and flush the ones that match to disk
+    let mismatched: Vec<&Path> = file_sets
+        .files()
+        .par_iter()
+        .filter(|file| {
+            if file_ops::file_differs(*file, src, dest, options) {
+                error!(
+                    "Marker Error -- {:?} does not match its source",
+                    dest_path.join(file.path())
+                );
+                return true;
+            }
+            match dirfd::open_file(dest_path, file.path()).and_then(|f| f.sync_all()) {
+                Ok(_) => false,
+                Err(e) => {
+                    error!(
+                        "Marker Error -- Flushing {:?}: {}",
+                        dest_path.join(file.path()),
+                        e
+                    );
+                    true
+                }
+            }
+        })
+        .map(|file| parent(file.path()))
+        .collect();
+    let mut incomplete: HashSet<&Path> = HashSet::new();
+    for dir in mismatched {
+        incomplete.extend(dir.ancestors());
+    }
+
+    let dirs: Vec<&Path> = file_sets
+        .dirs()
+        .iter()
+        .map(|dir| dir.path().as_path())
+        .chain(std::iter::once(Path::new("")))
+        .collect();
+    let max_depth = dirs.iter().map(|dir| depth(dir)).max().unwrap_or(0);
+
+    // Mark the deepest directories first, so that a directory is only marked after its children
+    let mut marked = 0;
+    for level in (0..=max_depth).rev() {
+        let ready: Vec<&Path> = dirs
+            .iter()
+            .filter(|dir| depth(dir) == level && !incomplete.contains(*dir))
+            .copied()
+            .collect();
+        let unmarked: Vec<&Path> = ready
+            .par_iter()
+            .filter(|dir| !write_marker(name, dest_path, dir))
+            .copied()
+            .collect();
+        marked += ready.len() - unmarked.len();
+        for dir in unmarked {
+            incomplete.extend(dir.ancestors());
+        }
+    }
+
+    for dir in &dirs {
+        if incomplete.contains(dir) {
+            error!(
+                "Marker Error -- {:?} is incomplete, so it was not marked",
+                dest_path.join(dir)
+            );
+        }
+    }
+
+    marked
+}
+
+/// Writes the marker named `name` into the directory `dir` of `dest`, and flushes it to disk
+///
+/// # Returns
+/// Whether the marker was written
+fn write_marker(name: &str, dest: &Path, dir: &Path) -> bool {
+    let marker = dir.join(name);
+    let written = dirfd::open_parent(dest, &marker, false)
+        .and_then(|(parent, name)| parent.create_file(&name, 0o644))
+        .and_then(|file| file.sync_all())
+        .and_then(|_| platform::sync_dir(&dest.join(dir)));
+    match written {
+        Ok(_) => {
+            info!("Marking dir {:?} as done", dest.join(dir));
+            true
+        }
+        Err(e) => {
+            error!("Marker Error -- Writing {:?}: {}", dest.join(&marker), e);
+            false
+        }
+    }
+}
+
+/// Checks whether `path` is a marker named `name`
+fn is_marker(path: &Path, name: &str) -> bool {
+    path.file_name().is_some_and(|file_name| file_name == name)
+}
+
+/// Gets the directory containing `path`, which is empty for the root of a tree
+fn parent(path: &Path) -> &Path {
+    path.parent().unwrap_or_else(|| Path::new(""))
+}
+
+/// Gets the number of components of `path`
+fn depth(path: &Path) -> usize {
+    path.components().count()
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_marker {
+    use super::*;
+    use crate::lumins::file_ops::File;
+    use std::fs;
+
+    #[test]
+    fn unlist_markers() {
+        let mut file_sets = FileSets::with(
+            ["DONE", "a/DONE", "a/b", "a/DONE.txt", "c/DONE"]
+                .iter()
+                .map(|file| File::from(file, 0))
+                .collect(),
+            HashSet::new(),
+            HashSet::new(),
+        );
+
+        let mut dirs = unlist(&mut file_sets, "DONE");
+        dirs.sort();
+        assert_eq!(
+            dirs,
+            vec![PathBuf::from(""), PathBuf::from("a"), PathBuf::from("c")]
+        );
+        let mut files: Vec<&PathBuf> = file_sets.files().iter().map(|file| file.path()).collect();
+        files.sort();
+        assert_eq!(files, vec![Path::new("a/DONE.txt"), Path::new("a/b")]);
+    }
+
+    #[test]
+    fn markers() {
+        const TEST_DIR: &str = "test_marker_markers";
+        const TEST_DIR_OUT: &str = "test_marker_markers_out";
+        let path = |dir: &str, path: &str| [dir, path].join("/");
+
+        for dir in &[TEST_DIR, TEST_DIR_OUT] {
+            fs::create_dir_all(path(dir, "good/deep")).unwrap();
+            fs::create_dir_all(path(dir, "bad/deep")).unwrap();
+            fs::write(path(dir, "good/deep/a"), b"a").unwrap();
+            fs::write(path(dir, "bad/deep/b"), b"b").unwrap();
+        }
+        fs::write(path(TEST_DIR_OUT, "bad/deep/b"), b"changed").unwrap();
+        fs::create_dir_all(path(TEST_DIR, "empty")).unwrap();
+        fs::create_dir_all(path(TEST_DIR_OUT, "empty")).unwrap();
+
+        let file_sets = file_ops::get_all_files(TEST_DIR).unwrap();
+        let options = Options::default();
+        assert_eq!(
+            write_markers("DONE", &file_sets, TEST_DIR, TEST_DIR_OUT, &options),
+            3
+        );
+
+        let marked = |dir: &str| fs::metadata(path(TEST_DIR_OUT, &[dir, "DONE"].join("/"))).is_ok();
+        assert!(marked("good"));
+        assert!(marked("good/deep"));
+        assert!(marked("empty"));
+        assert!(!marked("bad"));
+        assert!(!marked("bad/deep"));
+        assert!(!marked("."));
+
+        let dirs = [Path::new(""), Path::new("good"), Path::new("bad")];
+        remove_markers("DONE", dirs.to_vec(), TEST_DIR_OUT);
+        assert!(!marked("good"));
+        assert!(marked("good/deep"));
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_dir_all(TEST_DIR_OUT).unwrap();
+    }
+}
@@ -0,0 +1,606 @@
+//! Materializes only part of a tree, leaving lightweight placeholders for the rest
+//!
+//! Files that rules exclude from materialization are replaced by empty files, or by symlinks
+//! into a cold store that holds a full copy of the tree, while directories are always created,
+//! so that the structure of the tree is complete. Placeholders can be hydrated with their real
+//! contents later, from the source recorded in the materialized tree.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use hashbrown::HashSet;
+use log::{error, info};
+use rayon::prelude::*;
+
+use crate::lumins::core;
+use crate::lumins::dirfd;
+use crate::lumins::file_ops::{self, File, FileOps, FileSets, Symlink};
+use crate::lumins::glob::Glob;
+use crate::lumins::journal::{escape_path, unescape_path};
+use crate::lumins::parse::Options;
+use crate::progress::{self, PROGRESS_BAR};
+
+/// Name of the state of a partially materialized tree, in the root of the tree
+pub const STATE_NAME: &str = ".lumins-materialize";
+
+/// Enum to represent how the files matched by a rule are materialized
+#[derive(Eq, PartialEq, Debug, Clone)]
+enum Action {
+    /// Copied as usual
+    Full,
+    /// Replaced by an empty file
+    Empty,
+    /// Replaced by a symlink to the same path inside the given cold store
+    Link(PathBuf),
+}
+
+/// A rule that applies an action to the files matched by a pattern
+#[derive(Eq, PartialEq, Debug, Clone)]
+struct Rule {
+    pattern: Glob,
+    action: Action,
+}
+
+/// A struct that represents materialization rules
+///
+/// The last rule that matches a file decides how it is materialized. Files that no rule matches
+/// are copied as usual.
+#[derive(Eq, PartialEq, Debug, Clone, Default)]
+pub struct Rules {
+    rules: Vec<Rule>,
+}
+
+impl FromStr for Rules {
+    type Err = io::Error;
+
+    /// Parses materialization rules, one per line
+    ///
+    /// Each line is `full <pattern>`, `empty <pattern>`, or `link <pattern> <store>`, where
+    /// `<pattern>` is relative to the source, and `<store>` is the rest of the line.
+    /// A pattern matches a file if it matches the path of the file or of any directory
+    /// containing it. Empty lines and lines starting with `#` are ignored.
+    ///
+    /// # Examples
+    /// `empty assets` replaces every file under `assets` with an empty file, while a following
+    /// `full assets/icons` still copies the files under `assets/icons`
+    fn from_str(rules: &str) -> Result<Self, Self::Err> {
+        let mut parsed = Rules::default();
+
+        for (i, line) in rules.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let invalid = || {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "Materialize Error -- line {}: invalid rule {:?}",
+                        i + 1,
+                        line
+                    ),
+                )
+            };
+
+            let (action, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let rest = rest.trim_start();
+            let (pattern, store) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+            let store = Some(store.trim()).filter(|store| !store.is_empty());
+            let action = match (action, store) {
+                ("full", None) => Action::Full,
+                ("empty", None) => Action::Empty,
+                ("link", Some(store)) => Action::Link(PathBuf::from(store)),
+                _ => return Err(invalid()),
+            };
+
+            let pattern = Glob::new(pattern).ok_or_else(invalid)?;
+
+            parsed.rules.push(Rule { pattern, action });
+        }
+
+        Ok(parsed)
+    }
+}
+
+impl Rules {
+    /// Loads materialization rules from `path`
+    ///
+    /// # Errors
+    /// This function will return an error if `path` could not be read, or has invalid rules
+    pub fn load(path: &str) -> Result<Self, io::Error> {
+        let contents = fs::read_to_string(path).map_err(|e| {
+            io::Error::new(e.kind(), format!("Materialize Error -- {}: {}", path, e))
+        })?;
+        contents.parse()
+    }
+
+    /// Gets the action of the last rule that matches `path`
+    fn action(&self, path: &Path) -> &Action {
+        // Rules that match a directory match everything in it
+        self.rules
+            .iter()
+            .rev()
+            .find(|rule| rule.pattern.matches_within(path))
+            .map_or(&Action::Full, |rule| &rule.action)
+    }
+
+    /// Replaces the files of `file_sets` that are not materialized by placeholders
+    ///
+    /// Files in `hydrated` are always materialized, since they were hydrated on demand.
+    ///
+    /// # Returns
+    /// The file sets with the files to materialize, the directories, and the symlinks, along
+    /// with symlinks to the cold stores of linked files, and the empty placeholders of the rest
+    pub fn apply(
+        &self,
+        file_sets: &FileSets,
+        hydrated: &HashSet<PathBuf>,
+    ) -> (FileSets, HashSet<File>) {
+        let mut files = HashSet::new();
+        let mut symlinks = file_sets.symlinks().clone();
+        let mut empty = HashSet::new();
+
+        for file in file_sets.files() {
+            let action = if hydrated.contains(file.path()) {
+                &Action::Full
+            } else {
+                self.action(file.path())
+            };
+            match action {
+                Action::Full => {
+                    files.insert(file.clone());
+                }
+                Action::Empty => {
+                    empty.insert(File::from(&file.path().to_string_lossy(), 0));
+                }
+                Action::Link(store) => {
+                    let target = store.join(file.path());
+                    symlinks.insert(Symlink::from(
+                        &file.path().to_string_lossy(),
+                        &target.to_string_lossy(),
+                    ));
+                }
+            }
+        }
+
+        (
+            FileSets::with(files, file_sets.dirs().clone(), symlinks)
+                .with_specials(file_sets.specials().clone()),
+            empty,
+        )
+    }
+}
+
+/// A struct that represents an empty placeholder of a file that is not materialized
+#[derive(Hash, Eq, PartialEq, Debug, Clone)]
+pub struct Placeholder {
+    path: PathBuf,
+}
+
+impl FileOps for Placeholder {
+    fn path(&self) -> &PathBuf {
+        &self.path
+    }
+    fn size(&self) -> u64 {
+        0
+    }
+    fn remove(&self, location: &Path) {
+        let path = location.join(&self.path);
+        match dirfd::remove_file(location, &self.path) {
+            Ok(_) => info!("Deleting placeholder {:?}", path),
+            Err(e) => error!("Error -- Deleting placeholder {:?}: {}", path, e),
+        }
+    }
+    fn copy(&self, _src: &Path, dest: &Path, _options: &Options) -> bool {
+        let dest_file = dest.join(&self.path);
+        match dirfd::open_parent(dest, &self.path, true)
+            .and_then(|(parent, name)| parent.create_file(&name, 0o666))
+        {
+            Ok(_) => {
+                info!("Creating placeholder {:?}", dest_file);
+                true
+            }
+            Err(e) => {
+                error!("Error -- Creating placeholder {:?}: {}", dest_file, e);
+                false
+            }
+        }
+    }
+}
+
+impl From<&File> for Placeholder {
+    fn from(file: &File) -> Self {
+        Placeholder {
+            path: file.path().clone(),
+        }
+    }
+}
+
+/// A struct that represents the state of a partially materialized tree, which is the source it
+/// was materialized from, and the files that have been hydrated since
+#[derive(Eq, PartialEq, Debug, Clone, Default)]
+pub struct State {
+    pub source: PathBuf,
+    pub hydrated: HashSet<PathBuf>,
+}
+
+impl State {
+    /// Loads the state of the partially materialized tree `root`
+    ///
+    /// Lines that can not be parsed are ignored.
+    ///
+    /// # Returns
+    /// * Ok(Some): The state of the tree
+    /// * Ok(None): If `root` has no state, or it names no source
+    ///
+    /// # Errors
+    /// This function will return an error if the state exists and could not be read
+    pub fn load(root: &Path) -> Result<Option<Self>, io::Error> {
+        let path = root.join(STATE_NAME);
+        let contents = match fs::read(&path) {
+            Ok(contents) => contents,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(io::Error::new(
+                    e.kind(),
+                    format!("Materialize Error -- {:?}: {}", path, e),
+                ))
+            }
+        };
+
+        let mut source = None;
+        let mut hydrated = HashSet::new();
+        for line in contents.split(|&b| b == b'\n') {
+            let mut fields = line.splitn(2, |&b| b == b' ');
+            match (fields.next(), fields.next().and_then(unescape_path)) {
+                (Some(b"source"), Some(path)) => source = Some(path),
+                (Some(b"hydrated"), Some(path)) => {
+                    hydrated.insert(path);
+                }
+                _ => {}
+            }
+        }
+
+        Ok(source.map(|source| State { source, hydrated }))
+    }
+
+    /// Writes the state into the root of the partially materialized tree `root`
+    ///
+    /// # Errors
+    /// This function will return an error if the state could not be written
+    pub fn save(&self, root: &Path) -> Result<(), io::Error> {
+        let mut hydrated: Vec<&PathBuf> = self.hydrated.iter().collect();
+        hydrated.sort_unstable();
+
+        let mut contents = b"source ".to_vec();
+        contents.extend(escape_path(&self.source).unwrap_or_default());
+        contents.push(b'\n');
+        for path in hydrated {
+            if let Some(path) = escape_path(path) {
+                contents.extend(b"hydrated ");
+                contents.extend(path);
+                contents.push(b'\n');
+            }
+        }
+
+        let path = root.join(STATE_NAME);
+        fs::write(&path, contents).map_err(|e| {
+            io::Error::new(e.kind(), format!("Materialize Error -- {:?}: {}", path, e))
+        })
+    }
+}
+
+/// Replaces the placeholders in `paths` with their real contents
+///
+/// Each of `paths` is a placeholder, or a directory of placeholders, inside a partially
+/// materialized tree. Their contents are copied from `source`, or from the source that the tree
+/// was materialized from. Hydrated files are recorded in the tree, so that later
+/// synchronizations keep them materialized.
+///
+/// # Arguments
+/// * `paths`: Placeholders or directories to hydrate
+/// * `source`: Source to copy the contents from, instead of the recorded source
+/// * `options`: Options of the run
+///
+/// # Errors
+/// This function will return an error in the following situations,
+/// but is not limited to just these cases:
+/// * One of `paths` is not inside a partially materialized tree
+/// * The recorded state of the tree could not be updated
+/// * Some placeholders could not be hydrated
+pub fn hydrate(paths: &[String], source: Option<&str>, options: &Options) -> Result<(), io::Error> {
+    let options = &core::for_run(options);
+    let mut failed = 0;
+
+    for path in paths {
+        let (root, relative) = find_root(Path::new(path))?;
+        let mut state = State::load(&root)?.unwrap_or_default();
+        let source = match source {
+            Some(source) => PathBuf::from(source),
+            None if !state.source.as_os_str().is_empty() => state.source.clone(),
+            None => {
+                return Err(io::Error::other(format!(
+                    "Hydrate Error -- {:?} has no recorded source; use --from",
+                    root
+                )))
+            }
+        };
+
+        // Find the source files of the placeholders in `path`
+        let source_path = source.join(&relative);
+        let metadata = fs::metadata(&source_path).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Hydrate Error -- {:?}: {}", source_path, e),
+            )
+        })?;
+        let source_files: Vec<File> = if metadata.is_dir() {
+            let file_sets = file_ops::get_all_files(&source_path.to_string_lossy())?;
+            file_sets
+                .files()
+                .iter()
+                .map(|file| File::from(&relative.join(file.path()).to_string_lossy(), file.size()))
+                .collect()
+        } else {
+            vec![File::from(&relative.to_string_lossy(), metadata.len())]
+        };
+        let placeholders: Vec<&File> = source_files
+            .iter()
+            .filter(|file| is_placeholder(&root.join(file.path()), file.size()))
+            .collect();
+
+        let placeholders_len = placeholders.len();
+        progress::progress_init(placeholders_len as u64);
+        let hydrated: Vec<&PathBuf> = placeholders
+            .into_par_iter()
+            .filter_map(|file| {
+                // Symlinks into the cold store are replaced, instead of written through
+                if let Err(e) = dirfd::remove_file(&root, file.path()) {
+                    error!("Error -- Removing placeholder {:?}: {}", file.path(), e);
+                    PROGRESS_BAR.inc(1);
+                    return None;
+                }
+                let copied = file.copy(&source, &root, options);
+                PROGRESS_BAR.inc(1);
+                if copied {
+                    info!("Hydrated {:?}", root.join(file.path()));
+                    Some(file.path())
+                } else {
+                    None
+                }
+            })
+            .collect();
+        // Files that were still being written are copied too, though they count as failed
+        file_ops::copy_deferred(options);
+
+        failed += placeholders_len - hydrated.len();
+
+        state.hydrated.extend(hydrated.into_iter().cloned());
+        state.save(&root)?;
+    }
+
+    if failed == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::other(format!(
+            "Hydrate Error -- {} placeholder(s) could not be hydrated",
+            failed
+        )))
+    }
+}
+
+/// Checks whether `path` looks like a placeholder of a source file of size `size`, which is an
+/// empty file in place of a non-empty one, or a symlink in place of a file
+fn is_placeholder(path: &Path, size: u64) -> bool {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_symlink() => true,
+        Ok(metadata) => metadata.is_file() && metadata.len() == 0 && size > 0,
+        Err(_) => false,
+    }
+}
+
+/// Finds the root of the partially materialized tree containing `path`
+///
+/// # Returns
+/// The root, and `path` relative to it
+///
+/// # Errors
+/// This function will return an error if `path` does not exist, or is not inside a partially
+/// materialized tree
+fn find_root(path: &Path) -> Result<(PathBuf, PathBuf), io::Error> {
+    let hydrate_error =
+        |e: io::Error| io::Error::new(e.kind(), format!("Hydrate Error -- {:?}: {}", path, e));
+
+    // Symlinks into the cold store are placeholders themselves, so they are not followed
+    let metadata = fs::symlink_metadata(path).map_err(hydrate_error)?;
+    let path = match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) if metadata.file_type().is_symlink() => {
+            let parent = if parent.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                parent
+            };
+            fs::canonicalize(parent).map_err(hydrate_error)?.join(name)
+        }
+        _ => fs::canonicalize(path).map_err(hydrate_error)?,
+    };
+
+    path.ancestors()
+        .find(|root| root.join(STATE_NAME).is_file())
+        .map(|root| {
+            // Safe to unwrap since `root` is an ancestor of `path`
+            let relative = path.strip_prefix(root).unwrap().to_path_buf();
+            (root.to_path_buf(), relative)
+        })
+        .ok_or_else(|| {
+            io::Error::other(format!(
+                "Hydrate Error -- {:?} is not inside a tree materialized with --materialize",
+                path
+            ))
+        })
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_materialize {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let rules: Rules = "
+            # heavy assets stay in the cold store
+            link assets/** /mnt/cold store
+            empty /*.psd
+            full ./assets/icons
+        "
+        .parse()
+        .unwrap();
+        assert_eq!(
+            rules.rules,
+            vec![
+                Rule {
+                    pattern: Glob::new("assets/**").unwrap(),
+                    action: Action::Link(PathBuf::from("/mnt/cold store")),
+                },
+                Rule {
+                    pattern: Glob::new("*.psd").unwrap(),
+                    action: Action::Empty,
+                },
+                Rule {
+                    pattern: Glob::new("assets/icons").unwrap(),
+                    action: Action::Full,
+                },
+            ]
+        );
+
+        for rules in &[
+            "empty", "link a", "full a b", "skip a", "empty ..", "empty /",
+        ] {
+            assert!(rules.parse::<Rules>().is_err(), "{:?}", rules);
+        }
+    }
+
+    #[test]
+    fn actions() {
+        let rules: Rules = "empty assets\nfull assets/icons\nlink **/*.raw /cold\nempty a?c/**/d"
+            .parse()
+            .unwrap();
+        let action = |path: &str| rules.action(Path::new(path)).clone();
+
+        assert_eq!(action("src/main.rs"), Action::Full);
+        assert_eq!(action("assets"), Action::Empty);
+        assert_eq!(action("assets/big/texture.png"), Action::Empty);
+        assert_eq!(action("assets/icons/icon.png"), Action::Full);
+        assert_eq!(action("assets/icons.png"), Action::Empty);
+        assert_eq!(action("photo.raw"), Action::Link(PathBuf::from("/cold")));
+        assert_eq!(
+            action("assets/icons/photo.raw"),
+            Action::Link(PathBuf::from("/cold"))
+        );
+        assert_eq!(action("abc/d/e"), Action::Empty);
+        assert_eq!(action("abc/x/y/d"), Action::Empty);
+        assert_eq!(action("abbc/d"), Action::Full);
+    }
+
+    #[test]
+    fn apply() {
+        let rules: Rules = "empty a\nlink b /cold".parse().unwrap();
+        let mut files = HashSet::new();
+        files.insert(File::from("a/x", 10));
+        files.insert(File::from("b/y", 20));
+        files.insert(File::from("c", 30));
+        let file_sets = FileSets::with(files, HashSet::new(), HashSet::new());
+
+        let (materialized, empty) = rules.apply(&file_sets, &HashSet::new());
+        assert_eq!(
+            materialized.files().iter().collect::<Vec<_>>(),
+            vec![&File::from("c", 30)]
+        );
+        assert_eq!(
+            materialized.symlinks().iter().collect::<Vec<_>>(),
+            vec![&Symlink::from("b/y", "/cold/b/y")]
+        );
+        assert_eq!(
+            empty.iter().collect::<Vec<_>>(),
+            vec![&File::from("a/x", 0)]
+        );
+
+        // Hydrated files are always materialized
+        let mut hydrated = HashSet::new();
+        hydrated.insert(PathBuf::from("a/x"));
+        let (materialized, empty) = rules.apply(&file_sets, &hydrated);
+        assert!(materialized.files().contains(&File::from("a/x", 10)));
+        assert!(empty.is_empty());
+    }
+
+    #[test]
+    fn state() {
+        const TEST_DIR: &str = "test_materialize_state";
+        fs::create_dir_all(TEST_DIR).unwrap();
+        assert_eq!(State::load(Path::new(TEST_DIR)).unwrap(), None);
+
+        let mut state = State {
+            source: PathBuf::from("/src dir"),
+            hydrated: HashSet::new(),
+        };
+        state.hydrated.insert(PathBuf::from("a\nb"));
+        state.hydrated.insert(PathBuf::from("c"));
+        state.save(Path::new(TEST_DIR)).unwrap();
+        assert_eq!(State::load(Path::new(TEST_DIR)).unwrap(), Some(state));
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn hydrate_placeholders() {
+        use crate::lumins::core::synchronize;
+
+        const TEST_DIR: &str = "test_materialize_hydrate";
+        const TEST_DIR_OUT: &str = "test_materialize_hydrate_out";
+
+        fs::create_dir_all([TEST_DIR, "assets/big"].join("/")).unwrap();
+        fs::create_dir_all(TEST_DIR_OUT).unwrap();
+        fs::write([TEST_DIR, "assets/big/texture"].join("/"), b"texture").unwrap();
+        fs::write([TEST_DIR, "assets/icon"].join("/"), b"icon").unwrap();
+        fs::write([TEST_DIR, "photo"].join("/"), b"photo").unwrap();
+
+        let options = Options {
+            materialize: Some("empty assets\nlink photo /cold".parse().unwrap()),
+            ..Options::default()
+        };
+        assert!(synchronize(TEST_DIR, TEST_DIR_OUT, &options).is_ok());
+        assert_eq!(
+            fs::read([TEST_DIR_OUT, "assets/icon"].join("/")).unwrap(),
+            b""
+        );
+
+        // Trees that were not materialized have nothing to hydrate
+        assert!(hydrate(&[TEST_DIR.to_string()], None, &Options::default()).is_err());
+
+        let paths = [
+            [TEST_DIR_OUT, "assets/big"].join("/"),
+            [TEST_DIR_OUT, "photo"].join("/"),
+        ];
+        assert!(hydrate(&paths, None, &Options::default()).is_ok());
+        let read = |path: &str| fs::read([TEST_DIR_OUT, path].join("/")).unwrap();
+        assert_eq!(read("assets/big/texture"), b"texture");
+        assert_eq!(read("photo"), b"photo");
+        assert_eq!(read("assets/icon"), b"");
+
+        // Hydrated files stay materialized
+        assert!(synchronize(TEST_DIR, TEST_DIR_OUT, &options).is_ok());
+        assert_eq!(read("assets/big/texture"), b"texture");
+        assert_eq!(read("photo"), b"photo");
+        assert_eq!(read("assets/icon"), b"");
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_dir_all(TEST_DIR_OUT).unwrap();
+    }
+}
@@ -0,0 +1,268 @@
+//! Preserves extended metadata of files, such as extended attributes and ACLs
+
+use std::io;
+use std::path::Path;
+
+#[cfg(target_family = "unix")]
+use crate::lumins::hashcache;
+
+/// Copies all extended attributes of `src` to `dest`, and removes any extended attributes of
+/// `dest` that `src` does not have
+///
+/// Extended attributes are only supported on Unix-based platforms,
+/// so this does nothing on other platforms.
+///
+/// # Errors
+/// This function will return an error in the following situations,
+/// but is not limited to just these cases:
+/// * The extended attributes of `src` could not be read
+/// * The filesystem of `dest` does not support extended attributes
+/// * The process is not permitted to set an attribute, e.g. `security.*` as a regular user
+#[cfg(target_family = "unix")]
+pub fn copy_xattrs(src: &Path, dest: &Path) -> Result<(), io::Error> {
+    if !xattr::SUPPORTED_PLATFORM {
+        return Ok(());
+    }
+
+    let src_names: Vec<_> = xattr::list(src)?.filter(|name| is_xattr(name)).collect();
+
+    for name in xattr::list(dest)? {
+        if is_xattr(&name) && !src_names.contains(&name) {
+            xattr::remove(dest, &name)?;
+        }
+    }
+
+    for name in &src_names {
+        if let Some(value) = xattr::get(src, name)? {
+            if xattr::get(dest, name)?.as_ref() != Some(&value) {
+                xattr::set(dest, name, &value)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Copies all extended attributes of `src` to `dest`
+///
+/// Extended attributes are only supported on Unix-based platforms,
+/// so this does nothing on other platforms.
+#[cfg(not(target_family = "unix"))]
+pub fn copy_xattrs(_src: &Path, _dest: &Path) -> Result<(), io::Error> {
+    Ok(())
+}
+
+/// Checks whether an attribute name is a plain extended attribute, rather than an ACL
+/// stored as an extended attribute, or a hash cached by `hashcache`
+#[cfg(target_family = "unix")]
+fn is_xattr(name: &std::ffi::OsStr) -> bool {
+    !name.to_string_lossy().starts_with("system.posix_acl_") && name != hashcache::HASH_XATTR
+}
+
+/// Whether ACLs can be copied on this platform
+pub const ACLS_SUPPORTED: bool = cfg!(any(target_os = "linux", target_family = "windows"));
+
+/// Copies the POSIX access and default ACLs of `src` to `dest`
+///
+/// On Linux, ACLs are stored as the `system.posix_acl_access` and `system.posix_acl_default`
+/// extended attributes, so they are copied as such. ACLs that `src` does not have are removed
+/// from `dest`.
+///
+/// # Errors
+/// This function will return an error in the following situations,
+/// but is not limited to just these cases:
+/// * The ACLs of `src` could not be read
+/// * The filesystem of `dest` does not support ACLs
+#[cfg(target_os = "linux")]
+pub fn copy_acls(src: &Path, dest: &Path) -> Result<(), io::Error> {
+    const ACL_NAMES: [&str; 2] = ["system.posix_acl_access", "system.posix_acl_default"];
+
+    for name in &ACL_NAMES {
+        let src_acl = xattr::get(src, name)?;
+        let dest_acl = xattr::get(dest, name)?;
+
+        match src_acl {
+            Some(acl) => {
+                if dest_acl.as_ref() != Some(&acl) {
+                    xattr::set(dest, name, &acl)?;
+                }
+            }
+            None => {
+                if dest_acl.is_some() {
+                    xattr::remove(dest, name)?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Copies the discretionary access control list (DACL) of `src` to `dest`
+///
+/// # Errors
+/// This function will return an error if the DACL of `src` could not be read,
+/// or could not be applied to `dest`
+#[cfg(target_family = "windows")]
+pub fn copy_acls(src: &Path, dest: &Path) -> Result<(), io::Error> {
+    use std::os::windows::ffi::OsStrExt;
+    use std::ptr::null_mut;
+    use winapi::um::accctrl::SE_FILE_OBJECT;
+    use winapi::um::aclapi::{GetNamedSecurityInfoW, SetNamedSecurityInfoW};
+    use winapi::um::winbase::LocalFree;
+    use winapi::um::winnt::{DACL_SECURITY_INFORMATION, PACL, PSECURITY_DESCRIPTOR};
+
+    let src: Vec<u16> = src.as_os_str().encode_wide().chain(Some(0)).collect();
+    let mut dest: Vec<u16> = dest.as_os_str().encode_wide().chain(Some(0)).collect();
+    let mut dacl: PACL = null_mut();
+    let mut descriptor: PSECURITY_DESCRIPTOR = null_mut();
+
+    // Safe since `src` is a valid null-terminated wide string, and `dacl` points into
+    // `descriptor`, which is only freed after `dacl` is no longer used
+    unsafe {
+        let result = GetNamedSecurityInfoW(
+            src.as_ptr(),
+            SE_FILE_OBJECT,
+            DACL_SECURITY_INFORMATION,
+            null_mut(),
+            null_mut(),
+            &mut dacl,
+            null_mut(),
+            &mut descriptor,
+        );
+        if result != 0 {
+            return Err(io::Error::from_raw_os_error(result as i32));
+        }
+
+        let result = SetNamedSecurityInfoW(
+            dest.as_mut_ptr(),
+            SE_FILE_OBJECT,
+            DACL_SECURITY_INFORMATION,
+            null_mut(),
+            null_mut(),
+            dacl,
+            null_mut(),
+        );
+        LocalFree(descriptor as _);
+
+        if result != 0 {
+            return Err(io::Error::from_raw_os_error(result as i32));
+        }
+    }
+
+    Ok(())
+}
+
+/// Copies the ACLs of `src` to `dest`
+///
+/// ACLs are not supported on this platform, so this always returns an error
+#[cfg(not(any(target_os = "linux", target_family = "windows")))]
+pub fn copy_acls(_src: &Path, _dest: &Path) -> Result<(), io::Error> {
+    Err(io::Error::other("ACLs are not supported on this platform"))
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(all(test, target_family = "unix"))]
+mod test_copy_xattrs {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn invalid_file() {
+        assert!(copy_xattrs(Path::new("/?"), Path::new("/?")).is_err());
+    }
+
+    #[test]
+    fn user_xattrs() {
+        const TEST_DIR: &str = "test_copy_xattrs_user_xattrs";
+        let src = Path::new(TEST_DIR).join("src");
+        let dest = Path::new(TEST_DIR).join("dest");
+
+        fs::create_dir_all(TEST_DIR).unwrap();
+        fs::write(&src, b"src").unwrap();
+        fs::write(&dest, b"dest").unwrap();
+
+        // Skip if the filesystem does not support user xattrs
+        if xattr::set(&src, "user.lumins.a", b"1").is_ok() {
+            xattr::set(&src, "user.lumins.b", b"2").unwrap();
+            xattr::set(&dest, "user.lumins.b", b"old").unwrap();
+            xattr::set(&dest, "user.lumins.c", b"3").unwrap();
+
+            assert!(copy_xattrs(&src, &dest).is_ok());
+
+            assert_eq!(
+                xattr::get(&dest, "user.lumins.a").unwrap(),
+                Some(b"1".to_vec())
+            );
+            assert_eq!(
+                xattr::get(&dest, "user.lumins.b").unwrap(),
+                Some(b"2".to_vec())
+            );
+            assert_eq!(xattr::get(&dest, "user.lumins.c").unwrap(), None);
+        }
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod test_copy_acls {
+    use super::*;
+    use std::fs;
+
+    /// Builds a `system.posix_acl_access` value granting read access to user `uid`
+    fn acl_with_user(uid: u32) -> Vec<u8> {
+        const UNDEFINED_ID: u32 = u32::MAX;
+        // (tag, permissions, id) for USER_OBJ, USER, GROUP_OBJ, MASK, OTHER
+        let entries = [
+            (0x01u16, 6u16, UNDEFINED_ID),
+            (0x02, 4, uid),
+            (0x04, 4, UNDEFINED_ID),
+            (0x10, 4, UNDEFINED_ID),
+            (0x20, 4, UNDEFINED_ID),
+        ];
+
+        let mut acl = 2u32.to_le_bytes().to_vec();
+        for (tag, permissions, id) in &entries {
+            acl.extend_from_slice(&tag.to_le_bytes());
+            acl.extend_from_slice(&permissions.to_le_bytes());
+            acl.extend_from_slice(&id.to_le_bytes());
+        }
+        acl
+    }
+
+    #[test]
+    fn invalid_file() {
+        assert!(copy_acls(Path::new("/?"), Path::new("/?")).is_err());
+    }
+
+    #[test]
+    fn access_acl() {
+        const TEST_DIR: &str = "test_copy_acls_access_acl";
+        let src = Path::new(TEST_DIR).join("src");
+        let dest = Path::new(TEST_DIR).join("dest");
+
+        fs::create_dir_all(TEST_DIR).unwrap();
+        fs::write(&src, b"src").unwrap();
+        fs::write(&dest, b"dest").unwrap();
+
+        // Skip if the filesystem does not support ACLs
+        if xattr::set(&src, "system.posix_acl_access", &acl_with_user(1234)).is_ok() {
+            assert!(copy_acls(&src, &dest).is_ok());
+            assert_eq!(
+                xattr::get(&dest, "system.posix_acl_access").unwrap(),
+                xattr::get(&src, "system.posix_acl_access").unwrap()
+            );
+
+            // ACLs are not copied as plain xattrs
+            xattr::remove(&dest, "system.posix_acl_access").unwrap();
+            assert!(copy_xattrs(&src, &dest).is_ok());
+            assert_eq!(xattr::get(&dest, "system.posix_acl_access").unwrap(), None);
+        }
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+}
@@ -1,4 +1,66 @@
+pub mod archive;
+pub mod bisync;
+pub mod capabilities;
+pub mod casefold;
+pub mod cli;
+pub mod compress;
+pub mod config;
 pub mod core;
+pub mod daemon;
+#[cfg(target_family = "unix")]
+pub mod dedup;
+pub mod delta;
+pub mod dirfd;
+pub mod dirlinks;
+pub mod dupes;
+pub mod exit;
+pub mod fence;
+pub mod fidelity;
 pub mod file_ops;
+pub mod filelist;
+pub mod filter;
+pub mod glob;
+#[cfg(target_os = "linux")]
+pub mod harden;
+pub mod hashcache;
+pub mod help;
+pub mod history;
+pub mod hooks;
+pub mod idmap;
+pub mod image;
+#[cfg(target_os = "linux")]
+pub mod inotify;
+pub mod journal;
+pub mod json;
+pub mod limits;
+pub mod logfile;
+pub mod lookup;
+pub mod man;
+pub mod manifest;
+pub mod marker;
+pub mod materialize;
+pub mod metadata;
+pub mod oci;
+pub mod overlay;
 pub mod parse;
+pub mod platform;
+pub mod preview;
+#[cfg(target_os = "linux")]
+pub mod privsep;
 pub mod progress;
+pub mod quarantine;
+pub mod retry;
+pub mod review;
+pub mod rewrite;
+pub mod sandbox;
+pub mod sanitize;
+pub mod snapshot;
+pub mod space;
+pub mod squashfs;
+pub mod standby;
+pub mod stats;
+pub mod tree;
+pub mod unpack;
+pub mod watch;
+pub mod workers;
+pub mod zip;
@@ -0,0 +1,429 @@
+//! Exports directory trees as OCI container images
+
+use std::fs;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use log::info;
+
+use crate::lumins::archive::{self, TarWriter};
+use crate::lumins::json;
+
+/// Media type of an uncompressed tar layer
+const LAYER_MEDIA_TYPE: &str = "application/vnd.oci.image.layer.v1.tar";
+
+/// Media type of an image config
+const CONFIG_MEDIA_TYPE: &str = "application/vnd.oci.image.config.v1+json";
+
+/// Media type of an image manifest
+const MANIFEST_MEDIA_TYPE: &str = "application/vnd.oci.image.manifest.v1+json";
+
+/// Exports `src` as a single-layer image into the OCI image layout `layout`
+///
+/// The layer is an uncompressed tar of `src`, written with `archive::write_tree`, so the
+/// image only depends on the contents, permissions, and paths of the tree: exporting the
+/// same tree again gives the same digests. `index.json` is replaced by an index that only
+/// has the exported image, while blobs already in `layout` are kept.
+///
+/// # Arguments
+/// * `src`: directory to export
+/// * `layout`: OCI image layout directory, which is created if it does not exist
+/// * `tag`: reference name to annotate the image with in `index.json`, e.g. `latest`
+///
+/// # Returns
+/// * Ok: The digest of the image manifest, e.g. `sha256:3b4f...`
+/// * Error: If `src` is an invalid directory, or the image could not be written
+pub fn export(src: &str, layout: &str, tag: Option<&str>) -> Result<String, io::Error> {
+    let layout = Path::new(layout);
+    let blobs = layout.join("blobs").join("sha256");
+    fs::create_dir_all(&blobs)?;
+
+    // Write the layer into a temporary blob, since its digest is only known at the end
+    let layer_path = blobs.join(".lms-layer.tmp");
+    let writer = HashWriter::new(BufWriter::new(fs::File::create(&layer_path)?));
+    let mut tar = TarWriter::new(writer, Some(0));
+    let layer = archive::write_tree(src, &mut tar)
+        .and_then(|_| tar.finish())
+        .and_then(|writer| writer.finish())
+        .and_then(|(digest, size)| {
+            fs::rename(&layer_path, blobs.join(&digest[7..]))?;
+            Ok(Descriptor::new(LAYER_MEDIA_TYPE, digest, size))
+        });
+    let layer = match layer {
+        Ok(layer) => layer,
+        Err(e) => {
+            fs::remove_file(&layer_path).ok();
+            return Err(e);
+        }
+    };
+    info!("Writing layer {}", layer.digest);
+
+    let config = format!(
+        concat!(
+            "{{\"architecture\":{},\"os\":\"linux\",\"config\":{{}},",
+            "\"rootfs\":{{\"type\":\"layers\",\"diff_ids\":[{}]}}}}"
+        ),
+        json::string(architecture()),
+        json::string(&layer.digest),
+    );
+    let config = write_blob(&blobs, CONFIG_MEDIA_TYPE, config.as_bytes())?;
+    info!("Writing config {}", config.digest);
+
+    let manifest = format!(
+        "{{\"schemaVersion\":2,\"mediaType\":{},\"config\":{},\"layers\":[{}]}}",
+        json::string(MANIFEST_MEDIA_TYPE),
+        config.to_json(None),
+        layer.to_json(None),
+    );
+    let manifest = write_blob(&blobs, MANIFEST_MEDIA_TYPE, manifest.as_bytes())?;
+    info!("Writing manifest {}", manifest.digest);
+
+    let index = format!(
+        "{{\"schemaVersion\":2,\"manifests\":[{}]}}",
+        manifest.to_json(tag)
+    );
+    fs::write(layout.join("index.json"), index)?;
+    fs::write(
+        layout.join("oci-layout"),
+        "{\"imageLayoutVersion\":\"1.0.0\"}",
+    )?;
+
+    Ok(manifest.digest)
+}
+
+/// A struct that represents an OCI content descriptor
+struct Descriptor {
+    media_type: &'static str,
+    digest: String,
+    size: u64,
+}
+
+impl Descriptor {
+    fn new(media_type: &'static str, digest: String, size: u64) -> Self {
+        Descriptor {
+            media_type,
+            digest,
+            size,
+        }
+    }
+
+    /// Serializes the descriptor, annotated with the reference name `tag` if there is one
+    fn to_json(&self, tag: Option<&str>) -> String {
+        let annotations = match tag {
+            Some(tag) => format!(
+                ",\"annotations\":{{\"org.opencontainers.image.ref.name\":{}}}",
+                json::string(tag)
+            ),
+            None => String::new(),
+        };
+        format!(
+            "{{\"mediaType\":{},\"digest\":{},\"size\":{}{}}}",
+            json::string(self.media_type),
+            json::string(&self.digest),
+            self.size,
+            annotations
+        )
+    }
+}
+
+/// Writes `contents` into `blobs` under its digest
+fn write_blob(
+    blobs: &Path,
+    media_type: &'static str,
+    contents: &[u8],
+) -> Result<Descriptor, io::Error> {
+    let mut hasher = Sha256::new();
+    hasher.update(contents);
+    let digest = digest_string(&hasher.finalize());
+
+    fs::write(blobs.join(&digest[7..]), contents)?;
+    Ok(Descriptor::new(media_type, digest, contents.len() as u64))
+}
+
+/// Gets the OCI name of the architecture that lms was built for
+fn architecture() -> &'static str {
+    match std::env::consts::ARCH {
+        "x86_64" => "amd64",
+        "x86" => "386",
+        "aarch64" => "arm64",
+        "powerpc64" => "ppc64le",
+        arch => arch,
+    }
+}
+
+/// Formats a SHA-256 hash as an OCI digest
+fn digest_string(hash: &[u8; 32]) -> String {
+    let hex: String = hash.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("sha256:{}", hex)
+}
+
+/// A writer that computes the SHA-256 digest and size of everything written through it
+struct HashWriter<W: Write> {
+    writer: W,
+    hasher: Sha256,
+    size: u64,
+}
+
+impl<W: Write> HashWriter<W> {
+    fn new(writer: W) -> Self {
+        HashWriter {
+            writer,
+            hasher: Sha256::new(),
+            size: 0,
+        }
+    }
+
+    /// Flushes the underlying writer, and returns the digest and size of what was written
+    fn finish(mut self) -> Result<(String, u64), io::Error> {
+        self.writer.flush()?;
+        Ok((digest_string(&self.hasher.finalize()), self.size))
+    }
+}
+
+impl<W: Write> Write for HashWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.writer.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        self.size += written as u64;
+        Ok(written)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// SHA-256, as used for OCI digests
+struct Sha256 {
+    state: [u32; 8],
+    block: [u8; 64],
+    block_len: usize,
+    length: u64,
+}
+
+impl Sha256 {
+    #[rustfmt::skip]
+    const K: [u32; 64] = [
+        0x428a_2f98, 0x7137_4491, 0xb5c0_fbcf, 0xe9b5_dba5, 0x3956_c25b, 0x59f1_11f1, 0x923f_82a4,
+        0xab1c_5ed5, 0xd807_aa98, 0x1283_5b01, 0x2431_85be, 0x550c_7dc3, 0x72be_5d74, 0x80de_b1fe,
+        0x9bdc_06a7, 0xc19b_f174, 0xe49b_69c1, 0xefbe_4786, 0x0fc1_9dc6, 0x240c_a1cc, 0x2de9_2c6f,
+        0x4a74_84aa, 0x5cb0_a9dc, 0x76f9_88da, 0x983e_5152, 0xa831_c66d, 0xb003_27c8, 0xbf59_7fc7,
+        0xc6e0_0bf3, 0xd5a7_9147, 0x06ca_6351, 0x1429_2967, 0x27b7_0a85, 0x2e1b_2138, 0x4d2c_6dfc,
+        0x5338_0d13, 0x650a_7354, 0x766a_0abb, 0x81c2_c92e, 0x9272_2c85, 0xa2bf_e8a1, 0xa81a_664b,
+        0xc24b_8b70, 0xc76c_51a3, 0xd192_e819, 0xd699_0624, 0xf40e_3585, 0x106a_a070, 0x19a4_c116,
+        0x1e37_6c08, 0x2748_774c, 0x34b0_bcb5, 0x391c_0cb3, 0x4ed8_aa4a, 0x5b9c_ca4f, 0x682e_6ff3,
+        0x748f_82ee, 0x78a5_636f, 0x84c8_7814, 0x8cc7_0208, 0x90be_fffa, 0xa450_6ceb, 0xbef9_a3f7,
+        0xc671_78f2,
+    ];
+
+    fn new() -> Self {
+        Sha256 {
+            state: [
+                0x6a09_e667,
+                0xbb67_ae85,
+                0x3c6e_f372,
+                0xa54f_f53a,
+                0x510e_527f,
+                0x9b05_688c,
+                0x1f83_d9ab,
+                0x5be0_cd19,
+            ],
+            block: [0; 64],
+            block_len: 0,
+            length: 0,
+        }
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        self.length += data.len() as u64;
+        while !data.is_empty() {
+            let count = (64 - self.block_len).min(data.len());
+            self.block[self.block_len..self.block_len + count].copy_from_slice(&data[..count]);
+            self.block_len += count;
+            data = &data[count..];
+
+            if self.block_len == 64 {
+                let block = self.block;
+                self.compress(&block);
+                self.block_len = 0;
+            }
+        }
+    }
+
+    fn finalize(mut self) -> [u8; 32] {
+        let bit_length = self.length.wrapping_mul(8);
+
+        let mut padding = vec![0x80];
+        let padded_len = (self.block_len + 1 + 8).div_ceil(64) * 64;
+        padding.resize(padded_len - self.block_len - 8, 0);
+        padding.extend_from_slice(&bit_length.to_be_bytes());
+        self.update(&padding);
+
+        let mut hash = [0; 32];
+        for (chunk, word) in hash.chunks_mut(4).zip(self.state.iter()) {
+            chunk.copy_from_slice(&word.to_be_bytes());
+        }
+        hash
+    }
+
+    fn compress(&mut self, block: &[u8; 64]) {
+        let mut w = [0u32; 64];
+        for (i, chunk) in block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+
+        let mut v = self.state;
+        for (&k, &word) in Self::K.iter().zip(w.iter()) {
+            let s1 = v[4].rotate_right(6) ^ v[4].rotate_right(11) ^ v[4].rotate_right(25);
+            let ch = (v[4] & v[5]) ^ (!v[4] & v[6]);
+            let t1 = v[7]
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(k)
+                .wrapping_add(word);
+            let s0 = v[0].rotate_right(2) ^ v[0].rotate_right(13) ^ v[0].rotate_right(22);
+            let maj = (v[0] & v[1]) ^ (v[0] & v[2]) ^ (v[1] & v[2]);
+            let t2 = s0.wrapping_add(maj);
+
+            v = [
+                t1.wrapping_add(t2),
+                v[0],
+                v[1],
+                v[2],
+                v[3].wrapping_add(t1),
+                v[4],
+                v[5],
+                v[6],
+            ];
+        }
+
+        for (state, value) in self.state.iter_mut().zip(v.iter()) {
+            *state = state.wrapping_add(*value);
+        }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_sha256 {
+    use super::*;
+
+    fn sha256(data: &[u8]) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(data);
+        digest_string(&hasher.finalize())
+    }
+
+    #[test]
+    fn known_digests() {
+        assert_eq!(
+            sha256(b""),
+            "sha256:e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            sha256(b"abc"),
+            "sha256:ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            sha256(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"),
+            "sha256:248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+    }
+
+    #[test]
+    fn split_updates() {
+        let data = vec![0x5a; 1000];
+        let mut hasher = Sha256::new();
+        for chunk in data.chunks(7) {
+            hasher.update(chunk);
+        }
+        assert_eq!(digest_string(&hasher.finalize()), sha256(&data));
+    }
+}
+
+#[cfg(test)]
+mod test_export {
+    use super::*;
+
+    #[test]
+    fn invalid_src() {
+        const TEST_LAYOUT: &str = "test_oci_export_invalid_src";
+
+        assert!(export("/?", TEST_LAYOUT, None).is_err());
+        assert!(!Path::new(TEST_LAYOUT)
+            .join("blobs/sha256/.lms-layer.tmp")
+            .exists());
+
+        fs::remove_dir_all(TEST_LAYOUT).unwrap();
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn reproducible() {
+        use std::process::Command;
+
+        const TEST_DIR: &str = "test_oci_export_reproducible";
+        const TEST_LAYOUT: &str = "test_oci_export_reproducible_out";
+
+        fs::create_dir_all([TEST_DIR, "etc"].join("/")).unwrap();
+        fs::write([TEST_DIR, "etc/motd"].join("/"), b"hello").unwrap();
+
+        let digest = export(TEST_DIR, TEST_LAYOUT, Some("latest")).unwrap();
+
+        // Touching the tree does not change the image
+        Command::new("touch")
+            .args([[TEST_DIR, "etc/motd"].join("/")])
+            .output()
+            .unwrap();
+        assert_eq!(
+            export(TEST_DIR, TEST_LAYOUT, Some("latest")).unwrap(),
+            digest
+        );
+
+        let index = fs::read_to_string([TEST_LAYOUT, "index.json"].join("/")).unwrap();
+        assert!(index.contains(&digest));
+        assert!(index.contains("\"org.opencontainers.image.ref.name\":\"latest\""));
+
+        // Every blob is stored under its own digest
+        let blobs = [TEST_LAYOUT, "blobs/sha256"].join("/");
+        let mut count = 0;
+        for blob in fs::read_dir(&blobs).unwrap() {
+            let blob = blob.unwrap();
+            let mut hasher = Sha256::new();
+            hasher.update(&fs::read(blob.path()).unwrap());
+            assert_eq!(
+                digest_string(&hasher.finalize())[7..],
+                blob.file_name().to_string_lossy()
+            );
+            count += 1;
+        }
+        assert_eq!(count, 3);
+
+        // The manifest points to a layer that extracts to the tree
+        let manifest = fs::read_to_string([&blobs, &digest[7..]].join("/")).unwrap();
+        let layer = manifest
+            .split(LAYER_MEDIA_TYPE)
+            .nth(1)
+            .and_then(|rest| rest.split("sha256:").nth(1))
+            .map(|rest| &rest[..64])
+            .unwrap();
+        let list = Command::new("tar")
+            .args(["-tf", &[&blobs, layer].join("/")])
+            .output()
+            .unwrap();
+        assert_eq!(String::from_utf8_lossy(&list.stdout), "etc/\netc/motd\n");
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_dir_all(TEST_LAYOUT).unwrap();
+    }
+}
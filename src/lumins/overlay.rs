@@ -0,0 +1,280 @@
+//! Merges several source roots into one destination, where later roots take precedence
+
+use std::io;
+use std::path::Path;
+
+use hashbrown::{HashMap, HashSet};
+use log::error;
+use rayon::prelude::*;
+
+use crate::lumins::core;
+use crate::lumins::file_ops::{self, Dir, File, FileOps, FileSets, Symlink};
+use crate::lumins::parse::Options;
+use crate::progress;
+
+/// Enum to represent the type of the entry that a root has at a path
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+enum Kind {
+    File,
+    Dir,
+    Symlink,
+}
+
+/// Merges the file sets of `roots`, so that each path is taken from the last root that has it
+///
+/// An entry that a later root replaces with a file or symlink hides everything under it, while
+/// directories are merged with the directories of earlier roots.
+///
+/// # Returns
+/// The file sets of the entries that each root contributes, in the same order as `roots`
+fn merge(roots: &[FileSets]) -> Vec<FileSets> {
+    let mut winners: HashMap<&Path, (usize, Kind)> = HashMap::new();
+    for (i, file_sets) in roots.iter().enumerate() {
+        let entries = file_sets
+            .files()
+            .iter()
+            .map(|file| (file.path(), Kind::File))
+            .chain(file_sets.dirs().iter().map(|dir| (dir.path(), Kind::Dir)))
+            .chain(
+                file_sets
+                    .symlinks()
+                    .iter()
+                    .map(|symlink| (symlink.path(), Kind::Symlink)),
+            );
+        for (path, kind) in entries {
+            winners.insert(path.as_path(), (i, kind));
+        }
+    }
+
+    // Entries are only visible if all of their parents are directories in the merged tree
+    let visible = |path: &Path, i: usize| {
+        winners.get(path).map(|(winner, _)| *winner) == Some(i)
+            && path
+                .ancestors()
+                .skip(1)
+                .all(|ancestor| match winners.get(ancestor) {
+                    Some((_, kind)) => *kind == Kind::Dir,
+                    None => true,
+                })
+    };
+
+    roots
+        .par_iter()
+        .enumerate()
+        .map(|(i, file_sets)| {
+            let files: HashSet<File> = file_sets
+                .files()
+                .iter()
+                .filter(|file| visible(file.path(), i))
+                .cloned()
+                .collect();
+            let dirs: HashSet<Dir> = file_sets
+                .dirs()
+                .iter()
+                .filter(|dir| visible(dir.path(), i))
+                .cloned()
+                .collect();
+            let symlinks: HashSet<Symlink> = file_sets
+                .symlinks()
+                .iter()
+                .filter(|symlink| visible(symlink.path(), i))
+                .cloned()
+                .collect();
+            FileSets::with(files, dirs, symlinks)
+        })
+        .collect()
+}
+
+/// Synchronizes `dest` with the merge of `srcs`, where later roots take precedence
+///
+/// Each path in `dest` is taken from the last of `srcs` that has it. A file or symlink in a later
+/// root replaces a directory of an earlier root, along with everything in it, while directories
+/// that are in several roots are merged. Entries of `dest` that are in none of `srcs` are
+/// deleted, unless `Options::delete` is false.
+///
+/// # Arguments
+/// * `srcs`: Source roots, from lowest to highest precedence
+/// * `dest`: Destination directory
+/// * `options`: Options of the run
+///
+/// # Errors
+/// This function will return an error in the following situations,
+/// but is not limited to just these cases:
+/// * One of `srcs` is an invalid directory
+/// * `dest` is an invalid directory
+pub fn overlay(srcs: &[&str], dest: &str, options: &Options) -> Result<(), io::Error> {
+    let options = &core::for_run(options);
+    let roots = srcs
+        .par_iter()
+        .map(|src| file_ops::get_all_files(src))
+        .collect::<Result<Vec<FileSets>, io::Error>>()?;
+    let layers = merge(&roots);
+
+    let dest_file_sets = file_ops::get_all_files(dest)?;
+    let dest_files = dest_file_sets.files();
+    let dest_dirs = dest_file_sets.dirs();
+    let dest_symlinks = dest_file_sets.symlinks();
+
+    // Every entry of the merged tree is in exactly one layer
+    let merged_files: HashSet<&File> = layers.iter().flat_map(|layer| layer.files()).collect();
+    let merged_dirs: HashSet<&Dir> = layers.iter().flat_map(|layer| layer.dirs()).collect();
+    let merged_symlinks: HashSet<&Symlink> =
+        layers.iter().flat_map(|layer| layer.symlinks()).collect();
+
+    progress::progress_init(
+        (merged_files.len()
+            + merged_dirs.len()
+            + merged_symlinks.len()
+            + dest_files.len()
+            + dest_dirs.len()
+            + dest_symlinks.len()) as u64,
+    );
+
+    // What could not be listed in a root is not missing from it
+    let complete = roots.iter().all(FileSets::is_complete);
+    let delete = options.delete && complete;
+    if options.delete && !complete {
+        error!(
+            "Error -- Sources could not be listed completely, so nothing is deleted from {}",
+            dest
+        );
+    }
+
+    if delete {
+        let symlinks_to_delete = dest_symlinks
+            .par_iter()
+            .filter(|symlink| !merged_symlinks.contains(symlink));
+        file_ops::delete_files(symlinks_to_delete, dest);
+    }
+
+    for (layer, src) in layers.iter().zip(srcs) {
+        let dirs_to_copy = layer.dirs().par_difference(dest_dirs);
+        file_ops::create_dirs(dirs_to_copy, src, dest, options);
+    }
+
+    if delete {
+        let files_to_delete = dest_files
+            .par_iter()
+            .filter(|file| !merged_files.contains(file));
+        file_ops::delete_files(files_to_delete, dest);
+    }
+
+    for (layer, src) in layers.iter().zip(srcs) {
+        let symlinks_to_copy = layer.symlinks().par_difference(dest_symlinks);
+        let files_to_copy = layer.files().par_difference(dest_files);
+        let files_to_compare = layer.files().par_intersection(dest_files);
+
+        file_ops::copy_files(symlinks_to_copy, src, dest, options);
+        file_ops::copy_files(files_to_copy, src, dest, options);
+        file_ops::compare_and_copy_files(files_to_compare, src, dest, options);
+        // Files that were still being written are copied before the layers above them are
+        file_ops::copy_deferred(options);
+
+        if options.copies_metadata() {
+            let dirs_to_update = layer.dirs().par_intersection(dest_dirs);
+            file_ops::copy_files_metadata(dirs_to_update, src, dest, options);
+        }
+    }
+
+    // Delete dirs in the correct order
+    if delete {
+        let dirs_to_delete = dest_dirs
+            .par_iter()
+            .filter(|dir| !merged_dirs.contains(dir));
+        let dirs_to_delete: Vec<&Dir> = file_ops::sort_files(dirs_to_delete);
+        file_ops::delete_files_sequential(dirs_to_delete, dest);
+    }
+
+    progress::check_stopped()
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_overlay {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn merge_precedence() {
+        let root = |files: &[&str], dirs: &[&str], symlinks: &[&str]| {
+            FileSets::with(
+                files.iter().map(|file| File::from(file, 1)).collect(),
+                dirs.iter().map(|dir| Dir::from(dir)).collect(),
+                symlinks
+                    .iter()
+                    .map(|symlink| Symlink::from(symlink, "target"))
+                    .collect(),
+            )
+        };
+        let roots = [
+            root(&["a", "d/x", "f/y"], &["d", "f"], &["l"]),
+            root(&["a", "f"], &[], &[]),
+            root(&["d/z"], &["d", "l"], &[]),
+        ];
+
+        let layers = merge(&roots);
+        let paths = |layer: &FileSets| {
+            let mut paths: Vec<String> = layer
+                .files()
+                .iter()
+                .map(|file| file.path().to_string_lossy().to_string())
+                .chain(
+                    layer
+                        .dirs()
+                        .iter()
+                        .map(|dir| format!("{}/", dir.path().to_string_lossy())),
+                )
+                .chain(
+                    layer
+                        .symlinks()
+                        .iter()
+                        .map(|symlink| format!("{}@", symlink.path().to_string_lossy())),
+                )
+                .collect();
+            paths.sort();
+            paths
+        };
+        assert_eq!(paths(&layers[0]), vec!["d/x"]);
+        assert_eq!(paths(&layers[1]), vec!["a", "f"]);
+        assert_eq!(paths(&layers[2]), vec!["d/", "d/z", "l/"]);
+    }
+
+    #[test]
+    fn invalid_roots() {
+        assert!(overlay(&["src", "/?"], "src", &Options::default()).is_err());
+    }
+
+    #[test]
+    fn overlay_roots() {
+        const TEST_DIR: &str = "test_overlay_roots";
+        let path = |path: &str| [TEST_DIR, path].join("/");
+
+        for dir in &["base/d", "base/f", "env", "host/d", "dest/stale"] {
+            fs::create_dir_all(path(dir)).unwrap();
+        }
+        fs::write(path("base/a"), b"base").unwrap();
+        fs::write(path("base/d/x"), b"base").unwrap();
+        fs::write(path("base/f/y"), b"base").unwrap();
+        fs::write(path("env/a"), b"env").unwrap();
+        fs::write(path("env/f"), b"env").unwrap();
+        fs::write(path("host/d/z"), b"host").unwrap();
+        fs::write(path("dest/stale/old"), b"old").unwrap();
+
+        let roots = [path("base"), path("env"), path("host")];
+        let roots: Vec<&str> = roots.iter().map(String::as_str).collect();
+        for _ in 0..2 {
+            assert!(overlay(&roots, &path("dest"), &Options::default()).is_ok());
+
+            assert_eq!(fs::read(path("dest/a")).unwrap(), b"env");
+            assert_eq!(fs::read(path("dest/d/x")).unwrap(), b"base");
+            assert_eq!(fs::read(path("dest/d/z")).unwrap(), b"host");
+            assert_eq!(fs::read(path("dest/f")).unwrap(), b"env");
+            assert!(fs::metadata(path("dest/stale")).is_err());
+        }
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+}
@@ -2,95 +2,1507 @@
 
 use std::env;
 use std::fs;
-use std::path::PathBuf;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 
-use bitflags::bitflags;
-use clap::ArgMatches;
 use env_logger::Builder;
-use log::LevelFilter;
+use indicatif::ProgressDrawTarget;
+use log::{Level, LevelFilter};
 
-use crate::progress::PROGRESS_BAR;
+use crate::lumins::{
+    archive,
+    bisync::Prefer,
+    capabilities::Capabilities,
+    cli::{Cli, Command, LogArgs, TrailingSlash, TransferArgs},
+    compress::Codec,
+    dirfd::Reflink,
+    exit::ExitCode,
+    file_ops::{Deferred, Order},
+    filelist,
+    filter::Filter,
+    glob::Glob,
+    history,
+    history::{MaxDelete, Norms},
+    hooks::Hook,
+    idmap::IdMap,
+    image::{self, Filesystem},
+    journal::Journal,
+    limits::ShortenNames,
+    logfile::{LogFile, MAX_LOG_SIZE},
+    lookup::Lookup,
+    manifest::Format,
+    materialize::Rules,
+    metadata,
+    quarantine::Quarantine,
+    retry::Retry,
+    rewrite::Rewrite,
+    sandbox::SandboxMode,
+    sanitize::Sanitize,
+    squashfs,
+    workers::Workers,
+};
+use crate::progress::{PROGRESS_BAR, STATS};
 
-bitflags! {
-    /// Enum to represent command line flags
-    pub struct Flag: u32 {
-        const NO_DELETE     = 0x1;
-        const SECURE        = 0x2;
-        const VERBOSE       = 0x4;
-        const SEQUENTIAL    = 0x8;
+/// Checks whether `src` names the directory itself, to be copied into a subdirectory of the
+/// destination named after it, rather than its contents, with `TrailingSlash::Rsync`
+///
+/// Like with rsync, `src/` names the contents of `src`, and `src` the directory itself.
+///
+/// # Returns
+/// * Some: Whether `src` names the directory itself
+/// * None: If trailing separators do not matter, as they do not by default
+pub fn nests_source(src: &str, trailing_slash: TrailingSlash) -> Option<bool> {
+    (trailing_slash == TrailingSlash::Rsync).then(|| !src.ends_with(std::path::is_separator))
+}
+
+/// Struct to represent all options of a copy, synchronize, or remove
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// Whether a sync deletes what is not in the source, which it does unless `--nodelete`
+    pub delete: bool,
+    /// Whether files are compared with a cryptographic hash function, instead of by size and
+    /// modification time
+    pub secure: bool,
+    /// Whether files are copied one at a time, instead of in parallel, see `set_env`
+    pub sequential: bool,
+    /// Whether nothing but errors is printed, without a progress bar
+    pub quiet: bool,
+    /// Whether the changes of a sync are only printed, without being made, see `review::dry_run`
+    pub dry_run: bool,
+    /// Whether a dry run prints only the paths of the changes, each followed by a NUL
+    pub print0: bool,
+    /// Whether a sync goes on synchronizing the changes to the source, see `watch`
+    pub watch: bool,
+    /// Whether extended attributes are copied
+    pub xattrs: bool,
+    /// Whether ACLs are copied
+    pub acls: bool,
+    /// Whether sockets, FIFOs, and device nodes are created
+    pub specials: bool,
+    /// Whether archive destinations are written reproducibly, without owners or timestamps
+    pub deterministic: bool,
+    /// Whether destination files are updated directly, writing only the blocks that changed
+    pub inplace: bool,
+    /// Whether only what files grew by is appended to their copies, if their copies are still the
+    /// start of them
+    pub append_verify: bool,
+    /// Whether large files are copied without going through the page cache
+    pub direct_io: bool,
+    /// Whether every file is flushed to disk before it is counted as copied
+    pub fsync: bool,
+    /// Whether files that changed while they were copied are skipped, instead of copied again at
+    /// the end of the run, see `file_ops::copy_deferred`
+    pub skip_growing: bool,
+    /// Whether a run is checked to fit in the destination before it starts, see `space::check`
+    pub check_space: bool,
+    /// Whether identical files in the destination are hard linked to one copy, see `dedup`
+    pub dedup: bool,
+    /// Whether a move across filesystems compares the hashes of the copies before it removes the
+    /// files, instead of only their sizes
+    pub verify: bool,
+    /// Whether the source is copied into a subdirectory of the destination named after it
+    pub into: bool,
+    /// How a trailing separator on the source is read, see `nests_source`
+    pub trailing_slash: TrailingSlash,
+    /// Whether absolute symlinks inside the source are made relative, see `rewrite`
+    pub relativize_links: bool,
+    /// Whether replaced and deleted files are kept until the run completes, see `quarantine`
+    pub quarantine_files: bool,
+    /// Whether files that exist in the destination are never overwritten
+    pub ignore_existing: bool,
+    /// Whether only files that are older than their sources are overwritten
+    pub update: bool,
+    /// Whether files that are empty in the destination while their sources are not are
+    /// overwritten, even with `update` or `ignore_existing`
+    pub heal_empty_files: bool,
+    /// Whether listing stays on the filesystems of the source and destination
+    pub one_file_system: bool,
+    /// Whether symlinks to directories in the destination are written through where the source
+    /// has directories, see `dirlinks`
+    pub keep_dirlinks: bool,
+    /// Whether symlinks to directories in the source are copied as directories, see `dirlinks`
+    pub copy_dirlinks: bool,
+    /// Whether the process is restricted to reading the source and writing the destination, see
+    /// `harden`
+    pub harden: bool,
+    /// Whether the source is read in a process of its own, see `privsep`
+    pub privsep: bool,
+    /// Whether entries whose paths are too long for the destination are skipped, see `limits`
+    pub skip_long_paths: bool,
+    /// Whether every error is reported again at the end of the run
+    pub keep_going: bool,
+    /// Whether a snapshot of the destination is taken before it is modified, see `snapshot`
+    pub dest_snapshot: bool,
+    /// Whether completed files are journaled in the destination, so that an interrupted run can be
+    /// resumed, see `journal`
+    pub resume: bool,
+    /// Whether a sync that would delete far more than earlier runs did is refused, see
+    /// `history::check_plan`
+    pub refuse_anomalies: bool,
+    /// Whether runs that `refuse_anomalies` would refuse go on
+    pub force: bool,
+    /// Whether trees are synchronized one directory at a time, so that memory stays low however
+    /// large they are
+    pub low_memory: bool,
+    /// Whether the hashes of files in the destination are cached in their extended attributes, see
+    /// `hashcache`
+    pub xattr_hash_cache: bool,
+    /// Whether directories left empty by a sync are deleted
+    pub prune_empty_dirs: bool,
+    /// Whether a sync from a source without files, while the destination has some, is refused
+    pub safe_delete: bool,
+    /// Whether a sync from a source without files goes on anyway with `safe_delete`
+    pub force_empty_source: bool,
+    /// Whether paths that only differ in case are the same entry, see `casefold`
+    pub ignore_case: bool,
+    /// Whether `verify` keeps verifying until a pass finds a divergence, see `standby::verify`
+    pub continuous: bool,
+    /// Whether `dupes` replaces the copies of each set with hard links to one of them
+    pub link_dupes: bool,
+    /// Whether `dupes` deletes the extra copies of each set
+    pub delete_dupes: bool,
+    /// Whether `dupes` keeps the first copy of each set, in path order, when deleting
+    pub keep_first: bool,
+    /// When files are cloned instead of copied
+    pub reflink: Reflink,
+    /// When what is written to the destination is sandboxed, see `sandbox::is_sandboxed`
+    pub sandbox: SandboxMode,
+    /// What is done with entries whose names are too long for the destination, which are
+    /// otherwise written as they are, see `limits::check`
+    pub shorten_names: Option<ShortenNames>,
+    /// How names are sanitized, if they are, see `sanitize::renames`
+    pub sanitize_names: Option<Sanitize>,
+    /// Side whose changes win the conflicts of a bisync, which are otherwise left unresolved
+    pub prefer: Option<Prefer>,
+    /// Filesystem of the images that are written, see `image::write_image`
+    pub filesystem: Filesystem,
+    /// How the blocks of SquashFS images are compressed, see `squashfs`
+    pub compression: Codec,
+    /// User and group ID mappings to apply to the ownership of copied files
+    pub idmap: Option<IdMap>,
+    /// Journal to record completed files in, so that an interrupted run can be resumed
+    pub journal: Option<Arc<Journal>>,
+    /// Quarantine to move replaced and deleted files into, instead of removing them
+    pub quarantine: Option<Arc<Quarantine>>,
+    /// Threads that copy files for the run, see `workers::copy_workers`
+    pub workers: Option<Arc<Workers>>,
+    /// Files that the run deferred, since they were still being written, see
+    /// `file_ops::copy_deferred`
+    pub deferred: Arc<Deferred>,
+    /// Directories of the destination that the run has read, to find missing entries in without
+    /// looking each of them up, see `lookup`
+    pub lookup: Option<Arc<Lookup>>,
+    /// What earlier runs of the profile did, to check the changes planned by a run against
+    pub norms: Option<Norms>,
+    /// Rules for which files to replace by placeholders instead of copying
+    pub materialize: Option<Rules>,
+    /// Directories to write one at a time, in path order, see `fence::Phases`
+    pub fences: Vec<Glob>,
+    /// File name of the markers to write into directories once they are complete, see `marker`
+    pub done_marker: Option<String>,
+    /// Substitutions to apply to the targets of absolute symlinks, in order
+    pub link_rewrites: Vec<Rewrite>,
+    /// Number of errors after which a run stops, instead of going on
+    pub max_errors: Option<u64>,
+    /// Number of files in the destination a run may delete before it is refused, see
+    /// `history::MaxDelete`
+    pub max_delete: Option<MaxDelete>,
+    /// Deepest level of the source and destination to list, see `file_ops::get_files_within`
+    pub max_depth: Option<usize>,
+    /// Paths inside the source to copy, instead of everything in it, see `filelist`
+    pub files_from: Option<Vec<PathBuf>>,
+    /// Ordered rules for which entries of the source and destination a run sees, see `filter`
+    pub filter: Option<Filter>,
+    /// Order to copy files in, instead of interleaving small and large files, see
+    /// `file_ops::for_each_in_order`
+    pub order: Option<Order>,
+    /// How `diff` prints differences, see `manifest::Format`
+    pub format: Option<Format>,
+    /// Size of the buffer to copy files through, instead of one sized to each file
+    pub buffer_size: Option<usize>,
+    /// Number of threads to list, compare, and copy files with, instead of one per CPU, see
+    /// `set_env`
+    pub threads: Option<usize>,
+    /// How long to wait between the passes of `verify --continuous`, see `standby::verify`
+    pub interval: Option<Duration>,
+    /// URL to post an alert to when `verify --continuous` finds a divergence
+    pub webhook: Option<String>,
+    /// Stream to write the changes that `sync --watch` sees to, see `watch::Audit`
+    pub audit: Option<PathBuf>,
+    /// How long `sync --watch` waits for changes to stop before synchronizing them, see
+    /// `watch::DEFAULT_DEBOUNCE`
+    pub debounce: Option<Duration>,
+    /// Most changes that `sync --watch` synchronizes at once
+    pub max_batch: Option<usize>,
+    /// Number of transformed entries to show before asking whether to go on, see `preview`
+    pub preview: Option<usize>,
+    /// File to write the fidelity report of the run to, see `fidelity::write_report`
+    pub fidelity_report: Option<PathBuf>,
+    /// Command to run before each file is copied, which skips the file if it fails
+    pub hook_pre: Option<Hook>,
+    /// Command to run after each file is copied
+    pub hook_post: Option<Hook>,
+    /// How often, and after how long, copies that fail with transient errors are retried
+    pub retry: Retry,
+    /// File to log everything to, whether or not it is printed
+    pub log_file: Option<Arc<LogFile>>,
+    /// What the filesystem of the destination supports, once a run has probed it, see
+    /// `capabilities::probe`
+    pub capabilities: Option<Capabilities>,
+    /// Number of times `-v` was given, see `set_env`
+    pub verbosity: u64,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            delete: true,
+            secure: false,
+            sequential: false,
+            quiet: false,
+            dry_run: false,
+            print0: false,
+            watch: false,
+            xattrs: false,
+            acls: false,
+            specials: false,
+            deterministic: false,
+            inplace: false,
+            append_verify: false,
+            direct_io: false,
+            fsync: false,
+            skip_growing: false,
+            check_space: true,
+            dedup: false,
+            verify: true,
+            into: false,
+            trailing_slash: TrailingSlash::Strict,
+            relativize_links: false,
+            quarantine_files: false,
+            ignore_existing: false,
+            update: false,
+            heal_empty_files: false,
+            one_file_system: false,
+            keep_dirlinks: false,
+            copy_dirlinks: false,
+            harden: false,
+            privsep: false,
+            skip_long_paths: false,
+            keep_going: false,
+            dest_snapshot: false,
+            resume: false,
+            refuse_anomalies: false,
+            force: false,
+            low_memory: false,
+            xattr_hash_cache: false,
+            prune_empty_dirs: false,
+            safe_delete: false,
+            force_empty_source: false,
+            ignore_case: false,
+            continuous: false,
+            link_dupes: false,
+            delete_dupes: false,
+            keep_first: false,
+            reflink: Reflink::Auto,
+            sandbox: SandboxMode::Auto,
+            shorten_names: None,
+            sanitize_names: None,
+            prefer: None,
+            filesystem: Filesystem::Iso9660,
+            compression: Codec::Zstd,
+            idmap: None,
+            journal: None,
+            quarantine: None,
+            workers: None,
+            deferred: Arc::default(),
+            lookup: None,
+            norms: None,
+            materialize: None,
+            fences: Vec::new(),
+            done_marker: None,
+            link_rewrites: Vec::new(),
+            max_errors: None,
+            max_delete: None,
+            max_depth: None,
+            files_from: None,
+            filter: None,
+            order: None,
+            format: None,
+            buffer_size: None,
+            threads: None,
+            interval: None,
+            webhook: None,
+            audit: None,
+            debounce: None,
+            max_batch: None,
+            preview: None,
+            fidelity_report: None,
+            hook_pre: None,
+            hook_post: None,
+            retry: Retry::default(),
+            log_file: None,
+            capabilities: None,
+            verbosity: 0,
+        }
+    }
+}
+
+impl Options {
+    /// Checks whether any metadata besides contents and permissions is copied
+    pub fn copies_metadata(&self) -> bool {
+        self.xattrs || self.acls || self.idmap.is_some()
+    }
+
+    /// Checks whether changes are logged, which they are once `-v` is given
+    pub fn verbose(&self) -> bool {
+        self.verbosity > 0
+    }
+
+    /// Starts building options for a run, where everything is as without any command line
+    /// options
+    pub fn builder() -> OptionsBuilder {
+        OptionsBuilder::default()
+    }
+}
+
+/// Struct to build the options of a run one option at a time, for using lms as a library
+///
+/// Each option has its own method, and options are checked once they are built, like the command
+/// line checks them, see `OptionsBuilder::build`.
+///
+/// ```
+/// use lms::parse::Options;
+///
+/// let options = Options::builder()
+///     .delete(false)
+///     .secure(true)
+///     .max_errors(10)
+///     .threads(4)
+///     .build()
+///     .unwrap();
+/// assert!(!options.delete && options.secure);
+/// assert!(Options::builder().buffer_size(0).build().is_err());
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct OptionsBuilder {
+    options: Options,
+}
+
+impl OptionsBuilder {
+    /// Whether a sync deletes what is not in the source, which it does by default
+    pub fn delete(mut self, delete: bool) -> Self {
+        self.options.delete = delete;
+        self
+    }
+
+    /// Whether files are compared with a cryptographic hash function
+    pub fn secure(mut self, secure: bool) -> Self {
+        self.options.secure = secure;
+        self
+    }
+
+    /// Whether files are copied one at a time, instead of in parallel
+    pub fn sequential(mut self, sequential: bool) -> Self {
+        self.options.sequential = sequential;
+        self
+    }
+
+    /// Whether changes are logged
+    pub fn verbose(mut self, verbose: bool) -> Self {
+        self.options.verbosity = match verbose {
+            true => self.options.verbosity.max(1),
+            false => 0,
+        };
+        self
+    }
+
+    /// Whether nothing but errors is printed
+    pub fn quiet(mut self, quiet: bool) -> Self {
+        self.options.quiet = quiet;
+        self
+    }
+
+    /// Whether the changes of a sync are only printed, without being made
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.options.dry_run = dry_run;
+        self
+    }
+
+    /// Whether a dry run prints only the paths of the changes, each followed by a NUL
+    pub fn print0(mut self, print0: bool) -> Self {
+        self.options.print0 = print0;
+        self
+    }
+
+    /// Whether a sync goes on synchronizing the changes to the source
+    pub fn watch(mut self, watch: bool) -> Self {
+        self.options.watch = watch;
+        self
+    }
+
+    /// Whether extended attributes are copied
+    pub fn xattrs(mut self, xattrs: bool) -> Self {
+        self.options.xattrs = xattrs;
+        self
+    }
+
+    /// Whether ACLs are copied
+    pub fn acls(mut self, acls: bool) -> Self {
+        self.options.acls = acls;
+        self
+    }
+
+    /// Whether sockets, FIFOs, and device nodes are created
+    pub fn specials(mut self, specials: bool) -> Self {
+        self.options.specials = specials;
+        self
+    }
+
+    /// Whether archive destinations are written reproducibly
+    pub fn deterministic(mut self, deterministic: bool) -> Self {
+        self.options.deterministic = deterministic;
+        self
+    }
+
+    /// Whether destination files are updated directly, writing only the blocks that changed
+    pub fn inplace(mut self, inplace: bool) -> Self {
+        self.options.inplace = inplace;
+        self
+    }
+
+    /// Whether only what files grew by is appended to their copies
+    pub fn append_verify(mut self, append_verify: bool) -> Self {
+        self.options.append_verify = append_verify;
+        self
+    }
+
+    /// Whether large files are copied without going through the page cache
+    pub fn direct_io(mut self, direct_io: bool) -> Self {
+        self.options.direct_io = direct_io;
+        self
+    }
+
+    /// Whether every file is flushed to disk before it is counted as copied
+    pub fn fsync(mut self, fsync: bool) -> Self {
+        self.options.fsync = fsync;
+        self
+    }
+
+    /// Whether files that changed while they were copied are skipped
+    pub fn skip_growing(mut self, skip_growing: bool) -> Self {
+        self.options.skip_growing = skip_growing;
+        self
+    }
+
+    /// Whether a run is checked to fit in the destination before it starts, which it is by
+    /// default
+    pub fn check_space(mut self, check_space: bool) -> Self {
+        self.options.check_space = check_space;
+        self
+    }
+
+    /// Whether identical files in the destination are hard linked to one copy
+    pub fn dedup(mut self, dedup: bool) -> Self {
+        self.options.dedup = dedup;
+        self
+    }
+
+    /// Whether a move across filesystems compares the hashes of the copies, which it does by
+    /// default
+    pub fn verify(mut self, verify: bool) -> Self {
+        self.options.verify = verify;
+        self
+    }
+
+    /// Whether the source is copied into a subdirectory of the destination named after it
+    pub fn into(mut self, into: bool) -> Self {
+        self.options.into = into;
+        self
+    }
+
+    /// How a trailing separator on the source is read
+    pub fn trailing_slash(mut self, trailing_slash: TrailingSlash) -> Self {
+        self.options.trailing_slash = trailing_slash;
+        self
+    }
+
+    /// Whether absolute symlinks inside the source are made relative
+    pub fn relativize_links(mut self, relativize_links: bool) -> Self {
+        self.options.relativize_links = relativize_links;
+        self
+    }
+
+    /// Whether replaced and deleted files are kept until the run completes
+    pub fn quarantine_files(mut self, quarantine_files: bool) -> Self {
+        self.options.quarantine_files = quarantine_files;
+        self
+    }
+
+    /// Whether what an interrupted run already copied is skipped
+    pub fn resume(mut self, resume: bool) -> Self {
+        self.options.resume = resume;
+        self
+    }
+
+    /// Whether files that exist in the destination are never overwritten
+    pub fn ignore_existing(mut self, ignore_existing: bool) -> Self {
+        self.options.ignore_existing = ignore_existing;
+        self
+    }
+
+    /// Whether only files that are older than their sources are overwritten
+    pub fn update(mut self, update: bool) -> Self {
+        self.options.update = update;
+        self
+    }
+
+    /// Whether empty files in the destination are overwritten by sources that are not empty
+    pub fn heal_empty_files(mut self, heal_empty_files: bool) -> Self {
+        self.options.heal_empty_files = heal_empty_files;
+        self
+    }
+
+    /// Whether listing stays on the filesystems of the source and destination
+    pub fn one_file_system(mut self, one_file_system: bool) -> Self {
+        self.options.one_file_system = one_file_system;
+        self
+    }
+
+    /// Whether symlinks to directories in the destination are written through
+    pub fn keep_dirlinks(mut self, keep_dirlinks: bool) -> Self {
+        self.options.keep_dirlinks = keep_dirlinks;
+        self
+    }
+
+    /// Whether symlinks to directories in the source are copied as directories
+    pub fn copy_dirlinks(mut self, copy_dirlinks: bool) -> Self {
+        self.options.copy_dirlinks = copy_dirlinks;
+        self
+    }
+
+    /// Whether the process is restricted to reading the source and writing the destination
+    pub fn harden(mut self, harden: bool) -> Self {
+        self.options.harden = harden;
+        self
+    }
+
+    /// Whether the source is read in a process of its own
+    pub fn privsep(mut self, privsep: bool) -> Self {
+        self.options.privsep = privsep;
+        self
+    }
+
+    /// Whether entries whose paths are too long for the destination are skipped
+    pub fn skip_long_paths(mut self, skip_long_paths: bool) -> Self {
+        self.options.skip_long_paths = skip_long_paths;
+        self
+    }
+
+    /// Whether every error is reported again at the end of the run
+    pub fn keep_going(mut self, keep_going: bool) -> Self {
+        self.options.keep_going = keep_going;
+        self
+    }
+
+    /// Whether a snapshot of the destination is taken before it is modified
+    pub fn dest_snapshot(mut self, dest_snapshot: bool) -> Self {
+        self.options.dest_snapshot = dest_snapshot;
+        self
+    }
+
+    /// Whether a sync that would delete far more than earlier runs did is refused
+    pub fn refuse_anomalies(mut self, refuse_anomalies: bool) -> Self {
+        self.options.refuse_anomalies = refuse_anomalies;
+        self
+    }
+
+    /// Whether runs that `refuse_anomalies` would refuse go on
+    pub fn force(mut self, force: bool) -> Self {
+        self.options.force = force;
+        self
+    }
+
+    /// Whether trees are synchronized one directory at a time
+    pub fn low_memory(mut self, low_memory: bool) -> Self {
+        self.options.low_memory = low_memory;
+        self
+    }
+
+    /// Whether the hashes of files in the destination are cached in their extended attributes
+    pub fn xattr_hash_cache(mut self, xattr_hash_cache: bool) -> Self {
+        self.options.xattr_hash_cache = xattr_hash_cache;
+        self
+    }
+
+    /// Whether a sync from a source without files, while the destination has some, is refused
+    pub fn safe_delete(mut self, safe_delete: bool) -> Self {
+        self.options.safe_delete = safe_delete;
+        self
+    }
+
+    /// Whether a sync from a source without files goes on anyway with `safe_delete`
+    pub fn force_empty_source(mut self, force_empty_source: bool) -> Self {
+        self.options.force_empty_source = force_empty_source;
+        self
+    }
+
+    /// Whether directories left empty by a sync are deleted
+    pub fn prune_empty_dirs(mut self, prune_empty_dirs: bool) -> Self {
+        self.options.prune_empty_dirs = prune_empty_dirs;
+        self
+    }
+
+    /// Whether paths that only differ in case are the same entry, as in case-insensitive
+    /// destinations
+    pub fn ignore_case(mut self, ignore_case: bool) -> Self {
+        self.options.ignore_case = ignore_case;
+        self
+    }
+
+    /// Whether `verify` keeps verifying until a pass finds a divergence
+    pub fn continuous(mut self, continuous: bool) -> Self {
+        self.options.continuous = continuous;
+        self
+    }
+
+    /// Whether `dupes` replaces the copies of each set with hard links to one of them
+    pub fn link_dupes(mut self, link_dupes: bool) -> Self {
+        self.options.link_dupes = link_dupes;
+        self
+    }
+
+    /// Whether `dupes` deletes the extra copies of each set
+    pub fn delete_dupes(mut self, delete_dupes: bool) -> Self {
+        self.options.delete_dupes = delete_dupes;
+        self
+    }
+
+    /// Whether `dupes` keeps the first copy of each set, in path order, when deleting
+    pub fn keep_first(mut self, keep_first: bool) -> Self {
+        self.options.keep_first = keep_first;
+        self
+    }
+
+    /// Number of errors after which a run stops, instead of going on
+    pub fn max_errors(mut self, max_errors: u64) -> Self {
+        self.options.max_errors = Some(max_errors);
+        self
+    }
+
+    /// Number of files in the destination a sync may delete before it is refused
+    pub fn max_delete(mut self, max_delete: MaxDelete) -> Self {
+        self.options.max_delete = Some(max_delete);
+        self
+    }
+
+    /// Deepest level of the source and destination to list
+    pub fn max_depth(mut self, max_depth: usize) -> Self {
+        self.options.max_depth = Some(max_depth);
+        self
+    }
+
+    /// Paths inside the source to copy, instead of everything in it
+    pub fn files_from(mut self, files_from: Vec<PathBuf>) -> Self {
+        self.options.files_from = Some(files_from);
+        self
+    }
+
+    /// Ordered rules for which entries of the source and destination a run sees
+    pub fn filter(mut self, filter: Filter) -> Self {
+        self.options.filter = Some(filter);
+        self
+    }
+
+    /// When files are cloned instead of copied
+    pub fn reflink(mut self, reflink: Reflink) -> Self {
+        self.options.reflink = reflink;
+        self
+    }
+
+    /// When what is written to the destination is sandboxed
+    pub fn sandbox(mut self, sandbox: SandboxMode) -> Self {
+        self.options.sandbox = sandbox;
+        self
+    }
+
+    /// What is done with entries whose names are too long for the destination
+    pub fn shorten_names(mut self, shorten_names: ShortenNames) -> Self {
+        self.options.shorten_names = Some(shorten_names);
+        self
+    }
+
+    /// How names are sanitized
+    pub fn sanitize_names(mut self, sanitize_names: Sanitize) -> Self {
+        self.options.sanitize_names = Some(sanitize_names);
+        self
+    }
+
+    /// Side whose changes win the conflicts of a bisync
+    pub fn prefer(mut self, prefer: Prefer) -> Self {
+        self.options.prefer = Some(prefer);
+        self
+    }
+
+    /// Filesystem of the images that are written
+    pub fn filesystem(mut self, filesystem: Filesystem) -> Self {
+        self.options.filesystem = filesystem;
+        self
+    }
+
+    /// How the blocks of SquashFS images are compressed
+    pub fn compression(mut self, compression: Codec) -> Self {
+        self.options.compression = compression;
+        self
+    }
+
+    /// Order to copy files in
+    pub fn order(mut self, order: Order) -> Self {
+        self.options.order = Some(order);
+        self
+    }
+
+    /// Size of the buffer to copy files through, which must be at least 1 byte
+    pub fn buffer_size(mut self, buffer_size: usize) -> Self {
+        self.options.buffer_size = Some(buffer_size);
+        self
+    }
+
+    /// Number of threads to list, compare, and copy files with, which must be at least 1, and
+    /// which only applies once the options are set up with `set_env`
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.options.threads = Some(threads);
+        self
+    }
+
+    /// User and group ID mappings to apply to the ownership of copied files
+    pub fn idmap(mut self, idmap: IdMap) -> Self {
+        self.options.idmap = Some(idmap);
+        self
+    }
+
+    /// How often, and after how long, copies that fail with transient errors are retried
+    pub fn retry(mut self, retry: Retry) -> Self {
+        self.options.retry = retry;
+        self
+    }
+
+    /// Finishes building the options
+    ///
+    /// # Errors
+    /// This function will return an error in the following situations,
+    /// but is not limited to just these cases:
+    /// * The buffer size or the number of threads is 0
+    pub fn build(self) -> Result<Options, io::Error> {
+        let invalid = |message: String| {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Options Error -- {}", message),
+            ))
+        };
+        if self.options.buffer_size == Some(0) {
+            return invalid(String::from("the buffer size must be at least 1 byte"));
+        }
+        if self.options.threads == Some(0) {
+            return invalid(String::from("there must be at least 1 thread"));
+        }
+        Ok(self.options)
     }
 }
 
 /// Enum to represent subcommand type
-#[derive(Eq, PartialEq, Clone)]
+#[derive(Eq, PartialEq, Clone, Default)]
 pub enum SubCommandType {
     Copy,
+    #[default]
     Synchronize,
     Remove,
+    Daemon,
+    ExportOci,
+    ExportDiff,
+    History,
+    Manifest,
+    Diff,
+    Bisync,
+    Hydrate,
+    Snapshot,
+    Overlay,
+    Move,
+    Hash,
+    Stats,
+    Dupes,
+    Verify,
+    Tui,
+    PrivsepReader,
 }
 
 /// Struct to represent subcommands
+#[derive(Default)]
 pub struct SubCommand<'a> {
     pub src: Option<&'a str>,
+    /// Source roots of an overlay, from lowest to highest precedence
+    pub sources: Vec<&'a str>,
     pub dest: Vec<String>,
+    pub config: Option<&'a str>,
+    pub tag: Option<&'a str>,
+    /// Profile to record the run under, or to show the history of
+    pub profile: Option<&'a str>,
+    /// History file to show runs from
+    pub history: Option<&'a str>,
+    /// Number of runs to show per profile
+    pub runs: usize,
+    /// Number of the largest files to show with stats
+    pub top: usize,
     pub sub_command_type: SubCommandType,
 }
 
 /// Struct to represent the result of parsing args
 pub struct ParseResult<'a> {
     pub sub_command: SubCommand<'a>,
-    pub flags: Flag,
+    pub options: Options,
 }
 
-/// Parses command line arguments for source and destination folders and
-/// creates the destination folder if it does not exist
+/// Struct to represent what the source and destination of a copy or sync are
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub struct Endpoints {
+    /// The source is an archive, which is extracted by a copy or sync
+    pub archive_src: bool,
+    /// The source is a file other than an archive, which is copied as it is
+    pub file_src: bool,
+    /// The destination is an archive or image, which is written by a copy
+    pub archive_dest: bool,
+}
+
+impl Endpoints {
+    /// Finds what `src` and `dest` are for a run of `sub_command_type`, where `dest` is the
+    /// destination as it was given
+    pub fn new(sub_command_type: &SubCommandType, src: &str, dest: &str) -> Self {
+        let archive_src = matches!(
+            sub_command_type,
+            SubCommandType::Copy | SubCommandType::Synchronize
+        ) && archive::is_archive_src(src);
+        // Other files are copied as they are by a copy, into a directory or to a file
+        let file_src = *sub_command_type == SubCommandType::Copy
+            && !archive_src
+            && fs::metadata(src).is_ok_and(|m| m.is_file());
+        let archive_dest =
+            *sub_command_type == SubCommandType::Copy && !file_src && archive::is_archive(dest);
+        Endpoints {
+            archive_src,
+            file_src,
+            archive_dest,
+        }
+    }
+
+    /// Checks that `src` is a valid source, and that it can be written to the destination,
+    /// printing why if it is not
+    ///
+    /// # Errors
+    /// * `ExitCode::SourceMissing`: If `src` does not exist, or is not a directory or file as
+    ///   expected
+    /// * `ExitCode::Usage`: If both `src` and the destination are archives
+    pub fn check_src(&self, src: &str) -> Result<(), ExitCode> {
+        if !is_valid_src(src, self.archive_src || self.file_src) {
+            return Err(ExitCode::SourceMissing);
+        }
+        if self.archive_src && self.archive_dest {
+            eprintln!("Destination Error -- archives can not be copied into archives");
+            return Err(ExitCode::Usage);
+        }
+        Ok(())
+    }
+
+    /// Checks that `src` and `dest`, the destination that the tree is written to, are apart,
+    /// printing why if they are not
+    ///
+    /// Archive sources are files that are read before anything is written, so they may be inside
+    /// the destination.
+    ///
+    /// # Errors
+    /// * `ExitCode::Usage`: If `src` and `dest` are the same, or one is inside the other
+    pub fn check_dest(&self, src: &str, dest: &str) -> Result<(), ExitCode> {
+        // A tree copied into itself would be copied again and again, and a sync into a
+        // directory that holds its source would delete it
+        if !self.archive_src && !is_apart(src, dest, self.file_src) {
+            return Err(ExitCode::Usage);
+        }
+        Ok(())
+    }
+
+    /// Creates the destination `dest` if it does not exist, or the directory that it is written
+    /// into if it is a file, archive, or image, printing why if it could not be created
+    ///
+    /// # Errors
+    /// * `ExitCode::DestNotWritable`: If the directory could not be created
+    pub fn create_dest(&self, dest: &str, options: &Options) -> Result<(), ExitCode> {
+        let created = if self.file_src || self.archive_dest {
+            create_parent(dest, options)
+        } else {
+            create_dest(dest, options)
+        };
+        if !created {
+            return Err(ExitCode::DestNotWritable);
+        }
+        Ok(())
+    }
+}
+
+/// Parses the options of a subcommand from its arguments `command`, which are parsed the same way
+/// whether they were given on the command line or by a job of the config file
 ///
 /// # Errors
 /// This function will return an error in the following situations,
 /// but is not limited to just these cases:
-/// * The source folder is not a valid directory
-/// * The destination folder could not be created
-pub fn parse_args<'a>(args: &'a ArgMatches) -> Result<ParseResult<'a>, ()> {
-    // These are safe to unwrap since subcommands are required
-    let sub_command_name = args.subcommand_name().unwrap();
-    let args = args.subcommand_matches(sub_command_name).unwrap();
+/// * A value is invalid, such as a depth of 0 for `--max-depth`
+/// * A file that an option names could not be loaded, such as the map of `--idmap`
+/// * An option is not supported on this platform, such as `--acls`
+pub fn parse_options(command: &Command) -> Result<Options, io::Error> {
+    let mut options = Options::default();
+    match command {
+        Command::Cp(args) => {
+            parse_log(&args.log, &mut options)?;
+            options.sequential = args.sequential;
+            parse_transfer(&args.transfer, &mut options)?;
+            options.deterministic = args.deterministic;
+            options.privsep = args.privsep;
+            options.files_from = match &args.files_from {
+                Some(path) => Some(filelist::load(path)?),
+                None => None,
+            };
+            options.filesystem = args.fs.unwrap_or_default();
+            options.compression = args.comp.unwrap_or_default();
+        }
+        Command::Mv(args) => {
+            parse_log(&args.log, &mut options)?;
+            options.sequential = args.sequential;
+            options.verify = !args.no_verify;
+        }
+        Command::Rm(args) => {
+            parse_log(&args.log, &mut options)?;
+            options.sequential = args.sequential;
+        }
+        Command::Sync(args) => {
+            options.delete = !args.nodelete;
+            options.secure = args.secure;
+            parse_log(&args.log, &mut options)?;
+            options.sequential = args.sequential;
+            parse_transfer(&args.transfer, &mut options)?;
+            options.prune_empty_dirs = args.prune_empty_dirs;
+            options.safe_delete = args.safe_delete;
+            options.force_empty_source = args.force_empty_source;
+            options.ignore_case = args.ignore_case;
+            options.xattr_hash_cache = args.xattr_hash_cache;
+            options.dry_run = args.dry_run;
+            options.print0 = args.print0;
+            options.dest_snapshot = args.dest_snapshot;
+            options.resume = args.resume;
+            options.refuse_anomalies = args.refuse_anomalies;
+            options.force = args.force;
+            options.low_memory = args.low_memory;
+            options.watch = args.watch;
+            options.max_delete = match &args.max_delete {
+                Some(max_delete) => match MaxDelete::parse(max_delete) {
+                    Some(max_delete) => Some(max_delete),
+                    None => {
+                        return Err(invalid(format!(
+                            "Error -- invalid number or percentage of files for --max-delete: {}",
+                            max_delete
+                        )));
+                    }
+                },
+                None => None,
+            };
+            options.audit = args.audit.as_ref().map(PathBuf::from);
+            options.debounce = match args.debounce.as_deref().map(parse_interval) {
+                Some(Some(debounce)) if debounce > Duration::from_secs(0) => Some(debounce),
+                Some(_) => {
+                    return Err(invalid(String::from(
+                        "Error -- invalid duration for --debounce: expected a positive number of \
+                         seconds, optionally followed by ms, s, m, h, or d",
+                    )));
+                }
+                None => None,
+            };
+            options.max_batch = match args.max_batch {
+                Some(0) => {
+                    return Err(invalid(String::from(
+                        "Error -- invalid number of changes for --max-batch: must be at least 1",
+                    )));
+                }
+                max_batch => max_batch,
+            };
+        }
+        Command::Daemon(args) => {
+            parse_verbosity(args.verbose, &mut options);
+            options.log_file = open_log_file(&args.log_file)?;
+        }
+        Command::ExportOci(args) => parse_log(&args.log, &mut options)?,
+        Command::ExportDiff(args) => {
+            parse_log(&args.log, &mut options)?;
+            options.secure = args.secure;
+            options.deterministic = args.deterministic;
+        }
+        Command::Diff(args) => {
+            options.secure = args.secure;
+            options.format = Some(args.format);
+        }
+        Command::Dupes(args) => {
+            parse_verbosity(args.verbose, &mut options);
+            options.quiet = args.quiet;
+            options.link_dupes = args.link;
+            options.delete_dupes = args.delete;
+            options.keep_first = args.keep_first;
+        }
+        Command::Verify(args) => {
+            parse_verbosity(args.verbose, &mut options);
+            options.quiet = args.quiet;
+            options.log_file = open_log_file(&args.log_file)?;
+            options.continuous = args.continuous;
+            options.interval = match args.interval.as_deref().map(parse_interval) {
+                Some(Some(interval)) if interval > Duration::from_secs(0) => Some(interval),
+                Some(_) => {
+                    return Err(invalid(String::from(
+                        "Error -- invalid interval for --interval: expected a positive number of \
+                         seconds, optionally followed by ms, s, m, h, or d",
+                    )));
+                }
+                None => None,
+            };
+            options.webhook = args.webhook.clone();
+        }
+        Command::Snapshot(args) => {
+            options.secure = args.secure;
+            parse_log(&args.log, &mut options)?;
+            options.xattrs = args.xattrs;
+            options.acls = args.acls;
+        }
+        Command::Overlay(args) => {
+            options.delete = !args.nodelete;
+            options.secure = args.secure;
+            parse_log(&args.log, &mut options)?;
+            options.sequential = args.sequential;
+        }
+        Command::Bisync(args) => {
+            options.secure = args.secure;
+            parse_log(&args.log, &mut options)?;
+            options.prefer = args.prefer;
+            options.heal_empty_files = args.heal_empty_files;
+        }
+        Command::Hydrate(args) => parse_log(&args.log, &mut options)?,
+        Command::Tui(args) => {
+            options.delete = !args.nodelete;
+            options.secure = args.secure;
+            parse_verbosity(args.verbose, &mut options);
+            options.log_file = open_log_file(&args.log_file)?;
+        }
+        // The reader of --privsep writes its archive to stdout, so it never draws a progress bar
+        Command::PrivsepReader(_) => options.quiet = true,
+        Command::Manifest(_)
+        | Command::Hash(_)
+        | Command::Stats(_)
+        | Command::History(_)
+        | Command::Completions(_)
+        | Command::Man
+        | Command::Help(_) => {}
+    }
+
+    if options.acls && !metadata::ACLS_SUPPORTED {
+        return Err(invalid(String::from(
+            "ACL Error -- ACLs are not supported on this platform",
+        )));
+    }
+    Ok(options)
+}
+
+/// Makes the error of an invalid argument, with `message`
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+/// Sets how much is logged, from the number of times `-v` was given
+fn parse_verbosity(verbose: u8, options: &mut Options) {
+    options.verbosity = verbose.into();
+}
 
-    const FLAG_NAMES: [&str; 4] = ["nodelete", "secure", "verbose", "sequential"];
+/// Opens the file of `--log-file`, if it was given
+fn open_log_file(path: &Option<String>) -> Result<Option<Arc<LogFile>>, io::Error> {
+    match path {
+        Some(path) => Ok(Some(Arc::new(LogFile::open(
+            Path::new(path),
+            MAX_LOG_SIZE,
+        )?))),
+        None => Ok(None),
+    }
+}
+
+/// Sets the options of how a run is logged, see `cli::LogArgs`
+fn parse_log(args: &LogArgs, options: &mut Options) -> Result<(), io::Error> {
+    parse_verbosity(args.verbose, options);
+    options.quiet = args.quiet;
+    options.log_file = open_log_file(&args.log_file)?;
+    Ok(())
+}
 
-    // Parse for flags
-    let mut flags = Flag::empty();
-    for (i, &flag_name) in FLAG_NAMES.iter().enumerate() {
-        if args.is_present(flag_name) {
-            flags |= Flag::from_bits_truncate(1 << i);
+/// Sets the options of how files are copied, which cp and sync share, see `cli::TransferArgs`
+fn parse_transfer(args: &TransferArgs, options: &mut Options) -> Result<(), io::Error> {
+    options.xattrs = args.xattrs;
+    options.acls = args.acls;
+    options.inplace = args.inplace;
+    options.append_verify = args.append_verify;
+    options.direct_io = args.direct_io;
+    options.fsync = args.fsync;
+    options.skip_growing = args.skip_growing;
+    options.check_space = !args.no_check_space;
+    options.dedup = args.dedup;
+    options.trailing_slash = args.trailing_slash;
+    options.into = args.into;
+    options.relativize_links = args.relativize_links;
+    options.quarantine_files = args.quarantine;
+    options.ignore_existing = args.ignore_existing;
+    options.update = args.update;
+    options.heal_empty_files = args.heal_empty_files;
+    options.one_file_system = args.one_file_system;
+    options.specials = args.specials;
+    options.keep_dirlinks = args.keep_dirlinks;
+    options.copy_dirlinks = args.copy_dirlinks;
+    options.harden = args.harden;
+    options.skip_long_paths = args.skip_long_paths;
+    options.keep_going = args.keep_going;
+    options.order = args.order;
+    options.reflink = args.reflink;
+    options.sandbox = args.sandbox_dest;
+    options.shorten_names = args.shorten_names;
+    options.sanitize_names = args.sanitize_names;
+
+    options.idmap = match &args.idmap {
+        Some(_) if cfg!(not(target_family = "unix")) => {
+            return Err(invalid(String::from(
+                "IdMap Error -- ID mapping is not supported on this platform",
+            )));
+        }
+        Some(path) => Some(IdMap::load(path)?),
+        None => None,
+    };
+
+    options.materialize = match &args.materialize {
+        Some(path) => Some(Rules::load(path)?),
+        None => None,
+    };
+
+    for pattern in &args.fence {
+        match Glob::new(pattern) {
+            Some(glob) => options.fences.push(glob),
+            None => {
+                return Err(invalid(format!(
+                    "Fence Error -- invalid pattern {:?}",
+                    pattern
+                )));
+            }
         }
     }
 
-    // These values are safe to unwrap since the args are required
-    let mut sub_command = match sub_command_name {
-        "cp" => SubCommand {
-            src: Some(args.value_of("SOURCE").unwrap()),
-            dest: vec![args.value_of("DESTINATION").unwrap().to_string()],
+    options.max_errors = args.max_errors;
+
+    options.max_depth = match args.max_depth {
+        Some(0) => {
+            return Err(invalid(String::from(
+                "Error -- invalid depth for --max-depth: must be at least 1",
+            )));
+        }
+        max_depth => max_depth,
+    };
+
+    let filter = Filter::parse(args.filter.iter().map(String::as_str))?;
+    if !filter.is_empty() {
+        options.filter = Some(filter);
+    }
+
+    options.buffer_size = match args.buffer_size.as_deref().map(parse_size) {
+        Some(Some(0)) => {
+            return Err(invalid(String::from(
+                "Error -- invalid size for --buffer-size: must be at least 1",
+            )));
+        }
+        Some(Some(buffer_size)) => Some(buffer_size),
+        Some(None) => {
+            return Err(invalid(String::from(
+                "Error -- invalid size for --buffer-size: expected a number of bytes, \
+                 optionally followed by K, M, or G",
+            )));
+        }
+        None => None,
+    };
+
+    options.preview = match args.preview {
+        Some(0) => {
+            return Err(invalid(String::from(
+                "Preview Error -- invalid number of entries for --preview: must be at least 1",
+            )));
+        }
+        preview => preview,
+    };
+
+    if let Some(retries) = args.retries {
+        options.retry.retries = retries;
+    }
+    if let Some(delay) = args.retry_delay {
+        if !delay.is_finite() || delay < 0.0 {
+            return Err(invalid(format!("Retry Error -- invalid delay {:?}", delay)));
+        }
+        options.retry.delay = Duration::from_secs_f64(delay);
+    }
+
+    for expr in &args.link_rewrite {
+        options.link_rewrites.push(expr.parse()?);
+    }
+
+    if options.preview.is_some()
+        && options.link_rewrites.is_empty()
+        && options.materialize.is_none()
+        && !options.relativize_links
+    {
+        return Err(invalid(String::from(
+            "Preview Error -- --preview only applies with --link-rewrite, --relativize-links, or \
+             --materialize",
+        )));
+    }
+
+    if let Some(name) = &args.done_marker {
+        if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\\']) {
+            return Err(invalid(format!(
+                "Marker Error -- {:?} is not a file name",
+                name
+            )));
+        }
+        options.done_marker = Some(name.clone());
+    }
+
+    options.fidelity_report = args.fidelity_report.as_ref().map(PathBuf::from);
+    options.hook_pre = args.hook_pre.as_deref().map(Hook::new);
+    options.hook_post = args.hook_post.as_deref().map(Hook::new);
+    Ok(())
+}
+
+/// Parses command line arguments for source and destination folders and
+/// creates the destination folder if it does not exist
+///
+/// # Errors
+/// This function will return the exit code to exit with in the following situations,
+/// but is not limited to just these cases:
+/// * The source folder is not a valid directory, which is `ExitCode::SourceMissing`
+/// * The destination folder could not be created, which is `ExitCode::DestNotWritable`
+/// * The arguments are invalid, which is `ExitCode::Usage`
+pub fn parse_args(cli: &Cli) -> Result<ParseResult<'_>, ExitCode> {
+    let mut options = parse_options(&cli.command).map_err(|e| {
+        eprintln!("{}", e);
+        ExitCode::Usage
+    })?;
+
+    let mut sub_command = match &cli.command {
+        Command::Cp(args) => SubCommand {
+            src: Some(&args.source),
+            dest: vec![args.destination.clone()],
+            profile: args.transfer.profile.as_deref(),
             sub_command_type: SubCommandType::Copy,
+            ..Default::default()
+        },
+        Command::Mv(args) => SubCommand {
+            src: Some(&args.source),
+            dest: vec![args.destination.clone()],
+            sub_command_type: SubCommandType::Move,
+            ..Default::default()
         },
-        "rm" => SubCommand {
-            src: None,
-            dest: args
-                .values_of("TARGET")
-                .unwrap()
-                .map(|value| value.to_string())
-                .collect(),
+        Command::Rm(args) => SubCommand {
+            dest: args.target.clone(),
             sub_command_type: SubCommandType::Remove,
+            ..Default::default()
         },
-        "sync" => SubCommand {
-            src: Some(args.value_of("SOURCE").unwrap()),
-            dest: vec![args.value_of("DESTINATION").unwrap().to_string()],
+        Command::Sync(args) => SubCommand {
+            src: Some(&args.source),
+            dest: vec![args.destination.clone()],
+            profile: args.transfer.profile.as_deref(),
             sub_command_type: SubCommandType::Synchronize,
+            ..Default::default()
+        },
+        Command::Tui(args) => SubCommand {
+            src: Some(&args.source),
+            dest: vec![args.destination.clone()],
+            sub_command_type: SubCommandType::Tui,
+            ..Default::default()
         },
-        _ => return Err(()),
+        Command::Daemon(args) => SubCommand {
+            config: args.config.as_deref(),
+            sub_command_type: SubCommandType::Daemon,
+            ..Default::default()
+        },
+        Command::ExportOci(args) => SubCommand {
+            src: Some(&args.source),
+            dest: vec![args.layout.clone()],
+            tag: args.tag.as_deref(),
+            sub_command_type: SubCommandType::ExportOci,
+            ..Default::default()
+        },
+        Command::ExportDiff(args) => SubCommand {
+            src: Some(&args.source),
+            dest: vec![args.destination.clone(), args.output.clone()],
+            sub_command_type: SubCommandType::ExportDiff,
+            ..Default::default()
+        },
+        Command::History(args) => SubCommand {
+            profile: args.profile.as_deref(),
+            history: args.file.as_deref(),
+            runs: args.runs,
+            sub_command_type: SubCommandType::History,
+            ..Default::default()
+        },
+        Command::Manifest(args) => SubCommand {
+            src: Some(&args.source),
+            dest: args.output.iter().cloned().collect(),
+            sub_command_type: SubCommandType::Manifest,
+            ..Default::default()
+        },
+        Command::Diff(args) => SubCommand {
+            src: args.first.as_deref(),
+            dest: match &args.second {
+                Some(second) => vec![second.clone()],
+                None => args.manifest.clone(),
+            },
+            sub_command_type: SubCommandType::Diff,
+            ..Default::default()
+        },
+        Command::Stats(args) => SubCommand {
+            src: Some(&args.source),
+            top: args.top,
+            sub_command_type: SubCommandType::Stats,
+            ..Default::default()
+        },
+        Command::Dupes(args) => SubCommand {
+            src: Some(&args.source),
+            sub_command_type: SubCommandType::Dupes,
+            ..Default::default()
+        },
+        Command::Hash(args) => SubCommand {
+            src: Some(&args.source),
+            sub_command_type: SubCommandType::Hash,
+            ..Default::default()
+        },
+        Command::Verify(args) => SubCommand {
+            src: Some(&args.source),
+            dest: vec![args.manifest.clone()],
+            sub_command_type: SubCommandType::Verify,
+            ..Default::default()
+        },
+        Command::Bisync(args) => SubCommand {
+            src: Some(&args.a),
+            dest: vec![args.b.clone()],
+            sub_command_type: SubCommandType::Bisync,
+            ..Default::default()
+        },
+        Command::Snapshot(args) => SubCommand {
+            src: Some(&args.source),
+            dest: vec![args.destination.clone()],
+            sub_command_type: SubCommandType::Snapshot,
+            ..Default::default()
+        },
+        Command::Overlay(args) => {
+            // The destination is the last of the roots, of which there are at least 2
+            let (dest, sources) = args.roots.split_last().unwrap();
+            SubCommand {
+                sources: sources.iter().map(String::as_str).collect(),
+                dest: vec![dest.clone()],
+                sub_command_type: SubCommandType::Overlay,
+                ..Default::default()
+            }
+        }
+        Command::Hydrate(args) => SubCommand {
+            src: args.from.as_deref(),
+            dest: args.path.clone(),
+            sub_command_type: SubCommandType::Hydrate,
+            ..Default::default()
+        },
+        Command::PrivsepReader(args) => SubCommand {
+            src: Some(&args.source),
+            sub_command_type: SubCommandType::PrivsepReader,
+            ..Default::default()
+        },
+        // These print what they are asked for before any run is set up, see main
+        Command::Completions(_) | Command::Man | Command::Help(_) => return Err(ExitCode::Usage),
     };
 
     // Validate directories
     match sub_command.sub_command_type {
+        SubCommandType::Daemon | SubCommandType::History | SubCommandType::Hydrate => {}
+        SubCommandType::Manifest => {
+            if !is_valid_src(sub_command.src.unwrap(), false) {
+                return Err(ExitCode::SourceMissing);
+            }
+            if let Some(output) = sub_command.dest.first() {
+                if !create_parent(output, &options) {
+                    return Err(ExitCode::DestNotWritable);
+                }
+            }
+        }
+        SubCommandType::Hash
+        | SubCommandType::Stats
+        | SubCommandType::Dupes
+        | SubCommandType::Verify
+        | SubCommandType::PrivsepReader => {
+            if !is_valid_src(sub_command.src.unwrap(), false) {
+                return Err(ExitCode::SourceMissing);
+            }
+        }
+        SubCommandType::ExportDiff => {
+            if !is_valid_src(sub_command.src.unwrap(), false)
+                || !is_valid_src(&sub_command.dest[0], false)
+            {
+                return Err(ExitCode::SourceMissing);
+            }
+            let output = &sub_command.dest[1];
+            if archive::compression(output).is_none() {
+                eprintln!(
+                    "Destination Error -- {} is not a .tar or .tar.zst archive",
+                    output
+                );
+                return Err(ExitCode::Usage);
+            }
+            if !create_parent(output, &options) {
+                return Err(ExitCode::DestNotWritable);
+            }
+        }
+        SubCommandType::Diff if sub_command.src.is_some() => {
+            if !is_valid_src(sub_command.src.unwrap(), false)
+                || !is_valid_src(&sub_command.dest[0], false)
+            {
+                return Err(ExitCode::SourceMissing);
+            }
+        }
+        SubCommandType::Diff => {
+            if sub_command.dest.len() != 2 {
+                eprintln!("Manifest Error -- --manifest must be given exactly twice");
+                return Err(ExitCode::Usage);
+            }
+        }
+        SubCommandType::Overlay => {
+            if !sub_command
+                .sources
+                .iter()
+                .all(|src| is_valid_src(src, false))
+            {
+                return Err(ExitCode::SourceMissing);
+            }
+            let dest = &sub_command.dest[0];
+            if !sub_command
+                .sources
+                .iter()
+                .all(|src| is_apart(src, dest, false))
+            {
+                return Err(ExitCode::Usage);
+            }
+            if !create_dest(dest, &options) {
+                return Err(ExitCode::DestNotWritable);
+            }
+        }
         SubCommandType::Remove => {
             sub_command.dest.retain(|dest| {
                 // Target directory must be a valid directory
@@ -109,31 +1521,282 @@ pub fn parse_args<'a>(args: &'a ArgMatches) -> Result<ParseResult<'a>, ()> {
             });
 
             if sub_command.dest.is_empty() {
-                return Err(());
-            }
-        }
-        SubCommandType::Copy | SubCommandType::Synchronize => {
-            // Check if src is valid
-            match fs::metadata(sub_command.src.unwrap()) {
-                Ok(m) => {
-                    if !m.is_dir() {
-                        eprintln!(
-                            "Source Error -- {} is not a directory",
-                            sub_command.src.unwrap()
-                        );
-                        return Err(());
-                    }
+                return Err(ExitCode::SourceMissing);
+            }
+        }
+        SubCommandType::Copy
+        | SubCommandType::Move
+        | SubCommandType::Synchronize
+        | SubCommandType::Tui
+        | SubCommandType::ExportOci
+        | SubCommandType::Bisync
+        | SubCommandType::Snapshot => {
+            let endpoints = Endpoints::new(
+                &sub_command.sub_command_type,
+                sub_command.src.unwrap(),
+                &sub_command.dest[0],
+            );
+            endpoints.check_src(sub_command.src.unwrap())?;
+            let Endpoints {
+                archive_src,
+                file_src,
+                archive_dest,
+            } = endpoints;
+
+            if file_src && options.privsep {
+                eprintln!("Source Error -- --privsep does not apply to single files");
+                return Err(ExitCode::Usage);
+            }
+
+            if options.into && archive_src {
+                eprintln!("Source Error -- --into does not apply to archive sources");
+                return Err(ExitCode::Usage);
+            }
+
+            if (options.resume || options.refuse_anomalies || options.low_memory) && archive_src {
+                eprintln!(
+                    "Source Error -- --resume, --refuse-anomalies, and --low-memory do not apply \
+                     to archive sources"
+                );
+                return Err(ExitCode::Usage);
+            }
+
+            if options.ignore_case && archive_src {
+                eprintln!("Source Error -- --ignore-case does not apply to archive sources");
+                return Err(ExitCode::Usage);
+            }
+
+            if options.privsep {
+                if cfg!(not(target_os = "linux")) {
+                    eprintln!("Error -- --privsep is not supported on this platform");
+                    return Err(ExitCode::Usage);
                 }
-                Err(e) => {
-                    eprintln!("Source Error -- {}: {}", sub_command.src.unwrap(), e);
-                    return Err(());
+                if archive_src || archive_dest {
+                    eprintln!("Destination Error -- --privsep does not apply to archives");
+                    return Err(ExitCode::Usage);
                 }
-            };
+                // Both processes are hardened on their own
+                options.harden = false;
+            }
+            // The tree is streamed to the writer as an archive, so what does not apply to
+            // archive sources does not apply to --privsep either
+            let archive_src = archive_src || options.privsep;
 
-            // If the directory already exists, then the directory is directory + src name
-            if sub_command.sub_command_type == SubCommandType::Copy
-                && fs::metadata(&sub_command.dest[0]).is_ok()
-            {
+            if options.materialize.is_some() && archive_src {
+                eprintln!("Source Error -- --materialize does not apply to archive sources");
+                return Err(ExitCode::Usage);
+            }
+
+            if options.files_from.is_some() && (archive_src || archive_dest || file_src) {
+                eprintln!(
+                    "Source Error -- --files-from does not apply to archives, single files, or \
+                     --privsep"
+                );
+                return Err(ExitCode::Usage);
+            }
+
+            if options.filter.is_some() && (archive_dest || options.privsep) {
+                eprintln!(
+                    "Destination Error -- --filter does not apply to archive destinations or \
+                     --privsep"
+                );
+                return Err(ExitCode::Usage);
+            }
+
+            if options.deterministic && !archive_dest {
+                eprintln!(
+                    "Destination Error -- --deterministic only applies to archive destinations"
+                );
+                return Err(ExitCode::Usage);
+            }
+
+            if options.into && archive_dest {
+                eprintln!("Destination Error -- --into does not apply to archive destinations");
+                return Err(ExitCode::Usage);
+            }
+
+            if options.dedup {
+                if cfg!(not(target_family = "unix")) {
+                    eprintln!("Dedup Error -- deduplication is not supported on this platform");
+                    return Err(ExitCode::Usage);
+                }
+                if archive_src || archive_dest {
+                    eprintln!("Destination Error -- --dedup does not apply to archives");
+                    return Err(ExitCode::Usage);
+                }
+                // Writing into a linked file would change every file that is linked to it
+                if options.inplace || options.append_verify {
+                    eprintln!(
+                        "Destination Error -- --dedup can not be used with --inplace or \
+                         --append-verify"
+                    );
+                    return Err(ExitCode::Usage);
+                }
+            }
+
+            if !options.fences.is_empty() && (archive_src || archive_dest) {
+                eprintln!("Destination Error -- --fence does not apply to archives");
+                return Err(ExitCode::Usage);
+            }
+
+            if options.relativize_links && (archive_src || archive_dest) {
+                eprintln!("Destination Error -- --relativize-links does not apply to archives");
+                return Err(ExitCode::Usage);
+            }
+
+            if options.xattr_hash_cache {
+                if cfg!(not(target_family = "unix")) {
+                    eprintln!(
+                        "Hash Cache Error -- extended attributes are not supported on this platform"
+                    );
+                    return Err(ExitCode::Usage);
+                }
+                if archive_src || archive_dest {
+                    eprintln!("Destination Error -- --xattr-hash-cache does not apply to archives");
+                    return Err(ExitCode::Usage);
+                }
+            }
+
+            if options.one_file_system {
+                if cfg!(not(target_family = "unix")) {
+                    eprintln!("Error -- --one-file-system is not supported on this platform");
+                    return Err(ExitCode::Usage);
+                }
+                if archive_src || archive_dest {
+                    eprintln!("Destination Error -- --one-file-system does not apply to archives");
+                    return Err(ExitCode::Usage);
+                }
+            }
+
+            if options.sandbox != SandboxMode::Auto && archive_dest {
+                eprintln!("Destination Error -- --sandbox-dest does not apply to archives");
+                return Err(ExitCode::Usage);
+            }
+
+            if (options.keep_dirlinks || options.copy_dirlinks) && (archive_src || archive_dest) {
+                eprintln!(
+                    "Destination Error -- --keep-dirlinks and --copy-dirlinks do not apply to \
+                     archives"
+                );
+                return Err(ExitCode::Usage);
+            }
+
+            if options.harden {
+                if cfg!(not(target_os = "linux")) {
+                    eprintln!("Error -- --harden is not supported on this platform");
+                    return Err(ExitCode::Usage);
+                }
+                // Snapshots are taken by running btrfs or zfs
+                if options.dest_snapshot {
+                    eprintln!("Destination Error -- --harden can not be used with --dest-snapshot");
+                    return Err(ExitCode::Usage);
+                }
+                // Hooks are run with the shell, which is denied once the process is hardened
+                if options.hook_pre.is_some() || options.hook_post.is_some() {
+                    eprintln!(
+                        "Error -- --harden can not be used with --hook-pre or --hook-post, \
+                         since hardened runs can not run programs"
+                    );
+                    return Err(ExitCode::Usage);
+                }
+            }
+
+            if options.skip_long_paths && (archive_src || archive_dest) {
+                eprintln!("Destination Error -- --skip-long-paths does not apply to archives");
+                return Err(ExitCode::Usage);
+            }
+            if options.shorten_names.is_some() && (archive_src || archive_dest) {
+                eprintln!("Destination Error -- --shorten-names does not apply to archives");
+                return Err(ExitCode::Usage);
+            }
+            if options.sanitize_names.is_some() && (archive_src || archive_dest) {
+                eprintln!("Destination Error -- --sanitize-names does not apply to archives");
+                return Err(ExitCode::Usage);
+            }
+
+            if let Command::Cp(args) = &cli.command {
+                if args.fs.is_some() && !image::is_image(&sub_command.dest[0]) {
+                    eprintln!("Destination Error -- --fs only applies to .iso images");
+                    return Err(ExitCode::Usage);
+                }
+                if args.comp.is_some() && !squashfs::is_squashfs(&sub_command.dest[0]) {
+                    eprintln!("Destination Error -- --comp only applies to .sqfs images");
+                    return Err(ExitCode::Usage);
+                }
+            }
+
+            if options.specials {
+                if cfg!(not(target_family = "unix")) {
+                    eprintln!("Error -- --specials is not supported on this platform");
+                    return Err(ExitCode::Usage);
+                }
+                if archive_src || archive_dest {
+                    eprintln!("Destination Error -- --specials does not apply to archives");
+                    return Err(ExitCode::Usage);
+                }
+            }
+
+            // Archive sources are filtered and compared as they are extracted, but the tree that
+            // --privsep streams is not
+            if options.max_depth.is_some() && (options.privsep || archive_dest) {
+                eprintln!(
+                    "Destination Error -- --max-depth does not apply to archive destinations or \
+                     --privsep"
+                );
+                return Err(ExitCode::Usage);
+            }
+
+            if (options.ignore_existing || options.update) && (options.privsep || archive_dest) {
+                eprintln!(
+                    "Destination Error -- --ignore-existing and --update do not apply to archive \
+                     destinations or --privsep"
+                );
+                return Err(ExitCode::Usage);
+            }
+            if options.quarantine_files && (archive_src || archive_dest) {
+                eprintln!("Destination Error -- --quarantine does not apply to archives");
+                return Err(ExitCode::Usage);
+            }
+
+            if !options.link_rewrites.is_empty() && (archive_src || archive_dest) {
+                eprintln!("Destination Error -- --link-rewrite does not apply to archives");
+                return Err(ExitCode::Usage);
+            }
+
+            if options.done_marker.is_some() && (archive_src || archive_dest) {
+                eprintln!("Destination Error -- --done-marker does not apply to archives");
+                return Err(ExitCode::Usage);
+            }
+
+            if options.materialize.is_some() && archive_dest {
+                eprintln!(
+                    "Destination Error -- --materialize does not apply to archive destinations"
+                );
+                return Err(ExitCode::Usage);
+            }
+
+            // If the directory already exists for a copy or move, or --into is given, then the
+            // directory is directory + src name. Archives are extracted into the directory itself
+            let nests = match sub_command.sub_command_type {
+                SubCommandType::Copy => (!archive_src || options.privsep) && !archive_dest,
+                SubCommandType::Move => true,
+                _ => false,
+            };
+            // A single file is only copied into the directory if it is one, or ends with a
+            // separator, and is otherwise copied to the file itself
+            let exists = if file_src {
+                fs::metadata(&sub_command.dest[0]).is_ok_and(|m| m.is_dir())
+                    || sub_command.dest[0].ends_with(std::path::is_separator)
+            } else {
+                fs::metadata(&sub_command.dest[0]).is_ok()
+            };
+            // Like rsync, a directory source without a trailing separator may name the directory
+            // itself, and one with a trailing separator its contents
+            let nests = match nests_source(sub_command.src.unwrap(), options.trailing_slash) {
+                Some(nests_source) if !archive_src && !archive_dest && !file_src => nests_source,
+                _ => nests && exists,
+            };
+            if options.into || nests {
                 let mut new_dest = PathBuf::from(&sub_command.dest[0]);
                 let src_name = PathBuf::from(sub_command.src.unwrap());
                 if let Some(src_name) = src_name.file_name() {
@@ -142,46 +1805,353 @@ pub fn parse_args<'a>(args: &'a ArgMatches) -> Result<ParseResult<'a>, ()> {
                 }
             }
 
-            if fs::metadata(&sub_command.dest[0]).is_err() {
-                // Create destination folder if not already existing
-                match fs::create_dir_all(&sub_command.dest[0]) {
-                    Ok(_) => {
-                        if flags.contains(Flag::VERBOSE) {
-                            println!("Creating dir {:?}", sub_command.dest[0]);
-                        }
-                    }
-                    Err(e) => {
-                        eprintln!("Destination Error -- {}: {}", sub_command.dest[0], e);
-                        return Err(());
+            endpoints.check_dest(sub_command.src.unwrap(), &sub_command.dest[0])?;
+            endpoints.create_dest(&sub_command.dest[0], &options)?;
+        }
+    }
+
+    // Compare planned changes with earlier runs of the profile, if there is one
+    let norms = match sub_command.profile {
+        Some(profile) if options.refuse_anomalies => {
+            match Norms::load(&history::default_path(), profile) {
+                Ok(norms) => Some(norms),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return Err(ExitCode::Failed);
+                }
+            }
+        }
+        _ => None,
+    };
+
+    Ok(ParseResult {
+        sub_command,
+        options: Options { norms, ..options },
+    })
+}
+
+/// Creates the destination directory `dest` if it does not already exist, printing why if it
+/// could not be created
+fn create_dest(dest: &str, options: &Options) -> bool {
+    if fs::metadata(dest).is_ok() {
+        return true;
+    }
+    match fs::create_dir_all(dest) {
+        Ok(_) => {
+            if options.verbose() {
+                println!("Creating dir {:?}", dest);
+            }
+            true
+        }
+        Err(e) => {
+            eprintln!("Destination Error -- {}: {}", dest, e);
+            false
+        }
+    }
+}
+
+/// Creates the directory that the file `dest` is written into, like `create_dest`, if it does not
+/// already exist
+fn create_parent(dest: &str, options: &Options) -> bool {
+    match Path::new(dest).parent() {
+        Some(parent) if parent != Path::new("") => create_dest(&parent.to_string_lossy(), options),
+        _ => true,
+    }
+}
+
+/// Checks that `src` and `dest` are not the same, and that neither is inside the other, once their
+/// symlinks are resolved, printing why if they are
+///
+/// Only whether they are the same is checked if `file` is set, since nothing is inside a file.
+fn is_apart(src: &str, dest: &str, file: bool) -> bool {
+    let (src_path, dest_path) = (resolve(Path::new(src)), resolve(Path::new(dest)));
+    if src_path == dest_path {
+        eprintln!("Destination Error -- {} and {} are the same", src, dest);
+    } else if !file && dest_path.starts_with(&src_path) {
+        eprintln!(
+            "Destination Error -- {} is inside {}, so it would be copied into itself",
+            dest, src
+        );
+    } else if !file && src_path.starts_with(&dest_path) {
+        eprintln!(
+            "Destination Error -- {} is inside {}, so it would be overwritten or deleted",
+            src, dest
+        );
+    } else {
+        return true;
+    }
+    false
+}
+
+/// Resolves the symlinks of `path`, which may not exist yet, into an absolute path
+///
+/// What does not exist of `path` has no symlinks to resolve, so it is joined to what does as it
+/// is, with `..` removing the name before it, as it would once the directories are created.
+fn resolve(path: &Path) -> PathBuf {
+    let path = env::current_dir()
+        .map(|dir| dir.join(path))
+        .unwrap_or_else(|_| path.to_path_buf());
+
+    let mut resolved = PathBuf::new();
+    // The number of names at the end of `resolved` that do not exist
+    let mut missing = 0;
+    for component in path.components() {
+        match component {
+            Component::Prefix(_) | Component::RootDir => resolved.push(component),
+            Component::CurDir => {}
+            Component::ParentDir if missing > 0 => {
+                resolved.pop();
+                missing -= 1;
+            }
+            component => {
+                resolved.push(component);
+                if missing > 0 {
+                    missing += 1;
+                } else {
+                    match fs::canonicalize(&resolved) {
+                        Ok(canonical) => resolved = canonical,
+                        Err(_) => missing = 1,
                     }
                 }
             }
         }
     }
+    resolved
+}
+
+/// Checks that `src` is a valid source directory, or a valid file, such as an archive, if `file`
+/// is set, printing why if it is not
+fn is_valid_src(src: &str, file: bool) -> bool {
+    match fs::metadata(src) {
+        Ok(m) if file => {
+            if !m.is_file() {
+                eprintln!("Source Error -- {} is not a file", src);
+            }
+            m.is_file()
+        }
+        Ok(m) => {
+            if !m.is_dir() {
+                eprintln!("Source Error -- {} is not a directory", src);
+            }
+            m.is_dir()
+        }
+        Err(e) => {
+            eprintln!("Source Error -- {}: {}", src, e);
+            false
+        }
+    }
+}
+
+/// Parses a size in bytes, such as `4096`, or with a binary suffix, such as `64K` or `4M`
+fn parse_size(size: &str) -> Option<usize> {
+    let (number, shift) = match size.char_indices().last()? {
+        (i, 'k') | (i, 'K') => (&size[..i], 10),
+        (i, 'm') | (i, 'M') => (&size[..i], 20),
+        (i, 'g') | (i, 'G') => (&size[..i], 30),
+        _ => (size, 0),
+    };
+    number.parse::<usize>().ok()?.checked_mul(1 << shift)
+}
 
-    Ok(ParseResult { sub_command, flags })
+/// Parses a duration in seconds, such as `90`, or with a suffix, such as `250ms`, `30m`, `1h`, or
+/// `7d`
+fn parse_interval(interval: &str) -> Option<Duration> {
+    if let Some(millis) = interval.strip_suffix("ms") {
+        return millis.parse().ok().map(Duration::from_millis);
+    }
+    let (number, unit) = match interval.char_indices().last()? {
+        (i, 's') => (&interval[..i], 1),
+        (i, 'm') => (&interval[..i], 60),
+        (i, 'h') => (&interval[..i], 60 * 60),
+        (i, 'd') => (&interval[..i], 24 * 60 * 60),
+        _ => (interval, 1),
+    };
+    number
+        .parse::<u64>()
+        .ok()?
+        .checked_mul(unit)
+        .map(Duration::from_secs)
 }
 
-/// Sets up the environment based on given flags
-pub fn set_env(flags: Flag) {
+/// Sets up the environment based on given options
+pub fn set_env(options: &Options) {
+    set_run_env(options);
+
+    // Warnings and errors are printed unless quiet, while -v prints info, -vv debug, and -vvv
+    // trace messages, such as why files are copied
+    let quiet = options.quiet;
+    let print_level = match options.verbosity {
+        _ if quiet => LevelFilter::Error,
+        0 => LevelFilter::Warn,
+        1 => LevelFilter::Info,
+        2 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    };
+    let log_file = options.log_file.clone();
+
     let mut builder = Builder::new();
-    builder.format(|_, record| {
-        PROGRESS_BAR.println(format!("{}", record.args()));
+    builder.format(move |_, record| {
+        let message = format!("{}", record.args());
+        if let Some(log_file) = &log_file {
+            log_file.write(record.level(), &message);
+        }
+        if record.level() <= print_level {
+            // Without a progress bar, errors are printed on their own
+            if quiet {
+                eprintln!("{}", message);
+            } else {
+                PROGRESS_BAR.println(&message);
+            }
+        }
+        if record.level() == Level::Error {
+            STATS.add_error(message);
+        }
         Ok(())
     });
 
-    // If verbose, enable info logging
-    if flags.contains(Flag::VERBOSE) {
-        env::set_var("RUST_LOG", "info");
-        builder.filter(None, LevelFilter::Info).init();
-    } else {
-        // or else enable only error logging
-        env::set_var("RUST_LOG", "error");
-        builder.filter(None, LevelFilter::Error).init();
+    if quiet {
+        PROGRESS_BAR.set_draw_target(ProgressDrawTarget::hidden());
     }
 
+    // With a log file, everything is logged to it, whether or not it is printed
+    let filter = if options.log_file.is_some() {
+        print_level.max(LevelFilter::Info)
+    } else {
+        print_level
+    };
+    env::set_var("RUST_LOG", filter.to_string().to_lowercase());
+    builder.filter(None, filter).init();
+
     // If sequential, set Rayon to use only 1 thread
-    if flags.contains(Flag::SEQUENTIAL) {
+    if options.sequential {
         env::set_var("RAYON_NUM_THREADS", "1");
+    } else if let Some(threads) = options.threads {
+        env::set_var("RAYON_NUM_THREADS", threads.to_string());
+    }
+}
+
+/// Sets up what only applies to a single run based on given options, so that each job of the
+/// daemon runs with its own
+pub fn set_run_env(options: &Options) {
+    STATS.set_keeps_errors(options.keep_going);
+    STATS.set_max_errors(options.max_errors);
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_options_builder {
+    use super::*;
+
+    #[test]
+    fn builds_options() {
+        let options = Options::builder()
+            .delete(false)
+            .secure(true)
+            .check_space(false)
+            .buffer_size(1)
+            .threads(2)
+            .max_depth(3)
+            .build()
+            .unwrap();
+        assert!(!options.check_space);
+        assert!(!options.delete && options.secure);
+        assert_eq!(options.buffer_size, Some(1));
+        assert_eq!(options.threads, Some(2));
+        assert_eq!(options.max_depth, Some(3));
+
+        // Options that are turned back off are as they were
+        let options = Options::builder()
+            .secure(true)
+            .secure(false)
+            .delete(true)
+            .build()
+            .unwrap();
+        assert!(options.delete && !options.secure);
+        assert!(options.check_space);
+    }
+
+    #[test]
+    fn refuses_invalid_options() {
+        let error = |builder: OptionsBuilder| builder.build().unwrap_err().to_string();
+        assert_eq!(
+            error(Options::builder().buffer_size(0)),
+            "Options Error -- the buffer size must be at least 1 byte"
+        );
+        assert_eq!(
+            error(Options::builder().threads(0)),
+            "Options Error -- there must be at least 1 thread"
+        );
+        assert!(Options::builder()
+            .ignore_existing(true)
+            .update(true)
+            .build()
+            .is_ok());
+    }
+}
+
+#[cfg(test)]
+mod test_parse_options {
+    use super::*;
+    use clap::Parser;
+
+    /// Parses the options of the command line `args`
+    fn parse(args: &[&str]) -> Result<Options, io::Error> {
+        parse_options(&Cli::try_parse_from(args).unwrap().command)
+    }
+
+    #[test]
+    fn parses_options() {
+        let options = parse(&[
+            "lms",
+            "sync",
+            "-n",
+            "-vv",
+            "--max-delete",
+            "10%",
+            "--trailing-slash",
+            "rsync",
+            "--order",
+            "none",
+            "a",
+            "b",
+        ])
+        .unwrap();
+        assert!(!options.delete);
+        assert_eq!(options.verbosity, 2);
+        assert!(options.verbose() && !options.dry_run);
+        assert_eq!(options.trailing_slash, TrailingSlash::Rsync);
+        assert_eq!(options.max_delete, Some(MaxDelete::Percent(10.0)));
+        assert_eq!(options.order, Some(Order::Unsorted));
+
+        let options = parse(&[
+            "lms",
+            "cp",
+            "--reflink",
+            "never",
+            "--comp",
+            "gzip",
+            "a",
+            "b",
+        ])
+        .unwrap();
+        assert_eq!(options.reflink, Reflink::Never);
+        assert_eq!(options.compression, Codec::Gzip);
+        assert_eq!(options.filesystem, Filesystem::Iso9660);
+    }
+
+    #[test]
+    fn refuses_invalid_values() {
+        for args in &[
+            ["lms", "cp", "--max-depth", "0", "a", "b"],
+            ["lms", "cp", "--buffer-size", "4X", "a", "b"],
+            ["lms", "cp", "--preview", "5", "a", "b"],
+            ["lms", "sync", "--max-delete", "many", "a", "b"],
+        ] {
+            assert!(parse(args).is_err(), "{:?}", args);
+        }
+        assert!(Cli::try_parse_from(["lms", "cp", "--max-errors", "-1", "a", "b"]).is_err());
     }
 }
@@ -0,0 +1,466 @@
+//! Platform-specific helpers for paths and file attributes
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Converts `path` into a path that is not limited by the platform's maximum path length
+///
+/// On Windows, this returns the absolute extended-length (`\\?\`) form of `path`,
+/// which lifts the `MAX_PATH` limit of 260 characters.
+/// On other platforms, `path` is returned unchanged.
+pub fn long_path(path: &Path) -> PathBuf {
+    #[cfg(target_family = "windows")]
+    {
+        let absolute = if path.is_absolute() {
+            path.to_path_buf()
+        } else {
+            match std::env::current_dir() {
+                Ok(dir) => dir.join(path),
+                Err(_) => return path.to_path_buf(),
+            }
+        };
+        PathBuf::from(extended_length_path(&absolute.to_string_lossy()))
+    }
+
+    #[cfg(not(target_family = "windows"))]
+    {
+        path.to_path_buf()
+    }
+}
+
+/// Converts an absolute Windows path into its extended-length form
+///
+/// Extended-length paths are not normalized by Windows, so separators are converted to `\`
+/// and `.` and `..` components are resolved here.
+///
+/// # Examples
+/// `C:\a\..\b/c` becomes `\\?\C:\b\c`
+/// `\\server\share\a` becomes `\\?\UNC\server\share\a`
+#[cfg_attr(not(target_family = "windows"), allow(dead_code))]
+fn extended_length_path(path: &str) -> String {
+    if path.starts_with(r"\\?\") {
+        return path.to_string();
+    }
+
+    let path = path.replace('/', "\\");
+    let (prefix, rest, root_len) = match path.strip_prefix(r"\\") {
+        // UNC paths keep their server and share name
+        Some(unc) => (r"\\?\UNC\", unc, 2),
+        // Other paths keep their drive letter
+        None => (r"\\?\", path.as_str(), 1),
+    };
+
+    let mut components = Vec::new();
+    for component in rest.split('\\') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                if components.len() > root_len {
+                    components.pop();
+                }
+            }
+            component => components.push(component),
+        }
+    }
+
+    let mut extended = prefix.to_string() + &components.join("\\");
+    // A bare drive must keep its trailing separator to refer to the root directory
+    if components.len() == 1 && root_len == 1 {
+        extended.push('\\');
+    }
+    extended
+}
+
+/// Clears the read-only attribute of `path` so that it can be overwritten or deleted
+///
+/// This is only done on Windows, where read-only files cannot be replaced or removed.
+/// Nonexistent paths are ignored.
+pub fn clear_readonly(path: &Path) -> Result<(), io::Error> {
+    #[cfg(target_family = "windows")]
+    {
+        let metadata = match std::fs::symlink_metadata(path) {
+            Ok(metadata) => metadata,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+
+        let mut permissions = metadata.permissions();
+        if permissions.readonly() {
+            #[allow(clippy::permissions_set_readonly_false)]
+            permissions.set_readonly(false);
+            std::fs::set_permissions(path, permissions)?;
+        }
+    }
+
+    #[cfg(not(target_family = "windows"))]
+    let _ = path;
+
+    Ok(())
+}
+
+/// Copies the hidden and system attributes of `src` to `dest`
+///
+/// This is only done on Windows, since other platforms have no such attributes
+pub fn copy_attributes(src: &Path, dest: &Path) -> Result<(), io::Error> {
+    #[cfg(target_family = "windows")]
+    {
+        use std::os::windows::ffi::OsStrExt;
+        use std::os::windows::fs::MetadataExt;
+        use winapi::um::fileapi::SetFileAttributesW;
+        use winapi::um::winnt::{FILE_ATTRIBUTE_HIDDEN, FILE_ATTRIBUTE_SYSTEM};
+
+        const PRESERVED: u32 = FILE_ATTRIBUTE_HIDDEN | FILE_ATTRIBUTE_SYSTEM;
+
+        let src_attributes = std::fs::symlink_metadata(src)?.file_attributes();
+        let dest_attributes = std::fs::symlink_metadata(dest)?.file_attributes();
+        let attributes = (dest_attributes & !PRESERVED) | (src_attributes & PRESERVED);
+
+        if attributes != dest_attributes {
+            let wide: Vec<u16> = dest.as_os_str().encode_wide().chain(Some(0)).collect();
+            // Safe since `wide` is a valid null-terminated wide string
+            if unsafe { SetFileAttributesW(wide.as_ptr(), attributes) } == 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+    }
+
+    #[cfg(not(target_family = "windows"))]
+    let _ = (src, dest);
+
+    Ok(())
+}
+
+/// Sets the modification time of the file `path` to `modified`
+///
+/// The file is not opened for writing, so that this also works for read-only files
+pub fn set_modified(path: &Path, modified: SystemTime) -> Result<(), io::Error> {
+    let mut options = std::fs::OpenOptions::new();
+
+    #[cfg(target_family = "windows")]
+    {
+        use std::os::windows::fs::OpenOptionsExt;
+        use winapi::um::winnt::FILE_WRITE_ATTRIBUTES;
+
+        options.access_mode(FILE_WRITE_ATTRIBUTES);
+    }
+
+    #[cfg(not(target_family = "windows"))]
+    options.read(true);
+
+    options.open(path)?.set_modified(modified)
+}
+
+/// Gets the device of the filesystem that `path` is on, following symlinks
+///
+/// Devices are not exposed on Windows, so an error is returned there
+pub fn device(path: &Path) -> Result<u64, io::Error> {
+    #[cfg(target_family = "unix")]
+    {
+        use std::os::unix::fs::MetadataExt;
+
+        std::fs::metadata(path).map(|metadata| metadata.dev())
+    }
+
+    #[cfg(not(target_family = "unix"))]
+    {
+        let _ = path;
+        Err(io::Error::other(
+            "devices are not supported on this platform",
+        ))
+    }
+}
+
+/// Gets the longest name, and the longest path, that the filesystem of the directory `path` allows
+/// for its entries, in bytes, or in UTF-16 units on Windows
+///
+/// Limits that the filesystem does not have, or does not report, are `usize::MAX`. On Windows,
+/// paths are given in their extended-length form, see `long_path`, which NTFS allows to be 32767
+/// units long.
+pub fn path_limits(path: &Path) -> (usize, usize) {
+    #[cfg(target_family = "unix")]
+    {
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+
+        let limit = |name| match CString::new(path.as_os_str().as_bytes()) {
+            Ok(path) => match unsafe { libc::pathconf(path.as_ptr(), name) } {
+                limit if limit > 0 => limit as usize,
+                _ => usize::MAX,
+            },
+            Err(_) => usize::MAX,
+        };
+        // PATH_MAX counts the terminating NUL
+        let path_max = limit(libc::_PC_PATH_MAX);
+        (limit(libc::_PC_NAME_MAX), path_max.saturating_sub(1))
+    }
+
+    #[cfg(target_family = "windows")]
+    {
+        let _ = path;
+        (255, 32767)
+    }
+
+    #[cfg(not(any(target_family = "unix", target_family = "windows")))]
+    {
+        let _ = path;
+        (usize::MAX, usize::MAX)
+    }
+}
+
+/// Gets the number of bytes that are free for this user on the filesystem of `path`
+///
+/// This is done with `statvfs` on Unix, and `GetDiskFreeSpaceExW` on Windows, which both leave
+/// out the space reserved for the superuser, and quotas on Windows.
+pub fn free_space(path: &Path) -> Result<u64, io::Error> {
+    #[cfg(target_family = "unix")]
+    {
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = CString::new(path.as_os_str().as_bytes())?;
+        // This is safe to zero, since `statvfs` is a plain C struct
+        let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+        // Safe since `path` is null-terminated, and `stat` is valid for the duration of the call
+        match unsafe { libc::statvfs(path.as_ptr(), &mut stat) } {
+            -1 => Err(io::Error::last_os_error()),
+            _ => Ok((stat.f_bavail as u64).saturating_mul(stat.f_frsize as u64)),
+        }
+    }
+
+    #[cfg(target_family = "windows")]
+    {
+        use std::os::windows::ffi::OsStrExt;
+        use winapi::um::fileapi::GetDiskFreeSpaceExW;
+        use winapi::um::winnt::ULARGE_INTEGER;
+
+        let wide: Vec<u16> = path.as_os_str().encode_wide().chain(Some(0)).collect();
+        // This is safe to zero, since the union only holds a 64-bit integer
+        let mut free: ULARGE_INTEGER = unsafe { std::mem::zeroed() };
+        // Safe since `wide` is null-terminated, and `free` is valid for the duration of the call
+        match unsafe {
+            GetDiskFreeSpaceExW(
+                wide.as_ptr(),
+                &mut free,
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+            )
+        } {
+            0 => Err(io::Error::last_os_error()),
+            _ => Ok(unsafe { *free.QuadPart() }),
+        }
+    }
+
+    #[cfg(not(any(target_family = "unix", target_family = "windows")))]
+    {
+        let _ = path;
+        Err(io::Error::other(
+            "free space is not reported on this platform",
+        ))
+    }
+}
+
+/// Flushes the entries of the directory `path` to disk, so that files created in it survive a
+/// crash
+///
+/// Directories can not be flushed on Windows, where NTFS journals its metadata instead, so
+/// nothing is done there
+pub fn sync_dir(path: &Path) -> Result<(), io::Error> {
+    #[cfg(not(target_family = "windows"))]
+    return std::fs::File::open(path)?.sync_all();
+
+    #[cfg(target_family = "windows")]
+    {
+        let _ = path;
+        Ok(())
+    }
+}
+
+/// Reserves `len` bytes on disk for the empty file `file`, without changing its size, so that it
+/// is written in few extents, and so that a copy that does not fit fails before it is written
+///
+/// This is done with `fallocate` on Linux, `F_PREALLOCATE` on macOS, and the allocation size of
+/// the file on Windows. Filesystems that can not preallocate space are left to allocate it as the
+/// file is written, so only a lack of space is an error.
+pub fn preallocate(file: &std::fs::File, len: u64) -> Result<(), io::Error> {
+    // Space that could not be reserved for other reasons is allocated as the file is written
+    let lacks_space = |e: io::Error| match e.raw_os_error() {
+        #[cfg(target_family = "unix")]
+        Some(libc::ENOSPC) | Some(libc::EDQUOT) | Some(libc::EFBIG) => Err(e),
+        // ERROR_HANDLE_DISK_FULL and ERROR_DISK_FULL
+        #[cfg(target_family = "windows")]
+        Some(39) | Some(112) => Err(e),
+        _ => Ok(()),
+    };
+    if len == 0 {
+        return Ok(());
+    }
+
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::io::AsRawFd;
+
+        // Safe since the descriptor is valid for the duration of the call
+        match unsafe {
+            libc::fallocate(
+                file.as_raw_fd(),
+                libc::FALLOC_FL_KEEP_SIZE,
+                0,
+                len as libc::off_t,
+            )
+        } {
+            -1 => lacks_space(io::Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+
+    #[cfg(target_os = "macos")]
+    {
+        use std::os::unix::io::AsRawFd;
+
+        let mut store = libc::fstore_t {
+            fst_flags: libc::F_ALLOCATECONTIG | libc::F_ALLOCATEALL,
+            fst_posmode: libc::F_PEOFPOSMODE,
+            fst_offset: 0,
+            fst_length: len as libc::off_t,
+            fst_bytesalloc: 0,
+        };
+        // Safe since `store` is valid for the duration of the calls
+        if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_PREALLOCATE, &store) } != -1 {
+            return Ok(());
+        }
+        // Contiguous space is only preferred
+        store.fst_flags = libc::F_ALLOCATEALL;
+        match unsafe { libc::fcntl(file.as_raw_fd(), libc::F_PREALLOCATE, &store) } {
+            -1 => lacks_space(io::Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+
+    #[cfg(target_family = "windows")]
+    {
+        use std::os::windows::io::AsRawHandle;
+        use winapi::um::fileapi::{SetFileInformationByHandle, FILE_ALLOCATION_INFO};
+        use winapi::um::minwinbase::FileAllocationInfo;
+
+        // This is safe to zero, since `FILE_ALLOCATION_INFO` is a plain C struct
+        let mut info: FILE_ALLOCATION_INFO = unsafe { std::mem::zeroed() };
+        // Safe since the union only holds a 64-bit integer
+        unsafe { *info.AllocationSize.QuadPart_mut() = len as i64 };
+        // Safe since the handle and `info` are valid for the duration of the call
+        match unsafe {
+            SetFileInformationByHandle(
+                file.as_raw_handle() as _,
+                FileAllocationInfo,
+                &mut info as *mut _ as _,
+                std::mem::size_of::<FILE_ALLOCATION_INFO>() as u32,
+            )
+        } {
+            0 => lacks_space(io::Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "macos", target_family = "windows")))]
+    {
+        let _ = (file, lacks_space);
+        Ok(())
+    }
+}
+
+/// Lowers the IO priority of this process, so that its reads yield to those of everything else
+///
+/// On Linux, the process is put in the idle IO scheduling class, and on macOS, its disk IO is
+/// throttled. Other platforms have no such priority, so nothing is done there.
+pub fn lower_io_priority() -> Result<(), io::Error> {
+    #[cfg(target_os = "linux")]
+    {
+        const IOPRIO_WHO_PROCESS: libc::c_long = 1;
+        const IOPRIO_CLASS_IDLE: libc::c_long = 3;
+        const IOPRIO_CLASS_SHIFT: libc::c_long = 13;
+
+        let priority = IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT;
+        match unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, priority) } {
+            -1 => Err(io::Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+
+    #[cfg(target_os = "macos")]
+    {
+        const IOPOL_TYPE_DISK: libc::c_int = 0;
+        const IOPOL_SCOPE_PROCESS: libc::c_int = 0;
+        const IOPOL_THROTTLE: libc::c_int = 3;
+
+        extern "C" {
+            fn setiopolicy_np(
+                iotype: libc::c_int,
+                scope: libc::c_int,
+                policy: libc::c_int,
+            ) -> libc::c_int;
+        }
+        match unsafe { setiopolicy_np(IOPOL_TYPE_DISK, IOPOL_SCOPE_PROCESS, IOPOL_THROTTLE) } {
+            -1 => Err(io::Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    Ok(())
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_platform {
+    use super::*;
+
+    #[test]
+    fn extended_length_paths() {
+        assert_eq!(extended_length_path(r"C:\a\b"), r"\\?\C:\a\b");
+        assert_eq!(extended_length_path(r"C:\a\..\b/c"), r"\\?\C:\b\c");
+        assert_eq!(extended_length_path(r"C:\.\a\\b\"), r"\\?\C:\a\b");
+        assert_eq!(extended_length_path(r"C:\.."), r"\\?\C:\");
+        assert_eq!(extended_length_path(r"C:\"), r"\\?\C:\");
+        assert_eq!(extended_length_path(r"\\?\C:\a\..\b"), r"\\?\C:\a\..\b");
+        assert_eq!(
+            extended_length_path(r"\\server\share\a\..\b"),
+            r"\\?\UNC\server\share\b"
+        );
+        assert_eq!(
+            extended_length_path(r"\\server\share\..\.."),
+            r"\\?\UNC\server\share"
+        );
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn unix_paths_unchanged() {
+        assert_eq!(long_path(Path::new("a/../b")), PathBuf::from("a/../b"));
+        assert!(clear_readonly(Path::new("/?")).is_ok());
+        assert!(copy_attributes(Path::new("/?"), Path::new("/?")).is_ok());
+    }
+
+    #[test]
+    fn preallocates_without_growing() {
+        const TEST_FILE: &str = "test_platform_preallocates_without_growing";
+
+        let file = std::fs::File::create(TEST_FILE).unwrap();
+        preallocate(&file, 1 << 20).unwrap();
+        assert_eq!(file.metadata().unwrap().len(), 0);
+
+        // More than any filesystem holds fails, unless the filesystem can not preallocate at all
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::unix::fs::MetadataExt;
+
+            if file.metadata().unwrap().blocks() > 0 {
+                assert!(preallocate(&file, 1 << 62).is_err());
+            }
+        }
+
+        std::fs::remove_file(TEST_FILE).unwrap();
+    }
+}
@@ -0,0 +1,183 @@
+//! Previews how symlink rewrites and materialization map source entries into the destination,
+//! and asks whether to go on, before anything is written
+//!
+//! A misconfigured `--link-rewrite` or materialization rule would otherwise only show once
+//! thousands of entries were written with it.
+
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+
+use hashbrown::{HashMap, HashSet};
+
+use crate::lumins::file_ops::{File, FileOps, FileSets, Symlink};
+
+/// Finds every entry that is written to the destination differently than it is in the source,
+/// sorted by path
+///
+/// # Arguments
+/// * `symlinks`: symlinks of the source, before their targets were rewritten
+/// * `file_sets`: file sets of the source, after they were rewritten and materialized
+/// * `placeholders`: empty placeholders of materialized files
+///
+/// # Returns
+/// The path of every such entry, with how it is written to the destination
+pub fn mappings(
+    symlinks: &HashSet<Symlink>,
+    file_sets: &FileSets,
+    placeholders: &HashSet<File>,
+) -> Vec<(PathBuf, String)> {
+    let targets: HashMap<&PathBuf, &PathBuf> = symlinks
+        .iter()
+        .map(|symlink| (symlink.path(), symlink.target()))
+        .collect();
+
+    let mut mappings: Vec<(PathBuf, String)> = file_sets
+        .symlinks()
+        .iter()
+        .filter_map(|symlink| {
+            let mapping = match targets.get(symlink.path()) {
+                Some(target) if *target == symlink.target() => return None,
+                Some(target) => {
+                    format!("symlink to {:?}, instead of {:?}", symlink.target(), target)
+                }
+                None => format!("symlink to {:?}, instead of a copy", symlink.target()),
+            };
+            Some((symlink.path().clone(), mapping))
+        })
+        .chain(
+            placeholders
+                .iter()
+                .map(|file| (file.path().clone(), String::from("empty placeholder"))),
+        )
+        .collect();
+    mappings.sort();
+    mappings
+}
+
+/// Prints the first `limit` of `mappings` to stdout, and asks on stdin whether to go on
+///
+/// Nothing is asked if there are no mappings.
+///
+/// # Errors
+/// This function will return an error if going on was not confirmed, or could not be asked
+pub fn confirm(
+    limit: usize,
+    mappings: &[(PathBuf, String)],
+    src: &str,
+    dest: &str,
+) -> Result<(), io::Error> {
+    if mappings.is_empty() {
+        return Ok(());
+    }
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    if prompt(limit, mappings, src, dest, stdin.lock(), stdout.lock())? {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::Interrupted,
+            "Preview Error -- the mappings were not confirmed, so nothing was written",
+        ))
+    }
+}
+
+/// Writes the first `limit` of `mappings` to `output`, and reads whether to go on from `input`
+///
+/// # Returns
+/// * Ok(true): If `y` was answered
+/// * Ok(false): If anything else was answered, or `input` ended
+/// * Error: If the prompt could not be written or read
+fn prompt<R, W>(
+    limit: usize,
+    mappings: &[(PathBuf, String)],
+    src: &str,
+    dest: &str,
+    mut input: R,
+    mut output: W,
+) -> Result<bool, io::Error>
+where
+    R: BufRead,
+    W: Write,
+{
+    let shown = limit.min(mappings.len());
+    writeln!(
+        output,
+        "The first {} of {} transformed entries:",
+        shown,
+        mappings.len()
+    )?;
+    for (path, mapping) in &mappings[..shown] {
+        writeln!(
+            output,
+            "  {:?} -> {:?} ({})",
+            Path::new(src).join(path),
+            Path::new(dest).join(path),
+            mapping
+        )?;
+    }
+    write!(output, "Proceed? [y/N] ")?;
+    output.flush()?;
+
+    let mut answer = String::new();
+    input.read_line(&mut answer)?;
+    Ok(answer.trim() == "y")
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_preview {
+    use super::*;
+
+    #[test]
+    fn mappings_and_prompt() {
+        let symlinks: HashSet<Symlink> = [
+            Symlink::from("kept", "/a"),
+            Symlink::from("moved", "/old/b"),
+        ]
+        .iter()
+        .cloned()
+        .collect();
+        let file_sets = FileSets::with(
+            [File::from("copied", 1)].iter().cloned().collect(),
+            HashSet::new(),
+            [
+                Symlink::from("kept", "/a"),
+                Symlink::from("moved", "/new/b"),
+                Symlink::from("stored", "/store/stored"),
+            ]
+            .iter()
+            .cloned()
+            .collect(),
+        );
+        let placeholders = [File::from("empty", 0)].iter().cloned().collect();
+
+        let mappings = mappings(&symlinks, &file_sets, &placeholders);
+        assert_eq!(
+            mappings,
+            vec![
+                (PathBuf::from("empty"), String::from("empty placeholder")),
+                (
+                    PathBuf::from("moved"),
+                    String::from("symlink to \"/new/b\", instead of \"/old/b\"")
+                ),
+                (
+                    PathBuf::from("stored"),
+                    String::from("symlink to \"/store/stored\", instead of a copy")
+                ),
+            ]
+        );
+
+        let mut output = Vec::new();
+        assert!(prompt(1, &mappings, "src", "dest", &b"y\n"[..], &mut output).unwrap());
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "The first 1 of 3 transformed entries:\n  \"src/empty\" -> \"dest/empty\" (empty \
+             placeholder)\nProceed? [y/N] "
+        );
+        assert!(!prompt(5, &mappings, "src", "dest", &b"n\n"[..], io::sink()).unwrap());
+        assert!(!prompt(5, &mappings, "src", "dest", &b""[..], io::sink()).unwrap());
+    }
+}
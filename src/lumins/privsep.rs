@@ -0,0 +1,88 @@
+//! Copies a tree with a reader and a writer process, with `--privsep`
+//!
+//! The reader is this program run again, and only ever reads the source, which it streams to the
+//! writer as a tar archive over a pipe. The writer only ever writes the destination, and extracts
+//! the stream as it would an untrusted archive. Each is restricted to its own tree with `harden`,
+//! so that a writer that is taken over by what is on the destination media can not read anything
+//! that was not streamed to it, and a reader that is taken over by what is in the source can not
+//! write anything at all.
+
+use std::env;
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+use crate::lumins::archive::{self, TarWriter};
+use crate::lumins::harden;
+use crate::lumins::parse::Options;
+
+/// Name of the hidden subcommand that runs the reader
+pub const READER: &str = "privsep-reader";
+
+/// Copies `src` into `dest`, with `src` read by a reader process of its own
+///
+/// The reader is started before the process is restricted, since no programs can be run after.
+///
+/// # Arguments
+/// * `src`: directory to copy
+/// * `dest`: directory to copy into
+/// * `options`: Options of the run
+/// * `writable`: directories that the writer writes to besides `dest`, see
+///   `harden::writable_dirs`
+///
+/// # Errors
+/// This function will return an error if the reader could not be started or failed, if the
+/// writer could not be restricted, or if any entry could not be extracted
+pub fn copy(
+    src: &str,
+    dest: &str,
+    options: &Options,
+    writable: &[PathBuf],
+) -> Result<(), io::Error> {
+    let privsep_error = |e: io::Error| io::Error::new(e.kind(), format!("Privsep Error -- {}", e));
+
+    let mut reader = Command::new(env::current_exe().map_err(privsep_error)?)
+        .args([READER, src])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| {
+            privsep_error(io::Error::new(
+                e.kind(),
+                format!("Starting the reader: {}", e),
+            ))
+        })?;
+    // Safe to unwrap since stdout is piped
+    let stream = reader.stdout.take().unwrap();
+
+    let mut writable = writable.to_vec();
+    writable.push(PathBuf::from(dest));
+    let extracted = harden::harden::<PathBuf, PathBuf>(&[], &writable).and_then(|_| {
+        archive::extract_stream(stream, &format!("the stream of {}", src), dest, options)
+    });
+
+    // The stream is closed by now, so a reader that is still writing stops
+    let status = reader.wait().map_err(privsep_error)?;
+    if !status.success() {
+        return Err(privsep_error(io::Error::other(format!(
+            "the reader of {:?} failed, so {:?} is incomplete",
+            src, dest
+        ))));
+    }
+    extracted
+}
+
+/// Writes `src` as a tar archive to stdout, as the reader of `copy`
+///
+/// # Errors
+/// This function will return an error if the reader could not be restricted, or if `src` could
+/// not be read or written completely
+pub fn read(src: &str) -> Result<(), io::Error> {
+    harden::harden::<&str, &str>(&[src], &[])?;
+
+    let stdout = io::stdout();
+    let mut tar = TarWriter::new(BufWriter::new(stdout.lock()), None);
+    archive::write_tree(src, &mut tar)
+        .and_then(|_| tar.finish())
+        .and_then(|mut writer| writer.flush())
+}
@@ -1,8 +1,14 @@
 //! Keeps track of LuminS' progress
 
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+
 use indicatif::{ProgressBar, ProgressStyle};
 use lazy_static::lazy_static;
 
+use crate::lumins::fidelity::Degradation;
+
 lazy_static! {
     /// Provides a bar that shows the number of files
     /// copied, synchronized, or deleted, out of the total number of files
@@ -14,6 +20,118 @@ lazy_static! {
         );
         progress_bar
     };
+
+    /// Counts what the current run has done, for its summary in the history
+    pub static ref STATS: Stats = Stats::default();
+}
+
+/// Counters of the files that a run copied and deleted, and of the errors it logged
+#[derive(Debug, Default)]
+pub struct Stats {
+    files_copied: AtomicU64,
+    bytes_copied: AtomicU64,
+    files_deleted: AtomicU64,
+    errors: AtomicU64,
+    /// Whether to keep the messages of errors, for the report at the end of a run
+    keeps_errors: AtomicBool,
+    messages: Mutex<Vec<String>>,
+    /// Metadata that could not be preserved, for the fidelity report at the end of a run
+    degradations: Mutex<Vec<Degradation>>,
+    /// Number of errors after which the run stops
+    max_errors: Mutex<Option<u64>>,
+    stopped: AtomicBool,
+}
+
+impl Stats {
+    /// Counts a copied file of `bytes` bytes
+    pub fn add_copied(&self, bytes: u64) {
+        self.files_copied.fetch_add(1, Ordering::Relaxed);
+        self.bytes_copied.fetch_add(bytes, Ordering::Relaxed);
+    }
+    /// Counts a deleted file
+    pub fn add_deleted(&self) {
+        self.files_deleted.fetch_add(1, Ordering::Relaxed);
+    }
+    /// Counts an error, and keeps its `message` if errors are kept
+    ///
+    /// The run is stopped once there are more errors than the maximum.
+    pub fn add_error(&self, message: String) {
+        let errors = self.errors.fetch_add(1, Ordering::Relaxed) + 1;
+        if self.keeps_errors.load(Ordering::Relaxed) {
+            self.messages.lock().unwrap().push(message);
+        }
+        if let Some(max_errors) = *self.max_errors.lock().unwrap() {
+            if errors > max_errors {
+                self.stopped.store(true, Ordering::Relaxed);
+            }
+        }
+    }
+    /// Keeps metadata that could not be preserved, see `fidelity::record`
+    pub fn add_degradation(&self, degradation: Degradation) {
+        self.degradations.lock().unwrap().push(degradation);
+    }
+    /// Keeps the messages of errors from now on if `keeps_errors` is set, see `error_messages`
+    pub fn set_keeps_errors(&self, keeps_errors: bool) {
+        self.keeps_errors.store(keeps_errors, Ordering::Relaxed);
+    }
+    /// Stops the run once there are more than `max_errors` errors, if there is a maximum, see
+    /// `stopped`
+    pub fn set_max_errors(&self, max_errors: Option<u64>) {
+        *self.max_errors.lock().unwrap() = max_errors;
+    }
+    /// Sets every counter back to 0, at the start of a run
+    pub fn reset(&self) {
+        self.files_copied.store(0, Ordering::Relaxed);
+        self.bytes_copied.store(0, Ordering::Relaxed);
+        self.files_deleted.store(0, Ordering::Relaxed);
+        self.errors.store(0, Ordering::Relaxed);
+        self.messages.lock().unwrap().clear();
+        self.degradations.lock().unwrap().clear();
+        self.stopped.store(false, Ordering::Relaxed);
+    }
+    /// Gets the number of files copied
+    pub fn files_copied(&self) -> u64 {
+        self.files_copied.load(Ordering::Relaxed)
+    }
+    /// Gets the number of bytes copied
+    pub fn bytes_copied(&self) -> u64 {
+        self.bytes_copied.load(Ordering::Relaxed)
+    }
+    /// Gets the number of files deleted
+    pub fn files_deleted(&self) -> u64 {
+        self.files_deleted.load(Ordering::Relaxed)
+    }
+    /// Gets the number of errors
+    pub fn errors(&self) -> u64 {
+        self.errors.load(Ordering::Relaxed)
+    }
+    /// Gets the messages of the errors, if errors are kept
+    pub fn error_messages(&self) -> Vec<String> {
+        self.messages.lock().unwrap().clone()
+    }
+    /// Gets the metadata that could not be preserved
+    pub fn degradations(&self) -> Vec<Degradation> {
+        self.degradations.lock().unwrap().clone()
+    }
+    /// Checks whether the run has more errors than the maximum, so that the files that are left
+    /// are skipped
+    pub fn stopped(&self) -> bool {
+        self.stopped.load(Ordering::Relaxed)
+    }
+}
+
+/// Checks that the run was not stopped for having too many errors, before it finishes
+///
+/// # Errors
+/// This function will return an error if the run has more errors than the maximum
+pub fn check_stopped() -> Result<(), io::Error> {
+    if STATS.stopped() {
+        return Err(io::Error::other(format!(
+            "Error -- Stopped after {} errors, more than --max-errors allows",
+            STATS.errors()
+        )));
+    }
+    Ok(())
 }
 
 /// Initializes PROGRESS_BAR with `length` and sets draw delta
@@ -24,3 +142,33 @@ pub fn progress_init(length: u64) {
     PROGRESS_BAR.set_draw_delta(length / 1000);
     PROGRESS_BAR.set_position(0);
 }
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_stats {
+    use super::*;
+
+    #[test]
+    fn errors() {
+        let stats = Stats::default();
+        stats.add_error("ignored".to_string());
+        assert!(stats.error_messages().is_empty());
+
+        stats.set_keeps_errors(true);
+        stats.set_max_errors(Some(2));
+        stats.add_error("a".to_string());
+        assert!(!stats.stopped());
+        stats.add_error("b".to_string());
+        assert_eq!(stats.errors(), 3);
+        assert_eq!(stats.error_messages(), vec!["a", "b"]);
+        assert!(stats.stopped());
+
+        stats.reset();
+        assert_eq!(stats.errors(), 0);
+        assert!(stats.error_messages().is_empty());
+        assert!(!stats.stopped());
+    }
+}
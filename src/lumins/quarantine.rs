@@ -0,0 +1,193 @@
+//! Keeps the destination files that a run replaces or deletes in a quarantine directory, until
+//! the run has completed
+//!
+//! Every run gets its own quarantine directory, in the root of the destination, so that files are
+//! moved into it with renames on the same filesystem. It is purged once the run completes without
+//! errors, and is otherwise left in place with everything the run took out of the destination.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{error, info, warn};
+use rayon::prelude::*;
+
+use crate::lumins::dirfd;
+use crate::lumins::file_ops::{FileOps, FileSets};
+use crate::progress::{PROGRESS_BAR, STATS};
+
+/// Prefix of the names of quarantine directories, in the root of the destination
+pub const QUARANTINE_PREFIX: &str = ".lumins-quarantine-";
+
+/// The quarantine directory of one run
+#[derive(Debug)]
+pub struct Quarantine {
+    dest: PathBuf,
+    name: PathBuf,
+}
+
+impl Quarantine {
+    /// Creates a new quarantine directory in `dest`, named after the time and the process
+    ///
+    /// # Errors
+    /// This function will return an error if the directory can not be created
+    pub fn create(dest: &str) -> Result<Self, io::Error> {
+        let secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_secs());
+        let name = PathBuf::from(format!("{}{}-{}", QUARANTINE_PREFIX, secs, process::id()));
+        let dest = PathBuf::from(dest);
+        fs::create_dir_all(dest.join(&name)).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Quarantine Error -- {:?}: {}", dest.join(&name), e),
+            )
+        })?;
+        Ok(Quarantine { dest, name })
+    }
+
+    /// Gets the path of the quarantine directory
+    pub fn path(&self) -> PathBuf {
+        self.dest.join(&self.name)
+    }
+
+    /// Moves `relative` out of the destination into the quarantine directory, at the same path
+    /// inside it
+    ///
+    /// Nothing is moved if `relative` does not exist.
+    ///
+    /// # Returns
+    /// Whether `relative` is no longer in the destination
+    pub fn keep(&self, relative: &Path) -> bool {
+        let kept = self.name.join(relative);
+        // Look for `relative` first, so that no directories are made for what does not exist
+        let moved = dirfd::open_parent(&self.dest, relative, false)
+            .and_then(|(parent, name)| parent.kind(&name))
+            .and_then(|_| match kept.parent() {
+                Some(parent) => dirfd::create_dir_all(&self.dest, parent),
+                None => Ok(()),
+            })
+            .and_then(|_| dirfd::rename(&self.dest, relative, &kept));
+        match moved {
+            Ok(_) => {
+                info!(
+                    "Quarantining {:?} -> {:?}",
+                    self.dest.join(relative),
+                    self.dest.join(kept)
+                );
+                true
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => true,
+            Err(e) => {
+                error!(
+                    "Quarantine Error -- Moving {:?}: {}",
+                    self.dest.join(relative),
+                    e
+                );
+                false
+            }
+        }
+    }
+
+    /// Moves all given files out of the destination into the quarantine directory, in parallel,
+    /// instead of deleting them
+    pub fn keep_files<'a, T, S>(&self, files_to_keep: T)
+    where
+        T: ParallelIterator<Item = &'a S>,
+        S: FileOps + Sync + 'a,
+    {
+        files_to_keep.for_each(|file| {
+            if STATS.stopped() {
+                return;
+            }
+            if self.keep(file.path()) {
+                STATS.add_deleted();
+            }
+            PROGRESS_BAR.inc(1);
+        });
+    }
+
+    /// Ends the run, purging the quarantine directory if the run had no errors
+    ///
+    /// # Errors
+    /// This function will return an error if the directory could not be purged
+    pub fn finish(&self) -> Result<(), io::Error> {
+        if STATS.errors() > 0 {
+            // An empty quarantine has nothing to recover
+            if fs::remove_dir(self.path()).is_err() {
+                warn!("Keeping replaced files in {:?}", self.path());
+            }
+            return Ok(());
+        }
+        fs::remove_dir_all(self.path()).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Quarantine Error -- Purging {:?}: {}", self.path(), e),
+            )
+        })
+    }
+}
+
+/// Removes the quarantine directories of this and earlier runs from `file_sets` of a destination,
+/// so that they are neither synchronized nor deleted
+pub fn unlist(file_sets: &mut FileSets) {
+    file_sets.retain_paths(|path| !is_quarantined(path));
+}
+
+/// Checks whether `path`, relative to the root of a destination, is in a quarantine directory
+fn is_quarantined(path: &Path) -> bool {
+    path.iter()
+        .next()
+        .is_some_and(|first| first.to_string_lossy().starts_with(QUARANTINE_PREFIX))
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_quarantine {
+    use super::*;
+    use crate::lumins::file_ops::{self, File};
+
+    #[test]
+    fn quarantined_paths() {
+        assert!(is_quarantined(Path::new(".lumins-quarantine-1-2")));
+        assert!(is_quarantined(Path::new(".lumins-quarantine-1-2/a/b")));
+        assert!(!is_quarantined(Path::new("a/.lumins-quarantine-1-2")));
+        assert!(!is_quarantined(Path::new("")));
+    }
+
+    #[test]
+    fn keep() {
+        const TEST_DIR: &str = "test_quarantine_keep";
+        let path = |path: &str| [TEST_DIR, path].join("/");
+
+        fs::create_dir_all(path("a/b")).unwrap();
+        fs::write(path("a/b/c"), b"c").unwrap();
+        fs::write(path("d"), b"d").unwrap();
+
+        let quarantine = Quarantine::create(TEST_DIR).unwrap();
+        let files = [
+            File::from("a/b/c", 1),
+            File::from("d", 1),
+            File::from("e/f", 0),
+        ];
+        quarantine.keep_files(files.par_iter());
+
+        assert!(fs::metadata(path("a/b/c")).is_err());
+        assert!(fs::metadata(path("d")).is_err());
+        assert_eq!(fs::read(quarantine.path().join("a/b/c")).unwrap(), b"c");
+        assert_eq!(fs::read(quarantine.path().join("d")).unwrap(), b"d");
+        assert!(fs::metadata(quarantine.path().join("e")).is_err());
+
+        let mut file_sets = file_ops::get_all_files(TEST_DIR).unwrap();
+        unlist(&mut file_sets);
+        assert_eq!(file_sets.files().len(), 0);
+        assert_eq!(file_sets.dirs().len(), 2);
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+}
@@ -0,0 +1,148 @@
+//! Retries operations that fail with errors that are likely to go away, such as those of network
+//! shares that are briefly unavailable
+
+use std::fmt::Debug;
+use std::io;
+use std::thread;
+use std::time::Duration;
+
+use log::info;
+
+/// Longest time to wait before a retry, however many retries came before it
+const MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// A struct that represents how often, and after how long, failed operations are retried
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub struct Retry {
+    /// Number of times to retry an operation, after it first failed
+    pub retries: u32,
+    /// Time to wait before the first retry, which doubles with every retry after it
+    pub delay: Duration,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Retry {
+            retries: 0,
+            delay: Duration::from_secs(1),
+        }
+    }
+}
+
+impl Retry {
+    /// Runs `operation` until it succeeds, fails with an error that is not transient, or has
+    /// been retried `retries` times, waiting twice as long before each retry as before the last
+    ///
+    /// # Arguments
+    /// * `what`: What the operation works on, to log retries with
+    /// * `operation`: The operation to run
+    ///
+    /// # Returns
+    /// The result of the last run of `operation`
+    pub fn run<T, W, F>(&self, what: W, mut operation: F) -> Result<T, io::Error>
+    where
+        W: Debug,
+        F: FnMut() -> Result<T, io::Error>,
+    {
+        let mut delay = self.delay;
+        for _ in 0..self.retries {
+            match operation() {
+                Err(ref e) if is_transient(e) => {
+                    info!("Retrying {:?} in {:?}: {}", what, delay, e);
+                    thread::sleep(delay);
+                    delay = (delay * 2).min(MAX_DELAY);
+                }
+                result => return result,
+            }
+        }
+        operation()
+    }
+}
+
+/// Checks whether `error` is likely to go away if the operation is retried
+pub fn is_transient(error: &io::Error) -> bool {
+    match error.kind() {
+        io::ErrorKind::WouldBlock
+        | io::ErrorKind::Interrupted
+        | io::ErrorKind::TimedOut
+        | io::ErrorKind::ConnectionReset
+        | io::ErrorKind::ConnectionAborted => return true,
+        _ => {}
+    }
+
+    #[cfg(target_family = "unix")]
+    const TRANSIENT: &[i32] = &[
+        libc::EAGAIN,
+        libc::EBUSY,
+        libc::EINTR,
+        libc::ETIMEDOUT,
+        libc::ENETDOWN,
+        libc::ENETUNREACH,
+        libc::ENETRESET,
+        libc::ECONNRESET,
+        libc::ECONNABORTED,
+        libc::EHOSTUNREACH,
+    ];
+    // ERROR_SHARING_VIOLATION, ERROR_LOCK_VIOLATION, ERROR_UNEXP_NET_ERR, ERROR_NETNAME_DELETED,
+    // ERROR_SEM_TIMEOUT
+    #[cfg(target_family = "windows")]
+    const TRANSIENT: &[i32] = &[32, 33, 59, 64, 121];
+
+    error
+        .raw_os_error()
+        .is_some_and(|code| TRANSIENT.contains(&code))
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_retry {
+    use super::*;
+
+    #[test]
+    fn transient() {
+        assert!(is_transient(&io::Error::from(io::ErrorKind::Interrupted)));
+        assert!(!is_transient(&io::Error::from(io::ErrorKind::NotFound)));
+        #[cfg(target_family = "unix")]
+        {
+            assert!(is_transient(&io::Error::from_raw_os_error(libc::EBUSY)));
+            assert!(!is_transient(&io::Error::from_raw_os_error(libc::EACCES)));
+        }
+    }
+
+    #[test]
+    fn retries() {
+        let retry = Retry {
+            retries: 3,
+            delay: Duration::from_millis(1),
+        };
+        let run = |failures: usize, kind: io::ErrorKind| {
+            let mut runs = 0;
+            let result = retry.run("test", || {
+                runs += 1;
+                if runs <= failures {
+                    Err(io::Error::from(kind))
+                } else {
+                    Ok(runs)
+                }
+            });
+            (result.is_ok(), runs)
+        };
+
+        assert_eq!(run(0, io::ErrorKind::TimedOut), (true, 1));
+        assert_eq!(run(3, io::ErrorKind::TimedOut), (true, 4));
+        assert_eq!(run(4, io::ErrorKind::TimedOut), (false, 4));
+        assert_eq!(run(4, io::ErrorKind::NotFound), (false, 1));
+
+        // Nothing is retried by default
+        let mut runs = 0;
+        let result: Result<(), io::Error> = Retry::default().run("test", || {
+            runs += 1;
+            Err(io::Error::from(io::ErrorKind::TimedOut))
+        });
+        assert!(result.is_err());
+        assert_eq!(runs, 1);
+    }
+}
@@ -0,0 +1,1238 @@
+//! Reviews the changes that a sync would make, one at a time, like `git add -p`, or prints them
+//! for a dry run
+//!
+//! In a terminal, every pending create, update and delete is listed on a full screen, where each
+//! can be selected, approved, skipped, or inspected, see `Screen`. Otherwise, each is shown with
+//! a prompt on a line of its own. Nothing is applied until every change has been reviewed, so
+//! quitting part way through leaves the destination as it was.
+
+use std::fmt;
+use std::fs;
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use console::{Key, Term};
+use hashbrown::{HashMap, HashSet};
+use indicatif::ProgressDrawTarget;
+use log::{error, info, warn};
+
+use crate::lumins::core::{self, Listing};
+use crate::lumins::dirfd::EntryKind;
+use crate::lumins::dirlinks;
+use crate::lumins::file_ops::{self, Dir, File, FileOps, FileSets, Special, Symlink};
+use crate::lumins::history;
+use crate::lumins::limits::{self, Kind, Shortened};
+use crate::lumins::materialize::Placeholder;
+use crate::lumins::parse::Options;
+use crate::lumins::tree::FileTree;
+use crate::progress::PROGRESS_BAR;
+
+/// Enum to represent an entry of the source or destination
+#[derive(Clone, Copy)]
+enum Item<'a> {
+    File(&'a File),
+    Dir(&'a Dir),
+    Symlink(&'a Symlink),
+    Special(&'a Special),
+    /// A file of the source that is written as an empty placeholder, see `materialize`
+    Placeholder(&'a File),
+}
+
+impl<'a> Item<'a> {
+    fn path(&self) -> &'a Path {
+        match self {
+            Item::File(file) | Item::Placeholder(file) => file.path(),
+            Item::Dir(dir) => dir.path(),
+            Item::Symlink(symlink) => symlink.path(),
+            Item::Special(special) => special.path(),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Item::File(_) => "file",
+            Item::Dir(_) => "dir",
+            Item::Symlink(_) => "symlink",
+            Item::Special(_) => "special file",
+            Item::Placeholder(_) => "placeholder",
+        }
+    }
+
+    fn copy(&self, src: &str, dest: &str, options: &Options) -> bool {
+        let (src, dest) = (Path::new(src), Path::new(dest));
+        match self {
+            Item::File(file) => file.copy(src, dest, options),
+            Item::Dir(dir) => dir.copy(src, dest, options),
+            Item::Symlink(symlink) => symlink.copy(src, dest, options),
+            Item::Special(special) => special.copy(src, dest, options),
+            Item::Placeholder(file) => Placeholder::from(*file).copy(src, dest, options),
+        }
+    }
+
+    fn remove(&self, location: &str) {
+        let location = Path::new(location);
+        match self {
+            Item::File(file) | Item::Placeholder(file) => file.remove(location),
+            Item::Dir(dir) => dir.remove(location),
+            Item::Symlink(symlink) => symlink.remove(location),
+            Item::Special(special) => special.remove(location),
+        }
+    }
+}
+
+impl fmt::Display for Item<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {:?}", self.name(), self.path())?;
+        match self {
+            Item::File(file) => write!(f, " ({} bytes)", file.size()),
+            Item::Dir(_) | Item::Special(_) | Item::Placeholder(_) => Ok(()),
+            Item::Symlink(symlink) => write!(f, " -> {:?}", symlink.target()),
+        }
+    }
+}
+
+/// Enum to represent what a change does to the destination
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+enum Action {
+    Create,
+    Update,
+    Delete,
+}
+
+/// A pending change to one entry of the destination
+///
+/// Creates and updates carry the source entry that is copied, and deletes carry the destination
+/// entry that is removed. Entries that are written under other names carry how they are written,
+/// see `limits::write_shortened`.
+struct Change<'a> {
+    action: Action,
+    item: Item<'a>,
+    shortened: Option<&'a Shortened>,
+}
+
+impl<'a> Change<'a> {
+    fn new(action: Action, item: Item<'a>) -> Self {
+        Change {
+            action,
+            item,
+            shortened: None,
+        }
+    }
+
+    /// Gets the path of the entry of the destination that is changed
+    fn dest_path(&self) -> PathBuf {
+        match self.shortened {
+            Some(shortened) => shortened.dest_path(),
+            None => self.item.path().to_path_buf(),
+        }
+    }
+}
+
+impl fmt::Display for Change<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let action = match self.action {
+            Action::Create => "Create",
+            Action::Update => "Update",
+            Action::Delete => "Delete",
+        };
+        write!(f, "{} {}", action, self.item)?;
+        match self.shortened {
+            Some(shortened) => write!(f, " as {:?}", shortened.dest_path()),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Enum to represent the answer to the prompt of a change
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+enum Answer {
+    Yes,
+    No,
+    Inspect,
+    All,
+    Done,
+    Quit,
+    Help,
+}
+
+impl Answer {
+    fn parse(line: &str) -> Option<Self> {
+        match line.trim() {
+            "y" => Some(Answer::Yes),
+            "n" => Some(Answer::No),
+            "i" => Some(Answer::Inspect),
+            "a" => Some(Answer::All),
+            "d" => Some(Answer::Done),
+            "q" => Some(Answer::Quit),
+            "?" => Some(Answer::Help),
+            _ => None,
+        }
+    }
+}
+
+/// Help for the full screen of changes
+const SCREEN_HELP: &str = "up, down, k, j - select a change
+y - apply the selected change
+n - skip the selected change
+i, tab - inspect both sides of the selected change
+a - apply every change that is not answered yet
+d - skip every change that is not answered yet
+enter - apply the approved changes, once every change is answered
+q, esc - quit without applying anything
+? - hide this help";
+
+/// What the full screen of changes shows, and how it has been answered
+struct Screen {
+    /// Whether each change is approved, which is none until it has been answered
+    answers: Vec<Option<bool>>,
+    /// The change that is selected
+    selected: usize,
+    /// The first change that is shown
+    top: usize,
+    /// Whether both sides of the selected change are shown
+    inspecting: bool,
+    /// Whether `SCREEN_HELP` is shown
+    help: bool,
+    /// What the last key did not do, such as applying changes that are not all answered
+    status: Option<String>,
+}
+
+/// Enum to represent what the full screen of changes does after a key, see `Screen::handle`
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+enum Outcome {
+    Continue,
+    Apply,
+    Quit,
+}
+
+impl Screen {
+    fn new(count: usize) -> Self {
+        Screen {
+            answers: vec![None; count],
+            selected: 0,
+            top: 0,
+            inspecting: false,
+            help: false,
+            status: None,
+        }
+    }
+
+    fn select(&mut self, selected: usize) {
+        self.selected = selected.min(self.answers.len().saturating_sub(1));
+    }
+
+    /// Answers the selected change, and selects the next one
+    fn answer(&mut self, approved: bool) {
+        self.answers[self.selected] = Some(approved);
+        self.select(self.selected + 1);
+    }
+
+    /// Answers every change that is not answered yet
+    fn answer_rest(&mut self, approved: bool) {
+        for answer in self.answers.iter_mut().filter(|answer| answer.is_none()) {
+            *answer = Some(approved);
+        }
+    }
+
+    /// Handles `key`, which changes what is shown, answers changes, or ends the review
+    fn handle(&mut self, key: Key) -> Outcome {
+        self.status = None;
+        match key {
+            Key::ArrowUp | Key::Char('k') => self.select(self.selected.saturating_sub(1)),
+            Key::ArrowDown | Key::Char('j') => self.select(self.selected + 1),
+            Key::Home => self.select(0),
+            Key::End => self.select(self.answers.len()),
+            Key::Char('y') => self.answer(true),
+            Key::Char('n') => self.answer(false),
+            Key::Char('i') | Key::Tab => self.inspecting = !self.inspecting,
+            Key::Char('a') => self.answer_rest(true),
+            Key::Char('d') => self.answer_rest(false),
+            Key::Char('?') => self.help = !self.help,
+            Key::Char('q') | Key::Escape => return Outcome::Quit,
+            Key::Enter => match self.answers.iter().position(Option::is_none) {
+                Some(unanswered) => {
+                    let count = self
+                        .answers
+                        .iter()
+                        .filter(|answer| answer.is_none())
+                        .count();
+                    self.status = Some(format!("{} changes are not answered yet", count));
+                    self.select(unanswered);
+                }
+                None => return Outcome::Apply,
+            },
+            _ => self.status = Some(String::from("Press ? for help")),
+        }
+        Outcome::Continue
+    }
+
+    /// Gets the lines of the screen for `changes`, in a terminal `height` lines high, scrolled so
+    /// that the selected change is shown
+    fn lines(
+        &mut self,
+        changes: &[Change],
+        trees: &(FileTree, FileTree),
+        height: usize,
+    ) -> io::Result<Vec<String>> {
+        let count = |answer: Option<bool>| self.answers.iter().filter(|a| **a == answer).count();
+        let mut lines = vec![format!(
+            "{} changes: {} approved, {} skipped, {} not answered",
+            changes.len(),
+            count(Some(true)),
+            count(Some(false)),
+            count(None)
+        )];
+
+        let mut inspected = Vec::new();
+        if self.inspecting {
+            inspect(&changes[self.selected], trees, &mut inspected)?;
+        }
+        let inspected = String::from_utf8_lossy(&inspected).into_owned();
+        let help = if self.help {
+            SCREEN_HELP.lines().count()
+        } else {
+            0
+        };
+        let rows = height
+            .saturating_sub(2 + help + inspected.lines().count())
+            .max(1);
+        if self.selected < self.top {
+            self.top = self.selected;
+        } else if self.selected >= self.top + rows {
+            self.top = self.selected + 1 - rows;
+        }
+
+        for (i, change) in changes.iter().enumerate().skip(self.top).take(rows) {
+            let answer = match self.answers[i] {
+                Some(true) => "[y]",
+                Some(false) => "[n]",
+                None => "[ ]",
+            };
+            let cursor = if i == self.selected { ">" } else { " " };
+            lines.push(format!("{} {} {}", cursor, answer, change));
+            if i == self.selected {
+                lines.extend(inspected.lines().map(String::from));
+            }
+        }
+        if self.help {
+            lines.extend(SCREEN_HELP.lines().map(String::from));
+        }
+        lines.push(self.status.clone().unwrap_or_else(|| {
+            String::from("y approve, n skip, i inspect, enter apply, q quit, ? help")
+        }));
+        Ok(lines)
+    }
+}
+
+/// Reviews `changes` on the full screen of `term`, until each is approved or skipped
+///
+/// # Returns
+/// * Ok(Some): Whether each change was approved
+/// * Ok(None): If the review was quit
+/// * Error: If the screen could not be drawn, or keys could not be read
+fn review_screen(
+    changes: &[Change],
+    trees: &(FileTree, FileTree),
+    term: &Term,
+) -> Result<Option<Vec<bool>>, io::Error> {
+    let mut screen = Screen::new(changes.len());
+    term.hide_cursor()?;
+    let outcome = loop {
+        let (height, width) = term.size();
+        let lines = screen.lines(changes, trees, height as usize)?;
+        term.clear_screen()?;
+        let lines: Vec<String> = lines
+            .iter()
+            .map(|line| console::truncate_str(line, width as usize, "…").into_owned())
+            .collect();
+        term.write_str(&lines.join("\n"))?;
+        term.flush()?;
+        match screen.handle(term.read_key()?) {
+            Outcome::Continue => continue,
+            outcome => break outcome,
+        }
+    };
+    term.clear_screen()?;
+    term.show_cursor()?;
+    Ok(match outcome {
+        Outcome::Apply => Some(screen.answers.iter().map(|a| *a == Some(true)).collect()),
+        _ => None,
+    })
+}
+
+/// Help for the prompt of a change
+const HELP: &str = "y - apply this change
+n - skip this change
+i - inspect both sides of this change
+a - apply this change and all later ones
+d - skip this change and all later ones
+q - quit without applying anything
+? - print help";
+
+/// Reviews the changes that synchronizing `src` into `dest` would make, prompting on stdin for
+/// each of them, and applies the approved ones
+///
+/// Both trees are listed like `core::synchronize` lists them, see `core::list`, but without
+/// writing anything, so nothing is changed until every change has been reviewed.
+///
+/// # Arguments
+/// * `src`: Source directory
+/// * `dest`: Destination directory
+/// * `options`: Options of the run
+///
+/// # Errors
+/// This function will return an error in the following situations,
+/// but is not limited to just these cases:
+/// * `src` is an invalid directory
+/// * `dest` is an invalid directory
+/// * The sync would delete what its guards do not allow, see `core::check_deletes`
+/// * The prompts could not be written or answered
+pub fn review_sync(src: &str, dest: &str, options: &Options) -> Result<(), io::Error> {
+    let options = &core::for_run(options);
+    // The progress bar would be drawn over the prompts
+    PROGRESS_BAR.set_draw_target(ProgressDrawTarget::hidden());
+
+    let listing_options = Options {
+        dry_run: true,
+        ..options.clone()
+    };
+    let listing = core::list(src, dest, &listing_options)?;
+    let changes = plan(&listing, src, dest, &listing_options)?;
+
+    // Changes are listed on a full screen in terminals, and prompted for one per line otherwise,
+    // such as when they are answered by another program
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    let term = Term::stdout();
+    let trees = (FileTree::open(src)?, FileTree::open(dest)?);
+    let answers = if term.features().is_attended() && stdin.is_terminal() && !changes.is_empty() {
+        review_screen(&changes, &trees, &term)?
+    } else {
+        review(&changes, &trees, stdin.lock(), stdout.lock())?
+    };
+    match answers {
+        Some(answers) => {
+            let approved = approved(&changes, &answers);
+            apply(&approved, src, dest, options);
+            println!("Applied {} of {} changes", approved.len(), changes.len());
+        }
+        None => println!("Applied none of {} changes", changes.len()),
+    }
+    Ok(())
+}
+
+/// Prints the changes that synchronizing `src` into `dest` would make, without applying any of
+/// them
+///
+/// Changes are printed one per line, or with `Options::print0`, only their paths are printed,
+/// relative to `src` and `dest`, and each followed by a NUL, such as for `xargs -0`. Both trees
+/// are listed like `core::synchronize` lists them, see `core::list`, so what is printed is what
+/// a run with the same options would change.
+///
+/// # Errors
+/// This function will return an error in the following situations,
+/// but is not limited to just these cases:
+/// * `src` is an invalid directory
+/// * `dest` is an invalid directory
+/// * The sync would delete what its guards do not allow, see `core::check_deletes`
+/// * The changes could not be printed
+pub fn dry_run(src: &str, dest: &str, options: &Options) -> Result<(), io::Error> {
+    // The progress bar would be drawn over the changes
+    PROGRESS_BAR.set_draw_target(ProgressDrawTarget::hidden());
+
+    let listing = core::list(src, dest, options)?;
+    let changes = plan(&listing, src, dest, options)?;
+
+    let stdout = io::stdout();
+    let print0 = options.print0;
+    write_changes(&changes, print0, &mut stdout.lock())
+}
+
+/// Writes `changes` to `output`, one per line, or only their paths, each followed by a NUL, if
+/// `print0` is set
+///
+/// Paths that are deleted and created again are only written once.
+fn write_changes<W>(changes: &[Change], print0: bool, output: &mut W) -> io::Result<()>
+where
+    W: Write,
+{
+    let mut last_path = None;
+    for change in changes {
+        if !print0 {
+            writeln!(output, "{}", change)?;
+            continue;
+        }
+        let path = change.dest_path();
+        if last_path.as_ref() == Some(&path) {
+            continue;
+        }
+        #[cfg(target_family = "unix")]
+        output.write_all(std::os::unix::ffi::OsStrExt::as_bytes(path.as_os_str()))?;
+        #[cfg(not(target_family = "unix"))]
+        output.write_all(path.to_string_lossy().as_bytes())?;
+        output.write_all(b"\0")?;
+        last_path = Some(path);
+    }
+    output.flush()
+}
+
+/// Finds the changes that synchronizing `src` into `dest` would make, as listed by `listing`,
+/// sorted by path
+///
+/// Entries that changed type are deleted, then created again. Files are compared like
+/// `lms sync` does, by size and then by hash, unless they are kept as they are, see
+/// `core::kept_files`. Special files are only created with `Options::specials`, and those that are
+/// skipped in `src` are left as they are in `dest`. Entries that are written under other names
+/// are compared with what is written under those names, and shortened directories that are
+/// already in `dest` are updated if the run of their own, see `limits::write_shortened`, would
+/// change anything in them.
+///
+/// # Errors
+/// This function will return an error if the deletes are refused like `core::synchronize` refuses
+/// them, see `core::check_deletes`
+fn plan<'a>(
+    listing: &'a Listing,
+    src: &str,
+    dest: &str,
+    options: &Options,
+) -> Result<Vec<Change<'a>>, io::Error> {
+    let (src_file_sets, dest_file_sets) = (&listing.src_file_sets, &listing.dest_file_sets);
+    let mut src_items = items(src_file_sets);
+    src_items.extend(
+        (listing.placeholders.iter())
+            .map(|placeholder| (placeholder.path().as_path(), Item::Placeholder(placeholder))),
+    );
+    let skipped: HashSet<&Path> = if options.specials {
+        HashSet::new()
+    } else {
+        (src_file_sets.specials().iter())
+            .map(|special| special.path().as_path())
+            .collect()
+    };
+    src_items.retain(|path, item| !matches!(item, Item::Special(_)) || !skipped.contains(path));
+    let dest_items = items(dest_file_sets);
+    let delete = options.delete && listing.src_complete;
+    let kept = core::kept_files(
+        src_file_sets.files(),
+        dest_file_sets.files(),
+        src,
+        dest,
+        options,
+    );
+
+    let mut changes = Vec::new();
+    for (path, src_item) in &src_items {
+        let changed = match (src_item, dest_items.get(path)) {
+            (_, None) => Some(Action::Create),
+            (Item::File(src_file), Some(Item::File(_))) if kept.contains(src_file.path()) => None,
+            (Item::File(src_file), Some(Item::File(dest_file))) => {
+                if src_file.size() != dest_file.size()
+                    || file_ops::file_differs(*src_file, src, dest, options)
+                {
+                    Some(Action::Update)
+                } else {
+                    None
+                }
+            }
+            (Item::Placeholder(_), Some(Item::File(dest_file))) if dest_file.size() == 0 => None,
+            (Item::Symlink(src_link), Some(Item::Symlink(dest_link))) => {
+                if src_link.target() != dest_link.target() {
+                    Some(Action::Update)
+                } else {
+                    None
+                }
+            }
+            (Item::Dir(_), Some(Item::Dir(_))) => None,
+            (Item::Special(src_special), Some(Item::Special(dest_special))) => {
+                if src_special != dest_special {
+                    Some(Action::Update)
+                } else {
+                    None
+                }
+            }
+            (_, Some(dest_item)) => {
+                changes.push(Change::new(Action::Delete, *dest_item));
+                Some(Action::Create)
+            }
+        };
+        if let Some(action) = changed {
+            changes.push(Change::new(action, *src_item));
+        }
+    }
+    if delete {
+        // What is in directories at the bounds of the listing is not known, so they are never
+        // deleted
+        changes.extend(
+            dest_items
+                .iter()
+                .filter(|(path, _)| !src_items.contains_key(*path))
+                .filter(|(path, item)| match item {
+                    Item::Dir(_) => !dest_file_sets.is_bounded(path),
+                    // Special files skipped in src are left as they are in dest
+                    Item::Special(_) => !skipped.contains(*path),
+                    _ => true,
+                })
+                .map(|(_, item)| Change::new(Action::Delete, *item)),
+        );
+
+        // Deletes are refused like a run refuses them, where files and symlinks that are replaced
+        // by files or symlinks are still in src
+        let src_empty = src_file_sets.files().is_empty()
+            && src_file_sets.symlinks().is_empty()
+            && listing.placeholders.is_empty();
+        let dest_count = (dest_file_sets.files().len() + dest_file_sets.symlinks().len()) as u64;
+        let to_delete = || {
+            (changes.iter())
+                .filter(|change| change.action == Action::Delete)
+                .filter(|change| matches!(change.item, Item::File(_) | Item::Symlink(_)))
+                .filter(|change| {
+                    !matches!(
+                        src_items.get(change.item.path()),
+                        Some(Item::File(_) | Item::Symlink(_) | Item::Placeholder(_))
+                    )
+                })
+                .count() as u64
+        };
+        core::check_deletes(src, dest, src_empty, dest_count, to_delete, options)?;
+    }
+    changes.extend(
+        (listing.shortened.iter())
+            .filter_map(|shortened| plan_shortened(shortened, src, dest, options)),
+    );
+
+    // Deletes come before creates of the same path, so the sort has to be stable
+    changes.sort_by(|a, b| a.item.path().cmp(b.item.path()));
+    Ok(changes)
+}
+
+/// Finds the change that writing `shortened` under its name in `dest` would make, if any
+fn plan_shortened<'a>(
+    shortened: &'a Shortened,
+    src: &str,
+    dest: &str,
+    options: &Options,
+) -> Option<Change<'a>> {
+    let to = shortened.dest_path();
+    let metadata = fs::symlink_metadata(Path::new(dest).join(&to)).ok();
+    let (item, unchanged) = match &shortened.kind {
+        Kind::File(file) => (
+            Item::File(file),
+            !file_ops::file_differs_at(file, src, dest, &to, options),
+        ),
+        Kind::Dir(dir) => {
+            let unchanged = metadata.as_ref().is_some_and(|metadata| metadata.is_dir())
+                && dirlinks::linked_options(options, &shortened.path).is_none_or(|options| {
+                    let src = Path::new(src).join(&shortened.path);
+                    let dest = Path::new(dest).join(&to);
+                    let (src, dest) = (src.to_string_lossy(), dest.to_string_lossy());
+                    core::list(&src, &dest, &options).is_ok_and(|listing| {
+                        plan(&listing, &src, &dest, &options).is_ok_and(|c| c.is_empty())
+                    })
+                });
+            (Item::Dir(dir), unchanged)
+        }
+        Kind::Symlink(symlink) => (
+            Item::Symlink(symlink),
+            fs::read_link(Path::new(dest).join(&to))
+                .is_ok_and(|target| target == *symlink.target()),
+        ),
+        // Special files can not be written under other names
+        Kind::Special => return None,
+    };
+    let action = match (unchanged, metadata) {
+        (true, _) => return None,
+        (false, Some(_)) => Action::Update,
+        (false, None) => Action::Create,
+    };
+    Some(Change {
+        action,
+        item,
+        shortened: Some(shortened),
+    })
+}
+
+/// Gets every entry of `file_sets`, by path
+fn items(file_sets: &FileSets) -> HashMap<&Path, Item<'_>> {
+    let files = file_sets.files().iter().map(Item::File);
+    let dirs = file_sets.dirs().iter().map(Item::Dir);
+    let symlinks = file_sets.symlinks().iter().map(Item::Symlink);
+    let specials = file_sets.specials().iter().map(Item::Special);
+    files
+        .chain(dirs)
+        .chain(symlinks)
+        .chain(specials)
+        .map(|item| (item.path(), item))
+        .collect()
+}
+
+/// Prompts on `input` and `output` for each of `changes`, until each is approved or skipped
+///
+/// # Returns
+/// * Ok(Some): Whether each change was approved
+/// * Ok(None): If the review was quit, or `input` ended before every change was answered
+/// * Error: If the prompts could not be written or read
+fn review<R, W>(
+    changes: &[Change],
+    trees: &(FileTree, FileTree),
+    mut input: R,
+    mut output: W,
+) -> Result<Option<Vec<bool>>, io::Error>
+where
+    R: BufRead,
+    W: Write,
+{
+    let mut approved = Vec::with_capacity(changes.len());
+    let mut rest = None;
+    for (i, change) in changes.iter().enumerate() {
+        if let Some(rest) = rest {
+            approved.push(rest);
+            continue;
+        }
+        loop {
+            write!(
+                output,
+                "({}/{}) {} [y,n,i,a,d,q,?]? ",
+                i + 1,
+                changes.len(),
+                change
+            )?;
+            output.flush()?;
+
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                writeln!(output)?;
+                return Ok(None);
+            }
+            match Answer::parse(&line) {
+                Some(Answer::Yes) => approved.push(true),
+                Some(Answer::No) => approved.push(false),
+                Some(Answer::All) => rest = Some(true),
+                Some(Answer::Done) => rest = Some(false),
+                Some(Answer::Quit) => return Ok(None),
+                Some(Answer::Inspect) => {
+                    inspect(change, trees, &mut output)?;
+                    continue;
+                }
+                Some(Answer::Help) | None => {
+                    writeln!(output, "{}", HELP)?;
+                    continue;
+                }
+            }
+            if let Some(rest) = rest {
+                approved.push(rest);
+            }
+            break;
+        }
+    }
+    Ok(Some(approved))
+}
+
+/// Writes what is at the path of `change` in the source and in the destination to `output`
+fn inspect<W>(change: &Change, trees: &(FileTree, FileTree), output: &mut W) -> io::Result<()>
+where
+    W: Write,
+{
+    let dest_path = change.dest_path();
+    let sides = [
+        ("source", &trees.0, change.item.path()),
+        ("destination", &trees.1, dest_path.as_path()),
+    ];
+    for (side, tree, path) in &sides {
+        let node = match tree.node(path) {
+            Ok(Some(node)) => node,
+            _ => {
+                writeln!(output, "  {:<12} not present", side)?;
+                continue;
+            }
+        };
+        let modified = tree
+            .metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map_or_else(
+                || String::from("unknown"),
+                |modified| history::format_time(modified.as_secs()),
+            );
+        let kind = match node.kind() {
+            EntryKind::File(size) => format!("file of {} bytes", size),
+            EntryKind::Dir => String::from("dir"),
+            EntryKind::Symlink(target) => format!("symlink to {:?}", target),
+            EntryKind::Special { .. } => String::from("special file"),
+        };
+        writeln!(output, "  {:<12} {}, modified {} UTC", side, kind, modified)?;
+    }
+    Ok(())
+}
+
+/// Gets the changes that are approved in `answers`, leaving out those that depend on changes that
+/// are skipped
+///
+/// Directories are not deleted while skipped deletes keep anything in them, and nothing is created
+/// in directories whose creates are skipped, or where skipped deletes leave entries in the way.
+fn approved<'a, 'b>(changes: &'b [Change<'a>], answers: &[bool]) -> Vec<&'b Change<'a>> {
+    let mut answers = answers.to_vec();
+    let refuse = |answers: &mut Vec<bool>, i: usize, reason: &str| {
+        if answers[i] {
+            warn!(
+                "Review Warning -- {} is skipped, since {}",
+                changes[i], reason
+            );
+            answers[i] = false;
+        }
+    };
+
+    // Deletes are answered from the deepest path up, so that what they keep is known before the
+    // directories that hold it
+    let mut kept = HashSet::new();
+    let mut kept_dirs = HashSet::new();
+    for i in (0..changes.len()).rev() {
+        let change = &changes[i];
+        let path = change.dest_path();
+        if change.action != Action::Delete {
+            continue;
+        }
+        if matches!(change.item, Item::Dir(_)) && kept_dirs.contains(&path) {
+            refuse(&mut answers, i, "what is in it is kept");
+        }
+        if !answers[i] {
+            kept_dirs.extend(path.ancestors().skip(1).map(Path::to_path_buf));
+            kept.insert(path);
+        }
+    }
+
+    // Creates and updates are answered from the shallowest path down, so that the directories
+    // they are written into are known before them
+    let mut missing = HashSet::new();
+    for i in 0..changes.len() {
+        let change = &changes[i];
+        let path = change.dest_path();
+        if change.action == Action::Delete {
+            continue;
+        }
+        if path
+            .ancestors()
+            .skip(1)
+            .any(|parent| missing.contains(parent))
+        {
+            refuse(&mut answers, i, "its directory is not created");
+        } else if change.action == Action::Create && kept.contains(&path) {
+            refuse(&mut answers, i, "what is in its way is kept");
+        }
+        if !answers[i] && change.action == Action::Create && matches!(change.item, Item::Dir(_)) {
+            missing.insert(path);
+        }
+    }
+
+    changes
+        .iter()
+        .zip(answers)
+        .filter_map(|(change, approved)| if approved { Some(change) } else { None })
+        .collect()
+}
+
+/// Applies `changes` to `dest`, so that deletes make room for creates, and parent directories
+/// are created before what is in them
+fn apply(changes: &[&Change], src: &str, dest: &str, options: &Options) {
+    let of = |action: Action| changes.iter().filter(move |change| change.action == action);
+
+    // Directories are deleted after what is in them, in reverse order of their paths
+    let (dirs, others): (Vec<&&Change>, Vec<&&Change>) =
+        of(Action::Delete).partition(|change| matches!(change.item, Item::Dir(_)));
+    for change in others.iter().chain(dirs.iter().rev()) {
+        change.item.remove(dest);
+    }
+
+    // Entries that are written under other names are written like a run writes them
+    let shortened: Vec<Shortened> = changes
+        .iter()
+        .filter_map(|change| change.shortened)
+        .cloned()
+        .collect();
+    for change in changes {
+        match (change.action, change.item) {
+            (Action::Delete, _) => continue,
+            _ if change.shortened.is_some() => continue,
+            // Symlinks and special files can not be written over, so the old ones are removed first
+            (Action::Update, Item::Symlink(_) | Item::Special(_)) => change.item.remove(dest),
+            _ => {}
+        }
+        if change.item.copy(src, dest, options) {
+            info!("Applied: {}", change);
+        }
+    }
+    // Files that were still being written are copied again once the others are
+    file_ops::copy_deferred(options);
+    if let Err(e) = limits::write_shortened(&shortened, src, dest, options, core::synchronize) {
+        error!(
+            "Error -- Writing entries under other names in {:?}: {}",
+            dest, e
+        );
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_review {
+    use super::*;
+    use crate::lumins::sanitize::Sanitize;
+    use std::fs;
+
+    #[test]
+    fn plans_and_applies_approved_changes() {
+        const TEST_DIR: &str = "test_review_plans_and_applies_approved_changes";
+        const TEST_DIR_OUT: &str = "test_review_plans_and_applies_approved_changes_out";
+        let path = |dir: &str, path: &str| [dir, path].join("/");
+
+        fs::create_dir_all(path(TEST_DIR, "dir")).unwrap();
+        fs::create_dir_all(path(TEST_DIR_OUT, "stale")).unwrap();
+        fs::write(path(TEST_DIR, "dir/new"), b"new").unwrap();
+        fs::write(path(TEST_DIR, "changed"), b"abc").unwrap();
+        fs::write(path(TEST_DIR_OUT, "changed"), b"abd").unwrap();
+        fs::write(path(TEST_DIR, "same"), b"same").unwrap();
+        fs::write(path(TEST_DIR_OUT, "same"), b"same").unwrap();
+        fs::write(path(TEST_DIR_OUT, "stale/old"), b"old").unwrap();
+
+        let options = Options::default();
+        let listing = core::list(TEST_DIR, TEST_DIR_OUT, &options).unwrap();
+        let changes = plan(&listing, TEST_DIR, TEST_DIR_OUT, &options).unwrap();
+        let changes_text: Vec<String> = changes.iter().map(Change::to_string).collect();
+        let mut paths = Vec::new();
+        write_changes(&changes, true, &mut paths).unwrap();
+        assert_eq!(paths, b"changed\0dir\0dir/new\0stale\0stale/old\0");
+        assert_eq!(
+            changes_text,
+            vec![
+                "Update file \"changed\" (3 bytes)",
+                "Create dir \"dir\"",
+                "Create file \"dir/new\" (3 bytes)",
+                "Delete dir \"stale\"",
+                "Delete file \"stale/old\" (3 bytes)",
+            ]
+        );
+
+        // Skip the update, inspect and approve the rest
+        let trees = (
+            FileTree::open(TEST_DIR).unwrap(),
+            FileTree::open(TEST_DIR_OUT).unwrap(),
+        );
+        let mut output = Vec::new();
+        let approved = review(&changes, &trees, &b"n\ni\nx\ny\na\n"[..], &mut output)
+            .unwrap()
+            .unwrap();
+        assert_eq!(approved, vec![false, true, true, true, true]);
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("destination  not present"));
+        assert!(output.contains(HELP));
+
+        apply(
+            &super::approved(&changes, &approved),
+            TEST_DIR,
+            TEST_DIR_OUT,
+            &options,
+        );
+        assert_eq!(fs::read(path(TEST_DIR_OUT, "changed")).unwrap(), b"abd");
+        assert_eq!(fs::read(path(TEST_DIR_OUT, "dir/new")).unwrap(), b"new");
+        assert!(fs::metadata(path(TEST_DIR_OUT, "stale")).is_err());
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_dir_all(TEST_DIR_OUT).unwrap();
+    }
+
+    #[test]
+    fn answers_on_screen() {
+        const TEST_DIR: &str = "test_review_answers_on_screen";
+        const TEST_DIR_OUT: &str = "test_review_answers_on_screen_out";
+        fs::create_dir_all(TEST_DIR).unwrap();
+        fs::create_dir_all(TEST_DIR_OUT).unwrap();
+        for name in &["a", "b", "c"] {
+            fs::write([TEST_DIR, name].join("/"), name).unwrap();
+        }
+
+        let options = Options::default();
+        let listing = core::list(TEST_DIR, TEST_DIR_OUT, &options).unwrap();
+        let changes = plan(&listing, TEST_DIR, TEST_DIR_OUT, &options).unwrap();
+        let trees = (
+            FileTree::open(TEST_DIR).unwrap(),
+            FileTree::open(TEST_DIR_OUT).unwrap(),
+        );
+        let mut screen = Screen::new(changes.len());
+
+        // Nothing is applied until every change is answered
+        assert_eq!(screen.handle(Key::Char('y')), Outcome::Continue);
+        assert_eq!(screen.handle(Key::Enter), Outcome::Continue);
+        let lines = screen.lines(&changes, &trees, 20).unwrap();
+        assert_eq!(
+            lines,
+            vec![
+                "3 changes: 1 approved, 0 skipped, 2 not answered",
+                "  [y] Create file \"a\" (1 bytes)",
+                "> [ ] Create file \"b\" (1 bytes)",
+                "  [ ] Create file \"c\" (1 bytes)",
+                "2 changes are not answered yet",
+            ]
+        );
+
+        // Both sides are shown below the selected change
+        screen.handle(Key::Char('i'));
+        let lines = screen.lines(&changes, &trees, 20).unwrap();
+        assert!(lines[3].contains("source       file of 1 bytes"));
+        assert_eq!(lines[4], "  destination  not present");
+
+        // Only the rows that fit are shown, with the selected change among them
+        screen.handle(Key::Char('i'));
+        screen.handle(Key::End);
+        let lines = screen.lines(&changes, &trees, 3).unwrap();
+        assert_eq!(lines[1], "> [ ] Create file \"c\" (1 bytes)");
+        assert_eq!(lines.len(), 3);
+
+        screen.handle(Key::Home);
+        screen.handle(Key::Char('n'));
+        screen.handle(Key::Char('a'));
+        assert_eq!(screen.answers, vec![Some(false), Some(true), Some(true)]);
+        assert_eq!(screen.handle(Key::Char('x')), Outcome::Continue);
+        assert_eq!(screen.status.as_deref(), Some("Press ? for help"));
+        assert_eq!(screen.handle(Key::Enter), Outcome::Apply);
+        assert_eq!(screen.handle(Key::Escape), Outcome::Quit);
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_dir_all(TEST_DIR_OUT).unwrap();
+    }
+
+    #[test]
+    fn quits() {
+        const TEST_DIR: &str = "test_review_quits";
+        const TEST_DIR_OUT: &str = "test_review_quits_out";
+        fs::create_dir_all(TEST_DIR).unwrap();
+        fs::create_dir_all(TEST_DIR_OUT).unwrap();
+        fs::write([TEST_DIR, "a"].join("/"), b"a").unwrap();
+        fs::write([TEST_DIR, "b"].join("/"), b"b").unwrap();
+
+        let options = Options::default();
+        let listing = core::list(TEST_DIR, TEST_DIR_OUT, &options).unwrap();
+        let changes = plan(&listing, TEST_DIR, TEST_DIR_OUT, &options).unwrap();
+        let trees = (
+            FileTree::open(TEST_DIR).unwrap(),
+            FileTree::open(TEST_DIR_OUT).unwrap(),
+        );
+
+        // Quitting, or running out of answers, approves nothing
+        let review = |input: &[u8]| review(&changes, &trees, input, io::sink()).unwrap();
+        assert_eq!(review(b"y\nq\n"), None);
+        assert_eq!(review(b"y\n"), None);
+        assert_eq!(review(b"y\nd\n"), Some(vec![true, false]));
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_dir_all(TEST_DIR_OUT).unwrap();
+    }
+
+    #[test]
+    fn plans_what_a_run_changes() {
+        use crate::lumins::filter::Filter;
+        const TEST_DIR: &str = "test_review_plans_what_a_run_changes";
+        const TEST_DIR_OUT: &str = "test_review_plans_what_a_run_changes_out";
+        let path = |dir: &str, path: &str| [dir, path].join("/");
+
+        fs::create_dir_all(path(TEST_DIR, "dir")).unwrap();
+        fs::create_dir_all(TEST_DIR_OUT).unwrap();
+        fs::write(path(TEST_DIR, "kept"), b"kept").unwrap();
+        fs::write(path(TEST_DIR_OUT, "kept"), b"kept").unwrap();
+        fs::write(path(TEST_DIR, "a:b"), b"ab").unwrap();
+        fs::write(path(TEST_DIR, "dir/new"), b"new").unwrap();
+        fs::write(path(TEST_DIR, "dir/skipped.tmp"), b"tmp").unwrap();
+        fs::write(path(TEST_DIR_OUT, "stale"), b"stale").unwrap();
+        fs::write(path(TEST_DIR_OUT, "other.tmp"), b"tmp").unwrap();
+
+        // Excluded entries are neither copied nor deleted, and sanitized names are written as
+        // they are escaped
+        let options = Options {
+            filter: Some(Filter::parse(vec!["- *.tmp"]).unwrap()),
+            sanitize_names: Some(Sanitize::Escape),
+            dry_run: true,
+            ..Options::default()
+        };
+        let listing = core::list(TEST_DIR, TEST_DIR_OUT, &options).unwrap();
+        let changes: Vec<String> = plan(&listing, TEST_DIR, TEST_DIR_OUT, &options)
+            .unwrap()
+            .iter()
+            .map(Change::to_string)
+            .collect();
+        assert_eq!(
+            changes,
+            vec![
+                "Create file \"a:b\" (2 bytes) as \"a%3Ab\"",
+                "Create dir \"dir\"",
+                "Create file \"dir/new\" (3 bytes)",
+                "Delete file \"stale\" (5 bytes)",
+            ]
+        );
+        // Listing for a dry run writes nothing
+        assert_eq!(fs::read_dir(TEST_DIR_OUT).unwrap().count(), 3);
+
+        // A run changes what was planned, and nothing is left to change after it
+        let run_options = Options {
+            dry_run: false,
+            ..options.clone()
+        };
+        core::synchronize(TEST_DIR, TEST_DIR_OUT, &run_options).unwrap();
+        assert_eq!(fs::read(path(TEST_DIR_OUT, "a%3Ab")).unwrap(), b"ab");
+        assert_eq!(fs::read(path(TEST_DIR_OUT, "dir/new")).unwrap(), b"new");
+        assert!(fs::metadata(path(TEST_DIR_OUT, "stale")).is_err());
+        assert!(fs::metadata(path(TEST_DIR_OUT, "dir/skipped.tmp")).is_err());
+        assert!(fs::metadata(path(TEST_DIR_OUT, "other.tmp")).is_ok());
+        let listing = core::list(TEST_DIR, TEST_DIR_OUT, &options).unwrap();
+        assert!(plan(&listing, TEST_DIR, TEST_DIR_OUT, &options)
+            .unwrap()
+            .is_empty());
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_dir_all(TEST_DIR_OUT).unwrap();
+    }
+
+    #[test]
+    fn skips_what_depends_on_skipped_changes() {
+        const TEST_DIR: &str = "test_review_skips_what_depends_on_skipped_changes";
+        const TEST_DIR_OUT: &str = "test_review_skips_what_depends_on_skipped_changes_out";
+        let path = |dir: &str, path: &str| [dir, path].join("/");
+
+        fs::create_dir_all(path(TEST_DIR, "dir")).unwrap();
+        fs::create_dir_all(path(TEST_DIR_OUT, "stale")).unwrap();
+        fs::write(path(TEST_DIR, "dir/new"), b"new").unwrap();
+        fs::write(path(TEST_DIR, "file"), b"file").unwrap();
+        fs::create_dir_all(path(TEST_DIR_OUT, "file")).unwrap();
+        fs::write(path(TEST_DIR_OUT, "stale/kept"), b"kept").unwrap();
+        fs::write(path(TEST_DIR_OUT, "stale/old"), b"old").unwrap();
+
+        let options = Options::default();
+        let listing = core::list(TEST_DIR, TEST_DIR_OUT, &options).unwrap();
+        let changes = plan(&listing, TEST_DIR, TEST_DIR_OUT, &options).unwrap();
+        let changes_text: Vec<String> = changes.iter().map(Change::to_string).collect();
+        assert_eq!(
+            changes_text,
+            vec![
+                "Create dir \"dir\"",
+                "Create file \"dir/new\" (3 bytes)",
+                "Delete dir \"file\"",
+                "Create file \"file\" (4 bytes)",
+                "Delete dir \"stale\"",
+                "Delete file \"stale/kept\" (4 bytes)",
+                "Delete file \"stale/old\" (3 bytes)",
+            ]
+        );
+
+        // Nothing is created in a directory that is not created, or where a directory is kept,
+        // and directories are not deleted while anything in them is kept
+        let answers = [false, true, false, true, true, false, true];
+        let approved: Vec<String> = super::approved(&changes, &answers)
+            .iter()
+            .map(|change| change.to_string())
+            .collect();
+        assert_eq!(approved, vec!["Delete file \"stale/old\" (3 bytes)"]);
+
+        apply(
+            &super::approved(&changes, &answers),
+            TEST_DIR,
+            TEST_DIR_OUT,
+            &options,
+        );
+        assert!(fs::metadata(path(TEST_DIR_OUT, "dir")).is_err());
+        assert!(fs::metadata(path(TEST_DIR_OUT, "file")).unwrap().is_dir());
+        assert_eq!(fs::read(path(TEST_DIR_OUT, "stale/kept")).unwrap(), b"kept");
+        assert!(fs::metadata(path(TEST_DIR_OUT, "stale/old")).is_err());
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_dir_all(TEST_DIR_OUT).unwrap();
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn plans_special_files() {
+        use std::process::Command;
+        const TEST_DIR: &str = "test_review_plans_special_files";
+        const TEST_DIR_OUT: &str = "test_review_plans_special_files_out";
+        let path = |dir: &str, path: &str| [dir, path].join("/");
+
+        fs::create_dir_all(TEST_DIR).unwrap();
+        fs::create_dir_all(TEST_DIR_OUT).unwrap();
+        for fifo in &[path(TEST_DIR, "fifo"), path(TEST_DIR_OUT, "stale")] {
+            assert!(Command::new("mkfifo").arg(fifo).status().unwrap().success());
+        }
+
+        let plan_text = |options: &Options| -> Vec<String> {
+            let listing = core::list(TEST_DIR, TEST_DIR_OUT, options).unwrap();
+            (plan(&listing, TEST_DIR, TEST_DIR_OUT, options)
+                .unwrap()
+                .iter())
+            .map(Change::to_string)
+            .collect()
+        };
+        assert_eq!(
+            plan_text(&Options::default()),
+            vec!["Delete special file \"stale\""]
+        );
+        assert_eq!(
+            plan_text(&Options {
+                specials: true,
+                ..Options::default()
+            }),
+            vec![
+                "Create special file \"fifo\"",
+                "Delete special file \"stale\""
+            ]
+        );
+
+        // Special files skipped in the source are left as they are in the destination
+        fs::rename(path(TEST_DIR_OUT, "stale"), path(TEST_DIR_OUT, "fifo")).unwrap();
+        assert!(plan_text(&Options::default()).is_empty());
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_dir_all(TEST_DIR_OUT).unwrap();
+    }
+
+    #[test]
+    fn refuses_deletes_like_a_run() {
+        use crate::lumins::history::MaxDelete;
+        const TEST_DIR: &str = "test_review_refuses_deletes_like_a_run";
+        const TEST_DIR_OUT: &str = "test_review_refuses_deletes_like_a_run_out";
+
+        fs::create_dir_all(TEST_DIR).unwrap();
+        fs::create_dir_all(TEST_DIR_OUT).unwrap();
+        fs::write([TEST_DIR_OUT, "old"].join("/"), b"old").unwrap();
+
+        let plan_result = |options: &Options| {
+            let listing = core::list(TEST_DIR, TEST_DIR_OUT, options).unwrap();
+            plan(&listing, TEST_DIR, TEST_DIR_OUT, options).map(|changes| changes.len())
+        };
+        assert_eq!(plan_result(&Options::default()).unwrap(), 1);
+        assert!(plan_result(&Options {
+            safe_delete: true,
+            ..Options::default()
+        })
+        .is_err());
+        assert!(plan_result(&Options {
+            safe_delete: true,
+            force_empty_source: true,
+            ..Options::default()
+        })
+        .is_ok());
+
+        fs::write([TEST_DIR, "new"].join("/"), b"new").unwrap();
+        let options = Options {
+            max_delete: Some(MaxDelete::Files(0)),
+            ..Options::default()
+        };
+        assert!(plan_result(&options).is_err());
+        assert!(plan_result(&Options {
+            safe_delete: true,
+            ..Options::default()
+        })
+        .is_ok());
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_dir_all(TEST_DIR_OUT).unwrap();
+    }
+}
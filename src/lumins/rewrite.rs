@@ -0,0 +1,295 @@
+//! Rewrites the targets of absolute symlinks, for trees that are moved to a different mount point,
+//! or are made relative so that they can be moved anywhere
+
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+
+use regex::Regex;
+
+use crate::lumins::file_ops::{FileOps, FileSets};
+
+/// A struct that represents a sed-like substitution of symlink targets
+#[derive(Debug, Clone)]
+pub struct Rewrite {
+    pattern: Regex,
+    /// Replacement in the syntax of `Regex::replace`
+    replacement: String,
+    /// Whether every match is replaced, instead of only the first one
+    global: bool,
+}
+
+impl FromStr for Rewrite {
+    type Err = io::Error;
+
+    /// Parses a substitution of the form `s#pattern#replacement#`, where `#` can be any
+    /// character, and a trailing `g` replaces every match instead of only the first one
+    ///
+    /// As in sed, `&` in the replacement is the whole match, `\1` to `\9` are the groups of
+    /// the pattern, and the delimiter can be escaped with `\` in both.
+    ///
+    /// # Examples
+    /// `s#^/old/root#/new/root#` rewrites `/old/root/a` to `/new/root/a`
+    fn from_str(expr: &str) -> Result<Self, Self::Err> {
+        let invalid = |reason: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Link Rewrite Error -- {:?}: {}", expr, reason),
+            )
+        };
+
+        let mut chars = expr.chars();
+        let delimiter = match (chars.next(), chars.next()) {
+            (Some('s'), Some(delimiter)) if delimiter != '\\' && delimiter != '\n' => delimiter,
+            _ => return Err(invalid("expected s, followed by a delimiter")),
+        };
+
+        let mut parts = vec![String::new()];
+        let mut escaped = false;
+        for c in chars {
+            let part = parts.last_mut().unwrap();
+            if escaped {
+                escaped = false;
+                if c == delimiter {
+                    part.push_str(&regex::escape(&c.to_string()));
+                } else {
+                    part.push('\\');
+                    part.push(c);
+                }
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == delimiter {
+                parts.push(String::new());
+            } else {
+                part.push(c);
+            }
+        }
+        if escaped {
+            return Err(invalid("trailing \\"));
+        }
+
+        let global = match parts.as_slice() {
+            [_, _, flags] if flags.is_empty() => false,
+            [_, _, flags] if flags == "g" => true,
+            [_, _, _] => return Err(invalid("the only flag is g")),
+            _ => return Err(invalid("expected a pattern and a replacement")),
+        };
+        let pattern = Regex::new(&parts[0]).map_err(|e| invalid(&e.to_string()))?;
+
+        Ok(Rewrite {
+            pattern,
+            replacement: replacement(&parts[1]),
+            global,
+        })
+    }
+}
+
+impl Rewrite {
+    /// Applies the substitution to `target`
+    ///
+    /// Targets that are not valid UTF-8 are left as they are.
+    pub fn apply(&self, target: &Path) -> PathBuf {
+        let target_str = match target.to_str() {
+            Some(target) => target,
+            None => return target.to_path_buf(),
+        };
+        let rewritten = if self.global {
+            self.pattern
+                .replace_all(target_str, self.replacement.as_str())
+        } else {
+            self.pattern.replace(target_str, self.replacement.as_str())
+        };
+        PathBuf::from(rewritten.into_owned())
+    }
+}
+
+/// Applies `rewrites` in order to `target`, if it is absolute
+///
+/// # Returns
+/// The rewritten target, or `target` itself if it is relative
+pub fn rewrite_target(rewrites: &[Rewrite], target: &Path) -> PathBuf {
+    if !target.is_absolute() {
+        return target.to_path_buf();
+    }
+    rewrites
+        .iter()
+        .fold(target.to_path_buf(), |target, rewrite| {
+            rewrite.apply(&target)
+        })
+}
+
+/// Applies `rewrites` to the targets of the absolute symlinks of `file_sets`, so that they are
+/// recreated, and compared, with their rewritten targets
+pub fn rewrite_targets(rewrites: &[Rewrite], file_sets: &mut FileSets) {
+    if rewrites.is_empty() {
+        return;
+    }
+    file_sets.map_symlinks(|symlink| {
+        let target = rewrite_target(rewrites, symlink.target());
+        symlink.with_target(target)
+    });
+}
+
+/// Makes the targets of the absolute symlinks of `file_sets` that point inside `src` relative,
+/// so that the copy of the tree is self-contained
+///
+/// Targets are compared with both the absolute path of `src`, and its canonical path, without
+/// resolving the targets themselves.
+///
+/// # Errors
+/// This function will return an error if the absolute path of `src` could not be found
+pub fn relativize_targets(src: &str, file_sets: &mut FileSets) -> Result<(), io::Error> {
+    let roots = [env::current_dir()?.join(src), fs::canonicalize(src)?];
+    file_sets.map_symlinks(
+        |symlink| match relativize(&roots, symlink.path(), symlink.target()) {
+            Some(target) => symlink.with_target(target),
+            None => symlink,
+        },
+    );
+    Ok(())
+}
+
+/// Gets the relative target of the symlink at `path`, relative to the roots, that points to the
+/// same entry as the absolute `target` does
+///
+/// # Returns
+/// * Some: The relative target
+/// * None: If `target` is relative, or is not inside any of `roots`
+fn relativize(roots: &[PathBuf], path: &Path, target: &Path) -> Option<PathBuf> {
+    if !target.is_absolute() {
+        return None;
+    }
+    let inside = roots
+        .iter()
+        .find_map(|root| target.strip_prefix(root).ok())?;
+
+    // Go up from the directory containing the symlink to the root, and then down to the target
+    let depth = path
+        .parent()
+        .map_or(0, |parent| parent.components().count());
+    let mut relative: PathBuf = (0..depth).map(|_| Component::ParentDir).collect();
+    relative.push(inside);
+    if relative.as_os_str().is_empty() {
+        relative.push(Component::CurDir);
+    }
+    Some(relative)
+}
+
+/// Converts a sed replacement, with escaped delimiters already unescaped, into the syntax of
+/// `Regex::replace`
+fn replacement(sed: &str) -> String {
+    let mut replacement = String::new();
+    let mut chars = sed.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '&' => replacement.push_str("${0}"),
+            '$' => replacement.push_str("$$"),
+            '\\' => match chars.next() {
+                Some(group) if group.is_ascii_digit() => {
+                    replacement.push_str(&format!("${{{}}}", group))
+                }
+                Some(c) => replacement.push(c),
+                None => replacement.push('\\'),
+            },
+            c => replacement.push(c),
+        }
+    }
+    replacement
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_rewrite {
+    use super::*;
+    use crate::lumins::file_ops::Symlink;
+    use hashbrown::HashSet;
+
+    #[test]
+    fn parse() {
+        for expr in &[
+            "", "s", "s#a", "s#a#b", "s#a#b#x", "s#a#b#c#", "s#(#b#", "x#a#b#", "s#a#b\\",
+        ] {
+            assert!(expr.parse::<Rewrite>().is_err(), "{:?}", expr);
+        }
+        assert!("s#a#b#".parse::<Rewrite>().is_ok());
+        assert!("s|a|b|g".parse::<Rewrite>().is_ok());
+    }
+
+    #[test]
+    fn apply() {
+        let rewrite = |expr: &str, target: &str| {
+            let rewrite: Rewrite = expr.parse().unwrap();
+            rewrite.apply(Path::new(target))
+        };
+        assert_eq!(
+            rewrite("s#^/old/root#/new/root#", "/old/root/a"),
+            Path::new("/new/root/a")
+        );
+        assert_eq!(
+            rewrite("s#^/old/root#/new/root#", "/mnt/old/root/a"),
+            Path::new("/mnt/old/root/a")
+        );
+        assert_eq!(rewrite("s/\\/a/\\/b/", "/a/a"), Path::new("/b/a"));
+        assert_eq!(rewrite("s/\\/a/\\/b/g", "/a/a"), Path::new("/b/b"));
+        assert_eq!(
+            rewrite("s#^/(\\w+)/(\\w+)#/\\2/\\1#", "/x/y/z"),
+            Path::new("/y/x/z")
+        );
+        assert_eq!(rewrite("s#^/x#&/\\&$1#", "/x"), Path::new("/x/&$1"));
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn relative_targets() {
+        let roots = [PathBuf::from("/data/tree"), PathBuf::from("/mnt/tree")];
+        let relativize =
+            |path: &str, target: &str| relativize(&roots, Path::new(path), Path::new(target));
+        assert_eq!(relativize("l", "/data/tree/a"), Some(PathBuf::from("a")));
+        assert_eq!(
+            relativize("x/y/l", "/mnt/tree/a/b"),
+            Some(PathBuf::from("../../a/b"))
+        );
+        assert_eq!(relativize("x/l", "/data/tree"), Some(PathBuf::from("..")));
+        assert_eq!(relativize("l", "/data/tree"), Some(PathBuf::from(".")));
+        assert_eq!(relativize("l", "/data/treehouse/a"), None);
+        assert_eq!(relativize("l", "/elsewhere/a"), None);
+        assert_eq!(relativize("l", "a"), None);
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn targets() {
+        // Every rewrite applies to the result of the ones before it
+        let rewrites: Vec<Rewrite> =
+            vec!["s#^/a#/b#".parse().unwrap(), "s#^/b#/c#".parse().unwrap()];
+        assert_eq!(
+            rewrite_target(&rewrites, Path::new("/a/f")),
+            Path::new("/c/f")
+        );
+        assert_eq!(
+            rewrite_target(&rewrites, Path::new("a/f")),
+            Path::new("a/f")
+        );
+
+        let mut file_sets = FileSets::with(
+            HashSet::new(),
+            HashSet::new(),
+            [Symlink::from("l", "/a/f"), Symlink::from("r", "a/f")]
+                .iter()
+                .cloned()
+                .collect(),
+        );
+        rewrite_targets(&rewrites, &mut file_sets);
+        let mut symlinks: Vec<&Symlink> = file_sets.symlinks().iter().collect();
+        symlinks.sort_by_key(|symlink| symlink.path());
+        assert_eq!(
+            symlinks,
+            vec![&Symlink::from("l", "/c/f"), &Symlink::from("r", "a/f")]
+        );
+    }
+}
@@ -0,0 +1,150 @@
+//! Keeps what an untrusted source writes inside the destination
+//!
+//! Entries are always written relative to the destination with `dirfd`, so that no component of
+//! their paths is followed if it is a symlink, and hardlinks in archives are extracted as copies
+//! of entries already in the destination. What is left is checked here before anything is
+//! written: entries whose paths are not plain relative paths, hardlinks to such paths, and
+//! symlinks whose targets are absolute or may climb above the destination with `..`, which would
+//! lead whatever later follows them out of the destination.
+//!
+//! Archive sources are sandboxed unless `--sandbox-dest never` is given, and directory sources
+//! only with `--sandbox-dest always`, since absolute symlinks are common in trusted trees.
+
+use std::io;
+use std::path::{Component, Path};
+
+use clap::ValueEnum;
+
+use crate::lumins::parse::Options;
+
+/// Enum to represent when what is written to the destination is sandboxed, with `--sandbox-dest`
+#[derive(Eq, PartialEq, Debug, Clone, Copy, Default, ValueEnum)]
+pub enum SandboxMode {
+    /// Only archive sources are sandboxed
+    #[default]
+    Auto,
+    /// Everything is sandboxed
+    Always,
+    /// Nothing is sandboxed
+    Never,
+}
+
+/// Checks whether what is written to the destination is sandboxed
+///
+/// # Arguments
+/// * `options`: Options of the run
+/// * `archive_src`: whether the source is an archive, which is sandboxed by default
+pub fn is_sandboxed(options: &Options, archive_src: bool) -> bool {
+    match options.sandbox {
+        SandboxMode::Auto => archive_src,
+        SandboxMode::Always => true,
+        SandboxMode::Never => false,
+    }
+}
+
+/// Checks that `relative` is a plain relative path, which stays inside the destination
+///
+/// # Errors
+/// This function will return an error if `relative` is absolute, or has a `..` component
+pub fn check_path(relative: &Path) -> Result<(), io::Error> {
+    if relative
+        .components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+    {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "Sandbox Error -- {:?} is not inside the destination",
+                relative
+            ),
+        ))
+    }
+}
+
+/// Checks that the symlink `relative` and what it points to are inside the destination
+///
+/// # Errors
+/// This function will return an error if `relative` is not a plain relative path, or `target`
+/// is absolute, or climbs above the destination from the directory of `relative`
+pub fn check_symlink(relative: &Path, target: &Path) -> Result<(), io::Error> {
+    check_path(relative)?;
+    if escapes(relative, target) {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "Sandbox Error -- {:?} points to {:?}, which is outside of the destination",
+                relative, target
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Checks whether `target`, resolved from the directory of the symlink `relative`, may leave the
+/// destination
+///
+/// Targets are resolved without looking at the destination, so `..` is only allowed before the
+/// first normal component of `target`. The directories it climbs out of are then the real parents
+/// of `relative`, while a `..` after a component that is itself a symlink would climb out of
+/// wherever that symlink points.
+fn escapes(relative: &Path, target: &Path) -> bool {
+    let mut depth = relative
+        .parent()
+        .map_or(0, |parent| parent.components().count());
+    let mut descended = false;
+    for component in target.components() {
+        match component {
+            Component::Normal(_) => descended = true,
+            Component::CurDir => {}
+            Component::ParentDir if !descended && depth > 0 => depth -= 1,
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => return true,
+        }
+    }
+    false
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_sandbox {
+    use super::*;
+
+    #[test]
+    fn paths_and_symlinks() {
+        assert!(check_path(Path::new("a/./b")).is_ok());
+        assert!(check_path(Path::new("a/../../b")).is_err());
+        assert!(check_path(Path::new("/etc/passwd")).is_err());
+
+        let check = |relative: &str, target: &str| {
+            check_symlink(Path::new(relative), Path::new(target)).is_ok()
+        };
+        assert!(check("a/link", "b"));
+        assert!(check("a/link", "../b"));
+        assert!(check("a/b/link", "../../c/./d"));
+        assert!(!check("a/link", "../../b"));
+        assert!(!check("a/link", "b/../../../c"));
+        // `b` may be a symlink to anywhere in dest, so nothing can be climbed out of it
+        assert!(!check("a/link", "b/.."));
+        assert!(!check("link", "/etc"));
+        assert!(!check("../link", "b"));
+    }
+
+    #[test]
+    fn sandboxed() {
+        assert!(is_sandboxed(&Options::default(), true));
+        assert!(!is_sandboxed(&Options::default(), false));
+        let sandboxed = |sandbox: SandboxMode, archive_src: bool| {
+            let options = Options {
+                sandbox,
+                ..Options::default()
+            };
+            is_sandboxed(&options, archive_src)
+        };
+        assert!(sandboxed(SandboxMode::Always, false));
+        assert!(!sandboxed(SandboxMode::Never, true));
+    }
+}
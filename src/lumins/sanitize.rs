@@ -0,0 +1,306 @@
+//! Escapes what FAT, exFAT, and NTFS do not allow in names, for `--sanitize-names escape`, and
+//! restores it, for `--sanitize-names restore`
+//!
+//! Each character of `ESCAPED`, and each control character, is written as `%` and its code in
+//! hexadecimal, such as `%3A` for `:`, so `a:b` is written as `a%3Ab`. So is a `.` or space that
+//! ends a name, which Windows would drop, so `a.` is written as `a%2E`, and the first character of
+//! a name that Windows reserves for a device, with or without an extension, so `nul.txt` is
+//! written as `%6Eul.txt`. A `%` is itself escaped, as `%25`, only where it would otherwise be
+//! restored as an escape, so that every name is restored as it was, without a record of the names
+//! next to them. Entries are written under their escaped names like entries whose names are
+//! shortened, see `limits::write_shortened`.
+
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+
+use clap::ValueEnum;
+use hashbrown::HashSet;
+
+use crate::lumins::file_ops::{FileOps, FileSets};
+use crate::lumins::limits;
+use crate::lumins::parse::Options;
+
+/// Enum to represent how names are sanitized, with `--sanitize-names`
+#[derive(Eq, PartialEq, Debug, Clone, Copy, ValueEnum)]
+pub enum Sanitize {
+    /// What FAT, exFAT, and NTFS do not allow in names is escaped
+    Escape,
+    /// Escapes are restored to the characters they stand for
+    Restore,
+}
+
+/// The characters that are escaped wherever they are in names, which FAT, exFAT, and NTFS do not
+/// allow, with `%`, which escapes them
+pub const ESCAPED: &[u8] = b"\"*:<>?\\|%";
+
+/// The characters that are escaped at the end of names, which Windows drops from them
+pub const TRAILING: &[u8] = b". ";
+
+/// The names that Windows reserves for devices, which a name must not start with, but for an
+/// extension or anything else that is not a letter or a digit
+const RESERVED: &[&str] = &["CON", "PRN", "AUX", "NUL"];
+
+/// The names that Windows reserves for ports, which are reserved with any digit after them
+const RESERVED_PORTS: &[&str] = &["COM", "LPT"];
+
+/// Checks whether `name` is one of the names that Windows reserves, see `RESERVED`
+///
+/// Everything after the letters and digits at the start of `name` is ignored, which is never
+/// changed by escaping it, so a name is reserved both before and after it is escaped.
+fn is_reserved(name: &[u8]) -> bool {
+    let stem = name
+        .iter()
+        .position(|byte| !byte.is_ascii_alphanumeric())
+        .map_or(name, |end| &name[..end]);
+    let is = |reserved: &str| stem.eq_ignore_ascii_case(reserved.as_bytes());
+    RESERVED.iter().any(|reserved| is(reserved))
+        || (stem.len() == 4
+            && stem[3].is_ascii_digit()
+            && RESERVED_PORTS
+                .iter()
+                .any(|port| is(&format!("{}{}", port, stem[3] as char))))
+}
+
+/// Checks whether `byte` is escaped where it is in a name, at its start if `first`, and followed
+/// by `rest`
+fn is_escaped(byte: u8, first: bool, rest: &[u8]) -> bool {
+    ESCAPED.contains(&byte)
+        || (0x01..0x20).contains(&byte)
+        || (rest.is_empty() && TRAILING.contains(&byte))
+        || (first && is_reserved(&[&[byte], rest].concat()))
+}
+
+/// Gets the character that the escape at `i` of `name` is restored as, if there is one
+fn escape_at(name: &[u8], i: usize) -> Option<u8> {
+    let code = std::str::from_utf8(name.get(i + 1..i + 3)?).ok()?;
+    let byte = u8::from_str_radix(code, 16).ok()?;
+    // Only the escapes that `escape` writes are restored
+    Some(byte).filter(|&byte| {
+        name[i] == b'%'
+            && code == format!("{:02X}", byte)
+            && is_escaped(byte, i == 0, &name[i + 3..])
+    })
+}
+
+/// Escapes what FAT, exFAT, and NTFS do not allow in `name`
+///
+/// # Returns
+/// The escaped name, which is none if nothing in `name` needed to be escaped
+pub fn escape(name: &OsStr) -> Option<OsString> {
+    let bytes = as_bytes(name)?;
+    let mut escaped = Vec::with_capacity(bytes.len());
+    for (i, &byte) in bytes.iter().enumerate() {
+        let illegal = match byte {
+            b'%' => escape_at(bytes, i).is_some(),
+            _ => is_escaped(byte, i == 0, &bytes[i + 1..]),
+        };
+        if illegal {
+            escaped.extend(format!("%{:02X}", byte).bytes());
+        } else {
+            escaped.push(byte);
+        }
+    }
+    Some(escaped)
+        .filter(|escaped| escaped.as_slice() != bytes)
+        .map(from_bytes)
+}
+
+/// Restores the characters of `name` that `escape` escaped
+///
+/// # Returns
+/// The restored name, which is none if `name` has no escapes
+pub fn restore(name: &OsStr) -> Option<OsString> {
+    let bytes = as_bytes(name)?;
+    let mut restored = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match escape_at(bytes, i) {
+            Some(byte) => {
+                restored.push(byte);
+                i += 3;
+            }
+            None => {
+                restored.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    Some(restored)
+        .filter(|restored| restored.as_slice() != bytes)
+        .map(from_bytes)
+}
+
+/// Finds the entries of `file_sets` whose names are escaped with `Sanitize::Escape`, or restored
+/// with `Sanitize::Restore`
+///
+/// Only the outermost entry is found of a directory whose name changes, since what is in it is
+/// written by a run of its own, see `limits::write_shortened`.
+///
+/// # Returns
+/// The path of every such entry, with the name it is written under, sorted by path
+pub fn renames(file_sets: &FileSets, options: &Options) -> Vec<(PathBuf, OsString)> {
+    let rename: fn(&OsStr) -> Option<OsString> = match options.sanitize_names {
+        Some(Sanitize::Escape) => escape,
+        Some(Sanitize::Restore) => restore,
+        None => return Vec::new(),
+    };
+
+    let mut paths: Vec<&PathBuf> = file_sets
+        .files()
+        .iter()
+        .map(FileOps::path)
+        .chain(file_sets.dirs().iter().map(FileOps::path))
+        .chain(file_sets.symlinks().iter().map(FileOps::path))
+        .chain(file_sets.specials().iter().map(FileOps::path))
+        .collect();
+    paths.sort();
+
+    let mut renames: Vec<(PathBuf, OsString)> = Vec::new();
+    let mut renamed: HashSet<&Path> = HashSet::new();
+    for path in paths {
+        if limits::is_within(path, &renamed) {
+            continue;
+        }
+        if let Some(name) = path.file_name().and_then(rename) {
+            renames.push((path.clone(), name));
+            renamed.insert(path.as_path());
+        }
+    }
+    renames
+}
+
+/// Gets the bytes of `name`, which are only known for names that are valid Unicode outside of
+/// Unix
+fn as_bytes(name: &OsStr) -> Option<&[u8]> {
+    #[cfg(target_family = "unix")]
+    {
+        Some(std::os::unix::ffi::OsStrExt::as_bytes(name))
+    }
+
+    #[cfg(not(target_family = "unix"))]
+    {
+        name.to_str().map(str::as_bytes)
+    }
+}
+
+/// Converts `bytes` of `as_bytes` back into a name
+fn from_bytes(bytes: Vec<u8>) -> OsString {
+    #[cfg(target_family = "unix")]
+    {
+        std::os::unix::ffi::OsStringExt::from_vec(bytes)
+    }
+
+    // Only ASCII is ever escaped or restored, so what was valid Unicode still is
+    #[cfg(not(target_family = "unix"))]
+    {
+        OsString::from(String::from_utf8_lossy(&bytes).into_owned())
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_sanitize {
+    use super::*;
+    use crate::lumins::file_ops::{Dir, File};
+    use hashbrown::HashSet;
+
+    #[test]
+    fn escapes_and_restores() {
+        let escaped = |name: &str| escape(OsStr::new(name)).map(|name| name.into_string().unwrap());
+        assert_eq!(escaped("plain name.txt"), None);
+        assert_eq!(escaped("50% off"), None);
+        assert_eq!(escaped("a:b?.txt"), Some("a%3Ab%3F.txt".to_string()));
+        assert_eq!(escaped("<\"*|\\>"), Some("%3C%22%2A%7C%5C%3E".to_string()));
+        // Only what would be restored as an escape is escaped
+        assert_eq!(escaped("a%3Ab"), Some("a%253Ab".to_string()));
+        assert_eq!(escaped("a%3ab%41"), None);
+
+        // Control characters, and dots and spaces that end names, are escaped
+        assert_eq!(escaped("a\tb\u{1}"), Some("a%09b%01".to_string()));
+        assert_eq!(escaped("name."), Some("name%2E".to_string()));
+        assert_eq!(escaped("a. b "), Some("a. b%20".to_string()));
+        assert_eq!(escaped(".hidden"), None);
+        // Names that Windows reserves are escaped with any extension
+        assert_eq!(escaped("CON"), Some("%43ON".to_string()));
+        assert_eq!(escaped("nul.txt"), Some("%6Eul.txt".to_string()));
+        assert_eq!(escaped("Com1.tar.gz"), Some("%43om1.tar.gz".to_string()));
+        assert_eq!(escaped("lpt9"), Some("%6Cpt9".to_string()));
+        assert_eq!(escaped("console"), None);
+        assert_eq!(escaped("COM10"), None);
+        assert_eq!(escaped("xcon"), None);
+
+        let names = [
+            "a:b",
+            "a%3Ab",
+            "%%3A:",
+            "%3%3A",
+            "x%25:",
+            "%",
+            "é:?",
+            "a\u{1f}",
+            "%1F",
+            "%01x",
+            "CON",
+            "con.",
+            "CON ",
+            "%43ON",
+            "%43ON:x",
+            "%6Eul.txt",
+            "aux%2E",
+            "PRN.txt.",
+            "a.",
+            "a..",
+            "a%2E",
+            "a%2E.",
+            "a%20",
+            "%2E",
+            " ",
+            "x%2Ey",
+            "COM1:",
+            "%4COM10",
+        ];
+        for name in &names {
+            let escaped = escape(OsStr::new(name)).unwrap_or_else(|| OsString::from(name));
+            let escaped_name = escaped.to_string_lossy();
+            assert!(!escaped_name.contains(|c| ":?*<>|\"\\".contains(c) || c < ' '));
+            assert!(!escaped_name.ends_with(['.', ' ']));
+            assert!(!is_reserved(escaped_name.as_bytes()), "{:?}", escaped);
+            let restored = restore(&escaped).unwrap_or_else(|| escaped.clone());
+            assert_eq!(restored, OsString::from(name));
+        }
+        assert_eq!(restore(OsStr::new("50% off")), None);
+    }
+
+    #[test]
+    fn finds_renames() {
+        let file_sets = FileSets::with(
+            [
+                File::from("plain", 1),
+                File::from("a:b", 1),
+                File::from("dir?/c:d", 1),
+            ]
+            .iter()
+            .cloned()
+            .collect(),
+            [Dir::from("dir?")].iter().cloned().collect(),
+            HashSet::new(),
+        );
+        assert!(renames(&file_sets, &Options::default()).is_empty());
+
+        // Nothing in a directory that is renamed is found again
+        let options = Options {
+            sanitize_names: Some(Sanitize::Escape),
+            ..Options::default()
+        };
+        assert_eq!(
+            renames(&file_sets, &options),
+            vec![
+                (PathBuf::from("a:b"), OsString::from("a%3Ab")),
+                (PathBuf::from("dir?"), OsString::from("dir%3F")),
+            ]
+        );
+    }
+}
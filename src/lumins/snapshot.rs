@@ -0,0 +1,413 @@
+//! Creates read-only snapshots of destinations on snapshot-capable filesystems, and incremental
+//! snapshots of directories as hard-linked trees
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::{fs, io};
+
+use log::{error, info, warn};
+use rayon::prelude::*;
+
+use crate::lumins::core;
+use crate::lumins::file_ops::{self, File, FileOps};
+use crate::lumins::history;
+use crate::lumins::parse::Options;
+use crate::lumins::platform;
+use crate::progress::{self, PROGRESS_BAR};
+
+/// Suffix of a hard-linked snapshot that is still being created
+const PARTIAL_SUFFIX: &str = ".partial";
+
+/// Enum to represent filesystems that support snapshots
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub enum SnapshotFs {
+    Btrfs,
+    Zfs,
+}
+
+/// Determines whether `path` lives on a snapshot-capable filesystem
+///
+/// # Arguments
+/// * `path`: path to check
+///
+/// # Returns
+/// * Ok(Some): The snapshot-capable filesystem `path` is on
+/// * Ok(None): If the filesystem of `path` does not support snapshots
+/// * Error: If the filesystem of `path` cannot be determined
+#[cfg(target_os = "linux")]
+pub fn snapshot_fs(path: &str) -> Result<Option<SnapshotFs>, io::Error> {
+    use std::ffi::CString;
+    use std::mem::MaybeUninit;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::PathBuf;
+
+    const BTRFS_SUPER_MAGIC: i64 = 0x9123_683e;
+    const ZFS_SUPER_MAGIC: i64 = 0x2fc1_2fc1;
+
+    let c_path = CString::new(PathBuf::from(path).as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut stat = MaybeUninit::<libc::statfs>::uninit();
+
+    // Safe since `c_path` is a valid C string and `stat` is large enough to hold the result
+    if unsafe { libc::statfs(c_path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let stat = unsafe { stat.assume_init() };
+
+    #[allow(clippy::unnecessary_cast)]
+    match stat.f_type as i64 {
+        BTRFS_SUPER_MAGIC => Ok(Some(SnapshotFs::Btrfs)),
+        ZFS_SUPER_MAGIC => Ok(Some(SnapshotFs::Zfs)),
+        _ => Ok(None),
+    }
+}
+
+/// Determines whether `path` lives on a snapshot-capable filesystem
+///
+/// Snapshots are only supported on Linux, so this always returns Ok(None)
+/// after checking that `path` exists
+#[cfg(not(target_os = "linux"))]
+pub fn snapshot_fs(path: &str) -> Result<Option<SnapshotFs>, io::Error> {
+    fs::metadata(path)?;
+    Ok(None)
+}
+
+/// Creates a read-only snapshot of `dest`, if its filesystem supports it
+///
+/// On btrfs, `dest` must be a subvolume, and the snapshot is created next to it as
+/// `dest.lumins-<timestamp>`. On ZFS, the dataset containing `dest` is snapshotted as
+/// `dataset@lumins-<timestamp>`. Anywhere else, a warning is logged and nothing is snapshotted.
+///
+/// # Arguments
+/// * `dest`: destination directory to snapshot
+///
+/// # Returns
+/// * Ok(Some): The name of the created snapshot
+/// * Ok(None): If `dest` is not a btrfs subvolume or on ZFS, so no snapshot was created
+/// * Error: If `dest` is an invalid directory or the snapshot could not be created
+pub fn snapshot_dest(dest: &str) -> Result<Option<String>, io::Error> {
+    let dest_path = fs::canonicalize(dest)?;
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+
+    let snapshot = match snapshot_fs(dest)? {
+        Some(SnapshotFs::Btrfs) if is_subvolume(&dest_path)? => {
+            let snapshot = format!("{}.lumins-{}", dest_path.to_string_lossy(), timestamp);
+            run_command(
+                Command::new("btrfs")
+                    .args(["subvolume", "snapshot", "-r"])
+                    .arg(&dest_path)
+                    .arg(&snapshot),
+            )?;
+            snapshot
+        }
+        Some(SnapshotFs::Zfs) => {
+            let dataset = run_command(
+                Command::new("zfs")
+                    .args(["list", "-H", "-o", "name"])
+                    .arg(&dest_path),
+            )?;
+            let snapshot = format!("{}@lumins-{}", dataset.trim(), timestamp);
+            run_command(Command::new("zfs").arg("snapshot").arg(&snapshot))?;
+            snapshot
+        }
+        Some(SnapshotFs::Btrfs) => {
+            warn!(
+                "Snapshot Warning -- {} is not a btrfs subvolume, so it is not snapshotted",
+                dest
+            );
+            return Ok(None);
+        }
+        None => {
+            warn!(
+                "Snapshot Warning -- {} is not on a snapshot-capable filesystem (btrfs, zfs), so \
+                 it is not snapshotted",
+                dest
+            );
+            return Ok(None);
+        }
+    };
+
+    info!("Creating snapshot {:?} of {:?}", snapshot, dest_path);
+    Ok(Some(snapshot))
+}
+
+/// Checks whether `path`, which is on btrfs, is the root of a subvolume, which is the only kind of
+/// directory btrfs can snapshot
+#[cfg(target_family = "unix")]
+fn is_subvolume(path: &Path) -> Result<bool, io::Error> {
+    use std::os::unix::fs::MetadataExt;
+
+    // The root directory of every subvolume has the first free object ID of btrfs as its inode
+    const BTRFS_FIRST_FREE_OBJECTID: u64 = 256;
+
+    Ok(fs::metadata(path)?.ino() == BTRFS_FIRST_FREE_OBJECTID)
+}
+
+/// Checks whether `path` is the root of a btrfs subvolume, which is never the case off of Unix
+#[cfg(not(target_family = "unix"))]
+fn is_subvolume(_path: &Path) -> Result<bool, io::Error> {
+    Ok(false)
+}
+
+/// Creates an incremental snapshot of `src` in the snapshot directory `dest`
+///
+/// Each snapshot is a full copy of `src`, in a directory of `dest` named after the time it was
+/// taken, e.g. `2020-06-15-033000`. Files that have not changed since the latest snapshot in
+/// `dest` are hard links into it instead of copies, so every snapshot is a complete restore point
+/// that only takes up the space of what changed. Files are unchanged if they have the same size
+/// and modification time, or with `Options::secure`, the same size and contents.
+///
+/// The snapshot is created as `<name>.partial`, and only renamed once it is complete, so that an
+/// interrupted snapshot is never linked against.
+///
+/// # Arguments
+/// * `src`: Source directory
+/// * `dest`: Directory of snapshots
+/// * `options`: Options of the run
+///
+/// # Returns
+/// * Ok: The path of the created snapshot
+/// * Error: If `src` or `dest` is an invalid directory, or the snapshot could not be created
+pub fn snapshot_tree(src: &str, dest: &str, options: &Options) -> Result<PathBuf, io::Error> {
+    let options = &core::for_run(options);
+    let previous = latest_snapshot(Path::new(dest))?;
+
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let name = snapshot_name(secs);
+    let snapshot = Path::new(dest).join(&name);
+    if fs::symlink_metadata(&snapshot).is_ok() {
+        return Err(io::Error::other(format!(
+            "Snapshot Error -- {:?} already exists",
+            snapshot
+        )));
+    }
+    let partial = Path::new(dest).join(name + PARTIAL_SUFFIX);
+
+    let src_file_sets = file_ops::get_all_files(src)?;
+    let src_files = src_file_sets.files();
+    let src_dirs = src_file_sets.dirs();
+    let src_symlinks = src_file_sets.symlinks();
+    progress::progress_init((src_files.len() + src_dirs.len() + src_symlinks.len()) as u64);
+
+    // Count the entries that could not be copied, since their errors are only logged
+    let failed = AtomicUsize::new(0);
+    let copy = |entry: &dyn FileOps| {
+        let copied = entry.copy(Path::new(src), &partial, options);
+        if !copied {
+            failed.fetch_add(1, Ordering::Relaxed);
+        }
+        PROGRESS_BAR.inc(1);
+        copied
+    };
+
+    fs::create_dir_all(&partial)?;
+    src_dirs.par_iter().for_each(|dir| {
+        copy(dir);
+    });
+    file_ops::for_each_prioritized(Vec::from_par_iter(src_files.into_par_iter()), |file| {
+        let linked = match &previous {
+            Some(previous) if is_unchanged(file, src, previous, options) => {
+                match fs::hard_link(previous.join(file.path()), partial.join(file.path())) {
+                    Ok(_) => {
+                        info!("Linking file {:?}", partial.join(file.path()));
+                        true
+                    }
+                    // Copy the file instead, e.g. if it has too many links
+                    Err(e) => {
+                        info!("Linking file {:?} failed: {}", partial.join(file.path()), e);
+                        false
+                    }
+                }
+            }
+            _ => false,
+        };
+        if linked {
+            PROGRESS_BAR.inc(1);
+        } else if copy(file) {
+            copy_modified(file, src, &partial);
+        }
+    });
+    src_symlinks.par_iter().for_each(|symlink| {
+        copy(symlink);
+    });
+    // Files that were still being written are copied too, though the snapshot is incomplete
+    file_ops::copy_deferred(options);
+
+    if failed.into_inner() > 0 {
+        return Err(io::Error::other(format!(
+            "Snapshot Error -- {:?} is incomplete, since some files could not be copied",
+            partial
+        )));
+    }
+    fs::rename(&partial, &snapshot)?;
+    info!("Creating snapshot {:?}", snapshot);
+    Ok(snapshot)
+}
+
+/// Finds the latest complete snapshot in the snapshot directory `dest`
+///
+/// # Returns
+/// * Ok(Some): The path of the latest snapshot
+/// * Ok(None): If `dest` has no complete snapshots
+/// * Error: If `dest` could not be read
+fn latest_snapshot(dest: &Path) -> Result<Option<PathBuf>, io::Error> {
+    let mut latest = None;
+    for entry in fs::read_dir(dest)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if is_snapshot_name(&name) && entry.file_type()?.is_dir() {
+            latest = latest.max(Some(name));
+        }
+    }
+    Ok(latest.map(|name| dest.join(name)))
+}
+
+/// Names a snapshot taken at `secs` since the Unix epoch, e.g. `2020-06-15-033000`, so that
+/// snapshots sort by the time they were taken
+fn snapshot_name(secs: u64) -> String {
+    let (year, month, day, hour, minute, second) = history::utc_time(secs);
+    format!(
+        "{:04}-{:02}-{:02}-{:02}{:02}{:02}",
+        year, month, day, hour, minute, second
+    )
+}
+
+/// Checks whether `name` is the name of a complete snapshot, see `snapshot_name`
+fn is_snapshot_name(name: &str) -> bool {
+    let bytes = name.as_bytes();
+    bytes.len() == 17
+        && bytes.iter().enumerate().all(|(i, b)| match i {
+            4 | 7 | 10 => *b == b'-',
+            _ => b.is_ascii_digit(),
+        })
+}
+
+/// Checks whether `file` in `src` is unchanged from the same file in the snapshot `previous`
+fn is_unchanged(file: &File, src: &str, previous: &Path, options: &Options) -> bool {
+    let (src_metadata, previous_metadata) = match (
+        fs::symlink_metadata(Path::new(src).join(file.path())),
+        fs::symlink_metadata(previous.join(file.path())),
+    ) {
+        (Ok(src_metadata), Ok(previous_metadata)) => (src_metadata, previous_metadata),
+        _ => return false,
+    };
+    if !previous_metadata.is_file() || previous_metadata.len() != src_metadata.len() {
+        return false;
+    }
+
+    if options.secure {
+        let previous = previous.to_string_lossy();
+        let hash = file_ops::hash_file_secure(file, src);
+        hash.is_some() && hash == file_ops::hash_file_secure(file, &previous)
+    } else {
+        match (src_metadata.modified(), previous_metadata.modified()) {
+            (Ok(src_modified), Ok(previous_modified)) => src_modified == previous_modified,
+            _ => false,
+        }
+    }
+}
+
+/// Copies the modification time of `file` in `src` to the copy in `dest`, so that the next
+/// snapshot can tell whether it changed
+fn copy_modified(file: &File, src: &str, dest: &Path) {
+    let dest_file = dest.join(file.path());
+    let modified = fs::symlink_metadata(Path::new(src).join(file.path()))
+        .and_then(|metadata| metadata.modified())
+        .and_then(|modified| platform::set_modified(&dest_file, modified));
+    if let Err(e) = modified {
+        error!(
+            "Error -- Setting modification time of {:?}: {}",
+            dest_file, e
+        );
+    }
+}
+
+/// Runs `command` and returns its stdout, or an error containing its stderr if it fails
+fn run_command(command: &mut Command) -> Result<String, io::Error> {
+    let output = command.output()?;
+
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "Snapshot Error -- {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_snapshot {
+    use super::*;
+
+    #[test]
+    fn invalid_dir() {
+        assert!(snapshot_fs("/?").is_err());
+        assert!(snapshot_dest("/?").is_err());
+    }
+
+    #[test]
+    fn unsupported_fs() {
+        if snapshot_fs("src").unwrap().is_none() {
+            assert!(snapshot_dest("src").unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn snapshot_names() {
+        assert_eq!(snapshot_name(1_592_191_800), "2020-06-15-033000");
+        assert!(is_snapshot_name(&snapshot_name(0)));
+        assert!(!is_snapshot_name("2020-06-15-033000.partial"));
+        assert!(!is_snapshot_name("2020-06-15 033000"));
+        assert!(!is_snapshot_name("latest"));
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn hard_linked_snapshots() {
+        use std::os::unix::fs::MetadataExt;
+
+        const TEST_DIR: &str = "test_snapshot_hard_linked";
+        const TEST_DIR_OUT: &str = "test_snapshot_hard_linked_out";
+
+        fs::create_dir_all([TEST_DIR, "dir"].join("/")).unwrap();
+        fs::create_dir_all([TEST_DIR_OUT, "2000-01-01-000000.partial"].join("/")).unwrap();
+        fs::write([TEST_DIR, "dir/same"].join("/"), b"same").unwrap();
+        fs::write([TEST_DIR, "changed"].join("/"), b"old").unwrap();
+
+        // Partial snapshots are never linked against
+        let first = snapshot_tree(TEST_DIR, TEST_DIR_OUT, &Options::default()).unwrap();
+        assert_eq!(fs::read(first.join("dir/same")).unwrap(), b"same");
+        assert_eq!(fs::metadata(first.join("changed")).unwrap().nlink(), 1);
+
+        // Snapshots are named by the second they were taken
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        fs::write([TEST_DIR, "changed"].join("/"), b"new").unwrap();
+        let second = snapshot_tree(TEST_DIR, TEST_DIR_OUT, &Options::default()).unwrap();
+        assert!(second > first);
+
+        let inode = |path: PathBuf| fs::metadata(path).unwrap().ino();
+        assert_eq!(
+            inode(first.join("dir/same")),
+            inode(second.join("dir/same"))
+        );
+        assert_ne!(inode(first.join("changed")), inode(second.join("changed")));
+        assert_eq!(fs::read(first.join("changed")).unwrap(), b"old");
+        assert_eq!(fs::read(second.join("changed")).unwrap(), b"new");
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_dir_all(TEST_DIR_OUT).unwrap();
+    }
+}
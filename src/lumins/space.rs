@@ -0,0 +1,144 @@
+//! Checks that the destination has room for what a run writes, before anything is written
+//!
+//! A run that fills its destination fails partway through, and leaves a tree that is neither the
+//! old one nor the new one. So once a run is planned, the bytes that it writes, less those of the
+//! files that it deletes or overwrites, are compared with the space that is free in the
+//! destination, and the run is aborted if they do not fit. `--no-check-space` skips the check,
+//! such as for filesystems that compress or deduplicate what is written to them.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use indicatif::HumanBytes;
+use log::warn;
+use rayon::prelude::*;
+
+use crate::lumins::file_ops::{File, FileOps};
+use crate::lumins::parse::Options;
+use crate::lumins::platform;
+
+/// Checks that `needed` bytes fit in `dest`, once the bytes that `freed` counts are freed
+///
+/// `freed` is only called if `needed` bytes do not fit as it is, since counting what is freed may
+/// take as long as listing the files again. Filesystems that do not report their free space are
+/// not checked.
+///
+/// # Errors
+/// This function will return an error if `needed` bytes do not fit in `dest`, unless
+/// `Options::check_space` is not set
+pub fn check<F>(needed: u64, freed: F, dest: &str, options: &Options) -> Result<(), io::Error>
+where
+    F: FnOnce() -> u64,
+{
+    if needed == 0 || !options.check_space {
+        return Ok(());
+    }
+    let free = match platform::free_space(Path::new(dest)) {
+        Ok(free) => free,
+        Err(e) => {
+            warn!("Could not get the free space of {}: {}", dest, e);
+            return Ok(());
+        }
+    };
+    if needed <= free {
+        return Ok(());
+    }
+    let needed = needed.saturating_sub(freed());
+    if needed > free {
+        return Err(io::Error::new(
+            io::ErrorKind::StorageFull,
+            format!(
+                "Space Error -- {} has {} free, but the run needs {} \
+                 (--no-check-space skips this check)",
+                dest,
+                HumanBytes(free),
+                HumanBytes(needed)
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Gets the number of bytes that copying `files` writes
+pub fn size_of<'a, I>(files: I) -> u64
+where
+    I: ParallelIterator<Item = &'a File>,
+{
+    files.map(|file| file.size()).sum()
+}
+
+/// Gets the number of bytes of the files in `dest` that copying `files` overwrites
+///
+/// With `options.lookup`, only files that may be in `dest` are looked up, once the directories
+/// that would hold them are read, see `lookup`.
+pub fn overwritten<'a, I>(files: I, dest: &str, options: &Options) -> u64
+where
+    I: ParallelIterator<Item = &'a File>,
+{
+    let dest = Path::new(dest);
+    let files: Vec<&File> = files.collect();
+    if let Some(lookup) = &options.lookup {
+        lookup.prefetch(files.iter().map(|file| file.path().as_path()));
+    }
+    files
+        .par_iter()
+        .filter(|file| match &options.lookup {
+            Some(lookup) => !lookup.is_missing(dest, file.path()),
+            None => true,
+        })
+        .filter_map(|file| fs::symlink_metadata(dest.join(file.path())).ok())
+        .filter(|metadata| metadata.is_file())
+        .map(|metadata| metadata.len())
+        .sum()
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_space {
+    use super::*;
+    use crate::lumins::lookup::Lookup;
+    use hashbrown::HashSet;
+    use std::sync::Arc;
+
+    #[test]
+    fn refuses_what_does_not_fit() {
+        const TEST_DEST: &str = "test_space_refuses_what_does_not_fit";
+
+        fs::create_dir_all(TEST_DEST).unwrap();
+        let free = platform::free_space(Path::new(TEST_DEST)).unwrap();
+        assert!(check(1, || 0, TEST_DEST, &Options::default()).is_ok());
+
+        let e = check(free + (1 << 30), || 0, TEST_DEST, &Options::default()).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::StorageFull);
+        // What is freed makes room, and is only counted when it is needed
+        assert!(check(free + (1 << 30), || 1 << 31, TEST_DEST, &Options::default()).is_ok());
+        assert!(check(1, || panic!("counted"), TEST_DEST, &Options::default()).is_ok());
+        let options = Options {
+            check_space: false,
+            ..Options::default()
+        };
+        assert!(check(u64::MAX, || 0, TEST_DEST, &options).is_ok());
+
+        fs::write([TEST_DEST, "old"].join("/"), b"four").unwrap();
+        let files: HashSet<File> = [File::from("old", 8), File::from("new", 2)]
+            .iter()
+            .cloned()
+            .collect();
+        assert_eq!(size_of(files.par_iter()), 10);
+        assert_eq!(
+            overwritten(files.par_iter(), TEST_DEST, &Options::default()),
+            4
+        );
+        let options = Options {
+            lookup: Some(Arc::new(Lookup::new(TEST_DEST))),
+            ..Options::default()
+        };
+        assert_eq!(overwritten(files.par_iter(), TEST_DEST, &options), 4);
+
+        fs::remove_dir_all(TEST_DEST).unwrap();
+    }
+}
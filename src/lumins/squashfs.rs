@@ -0,0 +1,702 @@
+//! Writes directory trees into SquashFS images, which are compressed, mountable, and read only
+//!
+//! Images are written as SquashFS 4.0, which Linux mounts and `unsquashfs` extracts. Every block
+//! of data is compressed on its own, see `compress`, and stored as it is if it does not get
+//! smaller. Files are not packed into shared fragment blocks, and have no extended attributes,
+//! so that the image can be written in a single pass over the tree.
+
+use std::fs;
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hashbrown::HashMap;
+use log::warn;
+use rayon::prelude::*;
+
+use crate::lumins::archive::{self, EntryMetadata};
+use crate::lumins::compress::{self, Codec};
+use crate::lumins::dirfd;
+use crate::lumins::file_ops::{self, FileOps};
+use crate::lumins::parse::Options;
+use crate::progress::{self, PROGRESS_BAR};
+
+/// Magic number that SquashFS images start with, "hsqs"
+const MAGIC: u32 = 0x7371_7368;
+
+/// Size of the blocks that the contents of files are compressed in
+const BLOCK_SIZE: usize = 128 * 1024;
+
+/// Size of the blocks that inodes, directories, and IDs are compressed in
+const METADATA_SIZE: usize = 8192;
+
+/// Size of the superblock, after which the contents of files start
+const SUPERBLOCK_SIZE: u64 = 96;
+
+/// Longest name that SquashFS allows
+const MAX_NAME: usize = 256;
+
+/// Number of blocks of file contents that are compressed in parallel
+const BLOCKS_PER_BATCH: usize = 64;
+
+/// Flags of the superblock, for images without fragments or extended attributes
+const FLAGS: u16 = 0x0010 | 0x0200;
+
+/// Marks a table or a fragment as absent
+const INVALID: u64 = u64::MAX;
+
+/// Checks whether `path` is a SquashFS image that a tree can be written into, by its extension
+pub fn is_squashfs(path: &str) -> bool {
+    path.ends_with(".sqfs") || path.ends_with(".squashfs")
+}
+
+/// Enum to represent what an entry of an image is
+#[derive(Eq, PartialEq, Debug, Clone)]
+enum Kind {
+    /// A directory, with the indices of its entries, sorted by name
+    Dir(Vec<usize>),
+    File(u64),
+    Symlink(PathBuf),
+}
+
+/// An entry of the tree to write into an image
+#[derive(Eq, PartialEq, Debug, Clone)]
+struct Entry {
+    /// Path of the entry, relative to the root of the tree
+    path: PathBuf,
+    name: Vec<u8>,
+    kind: Kind,
+    metadata: EntryMetadata,
+    /// Index of the directory that contains the entry, where the root contains itself
+    parent: usize,
+}
+
+/// Writes every file, directory, and symlink in `src` into a new SquashFS image at `dest`,
+/// compressed with the codec that `options` select
+///
+/// With `Options::deterministic`, entries have no owners and their timestamps are clamped to
+/// `SOURCE_DATE_EPOCH`, or to 0 if it is not set, like in archives. If the image can not be
+/// completely written, `dest` is removed.
+///
+/// # Errors
+/// This function will return an error if `src` is an invalid directory, if any name is too long
+/// for SquashFS, or if the image could not be written
+pub fn write_squashfs(src: &str, dest: &str, options: &Options) -> Result<(), io::Error> {
+    let deterministic = if options.deterministic {
+        Some(archive::source_date_epoch())
+    } else {
+        None
+    };
+    let entries = plan(src, deterministic)?;
+    let now = deterministic.unwrap_or_else(|| {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |now| now.as_secs())
+    });
+
+    let file = fs::File::create(dest).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("SquashFS Error -- Creating {:?}: {}", dest, e),
+        )
+    })?;
+    progress::progress_init(entries.len() as u64);
+
+    let mut writer = BufWriter::new(file);
+    let result = write(
+        &entries,
+        Path::new(src),
+        options.compression,
+        now,
+        &mut writer,
+    )
+    .and_then(|_| writer.flush());
+    if result.is_err() {
+        fs::remove_file(dest).ok();
+    }
+    result
+}
+
+/// Lists `src` into the entries of an image, with the root first and every directory before what
+/// it contains
+///
+/// Special files are left out with a warning, like in images.
+///
+/// # Errors
+/// This function will return an error if `src` is an invalid directory, if the metadata of any
+/// entry can not be read, or if any name is too long for SquashFS
+fn plan(src: &str, deterministic: Option<u64>) -> Result<Vec<Entry>, io::Error> {
+    let file_sets = file_ops::get_all_files(src)?;
+    let src_path = Path::new(src);
+    for special in file_sets.specials() {
+        warn!(
+            "SquashFS Warning -- Leaving out {:?}, since images can not hold special files",
+            src_path.join(special.path())
+        );
+    }
+
+    let mut paths: Vec<(&PathBuf, Kind)> = Vec::new();
+    paths.extend(
+        file_sets
+            .dirs()
+            .iter()
+            .map(|dir| (dir.path(), Kind::Dir(Vec::new()))),
+    );
+    paths.extend(
+        file_sets
+            .files()
+            .iter()
+            .map(|file| (file.path(), Kind::File(file.size()))),
+    );
+    paths.extend(
+        file_sets
+            .symlinks()
+            .iter()
+            .map(|symlink| (symlink.path(), Kind::Symlink(symlink.target().clone()))),
+    );
+    // Path's ordering compares the bytes of components, so entries are sorted as SquashFS sorts
+    // them, and parents sort before their children
+    paths.sort_unstable_by(|a, b| a.0.cmp(b.0));
+
+    let metadata = |path: &Path| {
+        let metadata = fs::symlink_metadata(src_path.join(path)).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("SquashFS Error -- {:?}: {}", src_path.join(path), e),
+            )
+        })?;
+        let mut metadata = EntryMetadata::from(&metadata);
+        if let Some(epoch) = deterministic {
+            metadata.uid = 0;
+            metadata.gid = 0;
+            metadata.mtime = metadata.mtime.min(epoch);
+        }
+        Ok::<EntryMetadata, io::Error>(metadata)
+    };
+
+    let mut entries = vec![Entry {
+        path: PathBuf::new(),
+        name: Vec::new(),
+        kind: Kind::Dir(Vec::new()),
+        metadata: metadata(Path::new(""))?,
+        parent: 0,
+    }];
+    let mut indices: HashMap<&Path, usize> = HashMap::new();
+    indices.insert(Path::new(""), 0);
+    let mut too_long = 0;
+    for (path, kind) in paths {
+        // Safe to unwrap since every listed path is inside the root
+        let name = archive::archive_path(Path::new(path.file_name().unwrap()))?;
+        let parent = match indices.get(path.parent().unwrap_or_else(|| Path::new(""))) {
+            Some(&parent) => parent,
+            None => continue,
+        };
+        if name.len() > MAX_NAME {
+            warn!(
+                "SquashFS Error -- {:?} can not be written into SquashFS images, since its name is \
+                 {} bytes long, while SquashFS only allows {}",
+                src_path.join(path),
+                name.len(),
+                MAX_NAME
+            );
+            too_long += 1;
+            continue;
+        }
+
+        let index = entries.len();
+        if let Kind::Dir(_) = kind {
+            indices.insert(path, index);
+        }
+        if let Kind::Dir(children) = &mut entries[parent].kind {
+            children.push(index);
+        }
+        entries.push(Entry {
+            path: path.clone(),
+            name,
+            kind,
+            metadata: metadata(path)?,
+            parent,
+        });
+    }
+
+    if too_long > 0 {
+        return Err(io::Error::other(format!(
+            "SquashFS Error -- {} names in {:?} are too long for SquashFS images, so nothing is \
+             written",
+            too_long, src
+        )));
+    }
+    Ok(entries)
+}
+
+/// Writes `entries` of `src` into `writer` as a SquashFS image
+///
+/// The contents of every file are written after the superblock, followed by the inode table, the
+/// directory table, and the ID table. Inodes are written from the last entry to the root, so that
+/// the inodes of a directory's entries are all written, and can be listed, before its own.
+fn write<W: Write + Seek>(
+    entries: &[Entry],
+    src: &Path,
+    codec: Codec,
+    now: u64,
+    writer: &mut W,
+) -> Result<(), io::Error> {
+    writer.write_all(&[0; SUPERBLOCK_SIZE as usize])?;
+    let mut position = SUPERBLOCK_SIZE;
+
+    // Where the contents of each file start, and the size of each of their blocks
+    let mut blocks: Vec<(u64, Vec<u32>)> = vec![(0, Vec::new()); entries.len()];
+    for (i, entry) in entries.iter().enumerate() {
+        if let Kind::File(size) = entry.kind {
+            blocks[i] = (
+                position,
+                write_contents(src, &entry.path, size, codec, writer, &mut position).map_err(
+                    |e| {
+                        io::Error::new(
+                            e.kind(),
+                            format!(
+                                "SquashFS Error -- Writing {:?}: {}",
+                                src.join(&entry.path),
+                                e
+                            ),
+                        )
+                    },
+                )?,
+            );
+        }
+        PROGRESS_BAR.inc(1);
+    }
+
+    let mut ids = IdTable::default();
+    let mut inodes = MetadataWriter::new(codec);
+    let mut dirs = MetadataWriter::new(codec);
+    // The root is numbered last, after every entry in it
+    let numbers: Vec<u32> = (0..entries.len())
+        .map(|i| (entries.len() - i) as u32)
+        .collect();
+    let mut references = vec![0; entries.len()];
+    for (i, entry) in entries.iter().enumerate().rev() {
+        let mut inode = Vec::new();
+        let header = |inode: &mut Vec<u8>, inode_type: u16, ids: &mut IdTable| {
+            inode.extend(inode_type.to_le_bytes());
+            inode.extend(((entry.metadata.mode & 0o7777) as u16).to_le_bytes());
+            inode.extend(ids.index(entry.metadata.uid).to_le_bytes());
+            inode.extend(ids.index(entry.metadata.gid).to_le_bytes());
+            inode.extend((entry.metadata.mtime.min(u64::from(u32::MAX)) as u32).to_le_bytes());
+            inode.extend(numbers[i].to_le_bytes());
+        };
+
+        match &entry.kind {
+            Kind::File(size) => {
+                let (start, sizes) = &blocks[i];
+                if *start <= u64::from(u32::MAX) && *size <= u64::from(u32::MAX) {
+                    header(&mut inode, 2, &mut ids);
+                    inode.extend((*start as u32).to_le_bytes());
+                    inode.extend(u32::MAX.to_le_bytes());
+                    inode.extend(0u32.to_le_bytes());
+                    inode.extend((*size as u32).to_le_bytes());
+                } else {
+                    header(&mut inode, 9, &mut ids);
+                    inode.extend(start.to_le_bytes());
+                    inode.extend(size.to_le_bytes());
+                    inode.extend(0u64.to_le_bytes());
+                    inode.extend(1u32.to_le_bytes());
+                    inode.extend(u32::MAX.to_le_bytes());
+                    inode.extend(0u32.to_le_bytes());
+                    inode.extend(u32::MAX.to_le_bytes());
+                }
+                for size in sizes {
+                    inode.extend(size.to_le_bytes());
+                }
+            }
+            Kind::Symlink(target) => {
+                let target = archive::archive_path(target)?;
+                header(&mut inode, 3, &mut ids);
+                inode.extend(1u32.to_le_bytes());
+                inode.extend((target.len() as u32).to_le_bytes());
+                inode.extend(target);
+            }
+            Kind::Dir(children) => {
+                let (block, offset) = dirs.position();
+                let listing = listing(entries, children, &references, &numbers);
+                dirs.write(&listing);
+                // Sizes of directories count the 3 bytes of "." and ".." too
+                let size = listing.len() as u64 + 3;
+                let links = 2 + children
+                    .iter()
+                    .filter(|&&child| matches!(entries[child].kind, Kind::Dir(_)))
+                    .count() as u32;
+                let parent = if i == 0 {
+                    entries.len() as u32 + 1
+                } else {
+                    numbers[entry.parent]
+                };
+                if size <= u64::from(u16::MAX) {
+                    header(&mut inode, 1, &mut ids);
+                    inode.extend((block as u32).to_le_bytes());
+                    inode.extend(links.to_le_bytes());
+                    inode.extend((size as u16).to_le_bytes());
+                    inode.extend(offset.to_le_bytes());
+                    inode.extend(parent.to_le_bytes());
+                } else {
+                    header(&mut inode, 8, &mut ids);
+                    inode.extend(links.to_le_bytes());
+                    inode.extend((size as u32).to_le_bytes());
+                    inode.extend((block as u32).to_le_bytes());
+                    inode.extend(parent.to_le_bytes());
+                    inode.extend(0u16.to_le_bytes());
+                    inode.extend(offset.to_le_bytes());
+                    inode.extend(u32::MAX.to_le_bytes());
+                }
+                PROGRESS_BAR.inc(1);
+            }
+        }
+        if let Kind::Symlink(_) = entry.kind {
+            PROGRESS_BAR.inc(1);
+        }
+        references[i] = inodes.reference();
+        inodes.write(&inode);
+    }
+    if ids.ids.len() > usize::from(u16::MAX) {
+        return Err(io::Error::other(
+            "SquashFS Error -- the tree has more owners than SquashFS images can hold",
+        ));
+    }
+
+    let inode_table = position;
+    let inodes = inodes.finish();
+    writer.write_all(&inodes)?;
+    let directory_table = inode_table + inodes.len() as u64;
+    let dirs = dirs.finish();
+    writer.write_all(&dirs)?;
+
+    // The IDs are written in metadata blocks, which a table after them points to
+    let id_blocks = directory_table + dirs.len() as u64;
+    let mut id_writer = MetadataWriter::new(codec);
+    for id in &ids.ids {
+        id_writer.write(&id.to_le_bytes());
+    }
+    let id_block_starts = id_writer.starts();
+    let id_metadata = id_writer.finish();
+    writer.write_all(&id_metadata)?;
+    let id_table = id_blocks + id_metadata.len() as u64;
+    for start in &id_block_starts {
+        writer.write_all(&(id_blocks + start).to_le_bytes())?;
+    }
+    let bytes_used = id_table + 8 * id_block_starts.len() as u64;
+
+    // Images are padded to 4 KiB, so that they can be mounted from block devices
+    let padded = bytes_used.div_ceil(4096) * 4096;
+    writer.write_all(&vec![0; (padded - bytes_used) as usize])?;
+
+    let mut superblock = Vec::with_capacity(SUPERBLOCK_SIZE as usize);
+    superblock.extend(MAGIC.to_le_bytes());
+    superblock.extend((entries.len() as u32).to_le_bytes());
+    superblock.extend((now.min(u64::from(u32::MAX)) as u32).to_le_bytes());
+    superblock.extend((BLOCK_SIZE as u32).to_le_bytes());
+    superblock.extend(0u32.to_le_bytes());
+    let compression: u16 = match codec {
+        Codec::Gzip => 1,
+        Codec::Zstd => 6,
+    };
+    superblock.extend(compression.to_le_bytes());
+    superblock.extend((BLOCK_SIZE.trailing_zeros() as u16).to_le_bytes());
+    superblock.extend(FLAGS.to_le_bytes());
+    superblock.extend((ids.ids.len() as u16).to_le_bytes());
+    superblock.extend(4u16.to_le_bytes());
+    superblock.extend(0u16.to_le_bytes());
+    superblock.extend(references[0].to_le_bytes());
+    superblock.extend(bytes_used.to_le_bytes());
+    superblock.extend(id_table.to_le_bytes());
+    superblock.extend(INVALID.to_le_bytes());
+    superblock.extend(inode_table.to_le_bytes());
+    superblock.extend(directory_table.to_le_bytes());
+    // An empty fragment table would be right after the directory table
+    superblock.extend(id_blocks.to_le_bytes());
+    superblock.extend(INVALID.to_le_bytes());
+    writer.seek(SeekFrom::Start(0))?;
+    writer.write_all(&superblock)
+}
+
+/// Writes the contents of the file `path` inside `src` in compressed blocks, which must still be
+/// `size` bytes large
+///
+/// Blocks that are all zeros are not written at all, since SquashFS reads them as holes.
+///
+/// # Returns
+/// The size of each block, as it is written in the inode of the file
+fn write_contents<W: Write>(
+    src: &Path,
+    path: &Path,
+    size: u64,
+    codec: Codec,
+    writer: &mut W,
+    position: &mut u64,
+) -> Result<Vec<u32>, io::Error> {
+    let mut file = dirfd::open_file(src, path)?.take(size);
+    let mut sizes = Vec::new();
+    let mut read = 0;
+    loop {
+        let mut batch = Vec::with_capacity(BLOCKS_PER_BATCH);
+        while batch.len() < BLOCKS_PER_BATCH && read < size {
+            let length = (size - read).min(BLOCK_SIZE as u64) as usize;
+            let mut block = vec![0; length];
+            file.read_exact(&mut block).map_err(|e| match e.kind() {
+                io::ErrorKind::UnexpectedEof => io::Error::other(format!(
+                    "it shrank from {} bytes while it was written",
+                    size
+                )),
+                _ => e,
+            })?;
+            read += length as u64;
+            batch.push(block);
+        }
+        if batch.is_empty() {
+            return Ok(sizes);
+        }
+
+        let compressed: Vec<Option<Vec<u8>>> = batch
+            .par_iter()
+            .map(|block| {
+                if block.iter().all(|&byte| byte == 0) {
+                    return None;
+                }
+                // Blocks that could not be compressed are stored as they are, like those that do
+                // not get smaller
+                compress::compress(codec, block)
+                    .ok()
+                    .filter(|compressed| compressed.len() < block.len())
+            })
+            .collect();
+        for (block, compressed) in batch.iter().zip(compressed) {
+            let (contents, size) = match compressed {
+                Some(compressed) => {
+                    let size = compressed.len() as u32;
+                    (compressed, size)
+                }
+                None if block.iter().all(|&byte| byte == 0) => (Vec::new(), 0),
+                // Blocks that are stored as they are have bit 24 set
+                None => (block.clone(), block.len() as u32 | 1 << 24),
+            };
+            writer.write_all(&contents)?;
+            *position += contents.len() as u64;
+            sizes.push(size);
+        }
+    }
+}
+
+/// Gets the directory listing of `children`, whose inodes are at `references`
+///
+/// Entries are listed in runs that share a header, where every run has at most 256 entries whose
+/// inodes are in the same metadata block, and whose inode numbers are close to the first.
+fn listing(entries: &[Entry], children: &[usize], references: &[u64], numbers: &[u32]) -> Vec<u8> {
+    let mut listing = Vec::new();
+    let mut i = 0;
+    while i < children.len() {
+        let first = children[i];
+        let block = references[first] >> 16;
+        let run = children[i..]
+            .iter()
+            .take(256)
+            .take_while(|&&child| {
+                references[child] >> 16 == block
+                    && (i64::from(i16::MIN)..=i64::from(i16::MAX))
+                        .contains(&(i64::from(numbers[child]) - i64::from(numbers[first])))
+            })
+            .count();
+
+        listing.extend((run as u32 - 1).to_le_bytes());
+        listing.extend((block as u32).to_le_bytes());
+        listing.extend(numbers[first].to_le_bytes());
+        for &child in &children[i..i + run] {
+            let inode_type: u16 = match entries[child].kind {
+                Kind::Dir(_) => 1,
+                Kind::File(_) => 2,
+                Kind::Symlink(_) => 3,
+            };
+            let name = &entries[child].name;
+            listing.extend((references[child] as u16).to_le_bytes());
+            listing.extend(((numbers[child] as i64 - numbers[first] as i64) as i16).to_le_bytes());
+            listing.extend(inode_type.to_le_bytes());
+            listing.extend((name.len() as u16 - 1).to_le_bytes());
+            listing.extend(name);
+        }
+        i += run;
+    }
+    listing
+}
+
+/// A table of the owner and group IDs of an image, which inodes refer to by index
+#[derive(Default)]
+struct IdTable {
+    ids: Vec<u32>,
+    indices: HashMap<u32, u16>,
+}
+
+impl IdTable {
+    /// Gets the index of `id`, adding it to the table if it is not in it yet
+    fn index(&mut self, id: u64) -> u16 {
+        let id = id.min(u64::from(u32::MAX)) as u32;
+        let ids = &mut self.ids;
+        *self.indices.entry(id).or_insert_with(|| {
+            ids.push(id);
+            (ids.len() - 1) as u16
+        })
+    }
+}
+
+/// A writer of a metadata table, which is compressed in blocks of `METADATA_SIZE` bytes
+struct MetadataWriter {
+    codec: Codec,
+    /// Blocks that are written so far, each after the 2 bytes of its size
+    table: Vec<u8>,
+    /// Start of each block in the table
+    starts: Vec<u64>,
+    /// Contents of the block that is being written
+    block: Vec<u8>,
+}
+
+impl MetadataWriter {
+    fn new(codec: Codec) -> Self {
+        MetadataWriter {
+            codec,
+            table: Vec::new(),
+            starts: Vec::new(),
+            block: Vec::with_capacity(METADATA_SIZE),
+        }
+    }
+
+    /// Gets where the next write starts, as the start of its block in the table and the offset
+    /// in the block
+    fn position(&self) -> (u64, u16) {
+        (self.table.len() as u64, self.block.len() as u16)
+    }
+
+    /// Gets the reference of where the next write starts, as inodes are referred to
+    fn reference(&self) -> u64 {
+        let (block, offset) = self.position();
+        (block << 16) | u64::from(offset)
+    }
+
+    fn write(&mut self, mut bytes: &[u8]) {
+        while !bytes.is_empty() {
+            let length = bytes.len().min(METADATA_SIZE - self.block.len());
+            self.block.extend(&bytes[..length]);
+            bytes = &bytes[length..];
+            if self.block.len() == METADATA_SIZE {
+                self.flush();
+            }
+        }
+    }
+
+    /// Compresses the current block into the table, or stores it as it is if it does not get
+    /// smaller, which sets bit 15 of its size
+    fn flush(&mut self) {
+        if self.block.is_empty() {
+            return;
+        }
+        self.starts.push(self.table.len() as u64);
+        match compress::compress(self.codec, &self.block) {
+            Ok(compressed) if compressed.len() < self.block.len() => {
+                self.table.extend((compressed.len() as u16).to_le_bytes());
+                self.table.extend(compressed);
+            }
+            _ => {
+                self.table
+                    .extend((self.block.len() as u16 | 0x8000).to_le_bytes());
+                self.table.extend(&self.block);
+            }
+        }
+        self.block.clear();
+    }
+
+    /// Gets the start of each block in the table, including the one that is being written
+    fn starts(&self) -> Vec<u64> {
+        let mut starts = self.starts.clone();
+        if !self.block.is_empty() {
+            starts.push(self.table.len() as u64);
+        }
+        starts
+    }
+
+    /// Writes the last block, and gets the table
+    fn finish(mut self) -> Vec<u8> {
+        self.flush();
+        self.table
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_write_squashfs {
+    use super::*;
+
+    #[test]
+    fn metadata_blocks() {
+        let mut writer = MetadataWriter::new(Codec::Zstd);
+        writer.write(&[1; 100]);
+        assert_eq!(writer.reference(), 100);
+        writer.write(&[2; METADATA_SIZE - 100]);
+        writer.write(&(0..100).collect::<Vec<u8>>());
+        let start = writer.starts()[1];
+        assert_eq!(writer.reference(), (start << 16) | 100);
+        let table = writer.finish();
+        // Blocks of repeated bytes are compressed, and the last one is stored as it is
+        assert!(u16::from_le_bytes([table[0], table[1]]) < 0x8000);
+        assert_eq!(
+            u16::from_le_bytes([table[start as usize], table[start as usize + 1]]),
+            0x8000 | 100
+        );
+    }
+
+    #[test]
+    fn ids_are_shared() {
+        let mut ids = IdTable::default();
+        assert_eq!(ids.index(1000), 0);
+        assert_eq!(ids.index(0), 1);
+        assert_eq!(ids.index(1000), 0);
+        assert_eq!(ids.ids, [1000, 0]);
+    }
+
+    #[test]
+    fn superblock() {
+        const TEST_DIR: &str = "test_squashfs_write_squashfs_superblock";
+        const TEST_DIR_OUT: &str = "test_squashfs_write_squashfs_superblock_out";
+
+        fs::create_dir_all([TEST_DIR, "b/c"].join("/")).unwrap();
+        fs::create_dir_all(TEST_DIR_OUT).unwrap();
+        fs::write([TEST_DIR, "a"].join("/"), b"file a").unwrap();
+        fs::write([TEST_DIR, "b/c/d"].join("/"), vec![b'd'; 3 * BLOCK_SIZE]).unwrap();
+
+        let dest = [TEST_DIR_OUT, "tree.sqfs"].join("/");
+        let options = Options {
+            deterministic: true,
+            ..Options::default()
+        };
+        write_squashfs(TEST_DIR, &dest, &options).unwrap();
+        let image = fs::read(&dest).unwrap();
+
+        let u16_at = |i: usize| u16::from_le_bytes([image[i], image[i + 1]]);
+        let u32_at =
+            |i: usize| u32::from_le_bytes([image[i], image[i + 1], image[i + 2], image[i + 3]]);
+        let u64_at = |i: usize| u64::from(u32_at(i)) | u64::from(u32_at(i + 4)) << 32;
+        assert_eq!(u32_at(0), MAGIC);
+        assert_eq!(u32_at(4), 5);
+        assert_eq!(u16_at(20), 6);
+        assert_eq!(u16_at(28), 4);
+        assert_eq!(image.len() % 4096, 0);
+        assert!(u64_at(64) < u64_at(72) && u64_at(72) <= u64_at(48));
+        // The repeated block compresses well, while the small file can not get smaller
+        assert!(u64_at(64) < 2048);
+        assert_eq!(&image[96..102], b"file a");
+
+        write_squashfs(TEST_DIR, &dest, &options).unwrap();
+        assert_eq!(fs::read(&dest).unwrap(), image);
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_dir_all(TEST_DIR_OUT).unwrap();
+    }
+}
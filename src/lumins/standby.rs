@@ -0,0 +1,153 @@
+//! Verifies warm standby mirrors, once or continuously
+//!
+//! `lms verify --continuous` keeps checking that a mirror still matches its source, at a low IO
+//! priority so that the file server it runs on is not slowed down, and stops with an error at the
+//! first divergence, after posting an alert to a webhook.
+
+use std::io::{self, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+use log::{error, info, warn};
+
+use crate::lumins::json;
+use crate::lumins::manifest;
+use crate::lumins::parse::Options;
+use crate::lumins::platform;
+
+/// How long to wait between the passes of a continuous verification, unless given
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Checks `src` against `against`, once, or with `Options::continuous` until they diverge
+///
+/// If `against` is a directory, it is a mirror of `src` that must hold the same files, see
+/// `manifest::verify_mirror`. Otherwise, it is a manifest of checksums printed by the hash
+/// subcommand, see `manifest::verify_tree`. Continuous verifications run at a low IO priority,
+/// wait `options.interval` between passes, and post an alert to `options.webhook` when they fail.
+///
+/// # Errors
+/// This function will return an error as soon as a pass fails, such as when a file differs
+pub fn verify(src: &str, against: &str, options: &Options) -> Result<(), io::Error> {
+    let pass = || {
+        if Path::new(against).is_dir() {
+            manifest::verify_mirror(src, against)
+        } else {
+            manifest::verify_tree(src, against)
+        }
+    };
+    if !options.continuous {
+        return pass();
+    }
+
+    if let Err(e) = platform::lower_io_priority() {
+        warn!("Could not lower the IO priority of verify: {}", e);
+    }
+    let interval = options.interval.unwrap_or(DEFAULT_INTERVAL);
+    let result = (1..)
+        .map(|passes| {
+            let result = pass();
+            if result.is_ok() {
+                info!("{} matched {} in pass {}", src, against, passes);
+                thread::sleep(interval);
+            }
+            result
+        })
+        .find(Result::is_err)
+        // Safe to unwrap since the passes only end at an error
+        .unwrap();
+
+    if let (Some(url), Err(e)) = (&options.webhook, &result) {
+        if let Err(alert_error) = alert(url, src, against, e) {
+            error!("Webhook Error -- {}: {}", url, alert_error);
+        }
+    }
+    result
+}
+
+/// Posts the divergence of `src` and `against` to the webhook at `url`, as a JSON object
+///
+/// The alert is posted by `curl`, which is given 30 seconds to deliver it.
+///
+/// # Errors
+/// This function will return an error if `curl` could not be run, or if it failed
+fn alert(url: &str, src: &str, against: &str, e: &io::Error) -> Result<(), io::Error> {
+    let body = alert_body(src, against, e);
+    let mut curl = Command::new("curl")
+        .args(["-fsS", "-m", "30", "-H", "Content-Type: application/json"])
+        .args(["--data-binary", "@-", url])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // Safe to unwrap since stdin is piped
+    let written = curl.stdin.take().unwrap().write_all(body.as_bytes());
+    let output = curl.wait_with_output()?;
+    written?;
+    if !output.status.success() {
+        return Err(io::Error::other(
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Formats the alert that `src` diverged from `against` because of `e`
+fn alert_body(src: &str, against: &str, e: &io::Error) -> String {
+    format!(
+        "{{\"event\":\"divergence\",\"source\":{},\"mirror\":{},\"error\":{}}}",
+        json::string(src),
+        json::string(against),
+        json::string(&e.to_string())
+    )
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_verify {
+    use super::*;
+    use crate::lumins::core;
+    use std::fs;
+
+    #[test]
+    fn stops_at_divergence() {
+        const TEST_SRC: &str = "test_standby_stops_at_divergence";
+        const TEST_MIRROR: &str = "test_standby_stops_at_divergence_mirror";
+
+        fs::create_dir_all([TEST_SRC, "dir"].join("/")).unwrap();
+        fs::write([TEST_SRC, "dir/file"].join("/"), b"file").unwrap();
+        core::copy(TEST_SRC, TEST_MIRROR, &Options::default()).unwrap();
+        verify(TEST_SRC, TEST_MIRROR, &Options::default()).unwrap();
+
+        // Files that only the mirror holds are divergences too
+        fs::write([TEST_MIRROR, "extra"].join("/"), b"extra").unwrap();
+        let options = Options {
+            continuous: true,
+            interval: Some(Duration::from_millis(1)),
+            ..Options::default()
+        };
+        assert!(verify(TEST_SRC, TEST_MIRROR, &options).is_err());
+
+        fs::remove_file([TEST_MIRROR, "extra"].join("/")).unwrap();
+        fs::write([TEST_MIRROR, "dir/file"].join("/"), b"rot!").unwrap();
+        assert!(verify(TEST_SRC, TEST_MIRROR, &Options::default()).is_err());
+
+        fs::remove_dir_all(TEST_SRC).unwrap();
+        fs::remove_dir_all(TEST_MIRROR).unwrap();
+    }
+
+    #[test]
+    fn formats_alerts() {
+        let e = io::Error::other("Verify Error -- 1 of 2 files did not match \"a\"");
+        assert_eq!(
+            alert_body("a", "b", &e),
+            "{\"event\":\"divergence\",\"source\":\"a\",\"mirror\":\"b\",\
+             \"error\":\"Verify Error -- 1 of 2 files did not match \\\"a\\\"\"}"
+        );
+    }
+}
@@ -0,0 +1,187 @@
+//! Reports what a tree holds, such as to estimate how long a first sync of it takes
+//!
+//! The tree is listed by the same parallel traversal that copies and syncs list their sources
+//! with, so listing it takes about as long as the listing a sync of it starts with.
+
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use indicatif::HumanBytes;
+use rayon::prelude::*;
+
+use crate::lumins::file_ops::{self, FileOps};
+
+/// Upper bounds of the buckets of the size histogram, where files of 1 GiB or more fall into the
+/// last bucket
+const BUCKETS: [u64; 7] = [1, 1 << 10, 1 << 14, 1 << 18, 1 << 22, 1 << 26, 1 << 30];
+
+/// Labels of the buckets of the size histogram
+const BUCKET_LABELS: [&str; 8] = [
+    "empty", "< 1KB", "< 16KB", "< 256KB", "< 4MB", "< 64MB", "< 1GB", ">= 1GB",
+];
+
+/// Width of the longest bar of the size histogram
+const BAR_WIDTH: u64 = 40;
+
+/// A struct that represents what a tree holds
+#[derive(Eq, PartialEq, Debug, Clone, Default)]
+pub struct Stats {
+    pub files: u64,
+    pub dirs: u64,
+    pub symlinks: u64,
+    /// Sockets, FIFOs, and device nodes
+    pub specials: u64,
+    /// Total size of the files
+    pub bytes: u64,
+    /// The largest files, with their sizes, from the largest
+    pub largest: Vec<(PathBuf, u64)>,
+    /// Number of files in each bucket of `BUCKET_LABELS`
+    pub histogram: [u64; BUCKET_LABELS.len()],
+}
+
+impl Stats {
+    /// Lists `src` in parallel and gathers what it holds, keeping its `top` largest files
+    ///
+    /// # Errors
+    /// This function will return an error if `src` could not be listed
+    pub fn gather(src: &str, top: usize) -> Result<Self, io::Error> {
+        let file_sets = file_ops::get_all_files(src)?;
+        let files = file_sets.files();
+
+        let histogram = files
+            .par_iter()
+            .fold(
+                || [0; BUCKET_LABELS.len()],
+                |mut histogram, file| {
+                    histogram[bucket(file.size())] += 1;
+                    histogram
+                },
+            )
+            .reduce(
+                || [0; BUCKET_LABELS.len()],
+                |mut histogram, other| {
+                    histogram.iter_mut().zip(&other).for_each(|(a, b)| *a += b);
+                    histogram
+                },
+            );
+
+        let mut largest: Vec<(&Path, u64)> = files
+            .par_iter()
+            .map(|file| (file.path().as_path(), file.size()))
+            .collect();
+        if top < largest.len() {
+            largest.select_nth_unstable_by(top, |a, b| b.1.cmp(&a.1));
+            largest.truncate(top);
+        }
+        largest.par_sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+
+        Ok(Stats {
+            files: files.len() as u64,
+            dirs: file_sets.dirs().len() as u64,
+            symlinks: file_sets.symlinks().len() as u64,
+            specials: file_sets.specials().len() as u64,
+            bytes: files.par_iter().map(|file| file.size()).sum(),
+            largest: largest
+                .into_iter()
+                .map(|(path, size)| (path.to_path_buf(), size))
+                .collect(),
+            histogram,
+        })
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Files        {}", self.files)?;
+        writeln!(f, "Directories  {}", self.dirs)?;
+        writeln!(f, "Symlinks     {}", self.symlinks)?;
+        writeln!(f, "Specials     {}", self.specials)?;
+        write!(f, "Total size   {}", HumanBytes(self.bytes))?;
+
+        if !self.largest.is_empty() {
+            write!(f, "\n\nLargest files:")?;
+            for (path, size) in &self.largest {
+                write!(
+                    f,
+                    "\n  {:>10}  {}",
+                    HumanBytes(*size).to_string(),
+                    path.display()
+                )?;
+            }
+        }
+
+        let max = self.histogram.iter().copied().max().unwrap_or(0);
+        if max > 0 {
+            write!(f, "\n\nFile sizes:")?;
+            for (label, count) in BUCKET_LABELS.iter().zip(&self.histogram) {
+                // Buckets with any files show at least part of a bar
+                let bar = (count * BAR_WIDTH).div_ceil(max) as usize;
+                write!(f, "\n  {:>8}  {:>10}", label, count)?;
+                if bar > 0 {
+                    write!(f, "  {}", "#".repeat(bar))?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Gets the bucket of the size histogram that a file of `size` bytes falls into
+fn bucket(size: u64) -> usize {
+    BUCKETS
+        .iter()
+        .position(|&bound| size < bound)
+        .unwrap_or(BUCKETS.len())
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_stats {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn buckets() {
+        assert_eq!(bucket(0), 0);
+        assert_eq!(bucket(1), 1);
+        assert_eq!(bucket(1023), 1);
+        assert_eq!(bucket(1024), 2);
+        assert_eq!(bucket(1 << 30), 7);
+        assert_eq!(bucket(u64::MAX), 7);
+    }
+
+    #[test]
+    fn gathers_stats() {
+        const TEST_DIR: &str = "test_stats_gathers_stats";
+
+        fs::create_dir_all([TEST_DIR, "sub"].join("/")).unwrap();
+        fs::write([TEST_DIR, "empty"].join("/"), b"").unwrap();
+        fs::write([TEST_DIR, "small"].join("/"), b"small").unwrap();
+        fs::write([TEST_DIR, "sub/large"].join("/"), vec![0; 2048]).unwrap();
+        fs::write([TEST_DIR, "sub/medium"].join("/"), vec![0; 1024]).unwrap();
+
+        let stats = Stats::gather(TEST_DIR, 2).unwrap();
+        assert_eq!((stats.files, stats.dirs, stats.bytes), (4, 1, 3077));
+        assert_eq!(
+            stats.largest,
+            [
+                (PathBuf::from("sub/large"), 2048),
+                (PathBuf::from("sub/medium"), 1024)
+            ]
+        );
+        assert_eq!(stats.histogram, [1, 1, 2, 0, 0, 0, 0, 0]);
+        let report = stats.to_string();
+        assert!(report.starts_with("Files        4\nDirectories  1\n"));
+        assert!(report.contains(&format!("\n    < 16KB           2  {}", "#".repeat(40))));
+        assert!(report.contains(&format!("\n     < 1KB           1  {}", "#".repeat(20))));
+        assert!(report.ends_with("\n    >= 1GB           0"));
+
+        assert_eq!(Stats::gather(TEST_DIR, 10).unwrap().largest.len(), 4);
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+}
@@ -0,0 +1,313 @@
+//! A lazily loaded view of a directory tree, for embedders that browse and compare trees
+//! interactively
+//!
+//! Unlike `file_ops::get_all_files`, nothing is listed, stat'ed or hashed until it is asked for,
+//! so that a GUI or terminal browser only pays for the directories and files it shows. Everything
+//! that is loaded is cached, until it is invalidated after the tree changed.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use hashbrown::HashMap;
+
+use crate::lumins::dirfd::{self, DirHandle, EntryKind};
+use crate::lumins::file_ops;
+use crate::lumins::platform;
+
+/// An entry of a directory in a `FileTree`
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub struct Node {
+    path: PathBuf,
+    kind: EntryKind,
+}
+
+impl Node {
+    /// Gets the path of the entry, relative to the root of its tree
+    pub fn path(&self) -> &PathBuf {
+        &self.path
+    }
+
+    /// Gets the type of the entry
+    pub fn kind(&self) -> &EntryKind {
+        &self.kind
+    }
+}
+
+/// How an entry of one tree compares to the entry at the same path of another
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub enum Comparison {
+    /// Both are directories, symlinks with the same target, or files with the same contents
+    Same,
+    /// Both exist, but are of different types, or have different targets or contents
+    Different,
+    /// Only the first tree has the entry
+    OnlyLeft,
+    /// Only the second tree has the entry
+    OnlyRight,
+}
+
+/// A directory tree whose listings, metadata and hashes are loaded on demand
+///
+/// All methods take paths relative to the root of the tree, where the empty path is the root
+/// itself. Listings and hashes open paths like `dirfd` does, so that no component of them is
+/// followed if it is a symlink.
+#[derive(Debug)]
+pub struct FileTree {
+    root: PathBuf,
+    children: Mutex<HashMap<PathBuf, Arc<Vec<Node>>>>,
+    metadata: Mutex<HashMap<PathBuf, Arc<fs::Metadata>>>,
+    hashes: Mutex<HashMap<PathBuf, Arc<Vec<u8>>>>,
+}
+
+impl FileTree {
+    /// Opens the tree rooted at `root`, without listing anything yet
+    ///
+    /// # Errors
+    /// This function will return an error if `root` is not a directory that can be opened
+    pub fn open(root: &str) -> Result<Self, io::Error> {
+        DirHandle::open(Path::new(root))?;
+        Ok(FileTree {
+            root: PathBuf::from(root),
+            children: Mutex::new(HashMap::new()),
+            metadata: Mutex::new(HashMap::new()),
+            hashes: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Gets the root of the tree
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Lists the entries of the directory `relative`, sorted by name
+    ///
+    /// Entries whose types can not be read are left out.
+    ///
+    /// # Errors
+    /// This function will return an error if `relative` is not a directory that can be read
+    pub fn children(&self, relative: &Path) -> Result<Arc<Vec<Node>>, io::Error> {
+        if let Some(children) = self.children.lock().unwrap().get(relative) {
+            return Ok(Arc::clone(children));
+        }
+
+        let dir = if relative.as_os_str().is_empty() {
+            DirHandle::open(&self.root)?
+        } else {
+            let (parent, name) = dirfd::open_parent(&self.root, relative, false)?;
+            parent.open_dir(&name)?
+        };
+        let mut children: Vec<Node> = dir
+            .entries()?
+            .into_iter()
+            .filter_map(|(name, kind)| {
+                Some(Node {
+                    path: relative.join(name),
+                    kind: kind.ok()?,
+                })
+            })
+            .collect();
+        children.sort_by(|a, b| a.path.cmp(&b.path));
+
+        let children = Arc::new(children);
+        self.children
+            .lock()
+            .unwrap()
+            .insert(relative.to_path_buf(), Arc::clone(&children));
+        Ok(children)
+    }
+
+    /// Gets the entry at `relative`, from the listing of its parent
+    ///
+    /// # Returns
+    /// * Ok(Some): The entry
+    /// * Ok(None): If the parent of `relative` has no such entry
+    /// * Error: If the parent of `relative` could not be listed, or `relative` is the root
+    pub fn node(&self, relative: &Path) -> Result<Option<Node>, io::Error> {
+        let parent = match relative.parent() {
+            Some(parent) => parent,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "the root has no parent to list it",
+                ))
+            }
+        };
+        match self.children(parent) {
+            Ok(children) => Ok(children.iter().find(|node| node.path == relative).cloned()),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Gets the metadata of `relative`, without following it if it is a symlink
+    ///
+    /// # Errors
+    /// This function will return an error if the metadata of `relative` can not be read
+    pub fn metadata(&self, relative: &Path) -> Result<Arc<fs::Metadata>, io::Error> {
+        if let Some(metadata) = self.metadata.lock().unwrap().get(relative) {
+            return Ok(Arc::clone(metadata));
+        }
+
+        let metadata = Arc::new(fs::symlink_metadata(platform::long_path(
+            &self.root.join(relative),
+        ))?);
+        self.metadata
+            .lock()
+            .unwrap()
+            .insert(relative.to_path_buf(), Arc::clone(&metadata));
+        Ok(metadata)
+    }
+
+    /// Gets the BLAKE2b hash of the file `relative`, like `file_ops::hash_file_secure`
+    ///
+    /// # Returns
+    /// * Some: The hash of the file
+    /// * None: If the file could not be hashed
+    pub fn hash(&self, relative: &Path) -> Option<Arc<Vec<u8>>> {
+        if let Some(hash) = self.hashes.lock().unwrap().get(relative) {
+            return Some(Arc::clone(hash));
+        }
+
+        let hash = Arc::new(file_ops::hash_secure(&self.root, relative)?);
+        self.hashes
+            .lock()
+            .unwrap()
+            .insert(relative.to_path_buf(), Arc::clone(&hash));
+        Some(hash)
+    }
+
+    /// Compares the entry at `relative` in this tree to the one in `other`
+    ///
+    /// Directories are compared by type only, not by their entries, and files are only hashed if
+    /// they have the same size.
+    ///
+    /// # Errors
+    /// This function will return an error if the parent of `relative` could not be listed in
+    /// either tree, or neither tree has `relative`
+    pub fn compare(&self, other: &FileTree, relative: &Path) -> Result<Comparison, io::Error> {
+        let comparison = match (self.node(relative)?, other.node(relative)?) {
+            (None, None) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("{:?} is in neither tree", relative),
+                ))
+            }
+            (Some(_), None) => Comparison::OnlyLeft,
+            (None, Some(_)) => Comparison::OnlyRight,
+            (Some(left), Some(right)) => match (left.kind, right.kind) {
+                (EntryKind::File(left_size), EntryKind::File(right_size))
+                    if left_size == right_size =>
+                {
+                    match (self.hash(relative), other.hash(relative)) {
+                        (Some(left_hash), Some(right_hash)) if left_hash == right_hash => {
+                            Comparison::Same
+                        }
+                        _ => Comparison::Different,
+                    }
+                }
+                (left, right) if left == right => Comparison::Same,
+                _ => Comparison::Different,
+            },
+        };
+        Ok(comparison)
+    }
+
+    /// Drops everything cached for `relative` and everything inside it, as well as the listing
+    /// of its parent, so that they are loaded again from disk
+    pub fn invalidate(&self, relative: &Path) {
+        let stale = |path: &PathBuf| path.starts_with(relative);
+        self.children.lock().unwrap().retain(|path, _| !stale(path));
+        self.metadata.lock().unwrap().retain(|path, _| !stale(path));
+        self.hashes.lock().unwrap().retain(|path, _| !stale(path));
+        if let Some(parent) = relative.parent() {
+            self.children.lock().unwrap().remove(parent);
+        }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_tree {
+    use super::*;
+
+    #[test]
+    fn children() {
+        const TEST_DIR: &str = "test_tree_children";
+        let path = |path: &str| [TEST_DIR, path].join("/");
+
+        fs::create_dir_all(path("b/c")).unwrap();
+        fs::write(path("a"), b"a").unwrap();
+        fs::write(path("b/c/d"), b"dd").unwrap();
+
+        let tree = FileTree::open(TEST_DIR).unwrap();
+        let children = tree.children(Path::new("")).unwrap();
+        assert_eq!(
+            *children,
+            vec![
+                Node {
+                    path: PathBuf::from("a"),
+                    kind: EntryKind::File(1)
+                },
+                Node {
+                    path: PathBuf::from("b"),
+                    kind: EntryKind::Dir
+                },
+            ]
+        );
+        assert_eq!(
+            tree.node(Path::new("b/c/d")).unwrap().unwrap().kind(),
+            &EntryKind::File(2)
+        );
+        assert_eq!(tree.node(Path::new("b/e")).unwrap(), None);
+        assert!(tree.children(Path::new("a")).is_err());
+
+        // Listings are cached until they are invalidated
+        fs::write(path("e"), b"e").unwrap();
+        assert_eq!(tree.children(Path::new("")).unwrap().len(), 2);
+        tree.invalidate(Path::new("e"));
+        assert_eq!(tree.children(Path::new("")).unwrap().len(), 3);
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+
+    #[test]
+    fn compare() {
+        const TEST_DIR: &str = "test_tree_compare";
+        const TEST_DIR_OUT: &str = "test_tree_compare_out";
+        let path = |dir: &str, path: &str| [dir, path].join("/");
+
+        for dir in &[TEST_DIR, TEST_DIR_OUT] {
+            fs::create_dir_all(path(dir, "dir")).unwrap();
+            fs::write(path(dir, "same"), b"same").unwrap();
+        }
+        fs::write(path(TEST_DIR, "changed"), b"abc").unwrap();
+        fs::write(path(TEST_DIR_OUT, "changed"), b"abd").unwrap();
+        fs::write(path(TEST_DIR, "left"), b"").unwrap();
+        fs::create_dir_all(path(TEST_DIR_OUT, "right")).unwrap();
+        fs::write(path(TEST_DIR, "type"), b"").unwrap();
+        fs::create_dir_all(path(TEST_DIR_OUT, "type")).unwrap();
+
+        let left = FileTree::open(TEST_DIR).unwrap();
+        let right = FileTree::open(TEST_DIR_OUT).unwrap();
+        let compare = |relative: &str| left.compare(&right, Path::new(relative)).unwrap();
+        assert_eq!(compare("dir"), Comparison::Same);
+        assert_eq!(compare("same"), Comparison::Same);
+        assert_eq!(compare("changed"), Comparison::Different);
+        assert_eq!(compare("left"), Comparison::OnlyLeft);
+        assert_eq!(compare("right"), Comparison::OnlyRight);
+        assert_eq!(compare("type"), Comparison::Different);
+        assert!(left.compare(&right, Path::new("none")).is_err());
+
+        // Only files that have to be compared by contents are hashed
+        assert!(left.hashes.lock().unwrap().contains_key(Path::new("same")));
+        assert!(!left.hashes.lock().unwrap().contains_key(Path::new("type")));
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_dir_all(TEST_DIR_OUT).unwrap();
+    }
+}
@@ -0,0 +1,968 @@
+//! Copies and synchronizes directory trees out of tar and zip archives
+//!
+//! Archives are restored like any other source. Files that are already in the destination are
+//! only written if their contents differ from the archived ones, `--ignore-existing`, `--update`,
+//! `--max-depth`, and `--filter` apply, and a sync deletes whatever is not in the archive.
+//! Uncompressed tar archives and zip archives are listed first, and their files are then compared
+//! and written in parallel, each read from its own handle on the archive. `.tar.zst` archives can
+//! only be read front to back, so they are decompressed as a stream, while the files that are read
+//! from it are handed to the copy workers to compare and write.
+//!
+//! The archives that `lms export-diff` writes list what to delete from the destination, which is
+//! deleted before anything is restored, see `delta::export_diff`.
+//!
+//! Archived files are compared by their BLAKE2b hashes, which are computed as their contents are
+//! streamed, whether or not `--secure` is given.
+
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, UNIX_EPOCH};
+
+use blake2::{Blake2b, Digest};
+use crossbeam_channel::unbounded;
+use hashbrown::HashSet;
+use log::{debug, error, info};
+use rayon::prelude::*;
+
+use crate::lumins::archive::{self, ArchivedEntry, ArchivedKind, Compression, TarReader};
+use crate::lumins::core;
+use crate::lumins::delta;
+use crate::lumins::dirfd::{self, DirHandle, EntryKind};
+use crate::lumins::file_ops::{self, File, FileOps, Special, Symlink};
+use crate::lumins::parse::Options;
+use crate::lumins::platform;
+use crate::lumins::workers;
+use crate::lumins::zip;
+use crate::progress::{self, PROGRESS_BAR, STATS};
+
+/// Largest file of a streamed archive that is read into memory to be written by a copy worker,
+/// where larger files are written as they are read
+const STREAM_BUFFER_SIZE: u64 = 1 << 20;
+
+/// Copies every entry of the tar or zip archive `src` into `dest`, see `archive::copy_from_archive`
+///
+/// # Arguments
+/// * `src`: path of the archive
+/// * `dest`: directory to restore into
+/// * `options`: Options of the run
+/// * `delete`: whether to delete what is in `dest` but not in the archive, as a sync does
+///
+/// # Errors
+/// This function will return an error if `src` could not be read or is not a valid archive, if
+/// its decompressor could not be run, if `dest` could not be listed to delete from it, if what
+/// would be deleted is refused, see `core::check_deletes`, or if any entry could not be restored
+pub fn unpack(src: &str, dest: &str, options: &Options, delete: bool) -> Result<(), io::Error> {
+    let mut file = fs::File::open(src).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("Archive Error -- Opening {:?}: {}", src, e),
+        )
+    })?;
+    progress::progress_init(0);
+
+    let restore = Arc::new(Restore {
+        dest: PathBuf::from(dest),
+        options: options.clone(),
+        paths: Mutex::new(HashSet::new()),
+        links: Mutex::new(Vec::new()),
+        dirs: Mutex::new(Vec::new()),
+        entries: AtomicUsize::new(0),
+        failures: AtomicUsize::new(0),
+    });
+
+    if zip::is_zip(src) {
+        let members = zip::read_members(&mut file)?;
+        restore.restore_members(members, &|entry, data| {
+            zip::open_member(fs::File::open(src)?, entry, data)
+        });
+    } else if archive::compression(src) == Some(Compression::Zstd) {
+        restore_compressed(&restore, file)?;
+    } else {
+        let members = list_tar(file)?;
+        restore.restore_members(members, &|entry, &offset| {
+            let mut file = fs::File::open(src)?;
+            file.seek(SeekFrom::Start(offset))?;
+            Ok(Box::new(BufReader::new(file.take(entry.size))))
+        });
+    }
+    restore.finish();
+
+    if delete {
+        restore.delete_extras(src)?;
+    }
+    // Every file was flushed as it was extracted, which leaves the directories that hold them
+    if options.fsync {
+        file_ops::sync_dirs(file_ops::get_files_within(dest, options)?.dirs(), dest)?;
+    }
+
+    let failures = restore.failures.load(Ordering::Relaxed);
+    if failures > 0 {
+        return Err(io::Error::other(format!(
+            "Archive Error -- {} of {} entries in {} could not be extracted",
+            failures,
+            restore.entries.load(Ordering::Relaxed),
+            src
+        )));
+    }
+    Ok(())
+}
+
+/// Lists the entries of the uncompressed tar archive `file`, skipping over their contents
+///
+/// # Returns
+/// Every entry, in the order of the archive, along with the offset of its contents
+fn list_tar(file: fs::File) -> Result<Vec<(ArchivedEntry, u64)>, io::Error> {
+    let mut tar = TarReader::new(BufReader::new(file));
+    let mut entries = Vec::new();
+    while let Some(entry) = tar.next_entry()? {
+        let offset = tar.seek_past_contents()?;
+        entries.push((entry, offset));
+    }
+    Ok(entries)
+}
+
+/// Restores the archive that is decompressed with zstd from `file`
+///
+/// # Errors
+/// This function will return an error if `file` is not compressed with zstd, or if the archive is
+/// invalid
+fn restore_compressed(restore: &Arc<Restore>, file: fs::File) -> Result<(), io::Error> {
+    restore.restore_stream(&mut TarReader::new(zstd::Decoder::new(file)?))
+}
+
+/// Reader of the contents of one archived file
+type Member = Box<dyn Read + Send>;
+
+/// Where the contents of an archived file are read from
+enum Contents<'a> {
+    /// Contents that can be opened as often as they are needed
+    Reopen(&'a dyn Fn() -> Result<Box<dyn Read + 'a>, io::Error>),
+    /// Contents that can only be read once, by writing all of them into a writer
+    Once(&'a mut dyn FnMut(&mut dyn Write) -> Result<u64, io::Error>),
+}
+
+/// An archive that is being restored into a directory
+struct Restore {
+    dest: PathBuf,
+    options: Options,
+    /// Paths of every entry of the archive and of their parents, which are not deleted from dest
+    paths: Mutex<HashSet<PathBuf>>,
+    /// Hard links, which are restored once everything that they may link to is
+    links: Mutex<Vec<ArchivedEntry>>,
+    /// Directories, which get their metadata once everything in them is restored
+    dirs: Mutex<Vec<ArchivedEntry>>,
+    entries: AtomicUsize,
+    failures: AtomicUsize,
+}
+
+impl Restore {
+    /// Counts `entry` and records its path, and that of its parents
+    ///
+    /// # Returns
+    /// * Some: `entry`, with its path without `.` components, if it is restored
+    /// * None: If `entry` is the root of the archive, deeper than `options.max_depth`, or
+    ///   excluded by `options.filter`
+    fn add(&self, mut entry: ArchivedEntry) -> Option<ArchivedEntry> {
+        self.entries.fetch_add(1, Ordering::Relaxed);
+        PROGRESS_BAR.inc_length(1);
+
+        entry.path = without_cur_dirs(&entry.path);
+        if let ArchivedKind::HardLink(target) = &entry.kind {
+            entry.kind = ArchivedKind::HardLink(without_cur_dirs(target));
+        }
+        let depth = entry.path.components().count();
+        let excluded = (self.options.filter.as_ref()).is_some_and(|filter| {
+            filter.excludes_within(&entry.path, matches!(entry.kind, ArchivedKind::Dir))
+        });
+        if depth == 0 || self.options.max_depth.is_some_and(|max| depth > max) || excluded {
+            PROGRESS_BAR.inc(1);
+            return None;
+        }
+
+        let mut paths = self.paths.lock().unwrap();
+        for parent in entry.path.ancestors() {
+            if !paths.insert(parent.to_path_buf()) {
+                break;
+            }
+        }
+        Some(entry)
+    }
+
+    /// Restores `members`, whose contents `open` opens from where they are in the archive
+    ///
+    /// Directories are created first, in path order. Files and symlinks are then restored in
+    /// parallel, and hard links once they are done.
+    fn restore_members<L: Sync>(
+        &self,
+        members: Vec<(ArchivedEntry, L)>,
+        open: &(dyn Fn(&ArchivedEntry, &L) -> Result<Member, io::Error> + Sync),
+    ) {
+        let mut members: Vec<(ArchivedEntry, L)> = members
+            .into_iter()
+            .filter_map(|(entry, location)| self.add(entry).map(|entry| (entry, location)))
+            .collect();
+        if let Some(i) = members
+            .iter()
+            .position(|(entry, _)| delta::is_deletions(entry))
+        {
+            let (entry, location) = members.remove(i);
+            let mut list = Vec::new();
+            let read = open(&entry, &location).and_then(|mut reader| reader.read_to_end(&mut list));
+            self.delete_listed(&entry, read.map(|_| &list[..]));
+        }
+        let (mut dirs, members): (Vec<_>, Vec<_>) = members
+            .into_iter()
+            .partition(|(entry, _)| entry.kind == ArchivedKind::Dir);
+        let (links, members): (Vec<_>, Vec<_>) = members
+            .into_iter()
+            .partition(|(entry, _)| matches!(entry.kind, ArchivedKind::HardLink(_)));
+
+        dirs.sort_unstable_by(|(a, _), (b, _)| a.path.cmp(&b.path));
+        for (dir, _) in &dirs {
+            self.restore(dir, None);
+        }
+        members.par_iter().for_each(|(entry, location)| {
+            if STATS.stopped() {
+                return;
+            }
+            let open = || open(entry, location).map(|reader| reader as Box<dyn Read>);
+            self.restore(entry, Some(Contents::Reopen(&open)));
+        });
+        self.links
+            .lock()
+            .unwrap()
+            .extend(links.into_iter().map(|(entry, _)| entry));
+    }
+
+    /// Restores the entries of a tar archive that can only be read front to back
+    ///
+    /// Small files are read into memory and written by the copy workers, while the next entries
+    /// are read. Larger files are written as they are read, and so are directories and symlinks.
+    ///
+    /// # Errors
+    /// This function will return an error if the archive is invalid
+    fn restore_stream<R: Read>(self: &Arc<Self>, tar: &mut TarReader<R>) -> Result<(), io::Error> {
+        let (done, finished) = unbounded::<()>();
+        let copy_workers = workers::copy_workers(&self.options);
+
+        let mut read = || -> Result<(), io::Error> {
+            while let Some(entry) = tar.next_entry()? {
+                let entry = match self.add(entry) {
+                    Some(entry) => entry,
+                    None => continue,
+                };
+                match entry.kind {
+                    ArchivedKind::File if delta::is_deletions(&entry) => {
+                        let mut list = Vec::new();
+                        let read = tar.read_contents(&mut list);
+                        self.delete_listed(&entry, read.map(|_| &list[..]));
+                    }
+                    ArchivedKind::HardLink(_) => self.links.lock().unwrap().push(entry),
+                    ArchivedKind::File if entry.size <= STREAM_BUFFER_SIZE => {
+                        let mut contents = Vec::with_capacity(entry.size as usize);
+                        tar.read_contents(&mut contents)?;
+
+                        let restore = self.clone();
+                        let done = done.clone();
+                        copy_workers.execute(move || {
+                            let open = || Ok(Box::new(&contents[..]) as Box<dyn Read>);
+                            restore.restore(&entry, Some(Contents::Reopen(&open)));
+                            drop(done);
+                        });
+                    }
+                    ArchivedKind::File => {
+                        let mut read = |mut writer: &mut dyn Write| tar.read_contents(&mut writer);
+                        self.restore(&entry, Some(Contents::Once(&mut read)));
+                    }
+                    _ => self.restore(&entry, None),
+                }
+            }
+            Ok(())
+        };
+        let read = read();
+
+        // Returns once every job has dropped its sender
+        drop(done);
+        finished.recv().ok();
+        read
+    }
+
+    /// Deletes every path in the list of deletions `list`, which was read from `entry`, see
+    /// `delta::export_diff`
+    ///
+    /// Paths are deleted deepest first, and paths that are already gone are skipped.
+    fn delete_listed(&self, entry: &ArchivedEntry, list: Result<&[u8], io::Error>) {
+        let mut paths = match list.and_then(delta::read_deletions) {
+            Ok(paths) => paths,
+            Err(e) => {
+                self.failures.fetch_add(1, Ordering::Relaxed);
+                error!("Archive Error -- Reading {:?}: {}", entry.path, e);
+                return;
+            }
+        };
+        PROGRESS_BAR.inc(1);
+
+        // Path's ordering compares components, so children sort after their parents
+        paths.sort_unstable_by(|a, b| b.cmp(a));
+        for path in paths {
+            let deleted =
+                dirfd::open_parent(&self.dest, &path, false).and_then(|(parent, name)| {
+                    let dir = parent.kind(&name)? == EntryKind::Dir;
+                    parent.remove(&name, dir)
+                });
+            match deleted {
+                Ok(_) => {
+                    info!("Deleting {:?}", self.dest.join(&path));
+                    STATS.add_deleted();
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    self.failures.fetch_add(1, Ordering::Relaxed);
+                    error!(
+                        "Archive Error -- Deleting {:?}: {}",
+                        self.dest.join(&path),
+                        e
+                    );
+                }
+            }
+        }
+    }
+
+    /// Restores `entry`, reading the contents of files from `contents`, and logs what it did
+    fn restore(&self, entry: &ArchivedEntry, contents: Option<Contents>) {
+        let path = self.dest.join(&entry.path);
+        let restored = archive::check_entry(entry, &self.options).and_then(|_| match &entry.kind {
+            ArchivedKind::Dir => self.restore_dir(entry).map(|_| true),
+            ArchivedKind::File | ArchivedKind::HardLink(_) => match contents {
+                Some(contents) => self.restore_file(entry, contents),
+                None => Ok(false),
+            },
+            ArchivedKind::Symlink(target) => self.restore_symlink(entry, target),
+            ArchivedKind::Other(flag) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("entries of type {:?} can not be extracted", *flag as char),
+            )),
+        });
+
+        match restored {
+            Ok(true) => info!("Extracting {:?}", path),
+            Ok(false) => {}
+            Err(e) => {
+                self.failures.fetch_add(1, Ordering::Relaxed);
+                error!("Archive Error -- Extracting {:?}: {}", path, e);
+            }
+        }
+        PROGRESS_BAR.inc(1);
+    }
+
+    /// Creates the directory `entry`, whose metadata is set by `finish`
+    fn restore_dir(&self, entry: &ArchivedEntry) -> Result<(), io::Error> {
+        dirfd::create_dir_all(&self.dest, &entry.path)?;
+        let (parent, name) = dirfd::open_parent(&self.dest, &entry.path, false)?;
+        archive::set_owner(&parent, &name, entry, &self.options)?;
+        self.dirs.lock().unwrap().push(entry.clone());
+        Ok(())
+    }
+
+    /// Writes the file `entry` from `contents`, unless the file in dest is kept or already has
+    /// the same contents
+    ///
+    /// The file is written to a temporary file next to it, which is then renamed over it.
+    ///
+    /// # Returns
+    /// * Ok: Whether the file was written
+    /// * Error: If the file could not be written, or `contents` are not as large as `entry`
+    fn restore_file(&self, entry: &ArchivedEntry, contents: Contents) -> Result<bool, io::Error> {
+        let (parent, name) = dirfd::open_parent(&self.dest, &entry.path, true)?;
+        let path = self.dest.join(&entry.path);
+
+        let dest_hash = match parent.kind(&name) {
+            Ok(dirfd::EntryKind::File(0)) if entry.size > 0 && self.options.heal_empty_files => {
+                debug!(
+                    "{:?} is healed, since it is empty while the archive's copy is not",
+                    path
+                );
+                None
+            }
+            Ok(_) if self.options.ignore_existing => {
+                debug!("{:?} is skipped, since it already exists", path);
+                return Ok(false);
+            }
+            Ok(dirfd::EntryKind::File(size)) => {
+                if self.options.update && !is_older(&path, entry) {
+                    debug!(
+                        "{:?} is skipped, since it is not older than its source",
+                        path
+                    );
+                    return Ok(false);
+                }
+                if size == entry.size {
+                    file_ops::hash_secure(&self.dest, &entry.path)
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        };
+        let same = |hash: &Vec<u8>| {
+            let same = dest_hash.as_ref() == Some(hash);
+            if same {
+                debug!("{:?} is skipped, since its hash matches the archive", path);
+            }
+            same
+        };
+
+        // Contents that can be read twice are compared before anything is written
+        if let (Contents::Reopen(open), Some(_)) = (&contents, &dest_hash) {
+            let mut hasher = Blake2b::new();
+            io::copy(&mut open()?, &mut hasher)?;
+            if same(&hasher.finalize().to_vec()) {
+                return Ok(false);
+            }
+        }
+
+        let temp_name = dirfd::temp_name();
+        let written = self
+            .write_temp(&parent, &temp_name, entry, contents, dest_hash.is_some())
+            .and_then(|hash| match hash {
+                Some(hash) if same(&hash) => Ok(false),
+                _ => parent.rename(&temp_name, &parent, &name).and_then(|_| {
+                    if self.options.fsync {
+                        parent.sync()?;
+                    }
+                    Ok(true)
+                }),
+            });
+        match written {
+            Ok(true) => {
+                STATS.add_copied(entry.size);
+                Ok(true)
+            }
+            result => {
+                parent.remove(&temp_name, false).ok();
+                result
+            }
+        }
+    }
+
+    /// Writes `contents` into the new file `name` inside `parent`, with the metadata of `entry`
+    ///
+    /// # Returns
+    /// * Ok: The hash of `contents`, if `hash` is set and they could only be read once
+    /// * Error: If the file could not be written, or `contents` are not as large as `entry`
+    fn write_temp(
+        &self,
+        parent: &DirHandle,
+        name: &OsStr,
+        entry: &ArchivedEntry,
+        contents: Contents,
+        hash: bool,
+    ) -> Result<Option<Vec<u8>>, io::Error> {
+        let mut file = parent.create_file(name, entry.metadata.mode & 0o777)?;
+        platform::preallocate(&file, entry.size)?;
+        let (size, hash) = match contents {
+            Contents::Reopen(open) => (io::copy(&mut open()?, &mut file)?, None),
+            Contents::Once(read) if hash => {
+                let mut writer = HashingWriter {
+                    writer: &mut file,
+                    hasher: Blake2b::new(),
+                };
+                let size = read(&mut writer)?;
+                (size, Some(writer.hasher.finalize().to_vec()))
+            }
+            Contents::Once(read) => (read(&mut file)?, None),
+        };
+        if size != entry.size {
+            return Err(archive::truncated_archive());
+        }
+
+        archive::set_owner(parent, name, entry, &self.options)?;
+        archive::set_permissions(&file, entry.metadata.mode)?;
+        file.set_modified(UNIX_EPOCH + Duration::from_secs(entry.metadata.mtime))?;
+        if self.options.fsync {
+            file.sync_all()?;
+        }
+        Ok(hash)
+    }
+
+    /// Creates the symlink `entry` to `target`, unless the symlink in dest is kept or already
+    /// links to `target`
+    ///
+    /// # Returns
+    /// * Ok: Whether the symlink was created
+    /// * Error: If the symlink could not be created, or what is in its way could not be removed
+    fn restore_symlink(&self, entry: &ArchivedEntry, target: &Path) -> Result<bool, io::Error> {
+        let (parent, name) = dirfd::open_parent(&self.dest, &entry.path, true)?;
+        let path = self.dest.join(&entry.path);
+
+        match parent.kind(&name) {
+            Ok(dirfd::EntryKind::Symlink(existing)) if existing == target => {
+                debug!(
+                    "{:?} is skipped, since it already links to its target",
+                    path
+                );
+                return Ok(false);
+            }
+            Ok(_) if self.options.ignore_existing => {
+                debug!("{:?} is skipped, since it already exists", path);
+                return Ok(false);
+            }
+            // Directories in the way are left for the symlink to fail on
+            Ok(dirfd::EntryKind::Dir) | Err(_) => {}
+            Ok(_) => parent.remove(&name, false)?,
+        }
+
+        #[cfg(target_family = "unix")]
+        parent.symlink(target, &name)?;
+        #[cfg(not(target_family = "unix"))]
+        std::os::windows::fs::symlink_file(target, &path)?;
+        archive::set_owner(&parent, &name, entry, &self.options)?;
+        Ok(true)
+    }
+
+    /// Restores the hard links of the archive, as copies of what they link to, and then sets the
+    /// metadata of directories, deepest first
+    fn finish(&self) {
+        let links = std::mem::take(&mut *self.links.lock().unwrap());
+        for mut link in links {
+            if let ArchivedKind::HardLink(target) = link.kind.clone() {
+                // Hard links have no contents of their own, so they are as large as their target
+                if let Ok(metadata) = fs::symlink_metadata(self.dest.join(&target)) {
+                    link.size = metadata.len();
+                }
+                let open = || {
+                    dirfd::open_file(&self.dest, &target)
+                        .map(|file| Box::new(file) as Box<dyn Read>)
+                };
+                self.restore(&link, Some(Contents::Reopen(&open)));
+            }
+        }
+
+        let mut dirs = std::mem::take(&mut *self.dirs.lock().unwrap());
+        // Path's ordering compares components, so children sort after their parents
+        dirs.sort_unstable_by(|a, b| b.path.cmp(&a.path));
+        for dir in dirs {
+            if let Err(e) = archive::set_dir_metadata(&self.dest, &dir) {
+                self.failures.fetch_add(1, Ordering::Relaxed);
+                error!(
+                    "Archive Error -- Setting metadata {:?}: {}",
+                    self.dest.join(&dir.path),
+                    e
+                );
+            }
+        }
+    }
+
+    /// Deletes everything in dest that is not in the archive `src`
+    ///
+    /// # Errors
+    /// This function will return an error if dest could not be listed, or if the deletes are
+    /// refused, see `core::check_deletes`
+    fn delete_extras(&self, src: &str) -> Result<(), io::Error> {
+        let paths = self.paths.lock().unwrap();
+        let dest = self.dest.to_string_lossy();
+        let dest_file_sets = file_ops::get_files_within(&dest, &self.options)?;
+        let extra = |path: &Path| !paths.contains(path);
+
+        // What is deleted is checked like it is for directory sources, before anything is
+        // removed, where the archive is empty if nothing in dest was extracted from it
+        let dest_paths = || {
+            (dest_file_sets.files().iter().map(|file| file.path()))
+                .chain(dest_file_sets.symlinks().iter().map(|link| link.path()))
+        };
+        let src_empty = !dest_paths().any(|path| paths.contains(path));
+        let dest_count = dest_paths().count() as u64;
+        let to_delete = || dest_paths().filter(|path| extra(path)).count() as u64;
+        core::check_deletes(src, &dest, src_empty, dest_count, to_delete, &self.options)?;
+
+        let dirs = file_ops::sort_files(
+            dest_file_sets
+                .dirs()
+                .par_iter()
+                .filter(|dir| extra(dir.path())),
+        );
+        let files: Vec<&File> = (dest_file_sets.files().iter())
+            .filter(|file| extra(file.path()))
+            .collect();
+        let symlinks: Vec<&Symlink> = (dest_file_sets.symlinks().iter())
+            .filter(|symlink| extra(symlink.path()))
+            .collect();
+        let specials: Vec<&Special> = (dest_file_sets.specials().iter())
+            .filter(|special| extra(special.path()))
+            .collect();
+        PROGRESS_BAR
+            .inc_length((dirs.len() + files.len() + symlinks.len() + specials.len()) as u64);
+
+        file_ops::delete_files(files.into_par_iter(), &dest);
+        file_ops::delete_files(symlinks.into_par_iter(), &dest);
+        file_ops::delete_files(specials.into_par_iter(), &dest);
+        file_ops::delete_files_sequential(dirs, &dest);
+        Ok(())
+    }
+}
+
+/// A writer that hashes everything that is written through it
+struct HashingWriter<'a, W: Write> {
+    writer: &'a mut W,
+    hasher: Blake2b,
+}
+
+impl<W: Write> Write for HashingWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.writer.write(buf)?;
+        self.hasher.update(&buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Checks whether the file at `path` was modified before the archived `entry`
+fn is_older(path: &Path, entry: &ArchivedEntry) -> bool {
+    let modified = fs::symlink_metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok());
+    match modified {
+        Some(modified) => modified.as_secs() < entry.metadata.mtime,
+        None => true,
+    }
+}
+
+/// Removes the `.` components of `path`, which archives often start their paths with
+fn without_cur_dirs(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|component| *component != Component::CurDir)
+        .collect()
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_unpack {
+    use super::*;
+    use crate::lumins::archive::{EntryMetadata, TarWriter};
+    use crate::lumins::filter::Filter;
+    use crate::lumins::history::MaxDelete;
+    use std::process::Command;
+
+    /// Writes a tar archive of the files `files` to `path`, all modified at `mtime`
+    fn write_tar(path: &str, files: &[(&str, &[u8])], mtime: u64) {
+        let mut tar = TarWriter::new(Vec::new(), None);
+        for (name, contents) in files {
+            tar.append_file(
+                Path::new(name),
+                EntryMetadata {
+                    mode: 0o644,
+                    mtime,
+                    ..EntryMetadata::default()
+                },
+                contents.len() as u64,
+                &mut &contents[..],
+            )
+            .unwrap();
+        }
+        fs::write(path, tar.finish().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn skips_unchanged_files() {
+        const TEST_DIR: &str = "test_unpack_skips_unchanged_files";
+        const TEST_ARCHIVE: &str = "test_unpack_skips_unchanged_files.tar";
+        let path = |path: &str| [TEST_DIR, path].join("/");
+
+        write_tar(
+            TEST_ARCHIVE,
+            &[("same", b"same"), ("changed", b"new")],
+            1000,
+        );
+        fs::create_dir_all(TEST_DIR).unwrap();
+        fs::write(path("same"), b"same").unwrap();
+        fs::write(path("changed"), b"old").unwrap();
+        let same = fs::metadata(path("same")).unwrap().modified().unwrap();
+
+        assert!(unpack(TEST_ARCHIVE, TEST_DIR, &Options::default(), false).is_ok());
+        assert_eq!(
+            fs::metadata(path("same")).unwrap().modified().unwrap(),
+            same
+        );
+        assert_eq!(fs::read(path("changed")).unwrap(), b"new");
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_file(TEST_ARCHIVE).unwrap();
+    }
+
+    #[test]
+    fn keeps_existing_files() {
+        const TEST_DIR: &str = "test_unpack_keeps_existing_files";
+        const TEST_ARCHIVE: &str = "test_unpack_keeps_existing_files.tar";
+        let path = |path: &str| [TEST_DIR, path].join("/");
+
+        write_tar(
+            TEST_ARCHIVE,
+            &[("a", b"archived"), ("b", b"archived")],
+            1000,
+        );
+        fs::create_dir_all(TEST_DIR).unwrap();
+        fs::write(path("a"), b"kept").unwrap();
+
+        let options = Options {
+            ignore_existing: true,
+            ..Options::default()
+        };
+        assert!(unpack(TEST_ARCHIVE, TEST_DIR, &options, false).is_ok());
+        assert_eq!(fs::read(path("a")).unwrap(), b"kept");
+        assert_eq!(fs::read(path("b")).unwrap(), b"archived");
+
+        // Files that are newer than in the archive are kept with --update
+        let options = Options {
+            update: true,
+            ..Options::default()
+        };
+        assert!(unpack(TEST_ARCHIVE, TEST_DIR, &options, false).is_ok());
+        assert_eq!(fs::read(path("a")).unwrap(), b"kept");
+        write_tar(TEST_ARCHIVE, &[("a", b"archived")], u64::from(u32::MAX));
+        assert!(unpack(TEST_ARCHIVE, TEST_DIR, &options, false).is_ok());
+        assert_eq!(fs::read(path("a")).unwrap(), b"archived");
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_file(TEST_ARCHIVE).unwrap();
+    }
+
+    #[test]
+    fn deletes_extras() {
+        const TEST_DIR: &str = "test_unpack_deletes_extras";
+        const TEST_ARCHIVE: &str = "test_unpack_deletes_extras.tar";
+        let path = |path: &str| [TEST_DIR, path].join("/");
+
+        // Parents that the archive has no entries of are not extras
+        write_tar(TEST_ARCHIVE, &[("./dir/kept", b"kept")], 1000);
+        fs::create_dir_all(path("dir")).unwrap();
+        fs::create_dir_all(path("extra_dir/sub")).unwrap();
+        fs::write(path("dir/extra"), b"extra").unwrap();
+        fs::write(path("extra_dir/sub/file"), b"extra").unwrap();
+
+        assert!(unpack(TEST_ARCHIVE, TEST_DIR, &Options::default(), false).is_ok());
+        assert!(fs::metadata(path("dir/extra")).is_ok());
+
+        assert!(unpack(TEST_ARCHIVE, TEST_DIR, &Options::default(), true).is_ok());
+        assert_eq!(fs::read(path("dir/kept")).unwrap(), b"kept");
+        assert!(fs::metadata(path("dir/extra")).is_err());
+        assert!(fs::metadata(path("extra_dir")).is_err());
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_file(TEST_ARCHIVE).unwrap();
+    }
+
+    #[test]
+    fn max_delete() {
+        const TEST_DIR: &str = "test_unpack_max_delete";
+        const TEST_ARCHIVE: &str = "test_unpack_max_delete.tar";
+        let path = |path: &str| [TEST_DIR, path].join("/");
+
+        write_tar(TEST_ARCHIVE, &[("kept", b"kept")], 1000);
+        fs::create_dir_all(TEST_DIR).unwrap();
+        fs::write(path("a"), b"a").unwrap();
+        fs::write(path("b"), b"b").unwrap();
+
+        // Nothing is deleted if more would be than is allowed
+        let options = Options {
+            max_delete: Some(MaxDelete::Files(1)),
+            ..Options::default()
+        };
+        assert!(unpack(TEST_ARCHIVE, TEST_DIR, &options, true).is_err());
+        assert!(fs::metadata(path("a")).is_ok());
+        assert!(fs::metadata(path("b")).is_ok());
+
+        let options = Options {
+            max_delete: Some(MaxDelete::Files(2)),
+            ..Options::default()
+        };
+        assert!(unpack(TEST_ARCHIVE, TEST_DIR, &options, true).is_ok());
+        assert!(fs::metadata(path("a")).is_err());
+        assert!(fs::metadata(path("b")).is_err());
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_file(TEST_ARCHIVE).unwrap();
+    }
+
+    #[test]
+    fn safe_delete() {
+        const TEST_DIR: &str = "test_unpack_safe_delete";
+        const TEST_ARCHIVE: &str = "test_unpack_safe_delete.tar";
+        let path = |path: &str| [TEST_DIR, path].join("/");
+
+        write_tar(TEST_ARCHIVE, &[], 1000);
+        fs::create_dir_all(TEST_DIR).unwrap();
+        fs::write(path("a"), b"a").unwrap();
+
+        // A sync of an empty archive does not empty dest, unless it is forced to
+        let options = Options {
+            safe_delete: true,
+            ..Options::default()
+        };
+        assert!(core::synchronize(TEST_ARCHIVE, TEST_DIR, &options).is_err());
+        assert!(fs::metadata(path("a")).is_ok());
+
+        let options = Options {
+            safe_delete: true,
+            force_empty_source: true,
+            ..Options::default()
+        };
+        assert!(core::synchronize(TEST_ARCHIVE, TEST_DIR, &options).is_ok());
+        assert!(fs::metadata(path("a")).is_err());
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_file(TEST_ARCHIVE).unwrap();
+    }
+
+    #[test]
+    fn max_depth() {
+        const TEST_DIR: &str = "test_unpack_max_depth";
+        const TEST_ARCHIVE: &str = "test_unpack_max_depth.tar";
+        let path = |path: &str| [TEST_DIR, path].join("/");
+
+        write_tar(TEST_ARCHIVE, &[("a", b"a"), ("b/c", b"c")], 1000);
+        fs::create_dir_all(path("b")).unwrap();
+        fs::write(path("b/d"), b"d").unwrap();
+
+        // What is below the depth is neither restored nor deleted
+        let options = Options {
+            max_depth: Some(1),
+            ..Options::default()
+        };
+        assert!(unpack(TEST_ARCHIVE, TEST_DIR, &options, true).is_ok());
+        assert_eq!(fs::read(path("a")).unwrap(), b"a");
+        assert!(fs::metadata(path("b/c")).is_err());
+        assert!(fs::metadata(path("b/d")).is_ok());
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_file(TEST_ARCHIVE).unwrap();
+    }
+
+    #[test]
+    fn filter() {
+        const TEST_DIR: &str = "test_unpack_filter";
+        const TEST_ARCHIVE: &str = "test_unpack_filter.tar";
+        let path = |path: &str| [TEST_DIR, path].join("/");
+
+        write_tar(
+            TEST_ARCHIVE,
+            &[
+                ("a", b"a"),
+                ("a.tmp", b"tmp"),
+                ("b/c", b"c"),
+                ("b/d/e", b"e"),
+            ],
+            1000,
+        );
+        fs::create_dir_all(path("b/d")).unwrap();
+        fs::write(path("b/d/f"), b"f").unwrap();
+
+        // What is excluded is neither restored nor deleted
+        let options = Options {
+            filter: Some(Filter::parse(vec!["- *.tmp", "- /b/d/"]).unwrap()),
+            ..Options::default()
+        };
+        assert!(unpack(TEST_ARCHIVE, TEST_DIR, &options, true).is_ok());
+        assert_eq!(fs::read(path("a")).unwrap(), b"a");
+        assert_eq!(fs::read(path("b/c")).unwrap(), b"c");
+        assert!(fs::metadata(path("a.tmp")).is_err());
+        assert!(fs::metadata(path("b/d/e")).is_err());
+        assert!(fs::metadata(path("b/d/f")).is_ok());
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_file(TEST_ARCHIVE).unwrap();
+    }
+
+    #[test]
+    fn streamed_archive() {
+        const TEST_DIR: &str = "test_unpack_streamed_archive";
+        const TEST_ARCHIVE: &str = "test_unpack_streamed_archive.tar.zst";
+        let path = |path: &str| [TEST_DIR, path].join("/");
+
+        // Both small files and files too large to read into memory are compared
+        let large = vec![7; STREAM_BUFFER_SIZE as usize + 1];
+        let tar = "test_unpack_streamed_archive.tar";
+        write_tar(
+            tar,
+            &[("small", b"small"), ("large", &large), ("new", b"new")],
+            1000,
+        );
+        let compressed = zstd::encode_all(fs::File::open(tar).unwrap(), 0).unwrap();
+        fs::write(TEST_ARCHIVE, compressed).unwrap();
+        fs::remove_file(tar).unwrap();
+
+        fs::create_dir_all(TEST_DIR).unwrap();
+        fs::write(path("small"), b"small").unwrap();
+        fs::write(path("large"), &large).unwrap();
+        let small = fs::metadata(path("small")).unwrap().modified().unwrap();
+        let large_modified = fs::metadata(path("large")).unwrap().modified().unwrap();
+
+        assert!(unpack(TEST_ARCHIVE, TEST_DIR, &Options::default(), true).is_ok());
+        assert_eq!(
+            fs::metadata(path("small")).unwrap().modified().unwrap(),
+            small
+        );
+        assert_eq!(
+            fs::metadata(path("large")).unwrap().modified().unwrap(),
+            large_modified
+        );
+        assert_eq!(fs::read(path("new")).unwrap(), b"new");
+
+        // No temporary files are left behind
+        let mut names: Vec<_> = fs::read_dir(TEST_DIR)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["large", "new", "small"]);
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_file(TEST_ARCHIVE).unwrap();
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn zip_archive() {
+        const TEST_DIR: &str = "test_unpack_zip_archive";
+        const TEST_ARCHIVE: &str = "test_unpack_zip_archive.zip";
+        const TEST_DIR_OUT: &str = "test_unpack_zip_archive_out";
+
+        fs::create_dir_all([TEST_DIR, "dir"].join("/")).unwrap();
+        fs::write([TEST_DIR, "dir/file"].join("/"), b"contents ".repeat(1000)).unwrap();
+        fs::write([TEST_DIR, "empty"].join("/"), b"").unwrap();
+        std::os::unix::fs::symlink("dir/file", [TEST_DIR, "link"].join("/")).unwrap();
+
+        let zipped = Command::new("python3")
+            .args([
+                "-c",
+                &format!(
+                    "import shutil; shutil.make_archive({:?}, 'zip', {:?})",
+                    TEST_ARCHIVE.trim_end_matches(".zip"),
+                    TEST_DIR
+                ),
+            ])
+            .output();
+        if zipped.is_ok_and(|output| output.status.success()) {
+            assert!(unpack(TEST_ARCHIVE, TEST_DIR_OUT, &Options::default(), false).is_ok());
+            assert_eq!(
+                fs::read([TEST_DIR_OUT, "dir/file"].join("/")).unwrap(),
+                b"contents ".repeat(1000)
+            );
+            assert_eq!(fs::read([TEST_DIR_OUT, "empty"].join("/")).unwrap(), b"");
+            fs::remove_dir_all(TEST_DIR_OUT).unwrap();
+        }
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_file(TEST_ARCHIVE).ok();
+    }
+}
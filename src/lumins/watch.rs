@@ -0,0 +1,427 @@
+//! Keeps a destination synchronized with its source, by watching the source for changes
+//!
+//! `lms sync --watch` synchronizes once, and then waits for the source to change, with inotify on
+//! Linux, and synchronizes only the directories that changed. With `--audit`, every change is
+//! written to a JSONL stream too, before it is acted on, so that the watcher doubles as a change
+//! audit.
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hashbrown::HashSet;
+use log::{debug, error, info};
+
+use crate::lumins::parse::Options;
+use crate::lumins::{core, dirfd, history, json};
+
+/// How long to wait for changes to stop before synchronizing them, unless given, which is long
+/// enough for both halves of a rename to be seen together
+pub const DEFAULT_DEBOUNCE: Duration = Duration::from_millis(10);
+
+/// Enum to represent how an entry of the source changed
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub enum Change {
+    Create,
+    Modify,
+    Delete,
+    /// Renamed from the path given, within the source
+    Rename(PathBuf),
+    /// Events were lost, so anything in the entry may have changed
+    Overflow,
+}
+
+/// A struct that represents a change of an entry of the source
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub struct Event {
+    pub change: Change,
+    /// Path of the entry, relative to the source
+    pub path: PathBuf,
+    /// Whether the entry is a directory
+    pub dir: bool,
+    /// When the change was seen
+    pub time: SystemTime,
+}
+
+impl Event {
+    /// Creates an event of `change` to the entry at `path`
+    pub fn new(change: Change, path: PathBuf, dir: bool, time: SystemTime) -> Self {
+        Event {
+            change,
+            path,
+            dir,
+            time,
+        }
+    }
+
+    /// Formats the event as one line of JSON, for the audit stream
+    pub fn to_json(&self) -> String {
+        let since_epoch = self.time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let (year, month, day, hour, minute, second) = history::utc_time(since_epoch.as_secs());
+        let time = format!(
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+            year,
+            month,
+            day,
+            hour,
+            minute,
+            second,
+            since_epoch.subsec_millis()
+        );
+        let (event, from) = match &self.change {
+            Change::Create => ("create", None),
+            Change::Modify => ("modify", None),
+            Change::Delete => ("delete", None),
+            Change::Rename(from) => ("rename", Some(from)),
+            Change::Overflow => ("overflow", None),
+        };
+        let from = from.map_or(String::new(), |from| {
+            format!(",\"from\":{}", json::string(&from.to_string_lossy()))
+        });
+        format!(
+            "{{\"time\":\"{}\",\"event\":\"{}\",\"path\":{}{},\"dir\":{}}}",
+            time,
+            event,
+            json::string(&self.path.to_string_lossy()),
+            from,
+            self.dir
+        )
+    }
+}
+
+/// A struct that represents the stream that a watcher writes its events to
+pub struct Audit {
+    out: Box<dyn Write + Send>,
+}
+
+impl Audit {
+    /// Opens the audit stream at `path`, appending to it, or standard output if `path` is `-`
+    ///
+    /// # Errors
+    /// This function will return an error if `path` could not be opened
+    pub fn open(path: &Path) -> Result<Self, io::Error> {
+        let out: Box<dyn Write + Send> = if path == Path::new("-") {
+            Box::new(io::stdout())
+        } else {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|e| {
+                    io::Error::new(e.kind(), format!("Audit Error -- {:?}: {}", path, e))
+                })?;
+            Box::new(file)
+        };
+        Ok(Audit { out })
+    }
+
+    /// Writes `events` to the stream, one line each, and flushes it
+    ///
+    /// # Errors
+    /// This function will return an error if the stream could not be written
+    pub fn record(&mut self, events: &[Event]) -> Result<(), io::Error> {
+        let mut lines = String::new();
+        for event in events {
+            lines.push_str(&event.to_json());
+            lines.push('\n');
+        }
+        // Write all lines at once, so that other writers to the stream do not interleave them
+        self.out
+            .write_all(lines.as_bytes())
+            .and_then(|_| self.out.flush())
+            .map_err(|e| io::Error::new(e.kind(), format!("Audit Error -- {}", e)))
+    }
+}
+
+/// Synchronizes `dest` with `src`, and then keeps it synchronized as `src` changes, until an error
+/// stops the watcher
+///
+/// Changes are synchronized in batches, once none have been seen for `options.debounce`, or once
+/// there are `options.max_batch` of them, see `inotify::Watcher::next_events`. Each batch is
+/// written to the audit stream of `options.audit`, if any, before it is acted on, see `apply`.
+///
+/// # Errors
+/// This function will return an error if `src` could not be watched, if the first sync failed,
+/// or if the audit stream could not be written
+pub fn watch(src: &str, dest: &str, options: &Options) -> Result<(), io::Error> {
+    let mut audit = options.audit.as_deref().map(Audit::open).transpose()?;
+
+    #[cfg(target_os = "linux")]
+    {
+        // Watch before the first sync, so that nothing that changes during it is missed
+        let mut watcher = crate::lumins::inotify::Watcher::new(Path::new(src))
+            .map_err(|e| io::Error::new(e.kind(), format!("Watch Error -- {}: {}", src, e)))?;
+        core::synchronize(src, dest, options)?;
+        info!("Watching {} for changes", src);
+
+        let debounce = options.debounce.unwrap_or(DEFAULT_DEBOUNCE);
+        loop {
+            let events = watcher.next_events(debounce, options.max_batch)?;
+            if let Some(audit) = &mut audit {
+                audit.record(&events)?;
+            }
+            apply(&events, src, dest, options);
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = (&mut audit, dest);
+        Err(io::Error::other(format!(
+            "Watch Error -- {}: watching is only supported on Linux",
+            src
+        )))
+    }
+}
+
+/// Synchronizes what `events` changed in `src` into `dest`
+///
+/// Entries that were renamed in `src` are renamed in `dest` too, in the order they were renamed,
+/// so that reorganizing a tree does not copy it again. Directories that were created, or renamed
+/// but could not be renamed in `dest`, are synchronized with everything in them, while the
+/// entries of directories in which files changed are synchronized one level deep. Directories
+/// that are gone from `src` are removed from `dest`, unless `Options::delete` is false, in which
+/// case nothing is renamed either. Errors are logged, so that the watcher goes on.
+pub fn apply(events: &[Event], src: &str, dest: &str, options: &Options) {
+    let mut renamed: HashSet<&Path> = HashSet::new();
+    if options.delete {
+        for event in events {
+            if let Change::Rename(from) = &event.change {
+                if rename(from, &event.path, event.dir, Path::new(dest)) {
+                    renamed.insert(&event.path);
+                }
+            }
+        }
+    }
+
+    let mut trees: HashSet<&Path> = HashSet::new();
+    let mut parents: HashSet<&Path> = HashSet::new();
+    let mut removed: HashSet<&Path> = HashSet::new();
+    for event in events {
+        let parent = event.path.parent().unwrap_or_else(|| Path::new(""));
+        match &event.change {
+            Change::Overflow => {
+                trees.insert(&event.path);
+            }
+            Change::Create if event.dir => {
+                trees.insert(&event.path);
+            }
+            Change::Delete if event.dir => {
+                removed.insert(&event.path);
+            }
+            Change::Rename(from) => {
+                // What was renamed in dest only needs its metadata updated, by its parent
+                if event.dir && !renamed.contains(event.path.as_path()) {
+                    removed.insert(from);
+                    trees.insert(&event.path);
+                }
+                parents.insert(from.parent().unwrap_or_else(|| Path::new("")));
+                parents.insert(parent);
+            }
+            _ => {
+                parents.insert(parent);
+            }
+        }
+    }
+
+    let in_tree = |path: &Path| path.ancestors().skip(1).any(|dir| trees.contains(dir));
+    let (src, dest) = (Path::new(src), Path::new(dest));
+    let run = |dir: &Path, options: &Options| {
+        let (src_dir, dest_dir) = (src.join(dir), dest.join(dir));
+        // What is gone from the source by now is removed by the sync of its parent
+        if !src_dir.is_dir() {
+            return;
+        }
+        // Directories that are new to dest are created first, like the root of a sync is
+        let result = fs::create_dir_all(&dest_dir).and_then(|_| {
+            core::synchronize(
+                &src_dir.to_string_lossy(),
+                &dest_dir.to_string_lossy(),
+                options,
+            )
+        });
+        if let Err(e) = result {
+            error!("Watch Error -- Synchronizing {:?}: {}", src_dir, e);
+        }
+    };
+
+    if options.delete {
+        for path in removed.iter().filter(|path| !in_tree(path)) {
+            let dest_dir = dest.join(path);
+            if !src.join(path).exists() && fs::symlink_metadata(&dest_dir).is_ok_and(|m| m.is_dir())
+            {
+                if let Err(e) = core::remove(&dest_dir.to_string_lossy(), options) {
+                    error!("Watch Error -- Removing {:?}: {}", dest_dir, e);
+                }
+            }
+        }
+    }
+    for dir in trees.iter().filter(|dir| !in_tree(dir)) {
+        run(dir, options);
+    }
+    let shallow = Options {
+        max_depth: Some(1),
+        ..options.clone()
+    };
+    for dir in parents
+        .iter()
+        .filter(|dir| !trees.contains(*dir) && !in_tree(dir))
+    {
+        run(dir, &shallow);
+    }
+}
+
+/// Renames `from` in `dest` to `to`, like it was renamed in the source, replacing `to` if it is
+/// a file, or an empty directory
+///
+/// # Returns
+/// Whether it was renamed, which it is not if `from` is not in `dest` as the same kind of entry,
+/// or if `to` could not be replaced
+fn rename(from: &Path, to: &Path, dir: bool, dest: &Path) -> bool {
+    match fs::symlink_metadata(dest.join(from)) {
+        Ok(metadata) if metadata.is_dir() == dir => {}
+        _ => return false,
+    }
+    let parent = to.parent().unwrap_or_else(|| Path::new(""));
+    let renamed = fs::create_dir_all(dest.join(parent)).and_then(|_| dirfd::rename(dest, from, to));
+    match renamed {
+        Ok(_) => {
+            info!("Renaming {:?} -> {:?}", dest.join(from), dest.join(to));
+            true
+        }
+        Err(e) => {
+            debug!(
+                "{:?} is copied, since it could not be renamed: {}",
+                dest.join(to),
+                e
+            );
+            false
+        }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_watch {
+    use super::*;
+
+    #[test]
+    fn formats_events() {
+        let time = UNIX_EPOCH + Duration::from_millis(1_592_191_800_250);
+        let event = Event::new(Change::Create, PathBuf::from("dir/\"new\""), false, time);
+        assert_eq!(
+            event.to_json(),
+            "{\"time\":\"2020-06-15T03:30:00.250Z\",\"event\":\"create\",\
+             \"path\":\"dir/\\\"new\\\"\",\"dir\":false}"
+        );
+        let event = Event::new(
+            Change::Rename(PathBuf::from("old")),
+            PathBuf::from("new"),
+            true,
+            time,
+        );
+        assert_eq!(
+            event.to_json(),
+            "{\"time\":\"2020-06-15T03:30:00.250Z\",\"event\":\"rename\",\"path\":\"new\",\
+             \"from\":\"old\",\"dir\":true}"
+        );
+    }
+
+    #[test]
+    fn applies_events() {
+        const TEST_SRC: &str = "test_watch_applies_events";
+        const TEST_DEST: &str = "test_watch_applies_events_dest";
+
+        fs::create_dir_all([TEST_SRC, "kept/deep"].join("/")).unwrap();
+        fs::create_dir_all([TEST_SRC, "gone/deep"].join("/")).unwrap();
+        fs::write([TEST_SRC, "kept/deep/file"].join("/"), b"deep").unwrap();
+        fs::write([TEST_SRC, "gone/deep/file"].join("/"), b"gone").unwrap();
+        core::copy(TEST_SRC, TEST_DEST, &Options::default()).unwrap();
+
+        fs::write([TEST_SRC, "kept/file"].join("/"), b"new").unwrap();
+        fs::remove_dir_all([TEST_SRC, "gone"].join("/")).unwrap();
+        fs::create_dir_all([TEST_SRC, "created/deep"].join("/")).unwrap();
+        fs::write([TEST_SRC, "created/deep/file"].join("/"), b"created").unwrap();
+        // Changes that are not in the events are left alone, even in the directories synchronized
+        fs::write([TEST_SRC, "kept/deep/file"].join("/"), b"missed").unwrap();
+
+        let event =
+            |change, path: &str, dir| Event::new(change, PathBuf::from(path), dir, UNIX_EPOCH);
+        let events = [
+            event(Change::Create, "kept/file", false),
+            event(Change::Modify, "kept/file", false),
+            event(Change::Delete, "gone/deep/file", false),
+            event(Change::Delete, "gone/deep", true),
+            event(Change::Delete, "gone", true),
+            event(Change::Create, "created", true),
+        ];
+        apply(&events, TEST_SRC, TEST_DEST, &Options::default());
+
+        assert_eq!(
+            fs::read([TEST_DEST, "kept/file"].join("/")).unwrap(),
+            b"new"
+        );
+        assert_eq!(
+            fs::read([TEST_DEST, "created/deep/file"].join("/")).unwrap(),
+            b"created"
+        );
+        assert!(fs::symlink_metadata([TEST_DEST, "gone"].join("/")).is_err());
+        assert_eq!(
+            fs::read([TEST_DEST, "kept/deep/file"].join("/")).unwrap(),
+            b"deep"
+        );
+
+        fs::remove_dir_all(TEST_SRC).unwrap();
+        fs::remove_dir_all(TEST_DEST).unwrap();
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn applies_renames() {
+        use std::os::unix::fs::MetadataExt;
+
+        const TEST_SRC: &str = "test_watch_applies_renames";
+        const TEST_DEST: &str = "test_watch_applies_renames_dest";
+
+        fs::create_dir_all([TEST_SRC, "old/deep"].join("/")).unwrap();
+        fs::write([TEST_SRC, "old/deep/file"].join("/"), b"deep").unwrap();
+        fs::write([TEST_SRC, "a"].join("/"), b"a").unwrap();
+        core::copy(TEST_SRC, TEST_DEST, &Options::default()).unwrap();
+        let inode = |path: &str| fs::metadata([TEST_DEST, path].join("/")).unwrap().ino();
+        let (deep, a) = (inode("old/deep/file"), inode("a"));
+
+        // Renames are made in the order they happened, even when one renames what another did
+        fs::create_dir([TEST_SRC, "new"].join("/")).unwrap();
+        fs::rename(
+            [TEST_SRC, "old"].join("/"),
+            [TEST_SRC, "new/moved"].join("/"),
+        )
+        .unwrap();
+        fs::rename([TEST_SRC, "a"].join("/"), [TEST_SRC, "b"].join("/")).unwrap();
+        fs::rename([TEST_SRC, "b"].join("/"), [TEST_SRC, "c"].join("/")).unwrap();
+        let rename = |from: &str, path: &str, dir| {
+            let change = Change::Rename(PathBuf::from(from));
+            Event::new(change, PathBuf::from(path), dir, UNIX_EPOCH)
+        };
+        let events = [
+            Event::new(Change::Create, PathBuf::from("new"), true, UNIX_EPOCH),
+            rename("old", "new/moved", true),
+            rename("a", "b", false),
+            rename("b", "c", false),
+        ];
+        apply(&events, TEST_SRC, TEST_DEST, &Options::default());
+
+        assert_eq!(inode("new/moved/deep/file"), deep);
+        assert_eq!(inode("c"), a);
+        for gone in &["old", "a", "b"] {
+            assert!(fs::symlink_metadata([TEST_DEST, gone].join("/")).is_err());
+        }
+
+        fs::remove_dir_all(TEST_SRC).unwrap();
+        fs::remove_dir_all(TEST_DEST).unwrap();
+    }
+}
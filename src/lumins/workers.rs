@@ -0,0 +1,161 @@
+//! Keeps copy worker threads alive across the phases of a run
+
+use std::sync::Arc;
+use std::thread;
+
+use crossbeam_channel::{bounded, SendError, Sender};
+
+use crate::lumins::parse::Options;
+
+/// A unit of work to run on a worker thread
+type Job = Box<dyn FnOnce() + Send>;
+
+/// A fixed set of long-lived threads that run jobs from a bounded queue
+///
+/// The threads stop once the workers are dropped, after the jobs that are already queued.
+#[derive(Debug)]
+pub struct Workers {
+    sender: Sender<Job>,
+    count: usize,
+}
+
+impl Workers {
+    /// Starts `count` worker threads, with room in the queue for 16 jobs per worker
+    ///
+    /// At least one worker is started. If threads can not be spawned, fewer workers are started,
+    /// and jobs are run on the calling thread if there are none at all.
+    ///
+    /// # Arguments
+    /// * `count`: number of worker threads to start
+    pub fn new(count: usize) -> Self {
+        const QUEUE_SIZE_PER_WORKER: usize = 16;
+
+        let (sender, receiver) = bounded::<Job>(count.max(1) * QUEUE_SIZE_PER_WORKER);
+
+        let mut spawned_count = 0;
+        for i in 0..count.max(1) {
+            let receiver = receiver.clone();
+            let spawned = thread::Builder::new()
+                .name(format!("lms-copy-{}", i))
+                .spawn(move || {
+                    for job in receiver {
+                        job();
+                    }
+                });
+            if spawned.is_err() {
+                break;
+            }
+            spawned_count += 1;
+        }
+
+        Workers {
+            sender,
+            count: spawned_count,
+        }
+    }
+
+    /// Gets the number of worker threads
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// Queues `job` to be run on a worker thread, waiting while the queue is full
+    ///
+    /// If there are no workers left to run it, `job` is run on the calling thread instead
+    pub fn execute<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        if let Err(SendError(job)) = self.sender.send(Box::new(job)) {
+            job();
+        }
+    }
+}
+
+/// Gets the worker threads that copy files for the run of `options` while other threads are still
+/// comparing them
+///
+/// A run starts its workers once, with one per thread of `options.threads`, or one per CPU, and
+/// reuses them in every later phase instead of spawning and joining them each time, see
+/// `core::for_run`. Callers outside of a run get workers of their own.
+pub fn copy_workers(options: &Options) -> Arc<Workers> {
+    match &options.workers {
+        Some(workers) => workers.clone(),
+        None => Arc::new(Workers::new(
+            options.threads.unwrap_or_else(rayon::current_num_threads),
+        )),
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_workers {
+    use super::*;
+    use crossbeam_channel::unbounded;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[test]
+    fn runs_every_job() {
+        let workers = Workers::new(4);
+        let counter = Arc::new(AtomicUsize::new(0));
+        let (done, finished) = unbounded::<()>();
+
+        assert_eq!(workers.count(), 4);
+
+        for _ in 0..1000 {
+            let counter = counter.clone();
+            let done = done.clone();
+            workers.execute(move || {
+                counter.fetch_add(1, Ordering::Relaxed);
+                drop(done);
+            });
+        }
+        drop(done);
+
+        // Returns once every job has dropped its sender
+        assert!(finished.recv().is_err());
+        assert_eq!(counter.load(Ordering::Relaxed), 1000);
+    }
+
+    #[test]
+    fn reused_between_batches() {
+        let workers = Workers::new(2);
+
+        for batch in 0..3 {
+            let (done, finished) = unbounded::<thread::ThreadId>();
+            for _ in 0..10 {
+                let done = done.clone();
+                workers.execute(move || done.send(thread::current().id()).unwrap());
+            }
+            drop(done);
+
+            let ids: Vec<_> = finished.iter().collect();
+            assert_eq!(ids.len(), 10, "batch {}", batch);
+            assert!(ids.iter().all(|id| *id != thread::current().id()));
+        }
+    }
+
+    #[test]
+    fn at_least_one_worker() {
+        assert_eq!(Workers::new(0).count(), 1);
+    }
+
+    #[test]
+    fn sized_by_threads() {
+        let options = Options::builder().threads(3).build().unwrap();
+        let workers = copy_workers(&options);
+        assert_eq!(workers.count(), 3);
+
+        // Runs reuse the workers they started
+        let options = Options {
+            workers: Some(workers.clone()),
+            threads: Some(5),
+            ..options
+        };
+        assert!(Arc::ptr_eq(&copy_workers(&options), &workers));
+    }
+}
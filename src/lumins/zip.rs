@@ -0,0 +1,908 @@
+//! Reads zip archives, whose members are stored as they are or compressed with deflate
+//!
+//! The members of an archive are listed from its central directory, which also holds their
+//! sizes, checksums, and where their contents start, so that any member can be read on its own,
+//! from its own handle on the archive. Zip64 archives are read as well, while encrypted members
+//! and compression methods other than deflate are not. Unix permissions, owners, and modification
+//! times are read from the extra fields that Info-ZIP writes, where there are any.
+
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom};
+
+use lazy_static::lazy_static;
+
+use crate::lumins::archive::{self, ArchivedEntry, ArchivedKind, EntryMetadata};
+use crate::lumins::compress::{DISTANCE_BASES, DISTANCE_BITS, LENGTH_BASES, LENGTH_BITS};
+
+/// Signature of the end of central directory record
+const END_SIGNATURE: u32 = 0x0605_4b50;
+
+/// Signature of the Zip64 end of central directory record and of its locator
+const ZIP64_END_SIGNATURE: u32 = 0x0606_4b50;
+const ZIP64_LOCATOR_SIGNATURE: u32 = 0x0706_4b50;
+
+/// Signatures of the headers of members in the central directory and in front of their contents
+const CENTRAL_SIGNATURE: u32 = 0x0201_4b50;
+const LOCAL_SIGNATURE: u32 = 0x0403_4b50;
+
+/// Compression methods of members
+const STORED: u16 = 0;
+const DEFLATED: u16 = 8;
+
+/// Where the contents of a member are, and how they are compressed
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub struct ZipData {
+    /// Offset of the local header of the member
+    header: u64,
+    compressed_size: u64,
+    method: u16,
+    crc: u32,
+}
+
+/// Checks whether `path` is a zip archive, by its extension
+pub fn is_zip(path: &str) -> bool {
+    path.ends_with(".zip")
+}
+
+/// Lists the members of the zip archive `file` from its central directory
+///
+/// Symlinks are members whose Unix mode says so, and their targets are their contents.
+///
+/// # Returns
+/// Every member, in the order of the central directory, along with where its contents are
+///
+/// # Errors
+/// This function will return an error if `file` could not be read, or is not a valid zip archive
+pub fn read_members(file: &mut fs::File) -> Result<Vec<(ArchivedEntry, ZipData)>, io::Error> {
+    let (count, offset, size) = find_central_directory(file)?;
+
+    file.seek(SeekFrom::Start(offset))?;
+    let mut directory = Vec::new();
+    file.take(size).read_to_end(&mut directory)?;
+    if (directory.len() as u64) < size {
+        return Err(archive::truncated_archive());
+    }
+
+    let mut members = Vec::with_capacity(count.min(1 << 20) as usize);
+    let mut rest = &directory[..];
+    for _ in 0..count {
+        if rest.len() < 46 || le32(rest, 0) != CENTRAL_SIGNATURE {
+            return Err(archive::invalid_archive("central directory is corrupt"));
+        }
+        let name_len = usize::from(le16(rest, 28));
+        let extra_len = usize::from(le16(rest, 30));
+        let comment_len = usize::from(le16(rest, 32));
+        let len = 46 + name_len + extra_len + comment_len;
+        if rest.len() < len {
+            return Err(archive::invalid_archive("central directory is corrupt"));
+        }
+        let (header, next) = rest.split_at(len);
+        rest = next;
+
+        let mut entry = central_entry(header, name_len, extra_len)?;
+        let data = entry.1.clone();
+        if let ArchivedKind::Symlink(_) = entry.0.kind {
+            let mut target = Vec::new();
+            open_member(file.try_clone()?, &entry.0, &data)?.read_to_end(&mut target)?;
+            entry.0.kind = ArchivedKind::Symlink(archive::path_from_archive(target)?);
+        }
+        members.push(entry);
+    }
+    Ok(members)
+}
+
+/// Opens the contents of the member `entry` of the zip archive `file`, at `data`
+///
+/// The contents are decompressed as they are read, and reading fails at their end if their size
+/// or checksum does not match the central directory.
+///
+/// # Errors
+/// This function will return an error if the local header of the member could not be read, or
+/// the member is compressed with a method other than deflate
+pub fn open_member(
+    mut file: fs::File,
+    entry: &ArchivedEntry,
+    data: &ZipData,
+) -> Result<Box<dyn Read + Send>, io::Error> {
+    let mut header = [0; 30];
+    file.seek(SeekFrom::Start(data.header))?;
+    file.read_exact(&mut header)?;
+    if le32(&header, 0) != LOCAL_SIGNATURE {
+        return Err(archive::invalid_archive("local header is corrupt"));
+    }
+    let start = data.header + 30 + u64::from(le16(&header, 26)) + u64::from(le16(&header, 28));
+    file.seek(SeekFrom::Start(start))?;
+
+    let compressed = BufReader::new(file.take(data.compressed_size));
+    let contents: Box<dyn Read + Send> = match data.method {
+        STORED => Box::new(compressed),
+        DEFLATED => Box::new(Inflater::new(compressed)),
+        method => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("compression method {} is not supported", method),
+            ))
+        }
+    };
+    Ok(Box::new(Checked {
+        reader: contents,
+        crc: !0,
+        size: 0,
+        expected_crc: data.crc,
+        expected_size: entry.size,
+    }))
+}
+
+/// Finds the central directory of `file` from its end of central directory record
+///
+/// # Returns
+/// The number of members in the central directory, its offset, and its size
+fn find_central_directory(file: &mut fs::File) -> Result<(u64, u64, u64), io::Error> {
+    // The record is at the end, followed by a comment of at most 65535 bytes
+    let len = file.metadata()?.len();
+    let tail_len = len.min(22 + 65535);
+    file.seek(SeekFrom::Start(len - tail_len))?;
+    let mut tail = Vec::with_capacity(tail_len as usize);
+    file.take(tail_len).read_to_end(&mut tail)?;
+
+    let end = (0..tail.len().saturating_sub(21))
+        .rev()
+        .find(|&i| le32(&tail, i) == END_SIGNATURE)
+        .ok_or_else(|| archive::invalid_archive("end of central directory is missing"))?;
+    let record = &tail[end..];
+    let count = u64::from(le16(record, 10));
+    let size = u64::from(le32(record, 12));
+    let offset = u64::from(le32(record, 16));
+    if count != 0xffff && size != 0xffff_ffff && offset != 0xffff_ffff {
+        return Ok((count, offset, size));
+    }
+
+    // Zip64 archives keep the real values in a record of their own, which a locator in front of
+    // the end of central directory record points to
+    let locator_start = (len - tail_len + end as u64)
+        .checked_sub(20)
+        .ok_or_else(|| archive::invalid_archive("Zip64 locator is missing"))?;
+    let mut locator = [0; 20];
+    file.seek(SeekFrom::Start(locator_start))?;
+    file.read_exact(&mut locator)?;
+    if le32(&locator, 0) != ZIP64_LOCATOR_SIGNATURE {
+        return Err(archive::invalid_archive("Zip64 locator is missing"));
+    }
+    let mut record = [0; 56];
+    file.seek(SeekFrom::Start(le64(&locator, 8)))?;
+    file.read_exact(&mut record)?;
+    if le32(&record, 0) != ZIP64_END_SIGNATURE {
+        return Err(archive::invalid_archive(
+            "Zip64 end of central directory is corrupt",
+        ));
+    }
+    Ok((le64(&record, 32), le64(&record, 48), le64(&record, 40)))
+}
+
+/// Reads the entry of a member from its `header` in the central directory
+fn central_entry(
+    header: &[u8],
+    name_len: usize,
+    extra_len: usize,
+) -> Result<(ArchivedEntry, ZipData), io::Error> {
+    const S_IFMT: u32 = 0o170_000;
+    const S_IFDIR: u32 = 0o040_000;
+    const S_IFREG: u32 = 0o100_000;
+    const S_IFLNK: u32 = 0o120_000;
+    /// Host systems that store Unix modes in the upper half of the external attributes
+    const UNIX_HOSTS: [u8; 2] = [3, 19];
+
+    if le16(header, 8) & 1 != 0 {
+        return Err(archive::invalid_archive(
+            "encrypted members are not supported",
+        ));
+    }
+
+    let name = header[46..46 + name_len].to_vec();
+    let extra = &header[46 + name_len..46 + name_len + extra_len];
+    let is_dir = name.last() == Some(&b'/');
+
+    let mut size = u64::from(le32(header, 24));
+    let mut compressed_size = u64::from(le32(header, 20));
+    let mut offset = u64::from(le32(header, 42));
+    let mut metadata = EntryMetadata {
+        mode: if is_dir { 0o755 } else { 0o644 },
+        uid: 0,
+        gid: 0,
+        mtime: dos_time(le16(header, 14), le16(header, 12)),
+    };
+    let unix_mode = if UNIX_HOSTS.contains(&header[5]) {
+        le32(header, 38) >> 16
+    } else {
+        0
+    };
+
+    for (id, field) in extra_fields(extra) {
+        match id {
+            // Zip64 sizes and offset, for the fields that do not fit into 32 bits
+            0x0001 => {
+                let mut values = field.chunks_exact(8).map(|value| le64(value, 0));
+                for value in [&mut size, &mut compressed_size, &mut offset] {
+                    if *value == 0xffff_ffff {
+                        *value = values
+                            .next()
+                            .ok_or_else(|| archive::invalid_archive("Zip64 field is corrupt"))?;
+                    }
+                }
+            }
+            // Extended timestamp, whose first timestamp is the modification time
+            0x5455 if field.len() >= 5 && field[0] & 1 != 0 => {
+                metadata.mtime = u64::from(le32(field, 1));
+            }
+            // Unix owners, of the sizes that the field gives
+            0x7875 if field.len() >= 3 => {
+                let uid_len = usize::from(field[1]);
+                if let Some(uid) = field.get(2..2 + uid_len) {
+                    metadata.uid = le_var(uid);
+                    let gid_len = field.get(2 + uid_len).map(|&len| usize::from(len));
+                    if let Some(gid) =
+                        gid_len.and_then(|len| field.get(3 + uid_len..3 + uid_len + len))
+                    {
+                        metadata.gid = le_var(gid);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let kind = match unix_mode & S_IFMT {
+        S_IFLNK => ArchivedKind::Symlink(Default::default()),
+        S_IFDIR => ArchivedKind::Dir,
+        S_IFREG | 0 if is_dir => ArchivedKind::Dir,
+        S_IFREG | 0 => ArchivedKind::File,
+        // The ustar type flags of the same types
+        0o010_000 => ArchivedKind::Other(b'6'),
+        0o020_000 => ArchivedKind::Other(b'3'),
+        0o060_000 => ArchivedKind::Other(b'4'),
+        _ => ArchivedKind::Other(b'?'),
+    };
+    if unix_mode & 0o7777 != 0 {
+        metadata.mode = unix_mode & 0o7777;
+    }
+
+    let mut path = name;
+    while path.last() == Some(&b'/') {
+        path.pop();
+    }
+    Ok((
+        ArchivedEntry {
+            path: archive::path_from_archive(path)?,
+            kind,
+            metadata,
+            size,
+        },
+        ZipData {
+            header: offset,
+            compressed_size,
+            method: le16(header, 10),
+            crc: le32(header, 16),
+        },
+    ))
+}
+
+/// Splits the extra fields of a header into their IDs and contents
+fn extra_fields(mut extra: &[u8]) -> Vec<(u16, &[u8])> {
+    let mut fields = Vec::new();
+    while extra.len() >= 4 {
+        let len = usize::from(le16(extra, 2)).min(extra.len() - 4);
+        fields.push((le16(extra, 0), &extra[4..4 + len]));
+        extra = &extra[4 + len..];
+    }
+    fields
+}
+
+/// Converts an MS-DOS date and time, which have no time zone, into seconds since the Unix epoch,
+/// as if they were in UTC
+fn dos_time(date: u16, time: u16) -> u64 {
+    let year = 1980 + i64::from(date >> 9);
+    let month = i64::from((date >> 5) & 0xf).max(1);
+    let day = i64::from(date & 0x1f).max(1);
+
+    // Days since the epoch of the civil date, counting years from March
+    let (year, month) = if month <= 2 {
+        (year - 1, month + 9)
+    } else {
+        (year, month - 3)
+    };
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * month + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+
+    let seconds = i64::from(time >> 11) * 3600
+        + i64::from((time >> 5) & 0x3f) * 60
+        + i64::from(time & 0x1f) * 2;
+    (days * 86400 + seconds).max(0) as u64
+}
+
+/// Reads the little-endian number at `offset` in `bytes`
+fn le16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+/// Reads the little-endian number at `offset` in `bytes`
+fn le32(bytes: &[u8], offset: usize) -> u32 {
+    let mut value = [0; 4];
+    value.copy_from_slice(&bytes[offset..offset + 4]);
+    u32::from_le_bytes(value)
+}
+
+/// Reads the little-endian number at `offset` in `bytes`
+fn le64(bytes: &[u8], offset: usize) -> u64 {
+    let mut value = [0; 8];
+    value.copy_from_slice(&bytes[offset..offset + 8]);
+    u64::from_le_bytes(value)
+}
+
+/// Reads a little-endian number of up to 8 bytes
+fn le_var(bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .take(8)
+        .rev()
+        .fold(0, |value, &byte| value << 8 | u64::from(byte))
+}
+
+lazy_static! {
+    /// Table of the CRC-32 of every byte, for the reflected polynomial 0xedb88320
+    static ref CRC_TABLE: [u32; 256] = {
+        let mut table = [0; 256];
+        for (i, entry) in table.iter_mut().enumerate() {
+            *entry = (0..8).fold(i as u32, |crc, _| {
+                if crc & 1 == 0 {
+                    crc >> 1
+                } else {
+                    crc >> 1 ^ 0xedb8_8320
+                }
+            });
+        }
+        table
+    };
+}
+
+/// Continues the CRC-32 `crc`, before its final inversion, over `data`
+fn crc32(crc: u32, data: &[u8]) -> u32 {
+    data.iter().fold(crc, |crc, &byte| {
+        CRC_TABLE[((crc ^ u32::from(byte)) & 0xff) as usize] ^ crc >> 8
+    })
+}
+
+/// A reader that checks the size and CRC-32 of the contents of a member once they end
+struct Checked<R: Read> {
+    reader: R,
+    crc: u32,
+    size: u64,
+    expected_crc: u32,
+    expected_size: u64,
+}
+
+impl<R: Read> Read for Checked<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.reader.read(buf)?;
+        if len == 0 && !buf.is_empty() {
+            if self.size != self.expected_size {
+                return Err(archive::truncated_archive());
+            }
+            if !self.crc != self.expected_crc {
+                return Err(archive::invalid_archive("checksum does not match"));
+            }
+        }
+        self.crc = crc32(self.crc, &buf[..len]);
+        self.size += len as u64;
+        Ok(len)
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Inflate
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Size of the window that deflate matches reach back into
+const WINDOW_SIZE: usize = 32768;
+
+/// Longest code of a deflate Huffman code
+const MAX_CODE_LENGTH: u32 = 15;
+
+/// Order in which the lengths of the code length code are stored
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+/// Reads bits from the least significant end of the bytes of a reader, as deflate packs them
+struct BitReader<R: BufRead> {
+    reader: R,
+    bits: u64,
+    count: u32,
+}
+
+impl<R: BufRead> BitReader<R> {
+    /// Buffers at least `count` bits, unless the reader ends first
+    fn refill(&mut self, count: u32) -> Result<(), io::Error> {
+        while self.count < count {
+            let buffer = self.reader.fill_buf()?;
+            let byte = match buffer.first() {
+                Some(&byte) => byte,
+                None => return Ok(()),
+            };
+            self.reader.consume(1);
+            self.bits |= u64::from(byte) << self.count;
+            self.count += 8;
+        }
+        Ok(())
+    }
+
+    /// Reads the next `count` bits, of at most 32
+    fn read(&mut self, count: u32) -> Result<u32, io::Error> {
+        self.refill(count)?;
+        if self.count < count {
+            return Err(archive::truncated_archive());
+        }
+        let value = (self.bits & ((1 << count) - 1)) as u32;
+        self.bits >>= count;
+        self.count -= count;
+        Ok(value)
+    }
+
+    /// Drops the bits up to the next byte boundary
+    fn align(&mut self) {
+        let partial = self.count % 8;
+        self.bits >>= partial;
+        self.count -= partial;
+    }
+}
+
+/// A canonical Huffman code, decoded by looking up the next bits of the input in a table
+struct Huffman {
+    /// The symbol and code length of every pattern of `length` bits, where 0 means no code
+    table: Vec<u16>,
+    length: u32,
+}
+
+impl Huffman {
+    /// Builds the code with the code lengths `lengths` of each symbol
+    ///
+    /// # Errors
+    /// This function will return an error if the lengths do not form a prefix code
+    fn new(lengths: &[u8]) -> Result<Self, io::Error> {
+        let mut counts = [0u32; MAX_CODE_LENGTH as usize + 1];
+        for &length in lengths {
+            counts[usize::from(length)] += 1;
+        }
+        counts[0] = 0;
+
+        // No more codes of a length are used than there are left
+        let mut left = 1i64;
+        for &count in &counts[1..] {
+            left = left * 2 - i64::from(count);
+            if left < 0 {
+                return Err(archive::invalid_archive("Huffman code is over-subscribed"));
+            }
+        }
+
+        let mut next = [0u32; MAX_CODE_LENGTH as usize + 1];
+        for length in 1..next.len() {
+            next[length] = (next[length - 1] + counts[length - 1]) << 1;
+        }
+
+        let length = lengths.iter().copied().max().unwrap_or(0).max(1) as u32;
+        let mut table = vec![0; 1 << length];
+        for (symbol, &code_length) in lengths.iter().enumerate() {
+            if code_length == 0 {
+                continue;
+            }
+            let code_length = u32::from(code_length);
+            let code = next[code_length as usize];
+            next[code_length as usize] += 1;
+
+            let reversed = code.reverse_bits() >> (32 - code_length);
+            let entry = (symbol as u16) << 4 | code_length as u16;
+            for index in (reversed as usize..table.len()).step_by(1 << code_length) {
+                table[index] = entry;
+            }
+        }
+        Ok(Huffman { table, length })
+    }
+
+    /// Decodes the next symbol from `input`
+    fn decode<R: BufRead>(&self, input: &mut BitReader<R>) -> Result<usize, io::Error> {
+        input.refill(self.length)?;
+        let entry = self.table[(input.bits & ((1 << self.length) - 1)) as usize];
+        let length = u32::from(entry & 0xf);
+        if length == 0 {
+            return Err(archive::invalid_archive("Huffman code is invalid"));
+        }
+        if length > input.count {
+            return Err(archive::truncated_archive());
+        }
+        input.bits >>= length;
+        input.count -= length;
+        Ok(usize::from(entry >> 4))
+    }
+}
+
+/// The block that an inflater is in
+enum Block {
+    /// Between blocks, where the next block header is read
+    Header,
+    /// An uncompressed block, with the number of bytes left in it
+    Stored(usize),
+    /// A compressed block, with its literal and length code, and its distance code
+    Codes(Huffman, Huffman),
+    /// After the last block
+    Done,
+}
+
+/// A reader that decompresses a raw deflate stream as it is read
+pub struct Inflater<R: BufRead> {
+    input: BitReader<R>,
+    window: Vec<u8>,
+    /// Number of bytes decompressed so far
+    position: usize,
+    block: Block,
+    /// Whether the current block is the last one
+    last: bool,
+    /// Length and distance of the part of a match that is left to copy
+    copy: (usize, usize),
+}
+
+impl<R: BufRead> Inflater<R> {
+    /// Creates an inflater that decompresses the deflate stream that `reader` reads
+    pub fn new(reader: R) -> Self {
+        Inflater {
+            input: BitReader {
+                reader,
+                bits: 0,
+                count: 0,
+            },
+            window: vec![0; WINDOW_SIZE],
+            position: 0,
+            block: Block::Header,
+            last: false,
+            copy: (0, 0),
+        }
+    }
+
+    /// Reads the header of the next block, and the codes of compressed blocks
+    fn read_header(&mut self) -> Result<Block, io::Error> {
+        self.last = self.input.read(1)? == 1;
+        match self.input.read(2)? {
+            0 => {
+                self.input.align();
+                let len = self.input.read(16)?;
+                if len != !self.input.read(16)? & 0xffff {
+                    return Err(archive::invalid_archive("stored block length is corrupt"));
+                }
+                Ok(Block::Stored(len as usize))
+            }
+            1 => {
+                let mut lengths = [8; 288];
+                lengths[144..256].iter_mut().for_each(|length| *length = 9);
+                lengths[256..280].iter_mut().for_each(|length| *length = 7);
+                Ok(Block::Codes(
+                    Huffman::new(&lengths)?,
+                    Huffman::new(&[5; 30])?,
+                ))
+            }
+            2 => self.read_codes(),
+            _ => Err(archive::invalid_archive("block type is invalid")),
+        }
+    }
+
+    /// Reads the literal and length code, and the distance code, of a dynamic block
+    fn read_codes(&mut self) -> Result<Block, io::Error> {
+        let literals = self.input.read(5)? as usize + 257;
+        let distances = self.input.read(5)? as usize + 1;
+        let code_lengths = self.input.read(4)? as usize + 4;
+
+        let mut lengths = [0; 19];
+        for &symbol in &CODE_LENGTH_ORDER[..code_lengths] {
+            lengths[symbol] = self.input.read(3)? as u8;
+        }
+        let code_length_code = Huffman::new(&lengths)?;
+
+        let mut lengths = vec![0; literals + distances];
+        let mut i = 0;
+        while i < lengths.len() {
+            let (length, repeat) = match code_length_code.decode(&mut self.input)? {
+                symbol @ 0..=15 => (symbol as u8, 1),
+                16 if i > 0 => (lengths[i - 1], 3 + self.input.read(2)? as usize),
+                17 => (0, 3 + self.input.read(3)? as usize),
+                18 => (0, 11 + self.input.read(7)? as usize),
+                _ => return Err(archive::invalid_archive("code lengths are corrupt")),
+            };
+            if i + repeat > lengths.len() {
+                return Err(archive::invalid_archive("code lengths are corrupt"));
+            }
+            lengths[i..i + repeat].iter_mut().for_each(|l| *l = length);
+            i += repeat;
+        }
+        if lengths[256] == 0 {
+            return Err(archive::invalid_archive("block has no end"));
+        }
+        Ok(Block::Codes(
+            Huffman::new(&lengths[..literals])?,
+            Huffman::new(&lengths[literals..])?,
+        ))
+    }
+}
+
+impl<R: BufRead> Read for Inflater<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut written = 0;
+        while written < buf.len() {
+            let byte = if self.copy.0 > 0 {
+                self.copy.0 -= 1;
+                self.window[(self.position - self.copy.1) % WINDOW_SIZE]
+            } else {
+                match &mut self.block {
+                    Block::Done => break,
+                    Block::Header => {
+                        self.block = self.read_header()?;
+                        continue;
+                    }
+                    Block::Stored(0) => {
+                        self.block = if self.last {
+                            Block::Done
+                        } else {
+                            Block::Header
+                        };
+                        continue;
+                    }
+                    Block::Stored(left) => {
+                        *left -= 1;
+                        self.input.read(8)? as u8
+                    }
+                    Block::Codes(literals, distances) => match literals.decode(&mut self.input)? {
+                        symbol @ 0..=255 => symbol as u8,
+                        256 => {
+                            self.block = if self.last {
+                                Block::Done
+                            } else {
+                                Block::Header
+                            };
+                            continue;
+                        }
+                        symbol @ 257..=285 => {
+                            let code = symbol - 257;
+                            let length =
+                                LENGTH_BASES[code] + self.input.read(LENGTH_BITS[code])? as usize;
+                            let code = distances.decode(&mut self.input)?;
+                            if code >= DISTANCE_BASES.len() {
+                                return Err(archive::invalid_archive("distance is invalid"));
+                            }
+                            let distance = DISTANCE_BASES[code]
+                                + self.input.read(DISTANCE_BITS[code])? as usize;
+                            if distance > self.position.min(WINDOW_SIZE) {
+                                return Err(archive::invalid_archive("distance is too far"));
+                            }
+                            self.copy = (length, distance);
+                            continue;
+                        }
+                        _ => return Err(archive::invalid_archive("length is invalid")),
+                    },
+                }
+            };
+            self.window[self.position % WINDOW_SIZE] = byte;
+            self.position += 1;
+            buf[written] = byte;
+            written += 1;
+        }
+        Ok(written)
+    }
+}
+
+/// Reads the zip archive at `path`, for tests
+#[cfg(test)]
+fn read_archive(path: &std::path::Path) -> Vec<(ArchivedEntry, Vec<u8>)> {
+    let mut file = fs::File::open(path).unwrap();
+    read_members(&mut file)
+        .unwrap()
+        .into_iter()
+        .map(|(entry, data)| {
+            let mut contents = Vec::new();
+            if entry.kind == ArchivedKind::File {
+                open_member(file.try_clone().unwrap(), &entry, &data)
+                    .unwrap()
+                    .read_to_end(&mut contents)
+                    .unwrap();
+            }
+            (entry, contents)
+        })
+        .collect()
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_inflate {
+    use super::*;
+    use crate::lumins::compress::{self, Codec};
+
+    fn inflate(data: &[u8]) -> Result<Vec<u8>, io::Error> {
+        let mut contents = Vec::new();
+        Inflater::new(data)
+            .read_to_end(&mut contents)
+            .map(|_| contents)
+    }
+
+    #[test]
+    fn stored_blocks() {
+        // Two stored blocks, of which only the second is the last
+        let stream = [0, 2, 0, 0xfd, 0xff, b'a', b'b', 1, 1, 0, 0xfe, 0xff, b'c'];
+        assert_eq!(inflate(&stream).unwrap(), b"abc");
+    }
+
+    #[test]
+    fn fixed_codes() {
+        let data: Vec<u8> = b"abcabcabcabc, and some more abcabcabc".repeat(500);
+        // Skips the zlib header and the Adler-32 at the end
+        let stream = compress::compress(Codec::Gzip, &data).unwrap();
+        assert_eq!(inflate(&stream[2..stream.len() - 4]).unwrap(), data);
+    }
+
+    /// `zlib.compress(b"hello hello hello hello\n", wbits=-15)`
+    const HELLO: [u8; 11] = [
+        0xcb, 0x48, 0xcd, 0xc9, 0xc9, 0x57, 0xc8, 0x40, 0x27, 0xb9, 0x00,
+    ];
+
+    #[test]
+    fn fixed_codes_of_zlib() {
+        assert_eq!(inflate(&HELLO).unwrap(), b"hello hello hello hello\n");
+    }
+
+    #[test]
+    fn dynamic_codes() {
+        use std::io::Write;
+        use std::process::{Command, Stdio};
+
+        let data: Vec<u8> = (0..100_000u64)
+            .map(|i| b"abcdefgh "[(i * i % 97 % 9) as usize])
+            .collect();
+        let python = Command::new("python3")
+            .args([
+                "-c",
+                "import sys, zlib; c = zlib.compressobj(9, zlib.DEFLATED, -15); \
+                 sys.stdout.buffer.write(c.compress(sys.stdin.buffer.read()) + c.flush())",
+            ])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn();
+        let mut python = match python {
+            Ok(python) => python,
+            Err(_) => return,
+        };
+        python.stdin.take().unwrap().write_all(&data).unwrap();
+        let stream = python.wait_with_output().unwrap().stdout;
+
+        // The first block has codes of its own
+        assert_eq!((stream[0] >> 1) & 3, 2);
+        assert_eq!(inflate(&stream).unwrap(), data);
+    }
+
+    #[test]
+    fn corrupt_streams() {
+        // Block type 3, a truncated compressed block, and a truncated stored block
+        assert!(inflate(&[0x07]).is_err());
+        assert!(inflate(&HELLO[..6]).is_err());
+        assert!(inflate(&[1, 5, 0, 0xfa, 0xff, b'a']).is_err());
+    }
+}
+
+#[cfg(test)]
+mod test_read_members {
+    use super::*;
+    use std::path::Path;
+    use std::process::Command;
+
+    #[test]
+    fn crc32_of_check_string() {
+        assert_eq!(!crc32(!0, b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn dos_times() {
+        // 2020-02-29 13:45:58
+        let date = (40 << 9) | (2 << 5) | 29;
+        let time = (13 << 11) | (45 << 5) | 29;
+        assert_eq!(dos_time(date, time), 1_582_983_958);
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn python_archive() {
+        const TEST_DIR: &str = "test_zip_python_archive";
+
+        fs::create_dir_all(TEST_DIR).unwrap();
+        let archive = [TEST_DIR, "test.zip"].join("/");
+        // Python writes Unix modes, stored and deflated members, and symlinks
+        let script = format!(
+            r#"
+import zipfile
+z = zipfile.ZipFile({:?}, "w")
+i = zipfile.ZipInfo("dir/", (2020, 1, 2, 3, 4, 6))
+i.external_attr = 0o40750 << 16
+z.writestr(i, b"")
+i = zipfile.ZipInfo("dir/file", (2020, 1, 2, 3, 4, 6))
+i.external_attr = 0o100600 << 16
+i.compress_type = zipfile.ZIP_DEFLATED
+z.writestr(i, b"contents " * 1000)
+i = zipfile.ZipInfo("link", (2020, 1, 2, 3, 4, 6))
+i.external_attr = 0o120777 << 16
+z.writestr(i, b"dir/file")
+z.writestr("plain", b"stored")
+z.close()
+"#,
+            archive
+        );
+        let written = Command::new("python3").args(["-c", &script]).output();
+        if !written.is_ok_and(|output| output.status.success()) {
+            fs::remove_dir_all(TEST_DIR).unwrap();
+            return;
+        }
+
+        let members = read_archive(Path::new(&archive));
+        let paths: Vec<_> = members
+            .iter()
+            .map(|(entry, _)| entry.path.clone())
+            .collect();
+        assert_eq!(
+            paths,
+            vec![
+                Path::new("dir"),
+                Path::new("dir/file"),
+                Path::new("link"),
+                Path::new("plain")
+            ]
+        );
+
+        assert_eq!(members[0].0.kind, ArchivedKind::Dir);
+        assert_eq!(members[0].0.metadata.mode, 0o750);
+        assert_eq!(members[0].0.metadata.mtime, 1_577_934_246);
+        assert_eq!(members[1].0.kind, ArchivedKind::File);
+        assert_eq!(members[1].0.metadata.mode, 0o600);
+        assert_eq!(members[1].1, b"contents ".repeat(1000));
+        assert_eq!(
+            members[2].0.kind,
+            ArchivedKind::Symlink(Path::new("dir/file").to_path_buf())
+        );
+        // Python writes members that are only given a name with mode 0o600
+        assert_eq!(members[3].0.metadata.mode, 0o600);
+        assert_eq!(members[3].1, b"stored");
+
+        // Corrupt contents fail their checksum
+        let mut bytes = fs::read(&archive).unwrap();
+        let at = bytes
+            .windows(6)
+            .position(|window| window == b"stored")
+            .unwrap();
+        bytes[at] = b'S';
+        fs::write(&archive, bytes).unwrap();
+        let mut file = fs::File::open(&archive).unwrap();
+        let (entry, data) = read_members(&mut file).unwrap().pop().unwrap();
+        let mut contents = Vec::new();
+        assert!(open_member(file, &entry, &data)
+            .unwrap()
+            .read_to_end(&mut contents)
+            .is_err());
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+
+    #[test]
+    fn not_an_archive() {
+        const TEST_FILE: &str = "test_zip_not_an_archive.zip";
+
+        fs::write(TEST_FILE, b"PK not really").unwrap();
+        assert!(read_members(&mut fs::File::open(TEST_FILE).unwrap()).is_err());
+        fs::remove_file(TEST_FILE).unwrap();
+    }
+}
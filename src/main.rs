@@ -1,45 +1,220 @@
+use std::fmt::Display;
+use std::io;
+use std::path::{Path, PathBuf};
 use std::process;
 
-use clap::{load_yaml, App};
+use clap::{CommandFactory, Parser};
 
-use lms::core;
+use lms::cli::{Cli, Command};
+use lms::exit::ExitCode;
 use lms::parse::{self, SubCommandType};
-use lms::progress::PROGRESS_BAR;
+use lms::progress::{PROGRESS_BAR, STATS};
+use lms::{
+    archive, bisync, config, core, daemon, delta, dupes, fidelity, help, history, man, manifest,
+    materialize, oci, overlay, review, snapshot, standby, stats, watch,
+};
+#[cfg(target_os = "linux")]
+use lms::{harden, privsep};
 
 fn main() {
     // Parse command args
-    let yaml = load_yaml!("cli.yml");
-    let args = App::from_yaml(yaml).get_matches();
+    let cli = match Cli::try_parse() {
+        Ok(cli) => cli,
+        // Help and version are printed to stdout, and are not errors
+        Err(e) if !e.use_stderr() => e.exit(),
+        Err(e) => {
+            let _ = e.print();
+            process::exit(ExitCode::Usage.code());
+        }
+    };
+
+    match &cli.command {
+        // Help is printed for subcommands or topics, and runs nothing
+        Command::Help(args) => {
+            if let Err(e) = help::print_help(Cli::command(), args.topic.as_deref()) {
+                eprintln!("{}", e);
+                process::exit(ExitCode::Usage.code());
+            }
+            return;
+        }
+        // Completion scripts are generated from the same definition of the arguments that parses
+        // them
+        Command::Completions(args) => {
+            clap_complete::generate(args.shell, &mut Cli::command(), "lms", &mut io::stdout());
+            return;
+        }
+        Command::Man => {
+            print!("{}", man::render(&Cli::command(), help::TOPICS));
+            return;
+        }
+        _ => {}
+    }
+
+    // Determine subcommands and options from args
+    let (sub_command, options) = match parse::parse_args(&cli) {
+        Ok(f) => (f.sub_command, f.options),
+        Err(code) => process::exit(code.code()),
+    };
 
-    // Determine subcommands and flags from args
-    let (sub_command, flags) = match parse::parse_args(&args) {
-        Ok(f) => (f.sub_command, f.flags),
-        Err(_) => process::exit(1),
+    parse::set_env(&options);
+
+    // Restrict the process before any worker is started, so that every worker is restricted too
+    #[cfg(target_os = "linux")]
+    {
+        if options.harden {
+            let mut writable = harden::writable_dirs(&options, sub_command.profile.is_some());
+            let dest = &sub_command.dest[0];
+            let archive_dest = sub_command.sub_command_type == SubCommandType::Copy
+                && archive::is_archive(dest)
+                && !Path::new(dest).is_dir();
+            writable.push(harden::writable_dest(dest, archive_dest));
+            if let Err(e) = harden::harden(&[sub_command.src.unwrap()], &writable) {
+                eprintln!("{}", e);
+                process::exit(ExitCode::Failed.code());
+            }
+        }
+    }
+
+    // Results are printed to stdout, unless quiet
+    let quiet = options.quiet;
+    let print = |result: &dyn Display| {
+        if !quiet {
+            println!("{}", result);
+        }
     };
 
-    parse::set_env(flags);
+    // Runs are only recorded in the history if they are given a profile
+    let record = |run: &dyn Fn() -> Result<(), std::io::Error>| match sub_command.profile {
+        Some(profile) => history::record(&history::default_path(), profile, run),
+        None => run(),
+    };
 
     // Call correct core function depending on subcommand
     let result = match sub_command.sub_command_type {
-        SubCommandType::Copy => core::copy(sub_command.src.unwrap(), &sub_command.dest[0], flags),
+        #[cfg(target_os = "linux")]
+        SubCommandType::Copy if options.privsep => {
+            let writable = harden::writable_dirs(&options, sub_command.profile.is_some());
+            record(&|| {
+                privsep::copy(
+                    sub_command.src.unwrap(),
+                    &sub_command.dest[0],
+                    &options,
+                    &writable,
+                )
+            })
+        }
+        SubCommandType::Copy => {
+            record(&|| core::copy(sub_command.src.unwrap(), &sub_command.dest[0], &options))
+        }
+        SubCommandType::Move => {
+            core::move_dir(sub_command.src.unwrap(), &sub_command.dest[0], &options)
+        }
         SubCommandType::Remove => sub_command
             .dest
             .iter()
-            .map(|dest| core::remove(dest, flags))
-            .collect(),
+            .try_for_each(|dest| core::remove(dest, &options)),
+        SubCommandType::Synchronize if options.dry_run => {
+            review::dry_run(sub_command.src.unwrap(), &sub_command.dest[0], &options)
+        }
+        SubCommandType::Synchronize if options.watch => {
+            watch::watch(sub_command.src.unwrap(), &sub_command.dest[0], &options)
+        }
         SubCommandType::Synchronize => {
-            core::synchronize(sub_command.src.unwrap(), &sub_command.dest[0], flags)
+            record(&|| core::synchronize(sub_command.src.unwrap(), &sub_command.dest[0], &options))
+        }
+        SubCommandType::Tui => {
+            review::review_sync(sub_command.src.unwrap(), &sub_command.dest[0], &options)
+        }
+        SubCommandType::Daemon => {
+            config::load(sub_command.config).and_then(|config| daemon::run(&config))
+        }
+        SubCommandType::ExportOci => oci::export(
+            sub_command.src.unwrap(),
+            &sub_command.dest[0],
+            sub_command.tag,
+        )
+        .map(|digest| print(&digest)),
+        SubCommandType::ExportDiff => delta::export_diff(
+            sub_command.src.unwrap(),
+            &sub_command.dest[0],
+            &sub_command.dest[1],
+            &options,
+        ),
+        SubCommandType::Manifest => manifest::export(
+            sub_command.src.unwrap(),
+            sub_command.dest.first().map(String::as_str),
+        ),
+        SubCommandType::Diff => {
+            let format = options.format.unwrap_or(manifest::Format::Plain);
+            match sub_command.src {
+                Some(first) => manifest::diff_trees(first, &sub_command.dest[0], format, &options),
+                None => manifest::diff_files(&sub_command.dest[0], &sub_command.dest[1], format),
+            }
+        }
+        SubCommandType::Hash => manifest::hash_tree(sub_command.src.unwrap()),
+        SubCommandType::Dupes => dupes::run(sub_command.src.unwrap(), &options),
+        SubCommandType::Stats => stats::Stats::gather(sub_command.src.unwrap(), sub_command.top)
+            .map(|stats| println!("{}", stats)),
+        SubCommandType::Verify => {
+            standby::verify(sub_command.src.unwrap(), &sub_command.dest[0], &options)
+        }
+        SubCommandType::Overlay => {
+            overlay::overlay(&sub_command.sources, &sub_command.dest[0], &options)
+        }
+        SubCommandType::Snapshot => {
+            snapshot::snapshot_tree(sub_command.src.unwrap(), &sub_command.dest[0], &options)
+                .map(|snapshot| print(&snapshot.display()))
+        }
+        SubCommandType::Hydrate => {
+            materialize::hydrate(&sub_command.dest, sub_command.src, &options)
+        }
+        SubCommandType::Bisync => {
+            bisync::bisync(sub_command.src.unwrap(), &sub_command.dest[0], &options)
         }
+        #[cfg(target_os = "linux")]
+        SubCommandType::PrivsepReader => privsep::read(sub_command.src.unwrap()),
+        #[cfg(not(target_os = "linux"))]
+        SubCommandType::PrivsepReader => Ok(()),
+        SubCommandType::History => history::show(
+            &sub_command
+                .history
+                .map(PathBuf::from)
+                .unwrap_or_else(history::default_path),
+            sub_command.profile,
+            sub_command.runs,
+        ),
     };
 
     // End and remove progress bars
     PROGRESS_BAR.finish_and_clear();
 
-    // If error, print to stderr and exit
+    // With --keep-going, report every error again at the end, so that none are lost in the output
+    let messages = STATS.error_messages();
+    if !messages.is_empty() {
+        eprintln!("{} errors:", messages.len());
+        for message in messages {
+            eprintln!("  {}", message);
+        }
+    }
+
+    // Report the metadata that could not be preserved, so that it is known how faithful the copy is
+    let degradations = STATS.degradations();
+    // The summary is not an error, so it is left out with --quiet, like other summaries
+    if let Some(summary) = fidelity::summary(&degradations).filter(|_| !options.quiet) {
+        eprintln!("{}", summary);
+    }
+    if let Some(path) = &options.fidelity_report {
+        if let Err(e) = fidelity::write_report(path, &degradations) {
+            eprintln!("{}", e);
+        }
+    }
+
+    // If error, print to stderr, and exit with why the run failed
+    let code = ExitCode::of(&result, STATS.errors());
     if let Err(e) = result {
         eprintln!("{}", e);
-        process::exit(1);
     }
+    process::exit(code.code());
 }
 
 ///////////////////////////////////////////////////////////////////////////////////////////////////
@@ -48,103 +223,265 @@ fn main() {
 
 #[cfg(test)]
 mod test_main {
+    #[cfg(target_family = "unix")]
     use std::fs;
-    use std::process::Command;
+    use std::io::Write;
+    use std::process::{Command, Stdio};
 
-    #[cfg(debug_assertions)]
+    #[cfg(all(target_family = "unix", debug_assertions))]
     const BUILD_DIR: &str = "target/debug";
 
-    #[cfg(not(debug_assertions))]
+    #[cfg(all(target_family = "unix", not(debug_assertions)))]
     const BUILD_DIR: &str = "target/release";
 
     #[test]
     fn test_no_args() {
         Command::new("cargo")
-            .args(&["build", "--release"])
+            .args(["build", "--release"])
             .output()
             .unwrap();
 
         let output = Command::new("target/release/lms").output().unwrap();
 
-        assert_eq!(output.status.success(), false);
+        assert_eq!(output.status.code(), Some(2));
     }
 
     #[test]
     fn test_no_dest() {
         Command::new("cargo")
-            .args(&["build", "--release"])
+            .args(["build", "--release"])
             .output()
             .unwrap();
 
         let output = Command::new("target/release/lms")
-            .args(&["sync", "src"])
+            .args(["sync", "src"])
             .output()
             .unwrap();
 
-        assert_eq!(output.status.success(), false);
+        assert_eq!(output.status.code(), Some(2));
     }
 
     #[test]
     fn test_too_many_args() {
         Command::new("cargo")
-            .args(&["build", "--release"])
+            .args(["build", "--release"])
             .output()
             .unwrap();
 
         let output = Command::new("target/release/lms")
-            .args(&["sync", "src", "dest", "dest"])
+            .args(["sync", "src", "dest", "dest"])
             .output()
             .unwrap();
 
-        assert_eq!(output.status.success(), false);
+        assert_eq!(output.status.code(), Some(2));
+    }
+
+    #[test]
+    fn test_help() {
+        Command::new("cargo")
+            .args(["build", "--release"])
+            .output()
+            .unwrap();
+
+        let help = |topic: &str| {
+            Command::new("target/release/lms")
+                .args(["help", topic])
+                .output()
+                .unwrap()
+        };
+
+        // Topics and subcommands both have help, with examples for subcommands
+        let output = help("snapshots");
+        assert!(output.status.success());
+        assert!(String::from_utf8_lossy(&output.stdout).contains("--dest-snapshot"));
+        let output = help("sync");
+        assert!(output.status.success());
+        assert!(String::from_utf8_lossy(&output.stdout).contains("EXAMPLES:"));
+        assert_eq!(help("nothing").status.code(), Some(2));
+    }
+
+    #[test]
+    fn test_completions() {
+        Command::new("cargo")
+            .args(["build", "--release"])
+            .output()
+            .unwrap();
+
+        for shell in &["bash", "zsh", "fish", "powershell"] {
+            let output = Command::new("target/release/lms")
+                .args(["completions", shell])
+                .output()
+                .unwrap();
+            assert!(output.status.success());
+            assert!(String::from_utf8_lossy(&output.stdout).contains("safe-delete"));
+        }
+        let output = Command::new("target/release/lms")
+            .args(["completions", "tcsh"])
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(2));
     }
 
     #[test]
     fn test_invalid_args() {
         Command::new("cargo")
-            .args(&["build", "--release"])
+            .args(["build", "--release"])
+            .output()
+            .unwrap();
+
+        let output = Command::new("target/release/lms")
+            .args(["sync", "a", "dest"])
+            .output()
+            .unwrap();
+
+        assert_eq!(output.status.code(), Some(3));
+    }
+
+    #[test]
+    fn test_dest_not_writable() {
+        Command::new("cargo")
+            .args(["build", "--release"])
+            .output()
+            .unwrap();
+
+        let output = Command::new("target/release/lms")
+            .args(["cp", "src", "Cargo.toml/dest"])
+            .output()
+            .unwrap();
+
+        assert_eq!(output.status.code(), Some(4));
+    }
+
+    #[test]
+    fn test_daemon_invalid_config() {
+        Command::new("cargo")
+            .args(["build", "--release"])
             .output()
             .unwrap();
 
         let output = Command::new("target/release/lms")
-            .args(&["sync", "a", "dest"])
+            .args(["daemon", "-c", "/?"])
             .output()
             .unwrap();
 
-        assert_eq!(output.status.success(), false);
+        assert_eq!(output.status.code(), Some(5));
     }
 
     #[cfg(target_family = "unix")]
     #[test]
     fn test_copy() {
         Command::new("cargo")
-            .args(&["build", "--release"])
+            .args(["build", "--release"])
             .output()
             .unwrap();
 
         const TEST_SOURCE: &str = BUILD_DIR;
         const TEST_DEST: &str = "test_main_test_copy";
 
-        Command::new("target/release/lms")
-            .args(&["cp", "-v", TEST_SOURCE, TEST_DEST])
+        let output = Command::new("target/release/lms")
+            .args(["cp", "-v", TEST_SOURCE, TEST_DEST])
             .output()
             .unwrap();
 
+        assert_eq!(output.status.code(), Some(0));
+
         let diff = Command::new("diff")
-            .args(&["-r", TEST_SOURCE, TEST_DEST])
+            .args(["-r", TEST_SOURCE, TEST_DEST])
             .output()
             .unwrap();
 
-        assert_eq!(diff.status.success(), true);
+        assert!(diff.status.success());
 
         fs::remove_dir_all(TEST_DEST).unwrap();
     }
 
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn test_log_file() {
+        Command::new("cargo")
+            .args(["build", "--release"])
+            .output()
+            .unwrap();
+
+        const TEST_DEST: &str = "test_main_test_log_file";
+        const TEST_LOG: &str = "test_main_test_log_file.log";
+
+        let output = Command::new("target/release/lms")
+            .args(["cp", "--log-file", TEST_LOG, "src", TEST_DEST])
+            .output()
+            .unwrap();
+
+        assert_eq!(output.status.code(), Some(0));
+        assert!(output.stdout.is_empty());
+
+        let log = fs::read_to_string(TEST_LOG).unwrap();
+        assert!(log
+            .lines()
+            .any(|line| line.contains("Copying file \"src/main.rs\"")));
+        assert!(log.lines().all(|line| line.contains("Z INFO  ")));
+
+        fs::remove_dir_all(TEST_DEST).unwrap();
+        fs::remove_file(TEST_LOG).unwrap();
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn test_quiet() {
+        Command::new("cargo")
+            .args(["build", "--release"])
+            .output()
+            .unwrap();
+
+        const TEST_DEST: &str = "test_main_test_quiet";
+
+        let output = Command::new("target/release/lms")
+            .args(["snapshot", "-q", "src", TEST_DEST])
+            .output()
+            .unwrap();
+
+        assert_eq!(output.status.code(), Some(0));
+        assert!(output.stdout.is_empty());
+        assert!(output.stderr.is_empty());
+        assert_eq!(fs::read_dir(TEST_DEST).unwrap().count(), 1);
+
+        fs::remove_dir_all(TEST_DEST).unwrap();
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn test_verbosity() {
+        Command::new("cargo")
+            .args(["build", "--release"])
+            .output()
+            .unwrap();
+
+        const TEST_DEST: &str = "test_main_test_verbosity";
+        const TEST_LOG: &str = "test_main_test_verbosity.log";
+        fs::create_dir_all(TEST_DEST).unwrap();
+
+        // Files that are already synced are explained with -vv
+        for _ in 0..2 {
+            let output = Command::new("target/release/lms")
+                .args(["sync", "-vv", "--log-file", TEST_LOG, "src", TEST_DEST])
+                .output()
+                .unwrap();
+            assert_eq!(output.status.code(), Some(0));
+        }
+
+        let log = fs::read_to_string(TEST_LOG).unwrap();
+        assert!(log.lines().any(|line| line.contains("Z DEBUG ")
+            && line.contains("main.rs\" is skipped, since its hash matches its source")));
+        assert!(!log.contains("Z TRACE "));
+
+        fs::remove_dir_all(TEST_DEST).unwrap();
+        fs::remove_file(TEST_LOG).unwrap();
+    }
+
     #[cfg(target_family = "unix")]
     #[test]
     fn test_secure() {
         Command::new("cargo")
-            .args(&["build", "--release"])
+            .args(["build", "--release"])
             .output()
             .unwrap();
 
@@ -153,16 +490,16 @@ mod test_main {
         fs::create_dir_all(TEST_DEST).unwrap();
 
         Command::new("target/release/lms")
-            .args(&["sync", "-s", TEST_SOURCE, TEST_DEST])
+            .args(["sync", "-s", TEST_SOURCE, TEST_DEST])
             .output()
             .unwrap();
 
         let diff = Command::new("diff")
-            .args(&["-r", TEST_SOURCE, TEST_DEST])
+            .args(["-r", TEST_SOURCE, TEST_DEST])
             .output()
             .unwrap();
 
-        assert_eq!(diff.status.success(), true);
+        assert!(diff.status.success());
 
         fs::remove_dir_all(TEST_DEST).unwrap();
     }
@@ -171,7 +508,7 @@ mod test_main {
     #[test]
     fn test_sequential() {
         Command::new("cargo")
-            .args(&["build", "--release"])
+            .args(["build", "--release"])
             .output()
             .unwrap();
 
@@ -179,16 +516,16 @@ mod test_main {
         const TEST_DEST: &str = "test_main_test_sequential";
 
         Command::new("target/release/lms")
-            .args(&["sync", "-S", TEST_SOURCE, TEST_DEST])
+            .args(["sync", "-S", TEST_SOURCE, TEST_DEST])
             .output()
             .unwrap();
 
         let diff = Command::new("diff")
-            .args(&["-r", TEST_SOURCE, TEST_DEST])
+            .args(["-r", TEST_SOURCE, TEST_DEST])
             .output()
             .unwrap();
 
-        assert_eq!(diff.status.success(), true);
+        assert!(diff.status.success());
 
         fs::remove_dir_all(TEST_DEST).unwrap();
     }
@@ -197,7 +534,7 @@ mod test_main {
     #[test]
     fn test_sequential_copy() {
         Command::new("cargo")
-            .args(&["build", "--release"])
+            .args(["build", "--release"])
             .output()
             .unwrap();
 
@@ -205,16 +542,180 @@ mod test_main {
         const TEST_DEST: &str = "test_main_test_sequential_copy";
 
         Command::new("target/release/lms")
-            .args(&["cp", "-S", TEST_SOURCE, TEST_DEST])
+            .args(["cp", "-S", TEST_SOURCE, TEST_DEST])
+            .output()
+            .unwrap();
+
+        let diff = Command::new("diff")
+            .args(["-r", TEST_SOURCE, TEST_DEST])
+            .output()
+            .unwrap();
+
+        assert!(diff.status.success());
+
+        fs::remove_dir_all(TEST_DEST).unwrap();
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn test_copy_into() {
+        Command::new("cargo")
+            .args(["build", "--release"])
+            .output()
+            .unwrap();
+
+        const TEST_SOURCE: &str = BUILD_DIR;
+        const TEST_DEST: &str = "test_main_test_copy_into";
+
+        Command::new("target/release/lms")
+            .args(["cp", "--into", TEST_SOURCE, TEST_DEST])
             .output()
             .unwrap();
 
+        // The source is copied into a subdirectory named after it, even though TEST_DEST is new
+        let into = [TEST_DEST, TEST_SOURCE.rsplit('/').next().unwrap()].join("/");
         let diff = Command::new("diff")
-            .args(&["-r", TEST_SOURCE, TEST_DEST])
+            .args(["-r", TEST_SOURCE, &into])
+            .output()
+            .unwrap();
+
+        assert!(diff.status.success());
+
+        fs::remove_dir_all(TEST_DEST).unwrap();
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn test_trailing_slash() {
+        Command::new("cargo")
+            .args(["build", "--release"])
             .output()
             .unwrap();
 
-        assert_eq!(diff.status.success(), true);
+        const TEST_SOURCE: &str = "src/lumins";
+        const TEST_DEST: &str = "test_main_test_trailing_slash";
+
+        // Like rsync, the contents of src/ are copied into the destination even though it exists,
+        // and src is copied into a subdirectory named after it even though it does not
+        fs::create_dir_all([TEST_DEST, "contents"].join("/")).unwrap();
+        for (src, dest) in [
+            (format!("{}/", TEST_SOURCE), "contents"),
+            (TEST_SOURCE.to_string(), "itself"),
+        ] {
+            let output = Command::new("target/release/lms")
+                .args(["cp", "--trailing-slash", "rsync", &src])
+                .arg([TEST_DEST, dest].join("/"))
+                .output()
+                .unwrap();
+            assert!(output.status.success());
+        }
+
+        for copy in ["contents", "itself/lumins"] {
+            let diff = Command::new("diff")
+                .args(["-r", TEST_SOURCE, &[TEST_DEST, copy].join("/")])
+                .output()
+                .unwrap();
+            assert!(diff.status.success());
+        }
+
+        fs::remove_dir_all(TEST_DEST).unwrap();
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn test_copy_file() {
+        Command::new("cargo")
+            .args(["build", "--release"])
+            .output()
+            .unwrap();
+
+        const TEST_SOURCE: &str = "src/main.rs";
+        const TEST_DEST: &str = "test_main_test_copy_file";
+
+        // A file is copied into a directory that exists or ends with a separator, and is
+        // otherwise copied to the path it is given
+        for dest in [
+            &format!("{}/dir/", TEST_DEST),
+            TEST_DEST,
+            "test_main_test_copy_file/f",
+        ] {
+            let output = Command::new("target/release/lms")
+                .args(["cp", TEST_SOURCE, dest])
+                .output()
+                .unwrap();
+            assert!(output.status.success());
+        }
+        for copied in ["dir/main.rs", "main.rs", "f"] {
+            let copied = fs::read([TEST_DEST, copied].join("/")).unwrap();
+            assert_eq!(copied, fs::read(TEST_SOURCE).unwrap());
+        }
+
+        fs::remove_dir_all(TEST_DEST).unwrap();
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn test_into_itself() {
+        Command::new("cargo")
+            .args(["build", "--release"])
+            .output()
+            .unwrap();
+
+        const TEST_SOURCE: &str = "test_main_test_into_itself";
+        const TEST_LINK: &str = "test_main_test_into_itself_link";
+
+        fs::create_dir_all([TEST_SOURCE, "sub"].join("/")).unwrap();
+        std::os::unix::fs::symlink(TEST_SOURCE, TEST_LINK).unwrap();
+
+        // The same directory through a symlink, and directories inside each other, are refused
+        for (src, dest) in [
+            (TEST_SOURCE, TEST_LINK.to_string()),
+            (TEST_SOURCE, [TEST_LINK, "sub/new"].join("/")),
+            (&[TEST_SOURCE, "sub"].join("/"), TEST_SOURCE.to_string()),
+            // Directories that do not exist yet are left by .. like those that do
+            (
+                &[TEST_SOURCE, "sub"].join("/"),
+                ["test_main_test_into_itself_new/..", TEST_SOURCE].join("/"),
+            ),
+        ] {
+            let output = Command::new("target/release/lms")
+                .args(["sync", src, &dest])
+                .output()
+                .unwrap();
+            assert_eq!(output.status.code(), Some(2));
+        }
+        assert!(fs::metadata([TEST_SOURCE, "sub"].join("/")).is_ok());
+        assert!(fs::metadata([TEST_SOURCE, "sub/new"].join("/")).is_err());
+        assert!(fs::metadata("test_main_test_into_itself_new").is_err());
+
+        fs::remove_file(TEST_LINK).unwrap();
+        fs::remove_dir_all(TEST_SOURCE).unwrap();
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn test_create_dest() {
+        Command::new("cargo")
+            .args(["build", "--release"])
+            .output()
+            .unwrap();
+
+        const TEST_SOURCE: &str = "src";
+        const TEST_DEST: &str = "test_main_test_create_dest";
+
+        // Destinations are created along with their parents, as are the directories that
+        // archives are written into
+        for dest in ["new/place", "archives/src.tar"] {
+            let output = Command::new("target/release/lms")
+                .args(["cp", TEST_SOURCE, &[TEST_DEST, dest].join("/")])
+                .output()
+                .unwrap();
+            assert!(output.status.success());
+        }
+        assert!(fs::metadata([TEST_DEST, "new/place/main.rs"].join("/")).is_ok());
+        assert!(fs::metadata([TEST_DEST, "archives/src.tar"].join("/"))
+            .unwrap()
+            .is_file());
 
         fs::remove_dir_all(TEST_DEST).unwrap();
     }
@@ -223,7 +724,7 @@ mod test_main {
     #[test]
     fn test_no_delete() {
         Command::new("cargo")
-            .args(&["build", "--release"])
+            .args(["build", "--release"])
             .output()
             .unwrap();
 
@@ -244,21 +745,21 @@ mod test_main {
         fs::copy(TEST_FILE2, [TEST_EXPECTED, TEST_FILE2].join("/")).unwrap();
 
         Command::new("target/release/lms")
-            .args(&["cp", TEST_SOURCE1, TEST_DEST])
+            .args(["cp", TEST_SOURCE1, TEST_DEST])
             .output()
             .unwrap();
 
         Command::new("target/release/lms")
-            .args(&["sync", "-n", TEST_SOURCE2, TEST_DEST])
+            .args(["sync", "-n", TEST_SOURCE2, TEST_DEST])
             .output()
             .unwrap();
 
         let diff = Command::new("diff")
-            .args(&["-r", TEST_DEST, TEST_EXPECTED])
+            .args(["-r", TEST_DEST, TEST_EXPECTED])
             .output()
             .unwrap();
 
-        assert_eq!(diff.status.success(), true);
+        assert!(diff.status.success());
 
         fs::remove_dir_all(TEST_SOURCE1).unwrap();
         fs::remove_dir_all(TEST_SOURCE2).unwrap();
@@ -270,7 +771,7 @@ mod test_main {
     #[test]
     fn test_remove() {
         Command::new("cargo")
-            .args(&["build", "--release"])
+            .args(["build", "--release"])
             .output()
             .unwrap();
 
@@ -279,23 +780,23 @@ mod test_main {
         fs::create_dir_all(TEST_DEST).unwrap();
 
         Command::new("cp")
-            .args(&["-r", TEST_SOURCE, TEST_DEST])
+            .args(["-r", TEST_SOURCE, TEST_DEST])
             .output()
             .unwrap();
 
         Command::new("target/release/lms")
-            .args(&["rm", TEST_DEST])
+            .args(["rm", TEST_DEST])
             .output()
             .unwrap();
 
-        assert_eq!(fs::read_dir(TEST_DEST).is_err(), true);
+        assert!(fs::read_dir(TEST_DEST).is_err());
     }
 
     #[cfg(target_family = "unix")]
     #[test]
     fn test_remove_multiple() {
         Command::new("cargo")
-            .args(&["build", "--release"])
+            .args(["build", "--release"])
             .output()
             .unwrap();
 
@@ -305,29 +806,29 @@ mod test_main {
         fs::create_dir_all(TEST_DEST[1]).unwrap();
 
         Command::new("cp")
-            .args(&["-r", TEST_SOURCE, TEST_DEST[0]])
+            .args(["-r", TEST_SOURCE, TEST_DEST[0]])
             .output()
             .unwrap();
 
         Command::new("cp")
-            .args(&["-r", TEST_SOURCE, TEST_DEST[1]])
+            .args(["-r", TEST_SOURCE, TEST_DEST[1]])
             .output()
             .unwrap();
 
         Command::new("target/release/lms")
-            .args(&["rm", TEST_DEST[0], TEST_DEST[1]])
+            .args(["rm", TEST_DEST[0], TEST_DEST[1]])
             .output()
             .unwrap();
 
-        assert_eq!(fs::read_dir(TEST_DEST[0]).is_err(), true);
-        assert_eq!(fs::read_dir(TEST_DEST[1]).is_err(), true);
+        assert!(fs::read_dir(TEST_DEST[0]).is_err());
+        assert!(fs::read_dir(TEST_DEST[1]).is_err());
     }
 
     #[cfg(target_family = "unix")]
     #[test]
     fn test_sequential_remove() {
         Command::new("cargo")
-            .args(&["build", "--release"])
+            .args(["build", "--release"])
             .output()
             .unwrap();
 
@@ -336,15 +837,251 @@ mod test_main {
         fs::create_dir_all(TEST_DEST).unwrap();
 
         Command::new("cp")
-            .args(&["-r", TEST_SOURCE, TEST_DEST])
+            .args(["-r", TEST_SOURCE, TEST_DEST])
             .output()
             .unwrap();
 
         Command::new("target/release/lms")
-            .args(&["rm", "-S", TEST_DEST])
+            .args(["rm", "-S", TEST_DEST])
+            .output()
+            .unwrap();
+
+        assert!(fs::read_dir(TEST_DEST).is_err());
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn test_tui() {
+        Command::new("cargo")
+            .args(["build", "--release"])
+            .output()
+            .unwrap();
+
+        const TEST_DIR: &str = "test_main_test_tui";
+        const TEST_DEST: &str = "test_main_test_tui_out";
+        fs::create_dir_all(TEST_DIR).unwrap();
+        fs::write([TEST_DIR, "a"].join("/"), b"a").unwrap();
+        fs::write([TEST_DIR, "b"].join("/"), b"b").unwrap();
+
+        let mut child = Command::new("target/release/lms")
+            .args(["tui", TEST_DIR, TEST_DEST])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(b"n\ny\n").unwrap();
+        let output = child.wait_with_output().unwrap();
+
+        assert_eq!(output.status.code(), Some(0));
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        assert!(stdout.contains("(1/2) Create file \"a\" (1 bytes)"));
+        assert!(stdout.ends_with("Applied 1 of 2 changes\n"));
+        assert!(fs::metadata([TEST_DEST, "a"].join("/")).is_err());
+        assert_eq!(fs::read([TEST_DEST, "b"].join("/")).unwrap(), b"b");
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_dir_all(TEST_DEST).unwrap();
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn test_preview() {
+        Command::new("cargo")
+            .args(["build", "--release"])
+            .output()
+            .unwrap();
+
+        const TEST_DIR: &str = "test_main_test_preview";
+        const TEST_DEST: &str = "test_main_test_preview_out";
+        fs::create_dir_all(TEST_DIR).unwrap();
+        std::os::unix::fs::symlink("/old/a", [TEST_DIR, "a"].join("/")).unwrap();
+
+        let preview = |answer: &[u8]| {
+            let mut child = Command::new("target/release/lms")
+                .args(["sync", "--preview", "1", "--link-rewrite", "s#^/old#/new#"])
+                .args([TEST_DIR, TEST_DEST])
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn()
+                .unwrap();
+            child.stdin.take().unwrap().write_all(answer).unwrap();
+            child.wait_with_output().unwrap()
+        };
+
+        // Nothing is written unless the preview is confirmed
+        let output = preview(b"n\n");
+        assert_eq!(output.status.code(), Some(5));
+        assert!(String::from_utf8(output.stdout)
+            .unwrap()
+            .contains("symlink to \"/new/a\", instead of \"/old/a\""));
+        assert!(fs::symlink_metadata([TEST_DEST, "a"].join("/")).is_err());
+
+        let output = preview(b"y\n");
+        assert_eq!(output.status.code(), Some(0));
+        assert_eq!(
+            fs::read_link([TEST_DEST, "a"].join("/")).unwrap(),
+            std::path::PathBuf::from("/new/a")
+        );
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_dir_all(TEST_DEST).unwrap();
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn test_fidelity_report() {
+        Command::new("cargo")
+            .args(["build", "--release"])
             .output()
             .unwrap();
 
-        assert_eq!(fs::read_dir(TEST_DEST).is_err(), true);
+        const TEST_DEST: &str = "test_main_test_fidelity_report";
+        const TEST_REPORT: &str = "test_main_test_fidelity_report.json";
+
+        let output = Command::new("target/release/lms")
+            .args(["cp", "--fidelity-report", TEST_REPORT, "src", TEST_DEST])
+            .output()
+            .unwrap();
+
+        assert_eq!(output.status.code(), Some(0));
+        assert_eq!(
+            fs::read_to_string(TEST_REPORT).unwrap(),
+            "{\n  \"faithful\": true,\n  \"degraded\": []\n}\n"
+        );
+
+        fs::remove_dir_all(TEST_DEST).unwrap();
+
+        // The summary of what could not be preserved is left out with --quiet
+        const TEST_DIR: &str = "test_main_test_fidelity_report_src";
+        fs::create_dir_all(TEST_DIR).unwrap();
+        Command::new("mkfifo")
+            .arg([TEST_DIR, "fifo"].join("/"))
+            .status()
+            .unwrap();
+        let output = Command::new("target/release/lms")
+            .args([
+                "sync",
+                "-q",
+                "--fidelity-report",
+                TEST_REPORT,
+                TEST_DIR,
+                TEST_DEST,
+            ])
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(0));
+        assert!(output.stderr.is_empty());
+        assert!(fs::read_to_string(TEST_REPORT)
+            .unwrap()
+            .contains("\"faithful\": false"));
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_dir_all(TEST_DEST).unwrap();
+        fs::remove_file(TEST_REPORT).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_harden() {
+        use std::os::unix::fs::symlink;
+
+        Command::new("cargo")
+            .args(["build", "--release"])
+            .output()
+            .unwrap();
+
+        const TEST_DIR: &str = "test_main_test_harden";
+        const TEST_DEST: &str = "test_main_test_harden_out";
+        const TEST_LOG: &str = "test_main_test_harden.log";
+        const TEST_ARCHIVE: &str = "test_main_test_harden.tar.zst";
+
+        // Hooks could never run, since hardened runs can not run programs
+        for hook in &["--hook-pre", "--hook-post"] {
+            let output = Command::new("target/release/lms")
+                .args(["cp", "--harden", hook, "true", "src", TEST_DEST])
+                .output()
+                .unwrap();
+            assert_eq!(output.status.code(), Some(2));
+            assert!(fs::metadata(TEST_DEST).is_err());
+        }
+
+        let output = Command::new("target/release/lms")
+            .args(["cp", "--harden", "--log-file", TEST_LOG, "src", TEST_DEST])
+            .output()
+            .unwrap();
+        if String::from_utf8_lossy(&output.stderr).contains("Landlock is not available") {
+            return;
+        }
+        assert_eq!(output.status.code(), Some(0));
+        assert_eq!(
+            fs::read(format!("{}/main.rs", TEST_DEST)).unwrap(),
+            fs::read("src/main.rs").unwrap()
+        );
+        assert!(fs::metadata(TEST_LOG).unwrap().len() > 0);
+        fs::remove_dir_all(TEST_DEST).unwrap();
+
+        // Archives are written into, and read from, files in the directory they are in
+        let output = Command::new("target/release/lms")
+            .args(["cp", "--harden", "src", TEST_ARCHIVE])
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(0));
+        let output = Command::new("target/release/lms")
+            .args(["cp", "--harden", TEST_ARCHIVE, TEST_DEST])
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(0));
+        assert_eq!(
+            fs::read(format!("{}/main.rs", TEST_DEST)).unwrap(),
+            fs::read("src/main.rs").unwrap()
+        );
+        fs::remove_dir_all(TEST_DEST).unwrap();
+        fs::remove_file(TEST_ARCHIVE).unwrap();
+
+        // What a symlink in the source points to is out of reach
+        fs::create_dir_all(format!("{}/src", TEST_DIR)).unwrap();
+        fs::create_dir_all(format!("{}/outside", TEST_DIR)).unwrap();
+        fs::write(format!("{}/outside/secret", TEST_DIR), "secret").unwrap();
+        symlink("../outside", format!("{}/src/link", TEST_DIR)).unwrap();
+
+        let src = format!("{}/src", TEST_DIR);
+        let output = Command::new("target/release/lms")
+            .args(["sync", "--harden", "--copy-dirlinks", &src, TEST_DEST])
+            .output()
+            .unwrap();
+        assert_ne!(output.status.code(), Some(0));
+        assert!(fs::metadata(format!("{}/link/secret", TEST_DEST)).is_err());
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_dir_all(TEST_DEST).unwrap();
+        fs::remove_file(TEST_LOG).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_privsep() {
+        Command::new("cargo")
+            .args(["build", "--release"])
+            .output()
+            .unwrap();
+
+        const TEST_DEST: &str = "test_main_test_privsep";
+
+        let output = Command::new("target/release/lms")
+            .args(["cp", "--privsep", "src", TEST_DEST])
+            .output()
+            .unwrap();
+        if String::from_utf8_lossy(&output.stderr).contains("Landlock is not available") {
+            fs::remove_dir_all(TEST_DEST).unwrap();
+            return;
+        }
+        assert_eq!(output.status.code(), Some(0));
+        assert_eq!(
+            fs::read(format!("{}/lumins/privsep.rs", TEST_DEST)).unwrap(),
+            fs::read("src/lumins/privsep.rs").unwrap()
+        );
+
+        fs::remove_dir_all(TEST_DEST).unwrap();
     }
 }